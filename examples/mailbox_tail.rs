@@ -0,0 +1,52 @@
+//! Follows a disk mailbox like `tail -f`, without marking anything read
+//!
+//! ```text
+//! cargo run --example mailbox_tail -- <base path> --mailbox <id> [--extension item] [--decode] [--all]
+//! ```
+use color_eyre::eyre::eyre;
+use color_eyre::eyre::Result;
+use oml_mailbox::MailboxDisk;
+use oml_mailbox::MailboxTail;
+use oml_mailbox::PollPolicy;
+use oml_mailbox::RawItem;
+use std::path::PathBuf;
+use std::time::Duration;
+
+const PREVIEW_BYTES: usize = 64;
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    color_eyre::install()?;
+
+    let mut base_path = None;
+    let mut mailbox_id = None;
+    let mut extension = String::from("item");
+    let mut decode = false;
+    let mut all = false;
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--mailbox" => mailbox_id = args.next(),
+            "--extension" => extension = args.next().ok_or_else(|| eyre!("Missing extension"))?,
+            "--decode" => decode = true,
+            "--all" => all = true,
+            _ if base_path.is_none() && !arg.starts_with("--") => {
+                base_path = Some(PathBuf::from(arg))
+            }
+            _ => return Err(eyre!("Unexpected argument {arg:?}")),
+        }
+    }
+    let base_path = base_path.ok_or_else(|| eyre!("Missing base path"))?;
+    let mailbox_id = mailbox_id.ok_or_else(|| eyre!("Missing --mailbox"))?;
+
+    let mailbox = MailboxDisk::<RawItem>::at(&base_path, extension.as_ref()).read_only();
+    let mut tail = MailboxTail::new(&mailbox, &mailbox_id, all);
+    tail.follow(
+        PollPolicy::Fixed(Duration::from_millis(500)),
+        |entry| println!("{}", entry.summary(decode, PREVIEW_BYTES)),
+        |e| eprintln!("Can't read mailbox {mailbox_id}, retrying -> {e}"),
+    )
+    .await;
+
+    Ok(())
+}
@@ -0,0 +1,8 @@
+/// Totals over several mailboxes, see [crate::MailboxDisk::stats_prefix]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct AggregatedStats {
+    pub mailboxes: u64,
+    pub pending: u64,
+    /// Bytes, see [crate::MailboxDisk::disk_usage]
+    pub disk_usage: u64,
+}
@@ -0,0 +1,15 @@
+use serde::Deserialize;
+use serde::Serialize;
+use std::time::Duration;
+
+/// What `send` does when a mailbox is full, see [crate::MailboxDisk::with_capacity]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum Backpressure {
+    /// Fail with [crate::MailboxError::MailboxFull]
+    #[default]
+    Reject,
+    /// Wait until items are acknowledged, or fail with [crate::MailboxError::Timeout] after `timeout`
+    ///
+    /// Note: The mailbox is not locked while waiting, so everything else keeps working.
+    Wait { timeout: Option<Duration> },
+}
@@ -0,0 +1,240 @@
+use crate::BulkResult;
+use crate::ExpireReport;
+use crate::HeaderSelector;
+use crate::HealthReport;
+use crate::ItemSummary;
+use crate::Mailbox;
+use crate::MailboxDisk;
+use crate::MailboxItem;
+use crate::MailboxStats;
+use crate::Page;
+use color_eyre::eyre::Result;
+use core::future::Future;
+use std::path::Path;
+use std::time::Duration;
+use tokio::runtime::Runtime;
+
+/// A synchronous facade over any mailbox, for programs without an async runtime
+///
+/// Every call runs on an owned current-thread runtime, like reqwest's blocking client.
+///
+/// Note: Using it from within an async context panics,
+/// since blocking there would stall, or deadlock, the surrounding runtime.
+/// ```
+/// # use oml_mailbox::{BlockingMailbox, RawItem};
+/// # let dir = tempfile::TempDir::new().unwrap();
+/// let mailbox = BlockingMailbox::<RawItem>::disk(dir.path(), std::path::Path::new("item"))?;
+/// mailbox.send("42", RawItem::new(b"hello".to_vec()))?;
+/// let (item_id, item) = mailbox.receive("42")?.expect("Item pending");
+/// mailbox.acknowledge("42", &item_id)?;
+/// # Ok::<(), color_eyre::eyre::Report>(())
+/// ```
+#[derive(Debug)]
+pub struct BlockingMailbox<ITEM: MailboxItem> {
+    inner: Box<dyn Mailbox<ITEM>>,
+    runtime: Runtime,
+}
+
+impl<ITEM: MailboxItem + Send + 'static> BlockingMailbox<ITEM> {
+    pub fn new(inner: impl Mailbox<ITEM> + 'static) -> Result<Self> {
+        Ok(Self {
+            inner: Box::new(inner),
+            runtime: Self::runtime()?,
+        })
+    }
+
+    /// A [MailboxDisk], with its storage already ensured
+    pub fn disk(base_path: &Path, extension: &Path) -> Result<Self> {
+        let runtime = Self::runtime()?;
+        let inner = runtime.block_on(async {
+            let mut mailbox = MailboxDisk::<ITEM>::at(base_path, extension);
+            mailbox.ensure_storage_exists().await?;
+            Ok::<_, color_eyre::eyre::Report>(mailbox)
+        })?;
+        Ok(Self {
+            inner: Box::new(inner),
+            runtime,
+        })
+    }
+
+    pub fn inner(&self) -> &dyn Mailbox<ITEM> {
+        self.inner.as_ref()
+    }
+
+    pub fn into_inner(self) -> Box<dyn Mailbox<ITEM>> {
+        self.inner
+    }
+
+    fn runtime() -> Result<Runtime> {
+        Self::ensure_not_async();
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()?;
+        Ok(runtime)
+    }
+
+    fn ensure_not_async() {
+        if tokio::runtime::Handle::try_current().is_ok() {
+            panic!(
+                "BlockingMailbox can't be used from within an async runtime, use the Mailbox directly"
+            );
+        }
+    }
+
+    fn block_on<T>(&self, f: impl Future<Output = T>) -> T {
+        Self::ensure_not_async();
+        self.runtime.block_on(f)
+    }
+
+    pub fn ensure_storage_exists(&mut self) -> Result<()> {
+        Self::ensure_not_async();
+        self.runtime.block_on(self.inner.ensure_storage_exists())
+    }
+    pub fn close(&mut self) -> Result<()> {
+        Self::ensure_not_async();
+        self.runtime.block_on(self.inner.close())
+    }
+
+    pub fn create_mailbox(&self, id: &str) -> Result<()> {
+        self.block_on(self.inner.create_mailbox(id))
+    }
+    pub fn send(&self, id: &str, item: ITEM) -> Result<String> {
+        self.block_on(self.inner.send(id, item))
+    }
+    pub fn send_transaction(&self, id: &str, items: Vec<ITEM>) -> Result<Vec<String>> {
+        self.block_on(self.inner.send_transaction(id, items))
+    }
+    pub fn send_many(&self, id: &str, items: Vec<ITEM>) -> Result<BulkResult<String>> {
+        self.block_on(self.inner.send_many(id, items))
+    }
+    pub fn receive(&self, id: &str) -> Result<Option<(String, ITEM)>> {
+        self.block_on(self.inner.receive(id))
+    }
+    pub fn receive_any(&self, ids: &[String]) -> Result<Option<(String, String, ITEM)>> {
+        self.block_on(self.inner.receive_any(ids))
+    }
+    pub fn acknowledge(&self, id: &str, item_id: &str) -> Result<()> {
+        self.block_on(self.inner.acknowledge(id, item_id))
+    }
+    pub fn send_raw(&self, id: &str, data: &[u8]) -> Result<String> {
+        self.block_on(self.inner.send_raw(id, data))
+    }
+    pub fn receive_raw(&self, id: &str) -> Result<Option<(String, Vec<u8>)>> {
+        self.block_on(self.inner.receive_raw(id))
+    }
+    pub fn acknowledge_through(&self, id: &str, item_id: &str) -> Result<u64> {
+        self.block_on(self.inner.acknowledge_through(id, item_id))
+    }
+    pub fn release(&self, id: &str, item_id: &str) -> Result<()> {
+        self.block_on(self.inner.release(id, item_id))
+    }
+    pub fn update(&self, id: &str, item_id: &str, item: ITEM) -> Result<()> {
+        self.block_on(self.inner.update(id, item_id, item))
+    }
+    pub fn peek(&self, id: &str) -> Result<Option<(String, ITEM)>> {
+        self.block_on(self.inner.peek(id))
+    }
+    pub fn receive_many(&self, id: &str, max: usize) -> Result<Vec<(String, ITEM)>> {
+        self.block_on(self.inner.receive_many(id, max))
+    }
+    pub fn receive_batch(
+        &self,
+        id: &str,
+        max_items: usize,
+        max_wait: Duration,
+    ) -> Result<Vec<(String, ITEM)>> {
+        self.block_on(self.inner.receive_batch(id, max_items, max_wait))
+    }
+    pub fn receive_matching(
+        &self,
+        id: &str,
+        selector: &HeaderSelector,
+    ) -> Result<Option<(String, ITEM)>> {
+        self.block_on(self.inner.receive_matching(id, selector))
+    }
+    pub fn list_mailboxes(&self) -> Result<Vec<String>> {
+        self.block_on(self.inner.list_mailboxes())
+    }
+    pub fn delivery_count(&self, id: &str, item_id: &str) -> Result<u32> {
+        self.block_on(self.inner.delivery_count(id, item_id))
+    }
+    pub fn list_mailboxes_page(&self, after: Option<&str>, limit: usize) -> Result<Page<String>> {
+        self.block_on(self.inner.list_mailboxes_page(after, limit))
+    }
+    pub fn list_items_page(
+        &self,
+        id: &str,
+        after: Option<&str>,
+        limit: usize,
+    ) -> Result<Page<ItemSummary>> {
+        self.block_on(self.inner.list_items_page(id, after, limit))
+    }
+    pub fn item_size(&self, id: &str, item_id: &str) -> Result<u64> {
+        self.block_on(self.inner.item_size(id, item_id))
+    }
+    pub fn stats(&self, id: &str) -> Result<MailboxStats> {
+        self.block_on(self.inner.stats(id))
+    }
+    pub fn receive_for(&self, id: &str, group: &str) -> Result<Option<(String, ITEM)>> {
+        self.block_on(self.inner.receive_for(id, group))
+    }
+    pub fn acknowledge_for(&self, id: &str, group: &str, item_id: &str) -> Result<()> {
+        self.block_on(self.inner.acknowledge_for(id, group, item_id))
+    }
+    pub fn compact(&self, id: &str) -> Result<u64> {
+        self.block_on(self.inner.compact(id))
+    }
+    pub fn drop_older_than(&self, id: &str, max_age: Duration) -> Result<u64> {
+        self.block_on(self.inner.drop_older_than(id, max_age))
+    }
+    pub fn expire_items(&self, id: &str) -> Result<ExpireReport> {
+        self.block_on(self.inner.expire_items(id))
+    }
+    pub fn health_check(&self) -> Result<HealthReport> {
+        self.block_on(self.inner.health_check())
+    }
+    pub fn pop(&self, id: &str) -> Result<Option<(String, ITEM)>> {
+        self.block_on(self.inner.pop(id))
+    }
+    pub fn drain(&self, id: &str, max: Option<usize>) -> Result<Vec<(String, ITEM)>> {
+        self.block_on(self.inner.drain(id, max))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::BlockingMailbox;
+    use crate::MailboxMemory;
+    use crate::RawItem;
+    use color_eyre::Result;
+    use std::path::Path;
+    use tempfile::TempDir;
+
+    use test_log::test;
+
+    #[test]
+    fn it_works_without_a_runtime() -> Result<()> {
+        let dir = TempDir::new()?;
+        let mailbox = BlockingMailbox::<RawItem>::disk(dir.path(), Path::new("item"))?;
+
+        let item_id = mailbox.send("42", RawItem::new(b"one".to_vec()))?;
+        let (received_id, item) = mailbox.receive("42")?.expect("Item pending");
+        assert_eq!(received_id, item_id);
+        assert_eq!(item.data(), b"one");
+        mailbox.acknowledge("42", &item_id)?;
+        assert!(mailbox.receive("42")?.is_none());
+        assert_eq!(mailbox.list_mailboxes()?, ["42"]);
+
+        let memory = BlockingMailbox::new(MailboxMemory::<RawItem>::default())?;
+        memory.send("42", RawItem::new(b"two".to_vec()))?;
+        assert_eq!(memory.drain("42", None)?.len(), 1);
+
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    #[should_panic(expected = "can't be used from within an async runtime")]
+    async fn it_refuses_to_block_an_async_context() {
+        let _ = BlockingMailbox::new(MailboxMemory::<RawItem>::default());
+    }
+}
@@ -0,0 +1,141 @@
+use crate::MailboxError;
+use color_eyre::eyre::eyre;
+use color_eyre::eyre::Report;
+use color_eyre::eyre::Result;
+use serde::Deserialize;
+use serde::Serialize;
+use std::sync::Arc;
+
+/// Why one entry of a [BulkResult] failed
+///
+/// Keeps the original [Report] to downcast, e.g. with [BulkError::mailbox_error],
+/// but only the message, and if it is worth retrying, survive serialization.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BulkError {
+    pub message: String,
+    /// See [MailboxError::is_retryable_report]
+    pub retryable: bool,
+    #[serde(skip)]
+    report: Option<Arc<Report>>,
+}
+
+impl BulkError {
+    pub fn report(&self) -> Option<&Report> {
+        self.report.as_deref()
+    }
+
+    /// The first [MailboxError] in the chain, `None` once deserialized
+    pub fn mailbox_error(&self) -> Option<&MailboxError> {
+        self.report()?
+            .chain()
+            .find_map(|e| e.downcast_ref::<MailboxError>())
+    }
+}
+
+impl From<Report> for BulkError {
+    fn from(report: Report) -> Self {
+        Self {
+            message: format!("{report:#}"),
+            retryable: MailboxError::is_retryable_report(&report),
+            report: Some(Arc::new(report)),
+        }
+    }
+}
+
+impl std::fmt::Display for BulkError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for BulkError {}
+
+/// The outcome for one mailbox, or one item, of a bulk operation
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BulkEntry<T> {
+    pub mailbox_id: String,
+    /// `None` for operations on whole mailboxes, and for items that never got an id
+    pub item_id: Option<String>,
+    pub outcome: std::result::Result<T, BulkError>,
+}
+
+/// What a bulk operation did, entry by entry
+///
+/// Bulk operations carry on past failing entries,
+/// and only fail as a whole if they can't even start, e.g. because listing the mailboxes fails.
+/// The entries are in input order, unless the operation says otherwise.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BulkResult<T> {
+    pub entries: Vec<BulkEntry<T>>,
+}
+
+impl<T> Default for BulkResult<T> {
+    fn default() -> Self {
+        Self {
+            entries: Vec::new(),
+        }
+    }
+}
+
+impl<T> BulkResult<T> {
+    pub(crate) fn push(&mut self, mailbox_id: &str, item_id: Option<String>, outcome: Result<T>) {
+        self.entries.push(BulkEntry {
+            mailbox_id: mailbox_id.to_string(),
+            item_id,
+            outcome: outcome.map_err(BulkError::from),
+        });
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// The index and value of every entry that succeeded
+    pub fn succeeded(&self) -> impl Iterator<Item = (usize, &T)> {
+        self.entries
+            .iter()
+            .enumerate()
+            .filter_map(|(index, entry)| entry.outcome.as_ref().ok().map(|v| (index, v)))
+    }
+
+    /// The index and entry of every entry that failed
+    pub fn failed(&self) -> impl Iterator<Item = (usize, &BulkEntry<T>)> {
+        self.entries
+            .iter()
+            .enumerate()
+            .filter(|(_index, entry)| entry.outcome.is_err())
+    }
+
+    pub fn is_all_ok(&self) -> bool {
+        self.entries.iter().all(|entry| entry.outcome.is_ok())
+    }
+
+    /// All values, or the first failure, for callers that don't care about partial success
+    pub fn into_result(self) -> Result<Vec<T>> {
+        let failed = self.entries.iter().filter(|e| e.outcome.is_err()).count();
+        let mut values = Vec::with_capacity(self.entries.len());
+        for (index, entry) in self.entries.into_iter().enumerate() {
+            match entry.outcome {
+                Ok(v) => values.push(v),
+                Err(e) => {
+                    let context = format!(
+                        "Entry {index} for mailbox {} failed, {failed} failed in total",
+                        entry.mailbox_id
+                    );
+                    return Err(match e.report {
+                        Some(report) => match Arc::try_unwrap(report) {
+                            Ok(report) => report.wrap_err(context),
+                            Err(report) => eyre!("{report:#}").wrap_err(context),
+                        },
+                        None => eyre!(e.message).wrap_err(context),
+                    });
+                }
+            }
+        }
+        Ok(values)
+    }
+}
@@ -0,0 +1,26 @@
+use serde::Deserialize;
+use serde::Serialize;
+
+/// How far [crate::MailboxDisk] trusts the metas it loaded before, see [crate::MailboxDisk::with_meta_cache]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum CacheMode {
+    /// Load the meta, and replay the journal, for every operation
+    #[default]
+    Off,
+    /// This instance is the only one writing its mailboxes, so the cached meta is always up to date
+    Exclusive,
+    /// Check size, modification time, and file id of the meta and the journal, reload if anything changed
+    ///
+    /// For several instances, or processes, sharing the storage.
+    Validate,
+}
+
+/// Counters of the meta cache, see [crate::MailboxDisk::meta_cache_stats]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MetaCacheStats {
+    pub hits: u64,
+    /// Metas not in the cache yet
+    pub misses: u64,
+    /// Cached metas found out of date by [CacheMode::Validate], and loaded again
+    pub invalidations: u64,
+}
@@ -0,0 +1,412 @@
+use crate::ExpireReport;
+use crate::HeaderSelector;
+use crate::HealthReport;
+use crate::ItemSummary;
+use crate::Mailbox;
+use crate::MailboxItem;
+use crate::MailboxStats;
+use crate::Page;
+use crate::ReceiveAnyTurn;
+use crate::DEFAULT_GROUP;
+use async_trait::async_trait;
+use color_eyre::eyre::Result;
+use core::marker::PhantomData;
+use std::collections::HashMap;
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// How much [CachedMailbox] prefetches, and how long it trusts what it fetched
+#[derive(Debug, Clone)]
+pub struct CacheConfig {
+    /// Number of items fetched from the inner mailbox in one go
+    pub prefetch: usize,
+    /// How long cached state is used before it is refreshed from the inner mailbox
+    pub ttl: Duration,
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        Self {
+            prefetch: 16,
+            ttl: Duration::from_secs(5),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct CachedState {
+    /// Prefetched unread items, kept serialized since [MailboxItem] is not `Clone`
+    items: VecDeque<(String, Vec<u8>)>,
+    /// The last fetch returned everything that was unread
+    exhausted: bool,
+    fetched_at: Instant,
+}
+
+/// Wraps a (slow) mailbox, and serves `peek` and `receive` from prefetched items
+///
+/// Only `receive` prefetches, `peek` asks the inner mailbox when nothing is cached,
+/// so peeking doesn't count deliveries.
+///
+/// Sends and acknowledgements are written through to the inner mailbox right away.
+///
+/// Note: This assumes a single consumer per mailbox.
+/// Items received, or acknowledged, by someone else stay in the cache until the `ttl` expires,
+/// or [CachedMailbox::invalidate] is called.
+/// Items sent directly to the inner mailbox are only seen once the prefetched items are used up,
+/// and the `ttl` expired.
+///
+/// Note: Prefetched items count as delivered by the inner mailbox as soon as they are fetched.
+#[derive(Debug)]
+pub struct CachedMailbox<ITEM: MailboxItem, M: Mailbox<ITEM>> {
+    inner: M,
+    config: CacheConfig,
+    state: Mutex<HashMap<String, CachedState>>,
+    receive_any_turn: ReceiveAnyTurn,
+    item_type: PhantomData<ITEM>,
+}
+
+impl<ITEM: MailboxItem, M: Mailbox<ITEM>> CachedMailbox<ITEM, M> {
+    pub fn new(inner: M, config: CacheConfig) -> Self {
+        Self {
+            inner,
+            config,
+            state: Default::default(),
+            receive_any_turn: Default::default(),
+            item_type: PhantomData,
+        }
+    }
+
+    pub fn inner(&self) -> &M {
+        &self.inner
+    }
+
+    /// Forget everything cached for the mailbox
+    pub fn invalidate(&self, mailbox_id: &str) {
+        self.state.lock().unwrap().remove(mailbox_id);
+    }
+
+    /// The next item according to the cache
+    ///
+    /// `Some(None)` means the cache knows the mailbox is empty, `None` means the cache can't tell.
+    fn cached_next(&self, mailbox_id: &str) -> Option<Option<(String, Vec<u8>)>> {
+        let mut state = self.state.lock().unwrap();
+        let cached = state.get(mailbox_id)?;
+        if cached.fetched_at.elapsed() > self.config.ttl {
+            state.remove(mailbox_id);
+            return None;
+        }
+        match cached.items.front() {
+            Some(front) => Some(Some(front.clone())),
+            None if cached.exhausted => Some(None),
+            None => None,
+        }
+    }
+
+    async fn refresh(&self, mailbox_id: &str) -> Result<Option<(String, Vec<u8>)>> {
+        let prefetch = self.config.prefetch.max(1);
+        let items = self.inner.receive_many(mailbox_id, prefetch).await?;
+        let exhausted = items.len() < prefetch;
+        let items = items
+            .into_iter()
+            .map(|(id, item)| Ok((id, item.serialize()?)))
+            .collect::<Result<VecDeque<_>>>()?;
+        tracing::debug!("Prefetched {} items for {mailbox_id}", items.len());
+
+        let front = items.front().cloned();
+        self.state.lock().unwrap().insert(
+            mailbox_id.to_string(),
+            CachedState {
+                items,
+                exhausted,
+                fetched_at: Instant::now(),
+            },
+        );
+
+        Ok(front)
+    }
+
+    async fn next(&self, mailbox_id: &str) -> Result<Option<(String, ITEM)>> {
+        let next = match self.cached_next(mailbox_id) {
+            Some(next) => next,
+            None => self.refresh(mailbox_id).await?,
+        };
+        match next {
+            Some((item_id, data)) => Ok(Some((item_id, ITEM::deserialize(&data)?))),
+            None => Ok(None),
+        }
+    }
+}
+
+#[async_trait]
+impl<ITEM: MailboxItem + Send, M: Mailbox<ITEM>> Mailbox<ITEM> for CachedMailbox<ITEM, M> {
+    async fn ensure_storage_exists(&mut self) -> Result<()> {
+        self.inner.ensure_storage_exists().await
+    }
+    async fn close(&mut self) -> Result<()> {
+        self.state.lock().unwrap().clear();
+        self.inner.close().await
+    }
+
+    async fn create_mailbox(&self, id: &str) -> Result<()> {
+        self.inner.create_mailbox(id).await
+    }
+    async fn send(&self, id: &str, item: ITEM) -> Result<String> {
+        let item_id = self.inner.send(id, item).await?;
+        // Note: the new item is newer than everything prefetched, so we only have to look again
+        if let Some(cached) = self.state.lock().unwrap().get_mut(id) {
+            cached.exhausted = false;
+        }
+        Ok(item_id)
+    }
+    async fn send_transaction(&self, id: &str, items: Vec<ITEM>) -> Result<Vec<String>> {
+        let item_ids = self.inner.send_transaction(id, items).await?;
+        if let Some(cached) = self.state.lock().unwrap().get_mut(id) {
+            cached.exhausted = false;
+        }
+        Ok(item_ids)
+    }
+    async fn receive(&self, id: &str) -> Result<Option<(String, ITEM)>> {
+        self.next(id).await
+    }
+    fn receive_any_turn(&self) -> Option<&ReceiveAnyTurn> {
+        Some(&self.receive_any_turn)
+    }
+    async fn acknowledge(&self, id: &str, item_id: &str) -> Result<()> {
+        self.inner.acknowledge(id, item_id).await?;
+        if let Some(cached) = self.state.lock().unwrap().get_mut(id) {
+            cached.items.retain(|(cached_id, _)| cached_id != item_id);
+        }
+        Ok(())
+    }
+    async fn acknowledge_through(&self, id: &str, item_id: &str) -> Result<u64> {
+        let acknowledged = self.inner.acknowledge_through(id, item_id).await?;
+        let through = item_id.parse::<u64>().unwrap_or_default();
+        if let Some(cached) = self.state.lock().unwrap().get_mut(id) {
+            cached
+                .items
+                .retain(|(cached_id, _)| cached_id.parse::<u64>().is_ok_and(|c| c > through));
+        }
+        Ok(acknowledged)
+    }
+    async fn release(&self, id: &str, item_id: &str) -> Result<()> {
+        self.inner.release(id, item_id).await
+    }
+    fn release_later(&self, id: &str, item_id: &str) {
+        self.inner.release_later(id, item_id)
+    }
+    async fn update(&self, id: &str, item_id: &str, item: ITEM) -> Result<()> {
+        let data = item.serialize()?;
+        self.inner.update(id, item_id, item).await?;
+        if let Some(cached) = self.state.lock().unwrap().get_mut(id) {
+            for (cached_id, cached_data) in cached.items.iter_mut() {
+                if cached_id == item_id {
+                    *cached_data = data.clone();
+                }
+            }
+        }
+        Ok(())
+    }
+    async fn peek(&self, id: &str) -> Result<Option<(String, ITEM)>> {
+        match self.cached_next(id) {
+            Some(Some((item_id, data))) => Ok(Some((item_id, ITEM::deserialize(&data)?))),
+            Some(None) => Ok(None),
+            None => self.inner.peek(id).await,
+        }
+    }
+    /// Note: Not cached, the prefetched items are in id order, not grouped by headers.
+    async fn receive_matching(
+        &self,
+        id: &str,
+        selector: &HeaderSelector,
+    ) -> Result<Option<(String, ITEM)>> {
+        self.inner.receive_matching(id, selector).await
+    }
+    async fn list_mailboxes(&self) -> Result<Vec<String>> {
+        self.inner.list_mailboxes().await
+    }
+    async fn delivery_count(&self, id: &str, item_id: &str) -> Result<u32> {
+        self.inner.delivery_count(id, item_id).await
+    }
+    async fn list_items_page(
+        &self,
+        id: &str,
+        after: Option<&str>,
+        limit: usize,
+    ) -> Result<Page<ItemSummary>> {
+        self.inner.list_items_page(id, after, limit).await
+    }
+    async fn item_size(&self, id: &str, item_id: &str) -> Result<u64> {
+        self.inner.item_size(id, item_id).await
+    }
+    async fn stats(&self, id: &str) -> Result<MailboxStats> {
+        self.inner.stats(id).await
+    }
+    async fn receive_for(&self, id: &str, group: &str) -> Result<Option<(String, ITEM)>> {
+        // Note: only the default group is cached
+        if group == DEFAULT_GROUP {
+            return self.receive(id).await;
+        }
+        self.inner.receive_for(id, group).await
+    }
+    async fn acknowledge_for(&self, id: &str, group: &str, item_id: &str) -> Result<()> {
+        if group == DEFAULT_GROUP {
+            return self.acknowledge(id, item_id).await;
+        }
+        self.inner.acknowledge_for(id, group, item_id).await
+    }
+    async fn compact(&self, id: &str) -> Result<u64> {
+        self.inner.compact(id).await
+    }
+    async fn drop_older_than(&self, id: &str, max_age: Duration) -> Result<u64> {
+        self.invalidate(id);
+        self.inner.drop_older_than(id, max_age).await
+    }
+    async fn expire_items(&self, id: &str) -> Result<ExpireReport> {
+        self.invalidate(id);
+        self.inner.expire_items(id).await
+    }
+    async fn health_check(&self) -> Result<HealthReport> {
+        self.inner.health_check().await
+    }
+    async fn drain(&self, id: &str, max: Option<usize>) -> Result<Vec<(String, ITEM)>>
+    where
+        ITEM: 'static,
+    {
+        self.invalidate(id);
+        let r = self.inner.drain(id, max).await;
+        self.invalidate(id);
+        r
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::CacheConfig;
+    use crate::CachedMailbox;
+    use crate::Mailbox;
+    use crate::MailboxItem;
+    use crate::MockMailbox;
+    use crate::MockOperation;
+    use color_eyre::Result;
+    use std::time::Duration;
+
+    use test_log::test;
+
+    #[derive(Default, Debug)]
+    struct TestItem {
+        data: String,
+    }
+
+    impl MailboxItem for TestItem {
+        fn serialize(&self) -> Result<Vec<u8>> {
+            Ok(self.data.clone().into())
+        }
+        fn deserialize(data: &[u8]) -> Result<Self>
+        where
+            Self: Sized,
+        {
+            Ok(Self {
+                data: String::from_utf8(data.to_vec())?,
+            })
+        }
+    }
+
+    fn item(data: &str) -> TestItem {
+        TestItem {
+            data: data.to_string(),
+        }
+    }
+
+    #[test(tokio::test(start_paused = true))]
+    async fn it_serves_receive_from_prefetched_items() -> Result<()> {
+        let mailbox = CachedMailbox::new(
+            MockMailbox::<TestItem>::default(),
+            CacheConfig {
+                prefetch: 16,
+                ttl: Duration::from_secs(60),
+            },
+        );
+        for i in 0..10 {
+            mailbox.send("42", item(&format!("{i}"))).await?;
+        }
+
+        let mut received = Vec::new();
+        while let Some((id, item)) = mailbox.receive("42").await? {
+            // receiving again without acknowledging gets the same item
+            let (again_id, _item) = mailbox.peek("42").await?.expect("Item pending");
+            assert_eq!(id, again_id);
+
+            mailbox.acknowledge("42", &id).await?;
+            received.push(item.data);
+        }
+        let expected: Vec<String> = (0..10).map(|i| format!("{i}")).collect();
+        assert_eq!(received, expected);
+
+        let inner = mailbox.inner();
+        assert_eq!(inner.calls(MockOperation::ReceiveMany), 1);
+        assert_eq!(inner.calls(MockOperation::Receive), 0);
+        assert_eq!(inner.calls(MockOperation::Peek), 0);
+        assert_eq!(inner.calls(MockOperation::Acknowledge), 10);
+
+        // a send through the cache is seen right away
+        mailbox.send("42", item("late")).await?;
+        let (_id, late) = mailbox.receive("42").await?.expect("Item pending");
+        assert_eq!(late.data, "late");
+        assert_eq!(mailbox.inner().calls(MockOperation::ReceiveMany), 2);
+
+        Ok(())
+    }
+
+    #[test(tokio::test(start_paused = true))]
+    async fn it_refreshes_after_ttl_and_invalidate() -> Result<()> {
+        let mailbox = CachedMailbox::new(
+            MockMailbox::<TestItem>::default(),
+            CacheConfig {
+                prefetch: 4,
+                ttl: Duration::from_secs(1),
+            },
+        );
+        assert!(mailbox.receive("42").await?.is_none());
+
+        // someone else sends directly to the inner mailbox
+        mailbox.inner().send("42", item("direct")).await?;
+        assert!(mailbox.receive("42").await?.is_none());
+        assert_eq!(mailbox.inner().calls(MockOperation::ReceiveMany), 1);
+
+        tokio::time::advance(Duration::from_secs(2)).await;
+        let (id, direct) = mailbox.receive("42").await?.expect("Item pending");
+        assert_eq!(direct.data, "direct");
+        assert_eq!(mailbox.inner().calls(MockOperation::ReceiveMany), 2);
+
+        // someone else acknowledges it
+        mailbox.inner().acknowledge("42", &id).await?;
+        assert!(mailbox.receive("42").await?.is_some());
+        mailbox.invalidate("42");
+        assert!(mailbox.receive("42").await?.is_none());
+        assert_eq!(mailbox.inner().calls(MockOperation::ReceiveMany), 3);
+
+        Ok(())
+    }
+
+    #[test(tokio::test(start_paused = true))]
+    async fn it_peeks_without_prefetching() -> Result<()> {
+        let mailbox =
+            CachedMailbox::new(MockMailbox::<TestItem>::default(), CacheConfig::default());
+        let item_id = mailbox.send("42", item("one")).await?;
+
+        let (peeked_id, peeked) = mailbox.peek("42").await?.expect("Item pending");
+        assert_eq!(peeked_id, item_id);
+        assert_eq!(peeked.data, "one");
+        assert_eq!(mailbox.inner().calls(MockOperation::Peek), 1);
+        assert_eq!(mailbox.inner().calls(MockOperation::ReceiveMany), 0);
+        assert_eq!(mailbox.delivery_count("42", &item_id).await?, 0);
+
+        let (received_id, _item) = mailbox.receive("42").await?.expect("Item pending");
+        assert_eq!(received_id, item_id);
+        assert_eq!(mailbox.delivery_count("42", &item_id).await?, 1);
+
+        Ok(())
+    }
+}
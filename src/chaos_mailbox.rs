@@ -0,0 +1,405 @@
+use crate::ExpireReport;
+use crate::HeaderSelector;
+use crate::ItemSummary;
+use crate::Mailbox;
+use crate::MailboxError;
+use crate::MailboxItem;
+use crate::MailboxStats;
+use crate::Page;
+use crate::ReceiveAnyTurn;
+use crate::ReceiveStatus;
+use async_trait::async_trait;
+use color_eyre::eyre::Result;
+use core::marker::PhantomData;
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// How long [ChaosMailbox] delays each operation
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum LatencyDistribution {
+    #[default]
+    None,
+    Fixed(Duration),
+    /// Evenly spread between `min` and `max`
+    Uniform {
+        min: Duration,
+        max: Duration,
+    },
+    /// Mostly `fast`, but `slow` for a `slow_fraction` (0.0 - 1.0) of the operations, like a disk that stalls now and then
+    Bimodal {
+        fast: Duration,
+        slow: Duration,
+        slow_fraction: f64,
+    },
+}
+
+impl LatencyDistribution {
+    /// `roll` is uniform in 0.0 - 1.0
+    fn sample(&self, roll: f64) -> Duration {
+        match *self {
+            LatencyDistribution::None => Duration::ZERO,
+            LatencyDistribution::Fixed(latency) => latency,
+            LatencyDistribution::Uniform { min, max } => {
+                min + max.saturating_sub(min).mul_f64(roll)
+            }
+            LatencyDistribution::Bimodal {
+                fast,
+                slow,
+                slow_fraction,
+            } => {
+                if roll < slow_fraction {
+                    slow
+                } else {
+                    fast
+                }
+            }
+        }
+    }
+}
+
+/// What a [ChaosMailbox] injects
+///
+/// The same `seed` injects the same faults into the same sequence of operations.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct ChaosProfile {
+    pub latency_distribution: LatencyDistribution,
+    /// Chance (0.0 - 1.0) of every operation to fail
+    pub error_rate_per_op: f64,
+    /// Fraction (0.0 - 1.0) of each delay that is randomized, so concurrent operations finish out of order
+    pub jitter: f64,
+    pub seed: u64,
+}
+
+/// What a [ChaosMailbox] injected so far
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ChaosStats {
+    /// Operations seen, including the failed ones
+    pub operations: u64,
+    pub delayed: u64,
+    pub total_delay: Duration,
+    pub errors: u64,
+    /// Injected errors by operation, e.g. `"acknowledge"`
+    pub errors_by_op: BTreeMap<String, u64>,
+}
+
+#[derive(Debug)]
+struct ChaosState {
+    rng: fastrand::Rng,
+    stats: ChaosStats,
+}
+
+/// Wraps any mailbox, and delays or fails its operations, to load test consumers against unreliable storage
+///
+/// Errors are [MailboxError::Io] with [std::io::ErrorKind::Interrupted], so they are retryable,
+/// and returned before the inner mailbox is called, so nothing was changed.
+/// Note: Faults are only reproducible if the operations come in the same order,
+/// e.g. on a current thread runtime with paused time, like `#[tokio::test(start_paused = true)]`.
+#[derive(Debug)]
+pub struct ChaosMailbox<ITEM: MailboxItem, M: Mailbox<ITEM>> {
+    inner: M,
+    profile: ChaosProfile,
+    state: Mutex<ChaosState>,
+    receive_any_turn: ReceiveAnyTurn,
+    item_type: PhantomData<ITEM>,
+}
+
+impl<ITEM: MailboxItem, M: Mailbox<ITEM>> ChaosMailbox<ITEM, M> {
+    pub fn new(inner: M, profile: ChaosProfile) -> Self {
+        let state = ChaosState {
+            rng: fastrand::Rng::with_seed(profile.seed),
+            stats: ChaosStats::default(),
+        };
+        Self {
+            inner,
+            profile,
+            state: Mutex::new(state),
+            receive_any_turn: Default::default(),
+            item_type: PhantomData,
+        }
+    }
+
+    /// The mailbox without chaos, e.g. to check on it from a test
+    pub fn inner(&self) -> &M {
+        &self.inner
+    }
+
+    pub fn injected(&self) -> ChaosStats {
+        self.state.lock().unwrap().stats.clone()
+    }
+
+    /// Delays, and maybe fails, one operation
+    async fn disturb(&self, op: &str, mailbox_id: &str) -> Result<()> {
+        let (delay, fail) = {
+            let mut state = self.state.lock().unwrap();
+            // Note: always three rolls, so the profile doesn't change which operations fail
+            let latency = state.rng.f64();
+            let jitter = state.rng.f64();
+            let error = state.rng.f64();
+            let delay = self.profile.latency_distribution.sample(latency);
+            let delay = delay.mul_f64(1.0 - self.profile.jitter.clamp(0.0, 1.0) * jitter);
+            let fail = error < self.profile.error_rate_per_op;
+
+            let stats = &mut state.stats;
+            stats.operations += 1;
+            if !delay.is_zero() {
+                stats.delayed += 1;
+                stats.total_delay += delay;
+            }
+            if fail {
+                stats.errors += 1;
+                *stats.errors_by_op.entry(op.to_string()).or_default() += 1;
+            }
+            (delay, fail)
+        };
+
+        if !delay.is_zero() {
+            tokio::time::sleep(delay).await;
+        }
+        if fail {
+            tracing::debug!("Injecting a failure of {op} on {mailbox_id}");
+            let e = std::io::Error::new(
+                std::io::ErrorKind::Interrupted,
+                format!("{op} on {mailbox_id} failed by ChaosMailbox"),
+            );
+            return Err(MailboxError::Io(e).into());
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl<ITEM: MailboxItem + Send, M: Mailbox<ITEM>> Mailbox<ITEM> for ChaosMailbox<ITEM, M> {
+    async fn ensure_storage_exists(&mut self) -> Result<()> {
+        self.inner.ensure_storage_exists().await
+    }
+    async fn close(&mut self) -> Result<()> {
+        self.inner.close().await
+    }
+
+    async fn create_mailbox(&self, id: &str) -> Result<()> {
+        self.disturb("create_mailbox", id).await?;
+        self.inner.create_mailbox(id).await
+    }
+    async fn send(&self, id: &str, item: ITEM) -> Result<String> {
+        self.disturb("send", id).await?;
+        self.inner.send(id, item).await
+    }
+    async fn send_transaction(&self, id: &str, items: Vec<ITEM>) -> Result<Vec<String>> {
+        self.disturb("send_transaction", id).await?;
+        self.inner.send_transaction(id, items).await
+    }
+    async fn receive(&self, id: &str) -> Result<Option<(String, ITEM)>> {
+        self.disturb("receive", id).await?;
+        self.inner.receive(id).await
+    }
+    async fn receive_status(&self, id: &str) -> Result<ReceiveStatus<ITEM>> {
+        self.disturb("receive_status", id).await?;
+        self.inner.receive_status(id).await
+    }
+    fn receive_any_turn(&self) -> Option<&ReceiveAnyTurn> {
+        Some(&self.receive_any_turn)
+    }
+    async fn acknowledge(&self, id: &str, item_id: &str) -> Result<()> {
+        self.disturb("acknowledge", id).await?;
+        self.inner.acknowledge(id, item_id).await
+    }
+    async fn acknowledge_through(&self, id: &str, item_id: &str) -> Result<u64> {
+        self.disturb("acknowledge_through", id).await?;
+        self.inner.acknowledge_through(id, item_id).await
+    }
+    async fn release(&self, id: &str, item_id: &str) -> Result<()> {
+        self.disturb("release", id).await?;
+        self.inner.release(id, item_id).await
+    }
+    fn release_later(&self, id: &str, item_id: &str) {
+        self.inner.release_later(id, item_id)
+    }
+    async fn update(&self, id: &str, item_id: &str, item: ITEM) -> Result<()> {
+        self.disturb("update", id).await?;
+        self.inner.update(id, item_id, item).await
+    }
+    async fn peek(&self, id: &str) -> Result<Option<(String, ITEM)>> {
+        self.disturb("peek", id).await?;
+        self.inner.peek(id).await
+    }
+    async fn receive_many(&self, id: &str, max: usize) -> Result<Vec<(String, ITEM)>> {
+        self.disturb("receive_many", id).await?;
+        self.inner.receive_many(id, max).await
+    }
+    async fn receive_batch(
+        &self,
+        id: &str,
+        max_items: usize,
+        max_wait: Duration,
+    ) -> Result<Vec<(String, ITEM)>> {
+        self.disturb("receive_batch", id).await?;
+        self.inner.receive_batch(id, max_items, max_wait).await
+    }
+    async fn receive_matching(
+        &self,
+        id: &str,
+        selector: &HeaderSelector,
+    ) -> Result<Option<(String, ITEM)>> {
+        self.disturb("receive_matching", id).await?;
+        self.inner.receive_matching(id, selector).await
+    }
+    async fn list_mailboxes(&self) -> Result<Vec<String>> {
+        self.disturb("list_mailboxes", "*").await?;
+        self.inner.list_mailboxes().await
+    }
+    async fn delivery_count(&self, id: &str, item_id: &str) -> Result<u32> {
+        self.disturb("delivery_count", id).await?;
+        self.inner.delivery_count(id, item_id).await
+    }
+    async fn list_items_page(
+        &self,
+        id: &str,
+        after: Option<&str>,
+        limit: usize,
+    ) -> Result<Page<ItemSummary>> {
+        self.disturb("list_items_page", id).await?;
+        self.inner.list_items_page(id, after, limit).await
+    }
+    async fn item_size(&self, id: &str, item_id: &str) -> Result<u64> {
+        self.disturb("item_size", id).await?;
+        self.inner.item_size(id, item_id).await
+    }
+    async fn stats(&self, id: &str) -> Result<MailboxStats> {
+        self.disturb("stats", id).await?;
+        self.inner.stats(id).await
+    }
+    async fn receive_for(&self, id: &str, group: &str) -> Result<Option<(String, ITEM)>> {
+        self.disturb("receive_for", id).await?;
+        self.inner.receive_for(id, group).await
+    }
+    async fn acknowledge_for(&self, id: &str, group: &str, item_id: &str) -> Result<()> {
+        self.disturb("acknowledge_for", id).await?;
+        self.inner.acknowledge_for(id, group, item_id).await
+    }
+    async fn compact(&self, id: &str) -> Result<u64> {
+        self.disturb("compact", id).await?;
+        self.inner.compact(id).await
+    }
+    async fn drop_older_than(&self, id: &str, max_age: Duration) -> Result<u64> {
+        self.disturb("drop_older_than", id).await?;
+        self.inner.drop_older_than(id, max_age).await
+    }
+    async fn expire_items(&self, id: &str) -> Result<ExpireReport> {
+        self.disturb("expire_items", id).await?;
+        self.inner.expire_items(id).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::ChaosMailbox;
+    use crate::ChaosProfile;
+    use crate::ChaosStats;
+    use crate::LatencyDistribution;
+    use crate::Mailbox;
+    use crate::MailboxItem;
+    use crate::MailboxMemory;
+    use crate::MailboxWorker;
+    use crate::PollPolicy;
+    use crate::RetryPolicy;
+    use crate::WorkerOptions;
+    use crate::WorkerSummary;
+    use color_eyre::Result;
+    use std::collections::BTreeMap;
+    use std::sync::Arc;
+    use std::sync::Mutex;
+    use std::time::Duration;
+
+    use test_log::test;
+
+    #[derive(Debug, Default)]
+    struct Job {
+        data: String,
+    }
+
+    impl MailboxItem for Job {
+        fn serialize(&self) -> Result<Vec<u8>> {
+            Ok(self.data.clone().into())
+        }
+        fn deserialize(data: &[u8]) -> Result<Self>
+        where
+            Self: Sized,
+        {
+            Ok(Self {
+                data: String::from_utf8(data.to_vec())?,
+            })
+        }
+    }
+
+    /// Runs a worker until all 50 jobs are acknowledged, returns what was injected, and how often each job was handled
+    async fn run_worker(seed: u64) -> Result<(ChaosStats, WorkerSummary, BTreeMap<String, u32>)> {
+        let ms = Duration::from_millis;
+        let profile = ChaosProfile {
+            latency_distribution: LatencyDistribution::Bimodal {
+                fast: ms(1),
+                slow: ms(50),
+                slow_fraction: 0.1,
+            },
+            error_rate_per_op: 0.2,
+            jitter: 0.5,
+            seed,
+        };
+        let mailbox = Arc::new(ChaosMailbox::new(MailboxMemory::<Job>::new(), profile));
+        for n in 0..50 {
+            let data = format!("{n}");
+            mailbox.inner().send("jobs", Job { data }).await?;
+        }
+
+        let handled = Arc::new(Mutex::new(BTreeMap::new()));
+        let seen = handled.clone();
+        let opts = WorkerOptions {
+            concurrency: 4,
+            poll: PollPolicy::Fixed(ms(10)),
+            retry: RetryPolicy {
+                base_delay: ms(10),
+                jitter: 0.0,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let worker = MailboxWorker::spawn(
+            mailbox.clone(),
+            "jobs".to_string(),
+            move |_item_id, job: Job| {
+                *seen.lock().unwrap().entry(job.data).or_default() += 1;
+                async { Ok(()) }
+            },
+            opts,
+        );
+        while mailbox.inner().stats("jobs").await?.pending > 0 {
+            tokio::time::sleep(ms(10)).await;
+        }
+        let summary = worker.shutdown().await?;
+        let handled = handled.lock().unwrap().clone();
+
+        Ok((mailbox.injected(), summary, handled))
+    }
+
+    #[test(tokio::test(start_paused = true))]
+    async fn it_injects_reproducible_faults_without_losing_items() -> Result<()> {
+        let (injected, summary, handled) = run_worker(42).await?;
+
+        assert_eq!(injected.errors, 16);
+        assert_eq!(injected.errors_by_op["acknowledge"], 12);
+        assert_eq!(injected.errors_by_op["receive_many"], 3);
+        assert_eq!(injected.errors_by_op["receive_status"], 1);
+        assert_eq!(summary.unacknowledged, 12);
+        // every job is handled, and only handled again if acknowledging it failed
+        assert_eq!(handled.len(), 50);
+        assert_eq!(summary.handled, 50);
+        let calls: u32 = handled.values().sum();
+        assert_eq!(u64::from(calls), 50 + summary.unacknowledged);
+
+        // the same seed, the same faults
+        assert_eq!(run_worker(42).await?.0, injected);
+        assert_ne!(run_worker(7).await?.0, injected);
+
+        Ok(())
+    }
+}
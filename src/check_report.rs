@@ -0,0 +1,45 @@
+use std::path::PathBuf;
+
+/// A problem found by [crate::MailboxDisk::check]
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum CheckIssue {
+    /// The meta or its journal can't be loaded, the repair rebuilds it from the envelopes
+    BrokenMeta { reason: String },
+    /// The lowest unread id is beyond the next id to be used, the repair raises the highest used id
+    CursorAhead {
+        lowest_unread_id: u64,
+        highest_used_id: u64,
+    },
+    /// An unread item without an envelope, the repair drops it
+    MissingEnvelope { item_id: String },
+    /// An envelope above the highest used id, the repair renames it to `*.orphaned`
+    OrphanedEnvelope { item_id: String },
+    /// An envelope that can't be loaded, or whose signature doesn't match, not repairable
+    BrokenEnvelope { item_id: String, reason: String },
+    /// An unread item whose envelope is marked read, the repair clears the flag
+    ReadFlagMismatch { item_id: String },
+    /// A sidecar that doesn't match the checksum in its envelope, not repairable
+    PayloadMismatch { item_id: String, reason: String },
+    /// A temporary file left over by an interrupted write, the repair removes it
+    StaleTempFile { path: PathBuf },
+}
+
+/// What [crate::MailboxDisk::check] found, and repaired
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CheckReport {
+    pub issues: Vec<CheckIssue>,
+    /// The issues that were repaired, in the order they were found
+    pub repaired: Vec<CheckIssue>,
+}
+
+impl CheckReport {
+    pub fn is_healthy(&self) -> bool {
+        self.issues.is_empty()
+    }
+
+    /// The issues that are still there
+    pub fn unrepaired(&self) -> impl Iterator<Item = &CheckIssue> {
+        self.issues.iter().filter(|i| !self.repaired.contains(i))
+    }
+}
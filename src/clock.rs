@@ -0,0 +1,103 @@
+use chrono::DateTime;
+use chrono::Utc;
+use std::sync::Arc;
+#[cfg(any(test, feature = "test-util"))]
+use std::sync::Mutex;
+
+/// The source of the current time for timestamps, and time based decisions like TTLs, delays, and retention
+///
+/// Any `Fn() -> DateTime<Utc>` is a clock too, e.g. a plain `fn` reading a static in a test.
+pub trait Clock: Send + Sync {
+    fn now(&self) -> DateTime<Utc>;
+}
+
+impl<F: Fn() -> DateTime<Utc> + Send + Sync> Clock for F {
+    fn now(&self) -> DateTime<Utc> {
+        self()
+    }
+}
+
+/// The wall clock, [Utc::now]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> DateTime<Utc> {
+        Utc::now()
+    }
+}
+
+/// A clock that only moves when told to, clones share the time
+///
+/// ```
+/// # use oml_mailbox::{Clock, MockClock};
+/// let clock = MockClock::default();
+/// let start = clock.now();
+/// clock.advance(std::time::Duration::from_secs(60));
+/// assert_eq!((clock.now() - start).num_seconds(), 60);
+/// ```
+#[cfg(any(test, feature = "test-util"))]
+#[derive(Debug, Clone)]
+pub struct MockClock {
+    now: Arc<Mutex<DateTime<Utc>>>,
+}
+
+#[cfg(any(test, feature = "test-util"))]
+impl MockClock {
+    pub fn new(now: DateTime<Utc>) -> Self {
+        Self {
+            now: Arc::new(Mutex::new(now)),
+        }
+    }
+
+    pub fn set(&self, now: DateTime<Utc>) {
+        *self.now.lock().unwrap() = now;
+    }
+
+    /// Note: Panics if the time leaves the range of [DateTime], like adding to it does.
+    pub fn advance(&self, by: std::time::Duration) {
+        let by = chrono::Duration::from_std(by).expect("Duration out of range");
+        *self.now.lock().unwrap() += by;
+    }
+}
+
+/// Starts at the current time
+#[cfg(any(test, feature = "test-util"))]
+impl Default for MockClock {
+    fn default() -> Self {
+        Self::new(Utc::now())
+    }
+}
+
+#[cfg(any(test, feature = "test-util"))]
+impl Clock for MockClock {
+    fn now(&self) -> DateTime<Utc> {
+        *self.now.lock().unwrap()
+    }
+}
+
+/// The clock of a backend, the [SystemClock] unless replaced
+#[derive(Clone)]
+pub(crate) struct SharedClock(Arc<dyn Clock>);
+
+impl SharedClock {
+    pub(crate) fn new(clock: impl Clock + 'static) -> Self {
+        Self(Arc::new(clock))
+    }
+
+    pub(crate) fn now(&self) -> DateTime<Utc> {
+        self.0.now()
+    }
+}
+
+impl Default for SharedClock {
+    fn default() -> Self {
+        Self::new(SystemClock)
+    }
+}
+
+impl std::fmt::Debug for SharedClock {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("SharedClock")
+    }
+}
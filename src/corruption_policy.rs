@@ -0,0 +1,16 @@
+use serde::Deserialize;
+use serde::Serialize;
+
+/// What `receive` does with an item that can't be loaded, decoded, or deserialized, see [crate::MailboxDisk::with_corruption_policy]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum CorruptionPolicy {
+    /// Return the error, so the mailbox is stuck until the item is fixed or removed
+    #[default]
+    Fail,
+    /// Mark the item read without acknowledging it, log an error, and continue with the next item
+    Skip,
+    /// Like [CorruptionPolicy::Skip], but move the files of the item to the `.quarantine` folder of the mailbox first
+    ///
+    /// See [crate::MailboxDisk::quarantined].
+    Quarantine,
+}
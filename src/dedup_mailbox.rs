@@ -0,0 +1,357 @@
+use crate::ExpireReport;
+use crate::HeaderSelector;
+use crate::HealthReport;
+use crate::ItemSummary;
+use crate::Mailbox;
+use crate::MailboxItem;
+use crate::MailboxStats;
+use crate::Page;
+use crate::ReceiveAnyTurn;
+use async_trait::async_trait;
+use color_eyre::eyre::Result;
+use core::marker::PhantomData;
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// How [DedupReceiveMailbox] recognizes duplicates
+#[derive(Debug, Clone)]
+pub struct DedupConfig {
+    /// Number of recently delivered keys remembered, the least recently delivered is forgotten first
+    pub capacity: usize,
+    /// Items with this header are recognized by its value, all others by their item id
+    pub key_header: Option<String>,
+}
+
+impl Default for DedupConfig {
+    fn default() -> Self {
+        Self {
+            capacity: 1024,
+            key_header: Some("dedup_key".to_string()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum DedupKey {
+    ItemId { mailbox_id: String, item_id: String },
+    Header { mailbox_id: String, value: String },
+}
+
+/// A bounded LRU of keys
+#[derive(Debug, Default)]
+struct RecentKeys {
+    /// The position of every key in `order`
+    keys: HashMap<DedupKey, u64>,
+    order: BTreeMap<u64, DedupKey>,
+    next: u64,
+}
+
+impl RecentKeys {
+    /// Makes the key the most recent one, returns `true` if it was already known
+    fn touch(&mut self, key: DedupKey, capacity: usize) -> bool {
+        let known = match self.keys.get(&key) {
+            Some(position) => self.order.remove(position).is_some(),
+            None => false,
+        };
+        self.keys.insert(key.clone(), self.next);
+        self.order.insert(self.next, key);
+        self.next += 1;
+        while self.keys.len() > capacity.max(1) {
+            let Some((_, oldest)) = self.order.pop_first() else {
+                break;
+            };
+            self.keys.remove(&oldest);
+        }
+        known
+    }
+}
+
+/// Wraps a mailbox, and only delivers every item once, even if the inner mailbox delivers it again
+///
+/// Duplicates are acknowledged on the inner mailbox, and skipped.
+/// Everything except `receive` and `receive_many` is passed through as is.
+///
+/// Note: An item counts as delivered as soon as it is returned,
+/// so an item that is received again without being acknowledged in between is suppressed too.
+/// Only the last `capacity` keys are remembered, older duplicates are delivered again.
+#[derive(Debug)]
+pub struct DedupReceiveMailbox<ITEM: MailboxItem, M: Mailbox<ITEM>> {
+    inner: M,
+    config: DedupConfig,
+    recent: Mutex<RecentKeys>,
+    suppressed: AtomicU64,
+    receive_any_turn: ReceiveAnyTurn,
+    item_type: PhantomData<ITEM>,
+}
+
+impl<ITEM: MailboxItem, M: Mailbox<ITEM>> DedupReceiveMailbox<ITEM, M> {
+    pub fn new(inner: M, config: DedupConfig) -> Self {
+        Self {
+            inner,
+            config,
+            recent: Default::default(),
+            suppressed: AtomicU64::new(0),
+            receive_any_turn: Default::default(),
+            item_type: PhantomData,
+        }
+    }
+
+    pub fn inner(&self) -> &M {
+        &self.inner
+    }
+
+    /// Number of duplicates acknowledged without delivering them
+    pub fn suppressed_duplicates(&self) -> u64 {
+        self.suppressed.load(Ordering::Relaxed)
+    }
+
+    fn key(&self, mailbox_id: &str, item_id: &str, item: &ITEM) -> DedupKey {
+        let value = self
+            .config
+            .key_header
+            .as_ref()
+            .and_then(|header| item.headers().remove(header));
+        match value {
+            Some(value) => DedupKey::Header {
+                mailbox_id: mailbox_id.to_string(),
+                value,
+            },
+            None => DedupKey::ItemId {
+                mailbox_id: mailbox_id.to_string(),
+                item_id: item_id.to_string(),
+            },
+        }
+    }
+
+    /// Remembers the item as delivered, returns `false` if it already was
+    fn first_delivery(&self, mailbox_id: &str, item_id: &str, item: &ITEM) -> bool {
+        let key = self.key(mailbox_id, item_id, item);
+        let known = self.recent.lock().unwrap().touch(key, self.config.capacity);
+        !known
+    }
+
+    async fn suppress(&self, mailbox_id: &str, item_id: &str) -> Result<()> {
+        tracing::debug!("Suppressing duplicate {item_id} in mailbox {mailbox_id}");
+        self.inner.acknowledge(mailbox_id, item_id).await?;
+        self.suppressed.fetch_add(1, Ordering::Relaxed);
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl<ITEM: MailboxItem + Send, M: Mailbox<ITEM>> Mailbox<ITEM> for DedupReceiveMailbox<ITEM, M> {
+    async fn ensure_storage_exists(&mut self) -> Result<()> {
+        self.inner.ensure_storage_exists().await
+    }
+    async fn close(&mut self) -> Result<()> {
+        self.inner.close().await
+    }
+
+    async fn create_mailbox(&self, id: &str) -> Result<()> {
+        self.inner.create_mailbox(id).await
+    }
+    async fn send(&self, id: &str, item: ITEM) -> Result<String> {
+        self.inner.send(id, item).await
+    }
+    async fn send_transaction(&self, id: &str, items: Vec<ITEM>) -> Result<Vec<String>> {
+        self.inner.send_transaction(id, items).await
+    }
+    async fn receive(&self, id: &str) -> Result<Option<(String, ITEM)>> {
+        while let Some((item_id, item)) = self.inner.receive(id).await? {
+            if self.first_delivery(id, &item_id, &item) {
+                return Ok(Some((item_id, item)));
+            }
+            self.suppress(id, &item_id).await?;
+        }
+        Ok(None)
+    }
+    fn receive_any_turn(&self) -> Option<&ReceiveAnyTurn> {
+        Some(&self.receive_any_turn)
+    }
+    async fn acknowledge(&self, id: &str, item_id: &str) -> Result<()> {
+        self.inner.acknowledge(id, item_id).await
+    }
+    async fn acknowledge_through(&self, id: &str, item_id: &str) -> Result<u64> {
+        self.inner.acknowledge_through(id, item_id).await
+    }
+    async fn release(&self, id: &str, item_id: &str) -> Result<()> {
+        self.inner.release(id, item_id).await
+    }
+    fn release_later(&self, id: &str, item_id: &str) {
+        self.inner.release_later(id, item_id)
+    }
+    async fn update(&self, id: &str, item_id: &str, item: ITEM) -> Result<()> {
+        self.inner.update(id, item_id, item).await
+    }
+    async fn peek(&self, id: &str) -> Result<Option<(String, ITEM)>> {
+        self.inner.peek(id).await
+    }
+    /// Note: Receives from the inner mailbox only once, so with duplicates there can be fewer than `max` items,
+    /// even though more are pending.
+    async fn receive_many(&self, id: &str, max: usize) -> Result<Vec<(String, ITEM)>> {
+        let mut items = Vec::new();
+        for (item_id, item) in self.inner.receive_many(id, max).await? {
+            if self.first_delivery(id, &item_id, &item) {
+                items.push((item_id, item));
+            } else {
+                self.suppress(id, &item_id).await?;
+            }
+        }
+        Ok(items)
+    }
+    async fn receive_matching(
+        &self,
+        id: &str,
+        selector: &HeaderSelector,
+    ) -> Result<Option<(String, ITEM)>> {
+        self.inner.receive_matching(id, selector).await
+    }
+    async fn list_mailboxes(&self) -> Result<Vec<String>> {
+        self.inner.list_mailboxes().await
+    }
+    async fn delivery_count(&self, id: &str, item_id: &str) -> Result<u32> {
+        self.inner.delivery_count(id, item_id).await
+    }
+    async fn list_items_page(
+        &self,
+        id: &str,
+        after: Option<&str>,
+        limit: usize,
+    ) -> Result<Page<ItemSummary>> {
+        self.inner.list_items_page(id, after, limit).await
+    }
+    async fn item_size(&self, id: &str, item_id: &str) -> Result<u64> {
+        self.inner.item_size(id, item_id).await
+    }
+    async fn stats(&self, id: &str) -> Result<MailboxStats> {
+        self.inner.stats(id).await
+    }
+    async fn receive_for(&self, id: &str, group: &str) -> Result<Option<(String, ITEM)>> {
+        self.inner.receive_for(id, group).await
+    }
+    async fn acknowledge_for(&self, id: &str, group: &str, item_id: &str) -> Result<()> {
+        self.inner.acknowledge_for(id, group, item_id).await
+    }
+    async fn compact(&self, id: &str) -> Result<u64> {
+        self.inner.compact(id).await
+    }
+    async fn drop_older_than(&self, id: &str, max_age: Duration) -> Result<u64> {
+        self.inner.drop_older_than(id, max_age).await
+    }
+    async fn expire_items(&self, id: &str) -> Result<ExpireReport> {
+        self.inner.expire_items(id).await
+    }
+    async fn health_check(&self) -> Result<HealthReport> {
+        self.inner.health_check().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::DedupConfig;
+    use crate::DedupReceiveMailbox;
+    use crate::Mailbox;
+    use crate::MailboxItem;
+    use crate::MockMailbox;
+    use crate::MockOperation;
+    use color_eyre::Result;
+    use std::collections::BTreeMap;
+
+    use test_log::test;
+
+    /// `key/payload` has the header `dedup_key: key`
+    #[derive(Default, Debug)]
+    struct TestItem {
+        data: String,
+    }
+
+    impl MailboxItem for TestItem {
+        fn serialize(&self) -> Result<Vec<u8>> {
+            Ok(self.data.clone().into())
+        }
+        fn deserialize(data: &[u8]) -> Result<Self>
+        where
+            Self: Sized,
+        {
+            Ok(Self {
+                data: String::from_utf8(data.to_vec())?,
+            })
+        }
+        fn headers(&self) -> BTreeMap<String, String> {
+            self.data
+                .split_once('/')
+                .map(|(key, _)| ("dedup_key".to_string(), key.to_string()))
+                .into_iter()
+                .collect()
+        }
+    }
+
+    fn item(data: &str) -> TestItem {
+        TestItem {
+            data: data.to_string(),
+        }
+    }
+
+    #[test(tokio::test)]
+    async fn it_delivers_redelivered_items_once() -> Result<()> {
+        let mailbox =
+            DedupReceiveMailbox::new(MockMailbox::<TestItem>::default(), DedupConfig::default());
+        mailbox.send("42", item("first")).await?;
+        mailbox.send("42", item("k/original")).await?;
+        mailbox.send("42", item("k/retried")).await?;
+        mailbox.send("42", item("last")).await?;
+
+        // the consumer crashes before acknowledging, so the inner mailbox delivers it again
+        let (_id, first) = mailbox.receive("42").await?.expect("Item pending");
+        assert_eq!(first.data, "first");
+        let (id, original) = mailbox.receive("42").await?.expect("Item pending");
+        assert_eq!(original.data, "k/original");
+        assert_eq!(mailbox.suppressed_duplicates(), 1);
+        mailbox.acknowledge("42", &id).await?;
+
+        let received: Vec<String> = mailbox
+            .receive_many("42", 10)
+            .await?
+            .into_iter()
+            .map(|(_id, item)| item.data)
+            .collect();
+        assert_eq!(received, ["last"]);
+        assert_eq!(mailbox.suppressed_duplicates(), 2);
+
+        let inner = mailbox.inner();
+        assert_eq!(inner.calls(MockOperation::Acknowledge), 3);
+        assert_eq!(inner.stats("42").await?.pending, 1);
+
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn it_forgets_the_least_recently_delivered_keys() -> Result<()> {
+        let mailbox = DedupReceiveMailbox::new(
+            MockMailbox::<TestItem>::default(),
+            DedupConfig {
+                capacity: 2,
+                key_header: Some("dedup_key".to_string()),
+            },
+        );
+        for data in ["a/1", "b/1", "a/2", "c/1", "b/2", "a/3"] {
+            mailbox.send("42", item(data)).await?;
+        }
+
+        let received: Vec<String> = mailbox
+            .drain("42", None)
+            .await?
+            .into_iter()
+            .map(|(_id, item)| item.data)
+            .collect();
+        // `a/2` refreshes `a`, so `c/1` pushes out `b`
+        assert_eq!(received, ["a/1", "b/1", "c/1", "b/2", "a/3"]);
+        assert_eq!(mailbox.suppressed_duplicates(), 1);
+
+        Ok(())
+    }
+}
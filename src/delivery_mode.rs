@@ -0,0 +1,17 @@
+use serde::Deserialize;
+use serde::Serialize;
+
+/// When an item counts as delivered
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum DeliveryMode {
+    /// Items are delivered again until they are acknowledged
+    #[default]
+    AtLeastOnce,
+    /// `receive` marks the item as read before returning it, so it is never delivered twice
+    ///
+    /// `acknowledge` is not needed, and only warns.
+    /// An item that can't be loaded is skipped too, and the error returned, so it can't block the mailbox.
+    ///
+    /// Note: This only applies to the default consumer group.
+    AtMostOnce,
+}
@@ -0,0 +1,75 @@
+use color_eyre::eyre::Report;
+
+/// Returned (inside the [color_eyre::eyre::Report]) when [crate::Mailbox::drain] stops early
+///
+/// Carries the items that were drained, and acknowledged, before the failure,
+/// so they are not lost. The failing item is left unread.
+///
+/// ```
+/// # use color_eyre::eyre::Report;
+/// # use oml_mailbox::DrainError;
+/// # fn handle<ITEM: std::fmt::Debug + Send + Sync + 'static>(e: Report) {
+/// match e.downcast::<DrainError<ITEM>>() {
+///     Ok(drain_error) => {
+///         let (drained, failed_item_id, error) = drain_error.into_parts();
+///         // process `drained`, then report `error` for `failed_item_id`
+///     }
+///     Err(e) => { /* something else went wrong */ }
+/// }
+/// # }
+/// ```
+#[derive(Debug)]
+pub struct DrainError<ITEM> {
+    drained: Vec<(String, ITEM)>,
+    failed_item_id: Option<String>,
+    source: Report,
+}
+
+impl<ITEM> DrainError<ITEM> {
+    pub fn new(
+        drained: Vec<(String, ITEM)>,
+        failed_item_id: Option<String>,
+        source: Report,
+    ) -> Self {
+        Self {
+            drained,
+            failed_item_id,
+            source,
+        }
+    }
+
+    /// The items drained before the failure
+    pub fn drained(&self) -> &[(String, ITEM)] {
+        &self.drained
+    }
+
+    /// The id of the item the drain stopped at, if known
+    pub fn failed_item_id(&self) -> Option<&str> {
+        self.failed_item_id.as_deref()
+    }
+
+    /// The drained items, the id of the item the drain stopped at, if known, and the error
+    pub fn into_parts(self) -> (Vec<(String, ITEM)>, Option<String>, Report) {
+        (self.drained, self.failed_item_id, self.source)
+    }
+}
+
+impl<ITEM> std::fmt::Display for DrainError<ITEM> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let count = self.drained.len();
+        match &self.failed_item_id {
+            Some(item_id) => write!(
+                f,
+                "Drain stopped at {item_id} after {count} items -> {}",
+                self.source
+            ),
+            None => write!(f, "Drain stopped after {count} items -> {}", self.source),
+        }
+    }
+}
+
+impl<ITEM: std::fmt::Debug> std::error::Error for DrainError<ITEM> {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(self.source.as_ref())
+    }
+}
@@ -0,0 +1,499 @@
+use crate::ExpireReport;
+use crate::HeaderSelector;
+use crate::HealthReport;
+use crate::ItemSummary;
+use crate::Mailbox;
+use crate::MailboxError;
+use crate::MailboxItem;
+use crate::MailboxStats;
+use crate::Page;
+use crate::RawItem;
+use async_trait::async_trait;
+use chacha20poly1305::aead::Aead;
+use chacha20poly1305::aead::AeadCore;
+use chacha20poly1305::aead::KeyInit;
+use chacha20poly1305::aead::OsRng;
+use chacha20poly1305::aead::Payload;
+use chacha20poly1305::ChaCha20Poly1305;
+use chacha20poly1305::Key;
+use chacha20poly1305::Nonce;
+use color_eyre::eyre::eyre;
+use color_eyre::eyre::Result;
+use core::marker::PhantomData;
+use std::time::Duration;
+
+/// A named 256 bit key for [EncryptedMailbox]
+///
+/// The id is stored with every item, so it should be short, and must be unique.
+#[derive(Clone)]
+pub struct EncryptionKey {
+    id: String,
+    key: [u8; 32],
+}
+
+impl EncryptionKey {
+    /// # Panics
+    ///
+    /// If the id is empty, or longer than 255 bytes.
+    pub fn new(id: &str, key: [u8; 32]) -> Self {
+        assert!(
+            !id.is_empty() && id.len() <= u8::MAX as usize,
+            "Key id must be 1 to 255 bytes"
+        );
+        Self {
+            id: id.to_string(),
+            key,
+        }
+    }
+
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+}
+
+impl std::fmt::Debug for EncryptionKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("EncryptionKey")
+            .field("id", &self.id)
+            .field("key", &"<redacted>")
+            .finish()
+    }
+}
+
+const FORMAT_VERSION: u8 = 1;
+
+/// Encrypts items before handing them to any mailbox storing [RawItem]s
+///
+/// Items are serialized, and encrypted with ChaCha20-Poly1305.
+/// The stored bytes are framed as:
+/// ```text
+/// version (u8) | key id length (u8) | key id | nonce length (u8) | nonce | ciphertext
+/// ```
+/// with everything before the ciphertext, and the mailbox id, authenticated as associated data.
+/// So a frame copied into another mailbox fails to decrypt there.
+///
+/// For key rotation pass all keys that might still be in use, the last one is used for encryption.
+///
+/// Items that fail to decrypt are reported as [MailboxError::Decryption], and stay unacknowledged.
+/// Note: This includes items the inner mailbox moves itself, e.g. to the dead letter mailbox of [crate::MailboxDisk::with_defer_limit].
+#[derive(Debug)]
+pub struct EncryptedMailbox<ITEM: MailboxItem, M: Mailbox<RawItem>> {
+    inner: M,
+    keys: Vec<EncryptionKey>,
+    item_type: PhantomData<ITEM>,
+}
+
+impl<ITEM: MailboxItem, M: Mailbox<RawItem>> EncryptedMailbox<ITEM, M> {
+    /// # Panics
+    ///
+    /// If `keys` is empty, or contains duplicate ids.
+    pub fn new(inner: M, keys: Vec<EncryptionKey>) -> Self {
+        assert!(!keys.is_empty(), "EncryptedMailbox needs at least one key");
+        for (i, key) in keys.iter().enumerate() {
+            assert!(
+                !keys[..i].iter().any(|k| k.id == key.id),
+                "Duplicate key id {}",
+                key.id
+            );
+        }
+        Self {
+            inner,
+            keys,
+            item_type: PhantomData,
+        }
+    }
+
+    pub fn inner(&self) -> &M {
+        &self.inner
+    }
+
+    pub fn into_inner(self) -> M {
+        self.inner
+    }
+
+    fn encrypt(&self, mailbox_id: &str, item: &ITEM) -> Result<RawItem> {
+        let key = self.keys.last().expect("At least one key");
+        let cipher = ChaCha20Poly1305::new(Key::from_slice(&key.key));
+        let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+
+        let mut frame = vec![FORMAT_VERSION, key.id.len() as u8];
+        frame.extend_from_slice(key.id.as_bytes());
+        frame.push(nonce.len() as u8);
+        frame.extend_from_slice(&nonce);
+
+        let plaintext = item.serialize()?;
+        let ciphertext = cipher
+            .encrypt(
+                &nonce,
+                Payload {
+                    msg: &plaintext,
+                    aad: &[&frame, mailbox_id.as_bytes()].concat(),
+                },
+            )
+            .map_err(|e| eyre!("Can't encrypt item -> {e}"))?;
+        frame.extend_from_slice(&ciphertext);
+
+        Ok(RawItem::new(frame))
+    }
+
+    fn decrypt(&self, mailbox_id: &str, item_id: &str, raw: &RawItem) -> Result<ITEM> {
+        let plaintext = self
+            .decrypt_frame(mailbox_id, raw.data())
+            .map_err(|reason| MailboxError::Decryption {
+                mailbox_id: mailbox_id.to_string(),
+                item_id: item_id.to_string(),
+                reason,
+            })?;
+
+        ITEM::deserialize_from_bytes(plaintext.into())
+    }
+
+    fn decrypt_frame(
+        &self,
+        mailbox_id: &str,
+        frame: &[u8],
+    ) -> std::result::Result<Vec<u8>, String> {
+        let truncated = || String::from("truncated frame");
+
+        let (&version, rest) = frame.split_first().ok_or_else(truncated)?;
+        if version != FORMAT_VERSION {
+            return Err(format!("unsupported format version {version}"));
+        }
+        let (&key_id_len, rest) = rest.split_first().ok_or_else(truncated)?;
+        let key_id = rest.get(..key_id_len as usize).ok_or_else(truncated)?;
+        let rest = &rest[key_id_len as usize..];
+        let (&nonce_len, rest) = rest.split_first().ok_or_else(truncated)?;
+        if nonce_len != 12 {
+            return Err(format!("unexpected nonce length {nonce_len}"));
+        }
+        let nonce = rest.get(..12).ok_or_else(truncated)?;
+        let ciphertext = &rest[12..];
+        let header = &frame[..frame.len() - ciphertext.len()];
+
+        let key_id = String::from_utf8_lossy(key_id);
+        let key = self
+            .keys
+            .iter()
+            .find(|k| k.id == key_id)
+            .ok_or_else(|| format!("unknown key id {key_id:?}"))?;
+        let cipher = ChaCha20Poly1305::new(Key::from_slice(&key.key));
+        cipher
+            .decrypt(
+                Nonce::from_slice(nonce),
+                Payload {
+                    msg: ciphertext,
+                    aad: &[header, mailbox_id.as_bytes()].concat(),
+                },
+            )
+            .map_err(|_| format!("authentication with key {key_id:?} failed"))
+    }
+}
+
+#[async_trait]
+impl<ITEM: MailboxItem + Send, M: Mailbox<RawItem>> Mailbox<ITEM> for EncryptedMailbox<ITEM, M> {
+    async fn ensure_storage_exists(&mut self) -> Result<()> {
+        self.inner.ensure_storage_exists().await
+    }
+    async fn close(&mut self) -> Result<()> {
+        self.inner.close().await
+    }
+
+    async fn create_mailbox(&self, id: &str) -> Result<()> {
+        self.inner.create_mailbox(id).await
+    }
+    async fn send(&self, id: &str, item: ITEM) -> Result<String> {
+        let raw = self.encrypt(id, &item)?;
+        self.inner.send(id, raw).await
+    }
+    async fn send_transaction(&self, id: &str, items: Vec<ITEM>) -> Result<Vec<String>> {
+        let raws = items
+            .iter()
+            .map(|item| self.encrypt(id, item))
+            .collect::<Result<Vec<_>>>()?;
+        self.inner.send_transaction(id, raws).await
+    }
+    async fn receive(&self, id: &str) -> Result<Option<(String, ITEM)>> {
+        match self.inner.receive(id).await? {
+            Some((item_id, raw)) => {
+                let item = self.decrypt(id, &item_id, &raw)?;
+                Ok(Some((item_id, item)))
+            }
+            None => Ok(None),
+        }
+    }
+    async fn receive_any(&self, ids: &[String]) -> Result<Option<(String, String, ITEM)>> {
+        match self.inner.receive_any(ids).await? {
+            Some((id, item_id, raw)) => {
+                let item = self.decrypt(&id, &item_id, &raw)?;
+                Ok(Some((id, item_id, item)))
+            }
+            None => Ok(None),
+        }
+    }
+    async fn acknowledge(&self, id: &str, item_id: &str) -> Result<()> {
+        self.inner.acknowledge(id, item_id).await
+    }
+    async fn acknowledge_through(&self, id: &str, item_id: &str) -> Result<u64> {
+        self.inner.acknowledge_through(id, item_id).await
+    }
+    async fn release(&self, id: &str, item_id: &str) -> Result<()> {
+        self.inner.release(id, item_id).await
+    }
+    fn release_later(&self, id: &str, item_id: &str) {
+        self.inner.release_later(id, item_id)
+    }
+    async fn update(&self, id: &str, item_id: &str, item: ITEM) -> Result<()> {
+        let raw = self.encrypt(id, &item)?;
+        self.inner.update(id, item_id, raw).await
+    }
+    async fn peek(&self, id: &str) -> Result<Option<(String, ITEM)>> {
+        match self.inner.peek(id).await? {
+            Some((item_id, raw)) => {
+                let item = self.decrypt(id, &item_id, &raw)?;
+                Ok(Some((item_id, item)))
+            }
+            None => Ok(None),
+        }
+    }
+    async fn receive_many(&self, id: &str, max: usize) -> Result<Vec<(String, ITEM)>> {
+        let raws = self.inner.receive_many(id, max).await?;
+        raws.into_iter()
+            .map(|(item_id, raw)| {
+                let item = self.decrypt(id, &item_id, &raw)?;
+                Ok((item_id, item))
+            })
+            .collect()
+    }
+    async fn receive_batch(
+        &self,
+        id: &str,
+        max_items: usize,
+        max_wait: Duration,
+    ) -> Result<Vec<(String, ITEM)>> {
+        let raws = self.inner.receive_batch(id, max_items, max_wait).await?;
+        raws.into_iter()
+            .map(|(item_id, raw)| {
+                let item = self.decrypt(id, &item_id, &raw)?;
+                Ok((item_id, item))
+            })
+            .collect()
+    }
+    /// Note: Only an empty selector is supported, the headers are encrypted with the item.
+    async fn receive_matching(
+        &self,
+        id: &str,
+        selector: &HeaderSelector,
+    ) -> Result<Option<(String, ITEM)>> {
+        if !selector.is_empty() {
+            return Err(MailboxError::Unsupported {
+                op: "receive_matching".to_string(),
+                reason: "the headers are encrypted with the item".to_string(),
+            }
+            .into());
+        }
+        self.receive(id).await
+    }
+    async fn list_mailboxes(&self) -> Result<Vec<String>> {
+        self.inner.list_mailboxes().await
+    }
+    async fn delivery_count(&self, id: &str, item_id: &str) -> Result<u32> {
+        self.inner.delivery_count(id, item_id).await
+    }
+    /// Note: The sizes are the sizes of the encrypted frames.
+    async fn list_items_page(
+        &self,
+        id: &str,
+        after: Option<&str>,
+        limit: usize,
+    ) -> Result<Page<ItemSummary>> {
+        self.inner.list_items_page(id, after, limit).await
+    }
+    async fn item_size(&self, _id: &str, _item_id: &str) -> Result<u64> {
+        Err(MailboxError::Unsupported {
+            op: "item_size".to_string(),
+            reason: "the inner mailbox only knows the size of the encrypted frame".to_string(),
+        }
+        .into())
+    }
+    async fn stats(&self, id: &str) -> Result<MailboxStats> {
+        self.inner.stats(id).await
+    }
+    async fn receive_for(&self, id: &str, group: &str) -> Result<Option<(String, ITEM)>> {
+        match self.inner.receive_for(id, group).await? {
+            Some((item_id, raw)) => {
+                let item = self.decrypt(id, &item_id, &raw)?;
+                Ok(Some((item_id, item)))
+            }
+            None => Ok(None),
+        }
+    }
+    async fn acknowledge_for(&self, id: &str, group: &str, item_id: &str) -> Result<()> {
+        self.inner.acknowledge_for(id, group, item_id).await
+    }
+    async fn compact(&self, id: &str) -> Result<u64> {
+        self.inner.compact(id).await
+    }
+    async fn drop_older_than(&self, id: &str, max_age: Duration) -> Result<u64> {
+        self.inner.drop_older_than(id, max_age).await
+    }
+    async fn expire_items(&self, id: &str) -> Result<ExpireReport> {
+        self.inner.expire_items(id).await
+    }
+    async fn health_check(&self) -> Result<HealthReport> {
+        self.inner.health_check().await
+    }
+    // Note: `pop` and `drain` use the default implementations,
+    // since the inner ones would acknowledge items before we know they decrypt.
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::EncryptedMailbox;
+    use crate::EncryptionKey;
+    use crate::Mailbox;
+    use crate::MailboxDisk;
+    use crate::MailboxError;
+    use crate::MailboxItem;
+    use crate::MailboxMemory;
+    use crate::RawItem;
+    use color_eyre::Result;
+    use serde::Deserialize;
+    use serde::Serialize;
+    use std::path::Path;
+    use tempfile::TempDir;
+
+    use test_log::test;
+
+    #[derive(Default, Debug, PartialEq, Serialize, Deserialize)]
+    struct TestItem {
+        data: String,
+    }
+
+    impl MailboxItem for TestItem {
+        fn serialize(&self) -> Result<Vec<u8>> {
+            Ok(serde_json::to_vec(self)?)
+        }
+        fn deserialize(data: &[u8]) -> Result<Self>
+        where
+            Self: Sized,
+        {
+            Ok(serde_json::from_slice(data)?)
+        }
+    }
+
+    fn item(data: &str) -> TestItem {
+        TestItem {
+            data: data.to_string(),
+        }
+    }
+
+    fn key(id: &str, byte: u8) -> EncryptionKey {
+        EncryptionKey::new(id, [byte; 32])
+    }
+
+    async fn round_trip<M: Mailbox<RawItem>>(inner: M) -> Result<()> {
+        let mailbox = EncryptedMailbox::<TestItem, _>::new(inner, vec![key("k1", 1)]);
+        mailbox.send("42", item("secret one")).await?;
+        mailbox.send("42", item("secret two")).await?;
+
+        // nothing readable is stored
+        let (_id, raw) = mailbox.inner().peek("42").await?.expect("Item pending");
+        assert!(!String::from_utf8_lossy(raw.data()).contains("secret"));
+
+        let (_id, one) = mailbox.pop("42").await?.expect("Item pending");
+        assert_eq!(one, item("secret one"));
+        let (id, two) = mailbox.receive("42").await?.expect("Item pending");
+        assert_eq!(two, item("secret two"));
+        mailbox.acknowledge("42", &id).await?;
+        assert!(mailbox.receive("42").await?.is_none());
+
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn it_round_trips_in_memory() -> Result<()> {
+        round_trip(MailboxMemory::<RawItem>::new()).await
+    }
+
+    #[test(tokio::test)]
+    async fn it_round_trips_on_disk() -> Result<()> {
+        let dir = TempDir::new()?;
+        let mut disk = MailboxDisk::<RawItem>::at(dir.path(), Path::new("item"));
+        disk.ensure_storage_exists().await?;
+        round_trip(disk).await
+    }
+
+    #[test(tokio::test)]
+    async fn it_reports_wrong_keys() -> Result<()> {
+        let writer = EncryptedMailbox::<TestItem, _>::new(MailboxMemory::new(), vec![key("k1", 1)]);
+        let id = writer.send("42", item("one")).await?;
+        let reader = EncryptedMailbox::<TestItem, _>::new(writer.into_inner(), vec![key("k1", 2)]);
+
+        let e = reader.receive("42").await.expect_err("Wrong key");
+        match e.downcast_ref::<MailboxError>() {
+            Some(MailboxError::Decryption {
+                mailbox_id,
+                item_id,
+                ..
+            }) => {
+                assert_eq!(mailbox_id, "42");
+                assert_eq!(item_id, &id);
+            }
+            _ => panic!("Unexpected error {e:?}"),
+        }
+        // not acknowledged by a failed pop either
+        assert!(reader.pop("42").await.is_err());
+        let writer = EncryptedMailbox::<TestItem, _>::new(reader.into_inner(), vec![key("k1", 1)]);
+        assert_eq!(
+            writer.receive("42").await?.expect("Item pending").1,
+            item("one")
+        );
+
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn it_rejects_frames_moved_to_another_mailbox() -> Result<()> {
+        let mailbox =
+            EncryptedMailbox::<TestItem, _>::new(MailboxMemory::new(), vec![key("k1", 1)]);
+        mailbox.send("42", item("for 42 only")).await?;
+        let (_id, raw) = mailbox.inner().pop("42").await?.expect("Item pending");
+        let id = mailbox.inner().send("43", raw).await?;
+
+        let e = mailbox.receive("43").await.expect_err("Moved frame");
+        match e.downcast_ref::<MailboxError>() {
+            Some(MailboxError::Decryption {
+                mailbox_id,
+                item_id,
+                reason,
+            }) => {
+                assert_eq!(mailbox_id, "43");
+                assert_eq!(item_id, &id);
+                assert!(reason.contains("authentication"), "{reason}");
+            }
+            _ => panic!("Unexpected error {e:?}"),
+        }
+
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn it_rotates_keys() -> Result<()> {
+        let old = EncryptedMailbox::<TestItem, _>::new(MailboxMemory::new(), vec![key("k1", 1)]);
+        old.send("42", item("old")).await?;
+        let rotated = EncryptedMailbox::<TestItem, _>::new(
+            old.into_inner(),
+            vec![key("k1", 1), key("k2", 2)],
+        );
+        rotated.send("42", item("new")).await?;
+
+        let (_id, item_old) = rotated.pop("42").await?.expect("Item pending");
+        assert_eq!(item_old, item("old"));
+        // a reader without the new key can't read newer items
+        let old = EncryptedMailbox::<TestItem, _>::new(rotated.into_inner(), vec![key("k1", 1)]);
+        let e = old.peek("42").await.expect_err("Unknown key");
+        assert!(format!("{e}").contains("unknown key id"));
+
+        Ok(())
+    }
+}
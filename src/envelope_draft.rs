@@ -0,0 +1,65 @@
+use chrono::DateTime;
+use chrono::Utc;
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+/// An envelope about to be written, as seen by the hook of [crate::MailboxDisk::with_decorator]
+///
+/// Only the headers can be changed, the payload is not even visible,
+/// so checksums and signatures are computed over the final envelope as usual.
+#[derive(Debug)]
+pub struct EnvelopeDraft<'a> {
+    mailbox_id: &'a str,
+    item_id: &'a str,
+    sent_at: DateTime<Utc>,
+    /// The default headers, overridden by the ones of the item
+    pub headers: BTreeMap<String, String>,
+}
+
+impl<'a> EnvelopeDraft<'a> {
+    pub(crate) fn new(
+        mailbox_id: &'a str,
+        item_id: &'a str,
+        sent_at: DateTime<Utc>,
+        headers: BTreeMap<String, String>,
+    ) -> Self {
+        Self {
+            mailbox_id,
+            item_id,
+            sent_at,
+            headers,
+        }
+    }
+
+    pub fn mailbox_id(&self) -> &str {
+        self.mailbox_id
+    }
+
+    pub fn item_id(&self) -> &str {
+        self.item_id
+    }
+
+    pub fn sent_at(&self) -> DateTime<Utc> {
+        self.sent_at
+    }
+}
+
+/// The hook of [crate::MailboxDisk::with_decorator]
+#[derive(Clone)]
+pub(crate) struct Decorator(Arc<dyn Fn(&mut EnvelopeDraft) + Send + Sync>);
+
+impl Decorator {
+    pub(crate) fn new(f: impl Fn(&mut EnvelopeDraft) + Send + Sync + 'static) -> Self {
+        Self(Arc::new(f))
+    }
+
+    pub(crate) fn decorate(&self, draft: &mut EnvelopeDraft) {
+        (self.0)(draft)
+    }
+}
+
+impl std::fmt::Debug for Decorator {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Decorator")
+    }
+}
@@ -0,0 +1,15 @@
+use serde::Deserialize;
+use serde::Serialize;
+
+/// How [crate::MailboxDisk] arranges the envelopes inside a mailbox folder
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EnvelopeLayout {
+    /// All envelopes directly in the mailbox folder, e.g. `42/17.item`
+    #[default]
+    Flat,
+    /// One folder per day the envelopes were sent, e.g. `42/2024-06-01/17.item`
+    ///
+    /// Note: Days are in UTC.
+    Daily,
+}
@@ -0,0 +1,292 @@
+use crate::mailbox_event::EventBus;
+use crate::ExpireReport;
+use crate::HeaderSelector;
+use crate::HealthReport;
+use crate::ItemSummary;
+use crate::Mailbox;
+use crate::MailboxEvent;
+use crate::MailboxItem;
+use crate::MailboxStats;
+use crate::Page;
+use crate::ReceiveStatus;
+use crate::DEFAULT_EVENT_CAPACITY;
+use async_trait::async_trait;
+use color_eyre::eyre::Result;
+use core::marker::PhantomData;
+use std::time::Duration;
+use tokio::sync::broadcast;
+
+/// Wraps any mailbox, and sends a [MailboxEvent] after every successful send and acknowledge
+///
+/// The emptiness edges come from [Mailbox::stats] after the operation,
+/// it is only called while anybody is subscribed, and a failing call only costs the edge.
+///
+/// Note: Only changes made through the wrapper are seen, and items dropped or expired only update the edges.
+/// [crate::MailboxDisk] has events of its own, see [crate::MailboxDisk::subscribe_events].
+#[derive(Debug)]
+pub struct EventedMailbox<ITEM: MailboxItem, M: Mailbox<ITEM>> {
+    inner: M,
+    events: EventBus,
+    item_type: PhantomData<ITEM>,
+}
+
+impl<ITEM: MailboxItem, M: Mailbox<ITEM>> EventedMailbox<ITEM, M> {
+    pub fn new(inner: M) -> Self {
+        Self::with_capacity(inner, DEFAULT_EVENT_CAPACITY)
+    }
+
+    pub fn with_capacity(inner: M, capacity: usize) -> Self {
+        Self {
+            inner,
+            events: EventBus::new(capacity),
+            item_type: PhantomData,
+        }
+    }
+
+    pub fn subscribe_events(&self) -> broadcast::Receiver<MailboxEvent> {
+        self.events.subscribe()
+    }
+
+    pub fn inner(&self) -> &M {
+        &self.inner
+    }
+
+    pub fn into_inner(self) -> M {
+        self.inner
+    }
+
+    async fn publish(&self, id: &str, events: impl FnOnce() -> Vec<MailboxEvent>) {
+        if !self.events.is_observed() {
+            return;
+        }
+        let pending = self.inner.stats(id).await.ok().map(|stats| stats.pending);
+        self.events.publish(id, events(), pending);
+    }
+
+    async fn publish_sent(&self, id: &str, item_ids: &[String]) {
+        self.publish(id, || {
+            item_ids
+                .iter()
+                .map(|item_id| MailboxEvent::ItemSent {
+                    mailbox_id: id.to_string(),
+                    item_id: item_id.clone(),
+                })
+                .collect()
+        })
+        .await
+    }
+
+    async fn publish_acknowledged<'a>(&self, id: &str, item_ids: impl Iterator<Item = &'a String>) {
+        self.publish(id, || {
+            item_ids
+                .map(|item_id| MailboxEvent::ItemAcknowledged {
+                    mailbox_id: id.to_string(),
+                    item_id: item_id.clone(),
+                })
+                .collect()
+        })
+        .await
+    }
+}
+
+#[async_trait]
+impl<ITEM: MailboxItem + Send, M: Mailbox<ITEM>> Mailbox<ITEM> for EventedMailbox<ITEM, M> {
+    async fn ensure_storage_exists(&mut self) -> Result<()> {
+        self.inner.ensure_storage_exists().await
+    }
+    async fn close(&mut self) -> Result<()> {
+        self.inner.close().await
+    }
+
+    async fn create_mailbox(&self, id: &str) -> Result<()> {
+        self.inner.create_mailbox(id).await
+    }
+    async fn send(&self, id: &str, item: ITEM) -> Result<String> {
+        let item_id = self.inner.send(id, item).await?;
+        self.publish_sent(id, std::slice::from_ref(&item_id)).await;
+        Ok(item_id)
+    }
+    async fn send_transaction(&self, id: &str, items: Vec<ITEM>) -> Result<Vec<String>> {
+        let item_ids = self.inner.send_transaction(id, items).await?;
+        self.publish_sent(id, &item_ids).await;
+        Ok(item_ids)
+    }
+    async fn send_raw(&self, id: &str, data: &[u8]) -> Result<String> {
+        let item_id = self.inner.send_raw(id, data).await?;
+        self.publish_sent(id, std::slice::from_ref(&item_id)).await;
+        Ok(item_id)
+    }
+    async fn receive(&self, id: &str) -> Result<Option<(String, ITEM)>> {
+        self.inner.receive(id).await
+    }
+    async fn receive_status(&self, id: &str) -> Result<ReceiveStatus<ITEM>> {
+        self.inner.receive_status(id).await
+    }
+    async fn receive_any(&self, ids: &[String]) -> Result<Option<(String, String, ITEM)>> {
+        self.inner.receive_any(ids).await
+    }
+    async fn acknowledge(&self, id: &str, item_id: &str) -> Result<()> {
+        self.inner.acknowledge(id, item_id).await?;
+        self.publish_acknowledged(id, std::iter::once(&item_id.to_string()))
+            .await;
+        Ok(())
+    }
+    async fn acknowledge_through(&self, id: &str, item_id: &str) -> Result<u64> {
+        let acknowledged = self.inner.acknowledge_through(id, item_id).await?;
+        if acknowledged > 0 {
+            self.publish_acknowledged(id, std::iter::once(&item_id.to_string()))
+                .await;
+        }
+        Ok(acknowledged)
+    }
+    async fn release(&self, id: &str, item_id: &str) -> Result<()> {
+        self.inner.release(id, item_id).await
+    }
+    fn release_later(&self, id: &str, item_id: &str) {
+        self.inner.release_later(id, item_id)
+    }
+    async fn update(&self, id: &str, item_id: &str, item: ITEM) -> Result<()> {
+        self.inner.update(id, item_id, item).await
+    }
+    async fn peek(&self, id: &str) -> Result<Option<(String, ITEM)>> {
+        self.inner.peek(id).await
+    }
+    async fn receive_many(&self, id: &str, max: usize) -> Result<Vec<(String, ITEM)>> {
+        self.inner.receive_many(id, max).await
+    }
+    async fn receive_batch(
+        &self,
+        id: &str,
+        max_items: usize,
+        max_wait: Duration,
+    ) -> Result<Vec<(String, ITEM)>> {
+        self.inner.receive_batch(id, max_items, max_wait).await
+    }
+    async fn receive_matching(
+        &self,
+        id: &str,
+        selector: &HeaderSelector,
+    ) -> Result<Option<(String, ITEM)>> {
+        self.inner.receive_matching(id, selector).await
+    }
+    async fn list_mailboxes(&self) -> Result<Vec<String>> {
+        self.inner.list_mailboxes().await
+    }
+    async fn delivery_count(&self, id: &str, item_id: &str) -> Result<u32> {
+        self.inner.delivery_count(id, item_id).await
+    }
+    async fn list_items_page(
+        &self,
+        id: &str,
+        after: Option<&str>,
+        limit: usize,
+    ) -> Result<Page<ItemSummary>> {
+        self.inner.list_items_page(id, after, limit).await
+    }
+    async fn item_size(&self, id: &str, item_id: &str) -> Result<u64> {
+        self.inner.item_size(id, item_id).await
+    }
+    async fn stats(&self, id: &str) -> Result<MailboxStats> {
+        self.inner.stats(id).await
+    }
+    async fn receive_for(&self, id: &str, group: &str) -> Result<Option<(String, ITEM)>> {
+        self.inner.receive_for(id, group).await
+    }
+    async fn acknowledge_for(&self, id: &str, group: &str, item_id: &str) -> Result<()> {
+        self.inner.acknowledge_for(id, group, item_id).await
+    }
+    async fn compact(&self, id: &str) -> Result<u64> {
+        self.inner.compact(id).await
+    }
+    async fn drop_older_than(&self, id: &str, max_age: Duration) -> Result<u64> {
+        let dropped = self.inner.drop_older_than(id, max_age).await?;
+        self.publish(id, Vec::new).await;
+        Ok(dropped)
+    }
+    async fn expire_items(&self, id: &str) -> Result<ExpireReport> {
+        let report = self.inner.expire_items(id).await?;
+        self.publish(id, Vec::new).await;
+        Ok(report)
+    }
+    async fn health_check(&self) -> Result<HealthReport> {
+        self.inner.health_check().await
+    }
+    async fn pop(&self, id: &str) -> Result<Option<(String, ITEM)>> {
+        let popped = self.inner.pop(id).await?;
+        if let Some((item_id, _item)) = &popped {
+            self.publish_acknowledged(id, std::iter::once(item_id))
+                .await;
+        }
+        Ok(popped)
+    }
+    async fn drain(&self, id: &str, max: Option<usize>) -> Result<Vec<(String, ITEM)>>
+    where
+        ITEM: 'static,
+    {
+        let drained = self.inner.drain(id, max).await?;
+        if !drained.is_empty() {
+            self.publish_acknowledged(id, drained.iter().map(|(item_id, _item)| item_id))
+                .await;
+        }
+        Ok(drained)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::EventedMailbox;
+    use crate::Mailbox;
+    use crate::MailboxEvent;
+    use crate::MailboxItem;
+    use crate::MockMailbox;
+    use color_eyre::Result;
+
+    use test_log::test;
+
+    #[derive(Default, Debug)]
+    struct TestItem {}
+
+    impl MailboxItem for TestItem {
+        fn serialize(&self) -> Result<Vec<u8>> {
+            Ok(Vec::new())
+        }
+        fn deserialize(_data: &[u8]) -> Result<Self>
+        where
+            Self: Sized,
+        {
+            Ok(Self {})
+        }
+    }
+
+    #[test(tokio::test)]
+    async fn it_publishes_events_of_any_backend() -> Result<()> {
+        let mailbox = EventedMailbox::new(MockMailbox::<TestItem>::default());
+        let mut events = mailbox.subscribe_events();
+
+        let item_id = mailbox.send("42", TestItem::default()).await?;
+        let (received_id, _item) = mailbox.receive("42").await?.expect("Item pending");
+        assert_eq!(received_id, item_id);
+        mailbox.acknowledge("42", &item_id).await?;
+
+        let mailbox_id = "42".to_string();
+        for expected in [
+            MailboxEvent::ItemSent {
+                mailbox_id: mailbox_id.clone(),
+                item_id: item_id.clone(),
+            },
+            MailboxEvent::MailboxBecameNonEmpty {
+                mailbox_id: mailbox_id.clone(),
+            },
+            MailboxEvent::ItemAcknowledged {
+                mailbox_id: mailbox_id.clone(),
+                item_id,
+            },
+            MailboxEvent::MailboxBecameEmpty { mailbox_id },
+        ] {
+            assert_eq!(events.try_recv()?, expected);
+        }
+        assert!(events.try_recv().is_err());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,11 @@
+/// What [crate::Mailbox::expire_items] did
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ExpireReport {
+    /// Unread items past their expiry, now skipped without being acknowledged, in id order
+    pub expired: Vec<String>,
+    /// Items past their expiry, but received and not acknowledged yet, left to their consumer
+    pub in_flight: Vec<String>,
+    /// Items that couldn't be loaded, handled according to the [crate::CorruptionPolicy],
+    /// which leaves them as they are with [crate::CorruptionPolicy::Fail]
+    pub corrupt: Vec<String>,
+}
@@ -0,0 +1,396 @@
+#[cfg(feature = "failpoints")]
+use color_eyre::eyre::eyre;
+#[cfg(feature = "failpoints")]
+use color_eyre::eyre::Result;
+#[cfg(feature = "failpoints")]
+use std::sync::atomic::AtomicBool;
+#[cfg(feature = "failpoints")]
+use std::sync::atomic::Ordering;
+#[cfg(feature = "failpoints")]
+use std::time::Duration;
+
+/// A point in [crate::MailboxDisk] where a failure can be injected, to simulate a crash right there
+///
+/// See `MailboxDisk::with_failpoints`, with the `failpoints` feature.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Failpoint {
+    /// `send` wrote the envelope to its temporary file, but didn't rename it yet
+    SendEnvelopeWritten,
+    /// `send` saved the envelope, but not the meta
+    SendEnvelopeSaved,
+    /// `send` saved the meta, but didn't return yet
+    SendMetaSaved,
+    /// `acknowledge` wrote the envelope to its temporary file, but didn't rename it yet
+    AckEnvelopeWritten,
+    /// `acknowledge` saved the envelope, but not the meta
+    AckEnvelopeSaved,
+    /// `acknowledge` saved the meta, but didn't return yet
+    AckMetaSaved,
+    /// The meta was written to its temporary file, but not renamed yet, by any operation
+    MetaWritten,
+    /// `upgrade_mailbox` rewrote an envelope, but didn't record it in the upgrade manifest
+    UpgradeEnvelopeWritten,
+}
+
+#[cfg(feature = "failpoints")]
+impl Failpoint {
+    /// The failpoints reached by `send`, in order
+    pub const SEND: [Failpoint; 4] = [
+        Failpoint::SendEnvelopeWritten,
+        Failpoint::SendEnvelopeSaved,
+        Failpoint::MetaWritten,
+        Failpoint::SendMetaSaved,
+    ];
+    /// The failpoints reached by `acknowledge`, in order
+    pub const ACKNOWLEDGE: [Failpoint; 4] = [
+        Failpoint::AckEnvelopeWritten,
+        Failpoint::AckEnvelopeSaved,
+        Failpoint::MetaWritten,
+        Failpoint::AckMetaSaved,
+    ];
+}
+
+/// Decides if an operation fails at a [Failpoint]
+#[cfg(feature = "failpoints")]
+pub trait FailpointInjector: Send + Sync + std::fmt::Debug {
+    /// Called whenever `failpoint` is reached, an error aborts the operation right there
+    ///
+    /// Note: Nothing is cleaned up after the error, just like after a crash.
+    fn reached(&self, failpoint: Failpoint) -> Result<()>;
+
+    /// How long to wait when `failpoint` is reached, before [FailpointInjector::reached] is asked
+    ///
+    /// The wait is an await point, dropping the operation there simulates cancelling it.
+    /// Note: Only the failpoints between the steps of `send` and `acknowledge` pause, not the ones reached while a file is written.
+    fn pause(&self, _failpoint: Failpoint) -> Option<Duration> {
+        None
+    }
+}
+
+/// Fails the first time a single [Failpoint] is reached
+#[cfg(feature = "failpoints")]
+#[derive(Debug)]
+pub struct FailOnce {
+    failpoint: Failpoint,
+    failed: AtomicBool,
+}
+
+#[cfg(feature = "failpoints")]
+impl FailOnce {
+    pub fn new(failpoint: Failpoint) -> Self {
+        Self {
+            failpoint,
+            failed: AtomicBool::new(false),
+        }
+    }
+
+    pub fn has_failed(&self) -> bool {
+        self.failed.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(feature = "failpoints")]
+impl FailpointInjector for FailOnce {
+    fn reached(&self, failpoint: Failpoint) -> Result<()> {
+        if failpoint == self.failpoint && !self.failed.swap(true, Ordering::Relaxed) {
+            return Err(eyre!("Failpoint {failpoint:?} reached"));
+        }
+        Ok(())
+    }
+}
+
+/// Pauses the first time a single [Failpoint] is reached, without failing
+#[cfg(feature = "failpoints")]
+#[derive(Debug)]
+pub struct PauseAt {
+    failpoint: Failpoint,
+    pause: Duration,
+    paused: AtomicBool,
+}
+
+#[cfg(feature = "failpoints")]
+impl PauseAt {
+    pub fn new(failpoint: Failpoint, pause: Duration) -> Self {
+        Self {
+            failpoint,
+            pause,
+            paused: AtomicBool::new(false),
+        }
+    }
+
+    pub fn has_paused(&self) -> bool {
+        self.paused.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(feature = "failpoints")]
+impl FailpointInjector for PauseAt {
+    fn reached(&self, _failpoint: Failpoint) -> Result<()> {
+        Ok(())
+    }
+
+    fn pause(&self, failpoint: Failpoint) -> Option<Duration> {
+        if failpoint == self.failpoint && !self.paused.swap(true, Ordering::Relaxed) {
+            return Some(self.pause);
+        }
+        None
+    }
+}
+
+#[cfg(all(test, feature = "failpoints"))]
+mod tests {
+    use crate::FailOnce;
+    use crate::Failpoint;
+    use crate::LayoutVersion;
+    use crate::Mailbox;
+    use crate::MailboxDisk;
+    use crate::MailboxItem;
+    use crate::PauseAt;
+    use crate::QuotaManager;
+    use color_eyre::Result;
+    use std::path::Path;
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tempfile::TempDir;
+
+    use test_log::test;
+
+    #[derive(Debug, Default)]
+    struct TestItem {
+        data: String,
+    }
+
+    impl MailboxItem for TestItem {
+        fn serialize(&self) -> Result<Vec<u8>> {
+            Ok(self.data.clone().into())
+        }
+        fn deserialize(data: &[u8]) -> Result<Self>
+        where
+            Self: Sized,
+        {
+            Ok(Self {
+                data: String::from_utf8(data.to_vec())?,
+            })
+        }
+    }
+
+    fn item(data: &str) -> TestItem {
+        TestItem {
+            data: data.to_string(),
+        }
+    }
+
+    /// Strips what newer versions record from the envelopes and the meta of mailbox 42
+    fn make_legacy(dir: &TempDir) -> Result<()> {
+        for entry in std::fs::read_dir(dir.path().join("42"))? {
+            let p = entry?.path();
+            let fields: &[&str] = match p.extension().and_then(|e| e.to_str()) {
+                Some("item") => &["payload_len", "sent_at"],
+                Some("json") => &["layout_version"],
+                _ => continue,
+            };
+            let mut json: serde_json::Value = serde_json::from_slice(&std::fs::read(&p)?)?;
+            for field in fields {
+                json.as_object_mut().expect("Object").remove(*field);
+            }
+            std::fs::write(&p, serde_json::to_vec(&json)?)?;
+        }
+        Ok(())
+    }
+
+    fn disk(dir: &TempDir) -> MailboxDisk<TestItem> {
+        MailboxDisk::at(dir.path(), Path::new("item"))
+    }
+
+    /// A fresh instance, as after a restart, with the mailbox repaired
+    async fn recover(dir: &TempDir) -> Result<MailboxDisk<TestItem>> {
+        let disk = disk(dir);
+        disk.check("42", true).await?;
+        assert!(disk.check("42", false).await?.issues.is_empty());
+        Ok(disk)
+    }
+
+    /// Receives and acknowledges everything
+    async fn received(disk: &MailboxDisk<TestItem>) -> Result<Vec<String>> {
+        let mut received = Vec::new();
+        while let Some((item_id, item)) = disk.receive("42").await? {
+            disk.acknowledge("42", &item_id).await?;
+            received.push(item.data);
+        }
+        Ok(received)
+    }
+
+    #[test(tokio::test)]
+    async fn it_recovers_from_a_crash_while_sending() -> Result<()> {
+        for failpoint in Failpoint::SEND {
+            let dir = TempDir::new()?;
+            disk(&dir).send("42", item("before")).await?;
+
+            let injector = Arc::new(FailOnce::new(failpoint));
+            let failing = disk(&dir).with_failpoints(injector.clone());
+            assert!(failing.send("42", item("crashed")).await.is_err());
+            assert!(injector.has_failed(), "{failpoint:?} not reached");
+
+            let disk = recover(&dir).await?;
+            disk.send("42", item("after")).await?;
+            let expected = if failpoint == Failpoint::SendMetaSaved {
+                vec!["before", "crashed", "after"]
+            } else {
+                vec!["before", "after"]
+            };
+            assert_eq!(received(&disk).await?, expected, "{failpoint:?}");
+        }
+
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn it_recovers_from_a_crash_while_acknowledging() -> Result<()> {
+        for failpoint in Failpoint::ACKNOWLEDGE {
+            let dir = TempDir::new()?;
+            let disk = disk(&dir);
+            disk.send("42", item("one")).await?;
+            disk.send("42", item("two")).await?;
+            let (item_id, _item) = disk.receive("42").await?.expect("Item pending");
+
+            let injector = Arc::new(FailOnce::new(failpoint));
+            let failing = disk.with_failpoints(injector.clone());
+            assert!(failing.acknowledge("42", &item_id).await.is_err());
+            assert!(injector.has_failed(), "{failpoint:?} not reached");
+
+            // Note: at-least-once, so "one" is delivered again, unless the acknowledge made it into the meta
+            let disk = recover(&dir).await?;
+            let expected = if failpoint == Failpoint::AckMetaSaved {
+                vec!["two"]
+            } else {
+                vec!["one", "two"]
+            };
+            assert_eq!(received(&disk).await?, expected, "{failpoint:?}");
+        }
+
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn it_stays_consistent_when_cancelled_at_any_await_point() -> Result<()> {
+        let cancelled = |disk: MailboxDisk<TestItem>, failpoint| {
+            let injector = Arc::new(PauseAt::new(failpoint, Duration::from_secs(60)));
+            (disk.with_failpoints(injector.clone()), injector)
+        };
+        let timeout = Duration::from_millis(50);
+
+        for failpoint in [Failpoint::SendEnvelopeSaved, Failpoint::SendMetaSaved] {
+            let dir = TempDir::new()?;
+            disk(&dir).send("42", item("before")).await?;
+
+            let (pausing, injector) = cancelled(disk(&dir), failpoint);
+            let send = pausing.send("42", item("cancelled"));
+            assert!(tokio::time::timeout(timeout, send).await.is_err());
+            assert!(injector.has_paused(), "{failpoint:?} not reached");
+
+            // Note: no repair, unlike after a crash
+            let disk = disk(&dir);
+            assert!(disk.check("42", false).await?.is_healthy(), "{failpoint:?}");
+            let expected = if failpoint == Failpoint::SendMetaSaved {
+                vec!["before", "cancelled"]
+            } else {
+                vec!["before"]
+            };
+            assert_eq!(received(&disk).await?, expected, "{failpoint:?}");
+        }
+
+        for failpoint in [Failpoint::AckEnvelopeSaved, Failpoint::AckMetaSaved] {
+            let dir = TempDir::new()?;
+            let disk = disk(&dir);
+            disk.send("42", item("one")).await?;
+            disk.send("42", item("two")).await?;
+            let (item_id, _item) = disk.receive("42").await?.expect("Item pending");
+
+            let (pausing, injector) = cancelled(disk, failpoint);
+            let acknowledge = pausing.acknowledge("42", &item_id);
+            assert!(tokio::time::timeout(timeout, acknowledge).await.is_err());
+            assert!(injector.has_paused(), "{failpoint:?} not reached");
+
+            let disk = self::disk(&dir);
+            assert!(disk.check("42", false).await?.is_healthy(), "{failpoint:?}");
+            let expected = if failpoint == Failpoint::AckMetaSaved {
+                vec!["two"]
+            } else {
+                vec!["one", "two"]
+            };
+            assert_eq!(received(&disk).await?, expected, "{failpoint:?}");
+        }
+
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn it_gives_back_the_quota_of_cancelled_sends() -> Result<()> {
+        let with_quota = |dir: &TempDir| disk(dir).with_quota(QuotaManager::by_first_segment());
+        let timeout = Duration::from_millis(50);
+
+        for failpoint in [Failpoint::SendEnvelopeSaved, Failpoint::SendMetaSaved] {
+            let dir = TempDir::new()?;
+            with_quota(&dir).send("42", item("before")).await?;
+
+            let injector = Arc::new(PauseAt::new(failpoint, Duration::from_secs(60)));
+            let pausing = with_quota(&dir).with_failpoints(injector.clone());
+            let send = pausing.send("42", item("cancelled"));
+            assert!(tokio::time::timeout(timeout, send).await.is_err());
+            assert!(injector.has_paused(), "{failpoint:?} not reached");
+
+            // Note: charged before the meta is saved, so only a committed send keeps its charge
+            let disk = with_quota(&dir);
+            let expected = if failpoint == Failpoint::SendMetaSaved {
+                2
+            } else {
+                1
+            };
+            assert_eq!(disk.stats("42").await?.pending, expected, "{failpoint:?}");
+            let usage = disk.quota_usage("42").await?;
+            assert_eq!(usage.items, expected, "{failpoint:?}");
+        }
+
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn it_resumes_an_interrupted_upgrade() -> Result<()> {
+        use tokio_stream::StreamExt;
+
+        let dir = TempDir::new()?;
+        for data in ["one", "two", "three"] {
+            disk(&dir).send("42", item(data)).await?;
+        }
+        make_legacy(&dir)?;
+
+        let injector = Arc::new(FailOnce::new(Failpoint::UpgradeEnvelopeWritten));
+        let failing = disk(&dir).with_failpoints(injector.clone());
+        assert!(failing
+            .upgrade_mailbox("42", LayoutVersion::V2, false)
+            .await
+            .is_err());
+        assert!(injector.has_failed());
+
+        // the envelopes are mixed now, but all of them are still there, in order
+        let disk = disk(&dir);
+        let scanned: Vec<_> = disk.scan("42").collect().await;
+        let data: Vec<String> = scanned
+            .into_iter()
+            .map(|s| s.and_then(|s| s.decode()).map(|i| i.data))
+            .collect::<Result<_>>()?;
+        assert_eq!(data, ["one", "two", "three"]);
+
+        // Note: the first envelope was rewritten, but the manifest doesn't know, so it is checked again
+        let report = disk.upgrade_mailbox("42", LayoutVersion::V2, false).await?;
+        assert!(report.resumed);
+        assert_eq!(report.upgraded, ["2", "3"]);
+        assert!(disk
+            .upgrade_mailbox("42", LayoutVersion::V2, false)
+            .await?
+            .is_noop());
+        assert_eq!(received(&disk).await?, ["one", "two", "three"]);
+
+        Ok(())
+    }
+}
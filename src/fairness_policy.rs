@@ -0,0 +1,100 @@
+use serde::Deserialize;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+/// The header holding the priority of an item, e.g. `priority: 2`
+///
+/// Higher priorities are received first, items without the header, or with an invalid one, have priority 0.
+/// Note: Only used by a [FairnessPolicy], without one the oldest item is received first.
+pub const PRIORITY_HEADER: &str = "priority";
+
+/// How [crate::MailboxDisk] picks between items of different priority, see [crate::MailboxDisk::with_fairness]
+///
+/// Items of the same priority are always received oldest first.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FairnessPolicy {
+    /// Always the highest priority, lower ones can starve
+    Strict,
+    /// Every priority gets its weight in receives, e.g. `{1: 3, 0: 1}` receives three items of priority 1 for each of priority 0
+    ///
+    /// Priorities without a weight get 1.
+    /// Note: The turn is taken from the acknowledged items, so it only moves on once items are acknowledged.
+    WeightedRoundRobin { weights: BTreeMap<u32, u32> },
+    /// Like [FairnessPolicy::Strict], but items waiting `after` are received before everything else, oldest first
+    AgingBoost { after: Duration },
+}
+
+impl FairnessPolicy {
+    /// The priority of an item with these headers
+    pub fn priority_of(headers: &BTreeMap<String, String>) -> u32 {
+        headers
+            .get(PRIORITY_HEADER)
+            .and_then(|priority| priority.parse().ok())
+            .unwrap_or(0)
+    }
+
+    /// The order to receive `items` in, given as their priority and age, oldest first
+    ///
+    /// `turn` picks the priority served by [FairnessPolicy::WeightedRoundRobin].
+    pub(crate) fn order(&self, items: &[(u32, Duration)], turn: u64) -> Vec<usize> {
+        let mut order: Vec<usize> = (0..items.len()).collect();
+        // Note: the sorts are stable, so items of the same priority stay oldest first
+        order.sort_by_key(|&i| std::cmp::Reverse(items[i].0));
+        match self {
+            Self::Strict => {}
+            Self::WeightedRoundRobin { weights } => {
+                let mut priorities: Vec<u32> = order.iter().map(|&i| items[i].0).collect();
+                priorities.dedup();
+                let weight = |priority: &u32| u64::from(*weights.get(priority).unwrap_or(&1));
+                let total: u64 = priorities.iter().map(weight).sum();
+                if total > 0 {
+                    let mut slot = turn % total;
+                    let served = priorities.into_iter().find(|priority| {
+                        let w = weight(priority);
+                        if slot < w {
+                            return true;
+                        }
+                        slot -= w;
+                        false
+                    });
+                    order.sort_by_key(|&i| Some(items[i].0) != served);
+                }
+            }
+            Self::AgingBoost { after } => {
+                order.sort_by_key(|&i| items[i].1 < *after);
+                let boosted = order.iter().take_while(|&&i| items[i].1 >= *after).count();
+                order[..boosted].sort_unstable();
+            }
+        }
+        order
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::FairnessPolicy;
+    use std::collections::BTreeMap;
+    use std::time::Duration;
+
+    #[test]
+    fn it_orders_by_policy() {
+        let secs = Duration::from_secs;
+        // oldest first
+        let items = [(0, secs(50)), (1, secs(40)), (0, secs(30)), (1, secs(20))];
+
+        assert_eq!(FairnessPolicy::Strict.order(&items, 0), vec![1, 3, 0, 2]);
+
+        let weighted = FairnessPolicy::WeightedRoundRobin {
+            weights: BTreeMap::from([(1, 2), (0, 1)]),
+        };
+        let served: Vec<usize> = (0..6).map(|turn| weighted.order(&items, turn)[0]).collect();
+        assert_eq!(served, vec![1, 1, 0, 1, 1, 0]);
+
+        let aging = FairnessPolicy::AgingBoost { after: secs(25) };
+        assert_eq!(aging.order(&items, 0), vec![0, 1, 2, 3]);
+        let aging = FairnessPolicy::AgingBoost { after: secs(45) };
+        assert_eq!(aging.order(&items, 0), vec![0, 1, 3, 2]);
+    }
+}
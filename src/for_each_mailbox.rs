@@ -0,0 +1,123 @@
+use crate::BulkResult;
+use crate::Mailbox;
+use crate::MailboxItem;
+use color_eyre::eyre::Report;
+use color_eyre::eyre::Result;
+use futures_util::TryStreamExt;
+use std::future::Future;
+
+/// How many mailbox ids [for_each_mailbox] lists at a time
+const LIST_PAGE_SIZE: usize = 100;
+
+/// Runs `f` for every mailbox of the backend, at most `concurrency` at a time
+///
+/// The ids are listed page by page with [Mailbox::list_mailboxes_page], while the first ones are already worked on.
+/// A failing mailbox is recorded in the result, and the others carry on,
+/// only failing to list the mailboxes fails the whole run.
+/// The entries are in the order the mailboxes finished, not the order they were listed.
+/// A `concurrency` of zero is treated as one.
+///
+/// Note: `f` goes through the backend like any other caller, so its locking still applies,
+/// e.g. [crate::MailboxDisk] runs one operation at a time anyway, and only the waiting overlaps.
+pub async fn for_each_mailbox<ITEM, F, Fut, T>(
+    mailbox: &dyn Mailbox<ITEM>,
+    concurrency: usize,
+    f: F,
+) -> Result<BulkResult<T>>
+where
+    ITEM: MailboxItem,
+    F: Fn(String) -> Fut,
+    Fut: Future<Output = Result<T>>,
+{
+    let ids = async_stream::try_stream! {
+        let mut after = None;
+        loop {
+            let page = mailbox
+                .list_mailboxes_page(after.as_deref(), LIST_PAGE_SIZE)
+                .await?;
+            for id in page.entries {
+                yield id;
+            }
+            match page.next_cursor {
+                Some(cursor) => after = Some(cursor),
+                None => break,
+            }
+        }
+    };
+    let outcomes = ids
+        .map_ok(|id: String| {
+            let run = f(id.clone());
+            async move { Ok::<_, Report>((id, run.await)) }
+        })
+        .try_buffer_unordered(concurrency.max(1));
+    let mut outcomes = std::pin::pin!(outcomes);
+
+    let mut report = BulkResult::default();
+    while let Some((id, r)) = outcomes.try_next().await? {
+        if let Err(e) = &r {
+            tracing::warn!("{id} failed -> {e:?}");
+        }
+        report.push(&id, None, r);
+    }
+
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::for_each_mailbox;
+    use crate::Mailbox;
+    use crate::MailboxMemory;
+    use crate::RawItem;
+    use color_eyre::eyre::eyre;
+    use color_eyre::Result;
+    use std::collections::HashMap;
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::atomic::Ordering;
+    use std::sync::Mutex;
+    use std::time::Duration;
+
+    use test_log::test;
+
+    #[test(tokio::test)]
+    async fn it_visits_every_mailbox_once() -> Result<()> {
+        let mailbox = MailboxMemory::<RawItem>::new();
+        for n in 0..200 {
+            mailbox.create_mailbox(&format!("device-{n:03}")).await?;
+        }
+
+        let visits = Mutex::new(HashMap::<String, usize>::new());
+        let running = AtomicUsize::new(0);
+        let most_running = AtomicUsize::new(0);
+        let report = for_each_mailbox(&mailbox, 8, |id| {
+            let (visits, running, most_running) = (&visits, &running, &most_running);
+            async move {
+                *visits.lock().unwrap().entry(id.clone()).or_default() += 1;
+                let now_running = running.fetch_add(1, Ordering::SeqCst) + 1;
+                most_running.fetch_max(now_running, Ordering::SeqCst);
+                tokio::time::sleep(Duration::from_millis(1)).await;
+                running.fetch_sub(1, Ordering::SeqCst);
+                if id.ends_with("13") {
+                    return Err(eyre!("Poisoned {id}"));
+                }
+                Ok(())
+            }
+        })
+        .await?;
+
+        let visits = visits.into_inner().unwrap();
+        assert_eq!(visits.len(), 200);
+        assert!(visits.values().all(|n| *n == 1));
+        assert!(most_running.load(Ordering::SeqCst) <= 8);
+        assert_eq!(report.len(), 200);
+        let mut failed: Vec<&str> = report
+            .failed()
+            .map(|(_index, entry)| entry.mailbox_id.as_str())
+            .collect();
+        failed.sort();
+        assert_eq!(failed, ["device-013", "device-113"]);
+        assert!(!report.is_all_ok());
+
+        Ok(())
+    }
+}
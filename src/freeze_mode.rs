@@ -0,0 +1,30 @@
+use serde::Deserialize;
+use serde::Serialize;
+
+/// What a frozen mailbox refuses, see [crate::MailboxDisk::set_frozen]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum FreezeMode {
+    #[default]
+    None,
+    /// Nothing can be sent, consumers keep draining
+    NoSend,
+    /// Nothing can be received, producers keep queueing
+    ///
+    /// Items already received can still be acknowledged.
+    NoReceive,
+    Full,
+}
+
+impl FreezeMode {
+    pub fn blocks_send(&self) -> bool {
+        matches!(self, FreezeMode::NoSend | FreezeMode::Full)
+    }
+
+    pub fn blocks_receive(&self) -> bool {
+        matches!(self, FreezeMode::NoReceive | FreezeMode::Full)
+    }
+
+    pub fn is_frozen(&self) -> bool {
+        *self != FreezeMode::None
+    }
+}
@@ -0,0 +1,518 @@
+use crate::MailboxStorage;
+use crate::StorageEntry;
+use crate::StorageStat;
+use async_trait::async_trait;
+use color_eyre::eyre::eyre;
+use color_eyre::eyre::Result;
+#[cfg(feature = "fs-watch")]
+use color_eyre::eyre::WrapErr;
+#[cfg(feature = "fs-watch")]
+use notify::Watcher;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::Condvar;
+use std::sync::Mutex;
+use std::time::SystemTime;
+use tokio::io::AsyncRead;
+
+/// How often [crate::MailboxDisk::watch] polls, if the platform watcher is not available
+#[cfg(feature = "fs-watch")]
+const WATCH_POLL_INTERVAL: std::time::Duration = std::time::Duration::from_secs(1);
+
+#[cfg(any(test, feature = "test-util"))]
+type IoHook = Arc<dyn Fn(&Path) + Send + Sync>;
+
+/// The [MailboxStorage] of [crate::MailboxDisk], keys are file paths
+///
+/// Values are replaced by writing a hidden temporary file next to them, and renaming it into place.
+/// The file system calls run on the blocking threads of tokio, so a dropped future doesn't wait for them.
+/// Calls on the same file, or on a folder and the files in it, run one after the other, also across instances,
+/// so the calls of a dropped future still finish before the next ones on their files start.
+///
+/// Note: Errors are the plain `std::io::Error`s, callers add what they were doing.
+#[derive(Default, Clone)]
+pub struct FsStorage {
+    #[cfg(any(test, feature = "test-util"))]
+    io_hook: Option<IoHook>,
+}
+
+impl std::fmt::Debug for FsStorage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("FsStorage").finish_non_exhaustive()
+    }
+}
+
+impl FsStorage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Calls `hook` with the key, on the blocking thread, before every file system call
+    ///
+    /// E.g. to stall the file system in tests.
+    #[cfg(any(test, feature = "test-util"))]
+    pub fn with_io_hook(mut self, hook: impl Fn(&Path) + Send + Sync + 'static) -> Self {
+        self.io_hook = Some(Arc::new(hook));
+        self
+    }
+
+    /// Runs `f` on a blocking thread, once the calls started before on overlapping `paths` are done
+    ///
+    /// Note: Waiting for them doesn't take a blocking thread, and dropping the future while waiting skips `f`.
+    async fn run<T: Send + 'static>(
+        &self,
+        paths: &[&Path],
+        f: impl FnOnce() -> std::io::Result<T> + Send + 'static,
+    ) -> Result<T> {
+        let (before, done) = IO_QUEUE.enqueue(paths);
+        for before in before {
+            before.wait().await;
+        }
+        self.run_unordered(paths[0], move || {
+            // Note: moved in, so a call dropped by a shutting down runtime still counts as done
+            let _done = done;
+            f()
+        })
+        .await
+    }
+
+    /// Runs `f` on a blocking thread right away, for probes that don't touch any mailbox
+    async fn run_unordered<T: Send + 'static>(
+        &self,
+        key: &Path,
+        f: impl FnOnce() -> std::io::Result<T> + Send + 'static,
+    ) -> Result<T> {
+        match tokio::task::spawn_blocking(self.hooked(key, f)).await {
+            Ok(r) => Ok(r?),
+            Err(e) if e.is_panic() => std::panic::resume_unwind(e.into_panic()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    #[cfg(any(test, feature = "test-util"))]
+    fn hooked<T>(
+        &self,
+        key: &Path,
+        f: impl FnOnce() -> T + Send + 'static,
+    ) -> impl FnOnce() -> T + Send + 'static {
+        let hook = self.io_hook.clone();
+        let key = key.to_path_buf();
+        move || {
+            if let Some(hook) = hook {
+                hook(&key);
+            }
+            f()
+        }
+    }
+
+    #[cfg(not(any(test, feature = "test-util")))]
+    fn hooked<T>(
+        &self,
+        _key: &Path,
+        f: impl FnOnce() -> T + Send + 'static,
+    ) -> impl FnOnce() -> T + Send + 'static {
+        f
+    }
+}
+
+/// The order of the file system calls of all [FsStorage]s, so it holds for instances on the same folder too
+static IO_QUEUE: IoQueue = IoQueue {
+    started: Mutex::new(Vec::new()),
+};
+
+/// Orders the file system calls on overlapping paths, in the order they were started
+///
+/// Paths overlap if they are the same, or one is a folder above the other.
+/// Calls on other paths don't wait for each other.
+#[derive(Debug)]
+struct IoQueue {
+    /// The calls started, and maybe not done yet
+    started: Mutex<Vec<(Vec<PathBuf>, Arc<IoDone>)>>,
+}
+
+impl IoQueue {
+    /// Starts a call on `paths`, returns the calls to wait for, and the guard marking it done
+    fn enqueue(&self, paths: &[&Path]) -> (Vec<Arc<IoDone>>, DoneOnDrop) {
+        let overlaps = |a: &Path, b: &Path| a.starts_with(b) || b.starts_with(a);
+        let mut started = self.started.lock().unwrap();
+        started.retain(|(_, done)| !done.is_done());
+        let before = started
+            .iter()
+            .filter(|(started, _)| started.iter().any(|s| paths.iter().any(|p| overlaps(s, p))))
+            .map(|(_, done)| done.clone())
+            .collect();
+        let done = Arc::new(IoDone::default());
+        let paths = paths.iter().map(|p| p.to_path_buf()).collect();
+        started.push((paths, done.clone()));
+        (before, DoneOnDrop(done))
+    }
+
+    /// Runs `f` once the calls started before on `path` are done, without waiting for it
+    ///
+    /// Note: Outside of a runtime this waits for them right here.
+    fn spawn_detached(&self, path: &Path, f: impl FnOnce() + Send + 'static) {
+        let (before, done) = self.enqueue(&[path]);
+        match tokio::runtime::Handle::try_current() {
+            Ok(handle) => {
+                handle.spawn(async move {
+                    for before in before {
+                        before.wait().await;
+                    }
+                    let _ = tokio::task::spawn_blocking(move || {
+                        let _done = done;
+                        f()
+                    })
+                    .await;
+                });
+            }
+            Err(_) => {
+                let _done = done;
+                for before in before {
+                    before.wait_blocking();
+                }
+                f();
+            }
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct IoDone {
+    done: Mutex<bool>,
+    changed: Condvar,
+    notify: tokio::sync::Notify,
+}
+
+impl IoDone {
+    fn is_done(&self) -> bool {
+        *self.done.lock().unwrap()
+    }
+
+    async fn wait(&self) {
+        loop {
+            let notified = self.notify.notified();
+            if self.is_done() {
+                return;
+            }
+            notified.await;
+        }
+    }
+
+    fn wait_blocking(&self) {
+        let mut done = self.done.lock().unwrap();
+        while !*done {
+            done = self.changed.wait(done).unwrap();
+        }
+    }
+}
+
+/// Marks a call done, even if it panics
+struct DoneOnDrop(Arc<IoDone>);
+
+impl Drop for DoneOnDrop {
+    fn drop(&mut self) {
+        *self.0.done.lock().unwrap() = true;
+        self.0.changed.notify_all();
+        self.0.notify.notify_waiters();
+    }
+}
+
+#[async_trait]
+impl MailboxStorage for FsStorage {
+    async fn get(&self, key: &Path) -> Result<Option<Vec<u8>>> {
+        let key = key.to_path_buf();
+        self.run(&[&key.clone()], move || match fs::read(&key) {
+            Ok(value) => Ok(Some(value)),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        })
+        .await
+    }
+    async fn put(&self, key: &Path, value: &[u8]) -> Result<()> {
+        let (key, value) = (key.to_path_buf(), value.to_vec());
+        self.run(&[&key.clone()], move || put(&key, &value)).await
+    }
+    /// Note: Links a uniquely named temporary file, so the value is complete once it is visible.
+    async fn put_if_absent(&self, key: &Path, value: &[u8]) -> Result<bool> {
+        let tmp = unique_tmp_path(key)?;
+        let (key, value) = (key.to_path_buf(), value.to_vec());
+        self.run(&[&key.clone()], move || {
+            fs::write(&tmp, value)?;
+            let linked = fs::hard_link(&tmp, &key);
+            let _ = fs::remove_file(&tmp);
+            match linked {
+                Ok(()) => Ok(true),
+                Err(e) if e.kind() == ErrorKind::AlreadyExists => Ok(false),
+                Err(e) => Err(e),
+            }
+        })
+        .await
+    }
+    async fn delete(&self, key: &Path) -> Result<()> {
+        let key = key.to_path_buf();
+        self.run(&[&key.clone()], move || delete(&key)).await
+    }
+    async fn list(&self, folder: &Path) -> Result<Option<Vec<StorageEntry>>> {
+        let folder = folder.to_path_buf();
+        self.run(&[&folder.clone()], move || {
+            let entries = match fs::read_dir(&folder) {
+                Ok(entries) => entries,
+                Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+                Err(e) => return Err(e),
+            };
+            let mut listed = Vec::new();
+            for entry in entries {
+                let entry = entry?;
+                listed.push(StorageEntry {
+                    key: entry.path(),
+                    is_folder: entry.file_type()?.is_dir(),
+                });
+            }
+            Ok(Some(listed))
+        })
+        .await
+    }
+
+    async fn exists(&self, key: &Path) -> Result<bool> {
+        let key = key.to_path_buf();
+        self.run(&[&key.clone()], move || Ok(fs::metadata(&key).is_ok()))
+            .await
+    }
+    /// Note: The version is the inode, which changes whenever a value is replaced by renaming.
+    async fn stat(&self, key: &Path) -> Result<Option<StorageStat>> {
+        let key = key.to_path_buf();
+        self.run(&[&key.clone()], move || {
+            let m = match fs::metadata(&key) {
+                Ok(m) if m.is_dir() => return Ok(None),
+                Ok(m) => m,
+                Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+                Err(e) => return Err(e),
+            };
+            #[cfg(unix)]
+            let version = std::os::unix::fs::MetadataExt::ino(&m);
+            #[cfg(not(unix))]
+            let version = 0;
+            Ok(Some(StorageStat {
+                len: m.len(),
+                modified: Some(m.modified()?),
+                version,
+            }))
+        })
+        .await
+    }
+    async fn write(&self, key: &Path, value: &[u8]) -> Result<()> {
+        let (key, value) = (key.to_path_buf(), value.to_vec());
+        self.run(&[&key.clone()], move || fs::write(&key, value))
+            .await
+    }
+    async fn append(&self, key: &Path, value: &[u8]) -> Result<()> {
+        use std::io::Write;
+        let (key, value) = (key.to_path_buf(), value.to_vec());
+        self.run(&[&key.clone()], move || {
+            let mut f = fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&key)?;
+            f.write_all(&value)?;
+            f.flush()
+        })
+        .await
+    }
+    async fn rename(&self, from: &Path, to: &Path) -> Result<bool> {
+        let (from, to) = (from.to_path_buf(), to.to_path_buf());
+        self.run(&[&from.clone(), &to.clone()], move || {
+            match fs::rename(&from, &to) {
+                Ok(()) => Ok(true),
+                Err(e) if e.kind() == ErrorKind::NotFound && fs::metadata(&from).is_err() => {
+                    Ok(false)
+                }
+                Err(e) => Err(e),
+            }
+        })
+        .await
+    }
+    async fn touch(&self, key: &Path, modified: SystemTime) -> Result<()> {
+        let key = key.to_path_buf();
+        self.run(&[&key.clone()], move || {
+            fs::File::options()
+                .write(true)
+                .open(&key)?
+                .set_modified(modified)
+        })
+        .await
+    }
+    async fn create_folder(&self, folder: &Path) -> Result<()> {
+        let folder = folder.to_path_buf();
+        self.run(&[&folder.clone()], move || fs::create_dir_all(&folder))
+            .await
+    }
+    async fn delete_folder(&self, folder: &Path) -> Result<bool> {
+        let folder = folder.to_path_buf();
+        self.run(&[&folder.clone()], move || {
+            match fs::read_dir(&folder).map(|mut entries| entries.next().is_none()) {
+                Ok(true) => {}
+                Ok(false) => return Ok(false),
+                Err(e) if e.kind() == ErrorKind::NotFound => return Ok(false),
+                Err(e) => return Err(e),
+            }
+            fs::remove_dir(&folder)?;
+            Ok(true)
+        })
+        .await
+    }
+    async fn delete_all(&self, folder: &Path) -> Result<()> {
+        let folder = folder.to_path_buf();
+        self.run(&[&folder.clone()], move || {
+            match fs::remove_dir_all(&folder) {
+                Ok(()) => Ok(()),
+                Err(e) if e.kind() == ErrorKind::NotFound => Ok(()),
+                Err(e) => Err(e),
+            }
+        })
+        .await
+    }
+    async fn available_space(&self, folder: &Path) -> Result<Option<u64>> {
+        let folder = folder.to_path_buf();
+        self.run_unordered(&folder.clone(), move || {
+            fs2::available_space(&folder).map(Some)
+        })
+        .await
+    }
+    /// Note: Only opening the file is queued, reading it is left to `tokio::fs`.
+    async fn reader(&self, key: &Path) -> Result<Option<Box<dyn AsyncRead + Send + Unpin>>> {
+        let key = key.to_path_buf();
+        let file = self
+            .run(&[&key.clone()], move || match fs::File::open(&key) {
+                Ok(file) => Ok(Some(file)),
+                Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+                Err(e) => Err(e),
+            })
+            .await?;
+        Ok(file.map(|file| {
+            Box::new(tokio::fs::File::from_std(file)) as Box<dyn AsyncRead + Send + Unpin>
+        }))
+    }
+    async fn problem(&self, folder: &Path, writable: bool) -> Option<String> {
+        let folder = folder.to_path_buf();
+        let problem = self
+            .run_unordered(&folder.clone(), move || {
+                match fs::metadata(&folder) {
+                    Ok(m) if !m.is_dir() => {
+                        return Ok(Some(format!("{folder:?} is not a directory")))
+                    }
+                    Ok(_) => {}
+                    Err(e) => return Ok(Some(format!("Can't access {folder:?} -> {e}"))),
+                }
+                if !writable {
+                    return Ok(None);
+                }
+                Ok(probe_writable(&folder).err())
+            })
+            .await;
+        problem.unwrap_or_else(|e| Some(format!("{e:#}")))
+    }
+    /// Note: Queued behind the calls of the dropped future, so they can't overwrite the undo.
+    fn undo(self: Arc<Self>, key: PathBuf, value: Option<Vec<u8>>) {
+        let path = key.clone();
+        let undo = self.hooked(&path, move || {
+            let undone = match value {
+                Some(value) => put(&key, &value),
+                None => delete(&key),
+            };
+            if let Err(e) = undone {
+                tracing::warn!("Can't undo the write of {key:?} -> {e}");
+            }
+        });
+        IO_QUEUE.spawn_detached(&path, undo);
+    }
+    #[cfg(feature = "fs-watch")]
+    fn watch(
+        &self,
+        folder: &Path,
+        recursive: bool,
+        tx: tokio::sync::mpsc::UnboundedSender<notify::Result<notify::Event>>,
+    ) -> Result<Option<Box<dyn notify::Watcher + Send>>> {
+        let recursive_mode = match recursive {
+            true => notify::RecursiveMode::Recursive,
+            false => notify::RecursiveMode::NonRecursive,
+        };
+        start_watcher(folder, recursive_mode, tx).map(Some)
+    }
+}
+
+/// Writes to a temporary file next to `path` and renames it into place,
+/// so readers never observe a partially written file.
+fn put(path: &Path, data: &[u8]) -> std::io::Result<()> {
+    let tmp_path = crate::mailbox_disk::tmp_path(path).map_err(std::io::Error::other)?;
+
+    fs::write(&tmp_path, data)?;
+    if let Err(e) = fs::rename(&tmp_path, path) {
+        let _ = fs::remove_file(&tmp_path);
+        return Err(e);
+    }
+    Ok(())
+}
+
+fn delete(p: &Path) -> std::io::Result<()> {
+    match fs::remove_file(p) {
+        Ok(()) => Ok(()),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e),
+    }
+}
+
+/// A hidden temporary file next to `path`, that no one else uses
+fn unique_tmp_path(path: &Path) -> Result<PathBuf> {
+    let file_name = path
+        .file_name()
+        .ok_or_else(|| eyre!("Can't save to {path:?}: no file name"))?;
+    let mut tmp_name = std::ffi::OsString::from(".");
+    tmp_name.push(file_name);
+    tmp_name.push(format!(".{:016x}.tmp", fastrand::u64(..)));
+
+    Ok(path.with_file_name(tmp_name))
+}
+
+/// Writes and removes a hidden probe file in `folder`
+pub(crate) fn probe_writable(folder: &Path) -> std::result::Result<(), String> {
+    let probe = folder.join(format!(".probe-{:016x}.tmp", fastrand::u64(..)));
+    fs::write(&probe, b"probe").map_err(|e| format!("Can't write {probe:?} -> {e}"))?;
+    fs::remove_file(&probe).map_err(|e| format!("Can't remove {probe:?} -> {e}"))?;
+    Ok(())
+}
+
+#[cfg(feature = "fs-watch")]
+fn start_watcher(
+    path: &Path,
+    recursive_mode: notify::RecursiveMode,
+    tx: tokio::sync::mpsc::UnboundedSender<notify::Result<notify::Event>>,
+) -> Result<Box<dyn notify::Watcher + Send>> {
+    let handler = {
+        let tx = tx.clone();
+        move |event| {
+            let _ = tx.send(event);
+        }
+    };
+    let watcher = notify::recommended_watcher(handler).and_then(|mut watcher| {
+        watcher.watch(path, recursive_mode)?;
+        Ok(watcher)
+    });
+    match watcher {
+        Ok(watcher) => Ok(Box::new(watcher)),
+        Err(e) => {
+            tracing::warn!("Can't watch {path:?}, polling instead -> {e}");
+            let handler = move |event| {
+                let _ = tx.send(event);
+            };
+            let config = notify::Config::default().with_poll_interval(WATCH_POLL_INTERVAL);
+            let mut watcher = notify::PollWatcher::new(handler, config)?;
+            watcher
+                .watch(path, recursive_mode)
+                .wrap_err_with(|| format!("Can't watch {path:?}"))?;
+            Ok(Box::new(watcher))
+        }
+    }
+}
@@ -0,0 +1,51 @@
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
+
+/// Selects items by their [crate::MailboxItem::headers], see [crate::Mailbox::receive_matching]
+///
+/// An item matches if it has every selected header, with one of the accepted values.
+/// ```
+/// # use oml_mailbox::HeaderSelector;
+/// let selector = HeaderSelector::new()
+///     .equals("kind", "invoice")
+///     .one_of("region", ["eu", "us"]);
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct HeaderSelector {
+    conditions: BTreeMap<String, BTreeSet<String>>,
+}
+
+impl HeaderSelector {
+    /// Matches every item
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Require header `key` to be `value`
+    ///
+    /// Note: This replaces an earlier condition for the same key.
+    pub fn equals(self, key: &str, value: &str) -> Self {
+        self.one_of(key, [value])
+    }
+
+    /// Require header `key` to be one of the `values`
+    ///
+    /// Note: This replaces an earlier condition for the same key.
+    pub fn one_of<'a>(mut self, key: &str, values: impl IntoIterator<Item = &'a str>) -> Self {
+        self.conditions.insert(
+            key.to_string(),
+            values.into_iter().map(String::from).collect(),
+        );
+        self
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.conditions.is_empty()
+    }
+
+    pub fn matches(&self, headers: &BTreeMap<String, String>) -> bool {
+        self.conditions
+            .iter()
+            .all(|(key, values)| headers.get(key).is_some_and(|v| values.contains(v)))
+    }
+}
@@ -0,0 +1,52 @@
+use std::time::Duration;
+
+/// How usable a mailbox backend is, worst last, so the worse of two is their `max`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum HealthStatus {
+    Healthy,
+    /// Usable, but something needs attention, e.g. the secondary of a [crate::TeeMailbox]
+    Degraded,
+    /// Operations will fail
+    Unhealthy,
+}
+
+/// The result of [crate::Mailbox::health_check]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HealthReport {
+    pub status: HealthStatus,
+    /// What was checked, or what is wrong, for humans
+    pub message: String,
+    /// How long the check took
+    pub duration: Duration,
+    /// Bytes left on the storage, if the backend can tell
+    pub free_space: Option<u64>,
+}
+
+impl HealthReport {
+    pub fn new(status: HealthStatus, message: impl Into<String>) -> Self {
+        Self {
+            status,
+            message: message.into(),
+            duration: Duration::ZERO,
+            free_space: None,
+        }
+    }
+
+    pub fn is_healthy(&self) -> bool {
+        self.status == HealthStatus::Healthy
+    }
+
+    /// The worse status of both, with both messages, both durations added up, and the lower free space
+    pub fn combine(self, other: HealthReport) -> Self {
+        let free_space = match (self.free_space, other.free_space) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        };
+        Self {
+            status: self.status.max(other.status),
+            message: format!("{}; {}", self.message, other.message),
+            duration: self.duration + other.duration,
+            free_space,
+        }
+    }
+}
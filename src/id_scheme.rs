@@ -0,0 +1,16 @@
+use serde::Deserialize;
+use serde::Serialize;
+
+/// How [crate::MailboxDisk] assigns ids to new items
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum IdScheme {
+    /// Consecutive numbers, e.g. `17`, allocated from the meta
+    #[default]
+    Numeric,
+    /// ULIDs, e.g. `01HZ3K7Q2T0000000000000000`, the time of sending followed by random bits
+    ///
+    /// Sending doesn't touch the meta, so several writers can share a mailbox without colliding.
+    /// Items are received in id order, which is the order they were sent in, as far as the clocks of the writers agree.
+    Ulid,
+}
@@ -0,0 +1,214 @@
+use crate::Mailbox;
+use crate::MailboxItem;
+use color_eyre::eyre::Result;
+use std::ops::Deref;
+
+/// A received item, that has to be acknowledged or rejected, see [crate::MailboxExt::receive_guarded]
+///
+/// Dropping the guard without either logs a warning with the mailbox and item id,
+/// and releases the item with [Mailbox::release_later].
+/// The item was never acknowledged, so it stays unread, and is delivered again,
+/// with its delivery count already increased by the receive.
+///
+/// Note: With [crate::DeliveryMode::AtMostOnce] the item is gone once received, acknowledging and rejecting change nothing.
+#[derive(Debug)]
+pub struct ItemGuard<'a, ITEM: MailboxItem> {
+    mailbox: &'a dyn Mailbox<ITEM>,
+    mailbox_id: String,
+    item_id: String,
+    item: ITEM,
+    settled: bool,
+}
+
+impl<'a, ITEM: MailboxItem> ItemGuard<'a, ITEM> {
+    pub(crate) fn new(
+        mailbox: &'a dyn Mailbox<ITEM>,
+        mailbox_id: &str,
+        item_id: String,
+        item: ITEM,
+    ) -> Self {
+        Self {
+            mailbox,
+            mailbox_id: mailbox_id.to_string(),
+            item_id,
+            item,
+            settled: false,
+        }
+    }
+
+    pub fn id(&self) -> &str {
+        &self.item_id
+    }
+
+    pub fn mailbox_id(&self) -> &str {
+        &self.mailbox_id
+    }
+
+    pub async fn ack(mut self) -> Result<()> {
+        self.mailbox
+            .acknowledge(&self.mailbox_id, &self.item_id)
+            .await?;
+        self.settled = true;
+        Ok(())
+    }
+
+    /// Gives up on the item, with `requeue` it is released to be delivered again, see [Mailbox::release],
+    /// otherwise it is acknowledged without being handled
+    pub async fn reject(mut self, requeue: bool) -> Result<()> {
+        if requeue {
+            self.mailbox
+                .release(&self.mailbox_id, &self.item_id)
+                .await?;
+        } else {
+            tracing::info!(
+                "Discarding item {} of mailbox {}",
+                self.item_id,
+                self.mailbox_id
+            );
+            self.mailbox
+                .acknowledge(&self.mailbox_id, &self.item_id)
+                .await?;
+        }
+        self.settled = true;
+        Ok(())
+    }
+}
+
+impl<ITEM: MailboxItem> Deref for ItemGuard<'_, ITEM> {
+    type Target = ITEM;
+
+    fn deref(&self) -> &ITEM {
+        &self.item
+    }
+}
+
+impl<ITEM: MailboxItem> Drop for ItemGuard<'_, ITEM> {
+    fn drop(&mut self) {
+        if !self.settled {
+            tracing::warn!(
+                "Item {} of mailbox {} dropped without ack or reject, it is released to be delivered again",
+                self.item_id,
+                self.mailbox_id
+            );
+            self.mailbox.release_later(&self.mailbox_id, &self.item_id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::Mailbox;
+    use crate::MailboxExt;
+    use crate::MockMailbox;
+    use crate::MockOperation;
+    use crate::RawItem;
+    use color_eyre::Result;
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::atomic::Ordering;
+    use std::sync::Arc;
+    use tracing::subscriber::DefaultGuard;
+    use tracing_subscriber::layer::Context;
+    use tracing_subscriber::layer::SubscriberExt;
+    use tracing_subscriber::Layer;
+
+    struct CountWarnings(Arc<AtomicUsize>);
+
+    impl<S: tracing::Subscriber> Layer<S> for CountWarnings {
+        fn on_event(&self, event: &tracing::Event<'_>, _ctx: Context<'_, S>) {
+            if *event.metadata().level() == tracing::Level::WARN {
+                self.0.fetch_add(1, Ordering::SeqCst);
+            }
+        }
+    }
+
+    fn is_send<T: Send>(_: &T) {}
+
+    /// A mailbox with the items "one" and "two", and a subscriber counting the warnings
+    async fn mailbox_with_items() -> Result<(MockMailbox<RawItem>, Arc<AtomicUsize>, DefaultGuard)>
+    {
+        let warnings = Arc::new(AtomicUsize::new(0));
+        let subscriber = tracing_subscriber::registry().with(CountWarnings(warnings.clone()));
+        let guard = tracing::subscriber::set_default(subscriber);
+
+        let mailbox = MockMailbox::<RawItem>::default();
+        for data in ["one", "two"] {
+            mailbox.send("42", RawItem::new(data.into())).await?;
+        }
+        Ok((mailbox, warnings, guard))
+    }
+
+    // Note: no `test_log` here, the tests install their own subscriber
+    #[tokio::test]
+    async fn it_acknowledges_on_ack() -> Result<()> {
+        let (mailbox, warnings, _guard) = mailbox_with_items().await?;
+
+        let guard = mailbox.receive_guarded("42").await?.expect("Item pending");
+        is_send(&guard);
+        assert_eq!(guard.data(), b"one");
+        guard.ack().await?;
+        assert_eq!(mailbox.calls(MockOperation::Acknowledge), 1);
+        assert_eq!(mailbox.calls(MockOperation::Release), 0);
+
+        let guard = mailbox.receive_guarded("42").await?.expect("Item pending");
+        assert_eq!(guard.data(), b"two");
+        guard.ack().await?;
+        assert_eq!(warnings.load(Ordering::SeqCst), 0);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn it_releases_on_reject_with_requeue() -> Result<()> {
+        let (mailbox, warnings, _guard) = mailbox_with_items().await?;
+
+        let guard = mailbox.receive_guarded("42").await?.expect("Item pending");
+        guard.reject(true).await?;
+        assert_eq!(mailbox.calls(MockOperation::Release), 1);
+        assert_eq!(mailbox.calls(MockOperation::Acknowledge), 0);
+
+        let guard = mailbox.receive_guarded("42").await?.expect("Item pending");
+        assert_eq!(guard.data(), b"one");
+        assert_eq!(mailbox.delivery_count("42", guard.id()).await?, 2);
+        guard.ack().await?;
+        assert_eq!(warnings.load(Ordering::SeqCst), 0);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn it_acknowledges_on_reject_without_requeue() -> Result<()> {
+        let (mailbox, warnings, _guard) = mailbox_with_items().await?;
+
+        let guard = mailbox.receive_guarded("42").await?.expect("Item pending");
+        guard.reject(false).await?;
+        assert_eq!(mailbox.calls(MockOperation::Acknowledge), 1);
+        assert_eq!(mailbox.calls(MockOperation::Release), 0);
+
+        let guard = mailbox.receive_guarded("42").await?.expect("Item pending");
+        assert_eq!(guard.data(), b"two");
+        guard.ack().await?;
+        assert_eq!(warnings.load(Ordering::SeqCst), 0);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn it_releases_and_warns_on_drop() -> Result<()> {
+        let (mailbox, warnings, _guard) = mailbox_with_items().await?;
+
+        {
+            let guard = mailbox.receive_guarded("42").await?.expect("Item pending");
+            assert_eq!(guard.id(), "1");
+        }
+        assert_eq!(warnings.load(Ordering::SeqCst), 1);
+        assert_eq!(mailbox.calls(MockOperation::ReleaseLater), 1);
+        assert_eq!(mailbox.calls(MockOperation::Acknowledge), 0);
+
+        let guard = mailbox.receive_guarded("42").await?.expect("Item pending");
+        assert_eq!(guard.data(), b"one");
+        assert_eq!(mailbox.delivery_count("42", guard.id()).await?, 2);
+        guard.ack().await?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,15 @@
+use chrono::DateTime;
+use chrono::Utc;
+
+/// One item, as listed by [crate::Mailbox::list_items_page], without its payload
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ItemSummary {
+    pub item_id: String,
+    /// Read by the default group
+    pub read: bool,
+    pub delivery_count: u32,
+    /// `None` for items stored before the time was kept
+    pub sent_at: Option<DateTime<Utc>>,
+    /// The length of the serialized item, see [crate::Mailbox::item_size]
+    pub size: u64,
+}
@@ -0,0 +1,31 @@
+use serde::Deserialize;
+use serde::Serialize;
+
+/// The on-disk format of a [crate::MailboxDisk] mailbox, see [crate::MailboxDisk::upgrade_mailbox]
+///
+/// Newer versions only add to the envelopes, so all of them can still be read.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LayoutVersion {
+    /// Mailboxes created before the version was recorded in the meta
+    ///
+    /// Envelopes might not have their `payload_len` or `sent_at` yet.
+    V1,
+    /// Every envelope records `payload_len`, and `sent_at` unless it is signed
+    V2,
+}
+
+impl LayoutVersion {
+    /// The version of new mailboxes
+    pub const CURRENT: LayoutVersion = LayoutVersion::V2;
+
+    pub(crate) fn legacy() -> Self {
+        LayoutVersion::V1
+    }
+}
+
+impl Default for LayoutVersion {
+    fn default() -> Self {
+        LayoutVersion::CURRENT
+    }
+}
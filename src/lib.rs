@@ -3,6 +3,309 @@ pub use mailbox_item::MailboxItem;
 
 mod mailbox;
 pub use mailbox::Mailbox;
+pub use mailbox::ReceiveAnyTurn;
+pub use mailbox::DEFAULT_GROUP;
+mod mailbox_forwarding;
+
+mod failpoint;
+#[cfg(feature = "failpoints")]
+pub use failpoint::FailOnce;
+pub use failpoint::Failpoint;
+#[cfg(feature = "failpoints")]
+pub use failpoint::FailpointInjector;
+#[cfg(feature = "failpoints")]
+pub use failpoint::PauseAt;
+
+mod clock;
+pub use clock::Clock;
+#[cfg(any(test, feature = "test-util"))]
+pub use clock::MockClock;
+pub use clock::SystemClock;
+
+mod header_selector;
+pub use header_selector::HeaderSelector;
+
+mod mailbox_id_encoding;
+mod ulid;
+
+mod cache_mode;
+pub use cache_mode::CacheMode;
+pub use cache_mode::MetaCacheStats;
+
+mod mailbox_storage;
+pub use mailbox_storage::MailboxStorage;
+pub use mailbox_storage::MemoryStorage;
+pub use mailbox_storage::StorageEntry;
+pub use mailbox_storage::StorageStat;
+mod fs_storage;
+pub use fs_storage::FsStorage;
 
 mod mailbox_disk;
 pub use mailbox_disk::MailboxDisk;
+pub use mailbox_disk::MailboxKv;
+pub use mailbox_disk::MetaSnapshot;
+pub use mailbox_disk::DEFAULT_IN_FLIGHT_TIMEOUT;
+pub use mailbox_disk::DEFAULT_MESSAGE_GROUP_TIMEOUT;
+
+mod scanned_item;
+pub use scanned_item::ScannedItem;
+mod provenance;
+pub use provenance::ProvenanceAction;
+pub use provenance::ProvenanceEntry;
+pub use provenance::MAX_PROVENANCE;
+
+mod item_summary;
+pub use item_summary::ItemSummary;
+
+mod page;
+pub use page::Page;
+
+mod mailbox_tail;
+pub use mailbox_tail::MailboxTail;
+pub use mailbox_tail::TailEntry;
+
+mod mailbox_memory;
+pub use mailbox_memory::MailboxMemory;
+
+mod mailbox_wasm;
+pub use mailbox_wasm::MailboxWasm;
+
+#[cfg(feature = "wasm")]
+mod local_storage_kv_store;
+#[cfg(feature = "wasm")]
+pub use local_storage_kv_store::LocalStorageKvStore;
+
+mod delivery_mode;
+pub use delivery_mode::DeliveryMode;
+
+mod freeze_mode;
+pub use freeze_mode::FreezeMode;
+
+mod text_dump;
+
+mod ownership_token;
+pub use ownership_token::OwnershipToken;
+
+mod envelope_layout;
+pub use envelope_layout::EnvelopeLayout;
+
+mod envelope_draft;
+pub use envelope_draft::EnvelopeDraft;
+
+mod id_scheme;
+pub use id_scheme::IdScheme;
+
+mod backpressure;
+pub use backpressure::Backpressure;
+
+mod thresholds;
+pub use thresholds::ThresholdEvent;
+pub use thresholds::Thresholds;
+pub use thresholds::TracingWarningSink;
+pub use thresholds::WarningSink;
+pub use thresholds::DEFAULT_HYSTERESIS;
+
+mod retention_policy;
+pub use retention_policy::RetentionPolicy;
+
+mod quota;
+pub use quota::QuotaLimit;
+pub use quota::QuotaManager;
+pub use quota::QuotaUsage;
+
+mod corruption_policy;
+pub use corruption_policy::CorruptionPolicy;
+
+mod fairness_policy;
+pub use fairness_policy::FairnessPolicy;
+pub use fairness_policy::PRIORITY_HEADER;
+
+mod mailbox_settings;
+pub use mailbox_settings::MailboxSettings;
+
+mod quarantined_item;
+pub use quarantined_item::QuarantinedItem;
+
+mod redacted_export;
+pub use redacted_export::ExportContent;
+pub use redacted_export::ExportEntry;
+pub use redacted_export::ExportSummary;
+
+#[cfg(feature = "fs-watch")]
+mod watch_event;
+#[cfg(feature = "fs-watch")]
+pub use watch_event::WatchEvent;
+
+mod mailbox_stats;
+pub use mailbox_stats::MailboxStats;
+pub use mailbox_stats::PriorityStats;
+
+mod aggregated_stats;
+pub use aggregated_stats::AggregatedStats;
+
+mod mailbox_inspection;
+pub use mailbox_inspection::MailboxInspection;
+
+mod check_report;
+pub use check_report::CheckIssue;
+pub use check_report::CheckReport;
+
+mod expire_report;
+pub use expire_report::ExpireReport;
+
+mod layout_version;
+pub use layout_version::LayoutVersion;
+
+mod upgrade_report;
+pub use upgrade_report::UpgradeReport;
+
+mod health_report;
+pub use health_report::HealthReport;
+pub use health_report::HealthStatus;
+
+mod raw_item;
+pub use raw_item::RawItem;
+
+mod drain_error;
+pub use drain_error::DrainError;
+
+mod bulk_result;
+pub use bulk_result::BulkEntry;
+pub use bulk_result::BulkError;
+pub use bulk_result::BulkResult;
+
+mod mailbox_error;
+pub use mailbox_error::MailboxError;
+
+mod receive_status;
+pub use receive_status::ClosedReason;
+pub use receive_status::ReceiveStatus;
+
+mod poll_policy;
+pub use poll_policy::PollPolicy;
+pub use poll_policy::PollWaiter;
+mod retrying_mailbox;
+pub use retrying_mailbox::RetryPolicy;
+pub use retrying_mailbox::RetryingMailbox;
+
+mod rate_limited_mailbox;
+pub use rate_limited_mailbox::RateLimit;
+pub use rate_limited_mailbox::RateLimitConfig;
+pub use rate_limited_mailbox::RateLimitMode;
+pub use rate_limited_mailbox::RateLimitedMailbox;
+
+mod cached_mailbox;
+pub use cached_mailbox::CacheConfig;
+pub use cached_mailbox::CachedMailbox;
+
+mod dedup_mailbox;
+pub use dedup_mailbox::DedupConfig;
+pub use dedup_mailbox::DedupReceiveMailbox;
+
+mod item_guard;
+pub use item_guard::ItemGuard;
+
+mod mapped_mailbox;
+pub use mapped_mailbox::MailboxExt;
+pub use mapped_mailbox::MappedMailbox;
+
+mod tee_mailbox;
+pub use tee_mailbox::MirrorFailurePolicy;
+pub use tee_mailbox::TeeMailbox;
+
+mod sharded_mailbox;
+pub use sharded_mailbox::ShardedMailbox;
+
+mod scoped_mailbox;
+pub use scoped_mailbox::ScopedMailbox;
+pub use scoped_mailbox::SCOPE_SEPARATOR;
+
+mod encrypted_mailbox;
+pub use encrypted_mailbox::EncryptedMailbox;
+pub use encrypted_mailbox::EncryptionKey;
+
+#[cfg(feature = "tracing-propagation")]
+mod traced_mailbox;
+#[cfg(feature = "tracing-propagation")]
+pub use traced_mailbox::TracedItem;
+#[cfg(feature = "tracing-propagation")]
+pub use traced_mailbox::TracedMailbox;
+
+mod mailbox_event;
+pub use mailbox_event::MailboxEvent;
+pub use mailbox_event::DEFAULT_EVENT_CAPACITY;
+
+mod evented_mailbox;
+pub use evented_mailbox::EventedMailbox;
+
+#[cfg(feature = "webhook")]
+mod notifying_mailbox;
+#[cfg(feature = "webhook")]
+pub use notifying_mailbox::NotifyingMailbox;
+#[cfg(feature = "webhook")]
+pub use notifying_mailbox::WebhookConfig;
+#[cfg(feature = "webhook")]
+pub use notifying_mailbox::WebhookFailurePolicy;
+
+mod topic_router;
+pub use topic_router::TopicRouter;
+pub use topic_router::SUBSCRIPTIONS_KEY;
+
+mod mailbox_channel;
+pub use mailbox_channel::channel;
+pub use mailbox_channel::ChannelOptions;
+pub use mailbox_channel::MailboxReceiver;
+pub use mailbox_channel::MailboxSender;
+
+#[cfg(feature = "blocking")]
+mod blocking;
+#[cfg(feature = "blocking")]
+pub use blocking::BlockingMailbox;
+
+mod mailbox_maintainer;
+pub use mailbox_maintainer::MailboxMaintainer;
+pub use mailbox_maintainer::MaintenanceConfig;
+pub use mailbox_maintainer::MaintenanceSummary;
+
+mod stats_collector;
+pub use stats_collector::CsvStatsReporter;
+pub use stats_collector::MailboxSnapshot;
+pub use stats_collector::StatsCollector;
+pub use stats_collector::StatsReporter;
+pub use stats_collector::TracingStatsReporter;
+pub use stats_collector::DEFAULT_COLLECT_CONCURRENCY;
+
+mod mailbox_worker;
+pub use mailbox_worker::MailboxWorker;
+pub use mailbox_worker::PanicPolicy;
+pub use mailbox_worker::WorkerHandle;
+pub use mailbox_worker::WorkerOptions;
+pub use mailbox_worker::WorkerSummary;
+
+mod migrate;
+pub use migrate::migrate_mailbox;
+pub use migrate::MigrateOptions;
+pub use migrate::MigrateReport;
+pub use migrate::MigratedItem;
+pub use migrate::MIGRATE_GROUP;
+
+mod for_each_mailbox;
+pub use for_each_mailbox::for_each_mailbox;
+
+#[cfg(any(test, feature = "test-util"))]
+mod mock_mailbox;
+#[cfg(any(test, feature = "test-util"))]
+pub use mock_mailbox::MockMailbox;
+#[cfg(any(test, feature = "test-util"))]
+pub use mock_mailbox::MockOperation;
+
+#[cfg(any(test, feature = "test-util"))]
+mod chaos_mailbox;
+#[cfg(any(test, feature = "test-util"))]
+pub use chaos_mailbox::ChaosMailbox;
+#[cfg(any(test, feature = "test-util"))]
+pub use chaos_mailbox::ChaosProfile;
+#[cfg(any(test, feature = "test-util"))]
+pub use chaos_mailbox::ChaosStats;
+#[cfg(any(test, feature = "test-util"))]
+pub use chaos_mailbox::LatencyDistribution;
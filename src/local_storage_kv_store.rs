@@ -0,0 +1,234 @@
+use crate::MailboxStorage;
+use crate::StorageEntry;
+use async_trait::async_trait;
+use base64::prelude::*;
+use color_eyre::eyre::eyre;
+use color_eyre::eyre::Result;
+use std::collections::BTreeMap;
+use std::path::Path;
+
+/// A [MailboxStorage] in the browser's `localStorage`, for [crate::MailboxWasm]
+///
+/// All keys are prefixed, so several stores can share the `localStorage` of one origin.
+/// Values are base64 encoded, since `localStorage` only holds strings.
+/// Folders are kept as empty values, with the folder and a trailing `/` as their key.
+///
+/// Note: `localStorage` is synchronous, and limited to a few megabytes.
+#[derive(Debug, Clone)]
+pub struct LocalStorageKvStore {
+    prefix: String,
+}
+
+impl LocalStorageKvStore {
+    pub fn new(prefix: &str) -> Self {
+        Self {
+            prefix: prefix.to_string(),
+        }
+    }
+
+    // Note: the storage is fetched for every call, since js values can't be sent between threads
+    fn storage() -> Result<web_sys::Storage> {
+        let window = web_sys::window().ok_or_else(|| eyre!("No window"))?;
+        window
+            .local_storage()
+            .map_err(|e| eyre!("Can't access localStorage -> {e:?}"))?
+            .ok_or_else(|| eyre!("No localStorage"))
+    }
+
+    fn key(&self, key: &Path) -> String {
+        format!("{}{}", self.prefix, key.to_string_lossy())
+    }
+
+    fn folder_key(&self, folder: &Path) -> String {
+        format!("{}/", self.key(folder))
+    }
+
+    fn get_item(&self, storage: &web_sys::Storage, key: &str) -> Result<Option<String>> {
+        storage
+            .get_item(key)
+            .map_err(|e| eyre!("Can't get {key} -> {e:?}"))
+    }
+
+    fn set_item(&self, storage: &web_sys::Storage, key: &str, value: &str) -> Result<()> {
+        storage
+            .set_item(key, value)
+            .map_err(|e| eyre!("Can't put {key} -> {e:?}"))
+    }
+
+    fn remove_item(&self, storage: &web_sys::Storage, key: &str) -> Result<()> {
+        storage
+            .remove_item(key)
+            .map_err(|e| eyre!("Can't delete {key} -> {e:?}"))
+    }
+
+    /// All keys of this store starting with `start`, including the prefix
+    fn keys_below(&self, storage: &web_sys::Storage, start: &str) -> Result<Vec<String>> {
+        let len = storage
+            .length()
+            .map_err(|e| eyre!("Can't list {start} -> {e:?}"))?;
+        let mut keys = Vec::new();
+        for i in 0..len {
+            let key = storage
+                .key(i)
+                .map_err(|e| eyre!("Can't list {start} -> {e:?}"))?;
+            if let Some(key) = key.filter(|key| key.starts_with(start)) {
+                keys.push(key);
+            }
+        }
+
+        Ok(keys)
+    }
+}
+
+/// The keys and folders directly in `folder`, from the `keys` below its `folder_key`, `None` if there are none
+fn entries_in(folder: &Path, folder_key: &str, keys: &[String]) -> Option<Vec<StorageEntry>> {
+    if keys.is_empty() {
+        return None;
+    }
+    let mut entries = BTreeMap::new();
+    for rest in keys.iter().filter_map(|key| key.strip_prefix(folder_key)) {
+        match rest.split_once('/') {
+            _ if rest.is_empty() => {}
+            Some((name, _)) => {
+                entries.insert(name.to_string(), true);
+            }
+            None => {
+                entries.entry(rest.to_string()).or_insert(false);
+            }
+        }
+    }
+    let entries = entries
+        .into_iter()
+        .map(|(name, is_folder)| StorageEntry {
+            key: folder.join(name),
+            is_folder,
+        })
+        .collect();
+
+    Some(entries)
+}
+
+#[async_trait]
+impl MailboxStorage for LocalStorageKvStore {
+    async fn get(&self, key: &Path) -> Result<Option<Vec<u8>>> {
+        match self.get_item(&Self::storage()?, &self.key(key))? {
+            Some(value) => Ok(Some(BASE64_STANDARD.decode(value)?)),
+            None => Ok(None),
+        }
+    }
+    async fn put(&self, key: &Path, value: &[u8]) -> Result<()> {
+        let storage = Self::storage()?;
+        if let Some(folder) = key.parent() {
+            self.set_item(&storage, &self.folder_key(folder), "")?;
+        }
+        self.set_item(&storage, &self.key(key), &BASE64_STANDARD.encode(value))
+    }
+    /// Note: Nothing else runs between checking and putting, since `localStorage` is synchronous.
+    async fn put_if_absent(&self, key: &Path, value: &[u8]) -> Result<bool> {
+        let storage = Self::storage()?;
+        if self.get_item(&storage, &self.key(key))?.is_some() {
+            return Ok(false);
+        }
+        self.put(key, value).await?;
+        Ok(true)
+    }
+    async fn delete(&self, key: &Path) -> Result<()> {
+        self.remove_item(&Self::storage()?, &self.key(key))
+    }
+    async fn list(&self, folder: &Path) -> Result<Option<Vec<StorageEntry>>> {
+        let storage = Self::storage()?;
+        let folder_key = self.folder_key(folder);
+        let keys = self.keys_below(&storage, &folder_key)?;
+        Ok(entries_in(folder, &folder_key, &keys))
+    }
+
+    /// Note: Moves folders too, with everything in them.
+    async fn rename(&self, from: &Path, to: &Path) -> Result<bool> {
+        let storage = Self::storage()?;
+        let (from_key, to_key) = (self.key(from), self.key(to));
+        if let Some(value) = self.get_item(&storage, &from_key)? {
+            if let Some(folder) = to.parent() {
+                self.set_item(&storage, &self.folder_key(folder), "")?;
+            }
+            self.set_item(&storage, &to_key, &value)?;
+            self.remove_item(&storage, &from_key)?;
+            return Ok(true);
+        }
+        let moved = self.keys_below(&storage, &self.folder_key(from))?;
+        if moved.is_empty() {
+            return Ok(false);
+        }
+        for key in moved {
+            let value = self.get_item(&storage, &key)?.unwrap_or_default();
+            self.set_item(
+                &storage,
+                &format!("{to_key}{}", &key[from_key.len()..]),
+                &value,
+            )?;
+            self.remove_item(&storage, &key)?;
+        }
+        Ok(true)
+    }
+    async fn create_folder(&self, folder: &Path) -> Result<()> {
+        let storage = Self::storage()?;
+        for folder in folder.ancestors().filter(|f| !f.as_os_str().is_empty()) {
+            self.set_item(&storage, &self.folder_key(folder), "")?;
+        }
+        Ok(())
+    }
+    async fn delete_folder(&self, folder: &Path) -> Result<bool> {
+        let storage = Self::storage()?;
+        let folder_key = self.folder_key(folder);
+        let keys = self.keys_below(&storage, &folder_key)?;
+        if keys.is_empty() || keys.iter().any(|key| *key != folder_key) {
+            return Ok(false);
+        }
+        self.remove_item(&storage, &folder_key)?;
+        Ok(true)
+    }
+    async fn delete_all(&self, folder: &Path) -> Result<()> {
+        let storage = Self::storage()?;
+        for key in self.keys_below(&storage, &self.folder_key(folder))? {
+            self.remove_item(&storage, &key)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::entries_in;
+    use crate::StorageEntry;
+    use std::path::Path;
+    use std::path::PathBuf;
+
+    #[test]
+    fn it_lists_the_keys_and_folders_directly_in_a_folder() {
+        let keys = [
+            "mailboxes/",
+            "mailboxes/42/",
+            "mailboxes/42/1.item",
+            "mailboxes/43/1.item",
+            "mailboxes/trash.json",
+        ]
+        .map(String::from);
+        let entry = |key: &str, is_folder| StorageEntry {
+            key: PathBuf::from(key),
+            is_folder,
+        };
+        let folder = Path::new("mailboxes");
+        assert_eq!(
+            entries_in(folder, "mailboxes/", &keys),
+            Some(vec![
+                entry("mailboxes/42", true),
+                entry("mailboxes/43", true),
+                entry("mailboxes/trash.json", false),
+            ])
+        );
+        assert_eq!(
+            entries_in(folder, "mailboxes/", &keys[..1]),
+            Some(Vec::new())
+        );
+        assert_eq!(entries_in(Path::new("missing"), "missing/", &[]), None);
+    }
+}
@@ -1,10 +1,57 @@
+use crate::BulkResult;
+use crate::ClosedReason;
+use crate::DrainError;
+use crate::ExpireReport;
+use crate::HeaderSelector;
+use crate::HealthReport;
+use crate::HealthStatus;
+use crate::ItemSummary;
+use crate::MailboxError;
 use crate::MailboxItem;
+use crate::MailboxStats;
+use crate::Page;
+use crate::ReceiveStatus;
 use async_trait::async_trait;
-use chrono::DateTime;
-use chrono::Utc;
 use color_eyre::eyre::Result;
-use serde::Deserialize;
-use serde::Serialize;
+use core::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+
+/// The consumer group used by plain `receive` and `acknowledge`
+pub const DEFAULT_GROUP: &str = "default";
+
+/// How often `receive_batch` checks for new items
+pub(crate) const RECEIVE_BATCH_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Where the next `receive_any` starts looking, so a busy mailbox can't starve the others
+///
+/// Backends using the default `receive_any` keep one, and return it from [Mailbox::receive_any_turn].
+#[derive(Debug, Default)]
+pub struct ReceiveAnyTurn(AtomicUsize);
+
+impl ReceiveAnyTurn {
+    /// The indices of `len` mailboxes, in the order to look at them
+    pub(crate) fn order(&self, len: usize) -> impl Iterator<Item = usize> {
+        let start = self.0.load(Ordering::Relaxed);
+        (0..len).map(move |i| (start + i) % len)
+    }
+
+    /// The mailbox at `index` had an item, the next call starts after it
+    pub(crate) fn served(&self, index: usize) {
+        self.0.store(index + 1, Ordering::Relaxed);
+    }
+}
+
+/// The error of the default implementations, for backends that don't implement `op`
+fn unsupported(op: &str) -> color_eyre::eyre::Report {
+    MailboxError::Unsupported {
+        op: op.to_string(),
+        reason: "the backend doesn't implement it".to_string(),
+    }
+    .into()
+}
 
 /// The interface to all mailbox backends.
 ///
@@ -15,12 +62,588 @@ use serde::Serialize;
 /// Are mostly just noise in the documentation, and I didn't figure out how to remove it yet.
 ///
 /// You can just ignore them. In the end the `fn` are just `async` and return a [color_eyre::eyre::Result]
+///
+/// `Box<M>`, `Arc<M>`, and `&M` are mailboxes too, if `M` is,
+/// so e.g. an `Arc<MailboxDisk<_>>` can be passed to anything taking an `impl Mailbox<_>`.
 #[async_trait]
 pub trait Mailbox<ITEM: MailboxItem + Sized>: Send + Sync + std::fmt::Debug {
     /// Ensure the storage layer actually exists
+    ///
+    /// Note: Like `close` this takes `&mut self`, so through an `Arc` it only works before the `Arc` is cloned,
+    /// and not at all through `&M`.
     async fn ensure_storage_exists(&mut self) -> Result<()>;
 
+    /// Flush everything, and release all resources
+    ///
+    /// Afterwards all operations fail with [crate::MailboxError::Closed].
+    /// Note: The default implementation does nothing, for backends without anything to flush.
+    async fn close(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Create the mailbox, does nothing if it already exists
+    ///
+    /// Most backends create mailboxes on first use,
+    /// but e.g. [crate::MailboxDisk::strict] only accepts mailboxes created this way.
+    /// Note: The default implementation does nothing, for backends creating all mailboxes on first use.
+    async fn create_mailbox(&self, _id: &str) -> Result<()> {
+        Ok(())
+    }
+
     async fn send(&self, id: &str, item: ITEM) -> Result<String>;
+
+    /// Send all items, or none of them
+    ///
+    /// The items become visible together, in the given order, and their ids are returned in that order.
+    /// Note: The default implementation fails with [MailboxError::Unsupported].
+    // Note: written out like `#[async_trait]` would, so the items are dropped right away,
+    // instead of being moved into the future, which would need `ITEM: 'static`
+    fn send_transaction<'life0, 'life1, 'async_trait>(
+        &'life0 self,
+        _id: &'life1 str,
+        _items: Vec<ITEM>,
+    ) -> Pin<Box<dyn Future<Output = Result<Vec<String>>> + Send + 'async_trait>>
+    where
+        'life0: 'async_trait,
+        'life1: 'async_trait,
+        Self: 'async_trait,
+    {
+        Box::pin(std::future::ready(Err(unsupported("send_transaction"))))
+    }
+
+    /// Send every item on its own, carrying on past the ones that fail
+    ///
+    /// Unlike `send_transaction` the items that can be sent are sent,
+    /// with an entry per item, in the given order, holding its id or why it failed.
+    async fn send_many(&self, id: &str, items: Vec<ITEM>) -> Result<BulkResult<String>>
+    where
+        ITEM: Send + 'static,
+    {
+        let mut sent = BulkResult::default();
+        for item in items {
+            let r = self.send(id, item).await;
+            let item_id = r.as_ref().ok().cloned();
+            sent.push(id, item_id, r);
+        }
+        Ok(sent)
+    }
     async fn receive(&self, id: &str) -> Result<Option<(String, ITEM)>>;
     async fn acknowledge(&self, id: &str, item_id: &str) -> Result<()>;
+
+    /// Send an item in its serialized form, e.g. bytes passed on from another system
+    ///
+    /// Note: The default implementation deserializes `data` into an `ITEM`, and sends that,
+    /// which is a plain copy for [crate::RawItem].
+    async fn send_raw(&self, id: &str, data: &[u8]) -> Result<String>
+    where
+        ITEM: Send,
+    {
+        self.send(id, ITEM::deserialize(data)?).await
+    }
+
+    /// Like `receive`, but tells an empty mailbox apart from one that won't deliver anything, see [ReceiveStatus]
+    ///
+    /// Note: The default implementation turns the errors of `receive` into [ReceiveStatus::Closed], see [ClosedReason::of].
+    /// Backends creating mailboxes on first use report missing mailboxes as [ReceiveStatus::Empty] then.
+    async fn receive_status(&self, id: &str) -> Result<ReceiveStatus<ITEM>> {
+        match self.receive(id).await {
+            Ok(Some((item_id, item))) => Ok(ReceiveStatus::Received(item_id, item)),
+            Ok(None) => Ok(ReceiveStatus::Empty),
+            Err(e) => match ClosedReason::of(&e) {
+                Some(reason) => Ok(ReceiveStatus::Closed(reason)),
+                None => Err(e),
+            },
+        }
+    }
+
+    /// Like `receive`, but returns the item in its serialized form
+    ///
+    /// Note: The default implementation serializes the received item.
+    async fn receive_raw(&self, id: &str) -> Result<Option<(String, Vec<u8>)>> {
+        match self.receive(id).await? {
+            Some((item_id, item)) => Ok(Some((item_id, item.serialize()?))),
+            None => Ok(None),
+        }
+    }
+
+    /// Acknowledge all items up to, and including, `item_id`, returns how many were unread
+    ///
+    /// Ids above the highest id sent so far are clamped to it.
+    /// Note: The default implementation fails with [MailboxError::Unsupported].
+    async fn acknowledge_through(&self, _id: &str, _item_id: &str) -> Result<u64> {
+        Err(unsupported("acknowledge_through"))
+    }
+
+    /// Hand a received item back unhandled, it stays unread, and the next receive can deliver it right away
+    ///
+    /// E.g. the next item of its message group isn't held back until the item times out.
+    /// Note: The default implementation does nothing, the item was never acknowledged, so it is delivered again anyway.
+    async fn release(&self, _id: &str, _item_id: &str) -> Result<()> {
+        Ok(())
+    }
+
+    /// Like `release`, for callers that can't wait for it, e.g. a dropped [crate::ItemGuard]
+    ///
+    /// Backends record the release, and carry it out with a later call.
+    /// Note: The default implementation does nothing, like the default `release`.
+    fn release_later(&self, _id: &str, _item_id: &str) {}
+
+    /// Replace the payload of an unread item, keeping its id, and with that its position
+    ///
+    /// Fails with [crate::MailboxError::AlreadyRead] once the item has been acknowledged.
+    /// Note: The default implementation fails with [MailboxError::Unsupported].
+    // Note: written out like `send_transaction`
+    fn update<'life0, 'life1, 'life2, 'async_trait>(
+        &'life0 self,
+        _id: &'life1 str,
+        _item_id: &'life2 str,
+        _item: ITEM,
+    ) -> Pin<Box<dyn Future<Output = Result<()>> + Send + 'async_trait>>
+    where
+        'life0: 'async_trait,
+        'life1: 'async_trait,
+        'life2: 'async_trait,
+        Self: 'async_trait,
+    {
+        Box::pin(std::future::ready(Err(unsupported("update"))))
+    }
+
+    /// Look at the next unread item, without counting it as delivered
+    ///
+    /// Note: The default implementation fails with [MailboxError::Unsupported].
+    async fn peek(&self, _id: &str) -> Result<Option<(String, ITEM)>> {
+        Err(unsupported("peek"))
+    }
+
+    /// Receive up to `max` of the next unread items, oldest first, without acknowledging them
+    ///
+    /// Note: The default implementation only ever returns the one item `receive` returns.
+    async fn receive_many(&self, id: &str, max: usize) -> Result<Vec<(String, ITEM)>> {
+        if max == 0 {
+            return Ok(Vec::new());
+        }
+        Ok(self.receive(id).await?.into_iter().collect())
+    }
+
+    /// Receive from the first mailbox in `ids` with an unread item, returns the mailbox id, item id, and item
+    ///
+    /// Successive calls start looking after the mailbox served last,
+    /// so busy mailboxes take turns, and can't starve the others.
+    /// Note: The default implementation calls `receive` on one mailbox after the other.
+    /// It keeps the turn in [Mailbox::receive_any_turn], without one every call starts with the first mailbox.
+    async fn receive_any(&self, ids: &[String]) -> Result<Option<(String, String, ITEM)>> {
+        let turn = self.receive_any_turn();
+        let order: Vec<usize> = match turn {
+            Some(turn) => turn.order(ids.len()).collect(),
+            None => (0..ids.len()).collect(),
+        };
+        for index in order {
+            let id = &ids[index];
+            if let Some((item_id, item)) = self.receive(id).await? {
+                if let Some(turn) = turn {
+                    turn.served(index);
+                }
+                return Ok(Some((id.clone(), item_id, item)));
+            }
+        }
+        Ok(None)
+    }
+
+    /// The turn of the default `receive_any`, kept by the backend
+    ///
+    /// Note: The default implementation has none, so the default `receive_any` doesn't take turns.
+    fn receive_any_turn(&self) -> Option<&ReceiveAnyTurn> {
+        None
+    }
+
+    /// Receive up to `max_items`, as soon as they are available, or whatever is there after `max_wait`
+    ///
+    /// The result can be empty, and the items are not acknowledged.
+    /// Note: The default implementation calls `receive_many` every 10ms, until it has `max_items`,
+    /// so every poll counts a delivery of the items received so far, on backends handing them out again.
+    /// With the default `receive_many` that is at most one item.
+    async fn receive_batch(
+        &self,
+        id: &str,
+        max_items: usize,
+        max_wait: Duration,
+    ) -> Result<Vec<(String, ITEM)>>
+    where
+        ITEM: Send,
+    {
+        let deadline = tokio::time::Instant::now() + max_wait;
+        let mut batch: Vec<(String, ITEM)> = Vec::new();
+        // Note: once unacknowledged items come back, ask for the whole batch, they are only kept once
+        let mut handed_out_again = false;
+        while batch.len() < max_items {
+            let max = match handed_out_again {
+                true => max_items,
+                false => max_items - batch.len(),
+            };
+            for (item_id, item) in self.receive_many(id, max).await? {
+                if batch.iter().any(|(seen, _)| *seen == item_id) {
+                    handed_out_again = true;
+                } else if batch.len() < max_items {
+                    batch.push((item_id, item));
+                }
+            }
+            let now = tokio::time::Instant::now();
+            if batch.len() == max_items || now >= deadline {
+                break;
+            }
+            tokio::time::sleep_until(deadline.min(now + RECEIVE_BATCH_POLL_INTERVAL)).await;
+        }
+        Ok(batch)
+    }
+
+    /// Receive the next unread item whose headers match the `selector`
+    ///
+    /// Items that don't match are left untouched, and are still delivered by `receive`.
+    /// Note: The default implementation fails with [MailboxError::Unsupported].
+    async fn receive_matching(
+        &self,
+        _id: &str,
+        _selector: &HeaderSelector,
+    ) -> Result<Option<(String, ITEM)>> {
+        Err(unsupported("receive_matching"))
+    }
+
+    /// The ids of all mailboxes that exist
+    ///
+    /// Note: The default implementation fails with [MailboxError::Unsupported].
+    async fn list_mailboxes(&self) -> Result<Vec<String>> {
+        Err(unsupported("list_mailboxes"))
+    }
+
+    /// The ids of the mailboxes after the `after` cursor, at most `limit`, in order
+    ///
+    /// Note: The default implementation pages through `list_mailboxes`.
+    /// A `limit` of zero is treated as one.
+    async fn list_mailboxes_page(&self, after: Option<&str>, limit: usize) -> Result<Page<String>> {
+        let limit = limit.max(1);
+        let ids = self
+            .list_mailboxes()
+            .await?
+            .into_iter()
+            .filter(|id| after.is_none_or(|after| id.as_str() > after))
+            .take(limit + 1)
+            .collect();
+        Ok(Page::new(ids, limit, |id| id.clone()))
+    }
+
+    /// The items, read and unread, after the `after` cursor, at most `limit`, in id order
+    ///
+    /// Items removed between pages, e.g. by `compact`, are skipped, the others are listed exactly once.
+    /// A `limit` of zero is treated as one.
+    /// Note: The default implementation fails with [MailboxError::Unsupported].
+    async fn list_items_page(
+        &self,
+        _id: &str,
+        _after: Option<&str>,
+        _limit: usize,
+    ) -> Result<Page<ItemSummary>> {
+        Err(unsupported("list_items_page"))
+    }
+
+    /// How often the item has been handed out by `receive` so far
+    ///
+    /// Useful for retry budgets, since an unacknowledged item will be received again.
+    /// Note: The default implementation fails with [MailboxError::Unsupported].
+    async fn delivery_count(&self, _id: &str, _item_id: &str) -> Result<u32> {
+        Err(unsupported("delivery_count"))
+    }
+
+    /// The length of the serialized item, without deserializing it, or counting a delivery
+    ///
+    /// Note: The default implementation fails with [MailboxError::Unsupported].
+    async fn item_size(&self, _id: &str, _item_id: &str) -> Result<u64> {
+        Err(unsupported("item_size"))
+    }
+
+    /// Lifetime counters, and the number of pending items
+    ///
+    /// Note: The default implementation fails with [MailboxError::Unsupported].
+    async fn stats(&self, _id: &str) -> Result<MailboxStats> {
+        Err(unsupported("stats"))
+    }
+
+    /// Receive the next item for a consumer group
+    ///
+    /// Every group has its own cursor, so every group gets every item, independent of the other groups.
+    /// [DEFAULT_GROUP] is the same as plain `receive`.
+    /// Note: The default implementation only knows [DEFAULT_GROUP], and fails with [MailboxError::Unsupported] for the others.
+    async fn receive_for(&self, id: &str, group: &str) -> Result<Option<(String, ITEM)>> {
+        if group == DEFAULT_GROUP {
+            return self.receive(id).await;
+        }
+        Err(unsupported("receive_for"))
+    }
+
+    /// Acknowledge an item for a consumer group, without affecting the other groups
+    ///
+    /// Note: The default implementation only knows [DEFAULT_GROUP], like `receive_for`.
+    async fn acknowledge_for(&self, id: &str, group: &str, item_id: &str) -> Result<()> {
+        if group == DEFAULT_GROUP {
+            return self.acknowledge(id, item_id).await;
+        }
+        Err(unsupported("acknowledge_for"))
+    }
+
+    /// Remove items that have been acknowledged by all consumer groups, returns how many were removed
+    ///
+    /// Note: The default implementation fails with [MailboxError::Unsupported].
+    async fn compact(&self, _id: &str) -> Result<u64> {
+        Err(unsupported("compact"))
+    }
+
+    /// Skip unread items that were sent more than `max_age` ago, returns how many were skipped
+    ///
+    /// Only the oldest items are skipped, up to the first one that is young enough.
+    /// Skipped items are not counted as acknowledged, and are removed by `compact`.
+    /// Note: The default implementation fails with [MailboxError::Unsupported].
+    async fn drop_older_than(&self, _id: &str, _max_age: Duration) -> Result<u64> {
+        Err(unsupported("drop_older_than"))
+    }
+
+    /// Drop unread items whose time to live has passed, see [crate::MailboxDisk::send_with_ttl]
+    ///
+    /// Items received, but not acknowledged yet, are left alone, so their consumer can still acknowledge them.
+    /// Note: The default implementation does nothing, for backends without a time to live.
+    async fn expire_items(&self, _id: &str) -> Result<ExpireReport> {
+        Ok(ExpireReport::default())
+    }
+
+    /// Check if the storage is usable, without creating mailboxes, or changing any of them
+    ///
+    /// Wrappers report the health of their inner mailbox, and add their own.
+    /// Note: The default implementation has nothing to check, and is always healthy.
+    async fn health_check(&self) -> Result<HealthReport> {
+        Ok(HealthReport::new(HealthStatus::Healthy, "Nothing to check"))
+    }
+
+    /// Receive and acknowledge the next item in one go
+    ///
+    /// For fire-and-forget items where at-most-once delivery is acceptable.
+    /// If the item can't be deserialized it is not acknowledged, and the error is returned.
+    async fn pop(&self, id: &str) -> Result<Option<(String, ITEM)>>
+    where
+        ITEM: Send,
+    {
+        match self.receive(id).await? {
+            Some((item_id, item)) => {
+                self.acknowledge(id, &item_id).await?;
+                Ok(Some((item_id, item)))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Receive and acknowledge everything currently pending, up to `max` items
+    ///
+    /// If an item fails, the items drained so far are returned inside a [DrainError],
+    /// and the failing item is left unread.
+    ///
+    /// Note: The default implementation just loops over `receive` and `acknowledge`,
+    /// so items sent while draining might be included.
+    async fn drain(&self, id: &str, max: Option<usize>) -> Result<Vec<(String, ITEM)>>
+    where
+        ITEM: Send + 'static,
+    {
+        let mut drained = Vec::new();
+        while max.is_none_or(|max| drained.len() < max) {
+            match self.receive(id).await {
+                Ok(Some((item_id, item))) => {
+                    if let Err(e) = self.acknowledge(id, &item_id).await {
+                        return Err(DrainError::new(drained, Some(item_id), e).into());
+                    }
+                    drained.push((item_id, item));
+                }
+                Ok(None) => break,
+                Err(e) => return Err(DrainError::new(drained, None, e).into()),
+            }
+        }
+
+        Ok(drained)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::Mailbox;
+    use crate::MailboxError;
+    use crate::MailboxMemory;
+    use crate::RawItem;
+    use crate::ReceiveAnyTurn;
+    use crate::DEFAULT_GROUP;
+    use async_trait::async_trait;
+    use color_eyre::eyre::Report;
+    use color_eyre::eyre::Result;
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tokio::time::Instant;
+
+    use test_log::test;
+
+    /// A backend outside of the crate, implementing only the required methods
+    #[derive(Debug, Default)]
+    struct MinimalMailbox {
+        inner: MailboxMemory<RawItem>,
+    }
+
+    #[async_trait]
+    impl Mailbox<RawItem> for MinimalMailbox {
+        async fn ensure_storage_exists(&mut self) -> Result<()> {
+            Ok(())
+        }
+        async fn send(&self, id: &str, item: RawItem) -> Result<String> {
+            self.inner.send(id, item).await
+        }
+        async fn receive(&self, id: &str) -> Result<Option<(String, RawItem)>> {
+            self.inner.receive(id).await
+        }
+        async fn acknowledge(&self, id: &str, item_id: &str) -> Result<()> {
+            self.inner.acknowledge(id, item_id).await
+        }
+    }
+
+    fn is_unsupported(e: Report) -> bool {
+        matches!(
+            e.downcast_ref::<MailboxError>(),
+            Some(MailboxError::Unsupported { .. })
+        )
+    }
+
+    #[test(tokio::test)]
+    async fn it_has_defaults_for_everything_but_the_basics() -> Result<()> {
+        let mailbox = MinimalMailbox::default();
+        mailbox.create_mailbox("42").await?;
+        let item_id = mailbox.send("42", RawItem::from(b"one".to_vec())).await?;
+        let (received_id, _item) = mailbox
+            .receive_for("42", DEFAULT_GROUP)
+            .await?
+            .expect("Item");
+        assert_eq!(received_id, item_id);
+        mailbox
+            .acknowledge_for("42", DEFAULT_GROUP, &item_id)
+            .await?;
+
+        assert!(is_unsupported(
+            mailbox.receive_for("42", "audit").await.unwrap_err()
+        ));
+        assert!(is_unsupported(mailbox.peek("42").await.unwrap_err()));
+        assert!(is_unsupported(mailbox.stats("42").await.unwrap_err()));
+        let item = RawItem::from(b"two".to_vec());
+        assert!(is_unsupported(
+            mailbox.update("42", &item_id, item).await.unwrap_err()
+        ));
+        let items = vec![RawItem::from(b"three".to_vec())];
+        assert!(is_unsupported(
+            mailbox.send_transaction("42", items).await.unwrap_err()
+        ));
+
+        Ok(())
+    }
+
+    #[test(tokio::test(start_paused = true))]
+    async fn it_receives_batches_without_stats() -> Result<()> {
+        let mailbox = Arc::new(MinimalMailbox::default());
+        let max_wait = Duration::from_millis(200);
+
+        // nothing arrives
+        let start = Instant::now();
+        assert!(mailbox.receive_batch("42", 1, max_wait).await?.is_empty());
+        assert_eq!(start.elapsed(), max_wait);
+
+        // the batch fills up while waiting
+        let sender = {
+            let mailbox = mailbox.clone();
+            tokio::spawn(async move {
+                tokio::time::sleep(Duration::from_millis(50)).await;
+                mailbox.send("42", RawItem::from(b"one".to_vec())).await
+            })
+        };
+        let start = Instant::now();
+        let batch = mailbox.receive_batch("42", 1, max_wait).await?;
+        let item_id = sender.await??;
+        assert!(start.elapsed() < max_wait);
+        assert_eq!(batch.len(), 1);
+        assert_eq!(batch[0].0, item_id);
+
+        // the item handed out again is only in the batch once
+        mailbox.send("42", RawItem::from(b"two".to_vec())).await?;
+        let start = Instant::now();
+        let batch = mailbox.receive_batch("42", 3, max_wait).await?;
+        assert_eq!(start.elapsed(), max_wait);
+        let ids: Vec<&str> = batch.iter().map(|(id, _)| id.as_str()).collect();
+        assert_eq!(ids, [item_id.as_str()]);
+
+        Ok(())
+    }
+
+    /// A [MinimalMailbox] keeping the turn of the default `receive_any`
+    #[derive(Debug, Default)]
+    struct TurnTakingMailbox {
+        inner: MinimalMailbox,
+        turn: ReceiveAnyTurn,
+    }
+
+    #[async_trait]
+    impl Mailbox<RawItem> for TurnTakingMailbox {
+        async fn ensure_storage_exists(&mut self) -> Result<()> {
+            Ok(())
+        }
+        async fn send(&self, id: &str, item: RawItem) -> Result<String> {
+            self.inner.send(id, item).await
+        }
+        async fn receive(&self, id: &str) -> Result<Option<(String, RawItem)>> {
+            self.inner.receive(id).await
+        }
+        async fn acknowledge(&self, id: &str, item_id: &str) -> Result<()> {
+            self.inner.acknowledge(id, item_id).await
+        }
+        fn receive_any_turn(&self) -> Option<&ReceiveAnyTurn> {
+            Some(&self.turn)
+        }
+    }
+
+    /// Sends three items to `a` and `b` each, and returns the items `receive_any` hands out, in order
+    async fn serve_any(mailbox: &impl Mailbox<RawItem>) -> Result<Vec<String>> {
+        for i in 1..=3 {
+            for id in ["a", "b"] {
+                let data = format!("{id}{i}");
+                mailbox.send(id, RawItem::from(data.into_bytes())).await?;
+            }
+        }
+
+        let ids = ["a", "idle", "b"].map(String::from);
+        let mut served = Vec::new();
+        while let Some((id, item_id, item)) = mailbox.receive_any(&ids).await? {
+            mailbox.acknowledge(&id, &item_id).await?;
+            served.push(String::from_utf8(item.into_data())?);
+        }
+        Ok(served)
+    }
+
+    #[test(tokio::test)]
+    async fn it_takes_turns_receiving_from_any_mailbox_by_default() -> Result<()> {
+        let served = serve_any(&TurnTakingMailbox::default()).await?;
+        assert_eq!(served, ["a1", "b1", "a2", "b2", "a3", "b3"]);
+
+        // every instance keeps its own turn
+        let (first, second) = (TurnTakingMailbox::default(), TurnTakingMailbox::default());
+        first.send("b", RawItem::from(b"b".to_vec())).await?;
+        let ids = ["a", "b"].map(String::from);
+        assert!(first.receive_any(&ids).await?.is_some());
+        assert_eq!(serve_any(&second).await?[0], "a1");
+
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn it_starts_with_the_first_mailbox_without_a_turn() -> Result<()> {
+        let served = serve_any(&MinimalMailbox::default()).await?;
+        assert_eq!(served, ["a1", "a2", "a3", "b1", "b2", "b3"]);
+
+        Ok(())
+    }
 }
@@ -0,0 +1,268 @@
+use crate::Mailbox;
+use crate::MailboxItem;
+use crate::PollPolicy;
+use crate::PollWaiter;
+use color_eyre::eyre::Result;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Notify;
+
+/// How the handles returned by [channel] behave
+#[derive(Debug, Clone)]
+pub struct ChannelOptions {
+    /// How often an empty mailbox is checked again, and errors are retried
+    ///
+    /// With [PollPolicy::Notified] sends through a [MailboxSender] of the same channel wake the receivers right away.
+    pub poll: PollPolicy,
+    /// Acknowledge every item returned by [MailboxReceiver::recv]
+    pub auto_acknowledge: bool,
+    /// Keep waiting for items after all senders are dropped, e.g. sent by other processes
+    pub follow: bool,
+}
+
+impl Default for ChannelOptions {
+    fn default() -> Self {
+        Self {
+            poll: PollPolicy::Notified {
+                start: Duration::from_millis(10),
+                max: Duration::from_millis(100),
+            },
+            auto_acknowledge: true,
+            follow: false,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct ChannelState {
+    senders: AtomicUsize,
+    notify: Notify,
+}
+
+/// Use one mailbox through `tokio::sync::mpsc` style handles
+///
+/// ```no_run
+/// # use oml_mailbox::{channel, ChannelOptions, MailboxMemory};
+/// # use std::sync::Arc;
+/// # #[derive(Debug, Default)]
+/// # struct Job;
+/// # impl oml_mailbox::MailboxItem for Job {
+/// #     fn serialize(&self) -> color_eyre::eyre::Result<Vec<u8>> { Ok(Vec::new()) }
+/// #     fn deserialize(_data: &[u8]) -> color_eyre::eyre::Result<Self> { Ok(Job) }
+/// # }
+/// # async fn run() -> color_eyre::eyre::Result<()> {
+/// let mailbox = Arc::new(MailboxMemory::<Job>::new());
+/// let (tx, mut rx) = channel(mailbox, "jobs".to_string(), ChannelOptions::default());
+/// tx.send(Job).await?;
+/// drop(tx);
+/// while let Some(job) = rx.recv().await {
+///     // ...
+/// }
+/// # Ok(())
+/// # }
+/// ```
+pub fn channel<ITEM: MailboxItem + 'static>(
+    mailbox: Arc<dyn Mailbox<ITEM>>,
+    mailbox_id: String,
+    opts: ChannelOptions,
+) -> (MailboxSender<ITEM>, MailboxReceiver<ITEM>) {
+    let state = Arc::new(ChannelState::default());
+    state.senders.store(1, Ordering::SeqCst);
+    let sender = MailboxSender {
+        mailbox: mailbox.clone(),
+        mailbox_id: mailbox_id.clone(),
+        state: state.clone(),
+    };
+    let receiver = MailboxReceiver {
+        mailbox,
+        mailbox_id,
+        waiter: opts.poll.waiter(),
+        opts,
+        state,
+    };
+
+    (sender, receiver)
+}
+
+/// The sending half of a [channel], the channel closes once all senders are dropped
+#[derive(Debug)]
+pub struct MailboxSender<ITEM: MailboxItem + 'static> {
+    mailbox: Arc<dyn Mailbox<ITEM>>,
+    mailbox_id: String,
+    state: Arc<ChannelState>,
+}
+
+impl<ITEM: MailboxItem + 'static> MailboxSender<ITEM> {
+    /// Returns the id of the stored item
+    pub async fn send(&self, item: ITEM) -> Result<String> {
+        let item_id = self.mailbox.send(&self.mailbox_id, item).await?;
+        self.state.notify.notify_waiters();
+        Ok(item_id)
+    }
+}
+
+impl<ITEM: MailboxItem + 'static> Clone for MailboxSender<ITEM> {
+    fn clone(&self) -> Self {
+        self.state.senders.fetch_add(1, Ordering::SeqCst);
+        Self {
+            mailbox: self.mailbox.clone(),
+            mailbox_id: self.mailbox_id.clone(),
+            state: self.state.clone(),
+        }
+    }
+}
+
+impl<ITEM: MailboxItem + 'static> Drop for MailboxSender<ITEM> {
+    fn drop(&mut self) {
+        if self.state.senders.fetch_sub(1, Ordering::SeqCst) == 1 {
+            self.state.notify.notify_waiters();
+        }
+    }
+}
+
+/// The receiving half of a [channel]
+///
+/// Items stay in the mailbox until they are acknowledged,
+/// so they survive dropping the receiver, or a restart.
+///
+/// Note: Clones share the mailbox, so an item that is received but not acknowledged yet is seen by all of them.
+#[derive(Debug)]
+pub struct MailboxReceiver<ITEM: MailboxItem + 'static> {
+    mailbox: Arc<dyn Mailbox<ITEM>>,
+    mailbox_id: String,
+    opts: ChannelOptions,
+    /// Every receiver backs off on its own
+    waiter: PollWaiter,
+    state: Arc<ChannelState>,
+}
+
+impl<ITEM: MailboxItem + 'static> Clone for MailboxReceiver<ITEM> {
+    fn clone(&self) -> Self {
+        Self {
+            mailbox: self.mailbox.clone(),
+            mailbox_id: self.mailbox_id.clone(),
+            opts: self.opts.clone(),
+            waiter: self.opts.poll.waiter(),
+            state: self.state.clone(),
+        }
+    }
+}
+
+impl<ITEM: MailboxItem + 'static> MailboxReceiver<ITEM> {
+    /// Waits for the next item, `None` once all senders are dropped and the mailbox is empty
+    ///
+    /// Errors are logged, and retried according to [ChannelOptions::poll].
+    /// Without [ChannelOptions::auto_acknowledge] use [MailboxReceiver::recv_with_id] instead,
+    /// otherwise the same item is returned again.
+    pub async fn recv(&mut self) -> Option<ITEM> {
+        loop {
+            match self.recv_with_id().await {
+                Ok(received) => return received.map(|(_item_id, item)| item),
+                Err(e) => {
+                    tracing::warn!("Receiving from {} failed -> {e:?}", self.mailbox_id);
+                    self.waiter.wait().await;
+                }
+            }
+        }
+    }
+
+    /// Like [MailboxReceiver::recv], but with the item id, and failing on errors
+    pub async fn recv_with_id(&mut self) -> Result<Option<(String, ITEM)>> {
+        loop {
+            // Note: created before checking, so a send in between still wakes us
+            let notified = self.state.notify.notified();
+            let closed = !self.opts.follow && self.state.senders.load(Ordering::SeqCst) == 0;
+            if let Some((item_id, item)) = self.mailbox.receive(&self.mailbox_id).await? {
+                if self.opts.auto_acknowledge {
+                    self.mailbox.acknowledge(&self.mailbox_id, &item_id).await?;
+                }
+                self.waiter.reset();
+                return Ok(Some((item_id, item)));
+            }
+            if closed {
+                return Ok(None);
+            }
+            self.waiter.wait_or(notified).await;
+        }
+    }
+
+    pub async fn acknowledge(&self, item_id: &str) -> Result<()> {
+        self.mailbox.acknowledge(&self.mailbox_id, item_id).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::channel;
+    use crate::ChannelOptions;
+    use crate::Mailbox;
+    use crate::MailboxDisk;
+    use crate::MailboxItem;
+    use color_eyre::Result;
+    use std::path::Path;
+    use std::sync::Arc;
+    use tempfile::TempDir;
+
+    use test_log::test;
+
+    #[derive(Default, Debug)]
+    struct Job {
+        n: u32,
+    }
+
+    impl MailboxItem for Job {
+        fn serialize(&self) -> Result<Vec<u8>> {
+            Ok(self.n.to_be_bytes().to_vec())
+        }
+        fn deserialize(data: &[u8]) -> Result<Self>
+        where
+            Self: Sized,
+        {
+            Ok(Self {
+                n: u32::from_be_bytes(data.try_into()?),
+            })
+        }
+    }
+
+    #[test(tokio::test)]
+    async fn it_works_like_mpsc() -> Result<()> {
+        let dir = TempDir::new()?;
+        let mut disk = MailboxDisk::<Job>::at(dir.path(), Path::new("job"));
+        disk.ensure_storage_exists().await?;
+        let mailbox: Arc<dyn Mailbox<Job>> = Arc::new(disk);
+
+        // survives recreating the receiver
+        let (tx, rx) = channel(mailbox.clone(), "jobs".into(), ChannelOptions::default());
+        tx.send(Job { n: 1 }).await?;
+        drop(rx);
+        drop(tx);
+
+        let (tx, mut rx) = channel(mailbox.clone(), "jobs".into(), ChannelOptions::default());
+        let worker = tokio::spawn(async move {
+            let mut jobs = Vec::new();
+            while let Some(job) = rx.recv().await {
+                jobs.push(job.n);
+            }
+            jobs
+        });
+        let senders: Vec<_> = (2..=4)
+            .map(|n| {
+                let tx = tx.clone();
+                tokio::spawn(async move { tx.send(Job { n }).await })
+            })
+            .collect();
+        drop(tx);
+        for sender in senders {
+            sender.await??;
+        }
+
+        let mut jobs = worker.await?;
+        jobs.sort();
+        assert_eq!(jobs, vec![1, 2, 3, 4]);
+        assert_eq!(mailbox.stats("jobs").await?.pending, 0);
+
+        Ok(())
+    }
+}
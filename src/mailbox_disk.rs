@@ -1,365 +1,7579 @@
+use crate::clock::SharedClock;
+use crate::envelope_draft::Decorator;
+use crate::mailbox_event::EventBus;
+use crate::mailbox_id_encoding;
+use crate::provenance;
+use crate::text_dump;
+use crate::text_dump::DumpedItem;
+use crate::text_dump::DumpedMeta;
+use crate::thresholds::SharedWarningSink;
+use crate::ulid;
+use crate::ulid::UlidGenerator;
+use crate::AggregatedStats;
+use crate::Backpressure;
+use crate::BulkResult;
+use crate::CacheMode;
+use crate::CheckIssue;
+use crate::CheckReport;
+use crate::Clock;
+use crate::ClosedReason;
+use crate::CorruptionPolicy;
+use crate::DeliveryMode;
+use crate::DrainError;
+use crate::EnvelopeDraft;
+use crate::EnvelopeLayout;
+use crate::ExpireReport;
+use crate::ExportContent;
+use crate::ExportEntry;
+use crate::ExportSummary;
+use crate::Failpoint;
+use crate::FairnessPolicy;
+use crate::FreezeMode;
+use crate::FsStorage;
+use crate::HeaderSelector;
+use crate::HealthReport;
+use crate::HealthStatus;
+use crate::IdScheme;
+use crate::ItemSummary;
+use crate::LayoutVersion;
 use crate::Mailbox;
+use crate::MailboxError;
+use crate::MailboxEvent;
+use crate::MailboxInspection;
 use crate::MailboxItem;
+use crate::MailboxSettings;
+use crate::MailboxStats;
+use crate::MailboxStorage;
+use crate::MetaCacheStats;
+use crate::OwnershipToken;
+use crate::Page;
+use crate::PriorityStats;
+use crate::ProvenanceAction;
+use crate::ProvenanceEntry;
+use crate::QuarantinedItem;
+use crate::QuotaManager;
+use crate::QuotaUsage;
+use crate::ReceiveAnyTurn;
+use crate::ReceiveStatus;
+use crate::RetentionPolicy;
+use crate::ScannedItem;
+use crate::StorageEntry;
+use crate::StorageStat;
+use crate::TailEntry;
+use crate::ThresholdEvent;
+use crate::Thresholds;
+use crate::UpgradeReport;
+use crate::WarningSink;
+use crate::DEFAULT_EVENT_CAPACITY;
+use crate::DEFAULT_GROUP;
 use async_trait::async_trait;
+use bytes::Bytes;
+use chrono::DateTime;
+use chrono::NaiveDate;
+use chrono::Utc;
 use color_eyre::eyre::eyre;
 use color_eyre::eyre::Result;
+use color_eyre::eyre::WrapErr;
 use serde::Deserialize;
 use serde::Serialize;
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
+use std::collections::HashMap;
 use std::collections::HashSet;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::sync::Mutex;
+use tokio::io::AsyncRead;
+use tokio::io::AsyncWrite;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::broadcast;
+use tokio::sync::Notify;
 use tokio::sync::Semaphore;
+use tokio::sync::SemaphorePermit;
+use tokio_util::sync::CancellationToken;
 
 use core::marker::PhantomData;
 use std::fs;
 use std::path::Path;
 use std::path::PathBuf;
+use std::time::Duration;
+use std::time::Instant;
+use std::time::SystemTime;
 
+use crate::mailbox::RECEIVE_BATCH_POLL_INTERVAL;
+#[cfg(feature = "failpoints")]
+use crate::FailpointInjector;
+#[cfg(feature = "fs-watch")]
+use crate::WatchEvent;
+use tokio_stream::Stream;
+
+/// The limit for the keys and values of [MailboxDisk::set_mailbox_attr] together
+const MAX_MAILBOX_ATTRS_SIZE: usize = 4096;
+
+/// How long a temporary file must be left alone, before [MailboxDisk::check] considers it stale
+const STALE_TMP_AGE: Duration = Duration::from_secs(10 * 60);
+
+/// How long an item of a message group stays in flight, see [MailboxDisk::with_message_group_timeout]
+pub const DEFAULT_MESSAGE_GROUP_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How long a received item is left to its consumer by `expire_items`, see [MailboxDisk::with_in_flight_timeout]
+pub const DEFAULT_IN_FLIGHT_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+
+/// How long [MailboxDisk::with_min_free_space] trusts the last lookup of the free space
+const FREE_SPACE_TTL: Duration = Duration::from_secs(1);
+
+/// How long [MailboxDisk::watch] collects file system events, before reporting them
+#[cfg(feature = "fs-watch")]
+const WATCH_DEBOUNCE: Duration = Duration::from_millis(50);
+
+/// Stores every mailbox in a folder below the base path, with an envelope file per item
+///
+/// Operations are cancel safe: dropping the future leaves the mailbox as it was, or with the operation done.
+/// The writes of `send` and `acknowledge` are undone if the future is dropped before the meta is saved,
+/// see [MailboxDisk::send_with_cancel] to give up waiting without dropping anything.
+pub type MailboxDisk<ITEM> = MailboxKv<ITEM, FsStorage>;
+
+/// The layout of [MailboxDisk], on top of any [MailboxStorage]
+///
+/// Files are keys, and folders their prefixes, see [MailboxKv::in_storage].
 #[derive(Debug)]
-pub struct MailboxDisk<ITEM: MailboxItem> {
+pub struct MailboxKv<ITEM: MailboxItem, S: MailboxStorage> {
+    storage: Arc<S>,
     base_path: PathBuf,
     extension: PathBuf,
     item_type: PhantomData<ITEM>,
     lock_semaphore: Semaphore,
+    heal_id_collisions: bool,
+    read_only: bool,
+    layout: EnvelopeLayout,
+    id_scheme: IdScheme,
+    ulids: UlidGenerator,
+    clock: SharedClock,
+    sidecar_threshold: Option<usize>,
+    status_files: bool,
+    signer: Option<EnvelopeSigner>,
+    /// Set by [MailboxDisk::accept_unsigned], applied to the signer whenever it is built
+    accept_unsigned: bool,
+    encode_ids: bool,
+    strict: bool,
+    /// Set by [MailboxDisk::with_id_counter]
+    id_counters: Option<Mutex<HashMap<String, Arc<IdCounter>>>>,
+    limits: Limits,
+    soft_limits: SoftLimits,
+    metas: MetaState,
+    delivery: Delivery,
+    ownership: Ownership,
+    /// Set by [MailboxDisk::with_default_headers]
+    default_headers: HashMap<String, String>,
+    /// Set by [MailboxDisk::with_decorator]
+    decorator: Option<Decorator>,
+    /// Compaction moves items to the trash, instead of removing them
+    trash: bool,
+    /// Set by [MailboxDisk::with_retention], mailboxes can override it
+    retention: Option<RetentionPolicy>,
+    corruption_policy: CorruptionPolicy,
+    /// Set by [MailboxDisk::with_op_timeout]
+    op_timeout: Option<Duration>,
+    /// Set by [MailboxDisk::with_lock_timeout]
+    lock_timeout: Option<Duration>,
+    #[cfg(feature = "failpoints")]
+    failpoints: Option<Arc<dyn FailpointInjector>>,
+    /// Woken whenever items are acknowledged, for sends waiting on a full mailbox
+    space_freed: Mutex<HashMap<String, Arc<Notify>>>,
+    /// Woken when a mailbox can be received from again, see [MailboxDisk::set_frozen]
+    unfrozen: Mutex<HashMap<String, Arc<Notify>>>,
+    /// See [MailboxDisk::subscribe_events]
+    events: EventBus,
+}
+
+/// The hard limits of sends, a send crossing one of them fails
+#[derive(Debug, Default)]
+struct Limits {
+    /// Set by [MailboxDisk::with_capacity]
+    capacity: Option<(u64, Backpressure)>,
+    /// Set by [MailboxDisk::with_min_free_space]
+    min_free_space: Option<u64>,
+    /// The free space of the base path, and when it was looked up
+    free_space: Mutex<Option<(Instant, u64)>>,
+    /// Set by [MailboxDisk::with_max_item_size]
+    max_item_size: Option<u64>,
+    /// Set by [MailboxDisk::with_quota]
+    quota: Option<QuotaManager>,
+}
+
+/// The soft limits, only warning before the hard [Limits] are reached
+#[derive(Debug, Default)]
+struct SoftLimits {
+    /// Set by [MailboxDisk::with_thresholds]
+    thresholds: Option<(Thresholds, SharedWarningSink)>,
+    /// The soft limits that fired, and the mailbox, until they are re-armed
+    warned: Mutex<HashSet<(&'static str, String)>>,
+}
+
+/// How the metas are written, with their journal, and cached
+#[derive(Debug, Default)]
+struct MetaState {
+    /// Set by [MailboxDisk::with_journal]
+    checkpoint_every: Option<usize>,
+    /// Set by [MailboxDisk::with_meta_cache]
+    cache_mode: CacheMode,
+    cache: Mutex<HashMap<String, CachedMeta>>,
+    cache_hits: AtomicU64,
+    cache_misses: AtomicU64,
+    cache_invalidations: AtomicU64,
+    /// Counts the meta writes started, so an undo knows if its operation was done after all
+    commits: AtomicU64,
+}
+
+/// How items are handed to consumers, and what happens to the ones they give back
+#[derive(Debug)]
+struct Delivery {
+    mode: DeliveryMode,
+    /// Set by [MailboxDisk::with_fairness], mailboxes can override it
+    fairness: Option<FairnessPolicy>,
+    /// Set by [MailboxDisk::with_defer_limit]
+    defer_limit: Option<(u32, Option<String>)>,
+    /// Set by [MailboxDisk::with_message_group_timeout]
+    message_group_timeout: Duration,
+    /// Set by [MailboxDisk::with_in_flight_timeout]
+    in_flight_timeout: Duration,
+    receive_any_turn: ReceiveAnyTurn,
+    /// Recorded by [Mailbox::release_later], carried out once the lock is taken next
+    released: Mutex<Vec<(String, String)>>,
+}
+
+impl Default for Delivery {
+    fn default() -> Self {
+        Self {
+            mode: DeliveryMode::default(),
+            fairness: None,
+            defer_limit: None,
+            message_group_timeout: DEFAULT_MESSAGE_GROUP_TIMEOUT,
+            in_flight_timeout: DEFAULT_IN_FLIGHT_TIMEOUT,
+            receive_any_turn: Default::default(),
+            released: Default::default(),
+        }
+    }
+}
+
+/// The ownerships of mailboxes, see [MailboxDisk::create_mailbox_exclusive]
+#[derive(Debug, Default)]
+struct Ownership {
+    /// Set by [MailboxDisk::with_ownership_ttl]
+    ttl: Option<Duration>,
+    /// Set by [MailboxDisk::with_ownership_checks]
+    checks: bool,
+    /// The ownerships of this instance, by mailbox
+    held: Mutex<HashMap<String, OwnershipToken>>,
+}
+
+impl<ITEM: MailboxItem> MailboxKv<ITEM, FsStorage> {
+    #[deprecated(since = "0.1.2", note = "use MailboxDisk::at, or MailboxDisk::open")]
+    pub async fn new(base_path: &Path, extension: &Path) -> Self {
+        Self::at(base_path, extension)
+    }
+
+    /// A mailbox storing its items as `<id>.<extension>` files below `base_path`
+    ///
+    /// Nothing is checked or created yet, see [MailboxDisk::open] for that.
+    pub fn at(base_path: &Path, extension: &Path) -> Self {
+        Self::in_storage(FsStorage::new(), base_path, extension)
+    }
+
+    /// Like [MailboxDisk::at], but creates `base_path` if needed, and checks the configuration upfront
+    ///
+    /// Fails with [MailboxError::StorageNotADirectory], [MailboxError::StorageNotWritable]
+    /// or [MailboxError::InvalidExtension], instead of on the first send.
+    /// The base path is canonicalized, so later changes of the working directory don't matter.
+    pub fn open(base_path: &Path, extension: &Path) -> Result<Self> {
+        Self::open_checked(base_path, extension, true)
+    }
+
+    /// Like [MailboxDisk::open], but fails with [MailboxError::StorageNotFound] instead of creating `base_path`
+    ///
+    /// Note: This still checks that the storage is writable, use [MailboxDisk::at] with [MailboxDisk::read_only] for read-only storage.
+    pub fn open_existing(base_path: &Path, extension: &Path) -> Result<Self> {
+        Self::open_checked(base_path, extension, false)
+    }
+
+    fn open_checked(base_path: &Path, extension: &Path, auto_create: bool) -> Result<Self> {
+        validate_extension(extension)?;
+        let not_writable = |reason: String| MailboxError::StorageNotWritable {
+            path: base_path.to_path_buf(),
+            reason,
+        };
+        match fs::metadata(base_path) {
+            Ok(m) if !m.is_dir() => {
+                let path = base_path.to_path_buf();
+                return Err(MailboxError::StorageNotADirectory { path }.into());
+            }
+            Ok(_) => {}
+            Err(_) if auto_create => {
+                fs::create_dir_all(base_path)
+                    .map_err(|e| not_writable(format!("Can't create it -> {e}")))?;
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                let path = base_path.to_path_buf();
+                return Err(MailboxError::StorageNotFound { path }.into());
+            }
+            Err(e) => return Err(e).wrap_err_with(|| format!("Can't open {base_path:?}")),
+        }
+        let base_path = base_path
+            .canonicalize()
+            .wrap_err_with(|| format!("Can't canonicalize {base_path:?}"))?;
+
+        crate::fs_storage::probe_writable(&base_path).map_err(not_writable)?;
+
+        Ok(Self::at(&base_path, extension))
+    }
 }
 
-impl<ITEM: MailboxItem> MailboxDisk<ITEM> {
+impl<ITEM: MailboxItem, S: MailboxStorage> MailboxKv<ITEM, S> {
     pub async fn ensure_folder_exists(&mut self) -> Result<()> {
-        std::fs::create_dir_all(&self.base_path)
-            .map_err(|e| eyre!("Could not create folder {:?} -> {e}", &self.base_path))?;
+        self.storage
+            .create_folder(&self.base_path)
+            .await
+            .wrap_err_with(|| format!("Could not create folder {:?}", &self.base_path))?;
+
+        Ok(())
+    }
+
+    /// Day folders are created on demand
+    async fn ensure_item_folder_exists(&self, item_path: &Path) -> Result<()> {
+        if let Some(p) = item_path.parent() {
+            self.storage
+                .create_folder(p)
+                .await
+                .wrap_err_with(|| format!("Could not create folder {p:?}"))?;
+        }
 
         Ok(())
     }
 
     async fn ensure_mailbox_folder_exists(&self, mailbox_id: &str) -> Result<()> {
-        let p = self.mailbox_path(mailbox_id);
-        std::fs::create_dir_all(&p).map_err(|e| eyre!("Could not create folder {:?} -> {e}", p))?;
+        let p = self.mailbox_path(mailbox_id).await;
+        self.storage
+            .create_folder(&p)
+            .await
+            .wrap_err_with(|| format!("Could not create folder {p:?}"))?;
 
         Ok(())
     }
-    pub async fn new(base_path: &Path, extension: &Path) -> Self {
+
+    /// A mailbox storing its items as `<id>.<extension>` keys below `base_path` in `storage`
+    ///
+    /// Like [MailboxDisk::at], nothing is checked or created yet.
+    pub fn in_storage(storage: S, base_path: &Path, extension: &Path) -> Self {
         Self {
+            storage: Arc::new(storage),
             base_path: base_path.to_path_buf(),
             extension: extension.to_path_buf(),
             item_type: PhantomData,
             lock_semaphore: Semaphore::new(1),
+            heal_id_collisions: false,
+            read_only: false,
+            layout: EnvelopeLayout::default(),
+            id_scheme: IdScheme::default(),
+            ulids: UlidGenerator::default(),
+            clock: SharedClock::default(),
+            sidecar_threshold: None,
+            status_files: false,
+            signer: None,
+            accept_unsigned: false,
+            encode_ids: false,
+            strict: false,
+            id_counters: None,
+            limits: Limits::default(),
+            soft_limits: SoftLimits::default(),
+            metas: MetaState::default(),
+            delivery: Delivery::default(),
+            ownership: Ownership::default(),
+            default_headers: HashMap::new(),
+            decorator: None,
+            trash: false,
+            retention: None,
+            corruption_policy: CorruptionPolicy::default(),
+            op_timeout: None,
+            lock_timeout: None,
+            #[cfg(feature = "failpoints")]
+            failpoints: None,
+            space_freed: Default::default(),
+            unfrozen: Default::default(),
+            events: EventBus::new(DEFAULT_EVENT_CAPACITY),
         }
     }
 
-    fn mailbox_path(&self, mailbox_id: &str) -> PathBuf {
-        let mut p = PathBuf::new();
-        p.push(&self.base_path);
-        let idp = Path::new(mailbox_id);
-        p.push(idp);
+    /// Where the files are kept, e.g. to look at a [crate::MemoryStorage] in tests
+    pub fn storage(&self) -> &S {
+        &self.storage
+    }
 
-        p
+    /// If there is a file, or folder, at `p`
+    ///
+    /// Note: Like a failing `fs::metadata`, errors count as missing.
+    async fn exists_at(&self, p: &Path) -> bool {
+        self.storage.exists(p).await.unwrap_or(false)
     }
 
-    fn item_path(&self, mailbox_id: &str, item_id: &str) -> PathBuf {
-        let mut p = self.mailbox_path(mailbox_id);
-        let idp = Path::new(item_id);
-        p.push(idp);
-        p.set_extension(&self.extension);
+    /// The problem with the storage, if any, see [Mailbox::health_check]
+    async fn storage_problem(&self) -> Option<String> {
+        if self.lock_semaphore.is_closed() {
+            return Some("The mailbox is closed".to_string());
+        }
+        self.storage.problem(&self.base_path, !self.read_only).await
+    }
 
-        p
+    /// Append meta updates to a journal, instead of rewriting the whole meta every time
+    ///
+    /// The meta itself is only rewritten every `checkpoint_every` updates, or on [MailboxDisk::checkpoint].
+    /// Mailboxes with a journal can still be opened without journaling, the journal is folded in on the next update.
+    pub fn with_journal(mut self, checkpoint_every: usize) -> Self {
+        self.metas.checkpoint_every = Some(checkpoint_every.max(1));
+        self
     }
-    fn meta_path(&self, mailbox_id: &str) -> PathBuf {
-        let mut p = self.mailbox_path(mailbox_id);
-        let idp = Path::new("mailbox_meta");
-        p.push(idp);
-        p.set_extension("json");
 
-        p
+    /// Allocate ids from a per-mailbox counter, so `send` doesn't have to load and save the meta
+    ///
+    /// Sends only take the global lock once per mailbox, to set up the counter.
+    /// Sent items are added to the meta the next time it is loaded, e.g. by `receive`,
+    /// in id order, and only once all lower ids are either sent or failed.
+    /// Ids of failed sends are skipped, so ids can have gaps.
+    ///
+    /// Note: The counter lives in this instance, other instances only see the items once they are in the meta.
+    pub fn with_id_counter(mut self) -> Self {
+        self.id_counters = Some(Default::default());
+        self
     }
 
-    async fn ensure_meta(&self, mailbox_id: &str) -> Result<MailboxMeta> {
-        self.ensure_mailbox_folder_exists(mailbox_id).await?;
+    /// On an id collision, skip past all existing items instead of failing with [MailboxError::IdCollision]
+    ///
+    /// Note: Collisions only happen if the meta was lost or reset,
+    /// so the skipped items are most likely delivered again.
+    pub fn with_id_collision_healing(mut self) -> Self {
+        self.heal_id_collisions = true;
+        self
+    }
 
-        let p = self.meta_path(mailbox_id);
-        tracing::debug!("{p:?}");
-        let meta = if fs::metadata(&p).is_ok() {
-            // load
-            tracing::debug!("Loading existing meta for {mailbox_id}.");
-            let meta = MailboxMeta::load_from(&p).await?;
-            meta
-        } else {
-            // create
-            tracing::debug!("Meta for {mailbox_id} does not exist -> creating!");
-            let meta = MailboxMeta::default();
-            meta.save(&p).await?;
-            meta
-        };
+    pub fn with_delivery_mode(mut self, delivery_mode: DeliveryMode) -> Self {
+        self.delivery.mode = delivery_mode;
+        self
+    }
 
-        Ok(meta)
+    pub fn delivery_mode(&self) -> DeliveryMode {
+        self.delivery.mode
     }
-}
 
-#[async_trait]
-impl<ITEM: MailboxItem + std::marker::Send> Mailbox<ITEM> for MailboxDisk<ITEM> {
-    async fn ensure_storage_exists(&mut self) -> Result<()> {
-        self.ensure_folder_exists().await
+    /// Never write anything, e.g. for inspecting a read-only mount
+    ///
+    /// Everything that would write fails with [MailboxError::ReadOnly], including `receive`,
+    /// since it counts deliveries.
+    /// Mailboxes that don't exist are reported as [MailboxError::UnknownMailbox], instead of being created.
+    pub fn read_only(mut self) -> Self {
+        self.read_only = true;
+        self
     }
 
-    async fn send(&self, mailbox_id: &str, item: ITEM) -> Result<String> {
-        // Note: we take a global lock for all mailboxes :(
-        // You should not use disk storage in high load scenarios anyway -- for now
-        let _sem = self.lock_semaphore.acquire().await?;
-        //self.ensure_mailbox_folder_exists(id).await?;
-        let mut meta = self.ensure_meta(mailbox_id).await?;
-        tracing::debug!("Before Meta: {meta:?}");
+    /// Only use mailboxes created with `create_mailbox`, instead of creating them on first use
+    ///
+    /// Everything else fails with [MailboxError::UnknownMailbox] for mailboxes that don't exist,
+    /// so a typo in a mailbox id doesn't silently create a new mailbox.
+    pub fn strict(mut self) -> Self {
+        self.strict = true;
+        self
+    }
 
-        let item_id = meta.next_id().await?;
-        let data = item.serialize()?;
-        let mut e = Envelope::new(&item_id, data);
-        let _ = e.add_debug(); // for debugging
-        tracing::debug!("{e:?}");
+    /// Limit the number of pending items per mailbox to `max_pending`
+    ///
+    /// `backpressure` decides if sends to a full mailbox fail, or wait for items to be acknowledged.
+    /// A `send_transaction` with more than `max_pending` items always fails with [MailboxError::MailboxFull].
+    ///
+    /// Note: Only acknowledges on this instance wake waiting sends.
+    /// With [MailboxDisk::with_id_counter], concurrent sends can overshoot the limit by one send each.
+    pub fn with_capacity(mut self, max_pending: u64, backpressure: Backpressure) -> Self {
+        self.limits.capacity = Some((max_pending, backpressure));
+        self
+    }
 
-        let p = self.item_path(mailbox_id, &item_id);
-        e.save(&p).await?;
+    /// Fail sends with [MailboxError::StorageFull] once the file system of the base path has less than `bytes` free
+    ///
+    /// The check happens before anything is written, so the remaining space is left for acknowledging and compacting.
+    /// Note: The free space is looked up at most every [FREE_SPACE_TTL], sends in between use the last value.
+    pub fn with_min_free_space(mut self, bytes: u64) -> Self {
+        self.limits.min_free_space = Some(bytes);
+        self
+    }
 
-        tracing::debug!("After Meta: {meta:?}");
-        meta.save(&self.meta_path(&mailbox_id)).await?;
+    /// Reject items with a serialized size above `bytes` with [MailboxError::ItemTooLarge]
+    ///
+    /// Applies to sends, streams, and updates. Items already stored are still delivered.
+    pub fn with_max_item_size(mut self, bytes: u64) -> Self {
+        self.limits.max_item_size = Some(bytes);
+        self
+    }
 
-        Ok(item_id)
+    /// Limit the items of all mailboxes in a namespace together, failing sends with [MailboxError::QuotaExceeded]
+    ///
+    /// Items count from their send until they are compacted, or their mailbox is purged.
+    /// The usage is kept per namespace below the base path, see [MailboxDisk::quota_usage] and [MailboxDisk::recount].
+    /// Note: Not supported with [MailboxDisk::with_id_counter], or [IdScheme::Ulid].
+    /// Moves, e.g. by [MailboxDisk::defer], are counted, but never refused.
+    pub fn with_quota(mut self, quota: QuotaManager) -> Self {
+        self.limits.quota = Some(quota);
+        self
     }
-    async fn receive(&self, mailbox_id: &str) -> Result<Option<(String, ITEM)>> {
-        // Note: we take a global lock for all mailboxes :(
-        // You should not use disk storage in high load scenarios anyway -- for now
-        let _sem = self.lock_semaphore.acquire().await?;
-        //self.ensure_mailbox_folder_exists(id).await?;
-        let meta = self.ensure_meta(mailbox_id).await?;
-        tracing::debug!("Before Meta: {meta:?}");
 
-        if !meta.any_unread().await? {
-            Ok(None)
-        } else {
-            let item_id = meta.lowest_unread_id().await?;
-            let p = self.item_path(mailbox_id, &item_id);
-            match Envelope::load_from(&p).await {
-                Ok(e) => {
-                    let data = e.data()?;
-                    let item = ITEM::deserialize(&data)?;
-                    Ok(Some((item_id, item)))
-                }
-                Err(e) => {
-                    Err(eyre!("Broken mailbox {mailbox_id} can't load {item_id} -> {e:?}").into())
-                }
-            }
-        }
-        //Ok()
+    /// Warn `sink` once sends cross one of the soft limits of `thresholds`, the sends still go through
+    ///
+    /// Each limit fires once per mailbox, and is re-armed by a send that stays below it by the hysteresis,
+    /// e.g. after draining the mailbox. The soft limits for capacity and item size only apply with a hard one.
+    pub fn with_thresholds(
+        mut self,
+        thresholds: Thresholds,
+        sink: impl WarningSink + 'static,
+    ) -> Self {
+        self.soft_limits.thresholds = Some((thresholds, SharedWarningSink::new(sink)));
+        self
     }
-    async fn acknowledge(&self, mailbox_id: &str, item_id: &str) -> Result<()> {
-        // Note: we take a global lock for all mailboxes :(
-        // You should not use disk storage in high load scenarios anyway -- for now
-        let _sem = self.lock_semaphore.acquire().await?;
-        //self.ensure_mailbox_folder_exists(id).await?;
-        let mut meta = self.ensure_meta(mailbox_id).await?;
-        tracing::debug!("Before Meta: {meta:?}");
 
-        let p = self.item_path(mailbox_id, &item_id);
-        let mut envelope = match Envelope::load_from(&p).await {
-            Ok(e) => e,
-            Err(e) => {
-                return Err(
-                    eyre!("Broken mailbox {mailbox_id} can't load {item_id} -> {e:?}").into(),
-                )
-            }
-        };
+    /// Add `headers` to every item sent, the headers of the item win if they have the same key
+    ///
+    /// Note: Deferred and updated items keep the headers they were sent with.
+    pub fn with_default_headers(mut self, headers: HashMap<String, String>) -> Self {
+        self.default_headers = headers;
+        self
+    }
 
-        tracing::debug!("{envelope:?}");
-        if envelope.read() {
-            tracing::warn!(
-                "Trying to acknowledge message {mailbox_id} {item_id} that is already read!"
-            );
-        }
-        envelope.mark_read();
+    /// Call `f` for every item sent, after the default headers are merged, to add headers like a request id
+    ///
+    /// Runs once per item, inside the lock, so keep it cheap.
+    pub fn with_decorator(
+        mut self,
+        f: impl Fn(&mut EnvelopeDraft) + Send + Sync + 'static,
+    ) -> Self {
+        self.decorator = Some(Decorator::new(f));
+        self
+    }
 
-        let id = item_id.parse::<u64>()?;
-        meta.mark_read(id).await?;
+    /// Stop [MailboxDisk::defer] from deferring an item more than `max_defers` times
+    ///
+    /// Beyond that, the item is moved to the `dead_letter` mailbox, or `defer` fails with [MailboxError::DeferLimitReached].
+    pub fn with_defer_limit(mut self, max_defers: u32, dead_letter: Option<&str>) -> Self {
+        self.delivery.defer_limit = Some((max_defers, dead_letter.map(String::from)));
+        self
+    }
 
-        envelope.save(&p).await?;
+    /// Make compaction move items to the `.trash` folder of the mailbox, instead of removing them
+    ///
+    /// They can be brought back with [MailboxDisk::restore_from_trash], until [MailboxDisk::empty_trash] removes them.
+    pub fn with_trash(mut self) -> Self {
+        self.trash = true;
+        self
+    }
 
-        tracing::debug!("After Meta: {meta:?}");
-        meta.save(&self.meta_path(&mailbox_id)).await?;
+    /// Keep at most `policy.max_items` items per mailbox, trimming the oldest after every send
+    ///
+    /// Trimming removes items like compaction does, so items not read by every consumer group are kept.
+    /// [MailboxDisk::set_retention] overrides the policy for a single mailbox.
+    ///
+    /// Note: Not applied with [MailboxDisk::with_id_counter], or [IdScheme::Ulid].
+    pub fn with_retention(mut self, policy: RetentionPolicy) -> Self {
+        self.retention = Some(policy);
+        self
+    }
 
-        Ok(())
+    /// Receive items by their [crate::PRIORITY_HEADER], instead of oldest first
+    ///
+    /// [MailboxDisk::set_fairness] overrides the policy for a single mailbox.
+    /// Note: Every unread item is loaded to find the next one, so this gets slow with many unread items.
+    pub fn with_fairness(mut self, policy: FairnessPolicy) -> Self {
+        self.delivery.fairness = Some(policy);
+        self
     }
-}
 
-#[derive(Debug, Serialize, Deserialize)]
-struct MailboxMeta {
-    highest_used_id: u64,
-    lowest_unread_id: u64,
-    read_ids: HashSet<u64>, // Note: this only contains ids above the lowest_unread_id
-}
+    /// Let ownerships taken by [MailboxDisk::create_mailbox_exclusive] expire after `ttl`, unless renewed
+    ///
+    /// Expired mailboxes can be claimed by somebody else, so a crashed owner doesn't keep them forever.
+    pub fn with_ownership_ttl(mut self, ttl: Duration) -> Self {
+        self.ownership.ttl = Some(ttl);
+        self
+    }
 
-impl Default for MailboxMeta {
-    fn default() -> Self {
-        Self {
-            highest_used_id: 0,
-            lowest_unread_id: 1,
-            read_ids: Default::default(),
-        }
+    /// Fail sends and receives to owned mailboxes with [MailboxError::NotOwner], unless this instance holds the token
+    ///
+    /// Tokens are held after [MailboxDisk::create_mailbox_exclusive], or [MailboxDisk::hold_ownership].
+    /// Mailboxes without an owner, or with an expired one, take everybody.
+    pub fn with_ownership_checks(mut self) -> Self {
+        self.ownership.checks = true;
+        self
     }
-}
 
-impl MailboxMeta {
-    async fn load_from(path: &Path) -> Result<Self> {
-        let mut m = MailboxMeta::default();
-        m.load(path).await?;
+    /// Decide what `receive`, `receive_many` and `receive_matching` do with items that can't be loaded, decoded, or deserialized
+    ///
+    /// The default is [CorruptionPolicy::Fail]. I/O errors that might go away are always returned.
+    pub fn with_corruption_policy(mut self, policy: CorruptionPolicy) -> Self {
+        self.corruption_policy = policy;
+        self
+    }
 
-        Ok(m)
+    /// Fail `send`, `send_transaction`, `receive`, `receive_many`, `acknowledge`, `compact` and `drop_older_than`
+    /// with [MailboxError::Timeout], when they take longer than `timeout`
+    ///
+    /// This includes waiting for the lock, for space with [Backpressure::Wait], and for the storage.
+    /// A timed out operation is dropped, so it is undone, or done, like any dropped operation, see [MailboxDisk].
+    /// Note: [FsStorage] still finishes the file system calls already started in the background,
+    /// before the next ones on the same files.
+    pub fn with_op_timeout(mut self, timeout: Duration) -> Self {
+        self.op_timeout = Some(timeout);
+        self
     }
-    async fn load(&mut self, path: &Path) -> Result<()> {
-        let b = fs::read(path).map_err(|e| eyre!("Can't load from {path:?} -> {e}"))?;
-        let m = serde_json::from_slice(&b)?;
-        *self = m;
 
-        Ok(())
+    /// Fail with [MailboxError::LockTimeout] after waiting longer than `timeout` for the lock
+    ///
+    /// The lock is shared by all mailboxes, so this usually means another operation is stuck.
+    pub fn with_lock_timeout(mut self, timeout: Duration) -> Self {
+        self.lock_timeout = Some(timeout);
+        self
     }
-    async fn save(&self, path: &Path) -> Result<()> {
-        let json = serde_json::to_string_pretty(&self)?;
-        let b: Vec<u8> = json.into();
-        fs::write(path, b).map_err(|e| eyre!("Can't save to {path:?}: {e:?}"))?;
-        Ok(())
+
+    /// How long a received item of a message group blocks its group, unless it is acknowledged or rejected
+    ///
+    /// Afterwards the consumer is considered gone, and the item is delivered again.
+    /// The default is [DEFAULT_MESSAGE_GROUP_TIMEOUT], see [MailboxDisk::send_grouped].
+    pub fn with_message_group_timeout(mut self, timeout: Duration) -> Self {
+        self.delivery.message_group_timeout = timeout;
+        self
     }
 
-    async fn next_id(&mut self) -> Result<String> {
-        self.highest_used_id += 1;
-        let id = self.highest_used_id;
-        let id = format!("{id}");
+    /// How long `expire_items` leaves a received item to its consumer, before it expires like an unread one
+    ///
+    /// The default is [DEFAULT_IN_FLIGHT_TIMEOUT].
+    /// Note: Items received before the time of delivery was recorded are not in flight.
+    pub fn with_in_flight_timeout(mut self, timeout: Duration) -> Self {
+        self.delivery.in_flight_timeout = timeout;
+        self
+    }
 
-        Ok(id)
+    /// Arrange the envelopes of new mailboxes, the default is [EnvelopeLayout::Flat]
+    ///
+    /// Note: Opening a mailbox that was created with a different layout fails with [MailboxError::LayoutMismatch].
+    pub fn with_layout(mut self, layout: EnvelopeLayout) -> Self {
+        self.layout = layout;
+        self
     }
 
-    async fn any_unread(&self) -> Result<bool> {
-        Ok(self.highest_used_id > self.lowest_unread_id)
+    pub fn layout(&self) -> EnvelopeLayout {
+        self.layout
     }
 
-    async fn lowest_unread_id(&self) -> Result<String> {
-        let id = self.lowest_unread_id;
-        let id = format!("{id}");
+    /// Assign ids of new mailboxes with `id_scheme`, the default is [IdScheme::Numeric]
+    ///
+    /// Mailboxes with [IdScheme::Ulid] are found by listing the mailbox folder, and don't support consumer groups,
+    /// snapshots, scanning or tailing. Their envelopes are always stored flat, whatever the layout.
+    ///
+    /// Note: Opening a mailbox that was created with a different scheme fails with [MailboxError::IdSchemeMismatch].
+    pub fn with_id_scheme(mut self, id_scheme: IdScheme) -> Self {
+        self.id_scheme = id_scheme;
+        self
+    }
 
-        Ok(id)
+    pub fn id_scheme(&self) -> IdScheme {
+        self.id_scheme
     }
 
-    async fn mark_read(&mut self, id: u64) -> Result<()> {
-        if id == self.lowest_unread_id {
-            self.lowest_unread_id += 1;
-        } else {
-            tracing::warn!("Out of order acknowledgement is not implemented.");
-        }
-        Ok(())
+    /// Keep the state that changes after sending in a status file next to the envelope, e.g. `17.status.json`
+    ///
+    /// The envelope is then written once, and never rewritten by `receive`, `acknowledge`, or `defer`,
+    /// which keeps them cheap for large embedded payloads.
+    /// Envelopes without a status file, e.g. from before, are read as they are, and get one on their next change.
+    /// Note: Older versions ignore the status files, and see the delivery counts from when the envelope was written.
+    pub fn with_status_files(mut self) -> Self {
+        self.status_files = true;
+        self
     }
-}
 
-#[derive(Debug, Default, Serialize, Deserialize)]
-struct Envelope {
-    id: String,
-    read: bool,
-    data: String,
-    debug: Option<String>,
-}
+    /// Keep the metas in memory between operations, instead of loading them, and replaying the journal, every time
+    ///
+    /// Use [CacheMode::Exclusive] only if nothing else writes the mailboxes,
+    /// and [CacheMode::Validate] if other instances, or processes, do.
+    /// The default is [CacheMode::Off].
+    pub fn with_meta_cache(mut self, mode: CacheMode) -> Self {
+        self.metas.cache_mode = mode;
+        self
+    }
 
-use base64::prelude::*;
+    /// How many [MailboxEvent]s a subscriber can fall behind, the default is [DEFAULT_EVENT_CAPACITY]
+    pub fn with_event_capacity(mut self, capacity: usize) -> Self {
+        self.events = EventBus::new(capacity);
+        self
+    }
 
-// assert_eq!(BASE64_STANDARD.decode(b"+uwgVQA=")?, b"\xFA\xEC\x20\x55\0");
-// assert_eq!(BASE64_STANDARD.encode(b"\xFF\xEC\x20\x55\0"), "/+wgVQA=");
-impl Envelope {
-    pub fn new(id: &str, data: Vec<u8>) -> Self {
-        let data = BASE64_STANDARD.encode(data);
-        Self {
-            id: String::from(id),
-            read: false,
-            data,
-            debug: None,
+    /// Every item sent, acknowledged, or dead lettered, and every mailbox becoming empty, or non-empty
+    ///
+    /// Events are only collected while anybody is subscribed, and sending them never blocks, or fails, the operation.
+    /// Note: Counted sends, see [MailboxDisk::with_id_counter], are announced once they are folded into the meta,
+    /// and deferring an item within its mailbox shows up as a new [MailboxEvent::ItemSent].
+    pub fn subscribe_events(&self) -> broadcast::Receiver<MailboxEvent> {
+        self.events.subscribe()
+    }
+
+    pub fn meta_cache_stats(&self) -> MetaCacheStats {
+        MetaCacheStats {
+            hits: self.metas.cache_hits.load(Ordering::Relaxed),
+            misses: self.metas.cache_misses.load(Ordering::Relaxed),
+            invalidations: self.metas.cache_invalidations.load(Ordering::Relaxed),
         }
     }
 
-    fn data(&self) -> Result<Vec<u8>> {
-        let data = &self.data;
-        let data = BASE64_STANDARD.decode(data)?;
-        Ok(data)
+    /// Store payloads larger than `bytes` in a sidecar file next to the envelope, e.g. `17.payload`
+    ///
+    /// The envelope only keeps the length and checksum of the payload,
+    /// so updating it, e.g. on `acknowledge`, stays cheap.
+    pub fn with_sidecar_threshold(mut self, bytes: usize) -> Self {
+        self.sidecar_threshold = Some(bytes);
+        self
     }
 
-    fn read(&self) -> bool {
-        self.read
+    /// Store mailboxes in folders named after an encoding of their id, so any id can be used
+    ///
+    /// E.g. email addresses, or ids that only differ in case on a case-insensitive file system.
+    /// The original id is kept in the meta, for `list_mailboxes`.
+    /// Mailboxes created without encoding are still found, as long as their folder exists.
+    pub fn with_encoded_ids(mut self) -> Self {
+        self.encode_ids = true;
+        self
     }
 
-    fn mark_read(&mut self) {
-        self.read = true;
+    /// Sign envelopes with HMAC-SHA256 under `key`, and reject envelopes that don't match
+    ///
+    /// The signature covers the id, the payload (or the checksum of its sidecar), `sent_at` and the headers.
+    /// The read flag and the delivery count are not signed, they change on every delivery,
+    /// and the meta is authoritative for them anyway.
+    /// Envelopes without a signature fail with [MailboxError::SignatureMismatch],
+    /// unless [MailboxDisk::accept_unsigned] is set.
+    pub fn with_signing_key(mut self, key: &[u8]) -> Self {
+        let mut signer = EnvelopeSigner::new(key);
+        signer.accept_unsigned = self.accept_unsigned;
+        self.signer = Some(signer);
+        self
     }
 
-    async fn load_from(path: &Path) -> Result<Self> {
-        let b = fs::read(path).map_err(|e| eyre!("Can't load from {path:?} -> {e}"))?;
-        let e = serde_json::from_slice(&b)?;
-        Ok(e)
+    /// Accept envelopes written before signing was enabled, they are signed when they are next updated
+    ///
+    /// Note: Can be called before, or after, [MailboxDisk::with_signing_key].
+    pub fn accept_unsigned(mut self) -> Self {
+        self.accept_unsigned = true;
+        if let Some(signer) = &mut self.signer {
+            signer.accept_unsigned = true;
+        }
+        self
     }
 
-    pub fn add_debug(&mut self) -> Result<&str> {
-        let data = &self.data;
-        let data = BASE64_STANDARD.decode(data)?;
-        let d = String::from_utf8(data).unwrap_or_default();
+    /// Use a different source for the current time, e.g. a [crate::MockClock] to simulate a date change in tests
+    ///
+    /// All timestamps, and every decision based on time, like TTLs, delays, and retention, use it.
+    /// Note: Stale temporary files are still found by their modification time, which is set by the file system.
+    pub fn with_clock(mut self, clock: impl Clock + 'static) -> Self {
+        self.clock = SharedClock::new(clock);
+        self
+    }
 
-        self.debug = Some(d);
-        Ok(&self.debug.as_ref().unwrap())
+    /// Let `injector` decide if operations fail at a [Failpoint], to test recovering from crashes
+    #[cfg(feature = "failpoints")]
+    pub fn with_failpoints(mut self, injector: Arc<dyn FailpointInjector>) -> Self {
+        self.failpoints = Some(injector);
+        self
     }
 
-    async fn save(&self, path: &Path) -> Result<()> {
-        let json = serde_json::to_string_pretty(&self)?;
-        let b: Vec<u8> = json.into();
-        fs::write(path, b).map_err(|e| eyre!("Can't save to {path:?}: {e:?}"))?;
+    fn failpoint(&self, failpoint: Failpoint) -> Result<()> {
+        #[cfg(feature = "failpoints")]
+        if let Some(injector) = &self.failpoints {
+            injector.reached(failpoint)?;
+        }
+        #[cfg(not(feature = "failpoints"))]
+        let _ = failpoint;
         Ok(())
     }
-}
-
-#[cfg(test)]
-mod tests {
-    use crate::Mailbox;
-    use crate::MailboxDisk;
-    use crate::MailboxItem;
-    use color_eyre::Result;
-    use serde::Deserialize;
-    use serde::Serialize;
-    use std::env;
-    use std::path::Path;
 
-    use test_log::test;
+    /// Like [MailboxDisk::failpoint], but the injector can pause there too
+    async fn reach(&self, failpoint: Failpoint) -> Result<()> {
+        #[cfg(feature = "failpoints")]
+        if let Some(pause) = self.failpoints.as_ref().and_then(|i| i.pause(failpoint)) {
+            tokio::time::sleep(pause).await;
+        }
+        self.failpoint(failpoint)
+    }
 
-    #[derive(Default, Debug, Serialize, Deserialize)]
-    struct TestItem {
-        data: String,
+    fn now(&self) -> DateTime<Utc> {
+        self.clock.now()
     }
 
-    impl TestItem {
-        fn new(data: String) -> Self {
-            Self { data }
+    fn check_writable(&self, op: &str) -> Result<()> {
+        if self.read_only {
+            return Err(MailboxError::ReadOnly { op: op.to_string() }.into());
         }
+        Ok(())
     }
 
-    impl MailboxItem for TestItem {
-        fn serialize(&self) -> Result<Vec<u8>> {
-            let json = serde_json::to_string_pretty(&self)?;
+    /// Takes the global lock, fails once closed
+    ///
+    /// One lock for all mailboxes of this instance, held by every operation reading or writing the metas,
+    /// so a heavily used instance serializes everything on it.
+    /// Carries out the releases recorded by [Mailbox::release_later] first.
+    /// Note: It doesn't cover other instances, or processes, on the same storage.
+    async fn lock(&self) -> Result<SemaphorePermit<'_>> {
+        let acquire = self.lock_semaphore.acquire();
+        let acquired = match self.lock_timeout {
+            Some(timeout) => tokio::time::timeout(timeout, acquire)
+                .await
+                .map_err(|_| MailboxError::LockTimeout { timeout })?,
+            None => acquire.await,
+        };
+        let permit = acquired.map_err(|_| MailboxError::Closed)?;
+        self.release_recorded().await;
 
-            Ok(json.into())
-        }
-        fn deserialize(data: &[u8]) -> Result<Self>
-        where
-            Self: Sized,
-        {
-            let i = serde_json::from_slice(&data)?;
+        Ok(permit)
+    }
 
-            Ok(i)
+    /// Note: A release that fails, or is dropped, only leaves its item in flight until it times out.
+    async fn release_recorded(&self) {
+        let released = std::mem::take(&mut *self.delivery.released.lock().unwrap());
+        for (mailbox_id, item_id) in released {
+            match self
+                .reject_unlocked(&mailbox_id, &item_id, Duration::ZERO)
+                .await
+            {
+                Ok(()) => {}
+                // Note: e.g. received with [DeliveryMode::AtMostOnce]
+                Err(e) if is_already_read(&e) => {}
+                Err(e) => {
+                    tracing::warn!("Can't release item {item_id} of mailbox {mailbox_id} -> {e}")
+                }
+            }
         }
     }
 
-    #[test(tokio::test)]
-    async fn it_debugs() -> Result<()> {
-        let mut path = env::current_dir()?;
-        path.push("data");
-        path.push("test_items");
-        let extension = Path::new("test_item");
+    /// Runs `op` within the [MailboxDisk::with_op_timeout]
+    ///
+    /// Note: On timeout `f` is dropped wherever it waits, its [CancelGuard]s clean up.
+    async fn timed<T>(
+        &self,
+        op: &str,
+        mailbox_id: &str,
+        f: impl std::future::Future<Output = Result<T>>,
+    ) -> Result<T> {
+        let Some(timeout) = self.op_timeout else {
+            return f.await;
+        };
+        match tokio::time::timeout(timeout, f).await {
+            Ok(r) => r,
+            Err(_) => Err(MailboxError::Timeout {
+                op: op.to_string(),
+                mailbox_id: mailbox_id.to_string(),
+            }
+            .into()),
+        }
+    }
 
-        let mailbox = MailboxDisk::<TestItem>::new(&path, &extension).await;
-        println!("{mailbox:?}");
+    /// Runs `f`, unless `cancel` fires first, then `f` is dropped
+    ///
+    /// Note: Only for waiting, `f` must not have written anything yet.
+    async fn unless_cancelled<T>(
+        op: &str,
+        mailbox_id: &str,
+        cancel: Option<&CancellationToken>,
+        f: impl std::future::Future<Output = Result<T>>,
+    ) -> Result<T> {
+        let Some(cancel) = cancel else {
+            return f.await;
+        };
+        tokio::select! {
+            biased;
+            _ = cancel.cancelled() => Err(MailboxError::Cancelled {
+                op: op.to_string(),
+                mailbox_id: mailbox_id.to_string(),
+            }
+            .into()),
+            r = f => r,
+        }
+    }
+
+    /// Fails with [MailboxError::Cancelled] if `cancel` fired, the last chance before committing
+    fn check_cancelled(
+        op: &str,
+        mailbox_id: &str,
+        cancel: Option<&CancellationToken>,
+    ) -> Result<()> {
+        if cancel.is_some_and(|cancel| cancel.is_cancelled()) {
+            return Err(MailboxError::Cancelled {
+                op: op.to_string(),
+                mailbox_id: mailbox_id.to_string(),
+            }
+            .into());
+        }
+        Ok(())
+    }
+
+    /// Fails with [MailboxError::StorageFull] if there is less than [MailboxDisk::with_min_free_space] left
+    ///
+    /// Note: Storages that can't tell their free space are never full.
+    async fn check_free_space(&self) -> Result<()> {
+        let Some(min_free_space) = self.limits.min_free_space else {
+            return Ok(());
+        };
+        let cached = *self.limits.free_space.lock().unwrap();
+        let available = match cached {
+            Some((at, available)) if at.elapsed() < FREE_SPACE_TTL => available,
+            _ => {
+                let available = self
+                    .storage
+                    .available_space(&self.base_path)
+                    .await
+                    .wrap_err_with(|| {
+                        format!("Can't get the free space of {:?}", self.base_path)
+                    })?;
+                let Some(available) = available else {
+                    return Ok(());
+                };
+                *self.limits.free_space.lock().unwrap() = Some((Instant::now(), available));
+                available
+            }
+        };
+        if let Some(soft) = self
+            .soft_limits
+            .thresholds
+            .as_ref()
+            .and_then(|(t, _)| t.free_space)
+        {
+            let rearm = self.rearm_limit(soft as f64, -1.0);
+            self.check_threshold(
+                "free_space",
+                "",
+                available <= soft,
+                available as f64 > rearm,
+                || ThresholdEvent::FreeSpace {
+                    path: self.base_path.clone(),
+                    available,
+                    threshold: soft,
+                },
+            );
+        }
+        if available < min_free_space {
+            return Err(MailboxError::StorageFull {
+                path: self.base_path.clone(),
+                available,
+                min_free_space,
+            }
+            .into());
+        }
+        Ok(())
+    }
+
+    /// Fails with [MailboxError::ItemTooLarge] above [MailboxDisk::with_max_item_size]
+    fn check_item_size(&self, mailbox_id: &str, size: u64) -> Result<()> {
+        let Some(limit) = self.limits.max_item_size else {
+            return Ok(());
+        };
+        if size > limit {
+            return Err(MailboxError::ItemTooLarge {
+                mailbox_id: mailbox_id.to_string(),
+                size,
+                limit,
+            }
+            .into());
+        }
+        if let Some(fraction) = self
+            .soft_limits
+            .thresholds
+            .as_ref()
+            .and_then(|(t, _)| t.item_size)
+        {
+            let soft = fraction * limit as f64;
+            let crossed = size as f64 >= soft;
+            let rearmed = (size as f64) < self.rearm_limit(soft, 1.0);
+            self.check_threshold("item_size", mailbox_id, crossed, rearmed, || {
+                ThresholdEvent::ItemSize {
+                    mailbox_id: mailbox_id.to_string(),
+                    size,
+                    limit,
+                }
+            });
+        }
+        Ok(())
+    }
+
+    /// Fails with [MailboxError::QuotaExceeded] if `added` doesn't fit into the quota of the namespace of the mailbox
+    ///
+    /// Note: Only holding the lock until the usage is charged keeps concurrent sends from overshooting.
+    async fn check_quota(&self, mailbox_id: &str, added: &QuotaUsage) -> Result<()> {
+        let Some(quota) = &self.limits.quota else {
+            return Ok(());
+        };
+        let namespace = quota.namespace(mailbox_id);
+        let usage = self.load_quota_usage(&namespace).await?;
+        match quota.exceeded(&usage, added) {
+            Some(which) => Err(MailboxError::QuotaExceeded { namespace, which }.into()),
+            None => Ok(()),
+        }
+    }
+
+    /// Quotas are only kept while the lock is held for the whole send
+    fn check_quota_supported(&self, op: &str) -> Result<()> {
+        if self.limits.quota.is_some()
+            && (self.id_scheme == IdScheme::Ulid || self.id_counters.is_some())
+        {
+            return Err(MailboxError::Unsupported {
+                op: op.to_string(),
+                reason: "quotas with ULIDs, or an id counter".to_string(),
+            }
+            .into());
+        }
+        Ok(())
+    }
+
+    async fn charge_quota(&self, mailbox_id: &str, added: QuotaUsage) -> Result<()> {
+        self.update_quota_usage(mailbox_id, |usage| {
+            usage.items = usage.items.saturating_add(added.items);
+            usage.bytes = usage.bytes.saturating_add(added.bytes);
+        })
+        .await
+    }
+
+    async fn release_quota(&self, mailbox_id: &str, freed: QuotaUsage) -> Result<()> {
+        self.update_quota_usage(mailbox_id, |usage| {
+            usage.items = usage.items.saturating_sub(freed.items);
+            usage.bytes = usage.bytes.saturating_sub(freed.bytes);
+        })
+        .await
+    }
+
+    /// Puts back the usage saved by [MailboxDisk::quota_undo], after a send failed before it committed
+    fn give_back_quota(&self, quota_undo: Vec<(PathBuf, Option<Vec<u8>>)>) {
+        for (key, value) in quota_undo {
+            self.storage.clone().undo(key, value);
+        }
+    }
+
+    /// The write putting back the usage of the namespace of the mailbox, as it is now
+    ///
+    /// Sends charge the quota before they commit, and give the charge back with this when they don't get there,
+    /// see [MailboxDisk::undo_until_committed].
+    async fn quota_undo(&self, mailbox_id: &str) -> Result<Vec<(PathBuf, Option<Vec<u8>>)>> {
+        let Some(quota) = &self.limits.quota else {
+            return Ok(Vec::new());
+        };
+        let p = self.quota_path(&quota.namespace(mailbox_id));
+        let usage = self
+            .storage
+            .get(&p)
+            .await
+            .wrap_err_with(|| format!("Can't read {p:?}"))?;
+
+        Ok(vec![(p, usage)])
+    }
+
+    async fn update_quota_usage(
+        &self,
+        mailbox_id: &str,
+        f: impl FnOnce(&mut QuotaUsage),
+    ) -> Result<()> {
+        let Some(quota) = &self.limits.quota else {
+            return Ok(());
+        };
+        let namespace = quota.namespace(mailbox_id);
+        let mut usage = self.load_quota_usage(&namespace).await?;
+        f(&mut usage);
+        self.save_quota_usage(&namespace, &usage).await
+    }
+
+    /// Hidden, so it is never mistaken for a mailbox
+    fn quota_path(&self, namespace: &str) -> PathBuf {
+        self.base_path
+            .join(".quota")
+            .join(format!("{}.json", mailbox_id_encoding::encode(namespace)))
+    }
+
+    async fn load_quota_usage(&self, namespace: &str) -> Result<QuotaUsage> {
+        let p = self.quota_path(namespace);
+        let json = self
+            .storage
+            .get(&p)
+            .await
+            .wrap_err_with(|| format!("Can't read {p:?}"))?;
+        match json {
+            Some(json) => {
+                serde_json::from_slice(&json).wrap_err_with(|| format!("Can't parse {p:?}"))
+            }
+            None => Ok(QuotaUsage::default()),
+        }
+    }
+
+    async fn save_quota_usage(&self, namespace: &str, usage: &QuotaUsage) -> Result<()> {
+        let p = self.quota_path(namespace);
+        if let Some(folder) = p.parent() {
+            self.storage
+                .create_folder(folder)
+                .await
+                .wrap_err_with(|| format!("Can't create {folder:?}"))?;
+        }
+        write_atomic(&*self.storage, &p, &serde_json::to_vec(usage)?).await
+    }
+
+    /// The items stored in the mailbox, read or not, and their serialized size
+    ///
+    /// Note: Envelopes that can't be loaded count without their size.
+    async fn stored_usage(&self, mailbox_id: &str) -> Result<QuotaUsage> {
+        let mut usage = QuotaUsage::default();
+        if !self.exists_at(&self.mailbox_path(mailbox_id).await).await {
+            return Ok(usage);
+        }
+        for (_id, p) in self.item_files(mailbox_id).await? {
+            usage.items += 1;
+            usage.bytes += self.stored_len(&p).await;
+        }
+        Ok(usage)
+    }
+
+    async fn stored_len(&self, p: &Path) -> u64 {
+        let envelope = Envelope::load_from(&*self.storage, p).await.ok();
+        envelope
+            .and_then(|e| e.payload_len().ok())
+            .unwrap_or_default()
+    }
+
+    /// `soft` moved away from the limit by the hysteresis, `direction` is -1 for limits on what is left
+    fn rearm_limit(&self, soft: f64, direction: f64) -> f64 {
+        let hysteresis = self
+            .soft_limits
+            .thresholds
+            .as_ref()
+            .map(|(t, _)| t.hysteresis)
+            .unwrap_or_default();
+        soft * (1.0 - direction * hysteresis)
+    }
+
+    /// Warns once `crossed` a soft limit, and again only after it was `rearmed`
+    fn check_threshold(
+        &self,
+        kind: &'static str,
+        mailbox_id: &str,
+        crossed: bool,
+        rearmed: bool,
+        event: impl FnOnce() -> ThresholdEvent,
+    ) {
+        let Some((_thresholds, sink)) = &self.soft_limits.thresholds else {
+            return;
+        };
+        let key = (kind, mailbox_id.to_string());
+        let fire = {
+            let mut warned = self.soft_limits.warned.lock().unwrap();
+            if crossed {
+                warned.insert(key)
+            } else {
+                if rearmed {
+                    warned.remove(&key);
+                }
+                false
+            }
+        };
+        if fire {
+            sink.warn(event());
+        }
+    }
+
+    fn serialize_checked(&self, mailbox_id: &str, item: &ITEM) -> Result<Vec<u8>> {
+        let data = item.serialize()?;
+        self.check_item_size(mailbox_id, data.len() as u64)?;
+        Ok(data)
+    }
+
+    /// Takes the global lock once `count` more items fit into the mailbox
+    async fn lock_with_space(
+        &self,
+        mailbox_id: &str,
+        count: usize,
+    ) -> Result<(SemaphorePermit<'_>, MailboxMeta)> {
+        self.lock_with_space_or_cancel(mailbox_id, count, None)
+            .await
+    }
+
+    /// Like [MailboxDisk::lock_with_space], but gives up waiting once `cancel` fires
+    async fn lock_with_space_or_cancel(
+        &self,
+        mailbox_id: &str,
+        count: usize,
+        cancel: Option<&CancellationToken>,
+    ) -> Result<(SemaphorePermit<'_>, MailboxMeta)> {
+        let lock = || Self::unless_cancelled("send", mailbox_id, cancel, self.lock());
+        self.check_free_space().await?;
+        let sem = lock().await?;
+        let meta = self.ensure_meta(mailbox_id).await?;
+        self.check_access(mailbox_id, &meta, false)?;
+        let Some((capacity, backpressure)) = meta.settings.capacity.or(self.limits.capacity) else {
+            return Ok((sem, meta));
+        };
+        drop(sem);
+        let full = || MailboxError::MailboxFull {
+            mailbox_id: mailbox_id.to_string(),
+            capacity,
+        };
+        if count as u64 > capacity {
+            return Err(full().into());
+        }
+        let deadline = match backpressure {
+            Backpressure::Wait {
+                timeout: Some(timeout),
+            } => Some(tokio::time::Instant::now() + timeout),
+            _ => None,
+        };
+        let space_freed = self
+            .space_freed
+            .lock()
+            .unwrap()
+            .entry(mailbox_id.to_string())
+            .or_default()
+            .clone();
+        loop {
+            // Note: created before checking, so an acknowledge in between still wakes us up
+            let freed = space_freed.notified();
+            let sem = lock().await?;
+            let meta = self.ensure_meta(mailbox_id).await?;
+            self.check_access(mailbox_id, &meta, false)?;
+            let pending = self.unread_item_ids(mailbox_id, &meta).await?.count() as u64;
+            if pending + count as u64 <= capacity {
+                if let Some(fraction) = self
+                    .soft_limits
+                    .thresholds
+                    .as_ref()
+                    .and_then(|(t, _)| t.capacity)
+                {
+                    let soft = fraction * capacity as f64;
+                    let level = (pending + count as u64) as f64;
+                    let rearmed = level < self.rearm_limit(soft, 1.0);
+                    self.check_threshold("capacity", mailbox_id, level >= soft, rearmed, || {
+                        ThresholdEvent::Capacity {
+                            mailbox_id: mailbox_id.to_string(),
+                            pending: pending + count as u64,
+                            capacity,
+                        }
+                    });
+                }
+                return Ok((sem, meta));
+            }
+            drop(sem);
+            let wait = async {
+                match (backpressure, deadline) {
+                    (Backpressure::Reject, _) => Err(full().into()),
+                    (Backpressure::Wait { .. }, None) => {
+                        freed.await;
+                        Ok(())
+                    }
+                    (Backpressure::Wait { .. }, Some(deadline)) => {
+                        tokio::time::timeout_at(deadline, freed).await.map_err(|_| {
+                            MailboxError::Timeout {
+                                op: "send".to_string(),
+                                mailbox_id: mailbox_id.to_string(),
+                            }
+                            .into()
+                        })
+                    }
+                }
+            };
+            Self::unless_cancelled("send", mailbox_id, cancel, wait).await?;
+        }
+    }
+
+    fn notify_space_freed(&self, mailbox_id: &str) {
+        if let Some(space_freed) = self.space_freed.lock().unwrap().get(mailbox_id) {
+            space_freed.notify_waiters();
+        }
+    }
+
+    /// Fold the journal of the mailbox into its meta
+    pub async fn checkpoint(&self, mailbox_id: &str) -> Result<()> {
+        self.check_writable("checkpoint")?;
+        let _sem = self.lock().await?;
+        let mut meta = self.ensure_meta(mailbox_id).await?;
+        if meta.journal_len > 0 || meta.journal_broken {
+            self.write_meta_snapshot(mailbox_id, &mut meta).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Rewrites the envelopes and the meta of the mailbox to the `target` version, see [LayoutVersion]
+    ///
+    /// Runs under the lock, and rewrites one envelope at a time, each one atomically,
+    /// so the items stay deliverable in order, even if the upgrade is interrupted.
+    /// Progress is tracked in `mailbox_meta.upgrade`, calling it again resumes the upgrade,
+    /// and the meta only records the new version once all envelopes are done.
+    /// Fails with [MailboxError::LayoutDowngrade] for a version older than the one of the mailbox.
+    /// With `dry_run` the envelopes that need rewriting are listed, but nothing is written.
+    ///
+    /// Note: Envelopes that can't be parsed, and items in the trash or in quarantine, are left as they are.
+    pub async fn upgrade_mailbox(
+        &self,
+        mailbox_id: &str,
+        target: LayoutVersion,
+        dry_run: bool,
+    ) -> Result<UpgradeReport> {
+        if !dry_run {
+            self.check_writable("upgrade_mailbox")?;
+        }
+        self.check_numeric_ids("upgrade_mailbox")?;
+        let _sem = self.lock().await?;
+        let mut meta = self.load_meta(mailbox_id, false).await?;
+        if target < meta.layout_version {
+            return Err(MailboxError::LayoutDowngrade {
+                mailbox_id: mailbox_id.to_string(),
+                found: meta.layout_version,
+                target,
+            }
+            .into());
+        }
+        let up = self.upgrade_path(mailbox_id).await;
+        let progress = match self
+            .storage
+            .get(&up)
+            .await
+            .wrap_err_with(|| format!("Can't load {up:?}"))?
+        {
+            Some(b) => Some(
+                serde_json::from_slice::<UpgradeProgress>(&b)
+                    .wrap_err_with(|| format!("Broken upgrade manifest {up:?}"))?,
+            ),
+            None => None,
+        };
+        if let Some(progress) = progress.as_ref().filter(|p| p.target != target) {
+            return Err(eyre!(
+                "Mailbox {mailbox_id} is being upgraded to {:?}, not {target:?}",
+                progress.target
+            ));
+        }
+
+        let mut report = UpgradeReport {
+            from: meta.layout_version,
+            to: target,
+            upgraded: Vec::new(),
+            resumed: progress.is_some(),
+        };
+        if meta.layout_version == target && progress.is_none() {
+            return Ok(report);
+        }
+        let mut progress = progress.unwrap_or(UpgradeProgress {
+            target,
+            upgraded_through: 0,
+        });
+        if !dry_run {
+            write_atomic(&*self.storage, &up, &serde_json::to_vec_pretty(&progress)?).await?;
+        }
+
+        let mut files = self.item_files(mailbox_id).await?;
+        files.sort_unstable();
+        for (id, p) in files {
+            if id <= progress.upgraded_through {
+                continue;
+            }
+            let b = read(&*self.storage, &p).await?;
+            // Note: not Envelope::load_from, the status file stays as it is
+            let Ok(mut e) = serde_json::from_slice::<Envelope>(&b) else {
+                tracing::warn!("Not upgrading broken item {id} in mailbox {mailbox_id}");
+                continue;
+            };
+            // Note: storages without modification times upgrade to the time of the upgrade
+            let written_at = stat(&*self.storage, &p)
+                .await?
+                .and_then(|stat| stat.modified)
+                .map_or_else(|| self.now(), DateTime::from);
+            if !e.upgrade_to(target, written_at)? {
+                continue;
+            }
+            report.upgraded.push(format!("{id}"));
+            if dry_run {
+                continue;
+            }
+            write_atomic(&*self.storage, &p, &e.to_json()?).await?;
+            self.failpoint(Failpoint::UpgradeEnvelopeWritten)?;
+            progress.upgraded_through = id;
+            write_atomic(&*self.storage, &up, &serde_json::to_vec_pretty(&progress)?).await?;
+        }
+        if dry_run {
+            return Ok(report);
+        }
+
+        meta.layout_version = target;
+        self.write_meta_snapshot(mailbox_id, &mut meta).await?;
+        remove_if_exists(&*self.storage, &up).await?;
+
+        Ok(report)
+    }
+
+    /// Copy the control state of the mailbox, e.g. before risky maintenance, see [MailboxDisk::restore_meta]
+    pub async fn snapshot_meta(&self, mailbox_id: &str) -> Result<MetaSnapshot> {
+        self.check_numeric_ids("snapshot_meta")?;
+        let _sem = self.lock().await?;
+        let meta = self.load_meta(mailbox_id, false).await?;
+        let mut item_ids: Vec<u64> = self
+            .item_files(mailbox_id)
+            .await?
+            .into_iter()
+            .map(|(id, _)| id)
+            .collect();
+        item_ids.sort_unstable();
+
+        Ok(MetaSnapshot {
+            captured_at: self.now(),
+            item_ids,
+            meta,
+        })
+    }
+
+    /// Roll the control state of the mailbox back to the snapshot
+    ///
+    /// The attributes are not part of the control state, they are kept as they are.
+    /// Fails with [MailboxError::SnapshotItemsMissing] if items from the snapshot have been removed since, e.g. by `compact`.
+    /// Items sent after the snapshot are kept, and are unread afterwards, their ids are never reused.
+    pub async fn restore_meta(&self, mailbox_id: &str, snapshot: &MetaSnapshot) -> Result<()> {
+        self.restore(mailbox_id, snapshot, false).await
+    }
+
+    /// Like [MailboxDisk::restore_meta], but also if items from the snapshot are missing
+    ///
+    /// Note: Receiving a missing item fails with [MailboxError::NotFound], until it is acknowledged.
+    pub async fn force_restore_meta(
+        &self,
+        mailbox_id: &str,
+        snapshot: &MetaSnapshot,
+    ) -> Result<()> {
+        self.restore(mailbox_id, snapshot, true).await
+    }
+
+    async fn restore(&self, mailbox_id: &str, snapshot: &MetaSnapshot, force: bool) -> Result<()> {
+        self.check_writable("restore_meta")?;
+        self.check_numeric_ids("restore_meta")?;
+        let _sem = self.lock().await?;
+        let current = self.load_meta(mailbox_id, false).await?;
+        if snapshot.meta.layout != self.layout {
+            return Err(MailboxError::LayoutMismatch {
+                mailbox_id: mailbox_id.to_string(),
+                expected: self.layout,
+                found: snapshot.meta.layout,
+            }
+            .into());
+        }
+        let present: HashSet<u64> = self
+            .item_files(mailbox_id)
+            .await?
+            .into_iter()
+            .map(|(id, _)| id)
+            .collect();
+        let missing: Vec<u64> = snapshot
+            .item_ids
+            .iter()
+            .filter(|id| !present.contains(id))
+            .copied()
+            .collect();
+        if !missing.is_empty() {
+            if !force {
+                return Err(MailboxError::SnapshotItemsMissing {
+                    mailbox_id: mailbox_id.to_string(),
+                    item_ids: missing.iter().map(|id| format!("{id}")).collect(),
+                }
+                .into());
+            }
+            tracing::warn!("Restoring mailbox {mailbox_id} without items {missing:?}");
+        }
+
+        let mut meta = snapshot.meta.clone();
+        meta.attrs = current.attrs.clone();
+        meta.owner = current.owner.clone();
+        meta.pending.clear();
+        meta.journal_len = current.journal_len;
+        meta.journal_broken = current.journal_broken;
+        // Note: the snapshot doesn't know the items sent since, they stay unread
+        if current.highest_used_id > meta.highest_used_id {
+            let sent_since = meta.highest_used_id + 1;
+            meta.days.extend(
+                current
+                    .days
+                    .range(sent_since..)
+                    .map(|(id, day)| (*id, *day)),
+            );
+            meta.highest_used_id = current.highest_used_id;
+            meta.total_sent = meta.total_sent.max(current.total_sent);
+            meta.last_send_at = current.last_send_at;
+        }
+        self.write_meta_snapshot(mailbox_id, &mut meta).await
+    }
+
+    /// Creates the mailbox, owned by `owner`, failing with [MailboxError::AlreadyOwned] if it exists already
+    ///
+    /// Of several instances, or processes, racing to create the same mailbox exactly one wins.
+    /// A mailbox whose ownership expired, see [MailboxDisk::with_ownership_ttl], is taken over instead.
+    /// Note: Taking over is claimed through the file system, so of several processes exactly one takes over.
+    pub async fn create_mailbox_exclusive(
+        &self,
+        mailbox_id: &str,
+        owner: &str,
+    ) -> Result<OwnershipToken> {
+        self.check_writable("create_mailbox_exclusive")?;
+        let _sem = self.lock().await?;
+        let now = self.now();
+        let token = OwnershipToken {
+            mailbox_id: mailbox_id.to_string(),
+            owner: owner.to_string(),
+            token: format!("{:032x}", fastrand::u128(..)),
+            expires_at: self.ownership_expiry(now),
+        };
+
+        self.ensure_mailbox_folder_exists(mailbox_id).await?;
+        let meta = MailboxMeta {
+            layout: self.layout,
+            id_scheme: self.id_scheme,
+            mailbox_id: self.encode_ids.then(|| mailbox_id.to_string()),
+            owner: Some(token.clone()),
+            ..Default::default()
+        };
+        // Note: the storage puts it only if the meta doesn't exist, so nobody ever sees a partial meta
+        let p = self.meta_path(mailbox_id).await;
+        let created = self
+            .storage
+            .put_if_absent(&p, &serde_json::to_vec_pretty(&meta)?)
+            .await
+            .wrap_err_with(|| format!("Can't create {p:?}"))?;
+        match created {
+            true => {}
+            false => {
+                let mut meta = self.load_meta(mailbox_id, false).await?;
+                match &meta.owner {
+                    Some(current) if current.is_expired_at(now) => {
+                        if let Some(winner) =
+                            self.claim_takeover(mailbox_id, current, owner).await?
+                        {
+                            return Err(MailboxError::AlreadyOwned {
+                                mailbox_id: mailbox_id.to_string(),
+                                owner: Some(winner),
+                            }
+                            .into());
+                        }
+                        tracing::warn!(
+                            "{owner} takes over mailbox {mailbox_id} from {}",
+                            current.owner
+                        );
+                        // Note: the meta might have changed while claiming
+                        self.forget_meta(mailbox_id);
+                        meta = self.load_meta(mailbox_id, false).await?;
+                    }
+                    current => {
+                        return Err(MailboxError::AlreadyOwned {
+                            mailbox_id: mailbox_id.to_string(),
+                            owner: current.as_ref().map(|current| current.owner.clone()),
+                        }
+                        .into());
+                    }
+                }
+                meta.owner = Some(token.clone());
+                self.write_meta_snapshot(mailbox_id, &mut meta).await?;
+            }
+        }
+        self.forget_meta(mailbox_id);
+        self.hold_ownership(token.clone());
+
+        Ok(token)
+    }
+
+    /// Gives up the ownership, the mailbox and its items stay
+    ///
+    /// Fails with [MailboxError::NotOwner] if the token doesn't own the mailbox (anymore).
+    pub async fn release_mailbox(&self, mailbox_id: &str, token: &OwnershipToken) -> Result<()> {
+        self.check_writable("release_mailbox")?;
+        let _sem = self.lock().await?;
+        let mut meta = self.owned_meta(mailbox_id, token).await?;
+        meta.owner = None;
+        self.write_meta_snapshot(mailbox_id, &mut meta).await?;
+        self.ownership.held.lock().unwrap().remove(mailbox_id);
+
+        Ok(())
+    }
+
+    /// Pushes the expiry of the ownership `ttl` into the future again, see [MailboxDisk::with_ownership_ttl]
+    ///
+    /// Expired ownerships can be renewed too, as long as nobody took the mailbox over.
+    pub async fn renew_ownership(&self, token: &OwnershipToken) -> Result<OwnershipToken> {
+        self.check_writable("renew_ownership")?;
+        let _sem = self.lock().await?;
+        let mut meta = self.owned_meta(&token.mailbox_id, token).await?;
+        if let Some(current) = meta.owner.as_ref().filter(|o| o.is_expired_at(self.now())) {
+            if self
+                .claim_takeover(&token.mailbox_id, current, &token.owner)
+                .await?
+                .is_some()
+            {
+                return Err(MailboxError::NotOwner {
+                    mailbox_id: token.mailbox_id.clone(),
+                }
+                .into());
+            }
+        }
+        let renewed = OwnershipToken {
+            expires_at: self.ownership_expiry(self.now()),
+            ..token.clone()
+        };
+        meta.owner = Some(renewed.clone());
+        self.write_meta_snapshot(&token.mailbox_id, &mut meta)
+            .await?;
+        self.hold_ownership(renewed.clone());
+
+        Ok(renewed)
+    }
+
+    /// Lets this instance pass [MailboxDisk::with_ownership_checks] with a token created elsewhere
+    pub fn hold_ownership(&self, token: OwnershipToken) {
+        self.ownership
+            .held
+            .lock()
+            .unwrap()
+            .insert(token.mailbox_id.clone(), token);
+    }
+
+    /// Claims the expired ownership for `owner`, returns who claimed it, if somebody else was first
+    ///
+    /// The claim is a file named after the token and its expiry, so a renewed ownership expires into a new claim.
+    /// Note: Claims are kept, so late racers still find them, they go with the mailbox folder.
+    async fn claim_takeover(
+        &self,
+        mailbox_id: &str,
+        expired: &OwnershipToken,
+        owner: &str,
+    ) -> Result<Option<String>> {
+        let generation = expired
+            .expires_at
+            .map(|t| t.timestamp_millis())
+            .unwrap_or_default();
+        let p = self.meta_path(mailbox_id).await;
+        let claim = p.with_file_name(format!(".takeover.{}.{generation}", expired.token));
+        // Note: the storage puts it only if the claim doesn't exist, so nobody ever sees a partial claim
+        let claimed = self
+            .storage
+            .put_if_absent(&claim, owner.as_bytes())
+            .await
+            .wrap_err_with(|| format!("Can't create {claim:?}"))?;
+        if claimed {
+            return Ok(None);
+        }
+        let winner = self
+            .storage
+            .get(&claim)
+            .await
+            .and_then(|winner| winner.ok_or_else(|| not_found().into()))
+            .wrap_err_with(|| format!("Can't read {claim:?}"))?;
+        Ok(Some(String::from_utf8_lossy(&winner).into_owned()))
+    }
+
+    fn ownership_expiry(&self, now: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        self.ownership
+            .ttl
+            .and_then(|ttl| chrono::Duration::from_std(ttl).ok())
+            .map(|ttl| now + ttl)
+    }
+
+    async fn owned_meta(&self, mailbox_id: &str, token: &OwnershipToken) -> Result<MailboxMeta> {
+        let meta = self.load_meta(mailbox_id, false).await?;
+        if token.mailbox_id != mailbox_id || !meta.owner.as_ref().is_some_and(|o| o.matches(token))
+        {
+            return Err(MailboxError::NotOwner {
+                mailbox_id: mailbox_id.to_string(),
+            }
+            .into());
+        }
+        Ok(meta)
+    }
+
+    /// The checks of [MailboxDisk::set_frozen], and [MailboxDisk::with_ownership_checks]
+    fn check_access(&self, mailbox_id: &str, meta: &MailboxMeta, receiving: bool) -> Result<()> {
+        Self::check_frozen(mailbox_id, meta, receiving)?;
+        let Some(owner) = meta.owner.as_ref().filter(|_| self.ownership.checks) else {
+            return Ok(());
+        };
+        if owner.is_expired_at(self.now()) {
+            return Ok(());
+        }
+        let held = self.ownership.held.lock().unwrap();
+        if !held
+            .get(mailbox_id)
+            .is_some_and(|token| token.matches(owner))
+        {
+            return Err(MailboxError::NotOwner {
+                mailbox_id: mailbox_id.to_string(),
+            }
+            .into());
+        }
+        Ok(())
+    }
+
+    /// Stops sends, receives, or both, for a single mailbox, e.g. during an incident
+    ///
+    /// Frozen operations fail with [MailboxError::Frozen] before anything is written.
+    /// The freeze is kept in the meta, so it survives restarts, and is honored by all instances.
+    /// Acknowledging, deferring, and peeking are never frozen.
+    /// Unfreezing wakes [MailboxDisk::receive_wait] callers of this instance right away,
+    /// callers of other instances find out on their next wakeup.
+    pub async fn set_frozen(&self, mailbox_id: &str, mode: FreezeMode) -> Result<()> {
+        self.check_writable("set_frozen")?;
+        let _sem = self.lock().await?;
+        let mut meta = self.ensure_meta(mailbox_id).await?;
+        let was = meta.frozen;
+        if was == mode {
+            return Ok(());
+        }
+        meta.frozen = mode;
+        // Note: freezes are rare, so they are not journaled
+        self.write_meta_snapshot(mailbox_id, &mut meta).await?;
+        if was.blocks_receive() && !mode.blocks_receive() {
+            if let Some(unfrozen) = self.unfrozen.lock().unwrap().get(mailbox_id) {
+                unfrozen.notify_waiters();
+            }
+        }
+
+        Ok(())
+    }
+
+    pub async fn frozen(&self, mailbox_id: &str) -> Result<FreezeMode> {
+        let _sem = self.lock().await?;
+        Ok(self
+            .find_meta(mailbox_id)
+            .await?
+            .map(|meta| meta.frozen)
+            .unwrap_or_default())
+    }
+
+    fn check_frozen(mailbox_id: &str, meta: &MailboxMeta, receiving: bool) -> Result<()> {
+        let blocked = if receiving {
+            meta.frozen.blocks_receive()
+        } else {
+            meta.frozen.blocks_send()
+        };
+        if blocked {
+            return Err(MailboxError::Frozen {
+                mailbox_id: mailbox_id.to_string(),
+                mode: meta.frozen,
+            }
+            .into());
+        }
+        Ok(())
+    }
+
+    /// Attach application data to the mailbox, e.g. its owner, an empty `value` removes the key
+    ///
+    /// Keys and values together are limited to 4 KiB per mailbox, see [MailboxError::AttributesTooLarge].
+    /// The attributes are kept in the meta, so they survive `compact`, and are readable when read-only.
+    pub async fn set_mailbox_attr(&self, mailbox_id: &str, key: &str, value: &str) -> Result<()> {
+        self.check_writable("set_mailbox_attr")?;
+        if key.is_empty() {
+            return Err(MailboxError::InvalidId {
+                id: key.to_string(),
+            }
+            .into());
+        }
+        let _sem = self.lock().await?;
+        let mut meta = self.ensure_meta(mailbox_id).await?;
+        if value.is_empty() {
+            if meta.attrs.remove(key).is_none() {
+                return Ok(());
+            }
+        } else {
+            let size = meta
+                .attrs
+                .iter()
+                .filter(|(k, _)| k.as_str() != key)
+                .map(|(k, v)| k.len() + v.len())
+                .sum::<usize>()
+                + key.len()
+                + value.len();
+            if size > MAX_MAILBOX_ATTRS_SIZE {
+                return Err(MailboxError::AttributesTooLarge {
+                    mailbox_id: mailbox_id.to_string(),
+                    size,
+                    limit: MAX_MAILBOX_ATTRS_SIZE,
+                }
+                .into());
+            }
+            meta.attrs.insert(key.to_string(), value.to_string());
+        }
+        // Note: attributes are rare, so they are not journaled
+        self.write_meta_snapshot(mailbox_id, &mut meta).await
+    }
+
+    pub async fn delete_mailbox_attr(&self, mailbox_id: &str, key: &str) -> Result<()> {
+        self.set_mailbox_attr(mailbox_id, key, "").await
+    }
+
+    /// If the mailbox was created, by `create_mailbox` or a send
+    ///
+    /// Note: Receiving from a mailbox that doesn't exist finds nothing, without creating it.
+    pub async fn exists(&self, mailbox_id: &str) -> Result<bool> {
+        let _sem = self.lock().await?;
+        Ok(self.exists_at(&self.meta_path(mailbox_id).await).await)
+    }
+
+    pub async fn get_mailbox_attr(&self, mailbox_id: &str, key: &str) -> Result<Option<String>> {
+        Ok(self.mailbox_attrs(mailbox_id).await?.remove(key))
+    }
+
+    pub async fn mailbox_attrs(&self, mailbox_id: &str) -> Result<HashMap<String, String>> {
+        let _sem = self.lock().await?;
+        let meta = self.load_meta(mailbox_id, false).await?;
+
+        Ok(meta.attrs)
+    }
+
+    /// Override the [RetentionPolicy] of the backend for a mailbox, `None` goes back to the one of the backend
+    ///
+    /// The policy is stored in the meta, and applied from the next send on.
+    pub async fn set_retention(
+        &self,
+        mailbox_id: &str,
+        policy: Option<RetentionPolicy>,
+    ) -> Result<()> {
+        self.check_writable("set_retention")?;
+        self.check_numeric_ids("set_retention")?;
+        let _sem = self.lock().await?;
+        let mut meta = self.ensure_meta(mailbox_id).await?;
+        meta.settings.retention = policy;
+        self.write_meta_snapshot(mailbox_id, &mut meta).await
+    }
+
+    /// Override the [FairnessPolicy] of the backend for a single mailbox, `None` uses the one of the backend
+    ///
+    /// The policy is stored in the meta, so every instance receiving from the mailbox applies it.
+    pub async fn set_fairness(
+        &self,
+        mailbox_id: &str,
+        policy: Option<FairnessPolicy>,
+    ) -> Result<()> {
+        self.check_writable("set_fairness")?;
+        let _sem = self.lock().await?;
+        let mut meta = self.ensure_meta(mailbox_id).await?;
+        meta.settings.fairness = policy;
+        self.write_meta_snapshot(mailbox_id, &mut meta).await
+    }
+
+    /// The [RetentionPolicy] applied to a mailbox
+    pub async fn retention(&self, mailbox_id: &str) -> Result<Option<RetentionPolicy>> {
+        let _sem = self.lock().await?;
+        let meta = self.load_meta(mailbox_id, false).await?;
+
+        Ok(meta.settings.retention.or(self.retention))
+    }
+
+    /// Override the configuration of the backend for a mailbox, replacing all earlier overrides
+    ///
+    /// The settings are stored in the meta, and apply from the next operation on.
+    /// Nothing already in the mailbox is changed, e.g. a lower capacity only holds back sends.
+    /// Note: The capacity is not applied with [MailboxDisk::with_id_counter], only the one of the backend.
+    pub async fn set_settings(&self, mailbox_id: &str, settings: MailboxSettings) -> Result<()> {
+        self.check_writable("set_settings")?;
+        if settings.retention.is_some() {
+            self.check_numeric_ids("set_settings")?;
+        }
+        let _sem = self.lock().await?;
+        let mut meta = self.ensure_meta(mailbox_id).await?;
+        meta.settings = settings;
+        self.write_meta_snapshot(mailbox_id, &mut meta).await?;
+        // Note: a higher capacity might let waiting sends through
+        self.notify_space_freed(mailbox_id);
+
+        Ok(())
+    }
+
+    /// The overrides set by [MailboxDisk::set_settings], without the configuration of the backend
+    pub async fn get_settings(&self, mailbox_id: &str) -> Result<MailboxSettings> {
+        let _sem = self.lock().await?;
+        let meta = self.load_meta(mailbox_id, false).await?;
+
+        Ok(meta.settings)
+    }
+
+    /// The delivery mode of a mailbox, read from its meta without taking the lock
+    ///
+    /// Note: Settings only change when the whole meta is replaced, so they can be read at any time.
+    async fn delivery_mode_of(&self, mailbox_id: &str) -> Result<DeliveryMode> {
+        #[derive(Deserialize)]
+        struct StoredSettings {
+            #[serde(default)]
+            settings: MailboxSettings,
+        }
+        let mp = self.meta_path(mailbox_id).await;
+        let stored = match self
+            .storage
+            .get(&mp)
+            .await
+            .wrap_err_with(|| format!("Can't load from {mp:?}"))?
+        {
+            Some(b) => {
+                let stored: StoredSettings = serde_json::from_slice(&b)
+                    .wrap_err_with(|| format!("Can't load settings from {mp:?}"))?;
+                stored.settings.delivery_mode
+            }
+            None => None,
+        };
+
+        Ok(stored.unwrap_or(self.delivery.mode))
+    }
+
+    /// Move an unread item behind all other pending items, returns its new id
+    ///
+    /// The payload and headers are sent again under a new id, with `deferred_count` one higher,
+    /// and the original is marked read, without counting as acknowledged.
+    /// Past the limit set by [MailboxDisk::with_defer_limit], the item is sent to the dead-letter mailbox instead,
+    /// and its id there is returned.
+    ///
+    /// Note: Not supported with [MailboxDisk::with_id_counter], or [IdScheme::Ulid].
+    pub async fn defer(&self, mailbox_id: &str, item_id: &str) -> Result<String> {
+        self.check_writable("defer")?;
+        self.check_numeric_ids("defer")?;
+        self.check_no_id_counter("defer")?;
+        let id = Self::parse_item_id(item_id)?;
+        let _sem = self.lock().await?;
+        let mut meta = self.ensure_meta(mailbox_id).await?;
+
+        let (p, mut envelope) = self.load_envelope(mailbox_id, &meta, item_id).await?;
+        if meta.is_read(item_id) || envelope.read() {
+            return Err(MailboxError::AlreadyRead {
+                mailbox_id: mailbox_id.to_string(),
+                item_id: item_id.to_string(),
+            }
+            .into());
+        }
+        let deferred_count = envelope.deferred_count + 1;
+        let defer_limit = meta
+            .settings
+            .defer_limit
+            .as_ref()
+            .or(self.delivery.defer_limit.as_ref());
+        let dead_letter = match defer_limit {
+            Some((limit, dead_letter)) if deferred_count > *limit => match dead_letter {
+                Some(dead_letter) => Some(dead_letter.clone()),
+                None => {
+                    return Err(MailboxError::DeferLimitReached {
+                        mailbox_id: mailbox_id.to_string(),
+                        item_id: item_id.to_string(),
+                        limit: *limit,
+                    }
+                    .into());
+                }
+            },
+            _ => None,
+        };
+
+        let hop = |action| ProvenanceEntry {
+            action,
+            from_mailbox: mailbox_id.to_string(),
+            from_item_id: item_id.to_string(),
+            at: self.now(),
+        };
+        // Note: a crash in between leaves the item in both places, never in neither
+        let (new_id, new_path) = match dead_letter.as_deref() {
+            Some(dead_letter) => {
+                let mut dead_meta = self.ensure_meta(dead_letter).await?;
+                let hop = hop(ProvenanceAction::DeadLettered);
+                let (new_id, _new_path) = self
+                    .resend(dead_letter, &mut dead_meta, &envelope, deferred_count, hop)
+                    .await?;
+                self.save_meta(dead_letter, &mut dead_meta).await?;
+                tracing::warn!("Moved {mailbox_id} {item_id} to {dead_letter} {new_id}");
+                (new_id, None)
+            }
+            None => {
+                let hop = hop(ProvenanceAction::Deferred);
+                let (new_id, new_path) = self
+                    .resend(mailbox_id, &mut meta, &envelope, deferred_count, hop)
+                    .await?;
+                (new_id, Some(new_path))
+            }
+        };
+        meta.record(JournalRecord::Drop { id, at: self.now() });
+        if let Err(e) = self.save_meta(mailbox_id, &mut meta).await {
+            if let Some(new_path) = new_path {
+                let _ = self.storage.delete(&sidecar_path(&new_path)).await;
+                let _ = self.storage.delete(&new_path).await;
+            }
+            return Err(e);
+        }
+        self.notify_space_freed(mailbox_id);
+        if let Some(dead_letter) = dead_letter {
+            if self.events.is_observed() {
+                let event = MailboxEvent::ItemDeadLettered {
+                    mailbox_id: mailbox_id.to_string(),
+                    item_id: item_id.to_string(),
+                    dead_letter,
+                    new_item_id: new_id.clone(),
+                };
+                self.publish_events(mailbox_id, vec![event], &meta).await;
+            }
+        }
+        envelope.mark_read();
+        self.save_status(&p, &mut envelope).await?;
+
+        Ok(new_id)
+    }
+
+    /// How often the item was deferred by [MailboxDisk::defer]
+    pub async fn deferred_count(&self, mailbox_id: &str, item_id: &str) -> Result<u32> {
+        let _sem = self.lock().await?;
+        let Some(meta) = self.find_meta(mailbox_id).await? else {
+            return Err(MailboxError::NotFound {
+                mailbox_id: mailbox_id.to_string(),
+                item_id: item_id.to_string(),
+            }
+            .into());
+        };
+
+        let (_p, envelope) = self.load_envelope(mailbox_id, &meta, item_id).await?;
+
+        Ok(envelope.deferred_count)
+    }
+
+    /// Where the item was moved from, oldest first, see [ProvenanceEntry]
+    ///
+    /// Empty for items that never moved, and for items stored before it was recorded.
+    pub async fn provenance(
+        &self,
+        mailbox_id: &str,
+        item_id: &str,
+    ) -> Result<Vec<ProvenanceEntry>> {
+        let _sem = self.lock().await?;
+        let Some(meta) = self.find_meta(mailbox_id).await? else {
+            return Err(MailboxError::NotFound {
+                mailbox_id: mailbox_id.to_string(),
+                item_id: item_id.to_string(),
+            }
+            .into());
+        };
+
+        let (_p, envelope) = self.load_envelope(mailbox_id, &meta, item_id).await?;
+
+        Ok(envelope.provenance)
+    }
+
+    /// Gives up on an item for now, it stays unread but is only delivered again once `delay` has passed
+    ///
+    /// Until then `receive` and friends skip it, and deliver the items behind it,
+    /// except for the ones in its message group, which keep waiting for it.
+    /// A `delay` of zero hands an item of a message group back right away.
+    /// `drain` leaves it unread too.
+    /// Note: Consumer groups ignore the delay.
+    pub async fn reject_with_delay(
+        &self,
+        mailbox_id: &str,
+        item_id: &str,
+        delay: Duration,
+    ) -> Result<()> {
+        self.check_writable("reject_with_delay")?;
+        let _sem = self.lock().await?;
+        self.reject_unlocked(mailbox_id, item_id, delay).await
+    }
+
+    async fn reject_unlocked(
+        &self,
+        mailbox_id: &str,
+        item_id: &str,
+        delay: Duration,
+    ) -> Result<()> {
+        let meta = self.ensure_meta(mailbox_id).await?;
+
+        let (p, mut envelope) = self.load_envelope(mailbox_id, &meta, item_id).await?;
+        if meta.is_read(item_id) || envelope.read() {
+            return Err(MailboxError::AlreadyRead {
+                mailbox_id: mailbox_id.to_string(),
+                item_id: item_id.to_string(),
+            }
+            .into());
+        }
+        envelope.not_before = Some(self.now() + delay);
+        envelope.in_flight_since = None;
+        self.save_status(&p, &mut envelope).await?;
+
+        Ok(())
+    }
+
+    /// Send an item that [Mailbox::expire_items] drops, if it is still unread after `ttl`
+    ///
+    /// Expired items are no longer delivered, peeked, or drained, but still count as pending until `expire_items` drops them.
+    /// Note: Not supported with [MailboxDisk::with_id_counter], or [IdScheme::Ulid].
+    pub async fn send_with_ttl(
+        &self,
+        mailbox_id: &str,
+        item: ITEM,
+        ttl: Duration,
+    ) -> Result<String> {
+        self.check_writable("send_with_ttl")?;
+        self.check_numeric_ids("send_with_ttl")?;
+        self.check_no_id_counter("send_with_ttl")?;
+        let expires_at = self.now() + ttl;
+        self.timed("send", mailbox_id, async {
+            self.send_serialized(
+                mailbox_id,
+                item.serialize()?,
+                item.headers(),
+                Some(expires_at),
+                None,
+                None,
+            )
+            .await
+        })
+        .await
+    }
+
+    /// Send an item in the message group `group_id`, the items of a group are delivered in order, one at a time
+    ///
+    /// Once an item of a group is received, the items behind it are skipped until it is acknowledged, rejected,
+    /// or [MailboxDisk::with_message_group_timeout] has passed, while the items of other groups,
+    /// and the ones without a group, are still delivered.
+    /// This lets several consumers work on one mailbox, without reordering the items of a group.
+    /// `drain` takes at most the oldest item of each group, and nothing of a group with an item in flight.
+    /// Note: Message groups are not consumer groups, those still see every item, in plain order.
+    /// [MailboxDisk::defer] moves the item behind the rest of its group.
+    /// Not supported with [MailboxDisk::with_id_counter], or [IdScheme::Ulid].
+    pub async fn send_grouped(
+        &self,
+        mailbox_id: &str,
+        group_id: &str,
+        item: ITEM,
+    ) -> Result<String> {
+        self.check_writable("send_grouped")?;
+        self.check_numeric_ids("send_grouped")?;
+        self.check_no_id_counter("send_grouped")?;
+        self.timed("send", mailbox_id, async {
+            self.send_serialized(
+                mailbox_id,
+                item.serialize()?,
+                item.headers(),
+                None,
+                Some(group_id),
+                None,
+            )
+            .await
+        })
+        .await
+    }
+
+    /// Like `send`, but gives up waiting for the lock, or for space in the mailbox, once `cancel` fires
+    ///
+    /// Fails with [MailboxError::Cancelled] then, and nothing was sent.
+    /// Once the item is written the token is not looked at anymore, so a send that returns the item id was not cancelled.
+    /// Note: With [MailboxDisk::with_id_counter], or [IdScheme::Ulid], the token is only looked at before sending.
+    pub async fn send_with_cancel(
+        &self,
+        mailbox_id: &str,
+        item: ITEM,
+        cancel: &CancellationToken,
+    ) -> Result<String>
+    where
+        ITEM: std::marker::Send,
+    {
+        self.check_writable("send")?;
+        Self::check_cancelled("send", mailbox_id, Some(cancel))?;
+        if self.id_scheme == IdScheme::Ulid || self.id_counters.is_some() {
+            return self.send(mailbox_id, item).await;
+        }
+        self.timed("send", mailbox_id, async {
+            self.send_serialized(
+                mailbox_id,
+                item.serialize()?,
+                item.headers(),
+                None,
+                None,
+                Some(cancel),
+            )
+            .await
+        })
+        .await
+    }
+
+    /// Like `send_transaction`, but gives up once `cancel` fires, even between the items
+    ///
+    /// Fails with [MailboxError::Cancelled] then, and none of the items were sent.
+    /// Note: With [MailboxDisk::with_id_counter], or [IdScheme::Ulid], the token is only looked at before sending.
+    pub async fn send_transaction_with_cancel(
+        &self,
+        mailbox_id: &str,
+        items: Vec<ITEM>,
+        cancel: &CancellationToken,
+    ) -> Result<Vec<String>>
+    where
+        ITEM: std::marker::Send,
+    {
+        self.check_writable("send_transaction")?;
+        Self::check_cancelled("send_transaction", mailbox_id, Some(cancel))?;
+        if self.id_scheme == IdScheme::Ulid || self.id_counters.is_some() {
+            return self.send_transaction(mailbox_id, items).await;
+        }
+        self.timed("send_transaction", mailbox_id, async {
+            self.send_numbered(mailbox_id, items, Some(cancel)).await
+        })
+        .await
+    }
+
+    /// The items in the trash of the mailbox, in id order
+    pub async fn list_trash(&self, mailbox_id: &str) -> Result<Vec<ItemSummary>> {
+        let _sem = self.lock().await?;
+
+        let mut entries = Vec::new();
+        for (item_id, p) in self.trashed_items(mailbox_id).await? {
+            let e = Envelope::load_from(&*self.storage, &p)
+                .await
+                .wrap_err_with(|| {
+                    format!("Broken trash of mailbox {mailbox_id} can't load {item_id}")
+                })?;
+            entries.push(ItemSummary {
+                item_id,
+                read: true,
+                delivery_count: e.delivery_count(),
+                sent_at: e.sent_at,
+                size: e.payload_len()?,
+            });
+        }
+
+        Ok(entries)
+    }
+
+    /// Brings an item back from the trash, as a new unread item, and returns its new id
+    ///
+    /// The headers are kept, the delivery count starts over.
+    pub async fn restore_from_trash(&self, mailbox_id: &str, item_id: &str) -> Result<String> {
+        self.check_writable("restore_from_trash")?;
+        self.check_item_id(item_id)?;
+        let _sem = self.lock().await?;
+        let mut meta = self.ensure_meta(mailbox_id).await?;
+
+        let mut p = self.trash_path(mailbox_id).await.join(item_id);
+        p.set_extension(&self.extension);
+        if !self.exists_at(&p).await {
+            return Err(MailboxError::NotFound {
+                mailbox_id: mailbox_id.to_string(),
+                item_id: item_id.to_string(),
+            }
+            .into());
+        }
+        let envelope = Envelope::load_from(&*self.storage, &p)
+            .await
+            .wrap_err_with(|| {
+                format!("Broken trash of mailbox {mailbox_id} can't load {item_id}")
+            })?;
+        self.verify_signature(mailbox_id, item_id, &envelope)?;
+
+        let hop = ProvenanceEntry {
+            action: ProvenanceAction::RestoredFromTrash,
+            from_mailbox: mailbox_id.to_string(),
+            from_item_id: item_id.to_string(),
+            at: self.now(),
+        };
+        let (new_id, new_path) = self
+            .resend(mailbox_id, &mut meta, &envelope, 0, hop)
+            .await?;
+        if let Err(e) = self.save_meta(mailbox_id, &mut meta).await {
+            let _ = self.storage.delete(&sidecar_path(&new_path)).await;
+            let _ = self.storage.delete(&new_path).await;
+            return Err(e);
+        }
+        for p in [sidecar_path(&p), p] {
+            remove_if_exists(&*self.storage, &p).await?;
+        }
+
+        Ok(new_id)
+    }
+
+    /// The items moved aside by [CorruptionPolicy::Quarantine], oldest first
+    ///
+    /// Once fixed, an item can be sent again, e.g. with [Mailbox::send_raw],
+    /// and removed from the `.quarantine` folder, the listing follows the `manifest.json` in there.
+    pub async fn quarantined(&self, mailbox_id: &str) -> Result<Vec<QuarantinedItem>> {
+        let _sem = self.lock().await?;
+        self.quarantined_unlocked(mailbox_id).await
+    }
+
+    async fn quarantined_unlocked(&self, mailbox_id: &str) -> Result<Vec<QuarantinedItem>> {
+        let mp = self.quarantine_path(mailbox_id).await.join("manifest.json");
+        let manifest = self
+            .storage
+            .get(&mp)
+            .await
+            .wrap_err_with(|| format!("Can't load {mp:?}"))?;
+        match manifest {
+            Some(b) => Ok(serde_json::from_slice(&b)
+                .wrap_err_with(|| format!("Broken quarantine manifest {mp:?}"))?),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    /// The state of a mailbox as text, one line for the cursor, and one per item, e.g. for test fixtures and bug reports
+    ///
+    /// ```text
+    /// # oml-mailbox dump v1
+    /// meta {"highest_used_id":2,"lowest_unread_id":2,"read_ids":[],"total_sent":2,"total_acknowledged":1}
+    /// item 1 read,delivered=1,sent=2024-06-01T12:00:00Z {} "one"
+    /// item 2 sent=2024-06-01T12:01:00Z {"kind":"a"} base64:AAEC
+    /// ```
+    ///
+    /// Only the default group is covered, consumer groups, settings, attributes,
+    /// delays, time to live, and message groups are not.
+    pub async fn dump_text(&self, mailbox_id: &str) -> Result<String> {
+        self.check_numeric_ids("dump_text")?;
+        let _sem = self.lock().await?;
+        let meta = self.load_meta(mailbox_id, false).await?;
+        let mut ids: Vec<u64> = self
+            .item_files(mailbox_id)
+            .await?
+            .into_iter()
+            .map(|(id, _)| id)
+            .collect();
+        ids.sort_unstable();
+
+        let mut items = Vec::new();
+        for id in ids {
+            let item_id = format!("{id}");
+            let (_p, e) = self.load_envelope(mailbox_id, &meta, &item_id).await?;
+            items.push(DumpedItem {
+                id,
+                read: meta.is_read(&item_id),
+                delivery_count: e.delivery_count,
+                deferred_count: e.deferred_count,
+                sent_at: e.sent_at,
+                headers: e.headers.clone(),
+                provenance: e.provenance.clone(),
+                payload: e.data(&*self.storage).await?,
+            });
+        }
+        let dumped = DumpedMeta {
+            highest_used_id: meta.highest_used_id,
+            lowest_unread_id: meta.lowest_unread_id,
+            read_ids: meta.read_ids.iter().copied().collect(),
+            total_sent: meta.total_sent,
+            total_acknowledged: meta.total_acknowledged,
+        };
+        text_dump::write(&dumped, &items)
+    }
+
+    /// Replaces the state of a mailbox with a dump of [MailboxDisk::dump_text]
+    ///
+    /// The dump is checked before anything is written, e.g. ids must be unique, and the cursor within the used ids.
+    /// Fails with [MailboxError::MailboxNotEmpty] if the mailbox has items, or sent any, unless `force` is set,
+    /// which removes its items, and its journal, first.
+    /// Settings, attributes, the freeze, and the owner of an existing mailbox are kept.
+    pub async fn load_text(&self, mailbox_id: &str, text: &str, force: bool) -> Result<()> {
+        self.check_writable("load_text")?;
+        self.check_numeric_ids("load_text")?;
+        self.check_no_id_counter("load_text")?;
+        let (dumped, items) = text_dump::parse(text)?;
+        let _sem = self.lock().await?;
+        let existing = self.find_meta(mailbox_id).await?;
+        let files = match &existing {
+            Some(_) => self.item_files(mailbox_id).await?,
+            None => Vec::new(),
+        };
+        if let Some(existing) = &existing {
+            if !force && (existing.highest_used_id > 0 || !files.is_empty()) {
+                return Err(MailboxError::MailboxNotEmpty {
+                    mailbox_id: mailbox_id.to_string(),
+                }
+                .into());
+            }
+        }
+        for (_id, p) in files {
+            remove_if_exists(&*self.storage, &sidecar_path(&p)).await?;
+            remove_if_exists(&*self.storage, &status_path(&p)).await?;
+            remove_if_exists(&*self.storage, &p).await?;
+        }
+        // Note: the journal would be replayed on top of the new meta
+        remove_if_exists(&*self.storage, &self.journal_path(mailbox_id).await).await?;
+
+        let mut meta = MailboxMeta {
+            layout: self.layout,
+            id_scheme: self.id_scheme,
+            mailbox_id: self.encode_ids.then(|| mailbox_id.to_string()),
+            ..Default::default()
+        };
+        if let Some(existing) = existing {
+            meta.settings = existing.settings;
+            meta.attrs = existing.attrs;
+            meta.frozen = existing.frozen;
+            meta.owner = existing.owner;
+        }
+        let now = self.now();
+        for item in items.iter() {
+            let at = item.sent_at.unwrap_or(now);
+            meta.apply(&JournalRecord::Send { id: item.id, at });
+        }
+        meta.highest_used_id = dumped.highest_used_id;
+        meta.lowest_unread_id = dumped.lowest_unread_id;
+        meta.read_ids = dumped.read_ids.into_iter().collect();
+        meta.total_sent = dumped.total_sent;
+        meta.total_acknowledged = dumped.total_acknowledged;
+
+        self.ensure_mailbox_folder_exists(mailbox_id).await?;
+        for item in items {
+            let item_id = format!("{}", item.id);
+            let (mut e, sidecar) = self.new_envelope(
+                &item_id,
+                item.payload,
+                item.headers,
+                item.sent_at.unwrap_or(now),
+            );
+            e.sent_at = item.sent_at;
+            e.read = item.read;
+            e.delivery_count = item.delivery_count;
+            e.deferred_count = item.deferred_count;
+            e.provenance = item.provenance;
+            let p = self.item_path(mailbox_id, &meta, &item_id).await;
+            self.ensure_item_folder_exists(&p).await?;
+            if let Some(sidecar) = sidecar {
+                write_atomic(&*self.storage, &sidecar_path(&p), &sidecar).await?;
+            }
+            e.save(&*self.storage, &p, self.signer.as_ref()).await?;
+        }
+        self.write_meta_snapshot(mailbox_id, &mut meta).await
+    }
+
+    /// Writes the files of a mailbox to `writer`, without any item data, e.g. for a support bundle
+    ///
+    /// Payloads are replaced by their length and checksum, `debug` is stripped,
+    /// and only the headers in `allowed_headers` are kept, see [ExportContent].
+    /// Sidecars, the trash, and the quarantine are covered too.
+    /// The meta, the journal, and status files are kept as they are, to diagnose cursor and ordering bugs.
+    /// Works on broken mailboxes, the meta is never loaded.
+    /// Note: Signatures are dropped, they are computed over the payload.
+    /// Note: The reasons in the quarantine manifest come from [MailboxItem::deserialize], and are kept.
+    pub async fn redact_export(
+        &self,
+        mailbox_id: &str,
+        allowed_headers: &[&str],
+        mut writer: impl AsyncWrite + Unpin + std::marker::Send,
+    ) -> Result<ExportSummary> {
+        let _sem = self.lock().await?;
+        let base = self.mailbox_path(mailbox_id).await;
+        if !self.exists_at(&base).await {
+            return Err(MailboxError::UnknownMailbox {
+                mailbox_id: mailbox_id.to_string(),
+            }
+            .into());
+        }
+
+        // Note: other folders belong to mailboxes further down the hierarchy
+        let mut folders = vec![base.clone(), self.trash_path(mailbox_id).await];
+        folders.push(self.quarantine_path(mailbox_id).await);
+        folders.extend(self.day_folders(mailbox_id).await?);
+        let mut files = Vec::new();
+        for folder in folders {
+            let entries = self
+                .storage
+                .list(&folder)
+                .await
+                .wrap_err_with(|| format!("Can't list {folder:?}"))?;
+            for entry in entries.unwrap_or_default() {
+                if !entry.is_folder {
+                    files.push(entry.key);
+                }
+            }
+        }
+        files.sort();
+
+        let mut summary = ExportSummary::default();
+        for p in files {
+            let b = read(&*self.storage, &p).await?;
+            let content = self.redact_file(&p, b, allowed_headers, &mut summary);
+            let path = p
+                .strip_prefix(&base)?
+                .components()
+                .map(|c| c.as_os_str().to_string_lossy())
+                .collect::<Vec<_>>()
+                .join("/");
+            let mut line = serde_json::to_vec(&ExportEntry { path, content })?;
+            line.push(b'\n');
+            writer
+                .write_all(&line)
+                .await
+                .wrap_err("Can't write the export")?;
+            summary.files += 1;
+        }
+        writer.flush().await.wrap_err("Can't write the export")?;
+
+        Ok(summary)
+    }
+
+    fn redact_file(
+        &self,
+        p: &Path,
+        b: Vec<u8>,
+        allowed_headers: &[&str],
+        summary: &mut ExportSummary,
+    ) -> ExportContent {
+        let redacted = |b: &[u8], summary: &mut ExportSummary| {
+            summary.redacted_files += 1;
+            ExportContent::Redacted {
+                len: b.len() as u64,
+                sha256: sha256_hex(b),
+            }
+        };
+        let name = p.file_name().and_then(|n| n.to_str()).unwrap_or_default();
+        if p.extension() == Some(self.extension.as_os_str()) {
+            let Ok(mut e) = serde_json::from_slice::<Envelope>(&b) else {
+                return redacted(&b, summary);
+            };
+            let (len, sha256) = match &e.payload {
+                Some(payload) => (payload.len, payload.sha256.clone()),
+                None => {
+                    let data = BASE64_STANDARD
+                        .decode(&e.data)
+                        .unwrap_or_else(|_| e.data.clone().into_bytes());
+                    (data.len() as u64, sha256_hex(&data))
+                }
+            };
+            let headers = e.headers.len();
+            e.headers
+                .retain(|key, _| allowed_headers.contains(&key.as_str()));
+            summary.dropped_headers += (headers - e.headers.len()) as u64;
+            e.data = String::new();
+            e.debug = None;
+            e.signature = None;
+            e.payload_len = Some(len);
+            let Ok(serde_json::Value::Object(mut envelope)) = serde_json::to_value(&e) else {
+                return redacted(&b, summary);
+            };
+            envelope.insert("payload_sha256".to_string(), sha256.into());
+            summary.envelopes += 1;
+            return ExportContent::Envelope {
+                envelope: envelope.into(),
+            };
+        }
+        let structural = name.starts_with("mailbox_meta.")
+            || name.ends_with(".status.json")
+            || name == "manifest.json";
+        // Note: temporary files are hidden, so they never look structural
+        match String::from_utf8(b) {
+            Ok(text) if structural => ExportContent::Verbatim { text },
+            Ok(text) => redacted(text.as_bytes(), summary),
+            Err(e) => redacted(e.as_bytes(), summary),
+        }
+    }
+
+    /// Removes the items that were moved to the trash more than `older_than` ago, or all of them
+    ///
+    /// Returns the number of items removed.
+    pub async fn empty_trash(&self, mailbox_id: &str, older_than: Option<Duration>) -> Result<u64> {
+        self.check_writable("empty_trash")?;
+        let _sem = self.lock().await?;
+
+        let cutoff = older_than.map(|older_than| SystemTime::from(self.now() - older_than));
+        let mut removed = 0;
+        for (_item_id, p) in self.trashed_items(mailbox_id).await? {
+            if let Some(cutoff) = cutoff {
+                // Note: without modification times nothing is known to be old enough
+                let trashed_at = stat(&*self.storage, &p).await?.and_then(|s| s.modified);
+                if trashed_at.is_none_or(|trashed_at| trashed_at > cutoff) {
+                    continue;
+                }
+            }
+            for p in [sidecar_path(&p), p] {
+                remove_if_exists(&*self.storage, &p).await?;
+            }
+            removed += 1;
+        }
+        let trash = self.trash_path(mailbox_id).await;
+        self.storage
+            .delete_folder(&trash)
+            .await
+            .wrap_err_with(|| format!("Can't remove {trash:?}"))?;
+        tracing::debug!("Removed {removed} items from the trash of mailbox {mailbox_id}");
+
+        Ok(removed)
+    }
+
+    /// Sends the bytes read from `reader` as the payload of a new item, without loading all of them into memory
+    ///
+    /// The payload is always stored in a sidecar, and becomes visible once it is complete.
+    /// If `len_hint` is given, a stream of a different length is rejected.
+    /// The item has no headers, and is read back with [MailboxDisk::receive_stream], or as the serialized item by `receive`.
+    pub async fn send_stream(
+        &self,
+        mailbox_id: &str,
+        reader: impl AsyncRead + std::marker::Send + Unpin,
+        len_hint: Option<u64>,
+    ) -> Result<String> {
+        self.send_stream_or_cancel(mailbox_id, reader, len_hint, None)
+            .await
+    }
+
+    /// Like [MailboxDisk::send_stream], but gives up once `cancel` fires, with [MailboxError::Cancelled]
+    ///
+    /// The staged payload is removed then, the token is not looked at anymore once the payload is complete.
+    pub async fn send_stream_with_cancel(
+        &self,
+        mailbox_id: &str,
+        reader: impl AsyncRead + std::marker::Send + Unpin,
+        len_hint: Option<u64>,
+        cancel: &CancellationToken,
+    ) -> Result<String> {
+        self.send_stream_or_cancel(mailbox_id, reader, len_hint, Some(cancel))
+            .await
+    }
+
+    async fn send_stream_or_cancel(
+        &self,
+        mailbox_id: &str,
+        mut reader: impl AsyncRead + std::marker::Send + Unpin,
+        len_hint: Option<u64>,
+        cancel: Option<&CancellationToken>,
+    ) -> Result<String> {
+        self.check_writable("send_stream")?;
+        self.check_no_id_counter("send_stream")?;
+        self.check_quota_supported("send_stream")?;
+        // Note: only to create the mailbox, and to check its capacity, we don't hold the lock while streaming
+        drop(
+            self.lock_with_space_or_cancel(mailbox_id, 1, cancel)
+                .await?,
+        );
+
+        let staged = tmp_path(
+            &self
+                .mailbox_path(mailbox_id)
+                .await
+                .join(format!("stream-{:016x}.payload", fastrand::u64(..))),
+        )?;
+        // Note: never disarmed, once committed the staged payload is gone anyway,
+        // and streaming can take long, so being dropped in between is likely
+        let _staged = CancelGuard::new(|| {
+            self.storage.clone().undo(staged.clone(), None);
+        });
+        let stage = stage_stream(&*self.storage, &staged, &mut reader);
+        let (len, sha256) =
+            Self::unless_cancelled("send_stream", mailbox_id, cancel, stage).await?;
+        if len_hint.is_some_and(|expected| expected != len) {
+            return Err(eyre!(
+                "Stream for mailbox {mailbox_id} ended after {len} bytes, expected {len_hint:?}"
+            ));
+        }
+        self.check_item_size(mailbox_id, len)?;
+
+        self.commit_stream(mailbox_id, &staged, len, sha256, cancel)
+            .await
+    }
+
+    /// Moves the staged sidecar in place, and writes its envelope
+    async fn commit_stream(
+        &self,
+        mailbox_id: &str,
+        staged: &Path,
+        len: u64,
+        sha256: String,
+        cancel: Option<&CancellationToken>,
+    ) -> Result<String> {
+        let (_sem, mut meta) = self
+            .lock_with_space_or_cancel(mailbox_id, 1, cancel)
+            .await?;
+        Self::check_cancelled("send_stream", mailbox_id, cancel)?;
+        let added = QuotaUsage {
+            items: 1,
+            bytes: len,
+        };
+        self.check_quota(mailbox_id, &added).await?;
+        let now = self.now();
+        let (id, item_id) = match self.id_scheme {
+            IdScheme::Numeric => {
+                let id = self.free_ids(mailbox_id, &meta, now, 1).await?;
+                (Some(id), format!("{id}"))
+            }
+            IdScheme::Ulid => (None, self.ulids.next(now)),
+        };
+        let p = match id {
+            Some(_) => self.new_item_path(mailbox_id, now, &item_id).await,
+            None => self.item_path_on(mailbox_id, None, &item_id).await,
+        };
+        if self.exists_at(&p).await {
+            return Err(MailboxError::IdCollision {
+                mailbox_id: mailbox_id.to_string(),
+                item_id,
+            }
+            .into());
+        }
+        self.ensure_item_folder_exists(&p).await?;
+
+        let payload = PayloadRef {
+            file: format!("{item_id}.payload"),
+            len,
+            sha256,
+        };
+        let mut e = Envelope::with_payload(&item_id, payload, now);
+        e.headers = self.outgoing_headers(mailbox_id, &item_id, BTreeMap::new(), now);
+        let sp = sidecar_path(&p);
+        let quota_undo = self.quota_undo(mailbox_id).await?;
+        let mut undone = vec![(sp.clone(), None), (p.clone(), None)];
+        undone.extend(quota_undo.iter().cloned());
+        let undo = self.undo_until_committed(undone);
+        rename(&*self.storage, staged, &sp)
+            .await
+            .wrap_err_with(|| format!("Can't save to {sp:?}"))?;
+        let r: Result<()> = async {
+            e.save(&*self.storage, &p, self.signer.as_ref()).await?;
+            if let Some(id) = id {
+                self.charge_quota(mailbox_id, added).await?;
+                meta.record(JournalRecord::Send { id, at: now });
+                self.save_meta(mailbox_id, &mut meta).await?;
+            }
+            Ok(())
+        }
+        .await;
+        undo.disarm();
+        if let Err(e) = r {
+            self.give_back_quota(quota_undo);
+            let _ = self.storage.delete(&p).await;
+            let _ = self.storage.delete(&sp).await;
+            return Err(e);
+        }
+        if id.is_some() {
+            self.trim(mailbox_id, &mut meta).await?;
+        }
+
+        Ok(item_id)
+    }
+
+    /// Like `receive`, but hands out a reader over the payload, instead of the deserialized item
+    ///
+    /// The checksum of a sidecar is verified while reading, a mismatch is reported as [std::io::ErrorKind::InvalidData] at the end.
+    pub async fn receive_stream(
+        &self,
+        mailbox_id: &str,
+    ) -> Result<Option<(String, impl AsyncRead + std::marker::Send + Unpin)>> {
+        self.check_writable("receive_stream")?;
+        let _sem = self.lock().await?;
+        let Some(mut meta) = self.find_meta(mailbox_id).await? else {
+            return Ok(None);
+        };
+        self.check_access(mailbox_id, &meta, true)?;
+
+        let Some((item_id, p, mut e)) = self.visible_unread(mailbox_id, &meta, 1).await?.pop()
+        else {
+            return Ok(None);
+        };
+        // Note: the sidecar is opened under the lock, so compacting it away later doesn't hurt
+        let reader = e.reader(&*self.storage).await?;
+        e.deliver(self.now());
+        let at_most_once =
+            meta.settings.delivery_mode.unwrap_or(self.delivery.mode) == DeliveryMode::AtMostOnce;
+        if at_most_once {
+            e.mark_read();
+            meta.record(self.ack_record(&item_id)?);
+        }
+        self.save_status(&p, &mut e).await?;
+        if at_most_once {
+            self.save_meta(mailbox_id, &mut meta).await?;
+            self.notify_space_freed(mailbox_id);
+        }
+
+        Ok(Some((item_id, reader)))
+    }
+
+    /// The bytes taken by the files of a mailbox, its envelopes, sidecars, meta and journal
+    ///
+    /// Items in the trash, or in quarantine, count too, since they still take space.
+    /// Note: The folders of mailboxes nested in the mailbox folder, e.g. by [crate::ScopedMailbox], are not included.
+    pub async fn disk_usage(&self, mailbox_id: &str) -> Result<u64> {
+        let _sem = self.lock().await?;
+        self.disk_usage_unlocked(mailbox_id).await
+    }
+
+    async fn disk_usage_unlocked(&self, mailbox_id: &str) -> Result<u64> {
+        let mut usage = 0;
+        let mut folders = vec![self.mailbox_path(mailbox_id).await];
+        while let Some(folder) = folders.pop() {
+            let entries = self
+                .storage
+                .list(&folder)
+                .await
+                .wrap_err_with(|| format!("Can't list {folder:?}"))?;
+            for entry in entries.unwrap_or_default() {
+                if !entry.is_folder {
+                    let stat = stat(&*self.storage, &entry.key).await?;
+                    usage += stat.map(|s| s.len).unwrap_or_default();
+                } else if !self.exists_at(&entry.key.join("mailbox_meta.json")).await {
+                    folders.push(entry.key);
+                }
+            }
+        }
+        Ok(usage)
+    }
+
+    /// The mailboxes below `prefix` in the hierarchy of `/` separated ids, and the mailbox `prefix` itself
+    ///
+    /// Only whole segments match, `orders/eu` matches `orders/eu/123`, but not `orders/eu-archive`.
+    /// An empty prefix matches all mailboxes.
+    pub async fn list_mailboxes_with_prefix(&self, prefix: &str) -> Result<Vec<String>>
+    where
+        ITEM: std::marker::Send,
+    {
+        let prefix = prefix.trim_end_matches(crate::SCOPE_SEPARATOR);
+        let mut ids = self.list_mailboxes().await?;
+        ids.retain(|id| matches_prefix(id, prefix));
+        Ok(ids)
+    }
+
+    /// Adds up the pending items and disk usage of the mailboxes below `prefix`
+    ///
+    /// Note: Every mailbox is looked at on its own, so the totals are not a consistent snapshot.
+    pub async fn stats_prefix(&self, prefix: &str) -> Result<AggregatedStats>
+    where
+        ITEM: std::marker::Send,
+    {
+        let mut stats = AggregatedStats::default();
+        for mailbox_id in self.list_mailboxes_with_prefix(prefix).await? {
+            let s = self.stats(&mailbox_id).await?;
+            stats.mailboxes += 1;
+            stats.pending += s.pending;
+            stats.disk_usage += s.disk_usage.unwrap_or_default();
+        }
+        Ok(stats)
+    }
+
+    /// Removes the mailboxes below `prefix`, with all their items, with an entry per mailbox
+    ///
+    /// Folders of the hierarchy left empty are removed too.
+    /// A mailbox that can't be removed is recorded, and the others are still removed.
+    /// An empty prefix fails with [MailboxError::InvalidId], there is no purging everything by accident.
+    pub async fn purge_prefix(&self, prefix: &str) -> Result<BulkResult<()>>
+    where
+        ITEM: std::marker::Send,
+    {
+        self.check_writable("purge_prefix")?;
+        if prefix.trim_end_matches(crate::SCOPE_SEPARATOR).is_empty() {
+            return Err(MailboxError::InvalidId {
+                id: prefix.to_string(),
+            }
+            .into());
+        }
+        let mailbox_ids = self.list_mailboxes_with_prefix(prefix).await?;
+        let _sem = self.lock().await?;
+
+        let mut purged = BulkResult::default();
+        for mailbox_id in &mailbox_ids {
+            // Note: counted before, the files are gone afterwards
+            let freed = match &self.limits.quota {
+                Some(_) => self.stored_usage(mailbox_id).await,
+                None => Ok(QuotaUsage::default()),
+            };
+            let p = self.mailbox_path(mailbox_id).await;
+            if let Err(e) = self.storage.delete_all(&p).await {
+                let r = Err(e).wrap_err_with(|| format!("Can't remove {p:?}"));
+                purged.push(mailbox_id, None, r);
+                continue;
+            }
+            if let Some(id_counters) = &self.id_counters {
+                id_counters.lock().unwrap().remove(mailbox_id);
+            }
+            self.forget_meta(mailbox_id);
+            self.notify_space_freed(mailbox_id);
+            // Note: only empty folders can be removed, so this stops at the first one still in use
+            let mut folder = p.parent();
+            while let Some(f) = folder.filter(|f| *f != self.base_path) {
+                if !self.storage.delete_folder(f).await.unwrap_or(false) {
+                    break;
+                }
+                folder = f.parent();
+            }
+            let r = match freed {
+                Ok(freed) => self.release_quota(mailbox_id, freed).await,
+                Err(e) => Err(e),
+            };
+            purged.push(mailbox_id, None, r);
+        }
+
+        Ok(purged)
+    }
+
+    /// The usage of `namespace`, as counted by sends, compaction, and purges, see [MailboxDisk::with_quota]
+    pub async fn quota_usage(&self, namespace: &str) -> Result<QuotaUsage> {
+        let _sem = self.lock().await?;
+        self.load_quota_usage(namespace).await
+    }
+
+    /// Counts the items of all mailboxes in `namespace` again, and replaces the usage kept for it
+    ///
+    /// The usage drifts, e.g. with updates, quarantined items, [MailboxDisk::load_text], or files removed by hand.
+    /// Fails with [MailboxError::Unsupported] without [MailboxDisk::with_quota].
+    pub async fn recount(&self, namespace: &str) -> Result<QuotaUsage>
+    where
+        ITEM: std::marker::Send,
+    {
+        self.check_writable("recount")?;
+        let Some(quota) = &self.limits.quota else {
+            return Err(MailboxError::Unsupported {
+                op: "recount".to_string(),
+                reason: "mailboxes without a quota".to_string(),
+            }
+            .into());
+        };
+        let mut mailbox_ids = self.list_mailboxes().await?;
+        mailbox_ids.retain(|mailbox_id| quota.namespace(mailbox_id) == namespace);
+        let _sem = self.lock().await?;
+
+        let mut usage = QuotaUsage::default();
+        for mailbox_id in mailbox_ids {
+            let stored = self.stored_usage(&mailbox_id).await?;
+            usage.items += stored.items;
+            usage.bytes += stored.bytes;
+        }
+        self.save_quota_usage(namespace, &usage).await?;
+
+        Ok(usage)
+    }
+
+    /// A read-only view of the meta, and the files of a mailbox, to find out what went wrong
+    ///
+    /// Note: With [MailboxDisk::with_id_counter] the items of sends in progress are reported as stray files.
+    pub async fn inspect(&self, mailbox_id: &str) -> Result<MailboxInspection> {
+        self.check_numeric_ids("inspect")?;
+        let _sem = self.lock().await?;
+
+        let (meta, meta_error) = match self.read_meta(mailbox_id, false).await {
+            Ok(meta) => (meta, None),
+            Err(e) if is_unknown_mailbox(&e) => return Err(e),
+            Err(e) => (MailboxMeta::default(), Some(format!("{e:#}"))),
+        };
+        let first_id = meta.compacted_below.max(1);
+
+        let mut read_ranges = Vec::new();
+        if meta.lowest_unread_id > 1 {
+            read_ranges.push(1..=meta.lowest_unread_id - 1);
+        }
+        let mut read_ids: Vec<u64> = meta.read_ids.iter().copied().collect();
+        read_ids.sort_unstable();
+        for id in read_ids {
+            match read_ranges.last_mut() {
+                Some(range) if *range.end() + 1 == id => *range = *range.start()..=id,
+                _ => read_ranges.push(id..=id),
+            }
+        }
+
+        fn is_control_file(name: &str) -> bool {
+            let is_meta = [
+                "mailbox_meta.json",
+                "mailbox_meta.journal",
+                "mailbox_meta.counter",
+            ]
+            .contains(&name);
+            // Note: takeover claims are kept, see [MailboxDisk::claim_takeover]
+            let is_claim = name.starts_with(".takeover.") && !name.ends_with(".tmp");
+            is_meta || is_claim
+        }
+
+        // Note: the day folders are added, while the mailbox folder is listed
+        let mut folders = vec![(self.mailbox_path(mailbox_id).await, true)];
+        let mut envelopes = HashSet::new();
+        let mut sidecars = Vec::new();
+        let mut stray_files = Vec::new();
+        while let Some((folder, is_mailbox_folder)) = folders.pop() {
+            for entry in list(&*self.storage, &folder).await? {
+                let path = entry.key;
+                let name = file_name_of(&path).into_owned();
+                if entry.is_folder {
+                    let is_day = NaiveDate::parse_from_str(&name, "%Y-%m-%d").is_ok();
+                    if self.layout == EnvelopeLayout::Daily && is_day {
+                        folders.push((path, false));
+                    } else if name != ".trash" {
+                        stray_files.push(path);
+                    }
+                    continue;
+                }
+                if let Some(id) = item_id_of(&path, &self.extension) {
+                    if id < first_id || id > meta.highest_used_id {
+                        stray_files.push(path);
+                    } else {
+                        envelopes.insert(id);
+                    }
+                } else if path.extension().is_some_and(|e| e == "payload") {
+                    sidecars.push((path.with_extension(&self.extension), path));
+                } else if let Some(stem) = name.strip_suffix(".status.json") {
+                    let mut envelope = PathBuf::from(stem);
+                    envelope.set_extension(&self.extension);
+                    sidecars.push((path.with_file_name(envelope), path));
+                } else if !is_mailbox_folder || !is_control_file(&name) {
+                    stray_files.push(path);
+                }
+            }
+        }
+        for (envelope, sidecar) in sidecars {
+            let known =
+                item_id_of(&envelope, &self.extension).is_some_and(|id| envelopes.contains(&id));
+            if !known {
+                stray_files.push(sidecar);
+            }
+        }
+        stray_files.sort();
+        let missing_items = (first_id..=meta.highest_used_id)
+            .filter(|id| !envelopes.contains(id))
+            .map(|id| format!("{id}"))
+            .collect();
+
+        Ok(MailboxInspection {
+            highest_used_id: meta.highest_used_id,
+            lowest_unread_id: meta.lowest_unread_id,
+            read_ranges,
+            envelope_files: envelopes.len() as u64,
+            missing_items,
+            stray_files,
+            meta_error,
+            frozen: meta.frozen,
+        })
+    }
+
+    /// Checks the meta against the envelopes of a mailbox, and optionally repairs what is safe to repair
+    ///
+    /// See [CheckIssue] for what is checked, and how it is repaired.
+    /// The meta is authoritative, except when it can't be loaded at all.
+    /// Note: Temporary files are only considered stale after [STALE_TMP_AGE], so a running [MailboxDisk::send_stream] is left alone.
+    pub async fn check(&self, mailbox_id: &str, repair: bool) -> Result<CheckReport> {
+        self.check_numeric_ids("check")?;
+        if repair {
+            self.check_writable("check")?;
+        }
+        self.check_no_id_counter("check")?;
+        let _sem = self.lock().await?;
+        let mut report = CheckReport::default();
+        let mut found = |issue: CheckIssue, repaired: bool| {
+            if repaired {
+                report.repaired.push(issue.clone());
+            }
+            report.issues.push(issue);
+        };
+
+        let files: BTreeMap<u64, PathBuf> =
+            self.item_files(mailbox_id).await?.into_iter().collect();
+        let mut dirty = false;
+        let mut meta = match self.read_meta(mailbox_id, false).await {
+            Ok(meta) => meta,
+            Err(e) if is_unknown_mailbox(&e) => return Err(e),
+            Err(e) => {
+                let reason = format!("{e:#}");
+                found(CheckIssue::BrokenMeta { reason }, repair);
+                dirty = true;
+                self.rebuild_meta(mailbox_id, &files).await
+            }
+        };
+
+        if meta.lowest_unread_id > meta.highest_used_id + 1 {
+            let issue = CheckIssue::CursorAhead {
+                lowest_unread_id: meta.lowest_unread_id,
+                highest_used_id: meta.highest_used_id,
+            };
+            found(issue, repair);
+            meta.highest_used_id = meta.lowest_unread_id - 1;
+            dirty = true;
+        }
+
+        for (id, p) in files.range(meta.highest_used_id + 1..) {
+            let item_id = format!("{id}");
+            found(CheckIssue::OrphanedEnvelope { item_id }, repair);
+            if repair {
+                for p in [sidecar_path(p), status_path(p), p.clone()] {
+                    if self.exists_at(&p).await {
+                        let mut to = p.clone().into_os_string();
+                        to.push(".orphaned");
+                        rename(&*self.storage, &p, Path::new(&to))
+                            .await
+                            .wrap_err_with(|| format!("Can't move {p:?}"))?;
+                    }
+                }
+            }
+        }
+
+        let unread: HashSet<u64> = meta.unread_ids().collect();
+        for id in meta.compacted_below.max(1)..=meta.highest_used_id {
+            let item_id = format!("{id}");
+            let Some(p) = files.get(&id) else {
+                if unread.contains(&id) {
+                    found(CheckIssue::MissingEnvelope { item_id }, repair);
+                    meta.record(JournalRecord::Drop { id, at: self.now() });
+                    dirty = true;
+                }
+                continue;
+            };
+            let loaded = match Envelope::load_from(&*self.storage, p).await {
+                Ok(e) => self.verify_signature(mailbox_id, &item_id, &e).map(|()| e),
+                Err(e) => Err(e),
+            };
+            let mut e = match loaded {
+                Ok(e) => e,
+                Err(e) => {
+                    let reason = format!("{e:#}");
+                    found(CheckIssue::BrokenEnvelope { item_id, reason }, false);
+                    continue;
+                }
+            };
+            if e.payload.is_some() {
+                if let Err(e) = e.data_bytes(&*self.storage).await {
+                    let reason = format!("{e:#}");
+                    found(CheckIssue::PayloadMismatch { item_id, reason }, false);
+                    continue;
+                }
+            }
+            if unread.contains(&id) && e.read() {
+                found(CheckIssue::ReadFlagMismatch { item_id }, repair);
+                if repair {
+                    e.read = false;
+                    self.save_status(p, &mut e).await?;
+                }
+            }
+        }
+
+        for path in self.stale_tmp_files(mailbox_id).await? {
+            if repair {
+                remove_if_exists(&*self.storage, &path).await?;
+            }
+            found(CheckIssue::StaleTempFile { path }, repair);
+        }
+
+        if repair && dirty {
+            self.write_meta_snapshot(mailbox_id, &mut meta).await?;
+            // Note: a broken journal would break loading the new meta again
+            remove_if_exists(&*self.storage, &self.journal_path(mailbox_id).await).await?;
+            self.forget_meta(mailbox_id);
+            self.notify_space_freed(mailbox_id);
+        }
+
+        Ok(report)
+    }
+
+    /// A meta for the envelopes in `files`, with the read flags of the envelopes
+    async fn rebuild_meta(&self, mailbox_id: &str, files: &BTreeMap<u64, PathBuf>) -> MailboxMeta {
+        let first_id = files.keys().next().copied().unwrap_or(1);
+        let mut meta = MailboxMeta {
+            layout: self.layout,
+            id_scheme: self.id_scheme,
+            mailbox_id: self.encode_ids.then(|| mailbox_id.to_string()),
+            highest_used_id: first_id - 1,
+            lowest_unread_id: first_id,
+            compacted_below: first_id,
+            // Note: the envelopes could be from any version, upgrading again is harmless
+            layout_version: LayoutVersion::legacy(),
+            ..Default::default()
+        };
+        for (&id, p) in files {
+            let e = Envelope::load_from(&*self.storage, p).await.ok();
+            let at = e
+                .as_ref()
+                .and_then(|e| e.sent_at)
+                .unwrap_or_else(|| self.now());
+            for missing in meta.highest_used_id + 1..id {
+                meta.apply(&JournalRecord::Send { id: missing, at });
+                meta.apply(&JournalRecord::Drop { id: missing, at });
+            }
+            meta.apply(&JournalRecord::Send { id, at });
+            if e.is_some_and(|e| e.read()) {
+                meta.apply(&JournalRecord::Ack { id, at });
+            }
+        }
+        meta
+    }
+
+    /// Temporary files in the folders of a mailbox, that were not touched for [STALE_TMP_AGE]
+    ///
+    /// Note: Without modification times, temporary files are never stale.
+    async fn stale_tmp_files(&self, mailbox_id: &str) -> Result<Vec<PathBuf>> {
+        let mut folders = vec![
+            self.mailbox_path(mailbox_id).await,
+            self.trash_path(mailbox_id).await,
+        ];
+        if self.layout == EnvelopeLayout::Daily {
+            folders.extend(self.day_folders(mailbox_id).await?);
+        }
+        let cutoff = SystemTime::now() - STALE_TMP_AGE;
+        let mut stale = Vec::new();
+        for folder in folders {
+            let Ok(Some(entries)) = self.storage.list(&folder).await else {
+                continue;
+            };
+            for entry in entries {
+                if !file_name_of(&entry.key).ends_with(".tmp") {
+                    continue;
+                }
+                let modified = stat(&*self.storage, &entry.key)
+                    .await?
+                    .and_then(|s| s.modified);
+                if modified.is_some_and(|modified| modified < cutoff) {
+                    stale.push(entry.key);
+                }
+            }
+        }
+        stale.sort();
+
+        Ok(stale)
+    }
+
+    async fn trash_path(&self, mailbox_id: &str) -> PathBuf {
+        self.mailbox_path(mailbox_id).await.join(".trash")
+    }
+
+    async fn quarantine_path(&self, mailbox_id: &str) -> PathBuf {
+        self.mailbox_path(mailbox_id).await.join(".quarantine")
+    }
+
+    /// All items in the trash, sorted by id
+    async fn trashed_items(&self, mailbox_id: &str) -> Result<Vec<(String, PathBuf)>> {
+        let p = self.trash_path(mailbox_id).await;
+        let entries = self
+            .storage
+            .list(&p)
+            .await
+            .wrap_err_with(|| format!("Can't list {p:?}"))?;
+        let mut items = Vec::new();
+        for entry in entries.unwrap_or_default() {
+            let path = entry.key;
+            if path.extension() != Some(self.extension.as_os_str()) {
+                continue;
+            }
+            if let Some(id) = path.file_stem().and_then(|s| s.to_str()) {
+                items.push((id.to_string(), path.clone()));
+            }
+        }
+        // Note: numeric ids have no leading zeros, and ulids all have the same length
+        items.sort_unstable_by(|(a, _), (b, _)| (a.len(), a).cmp(&(b.len(), b)));
+
+        Ok(items)
+    }
+
+    /// Saves the state of an envelope that was loaded from `p`, see [MailboxDisk::with_status_files]
+    async fn save_status(&self, p: &Path, e: &mut Envelope) -> Result<()> {
+        self.save_status_checked(p, e, || Ok(())).await
+    }
+
+    async fn save_status_checked(
+        &self,
+        p: &Path,
+        e: &mut Envelope,
+        before_rename: impl FnOnce() -> Result<()>,
+    ) -> Result<()> {
+        if self.status_files {
+            return write_atomic_checked(
+                &*self.storage,
+                &status_path(p),
+                &e.status_json()?,
+                before_rename,
+            )
+            .await;
+        }
+        e.save_checked(&*self.storage, p, self.signer.as_ref(), before_rename)
+            .await
+    }
+
+    /// Removes the envelope at `p` and its sidecar, or moves them to the trash
+    ///
+    /// Note: The status file is always removed, the state of removed items doesn't matter anymore.
+    async fn remove_item_files(&self, mailbox_id: &str, p: &Path) -> Result<()> {
+        remove_if_exists(&*self.storage, &status_path(p)).await?;
+        if !self.trash {
+            for p in [sidecar_path(p), p.to_path_buf()] {
+                remove_if_exists(&*self.storage, &p).await?;
+            }
+            return Ok(());
+        }
+        let trash = self.trash_path(mailbox_id).await;
+        self.storage
+            .create_folder(&trash)
+            .await
+            .wrap_err_with(|| format!("Can't create {trash:?}"))?;
+        let sp = sidecar_path(p);
+        if let (Some(name), true) = (sp.file_name(), self.exists_at(&sp).await) {
+            let to = trash.join(name);
+            rename(&*self.storage, &sp, &to)
+                .await
+                .wrap_err_with(|| format!("Can't move {sp:?} to {to:?}"))?;
+        }
+        let Some(name) = p.file_name() else {
+            return Ok(());
+        };
+        let to = trash.join(name);
+        let moved = self
+            .storage
+            .rename(p, &to)
+            .await
+            .wrap_err_with(|| format!("Can't move {p:?} to {to:?}"))?;
+        if !moved {
+            return Ok(());
+        }
+        // Note: the modification time records when the item was trashed, for `empty_trash`
+        self.storage
+            .touch(&to, SystemTime::from(self.now()))
+            .await
+            .wrap_err_with(|| format!("Can't touch {to:?}"))?;
+
+        Ok(())
+    }
+
+    /// Writes a copy of the envelope under a new id, and records it in the meta, which the caller saves
+    ///
+    /// `hop` is appended to the provenance of the copy.
+    async fn resend(
+        &self,
+        mailbox_id: &str,
+        meta: &mut MailboxMeta,
+        envelope: &Envelope,
+        deferred_count: u32,
+        hop: ProvenanceEntry,
+    ) -> Result<(String, PathBuf)> {
+        let now = self.now();
+        let (id, item_id) = match self.id_scheme {
+            IdScheme::Numeric => {
+                let id = self.free_ids(mailbox_id, meta, now, 1).await?;
+                (Some(id), format!("{id}"))
+            }
+            IdScheme::Ulid => (None, self.ulids.next(now)),
+        };
+        let data = envelope.data(&*self.storage).await?;
+        let added = QuotaUsage {
+            items: 1,
+            bytes: data.len() as u64,
+        };
+        let (mut e, sidecar) = self.new_envelope(&item_id, data, envelope.headers.clone(), now);
+        e.deferred_count = deferred_count;
+        e.group_id = envelope.group_id.clone();
+        e.provenance = envelope.provenance.clone();
+        provenance::push(&mut e.provenance, hop);
+        let p = match id {
+            Some(_) => self.new_item_path(mailbox_id, now, &item_id).await,
+            None => self.item_path_on(mailbox_id, None, &item_id).await,
+        };
+        self.ensure_item_folder_exists(&p).await?;
+        if let Some(sidecar) = sidecar {
+            write_atomic(&*self.storage, &sidecar_path(&p), &sidecar).await?;
+        }
+        e.save(&*self.storage, &p, self.signer.as_ref()).await?;
+        if let Some(id) = id {
+            meta.record(JournalRecord::Send { id, at: now });
+        }
+        self.charge_quota(mailbox_id, added).await?;
+
+        Ok((item_id, p))
+    }
+
+    /// The original id of an encoded mailbox folder
+    async fn encoded_mailbox_id(&self, path: &Path, name: &str) -> Option<String> {
+        let meta = read(&*self.storage, &path.join("mailbox_meta.json"))
+            .await
+            .ok()
+            .and_then(|b| serde_json::from_slice::<MailboxMeta>(&b).ok());
+        meta.and_then(|meta| meta.mailbox_id)
+            .or_else(|| mailbox_id_encoding::decode(name))
+    }
+
+    /// Collects the mailboxes in a folder, and in the folders of scopes below it, see [crate::ScopedMailbox]
+    ///
+    /// A folder without a meta, but with folders in it, is a scope, everything else is a mailbox.
+    ///
+    /// Note: Boxed, since it recurses.
+    fn list_mailboxes_in<'a>(
+        &'a self,
+        entries: Vec<StorageEntry>,
+        prefix: String,
+        ids: &'a mut Vec<String>,
+    ) -> std::pin::Pin<Box<dyn std::future::Future<Output = Result<()>> + std::marker::Send + 'a>>
+    {
+        Box::pin(async move {
+            for entry in entries {
+                if !entry.is_folder {
+                    continue;
+                }
+                let path = entry.key;
+                let Some(id) = path.file_name().and_then(|n| n.to_str()).map(String::from) else {
+                    tracing::warn!("Skipping non UTF-8 folder {path:?}");
+                    continue;
+                };
+                // Note: hidden folders are reserved for internal use
+                if id.starts_with('.') {
+                    continue;
+                }
+                if prefix.is_empty() && id.starts_with(mailbox_id_encoding::ENCODED_PREFIX) {
+                    match self.encoded_mailbox_id(&path, &id).await {
+                        Some(id) => ids.push(id),
+                        None => tracing::warn!("Skipping folder {path:?} without id"),
+                    }
+                    continue;
+                }
+                if !self.exists_at(&path.join("mailbox_meta.json")).await
+                    && has_scope_folders(&*self.storage, &path).await?
+                {
+                    let prefix = format!("{prefix}{id}{}", crate::SCOPE_SEPARATOR);
+                    let entries = list(&*self.storage, &path).await?;
+                    self.list_mailboxes_in(entries, prefix, ids).await?;
+                    continue;
+                }
+                ids.push(format!("{prefix}{id}"));
+            }
+
+            Ok(())
+        })
+    }
+
+    async fn mailbox_path(&self, mailbox_id: &str) -> PathBuf {
+        let mut p = PathBuf::new();
+        p.push(&self.base_path);
+        let idp = Path::new(mailbox_id);
+        p.push(idp);
+        if !self.encode_ids || (is_plain_mailbox_id(mailbox_id) && self.exists_at(&p).await) {
+            return p;
+        }
+
+        self.base_path.join(mailbox_id_encoding::encode(mailbox_id))
+    }
+
+    /// The path of an existing item, its day is looked up in the meta
+    async fn item_path(&self, mailbox_id: &str, meta: &MailboxMeta, item_id: &str) -> PathBuf {
+        let day = match self.layout {
+            EnvelopeLayout::Flat => None,
+            EnvelopeLayout::Daily => item_id.parse::<u64>().ok().and_then(|id| meta.day_of(id)),
+        };
+        self.item_path_on(mailbox_id, day, item_id).await
+    }
+
+    /// The envelope for a new item, and the payload for its sidecar, if it is too large to embed
+    fn new_envelope(
+        &self,
+        item_id: &str,
+        data: Vec<u8>,
+        headers: BTreeMap<String, String>,
+        at: DateTime<Utc>,
+    ) -> (Envelope, Option<Vec<u8>>) {
+        let (mut e, sidecar) = match self.sidecar_threshold {
+            Some(threshold) if data.len() > threshold => {
+                let e = Envelope::with_sidecar(item_id, &data, at);
+                (e, Some(data))
+            }
+            _ => {
+                let mut e = Envelope::new(item_id, data, at);
+                let _ = e.add_debug(); // for debugging
+                (e, None)
+            }
+        };
+        e.headers = headers;
+        if let Some(signer) = &self.signer {
+            e.signature = Some(signer.sign(&e));
+        }
+        (e, sidecar)
+    }
+
+    /// The headers of a new envelope, see [MailboxDisk::with_default_headers] and [MailboxDisk::with_decorator]
+    fn outgoing_headers(
+        &self,
+        mailbox_id: &str,
+        item_id: &str,
+        headers: BTreeMap<String, String>,
+        at: DateTime<Utc>,
+    ) -> BTreeMap<String, String> {
+        if self.default_headers.is_empty() && self.decorator.is_none() {
+            return headers;
+        }
+        let mut merged: BTreeMap<String, String> = self
+            .default_headers
+            .iter()
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .collect();
+        merged.extend(headers);
+        let Some(decorator) = &self.decorator else {
+            return merged;
+        };
+        let mut draft = EnvelopeDraft::new(mailbox_id, item_id, at, merged);
+        decorator.decorate(&mut draft);
+        draft.headers
+    }
+
+    /// The path of an item sent `at`
+    async fn new_item_path(&self, mailbox_id: &str, at: DateTime<Utc>, item_id: &str) -> PathBuf {
+        let day = match self.layout {
+            EnvelopeLayout::Flat => None,
+            EnvelopeLayout::Daily => Some(at.date_naive()),
+        };
+        self.item_path_on(mailbox_id, day, item_id).await
+    }
+
+    async fn item_path_on(
+        &self,
+        mailbox_id: &str,
+        day: Option<NaiveDate>,
+        item_id: &str,
+    ) -> PathBuf {
+        let mut p = self.mailbox_path(mailbox_id).await;
+        if let Some(day) = day {
+            p.push(day.format("%Y-%m-%d").to_string());
+        }
+        let idp = Path::new(item_id);
+        p.push(idp);
+        p.set_extension(&self.extension);
+
+        p
+    }
+    async fn meta_path(&self, mailbox_id: &str) -> PathBuf {
+        let mut p = self.mailbox_path(mailbox_id).await;
+        let idp = Path::new("mailbox_meta");
+        p.push(idp);
+        p.set_extension("json");
+
+        p
+    }
+    async fn journal_path(&self, mailbox_id: &str) -> PathBuf {
+        let mut p = self.meta_path(mailbox_id).await;
+        p.set_extension("journal");
+
+        p
+    }
+    async fn counter_path(&self, mailbox_id: &str) -> PathBuf {
+        let mut p = self.meta_path(mailbox_id).await;
+        p.set_extension("counter");
+
+        p
+    }
+    async fn upgrade_path(&self, mailbox_id: &str) -> PathBuf {
+        let mut p = self.meta_path(mailbox_id).await;
+        p.set_extension("upgrade");
+
+        p
+    }
+
+    /// The id counter of the mailbox, hydrated on first use
+    ///
+    /// The counter starts above the counter file, the meta, and every envelope on disk,
+    /// so ids are never reused, even if the counter file is stale.
+    /// Note: The caller must hold the lock.
+    async fn id_counter_for(
+        &self,
+        mailbox_id: &str,
+        meta: &MailboxMeta,
+    ) -> Result<Option<Arc<IdCounter>>> {
+        let Some(id_counters) = &self.id_counters else {
+            return Ok(None);
+        };
+        if let Some(counter) = id_counters.lock().unwrap().get(mailbox_id) {
+            return Ok(Some(counter.clone()));
+        }
+
+        let cp = self.counter_path(mailbox_id).await;
+        let persisted = match self
+            .storage
+            .get(&cp)
+            .await
+            .wrap_err_with(|| format!("Can't load {cp:?}"))?
+        {
+            Some(b) => u64::from_le_bytes(
+                b.try_into()
+                    .map_err(|_| eyre!("Broken id counter {cp:?}"))?,
+            ),
+            None => 0,
+        };
+        // Note: items that made it to disk, but not into the meta before a crash
+        let mut sent = BTreeMap::new();
+        for (id, p) in self.item_files(mailbox_id).await? {
+            if id > meta.highest_used_id {
+                let at = Envelope::load_from(&*self.storage, &p)
+                    .await
+                    .ok()
+                    .and_then(|e| e.sent_at);
+                sent.insert(id, at.unwrap_or_else(|| self.now()));
+            }
+        }
+        let highest = sent
+            .last_key_value()
+            .map(|(id, _)| *id)
+            .unwrap_or_default()
+            .max(meta.highest_used_id)
+            .max(persisted);
+        if highest > persisted {
+            tracing::warn!(
+                "Id counter of mailbox {mailbox_id} is behind, continuing after {highest}"
+            );
+        }
+
+        let counter = Arc::new(IdCounter {
+            highest: AtomicU64::new(highest),
+            persisted: tokio::sync::Mutex::new(persisted),
+            state: Mutex::new(IdCounterState {
+                sent,
+                ..Default::default()
+            }),
+        });
+        id_counters
+            .lock()
+            .unwrap()
+            .insert(mailbox_id.to_string(), counter.clone());
+
+        Ok(Some(counter))
+    }
+
+    /// Takes `count` consecutive ids from the counter, and persists it
+    async fn allocate_ids(
+        &self,
+        mailbox_id: &str,
+        counter: &IdCounter,
+        count: usize,
+    ) -> Result<u64> {
+        let count = count as u64;
+        let first_id = counter.highest.fetch_add(count, Ordering::SeqCst) + 1;
+        let ids: Vec<u64> = (first_id..first_id + count).collect();
+        counter
+            .state
+            .lock()
+            .unwrap()
+            .in_flight
+            .extend(ids.iter().copied());
+        // Note: also releases the ids if writing the counter fails
+        let release = CancelGuard::new(|| counter.release(&ids));
+        let mut persisted = counter.persisted.lock().await;
+        // Note: whoever comes last writes the highest id, for everyone before
+        let highest = counter.highest.load(Ordering::SeqCst);
+        if highest > *persisted {
+            write_atomic(
+                &*self.storage,
+                &self.counter_path(mailbox_id).await,
+                &highest.to_le_bytes(),
+            )
+            .await?;
+            *persisted = highest;
+        }
+        release.disarm();
+
+        Ok(first_id)
+    }
+
+    /// `send` and `send_transaction` with [MailboxDisk::with_id_counter]
+    ///
+    /// The items become visible together, once all of them are written.
+    async fn send_counted(&self, mailbox_id: &str, items: Vec<ITEM>) -> Result<Vec<String>> {
+        self.check_quota_supported("send")?;
+        if self.lock_semaphore.is_closed() {
+            return Err(MailboxError::Closed.into());
+        }
+        // Note: only to check the freeze, and the capacity, the ids come from the counter
+        drop(self.lock_with_space(mailbox_id, items.len()).await?);
+        let counter = match self
+            .id_counters
+            .as_ref()
+            .and_then(|c| c.lock().unwrap().get(mailbox_id).cloned())
+        {
+            Some(counter) => counter,
+            None => {
+                let _sem = self.lock().await?;
+                let meta = self.ensure_meta(mailbox_id).await?;
+                self.id_counter_for(mailbox_id, &meta)
+                    .await?
+                    .ok_or_else(|| eyre!("No id counter"))?
+            }
+        };
+
+        let now = self.now();
+        let first_id = self.allocate_ids(mailbox_id, &counter, items.len()).await?;
+        let ids: Vec<u64> = (first_id..first_id + items.len() as u64).collect();
+        let written = Mutex::new(Vec::new());
+        // Note: the items are invisible until they are folded into the meta, so removing them is enough
+        let undo = CancelGuard::new(|| {
+            for p in written.lock().unwrap().drain(..) {
+                self.storage.clone().undo(p, None);
+            }
+            counter.release(&ids);
+        });
+        let r: Result<()> = async {
+            let mut staged = Vec::new();
+            for (id, item) in ids.iter().zip(items.iter()) {
+                let item_id = format!("{id}");
+                let headers = self.outgoing_headers(mailbox_id, &item_id, item.headers(), now);
+                let (mut e, sidecar) = self.new_envelope(
+                    &item_id,
+                    self.serialize_checked(mailbox_id, item)?,
+                    headers,
+                    now,
+                );
+                let p = self.new_item_path(mailbox_id, now, &item_id).await;
+                // Note: another instance might have sent without the counter
+                if self.exists_at(&p).await {
+                    return Err(MailboxError::IdCollision {
+                        mailbox_id: mailbox_id.to_string(),
+                        item_id,
+                    }
+                    .into());
+                }
+                self.ensure_item_folder_exists(&p).await?;
+                if let Some(sidecar) = sidecar {
+                    let sp = sidecar_path(&p);
+                    written.lock().unwrap().push(sp.clone());
+                    write_atomic(&*self.storage, &sp, &sidecar).await?;
+                }
+                let tmp = tmp_path(&p)?;
+                written.lock().unwrap().push(tmp.clone());
+                e.save(&*self.storage, &tmp, self.signer.as_ref()).await?;
+                staged.push((tmp, p));
+            }
+            for (tmp, p) in staged {
+                written.lock().unwrap().push(p.clone());
+                rename(&*self.storage, &tmp, &p)
+                    .await
+                    .wrap_err_with(|| format!("Can't save to {p:?}"))?;
+            }
+            Ok(())
+        }
+        .await;
+
+        let written = std::mem::take(&mut *written.lock().unwrap());
+        undo.disarm();
+        if let Err(e) = r {
+            counter.release(&ids);
+            for p in written {
+                let _ = self.storage.delete(&p).await;
+            }
+            return Err(e);
+        }
+        let mut state = counter.state.lock().unwrap();
+        state.in_flight.retain(|id| !ids.contains(id));
+        state.sent.extend(ids.iter().map(|id| (*id, now)));
+
+        Ok(ids.iter().map(|id| format!("{id}")).collect())
+    }
+
+    /// Adds the items sent via the id counter to the meta
+    ///
+    /// Only items below the lowest id still being written are added,
+    /// ids below them that were never written are dropped.
+    async fn fold_sent(&self, mailbox_id: &str, meta: &mut MailboxMeta) -> Result<()> {
+        let Some(counter) = self.id_counter_for(mailbox_id, meta).await? else {
+            return Ok(());
+        };
+        let sent: Vec<(u64, DateTime<Utc>)> = {
+            let mut state = counter.state.lock().unwrap();
+            let highest_used_id = meta.highest_used_id;
+            state.sent.retain(|id, _| *id > highest_used_id);
+            let below = state.in_flight.first().copied().unwrap_or(u64::MAX);
+            state
+                .sent
+                .range(..below)
+                .map(|(id, at)| (*id, *at))
+                .collect()
+        };
+        let Some((last_id, _)) = sent.last().copied() else {
+            return Ok(());
+        };
+
+        let first_id = meta.highest_used_id + 1;
+        for (id, at) in sent.iter() {
+            meta.record(JournalRecord::Send { id: *id, at: *at });
+        }
+        let sent: HashSet<u64> = sent.into_iter().map(|(id, _)| id).collect();
+        for id in first_id..last_id {
+            if !sent.contains(&id) {
+                tracing::warn!("Dropping unused id {id} in mailbox {mailbox_id}");
+                meta.record(JournalRecord::Drop { id, at: self.now() });
+            }
+        }
+        if !self.read_only {
+            self.save_meta(mailbox_id, meta).await?;
+        }
+
+        Ok(())
+    }
+
+    /// The first of `count` consecutive ids, that are free for new items
+    ///
+    /// Note: The caller must hold the lock, so checking is enough to never overwrite an item.
+    async fn free_ids(
+        &self,
+        mailbox_id: &str,
+        meta: &MailboxMeta,
+        at: DateTime<Utc>,
+        count: usize,
+    ) -> Result<u64> {
+        let first_id = meta.highest_used_id + 1;
+        for id in first_id..first_id + count as u64 {
+            if !self
+                .exists_at(&self.new_item_path(mailbox_id, at, &format!("{id}")).await)
+                .await
+            {
+                continue;
+            }
+            if !self.heal_id_collisions {
+                return Err(MailboxError::IdCollision {
+                    mailbox_id: mailbox_id.to_string(),
+                    item_id: format!("{id}"),
+                }
+                .into());
+            }
+            let highest = self.highest_item_id_on_disk(mailbox_id).await?;
+            let healed_id = highest.max(meta.highest_used_id) + 1;
+            tracing::warn!("Item {id} already exists in mailbox {mailbox_id}, using {healed_id}");
+            return Ok(healed_id);
+        }
+
+        Ok(first_id)
+    }
+
+    /// All item files in the mailbox folder, with their ids, in no particular order
+    async fn item_files(&self, mailbox_id: &str) -> Result<Vec<(u64, PathBuf)>> {
+        let mut files = Vec::new();
+        match self.layout {
+            EnvelopeLayout::Flat => {
+                self.collect_item_files(&self.mailbox_path(mailbox_id).await, &mut files)
+                    .await?
+            }
+            EnvelopeLayout::Daily => {
+                for day in self.day_folders(mailbox_id).await? {
+                    self.collect_item_files(&day, &mut files).await?;
+                }
+            }
+        }
+
+        Ok(files)
+    }
+
+    /// The day folders of a mailbox with the [EnvelopeLayout::Daily] layout
+    async fn day_folders(&self, mailbox_id: &str) -> Result<Vec<PathBuf>> {
+        let p = self.mailbox_path(mailbox_id).await;
+        let mut days = Vec::new();
+        for entry in list(&*self.storage, &p).await? {
+            let is_day = entry
+                .key
+                .file_name()
+                .and_then(|name| name.to_str())
+                .is_some_and(|name| NaiveDate::parse_from_str(name, "%Y-%m-%d").is_ok());
+            if is_day && entry.is_folder {
+                days.push(entry.key);
+            }
+        }
+
+        Ok(days)
+    }
+
+    async fn collect_item_files(&self, p: &Path, files: &mut Vec<(u64, PathBuf)>) -> Result<()> {
+        for entry in list(&*self.storage, p).await? {
+            let path = entry.key;
+            if let Some(id) = item_id_of(&path, &self.extension) {
+                files.push((id, path));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The highest item id that has a file in the mailbox folder
+    async fn highest_item_id_on_disk(&self, mailbox_id: &str) -> Result<u64> {
+        let files = self.item_files(mailbox_id).await?;
+        Ok(files.iter().map(|(id, _)| *id).max().unwrap_or_default())
+    }
+
+    /// Group names end up in the journal, so they must not contain whitespace
+    fn validate_group(group: &str) -> Result<()> {
+        if group.is_empty() || group.contains(char::is_whitespace) {
+            return Err(MailboxError::InvalidId {
+                id: group.to_string(),
+            }
+            .into());
+        }
+        Ok(())
+    }
+
+    fn parse_item_id(item_id: &str) -> Result<u64> {
+        item_id.parse::<u64>().map_err(|_| {
+            MailboxError::InvalidId {
+                id: item_id.to_string(),
+            }
+            .into()
+        })
+    }
+
+    fn check_item_id(&self, item_id: &str) -> Result<()> {
+        match self.id_scheme {
+            IdScheme::Numeric => Self::parse_item_id(item_id).map(|_| ()),
+            IdScheme::Ulid if ulid::decode(item_id).is_some() => Ok(()),
+            IdScheme::Ulid => Err(MailboxError::InvalidId {
+                id: item_id.to_string(),
+            }
+            .into()),
+        }
+    }
+
+    fn check_numeric_ids(&self, op: &str) -> Result<()> {
+        if self.id_scheme != IdScheme::Numeric {
+            return Err(MailboxError::Unsupported {
+                op: op.to_string(),
+                reason: format!("mailboxes with {:?} ids", self.id_scheme),
+            }
+            .into());
+        }
+        Ok(())
+    }
+
+    fn check_no_id_counter(&self, op: &str) -> Result<()> {
+        if self.id_counters.is_some() {
+            return Err(MailboxError::Unsupported {
+                op: op.to_string(),
+                reason: "mailboxes with an id counter".to_string(),
+            }
+            .into());
+        }
+        Ok(())
+    }
+
+    /// All unread ids, in ascending order
+    async fn unread_item_ids<'a>(
+        &self,
+        mailbox_id: &str,
+        meta: &'a MailboxMeta,
+    ) -> Result<Box<dyn Iterator<Item = String> + std::marker::Send + 'a>> {
+        match self.id_scheme {
+            IdScheme::Numeric => Ok(Box::new(meta.unread_ids().map(|id| format!("{id}")))),
+            IdScheme::Ulid => {
+                let mut ids = self.ulid_item_ids(mailbox_id).await?;
+                ids.retain(|id| !meta.read_ulids.contains(id));
+                Ok(Box::new(ids.into_iter()))
+            }
+        }
+    }
+
+    /// Up to `max` unread items, skipping the ones delayed by [MailboxDisk::reject_with_delay], or waiting for their message group
+    async fn visible_unread(
+        &self,
+        mailbox_id: &str,
+        meta: &MailboxMeta,
+        max: usize,
+    ) -> Result<Vec<(String, PathBuf, Envelope)>> {
+        let now = self.now();
+        let mut visible = Vec::new();
+        if max == 0 {
+            return Ok(visible);
+        }
+        let mut message_groups = HashSet::new();
+        for item_id in self.receive_order(mailbox_id, meta).await? {
+            let (p, e) = self.load_envelope(mailbox_id, meta, &item_id).await?;
+            if !self.deliverable(&e, now, &mut message_groups) {
+                continue;
+            }
+            visible.push((item_id, p, e));
+            if visible.len() == max {
+                break;
+            }
+        }
+        Ok(visible)
+    }
+
+    /// Up to `max` unread ids that can be delivered now, in the order `receive` delivers them
+    ///
+    /// Skips the same items as [MailboxDisk::visible_unread], but keeps the ones that can't be loaded,
+    /// so the caller can report them.
+    async fn deliverable_ids(
+        &self,
+        mailbox_id: &str,
+        meta: &MailboxMeta,
+        max: usize,
+    ) -> Result<Vec<String>> {
+        let now = self.now();
+        let mut ids = Vec::new();
+        let mut message_groups = HashSet::new();
+        for item_id in self.receive_order(mailbox_id, meta).await? {
+            if ids.len() == max {
+                break;
+            }
+            if let Ok((_p, e)) = self.load_envelope(mailbox_id, meta, &item_id).await {
+                if !self.deliverable(&e, now, &mut message_groups) {
+                    continue;
+                }
+            }
+            ids.push(item_id);
+        }
+        Ok(ids)
+    }
+
+    /// How many items, up to `max`, `receive_many` would deliver now
+    async fn deliverable_count(&self, mailbox_id: &str, max: usize) -> Result<usize> {
+        let _sem = self.lock().await?;
+        let Some(meta) = self.find_meta(mailbox_id).await? else {
+            return Ok(0);
+        };
+
+        Ok(self.deliverable_ids(mailbox_id, &meta, max).await?.len())
+    }
+
+    /// The unread ids, in the order the [FairnessPolicy] of the mailbox receives them
+    ///
+    /// Items of a message group all get the priority of the oldest one, so they stay in order.
+    async fn receive_order<'a>(
+        &self,
+        mailbox_id: &str,
+        meta: &'a MailboxMeta,
+    ) -> Result<Box<dyn Iterator<Item = String> + std::marker::Send + 'a>> {
+        let unread = self.unread_item_ids(mailbox_id, meta).await?;
+        let Some(fairness) = meta
+            .settings
+            .fairness
+            .as_ref()
+            .or(self.delivery.fairness.as_ref())
+        else {
+            return Ok(unread);
+        };
+
+        let now = self.now();
+        let mut group_priorities = HashMap::new();
+        let mut ids = Vec::new();
+        let mut items = Vec::new();
+        for item_id in unread {
+            // Note: broken items are left to the callers, and their CorruptionPolicy
+            let item = match self.load_envelope(mailbox_id, meta, &item_id).await {
+                Ok((_p, e)) => {
+                    let priority = FairnessPolicy::priority_of(&e.headers);
+                    let priority = match &e.group_id {
+                        Some(group_id) => {
+                            *group_priorities.entry(group_id.clone()).or_insert(priority)
+                        }
+                        None => priority,
+                    };
+                    (priority, e.age_at(now))
+                }
+                Err(_) => (0, Duration::ZERO),
+            };
+            ids.push(Some(item_id));
+            items.push(item);
+        }
+        let ordered: Vec<String> = fairness
+            .order(&items, meta.total_acknowledged)
+            .into_iter()
+            .filter_map(|i| ids[i].take())
+            .collect();
+        Ok(Box::new(ordered.into_iter()))
+    }
+
+    /// The pending items of a mailbox by priority
+    async fn priority_stats(
+        &self,
+        mailbox_id: &str,
+        meta: &MailboxMeta,
+    ) -> Result<BTreeMap<u32, PriorityStats>> {
+        let mut priorities = BTreeMap::<u32, PriorityStats>::new();
+        for item_id in self.unread_item_ids(mailbox_id, meta).await? {
+            // Note: broken items are left to receive, and its CorruptionPolicy
+            let Ok((_p, e)) = self.load_envelope(mailbox_id, meta, &item_id).await else {
+                continue;
+            };
+            let stats = priorities
+                .entry(FairnessPolicy::priority_of(&e.headers))
+                .or_default();
+            stats.pending += 1;
+            stats.oldest_sent_at = match (stats.oldest_sent_at, e.sent_at) {
+                (Some(oldest), Some(sent_at)) => Some(oldest.min(sent_at)),
+                (oldest, sent_at) => oldest.or(sent_at),
+            };
+        }
+        Ok(priorities)
+    }
+
+    /// If the item can be delivered `now`, going through the unread items in order
+    ///
+    /// Only the oldest unread item of a message group can be delivered, and only while it isn't in flight,
+    /// so `message_groups` collects the groups seen so far, to skip everything behind them.
+    fn deliverable(
+        &self,
+        e: &Envelope,
+        now: DateTime<Utc>,
+        message_groups: &mut HashSet<String>,
+    ) -> bool {
+        let Some(group_id) = &e.group_id else {
+            return e.visible_at(now);
+        };
+        if !message_groups.insert(group_id.clone()) {
+            return false;
+        }
+        e.visible_at(now) && !e.in_flight_at(now, self.delivery.message_group_timeout)
+    }
+
+    /// All items in the folder of a mailbox with [IdScheme::Ulid], in id order
+    async fn ulid_item_ids(&self, mailbox_id: &str) -> Result<Vec<String>> {
+        let p = self.mailbox_path(mailbox_id).await;
+        let mut ids = Vec::new();
+        for entry in list(&*self.storage, &p).await? {
+            let path = entry.key;
+            if path.extension() != Some(self.extension.as_os_str()) {
+                continue;
+            }
+            if let Some(id) = path.file_stem().and_then(|s| s.to_str()) {
+                if ulid::decode(id).is_some() {
+                    ids.push(id.to_string());
+                }
+            }
+        }
+        ids.sort_unstable();
+
+        Ok(ids)
+    }
+
+    fn ack_record(&self, item_id: &str) -> Result<JournalRecord> {
+        let at = self.now();
+        match self.id_scheme {
+            IdScheme::Numeric => Ok(JournalRecord::Ack {
+                id: Self::parse_item_id(item_id)?,
+                at,
+            }),
+            IdScheme::Ulid => Ok(JournalRecord::UlidAck {
+                id: item_id.to_string(),
+                at,
+            }),
+        }
+    }
+
+    /// Sends to a mailbox with [IdScheme::Ulid], without touching the meta
+    ///
+    /// Note: The envelopes are renamed into place one by one,
+    /// so a receiver can see the first items of a transaction before the last.
+    async fn send_ulids(&self, mailbox_id: &str, items: Vec<ITEM>) -> Result<Vec<String>> {
+        self.check_quota_supported("send")?;
+        // Note: only to create the mailbox, and to check its scheme and capacity
+        let (sem, meta) = self.lock_with_space(mailbox_id, items.len()).await?;
+        drop(sem);
+
+        let now = self.now();
+        let item_ids: Vec<String> = items.iter().map(|_| self.ulids.next(now)).collect();
+        // Note: the items are visible once renamed, a dropped send removes them again
+        let written = Mutex::new(Vec::new());
+        let undo = CancelGuard::new(|| {
+            for p in written.lock().unwrap().drain(..) {
+                self.storage.clone().undo(p, None);
+            }
+        });
+        let r: Result<()> = async {
+            let mut staged = Vec::new();
+            for (item_id, item) in item_ids.iter().zip(items.iter()) {
+                let headers = self.outgoing_headers(mailbox_id, item_id, item.headers(), now);
+                let (e, sidecar) = self.new_envelope(
+                    item_id,
+                    self.serialize_checked(mailbox_id, item)?,
+                    headers,
+                    now,
+                );
+                let p = self.item_path_on(mailbox_id, None, item_id).await;
+                if self.exists_at(&p).await {
+                    return Err(MailboxError::IdCollision {
+                        mailbox_id: mailbox_id.to_string(),
+                        item_id: item_id.clone(),
+                    }
+                    .into());
+                }
+                if let Some(sidecar) = sidecar {
+                    let sp = sidecar_path(&p);
+                    written.lock().unwrap().push(sp.clone());
+                    write_atomic(&*self.storage, &sp, &sidecar).await?;
+                }
+                let tmp = tmp_path(&p)?;
+                written.lock().unwrap().push(tmp.clone());
+                self.storage
+                    .write(&tmp, &e.to_json()?)
+                    .await
+                    .wrap_err_with(|| format!("Can't save to {tmp:?}"))?;
+                staged.push((tmp, p));
+            }
+            for (tmp, p) in staged {
+                written.lock().unwrap().push(p.clone());
+                rename(&*self.storage, &tmp, &p)
+                    .await
+                    .wrap_err_with(|| format!("Can't save to {p:?}"))?;
+            }
+            Ok(())
+        }
+        .await;
+
+        let written = std::mem::take(&mut *written.lock().unwrap());
+        undo.disarm();
+        if let Err(e) = r {
+            for p in written {
+                let _ = self.storage.delete(&p).await;
+            }
+            return Err(e);
+        }
+        if self.events.is_observed() {
+            let events = item_ids
+                .iter()
+                .map(|item_id| MailboxEvent::ItemSent {
+                    mailbox_id: mailbox_id.to_string(),
+                    item_id: item_id.clone(),
+                })
+                .collect();
+            self.publish_events(mailbox_id, events, &meta).await;
+        }
+
+        Ok(item_ids)
+    }
+
+    /// [Mailbox::acknowledge_through] for [IdScheme::Ulid], ids don't have to exist
+    async fn acknowledge_ulids_through(&self, mailbox_id: &str, item_id: &str) -> Result<u64> {
+        self.check_item_id(item_id)?;
+        let _sem = self.lock().await?;
+        let mut meta = self.ensure_meta(mailbox_id).await?;
+        let unread: Vec<String> = self
+            .unread_item_ids(mailbox_id, &meta)
+            .await?
+            .take_while(|unread| unread.as_str() <= item_id)
+            .collect();
+        if unread.is_empty() {
+            return Ok(0);
+        }
+        for id in unread.iter() {
+            meta.record(self.ack_record(id)?);
+        }
+        self.save_meta(mailbox_id, &mut meta).await?;
+        self.notify_space_freed(mailbox_id);
+
+        Ok(unread.len() as u64)
+    }
+
+    /// Removes the items below `limit` read by every consumer group, returns how many
+    async fn compact_read(
+        &self,
+        mailbox_id: &str,
+        meta: &mut MailboxMeta,
+        limit: u64,
+    ) -> Result<u64> {
+        let below = meta.fully_read_below().min(limit);
+        if below <= meta.compacted_below.max(1) {
+            return Ok(0);
+        }
+        let mut removed = 0;
+        let mut freed = QuotaUsage::default();
+        for (id, p) in self.item_files(mailbox_id).await? {
+            if id < below {
+                if self.limits.quota.is_some() {
+                    freed.bytes += self.stored_len(&p).await;
+                }
+                self.remove_item_files(mailbox_id, &p).await?;
+                removed += 1;
+            }
+        }
+        if self.layout == EnvelopeLayout::Daily {
+            for day in self.day_folders(mailbox_id).await? {
+                self.storage
+                    .delete_folder(&day)
+                    .await
+                    .wrap_err_with(|| format!("Can't remove {day:?}"))?;
+            }
+        }
+        meta.compacted_below = below;
+        self.write_meta_snapshot(mailbox_id, meta).await?;
+        freed.items = removed;
+        self.release_quota(mailbox_id, freed).await?;
+        tracing::debug!("Compacted {removed} items in mailbox {mailbox_id}");
+
+        Ok(removed)
+    }
+
+    /// Applies the [RetentionPolicy] of the mailbox after a send, with the meta already saved
+    async fn trim(&self, mailbox_id: &str, meta: &mut MailboxMeta) -> Result<()> {
+        let Some(policy) = meta.settings.retention.or(self.retention) else {
+            return Ok(());
+        };
+        let first_id = meta.compacted_below.max(1);
+        let retained = (meta.highest_used_id + 1).saturating_sub(first_id);
+        if retained <= policy.max_items {
+            return Ok(());
+        }
+        let keep_from = first_id + (retained - policy.max_items);
+        let mut dropped = 0;
+        if policy.drop_unread {
+            let unread: Vec<u64> = meta.unread_ids().take_while(|id| *id < keep_from).collect();
+            for id in unread {
+                meta.record(JournalRecord::Trim { id, at: self.now() });
+                dropped += 1;
+            }
+            if dropped > 0 {
+                tracing::debug!("Dropped {dropped} unread items in mailbox {mailbox_id}");
+                self.save_meta(mailbox_id, meta).await?;
+                self.notify_space_freed(mailbox_id);
+            }
+        }
+        self.compact_read(mailbox_id, meta, keep_from).await?;
+
+        Ok(())
+    }
+
+    /// [Mailbox::compact] for [IdScheme::Ulid], removes all read items
+    ///
+    /// Note: A read id stays in the meta until its files are gone, so a crash in between can't make it unread again.
+    async fn compact_ulids(&self, mailbox_id: &str, meta: &mut MailboxMeta) -> Result<u64> {
+        let mut removed = 0;
+        let mut freed = QuotaUsage::default();
+        let read: Vec<String> = meta.read_ulids.iter().cloned().collect();
+        for item_id in read {
+            let p = self.item_path_on(mailbox_id, None, &item_id).await;
+            if self.limits.quota.is_some() {
+                freed.bytes += self.stored_len(&p).await;
+            }
+            self.remove_item_files(mailbox_id, &p).await?;
+            meta.read_ulids.remove(&item_id);
+            removed += 1;
+        }
+        self.write_meta_snapshot(mailbox_id, meta).await?;
+        freed.items = removed;
+        self.release_quota(mailbox_id, freed).await?;
+        tracing::debug!("Compacted {removed} items in mailbox {mailbox_id}");
+
+        Ok(removed)
+    }
+
+    async fn load_envelope(
+        &self,
+        mailbox_id: &str,
+        meta: &MailboxMeta,
+        item_id: &str,
+    ) -> Result<(PathBuf, Envelope)> {
+        self.check_item_id(item_id)?;
+        let p = self.item_path(mailbox_id, meta, item_id).await;
+        if !self.exists_at(&p).await {
+            return Err(MailboxError::NotFound {
+                mailbox_id: mailbox_id.to_string(),
+                item_id: item_id.to_string(),
+            }
+            .into());
+        }
+        let envelope = Envelope::load_from(&*self.storage, &p)
+            .await
+            .wrap_err_with(|| format!("Broken mailbox {mailbox_id} can't load {item_id}"))?;
+        self.verify_signature(mailbox_id, item_id, &envelope)?;
+
+        Ok((p, envelope))
+    }
+
+    fn verify_signature(&self, mailbox_id: &str, item_id: &str, envelope: &Envelope) -> Result<()> {
+        if let Some(signer) = &self.signer {
+            signer
+                .verify(envelope)
+                .map_err(|reason| MailboxError::SignatureMismatch {
+                    mailbox_id: mailbox_id.to_string(),
+                    item_id: item_id.to_string(),
+                    reason: reason.to_string(),
+                })?;
+        }
+        Ok(())
+    }
+
+    /// Loads the item, and marks its envelope as delivered and read.
+    ///
+    /// Note: The envelope is only touched if the item deserializes, so a broken item is not lost.
+    /// The caller is responsible for updating the meta.
+    async fn take_item(&self, mailbox_id: &str, meta: &MailboxMeta, item_id: &str) -> Result<ITEM> {
+        let (p, mut envelope) = self.load_envelope(mailbox_id, meta, item_id).await?;
+
+        let item = ITEM::deserialize_from_bytes(envelope.data_bytes(&*self.storage).await?)?;
+
+        envelope.increment_delivery_count();
+        envelope.mark_read();
+        self.save_status(&p, &mut envelope).await?;
+
+        Ok(item)
+    }
+
+    /// Receive for [DeliveryMode::AtMostOnce], marking the items read right away
+    ///
+    /// Delayed items, and the ones waiting for their message group, are skipped like in [MailboxDisk::visible_unread].
+    /// A broken item is marked read, and its error returned, if it is the first one.
+    /// Otherwise we stop before it, and it is the first one next time.
+    async fn receive_at_most_once(
+        &self,
+        mailbox_id: &str,
+        max: usize,
+    ) -> Result<Vec<(String, ITEM)>> {
+        let _sem = self.lock().await?;
+        let Some(mut meta) = self.find_meta(mailbox_id).await? else {
+            return Ok(Vec::new());
+        };
+        self.check_access(mailbox_id, &meta, true)?;
+
+        let mut items = Vec::new();
+        let mut failure = None;
+        // Note: broken items are left to take_item, and reported below
+        for item_id in self.deliverable_ids(mailbox_id, &meta, max).await? {
+            let ack = self.ack_record(&item_id)?;
+            match self.take_item(mailbox_id, &meta, &item_id).await {
+                Ok(item) => items.push((item_id, item)),
+                Err(e) if items.is_empty() => {
+                    tracing::warn!("Skipping broken item {item_id} in mailbox {mailbox_id}");
+                    failure = Some(e);
+                }
+                Err(_) => break,
+            }
+            meta.record(ack);
+            if failure.is_some() {
+                break;
+            }
+        }
+        if !meta.pending.is_empty() {
+            self.save_meta(mailbox_id, &mut meta).await?;
+            self.notify_space_freed(mailbox_id);
+        }
+
+        match failure {
+            Some(e) => Err(e),
+            None => Ok(items),
+        }
+    }
+
+    async fn ensure_meta(&self, mailbox_id: &str) -> Result<MailboxMeta> {
+        self.load_meta(mailbox_id, !self.read_only && !self.strict)
+            .await
+    }
+
+    /// Like [MailboxDisk::ensure_meta], but `None` for a mailbox that doesn't exist, instead of creating it
+    ///
+    /// For operations that only look, or find nothing to do in an empty mailbox,
+    /// so probing mailbox ids doesn't leave empty folders behind.
+    /// Note: [MailboxDisk::strict] and [MailboxDisk::read_only] still fail with [MailboxError::UnknownMailbox].
+    async fn find_meta(&self, mailbox_id: &str) -> Result<Option<MailboxMeta>> {
+        match self.load_meta(mailbox_id, false).await {
+            Ok(meta) => Ok(Some(meta)),
+            Err(e) if is_unknown_mailbox(&e) && !self.read_only && !self.strict => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Loads the meta, and replays the journal
+    ///
+    /// Unless `create` is set a missing mailbox is reported as [MailboxError::UnknownMailbox], and nothing is written.
+    async fn load_meta(&self, mailbox_id: &str, create: bool) -> Result<MailboxMeta> {
+        let mut meta = self.read_meta(mailbox_id, create).await?;
+        self.fold_sent(mailbox_id, &mut meta).await?;
+
+        Ok(meta)
+    }
+
+    /// Checks the meta only, without loading any envelopes
+    ///
+    /// Note: With [IdScheme::Ulid] the meta doesn't know about sends, so every mailbox might.
+    async fn might_have_unread(&self, mailbox_id: &str) -> Result<bool> {
+        if self.id_scheme == IdScheme::Ulid {
+            return Ok(true);
+        }
+        let _sem = self.lock().await?;
+        match self.load_meta(mailbox_id, false).await {
+            Ok(meta) => meta.any_unread().await,
+            Err(e) if is_unknown_mailbox(&e) && !self.strict => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Like [MailboxDisk::load_meta], without the sends of the id counter, which might have to be saved
+    async fn read_meta(&self, mailbox_id: &str, create: bool) -> Result<MailboxMeta> {
+        if let Some(meta) = self.cached_meta(mailbox_id).await {
+            return Ok(meta);
+        }
+        // Note: taken before loading, so a change in between is found next time
+        let stamp = self.validation_stamp(mailbox_id).await;
+        let p = self.meta_path(mailbox_id).await;
+        if create {
+            self.ensure_mailbox_folder_exists(mailbox_id).await?;
+        } else if !self.exists_at(&p).await {
+            return Err(MailboxError::UnknownMailbox {
+                mailbox_id: mailbox_id.to_string(),
+            }
+            .into());
+        }
+
+        tracing::debug!("{p:?}");
+        let meta = if !create || self.exists_at(&p).await {
+            // load
+            tracing::debug!("Loading existing meta for {mailbox_id}.");
+            MailboxMeta::load_from(&*self.storage, &p).await?
+        } else {
+            // create
+            tracing::debug!("Meta for {mailbox_id} does not exist -> creating!");
+            let meta = MailboxMeta {
+                layout: self.layout,
+                id_scheme: self.id_scheme,
+                mailbox_id: self.encode_ids.then(|| mailbox_id.to_string()),
+                ..Default::default()
+            };
+            meta.save(&*self.storage, &p).await?;
+            meta
+        };
+        if meta.layout != self.layout {
+            return Err(MailboxError::LayoutMismatch {
+                mailbox_id: mailbox_id.to_string(),
+                expected: self.layout,
+                found: meta.layout,
+            }
+            .into());
+        }
+        if meta.id_scheme != self.id_scheme {
+            return Err(MailboxError::IdSchemeMismatch {
+                mailbox_id: mailbox_id.to_string(),
+                expected: self.id_scheme,
+                found: meta.id_scheme,
+            }
+            .into());
+        }
+
+        let mut meta = meta;
+        let jp = self.journal_path(mailbox_id).await;
+        if self.exists_at(&jp).await {
+            meta.replay(&*self.storage, &jp)
+                .await
+                .wrap_err_with(|| format!("Broken journal for mailbox {mailbox_id}"))?;
+        }
+        self.cache_meta(mailbox_id, &meta, stamp);
+
+        Ok(meta)
+    }
+
+    /// The cached meta, if [MailboxDisk::with_meta_cache] is on, and it can be trusted
+    async fn cached_meta(&self, mailbox_id: &str) -> Option<MailboxMeta> {
+        if self.metas.cache_mode == CacheMode::Off {
+            return None;
+        }
+        let cached = self
+            .metas
+            .cache
+            .lock()
+            .unwrap()
+            .get(mailbox_id)
+            .map(|cached| (cached.stamp, cached.meta.clone()));
+        let Some((cached_stamp, meta)) = cached else {
+            self.metas.cache_misses.fetch_add(1, Ordering::Relaxed);
+            return None;
+        };
+        if self.metas.cache_mode == CacheMode::Validate {
+            // Note: on any doubt, e.g. a file we can't stat, the meta is loaded again
+            match self.meta_stamp(mailbox_id).await {
+                Ok(stamp) if Some(stamp) == cached_stamp => {}
+                _ => {
+                    self.metas.cache.lock().unwrap().remove(mailbox_id);
+                    self.metas
+                        .cache_invalidations
+                        .fetch_add(1, Ordering::Relaxed);
+                    return None;
+                }
+            }
+        }
+        self.metas.cache_hits.fetch_add(1, Ordering::Relaxed);
+        Some(meta)
+    }
+
+    /// Note: [CacheMode::Validate] doesn't cache without a `stamp`, see [MailboxDisk::validation_stamp].
+    fn cache_meta(&self, mailbox_id: &str, meta: &MailboxMeta, stamp: Option<MetaStamp>) {
+        match self.metas.cache_mode {
+            CacheMode::Off => return,
+            CacheMode::Exclusive => {}
+            CacheMode::Validate if stamp.is_none() => return self.forget_meta(mailbox_id),
+            CacheMode::Validate => {}
+        }
+        let cached = CachedMeta {
+            stamp,
+            meta: meta.clone(),
+        };
+        self.metas
+            .cache
+            .lock()
+            .unwrap()
+            .insert(mailbox_id.to_string(), cached);
+    }
+
+    /// Called right before the meta, or its journal, is written, the operation is done from here on
+    ///
+    /// Note: If the future is dropped now, the write still happens, so the cached meta can't be trusted.
+    fn start_meta_commit(&self, mailbox_id: &str) {
+        self.forget_meta(mailbox_id);
+        self.metas.commits.fetch_add(1, Ordering::SeqCst);
+    }
+
+    /// Undoes the `writes` when dropped before [CancelGuard::disarm], unless the meta was written since
+    ///
+    /// Note: The operations using this hold the lock, so the meta written is theirs.
+    fn undo_until_committed(
+        &self,
+        writes: Vec<(PathBuf, Option<Vec<u8>>)>,
+    ) -> CancelGuard<impl FnOnce() + '_> {
+        let commits = self.metas.commits.load(Ordering::SeqCst);
+        CancelGuard::new(move || {
+            if self.metas.commits.load(Ordering::SeqCst) != commits {
+                return;
+            }
+            for (key, value) in writes {
+                self.storage.clone().undo(key, value);
+            }
+        })
+    }
+
+    fn forget_meta(&self, mailbox_id: &str) {
+        if self.metas.cache_mode != CacheMode::Off {
+            self.metas.cache.lock().unwrap().remove(mailbox_id);
+        }
+    }
+
+    /// The stamp to cache a meta with, `None` unless [CacheMode::Validate], or if the files can't be checked
+    async fn validation_stamp(&self, mailbox_id: &str) -> Option<MetaStamp> {
+        if self.metas.cache_mode != CacheMode::Validate {
+            return None;
+        }
+        self.meta_stamp(mailbox_id).await.ok()
+    }
+
+    /// What [CacheMode::Validate] compares, the meta and the journal
+    async fn meta_stamp(&self, mailbox_id: &str) -> Result<MetaStamp> {
+        Ok([
+            stat(&*self.storage, &self.meta_path(mailbox_id).await).await?,
+            stat(&*self.storage, &self.journal_path(mailbox_id).await).await?,
+        ])
+    }
+
+    /// The items from `from_id` on, without marking anything read, see [crate::MailboxTail]
+    ///
+    /// Starts with the lowest unread item, or the oldest item for `all`, if `from_id` is `None`,
+    /// or if the mailbox was recreated in the meantime.
+    /// Returns the entries, and the id to continue from.
+    pub(crate) async fn tail_entries(
+        &self,
+        mailbox_id: &str,
+        from_id: Option<u64>,
+        all: bool,
+    ) -> Result<(Vec<TailEntry>, u64)> {
+        self.check_numeric_ids("tail")?;
+        let _sem = self.lock().await?;
+        let meta = self.load_meta(mailbox_id, false).await?;
+
+        let first_id = if all {
+            meta.compacted_below.max(1)
+        } else {
+            meta.lowest_unread_id
+        };
+        let from_id = match from_id {
+            Some(id) if id <= meta.highest_used_id + 1 => id,
+            Some(id) => {
+                tracing::warn!("Mailbox {mailbox_id} was recreated, starting over after {id}");
+                first_id
+            }
+            None => first_id,
+        };
+        let mut entries = Vec::new();
+        for id in from_id.max(first_id)..=meta.highest_used_id {
+            let read = id < meta.lowest_unread_id || meta.read_ids.contains(&id);
+            if read && !all {
+                continue;
+            }
+            let item_id = format!("{id}");
+            let envelope = match self.load_envelope(mailbox_id, &meta, &item_id).await {
+                Ok((_p, envelope)) => envelope,
+                Err(e) if is_not_found(&e) => continue,
+                Err(e) => return Err(e),
+            };
+            entries.push(TailEntry {
+                item_id,
+                sent_at: envelope.sent_at,
+                read: read || envelope.read(),
+                data: envelope.data(&*self.storage).await?,
+            });
+        }
+
+        Ok((entries, meta.highest_used_id + 1))
+    }
+
+    /// All items, read and unread, in id order, without affecting their delivery
+    ///
+    /// The range of ids is taken when the scan starts, the lock is only held for that.
+    /// Items that can't be loaded are reported as an error, and the scan continues after them.
+    /// Items removed in the meantime, e.g. by `compact`, are skipped.
+    pub fn scan<'a>(
+        &'a self,
+        mailbox_id: &'a str,
+    ) -> impl Stream<Item = Result<ScannedItem<ITEM>>> + std::marker::Send + 'a
+    where
+        ITEM: std::marker::Send,
+    {
+        async_stream::stream! {
+            self.check_numeric_ids("scan")?;
+            let meta = {
+                let _sem = self.lock().await?;
+                self.load_meta(mailbox_id, false).await?
+            };
+            for id in meta.compacted_below.max(1)..=meta.highest_used_id {
+                let item_id = format!("{id}");
+                let envelope = match self.load_envelope(mailbox_id, &meta, &item_id).await {
+                    Ok((_p, envelope)) => envelope,
+                    Err(e) if is_not_found(&e) => continue,
+                    Err(e) => {
+                        yield Err(e);
+                        continue;
+                    }
+                };
+                let read = id < meta.lowest_unread_id || meta.read_ids.contains(&id);
+                yield envelope.data(&*self.storage).await.map(|data| ScannedItem {
+                    item_id,
+                    read,
+                    delivery_count: envelope.delivery_count(),
+                    sent_at: envelope.sent_at,
+                    updated_at: envelope.updated_at,
+                    headers: envelope.headers.clone(),
+                    provenance: envelope.provenance.clone(),
+                    data,
+                    item_type: PhantomData,
+                });
+            }
+        }
+    }
+
+    /// The numeric `send`, for an already serialized item
+    ///
+    /// `cancel` is only looked at until the envelope is written.
+    async fn send_serialized(
+        &self,
+        mailbox_id: &str,
+        data: Vec<u8>,
+        headers: BTreeMap<String, String>,
+        expires_at: Option<DateTime<Utc>>,
+        group_id: Option<&str>,
+        cancel: Option<&CancellationToken>,
+    ) -> Result<String> {
+        self.check_item_size(mailbox_id, data.len() as u64)?;
+        let added = QuotaUsage {
+            items: 1,
+            bytes: data.len() as u64,
+        };
+        let (_sem, mut meta) = self
+            .lock_with_space_or_cancel(mailbox_id, 1, cancel)
+            .await?;
+        Self::check_cancelled("send", mailbox_id, cancel)?;
+        self.check_quota(mailbox_id, &added).await?;
+        tracing::debug!("Before Meta: {meta:?}");
+
+        let now = self.now();
+        let item_id = format!("{}", self.free_ids(mailbox_id, &meta, now, 1).await?);
+        let p = self.new_item_path(mailbox_id, now, &item_id).await;
+        self.ensure_item_folder_exists(&p).await?;
+
+        let headers = self.outgoing_headers(mailbox_id, &item_id, headers, now);
+        let (mut e, sidecar) = self.new_envelope(&item_id, data, headers, now);
+        e.expires_at = expires_at;
+        e.group_id = group_id.map(String::from);
+        tracing::debug!("{e:?}");
+        // Note: the envelope is invisible until the meta is saved, so dropping it is enough
+        let quota_undo = self.quota_undo(mailbox_id).await?;
+        let mut undone = vec![(sidecar_path(&p), None), (p.clone(), None)];
+        undone.extend(quota_undo.iter().cloned());
+        let undo = self.undo_until_committed(undone);
+        let r: Result<()> = async {
+            self.charge_quota(mailbox_id, added).await?;
+            if let Some(sidecar) = sidecar {
+                write_atomic(&*self.storage, &sidecar_path(&p), &sidecar).await?;
+            }
+            e.save_checked(&*self.storage, &p, self.signer.as_ref(), || {
+                self.failpoint(Failpoint::SendEnvelopeWritten)
+            })
+            .await?;
+            self.reach(Failpoint::SendEnvelopeSaved).await?;
+            meta.record(JournalRecord::Send {
+                id: Self::parse_item_id(&item_id)?,
+                at: now,
+            });
+
+            tracing::debug!("After Meta: {meta:?}");
+            self.save_meta(mailbox_id, &mut meta).await
+        }
+        .await;
+        undo.disarm();
+        if let Err(e) = r {
+            self.give_back_quota(quota_undo);
+            return Err(e);
+        }
+        self.reach(Failpoint::SendMetaSaved).await?;
+        self.trim(mailbox_id, &mut meta).await?;
+
+        Ok(item_id)
+    }
+
+    /// The numeric `send_transaction`
+    ///
+    /// `cancel` is looked at before every item is written, and not anymore once they are all written.
+    async fn send_numbered(
+        &self,
+        mailbox_id: &str,
+        items: Vec<ITEM>,
+        cancel: Option<&CancellationToken>,
+    ) -> Result<Vec<String>> {
+        let (_sem, mut meta) = self
+            .lock_with_space_or_cancel(mailbox_id, items.len(), cancel)
+            .await?;
+        tracing::debug!("Before Meta: {meta:?}");
+        let data: Vec<Vec<u8>> = items
+            .iter()
+            .map(|item| self.serialize_checked(mailbox_id, item))
+            .collect::<Result<_>>()?;
+        let added = QuotaUsage {
+            items: data.len() as u64,
+            bytes: data.iter().map(|d| d.len() as u64).sum(),
+        };
+        self.check_quota(mailbox_id, &added).await?;
+
+        let now = self.now();
+        let first_id = self.free_ids(mailbox_id, &meta, now, items.len()).await?;
+        let item_ids: Vec<String> = (first_id..first_id + items.len() as u64)
+            .map(|id| format!("{id}"))
+            .collect();
+
+        let mut paths = Vec::new();
+        for item_id in item_ids.iter() {
+            paths.push(self.new_item_path(mailbox_id, now, item_id).await);
+        }
+
+        // Note: nothing is visible before the meta is saved,
+        // so on failure we only have to remove the files we wrote
+        let mut undone = Vec::new();
+        for p in paths.iter() {
+            undone.push((sidecar_path(p), None));
+            undone.push((tmp_path(p)?, None));
+            undone.push((p.clone(), None));
+        }
+        let quota_undo = self.quota_undo(mailbox_id).await?;
+        undone.extend(quota_undo.iter().cloned());
+        let undo = self.undo_until_committed(undone);
+        let mut written = Vec::new();
+        let r: Result<()> = async {
+            self.charge_quota(mailbox_id, added).await?;
+            let mut staged = Vec::new();
+            for (((item_id, item), data), p) in
+                item_ids.iter().zip(items.iter()).zip(data).zip(paths)
+            {
+                Self::check_cancelled("send_transaction", mailbox_id, cancel)?;
+                let headers = self.outgoing_headers(mailbox_id, item_id, item.headers(), now);
+                let (e, sidecar) = self.new_envelope(item_id, data, headers, now);
+                self.ensure_item_folder_exists(&p).await?;
+                if let Some(sidecar) = sidecar {
+                    let sp = sidecar_path(&p);
+                    written.push(sp.clone());
+                    write_atomic(&*self.storage, &sp, &sidecar).await?;
+                }
+                let tmp = tmp_path(&p)?;
+                written.push(tmp.clone());
+                self.storage
+                    .write(&tmp, &e.to_json()?)
+                    .await
+                    .wrap_err_with(|| format!("Can't save to {tmp:?}"))?;
+                staged.push((tmp, p));
+            }
+            for (tmp, p) in staged {
+                rename(&*self.storage, &tmp, &p)
+                    .await
+                    .wrap_err_with(|| format!("Can't save to {p:?}"))?;
+                written.push(p);
+            }
+            for item_id in item_ids.iter() {
+                meta.record(JournalRecord::Send {
+                    id: Self::parse_item_id(item_id)?,
+                    at: now,
+                });
+            }
+            tracing::debug!("After Meta: {meta:?}");
+            self.save_meta(mailbox_id, &mut meta).await
+        }
+        .await;
+        undo.disarm();
+
+        if let Err(e) = r {
+            self.give_back_quota(quota_undo);
+            for p in written {
+                let _ = self.storage.delete(&p).await;
+            }
+            return Err(e);
+        }
+        self.trim(mailbox_id, &mut meta).await?;
+
+        Ok(item_ids)
+    }
+
+    /// The `receive_many` for every delivery mode but [DeliveryMode::AtMostOnce], with `decode` turning the payloads into the results
+    ///
+    /// Items that fail to load or decode are handled according to the [CorruptionPolicy].
+    /// Note: If `decode` fails, the delivery is not counted.
+    async fn receive_decoded<T>(
+        &self,
+        mailbox_id: &str,
+        max: usize,
+        decode: impl Fn(Bytes) -> Result<T>,
+    ) -> Result<Vec<(String, T)>> {
+        let _sem = self.lock().await?;
+        let Some(mut meta) = self.find_meta(mailbox_id).await? else {
+            return Ok(Vec::new());
+        };
+        self.check_access(mailbox_id, &meta, true)?;
+        tracing::debug!("Before Meta: {meta:?}");
+
+        let now = self.now();
+        let policy = self.corruption_policy_of(&meta);
+        let mut decoded = Vec::new();
+        let mut corrupt = Vec::new();
+        let mut message_groups = HashSet::new();
+        for item_id in self.receive_order(mailbox_id, &meta).await? {
+            if decoded.len() == max {
+                break;
+            }
+            let r = match self.load_envelope(mailbox_id, &meta, &item_id).await {
+                Ok((_p, e)) if !self.deliverable(&e, now, &mut message_groups) => continue,
+                Ok((p, e)) => e
+                    .data_bytes(&*self.storage)
+                    .await
+                    .and_then(&decode)
+                    .map(|d| (p, e, d)),
+                Err(e) => Err(e),
+            };
+            match r {
+                Ok((p, mut e, d)) => {
+                    e.deliver(now);
+                    self.save_status(&p, &mut e).await?;
+                    decoded.push((item_id, d));
+                }
+                // Note: I/O errors that might go away are never handled by the policy
+                Err(e)
+                    if policy != CorruptionPolicy::Fail
+                        && !MailboxError::is_retryable_report(&e) =>
+                {
+                    corrupt.push((item_id, e))
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        if !corrupt.is_empty() {
+            self.set_aside(mailbox_id, &mut meta, corrupt).await?;
+        }
+
+        Ok(decoded)
+    }
+
+    fn corruption_policy_of(&self, meta: &MailboxMeta) -> CorruptionPolicy {
+        meta.settings
+            .corruption_policy
+            .unwrap_or(self.corruption_policy)
+    }
+
+    /// Marks corrupt items read, after moving them to the quarantine for [CorruptionPolicy::Quarantine]
+    async fn set_aside(
+        &self,
+        mailbox_id: &str,
+        meta: &mut MailboxMeta,
+        corrupt: Vec<(String, color_eyre::eyre::Report)>,
+    ) -> Result<()> {
+        let mut quarantined = Vec::new();
+        for (item_id, e) in corrupt {
+            let reason = format!("{e:#}");
+            tracing::error!(
+                "Setting aside corrupt item {item_id} in mailbox {mailbox_id} -> {reason}"
+            );
+            if self.corruption_policy_of(meta) == CorruptionPolicy::Quarantine {
+                let quarantine = self.quarantine_path(mailbox_id).await;
+                self.storage
+                    .create_folder(&quarantine)
+                    .await
+                    .wrap_err_with(|| format!("Can't create {quarantine:?}"))?;
+                let p = self.item_path(mailbox_id, meta, &item_id).await;
+                remove_if_exists(&*self.storage, &status_path(&p)).await?;
+                let mut path = quarantine.clone();
+                for from in [sidecar_path(&p), p] {
+                    let Some(name) = from.file_name() else {
+                        continue;
+                    };
+                    path = quarantine.join(name);
+                    self.storage
+                        .rename(&from, &path)
+                        .await
+                        .wrap_err_with(|| format!("Can't move {from:?} to {path:?}"))?;
+                }
+                quarantined.push(QuarantinedItem {
+                    item_id: item_id.clone(),
+                    path,
+                    reason,
+                    quarantined_at: self.now(),
+                });
+            }
+            match self.id_scheme {
+                IdScheme::Numeric => {
+                    let id = Self::parse_item_id(&item_id)?;
+                    meta.record(JournalRecord::Drop { id, at: self.now() });
+                }
+                IdScheme::Ulid => meta.record(self.ack_record(&item_id)?),
+            }
+        }
+        if !quarantined.is_empty() {
+            let mut manifest = self.quarantined_unlocked(mailbox_id).await?;
+            manifest.extend(quarantined);
+            let mp = self.quarantine_path(mailbox_id).await.join("manifest.json");
+            write_atomic(&*self.storage, &mp, &serde_json::to_vec_pretty(&manifest)?).await?;
+        }
+        self.save_meta(mailbox_id, meta).await?;
+        self.notify_space_freed(mailbox_id);
+
+        Ok(())
+    }
+
+    /// Persists the updates recorded since the meta was loaded
+    ///
+    /// Either by appending them to the journal, or by rewriting the meta.
+    async fn save_meta(&self, mailbox_id: &str, meta: &mut MailboxMeta) -> Result<()> {
+        let records = self.events.is_observed().then(|| meta.pending.clone());
+        let r = self.save_meta_uncached(mailbox_id, meta).await;
+        match r {
+            Ok(()) => {
+                self.cache_meta(mailbox_id, meta, self.validation_stamp(mailbox_id).await);
+                if let Some(records) = records {
+                    self.announce(mailbox_id, records, meta).await;
+                }
+            }
+            // Note: a partial journal line has to be found by loading the meta again
+            Err(_) => self.forget_meta(mailbox_id),
+        }
+        r
+    }
+
+    /// Publishes the saved `records` of the default group, followed by the emptiness edge of the mailbox
+    async fn announce(&self, mailbox_id: &str, records: Vec<JournalRecord>, meta: &MailboxMeta) {
+        let events = records
+            .into_iter()
+            .filter_map(|record| {
+                let mailbox_id = mailbox_id.to_string();
+                match record {
+                    JournalRecord::Send { id, .. } => Some(MailboxEvent::ItemSent {
+                        mailbox_id,
+                        item_id: format!("{id}"),
+                    }),
+                    JournalRecord::Ack { id, .. } | JournalRecord::AckThrough { id, .. } => {
+                        Some(MailboxEvent::ItemAcknowledged {
+                            mailbox_id,
+                            item_id: format!("{id}"),
+                        })
+                    }
+                    JournalRecord::UlidAck { id, .. } => Some(MailboxEvent::ItemAcknowledged {
+                        mailbox_id,
+                        item_id: id,
+                    }),
+                    _ => None,
+                }
+            })
+            .collect();
+        self.publish_events(mailbox_id, events, meta).await;
+    }
+
+    async fn publish_events(
+        &self,
+        mailbox_id: &str,
+        events: Vec<MailboxEvent>,
+        meta: &MailboxMeta,
+    ) {
+        let pending = self
+            .unread_item_ids(mailbox_id, meta)
+            .await
+            .ok()
+            .map(|ids| ids.count() as u64);
+        self.events.publish(mailbox_id, events, pending);
+    }
+
+    async fn save_meta_uncached(&self, mailbox_id: &str, meta: &mut MailboxMeta) -> Result<()> {
+        match self.metas.checkpoint_every {
+            Some(every)
+                if !meta.journal_broken && meta.journal_len + meta.pending.len() < every =>
+            {
+                let mut lines = String::new();
+                for record in meta.pending.iter() {
+                    lines.push_str(&record.to_line());
+                    lines.push('\n');
+                }
+                let jp = self.journal_path(mailbox_id).await;
+                self.start_meta_commit(mailbox_id);
+                self.storage
+                    .append(&jp, lines.as_bytes())
+                    .await
+                    .wrap_err_with(|| format!("Can't append to journal {jp:?}"))?;
+                meta.journal_len += meta.pending.len();
+                meta.pending.clear();
+                Ok(())
+            }
+            _ => self.write_meta_snapshot(mailbox_id, meta).await,
+        }
+    }
+
+    async fn write_meta_snapshot(&self, mailbox_id: &str, meta: &mut MailboxMeta) -> Result<()> {
+        let mp = self.meta_path(mailbox_id).await;
+        meta.save_checked(&*self.storage, &mp, || {
+            self.failpoint(Failpoint::MetaWritten)?;
+            self.start_meta_commit(mailbox_id);
+            Ok(())
+        })
+        .await?;
+        // Note: if we crash here the journal is replayed on top of the new meta, which is fine
+        if meta.journal_len > 0 || meta.journal_broken {
+            let jp = self.journal_path(mailbox_id).await;
+            self.storage
+                .delete(&jp)
+                .await
+                .wrap_err_with(|| format!("Can't remove journal {jp:?}"))?;
+        }
+        meta.journal_len = 0;
+        meta.journal_broken = false;
+        meta.pending.clear();
+        // Note: direct callers change the meta without going through save_meta
+        self.forget_meta(mailbox_id);
+
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl<ITEM: MailboxItem + std::marker::Send, S: MailboxStorage> Mailbox<ITEM>
+    for MailboxKv<ITEM, S>
+{
+    async fn ensure_storage_exists(&mut self) -> Result<()> {
+        if self.lock_semaphore.is_closed() {
+            return Err(MailboxError::Closed.into());
+        }
+        if self.read_only {
+            self.storage
+                .list(&self.base_path)
+                .await?
+                .ok_or_else(not_found)
+                .wrap_err_with(|| format!("Missing read-only storage {:?}", &self.base_path))?;
+            return Ok(());
+        }
+        self.ensure_folder_exists().await
+    }
+    /// Folds all journals into their metas, everything fails with [MailboxError::Closed] afterwards
+    ///
+    /// Note: Dropping without closing is fine too, the journals are replayed on the next start.
+    async fn close(&mut self) -> Result<()> {
+        if !self.read_only {
+            let _sem = self.lock().await?;
+            for mailbox_id in self.list_mailboxes().await? {
+                if self.exists_at(&self.journal_path(&mailbox_id).await).await {
+                    let mut meta = self.ensure_meta(&mailbox_id).await?;
+                    self.write_meta_snapshot(&mailbox_id, &mut meta).await?;
+                }
+            }
+        }
+        self.lock_semaphore.close();
+
+        Ok(())
+    }
+
+    async fn create_mailbox(&self, mailbox_id: &str) -> Result<()> {
+        self.check_writable("create_mailbox")?;
+        let _sem = self.lock().await?;
+        self.load_meta(mailbox_id, true).await?;
+        Ok(())
+    }
+    async fn send(&self, mailbox_id: &str, item: ITEM) -> Result<String> {
+        self.timed("send", mailbox_id, async {
+            self.check_writable("send")?;
+            if self.id_scheme == IdScheme::Ulid {
+                let mut item_ids = self.send_ulids(mailbox_id, vec![item]).await?;
+                return item_ids.pop().ok_or_else(|| eyre!("No id sent"));
+            }
+            if self.id_counters.is_some() {
+                let mut item_ids = self.send_counted(mailbox_id, vec![item]).await?;
+                return item_ids.pop().ok_or_else(|| eyre!("No id sent"));
+            }
+            self.send_serialized(
+                mailbox_id,
+                item.serialize()?,
+                item.headers(),
+                None,
+                None,
+                None,
+            )
+            .await
+        })
+        .await
+    }
+    /// Note: Raw items have no headers.
+    async fn send_raw(&self, mailbox_id: &str, data: &[u8]) -> Result<String> {
+        self.check_writable("send_raw")?;
+        if self.id_scheme == IdScheme::Ulid || self.id_counters.is_some() {
+            return self.send(mailbox_id, ITEM::deserialize(data)?).await;
+        }
+        self.send_serialized(mailbox_id, data.to_vec(), BTreeMap::new(), None, None, None)
+            .await
+    }
+    async fn receive_raw(&self, mailbox_id: &str) -> Result<Option<(String, Vec<u8>)>> {
+        self.check_writable("receive_raw")?;
+        if self.delivery_mode_of(mailbox_id).await? == DeliveryMode::AtMostOnce {
+            let Some((item_id, item)) = self.receive_at_most_once(mailbox_id, 1).await?.pop()
+            else {
+                return Ok(None);
+            };
+            return Ok(Some((item_id, item.serialize()?)));
+        }
+        let mut received = self
+            .receive_decoded(mailbox_id, 1, |data| Ok(data.into()))
+            .await?;
+        Ok(received.pop())
+    }
+    async fn send_transaction(&self, mailbox_id: &str, items: Vec<ITEM>) -> Result<Vec<String>> {
+        self.timed("send_transaction", mailbox_id, async {
+            self.check_writable("send_transaction")?;
+            if self.id_scheme == IdScheme::Ulid {
+                return self.send_ulids(mailbox_id, items).await;
+            }
+            if self.id_counters.is_some() {
+                return self.send_counted(mailbox_id, items).await;
+            }
+            self.send_numbered(mailbox_id, items, None).await
+        })
+        .await
+    }
+    async fn receive(&self, mailbox_id: &str) -> Result<Option<(String, ITEM)>> {
+        self.timed("receive", mailbox_id, async {
+            self.check_writable("receive")?;
+            if self.delivery_mode_of(mailbox_id).await? == DeliveryMode::AtMostOnce {
+                return Ok(self.receive_at_most_once(mailbox_id, 1).await?.pop());
+            }
+            let mut received = self
+                .receive_decoded(mailbox_id, 1, ITEM::deserialize_from_bytes)
+                .await?;
+            Ok(received.pop())
+        })
+        .await
+    }
+    /// Note: Mailboxes that don't exist, e.g. after [MailboxDisk::purge_prefix], are [ReceiveStatus::Closed],
+    /// even if they were never created, and a later send would create them.
+    async fn receive_status(&self, mailbox_id: &str) -> Result<ReceiveStatus<ITEM>> {
+        match self.receive(mailbox_id).await {
+            Ok(Some((item_id, item))) => Ok(ReceiveStatus::Received(item_id, item)),
+            Ok(None) if self.exists(mailbox_id).await? => Ok(ReceiveStatus::Empty),
+            Ok(None) => Ok(ReceiveStatus::Closed(ClosedReason::Deleted)),
+            Err(e) => match ClosedReason::of(&e) {
+                Some(reason) => Ok(ReceiveStatus::Closed(reason)),
+                None => Err(e),
+            },
+        }
+    }
+    /// Note: Only mailboxes with unread items in their meta are looked at,
+    /// mailboxes that don't exist are skipped, unless [MailboxDisk::strict].
+    /// Mailboxes frozen for receiving are skipped too.
+    async fn receive_any(&self, mailbox_ids: &[String]) -> Result<Option<(String, String, ITEM)>> {
+        for index in self.delivery.receive_any_turn.order(mailbox_ids.len()) {
+            let mailbox_id = &mailbox_ids[index];
+            if !self.might_have_unread(mailbox_id).await? {
+                continue;
+            }
+            let received = match self.receive(mailbox_id).await {
+                Err(e) if is_frozen(&e) => continue,
+                received => received?,
+            };
+            if let Some((item_id, item)) = received {
+                self.delivery.receive_any_turn.served(index);
+                return Ok(Some((mailbox_id.clone(), item_id, item)));
+            }
+        }
+        Ok(None)
+    }
+    async fn acknowledge(&self, mailbox_id: &str, item_id: &str) -> Result<()> {
+        self.timed("acknowledge", mailbox_id, async {
+            self.check_writable("acknowledge")?;
+            let _sem = self.lock().await?;
+            let mut meta = self.ensure_meta(mailbox_id).await?;
+            tracing::debug!("Before Meta: {meta:?}");
+
+            let (p, mut envelope) = self.load_envelope(mailbox_id, &meta, item_id).await?;
+
+            tracing::debug!("{envelope:?}");
+            if envelope.read() {
+                tracing::warn!(
+                    "Trying to acknowledge message {mailbox_id} {item_id} that is already read!"
+                );
+            }
+            // Note: restores the unread envelope if we are dropped before the meta is saved
+            let (unread_path, unread) = match self.status_files {
+                true => (status_path(&p), envelope.status_json()?),
+                false => (p.clone(), envelope.to_json()?),
+            };
+            let undo = self.undo_until_committed(vec![(unread_path, Some(unread))]);
+            envelope.mark_read();
+
+            meta.record(self.ack_record(item_id)?);
+
+            let r: Result<()> = async {
+                self.save_status_checked(&p, &mut envelope, || {
+                    self.failpoint(Failpoint::AckEnvelopeWritten)
+                })
+                .await?;
+                self.reach(Failpoint::AckEnvelopeSaved).await?;
+
+                tracing::debug!("After Meta: {meta:?}");
+                self.save_meta(mailbox_id, &mut meta).await
+            }
+            .await;
+            undo.disarm();
+            r?;
+            self.reach(Failpoint::AckMetaSaved).await?;
+            self.notify_space_freed(mailbox_id);
+
+            Ok(())
+        })
+        .await
+    }
+    async fn pop(&self, mailbox_id: &str) -> Result<Option<(String, ITEM)>> {
+        self.check_writable("pop")?;
+        if self.delivery_mode_of(mailbox_id).await? == DeliveryMode::AtMostOnce {
+            return Ok(self.receive_at_most_once(mailbox_id, 1).await?.pop());
+        }
+        let _sem = self.lock().await?;
+        let Some(mut meta) = self.find_meta(mailbox_id).await? else {
+            return Ok(None);
+        };
+        self.check_access(mailbox_id, &meta, true)?;
+        tracing::debug!("Before Meta: {meta:?}");
+
+        let Some((item_id, _p, _e)) = self.visible_unread(mailbox_id, &meta, 1).await?.pop() else {
+            return Ok(None);
+        };
+        let item = self.take_item(mailbox_id, &meta, &item_id).await?;
+
+        meta.record(self.ack_record(&item_id)?);
+
+        tracing::debug!("After Meta: {meta:?}");
+        self.save_meta(mailbox_id, &mut meta).await?;
+        self.notify_space_freed(mailbox_id);
+
+        Ok(Some((item_id, item)))
+    }
+    /// Note: Only the items `receive` would deliver right now are drained, in the same order.
+    /// Delayed and expired items, and the ones waiting for their message group, are left unread.
+    async fn drain(&self, mailbox_id: &str, max: Option<usize>) -> Result<Vec<(String, ITEM)>>
+    where
+        ITEM: 'static,
+    {
+        self.check_writable("drain")?;
+        // Note: held for the whole drain, so nothing sent after we started can sneak into it
+        let _sem = self.lock().await?;
+        let Some(mut meta) = self.find_meta(mailbox_id).await? else {
+            return Ok(Vec::new());
+        };
+        self.check_access(mailbox_id, &meta, true)?;
+        tracing::debug!("Before Meta: {meta:?}");
+
+        let mut drained = Vec::new();
+        let mut failure = None;
+        let deliverable = self
+            .deliverable_ids(mailbox_id, &meta, max.unwrap_or(usize::MAX))
+            .await?;
+        for item_id in deliverable {
+            match self.take_item(mailbox_id, &meta, &item_id).await {
+                Ok(item) => {
+                    meta.record(self.ack_record(&item_id)?);
+                    drained.push((item_id, item));
+                }
+                Err(e) => {
+                    failure = Some((item_id, e));
+                    break;
+                }
+            }
+        }
+
+        tracing::debug!("After Meta: {meta:?}");
+        self.save_meta(mailbox_id, &mut meta).await?;
+        self.notify_space_freed(mailbox_id);
+
+        match failure {
+            Some((item_id, e)) => Err(DrainError::new(drained, Some(item_id), e).into()),
+            None => Ok(drained),
+        }
+    }
+    /// Note: Only the meta is updated, the read flags in the envelopes are left as they are.
+    async fn acknowledge_through(&self, mailbox_id: &str, item_id: &str) -> Result<u64> {
+        self.check_writable("acknowledge_through")?;
+        if self.id_scheme == IdScheme::Ulid {
+            return self.acknowledge_ulids_through(mailbox_id, item_id).await;
+        }
+        let mut id = Self::parse_item_id(item_id)?;
+        let _sem = self.lock().await?;
+        let mut meta = self.ensure_meta(mailbox_id).await?;
+        if id > meta.highest_used_id {
+            tracing::warn!(
+                "Acknowledging {mailbox_id} through {id}, clamped to {}",
+                meta.highest_used_id
+            );
+            id = meta.highest_used_id;
+        }
+        let acknowledged = meta.unread_ids().take_while(|unread| *unread <= id).count() as u64;
+        if acknowledged == 0 {
+            return Ok(0);
+        }
+        meta.record(JournalRecord::AckThrough { id, at: self.now() });
+        self.save_meta(mailbox_id, &mut meta).await?;
+        self.notify_space_freed(mailbox_id);
+
+        Ok(acknowledged)
+    }
+    /// Like [MailboxDisk::reject_with_delay] without a delay
+    ///
+    /// Note: With [DeliveryMode::AtMostOnce] the item is read once received, there is nothing to hand back.
+    async fn release(&self, mailbox_id: &str, item_id: &str) -> Result<()> {
+        self.check_writable("release")?;
+        if self.delivery_mode_of(mailbox_id).await? == DeliveryMode::AtMostOnce {
+            return Ok(());
+        }
+        let _sem = self.lock().await?;
+        self.reject_unlocked(mailbox_id, item_id, Duration::ZERO)
+            .await
+    }
+    fn release_later(&self, mailbox_id: &str, item_id: &str) {
+        if self.read_only {
+            return;
+        }
+        let mut released = self.delivery.released.lock().unwrap();
+        released.push((mailbox_id.to_string(), item_id.to_string()));
+    }
+    async fn update(&self, mailbox_id: &str, item_id: &str, item: ITEM) -> Result<()> {
+        self.check_writable("update")?;
+        let data = self.serialize_checked(mailbox_id, &item)?;
+        let _sem = self.lock().await?;
+        let meta = self.ensure_meta(mailbox_id).await?;
+        self.check_access(mailbox_id, &meta, false)?;
+
+        let (p, old) = self.load_envelope(mailbox_id, &meta, item_id).await?;
+        if meta.is_read(item_id) || old.read() {
+            return Err(MailboxError::AlreadyRead {
+                mailbox_id: mailbox_id.to_string(),
+                item_id: item_id.to_string(),
+            }
+            .into());
+        }
+        let old_len = old.payload_len().unwrap_or_default();
+        let new_len = data.len() as u64;
+        let added = QuotaUsage {
+            items: 0,
+            bytes: new_len.saturating_sub(old_len),
+        };
+        self.check_quota(mailbox_id, &added).await?;
+
+        let sent_at = old.sent_at.unwrap_or_else(|| self.now());
+        let (mut e, sidecar) = self.new_envelope(item_id, data, old.headers.clone(), sent_at);
+        // Note: only the payload changes, the delivery state and history of the item are kept
+        e.delivery_count = old.delivery_count();
+        e.deferred_count = old.deferred_count;
+        e.not_before = old.not_before;
+        e.expires_at = old.expires_at;
+        e.group_id = old.group_id.clone();
+        e.in_flight_since = old.in_flight_since;
+        e.provenance = old.provenance.clone();
+        e.updated_at = Some(self.now());
+
+        // Note: the growth is charged before the envelope is saved, and given back if dropped until then
+        let quota_undo = match new_len > old_len {
+            true => self.quota_undo(mailbox_id).await?,
+            false => Vec::new(),
+        };
+        let undo = CancelGuard::new(|| self.give_back_quota(quota_undo.clone()));
+        if new_len > old_len {
+            self.charge_quota(mailbox_id, added).await?;
+        }
+        // Note: a crash between the two writes leaves a sidecar that fails its checksum, never a broken envelope
+        let sp = sidecar_path(&p);
+        if let Some(sidecar) = &sidecar {
+            write_atomic(&*self.storage, &sp, sidecar).await?;
+        }
+        undo.disarm();
+        if let Err(e) = e.save(&*self.storage, &p, self.signer.as_ref()).await {
+            self.give_back_quota(quota_undo);
+            return Err(e);
+        }
+        if sidecar.is_none() && old.payload.is_some() {
+            self.storage
+                .delete(&sp)
+                .await
+                .wrap_err_with(|| format!("Can't remove {sp:?}"))?;
+        }
+        if new_len < old_len {
+            let freed = QuotaUsage {
+                items: 0,
+                bytes: old_len - new_len,
+            };
+            self.release_quota(mailbox_id, freed).await?;
+        }
+
+        Ok(())
+    }
+    async fn peek(&self, mailbox_id: &str) -> Result<Option<(String, ITEM)>> {
+        let _sem = self.lock().await?;
+        let Some(meta) = self.find_meta(mailbox_id).await? else {
+            return Ok(None);
+        };
+
+        let Some((item_id, _p, e)) = self.visible_unread(mailbox_id, &meta, 1).await?.pop() else {
+            return Ok(None);
+        };
+        let item = ITEM::deserialize_from_bytes(e.data_bytes(&*self.storage).await?)?;
+
+        Ok(Some((item_id, item)))
+    }
+    /// Waits until `max_items` can be delivered, so waiting doesn't count deliveries
+    ///
+    /// Woken by [MailboxDisk::watch] if the storage can be watched, and polls for delayed items becoming visible.
+    async fn receive_batch(
+        &self,
+        mailbox_id: &str,
+        max_items: usize,
+        max_wait: Duration,
+    ) -> Result<Vec<(String, ITEM)>> {
+        // Note: watch first, so an item sent in between is not missed
+        #[cfg(feature = "fs-watch")]
+        let mut events = match self.watch(mailbox_id).await {
+            Ok(events) => Some(events),
+            Err(e) if is_unsupported(&e) => None,
+            Err(e) => return Err(e),
+        };
+        let deadline = tokio::time::Instant::now() + max_wait;
+        while self.deliverable_count(mailbox_id, max_items).await? < max_items {
+            let now = tokio::time::Instant::now();
+            if now >= deadline {
+                break;
+            }
+            let wake_at = deadline.min(now + RECEIVE_BATCH_POLL_INTERVAL);
+            #[cfg(feature = "fs-watch")]
+            if let Some(events) = &mut events {
+                use tokio_stream::StreamExt;
+
+                match tokio::time::timeout_at(wake_at, events.next()).await {
+                    Ok(Some(event)) => {
+                        event?;
+                    }
+                    Ok(None) => break,
+                    Err(_) => {}
+                }
+                continue;
+            }
+            tokio::time::sleep_until(wake_at).await;
+        }
+        self.receive_many(mailbox_id, max_items).await
+    }
+    async fn receive_many(&self, mailbox_id: &str, max: usize) -> Result<Vec<(String, ITEM)>> {
+        self.timed("receive_many", mailbox_id, async {
+            self.check_writable("receive_many")?;
+            if self.delivery_mode_of(mailbox_id).await? == DeliveryMode::AtMostOnce {
+                return self.receive_at_most_once(mailbox_id, max).await;
+            }
+            self.receive_decoded(mailbox_id, max, ITEM::deserialize_from_bytes)
+                .await
+        })
+        .await
+    }
+    async fn receive_matching(
+        &self,
+        mailbox_id: &str,
+        selector: &HeaderSelector,
+    ) -> Result<Option<(String, ITEM)>> {
+        self.check_writable("receive_matching")?;
+        let _sem = self.lock().await?;
+        let Some(mut meta) = self.find_meta(mailbox_id).await? else {
+            return Ok(None);
+        };
+        self.check_access(mailbox_id, &meta, true)?;
+
+        // Note: the headers are in the envelope, so non matching payloads are never loaded
+        let now = self.now();
+        let policy = self.corruption_policy_of(&meta);
+        let mut found = None;
+        let mut corrupt = Vec::new();
+        let mut message_groups = HashSet::new();
+        for item_id in self.receive_order(mailbox_id, &meta).await? {
+            // Note: I/O errors that might go away are never handled by the policy
+            let handled = |e: &color_eyre::eyre::Report| {
+                policy != CorruptionPolicy::Fail && !MailboxError::is_retryable_report(e)
+            };
+            let (p, e) = match self.load_envelope(mailbox_id, &meta, &item_id).await {
+                Ok(loaded) => loaded,
+                Err(e) if handled(&e) => {
+                    corrupt.push((item_id, e));
+                    continue;
+                }
+                Err(e) => return Err(e),
+            };
+            if !self.deliverable(&e, now, &mut message_groups) || !selector.matches(&e.headers) {
+                continue;
+            }
+            match e
+                .data_bytes(&*self.storage)
+                .await
+                .and_then(ITEM::deserialize_from_bytes)
+            {
+                Err(err) if handled(&err) => corrupt.push((item_id, err)),
+                item => {
+                    found = Some((item_id, p, e, item));
+                    break;
+                }
+            }
+        }
+        if !corrupt.is_empty() {
+            self.set_aside(mailbox_id, &mut meta, corrupt).await?;
+        }
+        let Some((item_id, p, mut e, item)) = found else {
+            return Ok(None);
+        };
+        let at_most_once =
+            meta.settings.delivery_mode.unwrap_or(self.delivery.mode) == DeliveryMode::AtMostOnce;
+        if item.is_ok() {
+            e.deliver(now);
+        }
+        // Note: like `receive`, a broken item is skipped for at most once delivery
+        if at_most_once {
+            e.mark_read();
+            meta.record(self.ack_record(&item_id)?);
+        }
+        self.save_status(&p, &mut e).await?;
+        if at_most_once {
+            self.save_meta(mailbox_id, &mut meta).await?;
+            self.notify_space_freed(mailbox_id);
+        }
+
+        Ok(Some((item_id, item?)))
+    }
+    async fn list_mailboxes(&self) -> Result<Vec<String>> {
+        if self.lock_semaphore.is_closed() {
+            return Err(MailboxError::Closed.into());
+        }
+        let entries = self
+            .storage
+            .list(&self.base_path)
+            .await
+            .wrap_err_with(|| format!("Can't list {:?}", &self.base_path))?;
+        let Some(entries) = entries else {
+            return Ok(Vec::new());
+        };
+
+        let mut ids = Vec::new();
+        self.list_mailboxes_in(entries, String::new(), &mut ids)
+            .await?;
+        ids.sort();
+
+        Ok(ids)
+    }
+    async fn delivery_count(&self, mailbox_id: &str, item_id: &str) -> Result<u32> {
+        let _sem = self.lock().await?;
+        let Some(meta) = self.find_meta(mailbox_id).await? else {
+            return Err(MailboxError::NotFound {
+                mailbox_id: mailbox_id.to_string(),
+                item_id: item_id.to_string(),
+            }
+            .into());
+        };
+
+        let (_p, envelope) = self.load_envelope(mailbox_id, &meta, item_id).await?;
+
+        Ok(envelope.delivery_count())
+    }
+    async fn list_items_page(
+        &self,
+        mailbox_id: &str,
+        after: Option<&str>,
+        limit: usize,
+    ) -> Result<Page<ItemSummary>> {
+        if let Some(after) = after {
+            self.check_item_id(after)?;
+        }
+        let limit = limit.max(1);
+        let _sem = self.lock().await?;
+        let Some(meta) = self.find_meta(mailbox_id).await? else {
+            return Ok(Page {
+                entries: Vec::new(),
+                next_cursor: None,
+            });
+        };
+
+        let item_ids: Box<dyn Iterator<Item = String> + std::marker::Send> = match self.id_scheme {
+            IdScheme::Numeric => {
+                let after = after.map(Self::parse_item_id).transpose()?;
+                let first_id = (after.unwrap_or_default() + 1).max(meta.compacted_below);
+                Box::new((first_id..=meta.highest_used_id).map(|id| format!("{id}")))
+            }
+            IdScheme::Ulid => {
+                let mut item_ids = self.ulid_item_ids(mailbox_id).await?;
+                item_ids.retain(|id| after.is_none_or(|after| id.as_str() > after));
+                Box::new(item_ids.into_iter())
+            }
+        };
+        let mut entries = Vec::new();
+        for item_id in item_ids {
+            if entries.len() > limit {
+                break;
+            }
+            let envelope = match self.load_envelope(mailbox_id, &meta, &item_id).await {
+                Ok((_p, envelope)) => envelope,
+                Err(e) if is_not_found(&e) => continue,
+                Err(e) => return Err(e),
+            };
+            entries.push(ItemSummary {
+                read: meta.is_read(&item_id),
+                item_id,
+                delivery_count: envelope.delivery_count(),
+                sent_at: envelope.sent_at,
+                size: envelope.payload_len()?,
+            });
+        }
+
+        Ok(Page::new(entries, limit, |e| e.item_id.clone()))
+    }
+    async fn item_size(&self, mailbox_id: &str, item_id: &str) -> Result<u64> {
+        let _sem = self.lock().await?;
+        let Some(meta) = self.find_meta(mailbox_id).await? else {
+            return Err(MailboxError::NotFound {
+                mailbox_id: mailbox_id.to_string(),
+                item_id: item_id.to_string(),
+            }
+            .into());
+        };
+
+        let (_p, envelope) = self.load_envelope(mailbox_id, &meta, item_id).await?;
+
+        envelope.payload_len()
+    }
+    async fn stats(&self, mailbox_id: &str) -> Result<MailboxStats> {
+        let _sem = self.lock().await?;
+        let Some(meta) = self.find_meta(mailbox_id).await? else {
+            return Ok(MailboxStats {
+                disk_usage: Some(0),
+                capacity: self.limits.capacity.map(|(capacity, _)| capacity),
+                ..Default::default()
+            });
+        };
+
+        let (pending, total_sent, last_send_at) = match self.id_scheme {
+            IdScheme::Numeric => (
+                meta.unread_ids().count() as u64,
+                meta.total_sent,
+                meta.last_send_at,
+            ),
+            IdScheme::Ulid => {
+                // Note: sending doesn't touch the meta, but every item sent is either pending or acknowledged
+                let item_ids = self.ulid_item_ids(mailbox_id).await?;
+                let pending = item_ids
+                    .iter()
+                    .filter(|id| !meta.read_ulids.contains(*id))
+                    .count() as u64;
+                let last_send_at = item_ids.last().and_then(|id| ulid::timestamp(id));
+                (pending, meta.total_acknowledged + pending, last_send_at)
+            }
+        };
+        Ok(MailboxStats {
+            pending,
+            total_sent,
+            total_acknowledged: meta.total_acknowledged,
+            total_dropped_unread: meta.total_dropped_unread,
+            last_send_at,
+            last_ack_at: meta.last_ack_at,
+            disk_usage: Some(self.disk_usage_unlocked(mailbox_id).await?),
+            capacity: meta
+                .settings
+                .capacity
+                .or(self.limits.capacity)
+                .map(|(capacity, _)| capacity),
+            frozen: meta.frozen,
+            priorities: self.priority_stats(mailbox_id, &meta).await?,
+        })
+    }
+    async fn receive_for(&self, mailbox_id: &str, group: &str) -> Result<Option<(String, ITEM)>> {
+        if group == DEFAULT_GROUP {
+            return self.receive(mailbox_id).await;
+        }
+        self.check_numeric_ids("receive_for")?;
+        Self::validate_group(group)?;
+        let _sem = self.lock().await?;
+        let Some(meta) = self.find_meta(mailbox_id).await? else {
+            return Ok(None);
+        };
+        self.check_access(mailbox_id, &meta, true)?;
+
+        let id = meta.group_lowest_unread_id(group);
+        if id > meta.highest_used_id {
+            return Ok(None);
+        }
+        // Note: the envelope belongs to the default group, so we don't touch it
+        let item_id = format!("{id}");
+        let (_p, e) = self.load_envelope(mailbox_id, &meta, &item_id).await?;
+        let item = ITEM::deserialize_from_bytes(e.data_bytes(&*self.storage).await?)?;
+
+        Ok(Some((item_id, item)))
+    }
+    async fn acknowledge_for(&self, mailbox_id: &str, group: &str, item_id: &str) -> Result<()> {
+        self.check_writable("acknowledge_for")?;
+        if group == DEFAULT_GROUP {
+            return self.acknowledge(mailbox_id, item_id).await;
+        }
+        self.check_numeric_ids("acknowledge_for")?;
+        Self::validate_group(group)?;
+        let _sem = self.lock().await?;
+        let mut meta = self.ensure_meta(mailbox_id).await?;
+
+        // Note: only to make sure the item exists
+        self.load_envelope(mailbox_id, &meta, item_id).await?;
+        meta.record(JournalRecord::GroupAck {
+            group: group.to_string(),
+            id: Self::parse_item_id(item_id)?,
+            at: self.now(),
+        });
+        self.save_meta(mailbox_id, &mut meta).await?;
+
+        Ok(())
+    }
+    async fn compact(&self, mailbox_id: &str) -> Result<u64> {
+        self.timed("compact", mailbox_id, async {
+            self.check_writable("compact")?;
+            let _sem = self.lock().await?;
+            let mut meta = self.ensure_meta(mailbox_id).await?;
+            if self.id_scheme == IdScheme::Ulid {
+                return self.compact_ulids(mailbox_id, &mut meta).await;
+            }
+
+            self.compact_read(mailbox_id, &mut meta, u64::MAX).await
+        })
+        .await
+    }
+    async fn drop_older_than(&self, mailbox_id: &str, max_age: Duration) -> Result<u64> {
+        self.timed("drop_older_than", mailbox_id, async {
+            self.check_writable("drop_older_than")?;
+            self.check_numeric_ids("drop_older_than")?;
+            let _sem = self.lock().await?;
+            let mut meta = self.ensure_meta(mailbox_id).await?;
+
+            let cutoff = self.now() - max_age;
+            let mut dropped = 0;
+            while meta.any_unread().await? {
+                let item_id = meta.lowest_unread_id().await?;
+                let (p, mut envelope) = self.load_envelope(mailbox_id, &meta, &item_id).await?;
+                // Note: envelopes from before we kept the time are never dropped
+                if envelope.sent_at.is_none_or(|sent_at| sent_at >= cutoff) {
+                    break;
+                }
+                envelope.mark_read();
+                self.save_status(&p, &mut envelope).await?;
+                meta.record(JournalRecord::Drop {
+                    id: Self::parse_item_id(&item_id)?,
+                    at: self.now(),
+                });
+                dropped += 1;
+            }
+            if dropped > 0 {
+                tracing::debug!("Dropped {dropped} old items in mailbox {mailbox_id}");
+                self.save_meta(mailbox_id, &mut meta).await?;
+                self.notify_space_freed(mailbox_id);
+            }
+
+            Ok(dropped)
+        })
+        .await
+    }
+    /// Note: The storage is [HealthStatus::Degraded] with less free space than [MailboxDisk::with_min_free_space].
+    async fn health_check(&self) -> Result<HealthReport> {
+        let started = std::time::Instant::now();
+        let mut report = match self.storage_problem().await {
+            Some(problem) => HealthReport::new(HealthStatus::Unhealthy, problem),
+            None if self.read_only => HealthReport::new(
+                HealthStatus::Healthy,
+                format!("{:?} is readable", self.base_path),
+            ),
+            None => HealthReport::new(
+                HealthStatus::Healthy,
+                format!("{:?} is writable", self.base_path),
+            ),
+        };
+        if report.status != HealthStatus::Unhealthy {
+            report.free_space = self
+                .storage
+                .available_space(&self.base_path)
+                .await
+                .ok()
+                .flatten();
+        }
+        if let (Some(available), Some(min_free_space)) =
+            (report.free_space, self.limits.min_free_space)
+        {
+            if available < min_free_space && !self.read_only {
+                report.status = HealthStatus::Degraded;
+                report.message = format!(
+                    "{:?} has only {available} bytes free, sends need {min_free_space}",
+                    self.base_path
+                );
+            }
+        }
+        report.duration = started.elapsed();
+
+        Ok(report)
+    }
+    /// Note: Expired items are skipped like with `drop_older_than`, and removed by `compact`, or moved to the trash with [MailboxDisk::with_trash].
+    /// Received items are in flight for [MailboxDisk::with_in_flight_timeout],
+    /// and items that can't be loaded are handled according to the [CorruptionPolicy].
+    async fn expire_items(&self, mailbox_id: &str) -> Result<ExpireReport> {
+        self.check_writable("expire_items")?;
+        // Note: only `send_with_ttl` sets an expiry, and it needs numeric ids
+        if self.id_scheme == IdScheme::Ulid || self.id_counters.is_some() {
+            return Ok(ExpireReport::default());
+        }
+        let _sem = self.lock().await?;
+        let mut meta = self.ensure_meta(mailbox_id).await?;
+
+        let now = self.now();
+        let policy = self.corruption_policy_of(&meta);
+        let mut report = ExpireReport::default();
+        let mut corrupt = Vec::new();
+        let unread: Vec<u64> = meta.unread_ids().collect();
+        for id in unread {
+            let item_id = format!("{id}");
+            let (p, mut envelope) = match self.load_envelope(mailbox_id, &meta, &item_id).await {
+                Ok(loaded) => loaded,
+                // Note: I/O errors that might go away are never handled by the policy
+                Err(e) if MailboxError::is_retryable_report(&e) => return Err(e),
+                Err(e) => {
+                    tracing::warn!(
+                        "Can't check expiry of item {item_id} in mailbox {mailbox_id} -> {e:#}"
+                    );
+                    report.corrupt.push(item_id.clone());
+                    if policy != CorruptionPolicy::Fail {
+                        corrupt.push((item_id, e));
+                    }
+                    continue;
+                }
+            };
+            if !envelope.expired_at(now) {
+                continue;
+            }
+            if envelope.in_flight_at(now, self.delivery.in_flight_timeout) {
+                report.in_flight.push(item_id);
+                continue;
+            }
+            envelope.mark_read();
+            self.save_status(&p, &mut envelope).await?;
+            meta.record(JournalRecord::Drop { id, at: now });
+            report.expired.push(item_id);
+        }
+        if !report.expired.is_empty() {
+            tracing::debug!(
+                "Expired {} items in mailbox {mailbox_id}",
+                report.expired.len()
+            );
+        }
+        // Note: setting aside saves the meta too
+        if !corrupt.is_empty() {
+            self.set_aside(mailbox_id, &mut meta, corrupt).await?;
+        } else if !report.expired.is_empty() {
+            self.save_meta(mailbox_id, &mut meta).await?;
+            self.notify_space_freed(mailbox_id);
+        }
+
+        Ok(report)
+    }
+}
+
+#[cfg(feature = "fs-watch")]
+impl<ITEM: MailboxItem, S: MailboxStorage> MailboxKv<ITEM, S> {
+    /// Events for new items in the mailbox, including items sent by other processes
+    ///
+    /// File system events are collected for 50ms, and reported once per item, in order.
+    /// If the platform watcher can't be started, the mailbox folder is polled instead.
+    ///
+    /// Note: Only items sent after the call are reported, and the stream ends when the mailbox is dropped.
+    /// Fails with [MailboxError::Unsupported] if the [MailboxStorage] can't be watched.
+    pub async fn watch(
+        &self,
+        mailbox_id: &str,
+    ) -> Result<impl Stream<Item = Result<WatchEvent>> + Unpin + std::marker::Send + 'static> {
+        if self.strict && !self.exists_at(&self.meta_path(mailbox_id).await).await {
+            return Err(MailboxError::UnknownMailbox {
+                mailbox_id: mailbox_id.to_string(),
+            }
+            .into());
+        }
+        if !self.read_only {
+            self.ensure_mailbox_folder_exists(mailbox_id).await?;
+        }
+        let path = self.mailbox_path(mailbox_id).await;
+        let recursive = self.layout == EnvelopeLayout::Daily;
+        let (raw_tx, mut raw_rx) = tokio::sync::mpsc::unbounded_channel();
+        let Some(watcher) = self.storage.watch(&path, recursive, raw_tx)? else {
+            return Err(MailboxError::Unsupported {
+                op: "watch".to_string(),
+                reason: "the storage can't be watched".to_string(),
+            }
+            .into());
+        };
+
+        let mut seen = match self.id_scheme {
+            IdScheme::Numeric => {
+                SeenItems::Below(self.highest_item_id_on_disk(mailbox_id).await? + 1)
+            }
+            IdScheme::Ulid => {
+                SeenItems::Listed(self.ulid_item_ids(mailbox_id).await?.into_iter().collect())
+            }
+        };
+        let extension = self.extension.clone();
+        let (tx, rx) = tokio::sync::mpsc::channel(16);
+        tokio::spawn(async move {
+            // Note: the watcher stops when dropped
+            let _watcher = watcher;
+            loop {
+                let first = tokio::select! {
+                    _ = tx.closed() => break,
+                    event = raw_rx.recv() => match event {
+                        Some(event) => event,
+                        None => break,
+                    },
+                };
+                tokio::time::sleep(WATCH_DEBOUNCE).await;
+                let mut events = vec![first];
+                while let Ok(event) = raw_rx.try_recv() {
+                    events.push(event);
+                }
+
+                let mut paths = Vec::new();
+                for event in events {
+                    match event {
+                        Ok(event) => {
+                            if matches!(
+                                event.kind,
+                                notify::EventKind::Remove(_) | notify::EventKind::Access(_)
+                            ) {
+                                continue;
+                            }
+                            paths.extend(event.paths);
+                        }
+                        Err(e) => {
+                            if tx.send(Err(e.into())).await.is_err() {
+                                return;
+                            }
+                        }
+                    }
+                }
+                for item_id in seen.new_items(&paths, &extension) {
+                    let event = WatchEvent::NewItem { item_id };
+                    if tx.send(Ok(event)).await.is_err() {
+                        return;
+                    }
+                }
+            }
+        });
+
+        Ok(tokio_stream::wrappers::ReceiverStream::new(rx))
+    }
+
+    /// Like `receive`, but waits up to `timeout` for an item to arrive
+    ///
+    /// Woken by [MailboxDisk::watch], so it also notices items sent by other processes without polling.
+    /// A mailbox frozen for receiving is waited on like an empty one, see [MailboxDisk::set_frozen].
+    pub async fn receive_wait(
+        &self,
+        mailbox_id: &str,
+        timeout: Duration,
+    ) -> Result<Option<(String, ITEM)>>
+    where
+        ITEM: std::marker::Send,
+    {
+        use tokio_stream::StreamExt;
+
+        // Note: watch first, so an item sent in between is not missed
+        let mut events = self.watch(mailbox_id).await?;
+        let deadline = tokio::time::Instant::now() + timeout;
+        let unfrozen = self
+            .unfrozen
+            .lock()
+            .unwrap()
+            .entry(mailbox_id.to_string())
+            .or_default()
+            .clone();
+        loop {
+            // Note: created before receiving, so unfreezing in between still wakes us up
+            let thawed = unfrozen.notified();
+            match self.receive(mailbox_id).await {
+                Ok(Some(received)) => return Ok(Some(received)),
+                Ok(None) => {}
+                Err(e) if is_frozen(&e) => {}
+                Err(e) => return Err(e),
+            }
+            tokio::select! {
+                event = tokio::time::timeout_at(deadline, events.next()) => match event {
+                    Ok(Some(event)) => {
+                        event?;
+                    }
+                    Ok(None) | Err(_) => return Ok(None),
+                },
+                _ = thawed => {}
+            }
+        }
+    }
+}
+
+/// The items a watch has already reported, or that existed before
+///
+/// Note: Envelopes are rewritten when read, so only ids we haven't seen yet are new.
+#[cfg(feature = "fs-watch")]
+enum SeenItems {
+    /// Numeric ids only grow
+    Below(u64),
+    /// ULIDs from other writers can arrive out of order
+    Listed(HashSet<String>),
+}
+
+#[cfg(feature = "fs-watch")]
+impl SeenItems {
+    /// The ids of the items at `paths` that are new, in order
+    fn new_items(&mut self, paths: &[PathBuf], extension: &Path) -> Vec<String> {
+        match self {
+            SeenItems::Below(below) => {
+                let ids: BTreeSet<u64> = paths
+                    .iter()
+                    .filter_map(|p| item_id_of(p, extension))
+                    .filter(|id| *id >= *below)
+                    .collect();
+                if let Some(highest) = ids.last() {
+                    *below = highest + 1;
+                }
+                ids.into_iter().map(|id| format!("{id}")).collect()
+            }
+            SeenItems::Listed(listed) => {
+                let ids: BTreeSet<String> = paths
+                    .iter()
+                    .filter(|p| p.extension() == Some(extension.as_os_str()))
+                    .filter_map(|p| p.file_stem().and_then(|s| s.to_str()))
+                    .filter(|id| ulid::decode(id).is_some() && !listed.contains(*id))
+                    .map(String::from)
+                    .collect();
+                listed.extend(ids.iter().cloned());
+                ids.into_iter().collect()
+            }
+        }
+    }
+}
+
+/// If `mailbox_id` is `prefix`, or below it, see [MailboxDisk::list_mailboxes_with_prefix]
+fn matches_prefix(mailbox_id: &str, prefix: &str) -> bool {
+    if prefix.is_empty() {
+        return true;
+    }
+    match mailbox_id.strip_prefix(prefix) {
+        Some(rest) => rest.is_empty() || rest.starts_with(crate::SCOPE_SEPARATOR),
+        None => false,
+    }
+}
+
+/// If the folder has folders in it, that are not reserved for internal use
+async fn has_scope_folders(storage: &dyn MailboxStorage, path: &Path) -> Result<bool> {
+    for entry in list(storage, path).await? {
+        if entry.is_folder && !file_name_of(&entry.key).starts_with('.') {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+/// The entries in `folder`, fails with `std::io::ErrorKind::NotFound` if it doesn't exist
+async fn list(storage: &dyn MailboxStorage, folder: &Path) -> Result<Vec<StorageEntry>> {
+    storage
+        .list(folder)
+        .await
+        .and_then(|entries| entries.ok_or_else(|| not_found().into()))
+        .wrap_err_with(|| format!("Can't list {folder:?}"))
+}
+
+/// The value at `p`, fails with `std::io::ErrorKind::NotFound` if there is none
+async fn read(storage: &dyn MailboxStorage, p: &Path) -> Result<Vec<u8>> {
+    storage
+        .get(p)
+        .await
+        .and_then(|value| value.ok_or_else(|| not_found().into()))
+        .wrap_err_with(|| format!("Can't load from {p:?}"))
+}
+
+/// Moves the value, fails with `std::io::ErrorKind::NotFound` if there is none
+async fn rename(storage: &dyn MailboxStorage, from: &Path, to: &Path) -> Result<()> {
+    match storage.rename(from, to).await? {
+        true => Ok(()),
+        false => Err(not_found().into()),
+    }
+}
+
+/// Like the error of the file system, for storages that report missing keys as `None`
+fn not_found() -> std::io::Error {
+    std::io::ErrorKind::NotFound.into()
+}
+
+fn file_name_of(key: &Path) -> std::borrow::Cow<'_, str> {
+    key.file_name().unwrap_or_default().to_string_lossy()
+}
+
+/// Ids that are used as folder names as they are, even with [MailboxDisk::with_encoded_ids]
+fn is_plain_mailbox_id(mailbox_id: &str) -> bool {
+    !mailbox_id.is_empty()
+        && !mailbox_id.starts_with(['.', '~'])
+        && mailbox_id
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_' || b == b'.')
+}
+
+/// The id of the item stored at `path`, `None` for everything else, e.g. the meta or temporary files
+fn item_id_of(path: &Path, extension: &Path) -> Option<u64> {
+    if path.extension() != Some(extension.as_os_str()) {
+        return None;
+    }
+    path.file_stem()
+        .and_then(|s| s.to_str())
+        .and_then(|s| s.parse::<u64>().ok())
+}
+
+/// Extensions of the other files in a mailbox folder
+const RESERVED_EXTENSIONS: [&str; 4] = ["json", "journal", "payload", "tmp"];
+
+fn validate_extension(extension: &Path) -> Result<()> {
+    let invalid = |reason: &str| -> Result<()> {
+        Err(MailboxError::InvalidExtension {
+            extension: extension.to_string_lossy().to_string(),
+            reason: reason.to_string(),
+        }
+        .into())
+    };
+    let Some(e) = extension.to_str() else {
+        return invalid("it is not valid UTF-8");
+    };
+    if e.is_empty() {
+        return invalid("it is empty");
+    }
+    if e.contains('.') {
+        return invalid("it contains a dot");
+    }
+    if e.contains(['/', '\\']) {
+        return invalid("it contains a path separator");
+    }
+    if RESERVED_EXTENSIONS.contains(&e) {
+        return invalid("it is used for other files in the mailbox folders");
+    }
+    Ok(())
+}
+
+/// The sidecar with the payload of the envelope at `path`, e.g. `17.payload` for `17.item`
+fn sidecar_path(path: &Path) -> PathBuf {
+    path.with_extension("payload")
+}
+
+/// The status of the envelope at `path`, e.g. `17.status.json` for `17.item`
+fn status_path(path: &Path) -> PathBuf {
+    path.with_extension("status.json")
+}
+
+async fn remove_if_exists(storage: &dyn MailboxStorage, p: &Path) -> Result<()> {
+    storage
+        .delete(p)
+        .await
+        .wrap_err_with(|| format!("Can't remove {p:?}"))
+}
+
+fn is_unknown_mailbox(e: &color_eyre::eyre::Report) -> bool {
+    matches!(
+        e.downcast_ref::<MailboxError>(),
+        Some(MailboxError::UnknownMailbox { .. })
+    )
+}
+
+#[cfg(feature = "fs-watch")]
+fn is_unsupported(e: &color_eyre::eyre::Report) -> bool {
+    matches!(
+        e.downcast_ref::<MailboxError>(),
+        Some(MailboxError::Unsupported { .. })
+    )
+}
+
+fn is_frozen(e: &color_eyre::eyre::Report) -> bool {
+    matches!(
+        e.downcast_ref::<MailboxError>(),
+        Some(MailboxError::Frozen { .. })
+    )
+}
+
+fn is_not_found(e: &color_eyre::eyre::Report) -> bool {
+    matches!(
+        e.downcast_ref::<MailboxError>(),
+        Some(MailboxError::NotFound { .. })
+    )
+}
+
+fn is_already_read(e: &color_eyre::eyre::Report) -> bool {
+    matches!(
+        e.downcast_ref::<MailboxError>(),
+        Some(MailboxError::AlreadyRead { .. })
+    )
+}
+
+/// Undoes a half done write, if the future doing it is dropped before [CancelGuard::disarm]
+///
+/// Note: Errors are not undone, they leave the files behind like a crash would, for [MailboxDisk::check] to find.
+struct CancelGuard<F: FnOnce()> {
+    undo: Option<F>,
+}
+
+impl<F: FnOnce()> CancelGuard<F> {
+    fn new(undo: F) -> Self {
+        Self { undo: Some(undo) }
+    }
+
+    fn disarm(mut self) {
+        self.undo = None;
+    }
+}
+
+impl<F: FnOnce()> Drop for CancelGuard<F> {
+    fn drop(&mut self) {
+        if let Some(undo) = self.undo.take() {
+            undo();
+        }
+    }
+}
+
+/// Puts `data` in place, so readers never observe a partially written file
+///
+/// Note: [FsStorage] writes a temporary file next to `path` and renames it into place.
+async fn write_atomic(storage: &dyn MailboxStorage, path: &Path, data: &[u8]) -> Result<()> {
+    storage
+        .put(path, data)
+        .await
+        .wrap_err_with(|| format!("Can't save to {path:?}"))
+}
+
+/// Like [write_atomic], but `before_rename` can still abort once the temporary file is written
+///
+/// Note: The temporary file is left behind then, like after a crash.
+async fn write_atomic_checked(
+    storage: &dyn MailboxStorage,
+    path: &Path,
+    data: &[u8],
+    before_rename: impl FnOnce() -> Result<()>,
+) -> Result<()> {
+    let tmp_path = tmp_path(path)?;
+
+    storage
+        .write(&tmp_path, data)
+        .await
+        .wrap_err_with(|| format!("Can't save to {tmp_path:?}"))?;
+    before_rename()?;
+    let renamed = storage
+        .rename(&tmp_path, path)
+        .await
+        .and_then(|renamed| match renamed {
+            true => Ok(()),
+            false => Err(not_found().into()),
+        });
+    if renamed.is_err() {
+        let _ = storage.delete(&tmp_path).await;
+    }
+    renamed.wrap_err_with(|| format!("Can't save to {path:?}"))
+}
+
+/// The hidden temporary file next to `path`, e.g. `.1.item.tmp` for `1.item`
+pub(crate) fn tmp_path(path: &Path) -> Result<PathBuf> {
+    let file_name = path
+        .file_name()
+        .ok_or_else(|| eyre!("Can't save to {path:?}: no file name"))?;
+    let mut tmp_name = std::ffi::OsString::from(".");
+    tmp_name.push(file_name);
+    tmp_name.push(".tmp");
+
+    Ok(path.with_file_name(tmp_name))
+}
+
+/// The control state of a [MailboxDisk] mailbox at one point in time
+///
+/// Created by [MailboxDisk::snapshot_meta], and serializable, to keep it around.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MetaSnapshot {
+    captured_at: DateTime<Utc>,
+    /// The items present on disk when the snapshot was taken
+    item_ids: Vec<u64>,
+    meta: MailboxMeta,
+}
+
+impl MetaSnapshot {
+    pub fn captured_at(&self) -> DateTime<Utc> {
+        self.captured_at
+    }
+
+    pub fn item_ids(&self) -> impl Iterator<Item = String> + '_ {
+        self.item_ids.iter().map(|id| format!("{id}"))
+    }
+}
+
+/// How far [MailboxDisk::upgrade_mailbox] got, kept until it is done
+#[derive(Debug, Serialize, Deserialize)]
+struct UpgradeProgress {
+    target: LayoutVersion,
+    /// The last envelope rewritten, in id order
+    upgraded_through: u64,
+}
+
+/// A meta kept by [MailboxDisk::with_meta_cache], with what it was loaded from for [CacheMode::Validate]
+#[derive(Debug)]
+struct CachedMeta {
+    stamp: Option<MetaStamp>,
+    meta: MailboxMeta,
+}
+
+/// The meta, and the journal
+///
+/// Note: The meta is replaced by renaming, so its version changes even if size and time don't.
+type MetaStamp = [Option<StorageStat>; 2];
+
+/// `None` for a missing file
+async fn stat(storage: &dyn MailboxStorage, path: &Path) -> Result<Option<StorageStat>> {
+    storage
+        .stat(path)
+        .await
+        .wrap_err_with(|| format!("Can't stat {path:?}"))
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct MailboxMeta {
+    highest_used_id: u64,
+    lowest_unread_id: u64,
+    read_ids: HashSet<u64>, // Note: this only contains ids above the lowest_unread_id
+    #[serde(default)]
+    total_sent: u64,
+    #[serde(default)]
+    total_acknowledged: u64,
+    #[serde(default)]
+    total_dropped_unread: u64,
+    #[serde(default)]
+    last_send_at: Option<DateTime<Utc>>,
+    #[serde(default)]
+    last_ack_at: Option<DateTime<Utc>>,
+    /// The cursors of all consumer groups, except the default group
+    #[serde(default)]
+    groups: BTreeMap<String, GroupCursor>,
+    /// All items below this id have been removed by compaction
+    #[serde(default)]
+    compacted_below: u64,
+    #[serde(default)]
+    layout: EnvelopeLayout,
+    #[serde(default)]
+    id_scheme: IdScheme,
+    /// The read items of a mailbox with [IdScheme::Ulid], until they are compacted
+    #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+    read_ulids: BTreeSet<String>,
+    /// The original id, if the folder name is encoded
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    mailbox_id: Option<String>,
+    /// Set by [MailboxDisk::set_mailbox_attr]
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    attrs: HashMap<String, String>,
+    /// The day folder of all items from this id on, up to the next entry
+    #[serde(default)]
+    days: BTreeMap<u64, NaiveDate>,
+    /// Set by [MailboxDisk::set_settings]
+    #[serde(default, skip_serializing_if = "MailboxSettings::is_empty")]
+    settings: MailboxSettings,
+    /// Missing in metas written before it was recorded, see [MailboxDisk::upgrade_mailbox]
+    #[serde(default = "LayoutVersion::legacy")]
+    layout_version: LayoutVersion,
+    /// Set by [MailboxDisk::set_frozen]
+    #[serde(default, skip_serializing_if = "is_unfrozen")]
+    frozen: FreezeMode,
+    /// Set by [MailboxDisk::create_mailbox_exclusive]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    owner: Option<OwnershipToken>,
+
+    /// Updates not persisted yet
+    #[serde(skip)]
+    pending: Vec<JournalRecord>,
+    /// Number of journal records replayed on top of the snapshot, or appended since
+    #[serde(skip)]
+    journal_len: usize,
+    /// The journal ends with a partial line, and must not be appended to
+    #[serde(skip)]
+    journal_broken: bool,
+}
+
+impl Default for MailboxMeta {
+    fn default() -> Self {
+        Self {
+            highest_used_id: 0,
+            lowest_unread_id: 1,
+            read_ids: Default::default(),
+            total_sent: 0,
+            total_acknowledged: 0,
+            total_dropped_unread: 0,
+            last_send_at: None,
+            last_ack_at: None,
+            groups: Default::default(),
+            compacted_below: 0,
+            layout: EnvelopeLayout::default(),
+            id_scheme: IdScheme::default(),
+            read_ulids: Default::default(),
+            mailbox_id: None,
+            attrs: Default::default(),
+            days: Default::default(),
+            settings: MailboxSettings::default(),
+            layout_version: LayoutVersion::CURRENT,
+            frozen: FreezeMode::None,
+            owner: None,
+            pending: Vec::new(),
+            journal_len: 0,
+            journal_broken: false,
+        }
+    }
+}
+
+impl MailboxMeta {
+    async fn load_from(storage: &dyn MailboxStorage, path: &Path) -> Result<Self> {
+        let mut m = MailboxMeta::default();
+        m.load(storage, path).await?;
+
+        Ok(m)
+    }
+    async fn load(&mut self, storage: &dyn MailboxStorage, path: &Path) -> Result<()> {
+        let b = read(storage, path).await?;
+        let m = serde_json::from_slice(&b)?;
+        *self = m;
+
+        Ok(())
+    }
+    async fn save(&self, storage: &dyn MailboxStorage, path: &Path) -> Result<()> {
+        self.save_checked(storage, path, || Ok(())).await
+    }
+    async fn save_checked(
+        &self,
+        storage: &dyn MailboxStorage,
+        path: &Path,
+        before_rename: impl FnOnce() -> Result<()>,
+    ) -> Result<()> {
+        let json = serde_json::to_string_pretty(&self)?;
+        let b: Vec<u8> = json.into();
+        write_atomic_checked(storage, path, &b, before_rename).await?;
+        Ok(())
+    }
+
+    /// Applies the update, and remembers it for [MailboxDisk::save_meta]
+    fn record(&mut self, record: JournalRecord) {
+        self.apply(&record);
+        self.pending.push(record);
+    }
+
+    /// Applies the update, unless it is already included
+    ///
+    /// Note: This makes replaying the journal idempotent.
+    fn apply(&mut self, record: &JournalRecord) {
+        match *record {
+            JournalRecord::GroupAck { ref group, id, .. } => {
+                let cursor = self.groups.entry(group.clone()).or_default();
+                // Note: groups created after a compaction start at the oldest remaining item
+                cursor.lowest_unread_id = cursor.lowest_unread_id.max(self.compacted_below);
+                if id == cursor.lowest_unread_id {
+                    cursor.lowest_unread_id += 1;
+                } else if id > cursor.lowest_unread_id {
+                    tracing::warn!("Out of order acknowledgement is not implemented.");
+                }
+            }
+            JournalRecord::Send { id, at } => {
+                if id > self.highest_used_id {
+                    self.highest_used_id = id;
+                    self.total_sent += 1;
+                    self.last_send_at = Some(at);
+                    if self.layout == EnvelopeLayout::Daily {
+                        let day = at.date_naive();
+                        if self.days.last_key_value().map(|(_, d)| *d) != Some(day) {
+                            self.days.insert(id, day);
+                        }
+                    }
+                }
+            }
+            JournalRecord::Ack { id, at } => {
+                if self.mark_read(id) {
+                    self.total_acknowledged += 1;
+                    self.last_ack_at = Some(at);
+                }
+            }
+            JournalRecord::Drop { id, .. } => {
+                self.mark_read(id);
+            }
+            JournalRecord::Trim { id, .. } => {
+                if self.mark_read(id) {
+                    self.total_dropped_unread += 1;
+                }
+            }
+            JournalRecord::AckThrough { id, at } => {
+                let acknowledged = self.mark_read_through(id);
+                if acknowledged > 0 {
+                    self.total_acknowledged += acknowledged;
+                    self.last_ack_at = Some(at);
+                }
+            }
+            JournalRecord::UlidAck { ref id, at } => {
+                if self.read_ulids.insert(id.clone()) {
+                    self.total_acknowledged += 1;
+                    self.last_ack_at = Some(at);
+                }
+            }
+        }
+    }
+
+    /// Applies all complete records in the journal
+    ///
+    /// A partial last line is left over by a crash while appending, and ignored.
+    async fn replay(&mut self, storage: &dyn MailboxStorage, path: &Path) -> Result<()> {
+        let b = read(storage, path).await?;
+        let journal = String::from_utf8_lossy(&b);
+        let mut lines: Vec<&str> = journal.split('\n').collect();
+        let partial = lines.pop().unwrap_or_default();
+        if !partial.is_empty() {
+            tracing::warn!("Ignoring partial journal record {partial:?} in {path:?}");
+            self.journal_broken = true;
+        }
+        for line in lines {
+            let record = JournalRecord::parse(line)?;
+            self.apply(&record);
+            self.journal_len += 1;
+        }
+
+        Ok(())
+    }
+
+    async fn any_unread(&self) -> Result<bool> {
+        Ok(self.highest_used_id >= self.lowest_unread_id)
+    }
+
+    /// All unread ids, in ascending order
+    fn unread_ids(&self) -> impl Iterator<Item = u64> + '_ {
+        (self.lowest_unread_id..=self.highest_used_id).filter(|id| !self.read_ids.contains(id))
+    }
+
+    async fn lowest_unread_id(&self) -> Result<String> {
+        let id = self.lowest_unread_id;
+        let id = format!("{id}");
+
+        Ok(id)
+    }
+
+    /// Note: An invalid id is never read.
+    fn is_read(&self, item_id: &str) -> bool {
+        match self.id_scheme {
+            IdScheme::Numeric => item_id
+                .parse::<u64>()
+                .is_ok_and(|id| id < self.lowest_unread_id || self.read_ids.contains(&id)),
+            IdScheme::Ulid => self.read_ulids.contains(item_id),
+        }
+    }
+
+    fn day_of(&self, id: u64) -> Option<NaiveDate> {
+        self.days.range(..=id).next_back().map(|(_, day)| *day)
+    }
+
+    fn group_lowest_unread_id(&self, group: &str) -> u64 {
+        self.groups
+            .get(group)
+            .map(|c| c.lowest_unread_id)
+            .unwrap_or(1)
+            .max(self.compacted_below)
+    }
+
+    /// All items below this id are read by all consumer groups
+    fn fully_read_below(&self) -> u64 {
+        self.groups
+            .values()
+            .map(|c| c.lowest_unread_id)
+            .fold(self.lowest_unread_id, u64::min)
+    }
+
+    /// Returns how many ids were unread
+    fn mark_read_through(&mut self, id: u64) -> u64 {
+        let id = id.min(self.highest_used_id);
+        let unread = self.unread_ids().take_while(|unread| *unread <= id).count() as u64;
+        if id >= self.lowest_unread_id {
+            self.read_ids.retain(|read| *read > id);
+            self.lowest_unread_id = id + 1;
+            while self.read_ids.remove(&self.lowest_unread_id) {
+                self.lowest_unread_id += 1;
+            }
+        }
+        unread
+    }
+
+    /// Returns `false` if nothing changed
+    ///
+    /// Ids above the lowest unread id are kept in `read_ids`, until the gap below them is closed.
+    fn mark_read(&mut self, id: u64) -> bool {
+        if id < self.lowest_unread_id || id > self.highest_used_id || !self.read_ids.insert(id) {
+            return false;
+        }
+        while self.read_ids.remove(&self.lowest_unread_id) {
+            self.lowest_unread_id += 1;
+        }
+        true
+    }
+}
+
+/// The id allocation of a mailbox, see [MailboxDisk::with_id_counter]
+#[derive(Debug)]
+struct IdCounter {
+    /// The highest id handed out
+    highest: AtomicU64,
+    /// The highest id in the counter file, locked while writing it
+    persisted: tokio::sync::Mutex<u64>,
+    state: Mutex<IdCounterState>,
+}
+
+impl IdCounter {
+    /// Gives up on `ids`, so later ids can be added to the meta without them
+    fn release(&self, ids: &[u64]) {
+        let mut state = self.state.lock().unwrap();
+        state.in_flight.retain(|id| !ids.contains(id));
+    }
+}
+
+#[derive(Debug, Default)]
+struct IdCounterState {
+    /// Ids of envelopes being written
+    in_flight: BTreeSet<u64>,
+    /// Ids of envelopes written, but not in the meta yet, with their send time
+    sent: BTreeMap<u64, DateTime<Utc>>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct GroupCursor {
+    lowest_unread_id: u64,
+}
+
+impl Default for GroupCursor {
+    fn default() -> Self {
+        Self {
+            lowest_unread_id: 1,
+        }
+    }
+}
+
+/// A single meta update, as stored in the journal, e.g. `send 17 2024-03-01T12:00:00Z`
+///
+/// Acknowledgements for a consumer group have the group appended.
+#[derive(Debug, Clone, PartialEq)]
+enum JournalRecord {
+    Send {
+        id: u64,
+        at: DateTime<Utc>,
+    },
+    Ack {
+        id: u64,
+        at: DateTime<Utc>,
+    },
+    GroupAck {
+        group: String,
+        id: u64,
+        at: DateTime<Utc>,
+    },
+    /// Acknowledges everything up to, and including, the id
+    AckThrough {
+        id: u64,
+        at: DateTime<Utc>,
+    },
+    /// Skipped without being acknowledged
+    Drop {
+        id: u64,
+        at: DateTime<Utc>,
+    },
+    /// Dropped by the [RetentionPolicy]
+    Trim {
+        id: u64,
+        at: DateTime<Utc>,
+    },
+    /// [JournalRecord::Ack] for [IdScheme::Ulid]
+    UlidAck {
+        id: String,
+        at: DateTime<Utc>,
+    },
+}
+
+impl JournalRecord {
+    fn to_line(&self) -> String {
+        match self {
+            JournalRecord::Send { id, at } => format!("send {id} {}", at.to_rfc3339()),
+            JournalRecord::Ack { id, at } => format!("ack {id} {}", at.to_rfc3339()),
+            JournalRecord::Drop { id, at } => format!("drop {id} {}", at.to_rfc3339()),
+            JournalRecord::Trim { id, at } => format!("trim {id} {}", at.to_rfc3339()),
+            JournalRecord::AckThrough { id, at } => {
+                format!("ack_through {id} {}", at.to_rfc3339())
+            }
+            JournalRecord::GroupAck { group, id, at } => {
+                format!("ack {id} {} {group}", at.to_rfc3339())
+            }
+            JournalRecord::UlidAck { id, at } => format!("ack_ulid {id} {}", at.to_rfc3339()),
+        }
+    }
+
+    fn parse(line: &str) -> Result<Self> {
+        let parts: Vec<&str> = line.split_whitespace().collect();
+        let (op, id, at, group) = match parts[..] {
+            [op, id, at] => (op, id, at, None),
+            ["ack", id, at, group] => ("ack", id, at, Some(group)),
+            _ => return Err(eyre!("Invalid journal record {line:?}")),
+        };
+        let at = DateTime::parse_from_rfc3339(at)
+            .wrap_err_with(|| format!("Invalid time in journal record {line:?}"))?
+            .with_timezone(&Utc);
+        if let ("ack_ulid", None) = (op, group) {
+            if ulid::decode(id).is_none() {
+                return Err(eyre!("Invalid id in journal record {line:?}"));
+            }
+            let id = id.to_string();
+            return Ok(JournalRecord::UlidAck { id, at });
+        }
+        let id = id
+            .parse::<u64>()
+            .wrap_err_with(|| format!("Invalid id in journal record {line:?}"))?;
+        match (op, group) {
+            ("send", _) => Ok(JournalRecord::Send { id, at }),
+            ("ack", None) => Ok(JournalRecord::Ack { id, at }),
+            ("drop", None) => Ok(JournalRecord::Drop { id, at }),
+            ("trim", None) => Ok(JournalRecord::Trim { id, at }),
+            ("ack_through", None) => Ok(JournalRecord::AckThrough { id, at }),
+            ("ack", Some(group)) => Ok(JournalRecord::GroupAck {
+                group: group.to_string(),
+                id,
+                at,
+            }),
+            _ => Err(eyre!("Invalid journal record {line:?}")),
+        }
+    }
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Envelope {
+    id: String,
+    /// Note: Advisory, e.g. `acknowledge_through` only updates the meta, which is authoritative
+    read: bool,
+    data: String,
+    debug: Option<String>,
+    #[serde(default)]
+    delivery_count: u32,
+    /// Set by [MailboxDisk::defer], not signed
+    #[serde(default, skip_serializing_if = "is_zero")]
+    deferred_count: u32,
+    #[serde(default)]
+    sent_at: Option<DateTime<Utc>>,
+    /// Set by [Mailbox::update], not signed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    updated_at: Option<DateTime<Utc>>,
+    /// Set by [MailboxDisk::reject_with_delay], not signed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    not_before: Option<DateTime<Utc>>,
+    /// Set by [MailboxDisk::send_with_ttl], not signed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    expires_at: Option<DateTime<Utc>>,
+    /// Set by [MailboxDisk::send_grouped], not signed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    group_id: Option<String>,
+    /// When the item was last received, until it is rejected
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    in_flight_since: Option<DateTime<Utc>>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    headers: BTreeMap<String, String>,
+    /// Set whenever the item is moved, e.g. by [MailboxDisk::defer], not signed
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    provenance: Vec<ProvenanceEntry>,
+    /// The length of the payload, missing in envelopes written before it was recorded
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    payload_len: Option<u64>,
+    /// Set if the payload is stored in a sidecar file, instead of `data`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    payload: Option<PayloadRef>,
+    /// HMAC-SHA256 over [Envelope::signed_bytes], see [MailboxDisk::with_signing_key]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    signature: Option<String>,
+    /// Where the envelope was loaded from, to find the sidecar
+    #[serde(skip)]
+    path: Option<PathBuf>,
+}
+
+/// The mutable part of an [Envelope], see [MailboxDisk::with_status_files]
+#[derive(Debug, Serialize, Deserialize)]
+struct EnvelopeStatus {
+    read: bool,
+    delivery_count: u32,
+    #[serde(default, skip_serializing_if = "is_zero")]
+    deferred_count: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    not_before: Option<DateTime<Utc>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    in_flight_since: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct PayloadRef {
+    file: String,
+    len: u64,
+    sha256: String,
+}
+
+/// Returned by [MailboxDisk::receive_stream]
+///
+/// Note: Reads go through [MailboxStorage::reader].
+struct PayloadReader {
+    inner: Box<dyn AsyncRead + std::marker::Send + Unpin>,
+    check: Option<PayloadCheck>,
+}
+
+struct PayloadCheck {
+    hasher: sha2::Sha256,
+    len: u64,
+    expected_len: u64,
+    expected_sha256: String,
+}
+
+impl AsyncRead for PayloadReader {
+    fn poll_read(
+        self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &mut tokio::io::ReadBuf<'_>,
+    ) -> std::task::Poll<std::io::Result<()>> {
+        use sha2::Digest;
+        let this = self.get_mut();
+        let before = buf.filled().len();
+        std::task::ready!(std::pin::Pin::new(&mut this.inner).poll_read(cx, buf))?;
+        let n = buf.filled().len() - before;
+        if let Some(check) = &mut this.check {
+            let filled = buf.filled();
+            check.hasher.update(&filled[filled.len() - n..]);
+            check.len += n as u64;
+            if n == 0 && buf.remaining() > 0 {
+                let sha256 = hex(&std::mem::take(&mut check.hasher).finalize());
+                if check.len != check.expected_len || sha256 != check.expected_sha256 {
+                    return std::task::Poll::Ready(Err(std::io::Error::new(
+                        std::io::ErrorKind::InvalidData,
+                        "payload doesn't match its envelope",
+                    )));
+                }
+            }
+        }
+        std::task::Poll::Ready(Ok(()))
+    }
+}
+
+/// Copies `reader` to `path`, returning the length and checksum of what was written
+async fn stage_stream(
+    storage: &dyn MailboxStorage,
+    path: &Path,
+    reader: &mut (impl AsyncRead + Unpin),
+) -> Result<(u64, String)> {
+    use sha2::Digest;
+    storage
+        .write(path, &[])
+        .await
+        .wrap_err_with(|| format!("Can't save to {path:?}"))?;
+    let mut hasher = sha2::Sha256::new();
+    let mut len = 0;
+    let mut chunk = vec![0u8; 64 * 1024];
+    loop {
+        let mut buf = tokio::io::ReadBuf::new(&mut chunk);
+        std::future::poll_fn(|cx| std::pin::Pin::new(&mut *reader).poll_read(cx, &mut buf)).await?;
+        let data = buf.filled();
+        if data.is_empty() {
+            break;
+        }
+        hasher.update(data);
+        storage
+            .append(path, data)
+            .await
+            .wrap_err_with(|| format!("Can't save to {path:?}"))?;
+        len += data.len() as u64;
+    }
+
+    Ok((len, hex(&hasher.finalize())))
+}
+
+fn is_zero(n: &u32) -> bool {
+    *n == 0
+}
+
+fn is_unfrozen(mode: &FreezeMode) -> bool {
+    !mode.is_frozen()
+}
+
+fn sha256_hex(data: &[u8]) -> String {
+    use sha2::Digest;
+    hex(&sha2::Sha256::digest(data))
+}
+
+fn hex(data: &[u8]) -> String {
+    data.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+/// Signs and verifies envelopes with HMAC-SHA256 (RFC 2104)
+struct EnvelopeSigner {
+    /// The key, hashed if longer than a block, and padded to a block
+    key: [u8; 64],
+    accept_unsigned: bool,
+}
+
+impl std::fmt::Debug for EnvelopeSigner {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("EnvelopeSigner")
+            .field("accept_unsigned", &self.accept_unsigned)
+            .finish_non_exhaustive()
+    }
+}
+
+impl EnvelopeSigner {
+    fn new(key: &[u8]) -> Self {
+        use sha2::Digest;
+        let mut block = [0u8; 64];
+        if key.len() > block.len() {
+            block[..32].copy_from_slice(&sha2::Sha256::digest(key));
+        } else {
+            block[..key.len()].copy_from_slice(key);
+        }
+        Self {
+            key: block,
+            accept_unsigned: false,
+        }
+    }
+
+    fn mac(&self, message: &[u8]) -> [u8; 32] {
+        use sha2::Digest;
+        let pad = |b: u8| self.key.map(|k| k ^ b);
+        let inner = sha2::Sha256::new()
+            .chain_update(pad(0x36))
+            .chain_update(message)
+            .finalize();
+        sha2::Sha256::new()
+            .chain_update(pad(0x5c))
+            .chain_update(inner)
+            .finalize()
+            .into()
+    }
+
+    fn sign(&self, envelope: &Envelope) -> String {
+        hex(&self.mac(&envelope.signed_bytes()))
+    }
+
+    fn verify(&self, envelope: &Envelope) -> std::result::Result<(), &'static str> {
+        let Some(signature) = &envelope.signature else {
+            return if self.accept_unsigned {
+                Ok(())
+            } else {
+                Err("unsigned")
+            };
+        };
+        let expected = self.sign(envelope);
+        // Note: compare in constant time, to not leak how much of a forged signature was right
+        let diff = expected
+            .bytes()
+            .zip(signature.bytes())
+            .fold(0, |d, (a, b)| d | (a ^ b));
+        if diff != 0 || expected.len() != signature.len() {
+            return Err("tampered");
+        }
+        Ok(())
+    }
+}
+
+use base64::prelude::*;
+
+// assert_eq!(BASE64_STANDARD.decode(b"+uwgVQA=")?, b"\xFA\xEC\x20\x55\0");
+// assert_eq!(BASE64_STANDARD.encode(b"\xFF\xEC\x20\x55\0"), "/+wgVQA=");
+impl Envelope {
+    pub fn new(id: &str, data: Vec<u8>, sent_at: DateTime<Utc>) -> Self {
+        let payload_len = Some(data.len() as u64);
+        let data = BASE64_STANDARD.encode(data);
+        Self {
+            id: String::from(id),
+            read: false,
+            data,
+            debug: None,
+            delivery_count: 0,
+            deferred_count: 0,
+            not_before: None,
+            expires_at: None,
+            group_id: None,
+            in_flight_since: None,
+            sent_at: Some(sent_at),
+            updated_at: None,
+            headers: BTreeMap::new(),
+            provenance: Vec::new(),
+            payload_len,
+            payload: None,
+            signature: None,
+            path: None,
+        }
+    }
+
+    /// The immutable parts of the envelope, each prefixed with its length
+    fn signed_bytes(&self) -> Vec<u8> {
+        let sent_at = self.sent_at.map(|t| t.to_rfc3339()).unwrap_or_default();
+        let checksum = self.payload.as_ref().map(|p| p.sha256.as_str());
+        let headers = self
+            .headers
+            .iter()
+            .flat_map(|(key, value)| [key.as_str(), value.as_str()]);
+        let mut bytes = Vec::new();
+        for field in [
+            self.id.as_str(),
+            self.data.as_str(),
+            checksum.unwrap_or_default(),
+            &sent_at,
+        ]
+        .into_iter()
+        .chain(headers)
+        {
+            bytes.extend_from_slice(&(field.len() as u64).to_be_bytes());
+            bytes.extend_from_slice(field.as_bytes());
+        }
+        bytes
+    }
+
+    /// An envelope referencing `data`, which the caller stores in the sidecar file
+    fn with_sidecar(id: &str, data: &[u8], sent_at: DateTime<Utc>) -> Self {
+        let payload = PayloadRef {
+            file: format!("{id}.payload"),
+            len: data.len() as u64,
+            sha256: sha256_hex(data),
+        };
+        Self::with_payload(id, payload, sent_at)
+    }
+
+    fn with_payload(id: &str, payload: PayloadRef, sent_at: DateTime<Utc>) -> Self {
+        Self {
+            payload_len: Some(payload.len),
+            payload: Some(payload),
+            ..Self::new(id, Vec::new(), sent_at)
+        }
+    }
+
+    async fn data(&self, storage: &dyn MailboxStorage) -> Result<Vec<u8>> {
+        Ok(self.data_bytes(storage).await?.into())
+    }
+
+    /// The payload, in a buffer of its own
+    async fn data_bytes(&self, storage: &dyn MailboxStorage) -> Result<Bytes> {
+        let Some(payload) = &self.payload else {
+            let data = &self.data;
+            let data = BASE64_STANDARD.decode(data)?;
+            return Ok(data.into());
+        };
+        let envelope = self
+            .path
+            .as_deref()
+            .ok_or_else(|| eyre!("Envelope {} was not loaded from disk", self.id))?;
+        let sidecar = envelope.with_file_name(&payload.file);
+        let data = storage
+            .get(&sidecar)
+            .await
+            .and_then(|data| data.ok_or_else(|| not_found().into()))
+            .wrap_err_with(|| format!("Can't load payload {sidecar:?} of envelope {envelope:?}"))?;
+        if data.len() as u64 != payload.len || sha256_hex(&data) != payload.sha256 {
+            return Err(eyre!(
+                "Payload {sidecar:?} doesn't match envelope {envelope:?}"
+            ));
+        }
+
+        Ok(data.into())
+    }
+
+    /// Streams the payload, checking the sidecar at the end
+    async fn reader(&self, storage: &dyn MailboxStorage) -> Result<PayloadReader> {
+        let Some(payload) = &self.payload else {
+            let data = BASE64_STANDARD.decode(&self.data)?;
+            return Ok(PayloadReader {
+                inner: Box::new(std::io::Cursor::new(data)),
+                check: None,
+            });
+        };
+        let envelope = self
+            .path
+            .as_deref()
+            .ok_or_else(|| eyre!("Envelope {} was not loaded from disk", self.id))?;
+        let sidecar = envelope.with_file_name(&payload.file);
+        let inner = storage
+            .reader(&sidecar)
+            .await
+            .and_then(|inner| inner.ok_or_else(|| not_found().into()))
+            .wrap_err_with(|| format!("Can't load payload {sidecar:?} of envelope {envelope:?}"))?;
+
+        Ok(PayloadReader {
+            inner,
+            check: Some(PayloadCheck {
+                hasher: Default::default(),
+                len: 0,
+                expected_len: payload.len,
+                expected_sha256: payload.sha256.clone(),
+            }),
+        })
+    }
+
+    /// The length of the payload, old envelopes are decoded to find out
+    fn payload_len(&self) -> Result<u64> {
+        if let Some(len) = self.payload_len.or(self.payload.as_ref().map(|p| p.len)) {
+            return Ok(len);
+        }
+        Ok(BASE64_STANDARD.decode(&self.data)?.len() as u64)
+    }
+
+    fn read(&self) -> bool {
+        self.read
+    }
+
+    fn mark_read(&mut self) {
+        self.read = true;
+        self.in_flight_since = None;
+    }
+
+    fn delivery_count(&self) -> u32 {
+        self.delivery_count
+    }
+
+    fn increment_delivery_count(&mut self) {
+        self.delivery_count += 1;
+    }
+
+    /// Counts the delivery, and puts the item in flight
+    ///
+    /// Note: Only items of a message group are held back while in flight, see [MailboxDisk::deliverable].
+    fn deliver(&mut self, now: DateTime<Utc>) {
+        self.increment_delivery_count();
+        self.in_flight_since = Some(now);
+    }
+
+    fn in_flight_at(&self, now: DateTime<Utc>, timeout: Duration) -> bool {
+        self.in_flight_since.is_some_and(|t| now < t + timeout)
+    }
+
+    /// Past its `not_before`, and not expired yet
+    fn visible_at(&self, now: DateTime<Utc>) -> bool {
+        self.not_before.is_none_or(|t| t <= now) && !self.expired_at(now)
+    }
+
+    fn expired_at(&self, now: DateTime<Utc>) -> bool {
+        self.expires_at.is_some_and(|t| t <= now)
+    }
+
+    /// How long the item has been waiting, zero for envelopes without `sent_at`
+    fn age_at(&self, now: DateTime<Utc>) -> Duration {
+        self.sent_at
+            .and_then(|t| (now - t).to_std().ok())
+            .unwrap_or_default()
+    }
+
+    async fn load_from(storage: &dyn MailboxStorage, path: &Path) -> Result<Self> {
+        let b = read(storage, path).await?;
+        let mut e: Envelope = serde_json::from_slice(&b)?;
+        e.path = Some(path.to_path_buf());
+        let status = status_path(path);
+        let b = storage
+            .get(&status)
+            .await
+            .wrap_err_with(|| format!("Can't load from {status:?}"))?;
+        if let Some(b) = b {
+            let status: EnvelopeStatus = serde_json::from_slice(&b)?;
+            e.read = status.read;
+            e.delivery_count = status.delivery_count;
+            e.deferred_count = status.deferred_count;
+            e.not_before = status.not_before;
+            e.in_flight_since = status.in_flight_since;
+        }
+        Ok(e)
+    }
+
+    pub fn add_debug(&mut self) -> Result<&str> {
+        let data = &self.data;
+        let data = BASE64_STANDARD.decode(data)?;
+        let d = String::from_utf8_lossy(&data).into_owned();
+
+        self.debug = Some(d);
+        Ok(self.debug.as_ref().unwrap())
+    }
+
+    /// Fills in what `target` records in every envelope, returns if anything changed
+    fn upgrade_to(&mut self, target: LayoutVersion, written_at: DateTime<Utc>) -> Result<bool> {
+        if target < LayoutVersion::V2 {
+            return Ok(false);
+        }
+        let mut changed = false;
+        if self.payload_len.is_none() {
+            self.payload_len = Some(self.payload_len()?);
+            changed = true;
+        }
+        // Note: the time is signed, so a signed envelope has to do without it
+        if self.sent_at.is_none() && self.signature.is_none() {
+            self.sent_at = Some(written_at);
+            changed = true;
+        }
+        Ok(changed)
+    }
+
+    fn to_json(&self) -> Result<Vec<u8>> {
+        let json = serde_json::to_string_pretty(&self)?;
+        Ok(json.into())
+    }
+
+    fn status_json(&self) -> Result<Vec<u8>> {
+        let status = EnvelopeStatus {
+            read: self.read,
+            delivery_count: self.delivery_count,
+            deferred_count: self.deferred_count,
+            not_before: self.not_before,
+            in_flight_since: self.in_flight_since,
+        };
+        Ok(serde_json::to_vec_pretty(&status)?)
+    }
+
+    /// Note: Without a signer an existing signature is kept, since it doesn't cover the mutable fields.
+    async fn save(
+        &mut self,
+        storage: &dyn MailboxStorage,
+        path: &Path,
+        signer: Option<&EnvelopeSigner>,
+    ) -> Result<()> {
+        self.save_checked(storage, path, signer, || Ok(())).await
+    }
+    async fn save_checked(
+        &mut self,
+        storage: &dyn MailboxStorage,
+        path: &Path,
+        signer: Option<&EnvelopeSigner>,
+        before_rename: impl FnOnce() -> Result<()>,
+    ) -> Result<()> {
+        if let Some(signer) = signer {
+            self.signature = Some(signer.sign(self));
+        }
+        write_atomic_checked(storage, path, &self.to_json()?, before_rename).await?;
+        // the envelope now has the whole state
+        remove_if_exists(storage, &status_path(path)).await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::Backpressure;
+    use crate::BulkResult;
+    use crate::CacheMode;
+    use crate::CheckIssue;
+    use crate::Clock;
+    use crate::CorruptionPolicy;
+    use crate::DeliveryMode;
+    use crate::DrainError;
+    use crate::EnvelopeLayout;
+    use crate::FairnessPolicy;
+    use crate::HeaderSelector;
+    use crate::LayoutVersion;
+    use crate::Mailbox;
+    use crate::MailboxDisk;
+    use crate::MailboxError;
+    use crate::MailboxEvent;
+    use crate::MailboxItem;
+    use crate::MailboxKv;
+    use crate::MailboxSettings;
+    use crate::MailboxStats;
+    use crate::MailboxStorage;
+    use crate::MemoryStorage;
+    use crate::MockClock;
+    use crate::ProvenanceAction;
+    use crate::QuotaLimit;
+    use crate::QuotaManager;
+    use crate::RawItem;
+    use crate::RetentionPolicy;
+    use crate::ThresholdEvent;
+    use crate::Thresholds;
+    use crate::DEFAULT_GROUP;
+    use crate::MAX_PROVENANCE;
+    use chrono::DateTime;
+    use chrono::Utc;
+    use color_eyre::Result;
+    use serde::Deserialize;
+    use serde::Serialize;
+    use std::collections::BTreeMap;
+    use std::collections::BTreeSet;
+    use std::collections::HashMap;
+    use std::collections::HashSet;
+    use std::env;
+    use std::fs;
+    use std::path::Path;
+    use std::path::PathBuf;
+    use std::sync::atomic::AtomicI64;
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::atomic::Ordering;
+    use std::sync::Arc;
+    use std::sync::Mutex;
+    use std::time::Duration;
+    use std::time::SystemTime;
+    use tempfile::TempDir;
+
+    use test_log::test;
+
+    #[derive(Default, Debug, Serialize, Deserialize)]
+    struct TestItem {
+        data: String,
+    }
+
+    impl TestItem {
+        fn new(data: String) -> Self {
+            Self { data }
+        }
+    }
+
+    impl MailboxItem for TestItem {
+        fn serialize(&self) -> Result<Vec<u8>> {
+            let json = serde_json::to_string_pretty(&self)?;
+
+            Ok(json.into())
+        }
+        fn deserialize(data: &[u8]) -> Result<Self>
+        where
+            Self: Sized,
+        {
+            let i = serde_json::from_slice(data)?;
+
+            Ok(i)
+        }
+    }
+
+    /// An item that serializes to something a [TestItem] can't deserialize
+    #[derive(Default, Debug)]
+    struct BrokenItem {
+        data: Vec<u8>,
+    }
+
+    impl MailboxItem for BrokenItem {
+        fn serialize(&self) -> Result<Vec<u8>> {
+            Ok(b"not json".to_vec())
+        }
+        fn deserialize(data: &[u8]) -> Result<Self>
+        where
+            Self: Sized,
+        {
+            Ok(Self {
+                data: data.to_vec(),
+            })
+        }
+    }
+
+    /// An item that can't be serialized at all
+    #[derive(Default, Debug)]
+    struct UnserializableItem {}
+
+    impl MailboxItem for UnserializableItem {
+        fn serialize(&self) -> Result<Vec<u8>> {
+            Err(color_eyre::eyre::eyre!("Can't serialize"))
+        }
+        fn deserialize(_data: &[u8]) -> Result<Self>
+        where
+            Self: Sized,
+        {
+            Ok(Self {})
+        }
+    }
+
+    /// A [TestItem] with a `kind` header
+    #[derive(Default, Debug, Serialize, Deserialize)]
+    struct KindItem {
+        kind: String,
+        data: String,
+    }
+
+    impl MailboxItem for KindItem {
+        fn serialize(&self) -> Result<Vec<u8>> {
+            Ok(serde_json::to_vec(&self)?)
+        }
+        fn deserialize(data: &[u8]) -> Result<Self>
+        where
+            Self: Sized,
+        {
+            Ok(serde_json::from_slice(data)?)
+        }
+        fn headers(&self) -> BTreeMap<String, String> {
+            BTreeMap::from([("kind".to_string(), self.kind.clone())])
+        }
+    }
+
+    #[derive(Default, Debug, Serialize, Deserialize)]
+    struct PriorityItem {
+        priority: u32,
+        data: String,
+    }
+
+    impl PriorityItem {
+        fn new(priority: u32, data: &str) -> Self {
+            Self {
+                priority,
+                data: data.to_string(),
+            }
+        }
+    }
+
+    impl MailboxItem for PriorityItem {
+        fn serialize(&self) -> Result<Vec<u8>> {
+            Ok(serde_json::to_vec(&self)?)
+        }
+        fn deserialize(data: &[u8]) -> Result<Self>
+        where
+            Self: Sized,
+        {
+            Ok(serde_json::from_slice(data)?)
+        }
+        fn headers(&self) -> BTreeMap<String, String> {
+            BTreeMap::from([(
+                crate::PRIORITY_HEADER.to_string(),
+                self.priority.to_string(),
+            )])
+        }
+    }
+
+    async fn create_mailbox<ITEM: MailboxItem + Send + 'static>(
+        dir: &TempDir,
+    ) -> Result<Box<dyn Mailbox<ITEM>>> {
+        let mut path = dir.path().to_path_buf();
+        path.push("test_items");
+        let extension = Path::new("test_item");
+
+        let mailbox = MailboxDisk::<ITEM>::at(&path, extension);
+        let mut mailbox: Box<dyn Mailbox<ITEM>> = Box::new(mailbox);
+        mailbox.ensure_storage_exists().await?;
+
+        Ok(mailbox)
+    }
+
+    async fn create_journaled_mailbox(
+        dir: &TempDir,
+        checkpoint_every: usize,
+    ) -> Result<MailboxDisk<TestItem>> {
+        let mut path = dir.path().to_path_buf();
+        path.push("test_items");
+        let extension = Path::new("test_item");
+
+        let mut mailbox =
+            MailboxDisk::<TestItem>::at(&path, extension).with_journal(checkpoint_every);
+        mailbox.ensure_storage_exists().await?;
+
+        Ok(mailbox)
+    }
+
+    /// All files of the mailbox, with the timestamps removed from meta and envelopes, so they can be compared
+    fn mailbox_files(dir: &TempDir, mailbox_id: &str) -> Result<BTreeMap<String, Vec<u8>>> {
+        let mut path = dir.path().to_path_buf();
+        path.push("test_items");
+        path.push(mailbox_id);
+
+        let mut files = BTreeMap::new();
+        for entry in std::fs::read_dir(path)? {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().to_string();
+            let mut data = std::fs::read(entry.path())?;
+            if name.ends_with(".json") || name.ends_with(".test_item") {
+                let mut json: serde_json::Value = serde_json::from_slice(&data)?;
+                if let Some(json) = json.as_object_mut() {
+                    json.remove("last_send_at");
+                    json.remove("last_ack_at");
+                    json.remove("sent_at");
+                }
+                data = serde_json::to_vec(&json)?;
+            }
+            files.insert(name, data);
+        }
+
+        Ok(files)
+    }
+
+    #[test(tokio::test)]
+    async fn it_debugs() -> Result<()> {
+        let mut path = env::current_dir()?;
+        path.push("data");
+        path.push("test_items");
+        let extension = Path::new("test_item");
+
+        let mailbox = MailboxDisk::<TestItem>::at(&path, extension);
+        println!("{mailbox:?}");
 
         let mailbox: Box<dyn Mailbox<TestItem>> = Box::new(mailbox);
         println!("{mailbox:?}");
@@ -368,39 +7582,4588 @@ mod tests {
     }
 
     #[test(tokio::test)]
-    async fn it_sends_and_receives() -> Result<()> {
-        let mut path = env::current_dir()?;
-        path.push("data");
-        path.push("test_items");
-        let extension = Path::new("test_item");
+    async fn it_sends_and_receives() -> Result<()> {
+        let mut path = env::current_dir()?;
+        path.push("data");
+        path.push("test_items");
+        let extension = Path::new("test_item");
+
+        let mailbox = MailboxDisk::<TestItem>::at(&path, extension);
+        let mut mailbox: Box<dyn Mailbox<TestItem>> = Box::new(mailbox);
+        mailbox
+            .ensure_storage_exists()
+            .await
+            .expect("Storage exists");
+
+        let mailbox_id = String::from("42");
+
+        let item = TestItem::new(String::from("one"));
+        mailbox.send(&mailbox_id, item).await.expect("Can send");
+        let item = TestItem::new(String::from("two"));
+        mailbox.send(&mailbox_id, item).await.expect("Can send");
+
+        let mut count = 0;
+        while let Some((id, item)) = mailbox.receive(&mailbox_id).await.expect("Can receive") {
+            count += 1;
+            tracing::info!("Received {id} {item:?}");
+
+            mailbox.acknowledge(&mailbox_id, &id).await?;
+            // break;
+            if count > 10 {
+                break;
+            }
+        }
+
+        assert!(count == 2);
+
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn it_receives_the_last_unread_item() -> Result<()> {
+        let dir = TempDir::new()?;
+        let extension = Path::new("test_item");
+        let mut mailbox = MailboxDisk::<TestItem>::at(dir.path(), extension);
+        mailbox.ensure_storage_exists().await?;
+
+        let sent_id = mailbox
+            .send("42", TestItem::new(String::from("one")))
+            .await?;
+        let (id, item) = mailbox.receive("42").await?.expect("Item pending");
+        assert_eq!(id, sent_id);
+        assert_eq!(item.data, "one");
+        mailbox.acknowledge("42", &id).await?;
+        assert!(mailbox.receive("42").await?.is_none());
+
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn it_counts_deliveries() -> Result<()> {
+        let dir = TempDir::new()?;
+        let mailbox = create_mailbox::<TestItem>(&dir).await?;
+
+        let mailbox_id = String::from("42");
+
+        let item = TestItem::new(String::from("one"));
+        let sent_id = mailbox.send(&mailbox_id, item).await?;
+        assert_eq!(mailbox.delivery_count(&mailbox_id, &sent_id).await?, 0);
+
+        for expected in 1..=3 {
+            let (id, item) = mailbox.receive(&mailbox_id).await?.expect("Item pending");
+            assert_eq!(id, sent_id);
+            assert_eq!(item.data, "one");
+            assert_eq!(mailbox.delivery_count(&mailbox_id, &id).await?, expected);
+        }
+
+        mailbox.acknowledge(&mailbox_id, &sent_id).await?;
+        assert!(mailbox.receive(&mailbox_id).await?.is_none());
+        assert_eq!(mailbox.delivery_count(&mailbox_id, &sent_id).await?, 3);
+
+        Ok(())
+    }
+
+    #[test]
+    fn it_loads_envelopes_without_delivery_count() -> Result<()> {
+        let json = r#"{ "id": "1", "read": false, "data": "", "debug": null }"#;
+        let envelope: super::Envelope = serde_json::from_str(json)?;
+        assert_eq!(envelope.delivery_count(), 0);
+
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn it_pops_like_receive_and_acknowledge() -> Result<()> {
+        let popped_dir = TempDir::new()?;
+        let popped = create_mailbox::<TestItem>(&popped_dir).await?;
+        let acked_dir = TempDir::new()?;
+        let acked = create_mailbox::<TestItem>(&acked_dir).await?;
+
+        let mailbox_id = String::from("42");
+
+        for data in ["one", "two", "three"] {
+            popped.send(&mailbox_id, TestItem::new(data.into())).await?;
+            acked.send(&mailbox_id, TestItem::new(data.into())).await?;
+        }
+
+        let mut popped_items = Vec::new();
+        while let Some((id, item)) = popped.pop(&mailbox_id).await? {
+            popped_items.push((id, item.data));
+        }
+
+        let mut acked_items = Vec::new();
+        while let Some((id, item)) = acked.receive(&mailbox_id).await? {
+            acked.acknowledge(&mailbox_id, &id).await?;
+            acked_items.push((id, item.data));
+        }
+
+        assert_eq!(popped_items.len(), 3);
+        assert_eq!(popped_items, acked_items);
+        assert_eq!(
+            mailbox_files(&popped_dir, &mailbox_id)?,
+            mailbox_files(&acked_dir, &mailbox_id)?
+        );
+
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn it_does_not_pop_broken_items() -> Result<()> {
+        let dir = TempDir::new()?;
+        let mailbox = create_mailbox::<TestItem>(&dir).await?;
+        let broken = create_mailbox::<BrokenItem>(&dir).await?;
+
+        let mailbox_id = String::from("42");
+        let id = broken.send(&mailbox_id, BrokenItem::default()).await?;
+
+        let before = mailbox_files(&dir, &mailbox_id)?;
+        assert!(mailbox.pop(&mailbox_id).await.is_err());
+        assert_eq!(before, mailbox_files(&dir, &mailbox_id)?);
+
+        // still there for someone who can read it
+        let (received_id, item) = broken.pop(&mailbox_id).await?.expect("Item pending");
+        assert_eq!(received_id, id);
+        assert_eq!(item.data, b"not json");
+
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn it_drains() -> Result<()> {
+        let dir = TempDir::new()?;
+        let mailbox = create_mailbox::<TestItem>(&dir).await?;
+
+        let mailbox_id = String::from("42");
+        for i in 0..25 {
+            mailbox
+                .send(&mailbox_id, TestItem::new(format!("{i}")))
+                .await?;
+        }
+
+        let drained = mailbox.drain(&mailbox_id, Some(10)).await?;
+        assert_eq!(drained.len(), 10);
+
+        let drained = mailbox.drain(&mailbox_id, None).await?;
+        assert_eq!(drained.len(), 15);
+        let data: Vec<String> = drained.into_iter().map(|(_, item)| item.data).collect();
+        let expected: Vec<String> = (10..25).map(|i| format!("{i}")).collect();
+        assert_eq!(data, expected);
+
+        assert!(mailbox.receive(&mailbox_id).await?.is_none());
+        assert!(mailbox.drain(&mailbox_id, None).await?.is_empty());
+
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn it_drains_up_to_a_broken_item() -> Result<()> {
+        let dir = TempDir::new()?;
+        let mailbox = create_mailbox::<TestItem>(&dir).await?;
+        let broken = create_mailbox::<BrokenItem>(&dir).await?;
+
+        let mailbox_id = String::from("42");
+        mailbox
+            .send(&mailbox_id, TestItem::new("one".into()))
+            .await?;
+        mailbox
+            .send(&mailbox_id, TestItem::new("two".into()))
+            .await?;
+        let broken_id = broken.send(&mailbox_id, BrokenItem::default()).await?;
+        mailbox
+            .send(&mailbox_id, TestItem::new("four".into()))
+            .await?;
+
+        let e = mailbox
+            .drain(&mailbox_id, None)
+            .await
+            .expect_err("Drain stops at broken item");
+        let e = e
+            .downcast::<DrainError<TestItem>>()
+            .expect("Error is a DrainError");
+        assert_eq!(e.failed_item_id(), Some(broken_id.as_str()));
+        let (drained, failed_item_id, _error) = e.into_parts();
+        assert_eq!(failed_item_id, Some(broken_id.clone()));
+        let data: Vec<String> = drained.into_iter().map(|(_, item)| item.data).collect();
+        assert_eq!(data, vec!["one", "two"]);
+
+        // the broken item is still unread
+        let (id, _item) = broken.receive(&mailbox_id).await?.expect("Item pending");
+        assert_eq!(id, broken_id);
+
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn it_returns_typed_errors() -> Result<()> {
+        let dir = TempDir::new()?;
+        let mailbox = create_mailbox::<TestItem>(&dir).await?;
+
+        let mailbox_id = String::from("42");
+        mailbox
+            .send(&mailbox_id, TestItem::new("one".into()))
+            .await?;
+
+        let e = mailbox
+            .acknowledge(&mailbox_id, "2")
+            .await
+            .expect_err("Item 2 doesn't exist");
+        assert!(matches!(
+            e.downcast_ref::<MailboxError>(),
+            Some(MailboxError::NotFound { item_id, .. }) if item_id == "2"
+        ));
+        assert!(!MailboxError::is_retryable_report(&e));
+
+        let e = mailbox
+            .acknowledge(&mailbox_id, "../1")
+            .await
+            .expect_err("Invalid id");
+        assert!(matches!(
+            e.downcast_ref::<MailboxError>(),
+            Some(MailboxError::InvalidId { .. })
+        ));
+
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn it_peeks_and_receives_many() -> Result<()> {
+        let dir = TempDir::new()?;
+        let mailbox = create_mailbox::<TestItem>(&dir).await?;
+
+        let mailbox_id = String::from("42");
+        assert!(mailbox.peek(&mailbox_id).await?.is_none());
+        for i in 1..=5 {
+            mailbox
+                .send(&mailbox_id, TestItem::new(format!("{i}")))
+                .await?;
+        }
+
+        let (id, item) = mailbox.peek(&mailbox_id).await?.expect("Item pending");
+        assert_eq!(id, "1");
+        assert_eq!(item.data, "1");
+        assert_eq!(mailbox.delivery_count(&mailbox_id, &id).await?, 0);
+
+        let items = mailbox.receive_many(&mailbox_id, 3).await?;
+        let ids: Vec<&str> = items.iter().map(|(id, _)| id.as_str()).collect();
+        assert_eq!(ids, vec!["1", "2", "3"]);
+        assert_eq!(mailbox.delivery_count(&mailbox_id, "3").await?, 1);
+        assert_eq!(mailbox.delivery_count(&mailbox_id, "4").await?, 0);
+
+        mailbox.acknowledge(&mailbox_id, "1").await?;
+        let items = mailbox.receive_many(&mailbox_id, 10).await?;
+        assert_eq!(items.len(), 4);
+        assert!(mailbox.receive_many(&mailbox_id, 0).await?.is_empty());
+
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn it_lists_mailboxes() -> Result<()> {
+        let dir = TempDir::new()?;
+        let mailbox = create_mailbox::<TestItem>(&dir).await?;
+        assert!(mailbox.list_mailboxes().await?.is_empty());
+
+        mailbox.send("b", TestItem::new("one".into())).await?;
+        mailbox.send("a", TestItem::new("two".into())).await?;
+        assert_eq!(mailbox.list_mailboxes().await?, ["a", "b"]);
+
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn it_keeps_lifetime_counters() -> Result<()> {
+        let dir = TempDir::new()?;
+        let mailbox = create_mailbox::<TestItem>(&dir).await?;
+        let mailbox_id = String::from("42");
+        let stats = mailbox.stats(&mailbox_id).await?;
+        assert!(stats.disk_usage.is_some());
+        assert_eq!(
+            MailboxStats {
+                disk_usage: None,
+                ..stats
+            },
+            MailboxStats::default()
+        );
+
+        for data in ["one", "two", "three", "four"] {
+            mailbox
+                .send(&mailbox_id, TestItem::new(data.into()))
+                .await?;
+        }
+        let (id, _item) = mailbox.receive(&mailbox_id).await?.expect("Item pending");
+        mailbox.acknowledge(&mailbox_id, &id).await?;
+        // acknowledging twice doesn't count
+        mailbox.acknowledge(&mailbox_id, &id).await?;
+        mailbox.pop(&mailbox_id).await?;
+        // receiving alone doesn't count
+        mailbox.receive(&mailbox_id).await?;
+
+        let stats = mailbox.stats(&mailbox_id).await?;
+        assert_eq!(stats.pending, 2);
+        assert_eq!(stats.total_sent, 4);
+        assert_eq!(stats.total_acknowledged, 2);
+        let last_send_at = stats.last_send_at.expect("Sent");
+        let last_ack_at = stats.last_ack_at.expect("Acknowledged");
+        assert!(last_send_at <= last_ack_at);
+
+        // a failed send doesn't count
+        let broken = create_mailbox::<UnserializableItem>(&dir).await?;
+        assert!(broken
+            .send(&mailbox_id, UnserializableItem::default())
+            .await
+            .is_err());
+        assert_eq!(mailbox.stats(&mailbox_id).await?.total_sent, 4);
+
+        mailbox.drain(&mailbox_id, None).await?;
+        let stats = mailbox.stats(&mailbox_id).await?;
+        assert_eq!(stats.pending, 0);
+        assert_eq!(stats.total_sent, 4);
+        assert_eq!(stats.total_acknowledged, 4);
+        assert_eq!(stats.last_send_at, Some(last_send_at));
+
+        Ok(())
+    }
+
+    #[test]
+    fn it_loads_meta_without_counters() -> Result<()> {
+        let json = r#"{ "highest_used_id": 3, "lowest_unread_id": 2, "read_ids": [] }"#;
+        let meta: super::MailboxMeta = serde_json::from_str(json)?;
+        assert_eq!(meta.total_sent, 0);
+        assert_eq!(meta.total_acknowledged, 0);
+        assert!(meta.last_send_at.is_none());
+        assert!(meta.last_ack_at.is_none());
+
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn it_replays_the_journal() -> Result<()> {
+        let dir = TempDir::new()?;
+        let mailbox_id = String::from("42");
+        {
+            let mailbox = create_journaled_mailbox(&dir, 100).await?;
+            for data in ["one", "two", "three"] {
+                mailbox
+                    .send(&mailbox_id, TestItem::new(data.into()))
+                    .await?;
+            }
+            mailbox.pop(&mailbox_id).await?.expect("Item pending");
+            // no checkpoint
+        }
+        let files = mailbox_files(&dir, &mailbox_id)?;
+        let journal = String::from_utf8(files["mailbox_meta.journal"].clone())?;
+        assert_eq!(journal.lines().count(), 4);
+        let meta: serde_json::Value = serde_json::from_slice(&files["mailbox_meta.json"])?;
+        assert_eq!(meta["highest_used_id"], 0);
+
+        // a fresh instance, with or without journaling, sees the same state
+        let journaled = create_journaled_mailbox(&dir, 100).await?;
+        let plain = create_mailbox::<TestItem>(&dir).await?;
+        let stats = journaled.stats(&mailbox_id).await?;
+        assert_eq!(stats, plain.stats(&mailbox_id).await?);
+        assert_eq!(
+            (stats.pending, stats.total_sent, stats.total_acknowledged),
+            (2, 3, 1)
+        );
+        let (id, item) = journaled.peek(&mailbox_id).await?.expect("Item pending");
+        assert_eq!((id.as_str(), item.data.as_str()), ("2", "two"));
+
+        // replaying a journal that is already part of the meta changes nothing,
+        // e.g. after a crash between writing the meta and removing the journal
+        journaled.checkpoint(&mailbox_id).await?;
+        assert!(!mailbox_files(&dir, &mailbox_id)?.contains_key("mailbox_meta.journal"));
+        let mut journal_path = dir.path().to_path_buf();
+        journal_path.push("test_items");
+        journal_path.push(&mailbox_id);
+        journal_path.push("mailbox_meta.journal");
+        std::fs::write(&journal_path, journal)?;
+        // Note: the journal file takes space again
+        let replayed = journaled.stats(&mailbox_id).await?;
+        assert_eq!(
+            MailboxStats {
+                disk_usage: stats.disk_usage,
+                ..replayed
+            },
+            stats
+        );
+
+        // without journaling the journal is folded in on the next update
+        plain
+            .send(&mailbox_id, TestItem::new("four".into()))
+            .await?;
+        assert!(!mailbox_files(&dir, &mailbox_id)?.contains_key("mailbox_meta.journal"));
+        assert_eq!(plain.stats(&mailbox_id).await?.total_sent, 4);
+
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn it_checkpoints_the_journal() -> Result<()> {
+        let dir = TempDir::new()?;
+        let mailbox = create_journaled_mailbox(&dir, 3).await?;
+        let mailbox_id = String::from("42");
+
+        mailbox
+            .send(&mailbox_id, TestItem::new("one".into()))
+            .await?;
+        mailbox
+            .send(&mailbox_id, TestItem::new("two".into()))
+            .await?;
+        assert!(mailbox_files(&dir, &mailbox_id)?.contains_key("mailbox_meta.journal"));
+        mailbox
+            .send(&mailbox_id, TestItem::new("three".into()))
+            .await?;
+        let files = mailbox_files(&dir, &mailbox_id)?;
+        assert!(!files.contains_key("mailbox_meta.journal"));
+        let meta: serde_json::Value = serde_json::from_slice(&files["mailbox_meta.json"])?;
+        assert_eq!(meta["highest_used_id"], 3);
+
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn it_ignores_a_partial_journal_record() -> Result<()> {
+        let dir = TempDir::new()?;
+        let mailbox = create_journaled_mailbox(&dir, 100).await?;
+        let mailbox_id = String::from("42");
+        mailbox
+            .send(&mailbox_id, TestItem::new("one".into()))
+            .await?;
+
+        let mut journal_path = dir.path().to_path_buf();
+        journal_path.push("test_items");
+        journal_path.push(&mailbox_id);
+        journal_path.push("mailbox_meta.journal");
+        let mut journal = std::fs::read(&journal_path)?;
+        journal.extend_from_slice(b"send 2 2024-03-0");
+        std::fs::write(&journal_path, journal)?;
+
+        let mailbox = create_journaled_mailbox(&dir, 100).await?;
+        assert_eq!(mailbox.stats(&mailbox_id).await?.total_sent, 1);
+        let id = mailbox
+            .send(&mailbox_id, TestItem::new("two".into()))
+            .await?;
+        assert_eq!(id, "2");
+        // the broken journal is not appended to
+        assert!(!mailbox_files(&dir, &mailbox_id)?.contains_key("mailbox_meta.journal"));
+        assert_eq!(mailbox.stats(&mailbox_id).await?.total_sent, 2);
+
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn it_never_overwrites_items() -> Result<()> {
+        let dir = TempDir::new()?;
+        let mailbox = create_mailbox::<TestItem>(&dir).await?;
+        let mailbox_id = String::from("42");
+        mailbox
+            .send(&mailbox_id, TestItem::new("old".into()))
+            .await?;
+
+        // lose the meta
+        let mut meta_path = dir.path().to_path_buf();
+        meta_path.push("test_items");
+        meta_path.push(&mailbox_id);
+        meta_path.push("mailbox_meta.json");
+        std::fs::remove_file(&meta_path)?;
+
+        let e = mailbox
+            .send(&mailbox_id, TestItem::new("new".into()))
+            .await
+            .expect_err("Item 1 exists");
+        assert!(matches!(
+            e.downcast_ref::<MailboxError>(),
+            Some(MailboxError::IdCollision { item_id, .. }) if item_id == "1"
+        ));
+        let files = mailbox_files(&dir, &mailbox_id)?;
+        assert!(String::from_utf8_lossy(&files["1.test_item"]).contains("old"));
+
+        let mut path = dir.path().to_path_buf();
+        path.push("test_items");
+        let healing =
+            MailboxDisk::<TestItem>::at(&path, Path::new("test_item")).with_id_collision_healing();
+        let id = healing
+            .send(&mailbox_id, TestItem::new("new".into()))
+            .await?;
+        assert_eq!(id, "2");
+        let items = healing.drain(&mailbox_id, None).await?;
+        let items: Vec<&str> = items.iter().map(|(_id, i)| i.data.as_str()).collect();
+        assert_eq!(items, ["old", "new"]);
+
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn it_keeps_a_cursor_per_group() -> Result<()> {
+        let dir = TempDir::new()?;
+        let mailbox = create_journaled_mailbox(&dir, 100).await?;
+        let mailbox_id = String::from("42");
+        for data in ["one", "two", "three"] {
+            mailbox
+                .send(&mailbox_id, TestItem::new(data.into()))
+                .await?;
+        }
+
+        let mut fast = Vec::new();
+        while let Some((id, item)) = mailbox.receive_for(&mailbox_id, "fast").await? {
+            mailbox.acknowledge_for(&mailbox_id, "fast", &id).await?;
+            fast.push(item.data);
+        }
+        assert_eq!(fast, ["one", "two", "three"]);
+
+        let (id, item) = mailbox
+            .receive_for(&mailbox_id, "slow")
+            .await?
+            .expect("Item pending");
+        assert_eq!(item.data, "one");
+        mailbox.acknowledge_for(&mailbox_id, "slow", &id).await?;
+
+        // the default group is not affected
+        let (id, item) = mailbox.pop(&mailbox_id).await?.expect("Item pending");
+        assert_eq!((id.as_str(), item.data.as_str()), ("1", "one"));
+        let (id, _item) = mailbox
+            .receive_for(&mailbox_id, DEFAULT_GROUP)
+            .await?
+            .expect("Item pending");
+        assert_eq!(id, "2");
+
+        // and survives a restart
+        let mailbox = create_mailbox::<TestItem>(&dir).await?;
+        assert!(mailbox.receive_for(&mailbox_id, "fast").await?.is_none());
+        let (id, _item) = mailbox
+            .receive_for(&mailbox_id, "slow")
+            .await?
+            .expect("Item pending");
+        assert_eq!(id, "2");
+        let (id, _item) = mailbox.receive(&mailbox_id).await?.expect("Item pending");
+        assert_eq!(id, "2");
+
+        let e = mailbox
+            .receive_for(&mailbox_id, "not a group")
+            .await
+            .expect_err("Invalid group");
+        assert!(matches!(
+            e.downcast_ref::<MailboxError>(),
+            Some(MailboxError::InvalidId { .. })
+        ));
+
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn it_delivers_at_most_once() -> Result<()> {
+        let dir = TempDir::new()?;
+        let mut path = dir.path().to_path_buf();
+        path.push("test_items");
+        let mailbox = MailboxDisk::<TestItem>::at(&path, Path::new("test_item"))
+            .with_delivery_mode(DeliveryMode::AtMostOnce);
+        assert_eq!(mailbox.delivery_mode(), DeliveryMode::AtMostOnce);
+        let broken = create_mailbox::<BrokenItem>(&dir).await?;
+        let mailbox_id = String::from("42");
+
+        mailbox
+            .send(&mailbox_id, TestItem::new("one".into()))
+            .await?;
+        broken.send(&mailbox_id, BrokenItem::default()).await?;
+        mailbox
+            .send(&mailbox_id, TestItem::new("three".into()))
+            .await?;
+        mailbox
+            .send(&mailbox_id, TestItem::new("four".into()))
+            .await?;
+
+        let (id, item) = mailbox.receive(&mailbox_id).await?.expect("Item pending");
+        assert_eq!((id.as_str(), item.data.as_str()), ("1", "one"));
+        // no acknowledge needed, and a late one does nothing
+        mailbox.acknowledge(&mailbox_id, &id).await?;
+
+        // the broken item is skipped, but reported
+        assert!(mailbox.receive(&mailbox_id).await.is_err());
+        let (id, _item) = mailbox.receive(&mailbox_id).await?.expect("Item pending");
+        assert_eq!(id, "3");
+        let items = mailbox.receive_many(&mailbox_id, 10).await?;
+        assert_eq!(items.len(), 1);
+        assert!(mailbox.receive(&mailbox_id).await?.is_none());
+
+        let stats = mailbox.stats(&mailbox_id).await?;
+        assert_eq!((stats.pending, stats.total_acknowledged), (0, 4));
+        assert_eq!(mailbox.delivery_count(&mailbox_id, "3").await?, 1);
+
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn it_skips_undeliverable_items_at_most_once() -> Result<()> {
+        let dir = TempDir::new()?;
+        let mut path = dir.path().to_path_buf();
+        path.push("test_items");
+        let at_least_once = MailboxDisk::<TestItem>::at(&path, Path::new("test_item"));
+        let mailbox = MailboxDisk::<TestItem>::at(&path, Path::new("test_item"))
+            .with_delivery_mode(DeliveryMode::AtMostOnce);
+        let mailbox_id = String::from("42");
+
+        at_least_once
+            .send(&mailbox_id, TestItem::new("delayed".into()))
+            .await?;
+        for data in ["a0", "a1"] {
+            mailbox
+                .send_grouped(&mailbox_id, "a", TestItem::new(data.into()))
+                .await?;
+        }
+        let (delayed, _item) = at_least_once
+            .receive(&mailbox_id)
+            .await?
+            .expect("Item pending");
+        at_least_once
+            .reject_with_delay(&mailbox_id, &delayed, Duration::from_secs(3600))
+            .await?;
+
+        // only the oldest item of the group, and nothing delayed
+        let items = mailbox.receive_many(&mailbox_id, 10).await?;
+        let data: Vec<_> = items.iter().map(|(_id, item)| item.data.as_str()).collect();
+        assert_eq!(data, ["a0"]);
+        let (_id, item) = mailbox.receive(&mailbox_id).await?.expect("a1");
+        assert_eq!(item.data, "a1");
+        assert!(mailbox.receive(&mailbox_id).await?.is_none());
+        assert_eq!(mailbox.stats(&mailbox_id).await?.pending, 1);
+
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn it_sends_all_or_nothing() -> Result<()> {
+        let dir = TempDir::new()?;
+        let mailbox = create_mailbox::<TestItem>(&dir).await?;
+        let mailbox_id = String::from("42");
+        mailbox
+            .send(&mailbox_id, TestItem::new("zero".into()))
+            .await?;
+        let before = mailbox_files(&dir, &mailbox_id)?;
+
+        // writing the third of five items fails
+        let mut blocker = dir.path().to_path_buf();
+        blocker.push("test_items");
+        blocker.push(&mailbox_id);
+        blocker.push(".4.test_item.tmp");
+        std::fs::create_dir(&blocker)?;
+
+        let items = || {
+            ["one", "two", "three", "four", "five"]
+                .into_iter()
+                .map(|data| TestItem::new(data.into()))
+                .collect::<Vec<_>>()
+        };
+        assert!(mailbox
+            .send_transaction(&mailbox_id, items())
+            .await
+            .is_err());
+        std::fs::remove_dir(&blocker)?;
+        assert_eq!(mailbox_files(&dir, &mailbox_id)?, before);
+
+        let ids = mailbox.send_transaction(&mailbox_id, items()).await?;
+        assert_eq!(ids, ["2", "3", "4", "5", "6"]);
+        let items = mailbox.drain(&mailbox_id, None).await?;
+        let items: Vec<&str> = items.iter().map(|(_id, i)| i.data.as_str()).collect();
+        assert_eq!(items, ["zero", "one", "two", "three", "four", "five"]);
+
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn it_compacts_items_read_by_all_groups() -> Result<()> {
+        let dir = TempDir::new()?;
+        let mailbox = create_journaled_mailbox(&dir, 100).await?;
+        let mailbox_id = String::from("42");
+        for data in ["one", "two", "three", "four"] {
+            mailbox
+                .send(&mailbox_id, TestItem::new(data.into()))
+                .await?;
+        }
+        mailbox.drain(&mailbox_id, Some(3)).await?;
+        let (id, _item) = mailbox
+            .receive_for(&mailbox_id, "slow")
+            .await?
+            .expect("Item pending");
+        mailbox.acknowledge_for(&mailbox_id, "slow", &id).await?;
+
+        // "slow" still needs 2 and 3
+        assert_eq!(mailbox.compact(&mailbox_id).await?, 1);
+        assert_eq!(mailbox.compact(&mailbox_id).await?, 0);
+        let files = mailbox_files(&dir, &mailbox_id)?;
+        assert!(!files.contains_key("1.test_item"));
+        assert!(files.contains_key("2.test_item"));
+
+        // new groups start at the oldest remaining item
+        let (id, _item) = mailbox
+            .receive_for(&mailbox_id, "new")
+            .await?
+            .expect("Item pending");
+        assert_eq!(id, "2");
+        mailbox.acknowledge_for(&mailbox_id, "new", &id).await?;
+
+        // dropping old items lets compaction catch up
+        assert_eq!(
+            mailbox
+                .drop_older_than(&mailbox_id, std::time::Duration::ZERO)
+                .await?,
+            1
+        );
+        assert!(mailbox.receive(&mailbox_id).await?.is_none());
+        let stats = mailbox.stats(&mailbox_id).await?;
+        assert_eq!((stats.pending, stats.total_acknowledged), (0, 3));
+
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn it_flushes_on_close() -> Result<()> {
+        let dir = TempDir::new()?;
+        let mut mailbox = create_journaled_mailbox(&dir, 100).await?;
+        let mailbox_id = String::from("42");
+        mailbox
+            .send(&mailbox_id, TestItem::new("one".into()))
+            .await?;
+        mailbox
+            .send(&mailbox_id, TestItem::new("two".into()))
+            .await?;
+        mailbox.pop(&mailbox_id).await?;
+        assert!(mailbox_files(&dir, &mailbox_id)?.contains_key("mailbox_meta.journal"));
+
+        mailbox.close().await?;
+        let files = mailbox_files(&dir, &mailbox_id)?;
+        assert!(!files.contains_key("mailbox_meta.journal"));
+        let meta: serde_json::Value = serde_json::from_slice(&files["mailbox_meta.json"])?;
+        assert_eq!(meta["highest_used_id"], 2);
+        assert_eq!(meta["lowest_unread_id"], 2);
+
+        let e = mailbox.receive(&mailbox_id).await.expect_err("Closed");
+        assert!(matches!(
+            e.downcast_ref::<MailboxError>(),
+            Some(MailboxError::Closed)
+        ));
+        assert!(mailbox.list_mailboxes().await.is_err());
+
+        let mailbox = create_mailbox::<TestItem>(&dir).await?;
+        let (id, item) = mailbox.receive(&mailbox_id).await?.expect("Item pending");
+        assert_eq!((id.as_str(), item.data.as_str()), ("2", "two"));
+
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn it_never_writes_when_read_only() -> Result<()> {
+        let dir = TempDir::new()?;
+        let mailbox = create_journaled_mailbox(&dir, 100).await?;
+        let mailbox_id = String::from("42");
+        mailbox
+            .send(&mailbox_id, TestItem::new("one".into()))
+            .await?;
+        mailbox
+            .send(&mailbox_id, TestItem::new("two".into()))
+            .await?;
+        mailbox.pop(&mailbox_id).await?;
+
+        let modified = |dir: &TempDir| -> Result<BTreeMap<PathBuf, SystemTime>> {
+            let mut path = dir.path().to_path_buf();
+            path.push("test_items");
+            let mut modified = BTreeMap::new();
+            for entry in fs::read_dir(&path)? {
+                let entry = entry?;
+                modified.insert(entry.path(), entry.metadata()?.modified()?);
+                if entry.file_type()?.is_dir() {
+                    for entry in std::fs::read_dir(entry.path())? {
+                        let entry = entry?;
+                        modified.insert(entry.path(), entry.metadata()?.modified()?);
+                    }
+                }
+            }
+            Ok(modified)
+        };
+        let before = modified(&dir)?;
+
+        let mut path = dir.path().to_path_buf();
+        path.push("test_items");
+        let mut mailbox = MailboxDisk::<TestItem>::at(&path, Path::new("test_item")).read_only();
+        mailbox.ensure_storage_exists().await?;
+
+        // the journal is replayed in memory
+        let (item_id, item) = mailbox.peek(&mailbox_id).await?.expect("Item pending");
+        assert_eq!(item_id, "2");
+        assert_eq!(item.data, "two");
+        let stats = mailbox.stats(&mailbox_id).await?;
+        assert_eq!(stats.pending, 1);
+        assert_eq!(stats.total_sent, 2);
+        assert_eq!(mailbox.list_mailboxes().await?, vec![mailbox_id.clone()]);
+        assert_eq!(mailbox.delivery_count(&mailbox_id, "2").await?, 0);
+
+        for e in [
+            mailbox
+                .send(&mailbox_id, TestItem::new("three".into()))
+                .await
+                .expect_err("Read-only"),
+            mailbox.receive(&mailbox_id).await.expect_err("Read-only"),
+            mailbox
+                .acknowledge(&mailbox_id, "2")
+                .await
+                .expect_err("Read-only"),
+            mailbox
+                .checkpoint(&mailbox_id)
+                .await
+                .expect_err("Read-only"),
+        ] {
+            assert!(matches!(
+                e.downcast_ref::<MailboxError>(),
+                Some(MailboxError::ReadOnly { .. })
+            ));
+        }
+
+        let e = mailbox.stats("43").await.expect_err("Unknown mailbox");
+        assert!(matches!(
+            e.downcast_ref::<MailboxError>(),
+            Some(MailboxError::UnknownMailbox { .. })
+        ));
+
+        mailbox.close().await?;
+        assert_eq!(modified(&dir)?, before);
+
+        Ok(())
+    }
+
+    static NOW: AtomicI64 = AtomicI64::new(0);
+
+    fn test_clock() -> DateTime<Utc> {
+        DateTime::from_timestamp(NOW.load(Ordering::Relaxed), 0).unwrap_or_default()
+    }
+
+    fn set_test_clock(now: &str) -> Result<()> {
+        let now = DateTime::parse_from_rfc3339(now)?;
+        NOW.store(now.timestamp(), Ordering::Relaxed);
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn it_groups_items_by_day() -> Result<()> {
+        let dir = TempDir::new()?;
+        let mut path = dir.path().to_path_buf();
+        path.push("test_items");
+        let open = || async {
+            let mut mailbox = MailboxDisk::<TestItem>::at(&path, Path::new("test_item"))
+                .with_journal(100)
+                .with_layout(EnvelopeLayout::Daily)
+                .with_clock(test_clock);
+            mailbox.ensure_storage_exists().await?;
+            Result::<_>::Ok(mailbox)
+        };
+        let mailbox = open().await?;
+        let mailbox_id = String::from("42");
+
+        set_test_clock("2024-06-01T23:59:00Z")?;
+        for data in ["one", "two"] {
+            mailbox
+                .send(&mailbox_id, TestItem::new(data.into()))
+                .await?;
+        }
+        set_test_clock("2024-06-02T00:01:00Z")?;
+        mailbox
+            .send(&mailbox_id, TestItem::new("three".into()))
+            .await?;
+        mailbox
+            .send_transaction(
+                &mailbox_id,
+                vec![TestItem::new("four".into()), TestItem::new("five".into())],
+            )
+            .await?;
+
+        let mut day_1 = path.join(&mailbox_id);
+        day_1.push("2024-06-01");
+        let mut day_2 = path.join(&mailbox_id);
+        day_2.push("2024-06-02");
+        assert!(day_1.join("1.test_item").exists());
+        assert!(day_1.join("2.test_item").exists());
+        for id in 3..=5 {
+            assert!(day_2.join(format!("{id}.test_item")).exists());
+        }
+
+        for (expected_id, expected_data) in [("1", "one"), ("2", "two"), ("3", "three")] {
+            let (item_id, item) = mailbox.receive(&mailbox_id).await?.expect("Item pending");
+            assert_eq!(item_id, expected_id);
+            assert_eq!(item.data, expected_data);
+            assert_eq!(mailbox.delivery_count(&mailbox_id, &item_id).await?, 1);
+            mailbox.acknowledge(&mailbox_id, &item_id).await?;
+        }
+
+        // the days are replayed from the journal
+        drop(mailbox);
+        let mailbox = open().await?;
+        let (item_id, item) = mailbox.peek(&mailbox_id).await?.expect("Item pending");
+        assert_eq!(item_id, "4");
+        assert_eq!(item.data, "four");
+
+        assert_eq!(mailbox.compact(&mailbox_id).await?, 3);
+        assert!(!day_1.exists());
+        assert!(day_2.exists());
+
+        let flat = MailboxDisk::<TestItem>::at(&path, Path::new("test_item"));
+        let e = flat.peek(&mailbox_id).await.expect_err("Layout mismatch");
+        assert!(matches!(
+            e.downcast_ref::<MailboxError>(),
+            Some(MailboxError::LayoutMismatch {
+                expected: EnvelopeLayout::Flat,
+                found: EnvelopeLayout::Daily,
+                ..
+            })
+        ));
+
+        Ok(())
+    }
+
+    #[cfg(feature = "fs-watch")]
+    #[test(tokio::test)]
+    async fn it_receives_batches_when_they_are_full() -> Result<()> {
+        use std::time::Duration;
+        use std::time::Instant;
+
+        let dir = TempDir::new()?;
+        let consumer = create_journaled_mailbox(&dir, 1).await?;
+        let producer = create_journaled_mailbox(&dir, 1).await?;
+        let mailbox_id = String::from("42");
+
+        let sender = tokio::spawn(async move {
+            for n in 1..=3 {
+                tokio::time::sleep(Duration::from_millis(50)).await;
+                producer.send("42", TestItem::new(format!("{n}"))).await?;
+            }
+            Result::<()>::Ok(())
+        });
+        let start = Instant::now();
+        let batch = consumer
+            .receive_batch(&mailbox_id, 3, Duration::from_secs(5))
+            .await?;
+        assert!(start.elapsed() < Duration::from_secs(5));
+        sender.await??;
+        let data: Vec<&str> = batch.iter().map(|(_, item)| item.data.as_str()).collect();
+        assert_eq!(data, ["1", "2", "3"]);
+
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn it_waits_for_deliverable_items_when_receiving_batches() -> Result<()> {
+        let dir = TempDir::new()?;
+        let mailbox = MailboxDisk::<TestItem>::at(dir.path(), Path::new("test_item"));
+        let mailbox_id = String::from("42");
+
+        // pending, but neither of them can be delivered
+        mailbox
+            .send(&mailbox_id, TestItem::new("delayed".into()))
+            .await?;
+        let (delayed, _item) = mailbox.receive(&mailbox_id).await?.expect("Item pending");
+        mailbox
+            .reject_with_delay(&mailbox_id, &delayed, Duration::from_secs(3600))
+            .await?;
+        mailbox
+            .send_grouped(&mailbox_id, "a", TestItem::new("in flight".into()))
+            .await?;
+        let (_id, item) = mailbox.receive(&mailbox_id).await?.expect("Item pending");
+        assert_eq!(item.data, "in flight");
+
+        mailbox
+            .send(&mailbox_id, TestItem::new("visible".into()))
+            .await?;
+        assert_eq!(mailbox.stats(&mailbox_id).await?.pending, 3);
+        let late = async {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            mailbox
+                .send(&mailbox_id, TestItem::new("late".into()))
+                .await
+        };
+        let (batch, late) = tokio::join!(
+            mailbox.receive_batch(&mailbox_id, 2, Duration::from_secs(5)),
+            late
+        );
+        late?;
+        let batch = batch?;
+        let data: Vec<&str> = batch.iter().map(|(_, item)| item.data.as_str()).collect();
+        assert_eq!(data, ["visible", "late"]);
+        for (item_id, _item) in &batch {
+            assert_eq!(mailbox.delivery_count(&mailbox_id, item_id).await?, 1);
+        }
+
+        Ok(())
+    }
+
+    #[cfg(feature = "fs-watch")]
+    #[test(tokio::test)]
+    async fn it_watches_for_items_from_other_instances() -> Result<()> {
+        use crate::WatchEvent;
+        use std::time::Duration;
+        use tokio_stream::StreamExt;
+
+        let dir = TempDir::new()?;
+        let consumer = create_journaled_mailbox(&dir, 1).await?;
+        let producer = create_journaled_mailbox(&dir, 1).await?;
+        let mailbox_id = String::from("42");
+
+        let mut events = consumer.watch(&mailbox_id).await?;
+        let sender = tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            for data in ["one", "two"] {
+                producer.send("42", TestItem::new(data.into())).await?;
+            }
+            tokio::time::sleep(Duration::from_millis(500)).await;
+            producer.send("42", TestItem::new("three".into())).await?;
+            Result::<()>::Ok(())
+        });
+
+        for expected_id in ["1", "2"] {
+            let event = tokio::time::timeout(Duration::from_secs(5), events.next())
+                .await?
+                .expect("Stream open")?;
+            assert_eq!(
+                event,
+                WatchEvent::NewItem {
+                    item_id: expected_id.into()
+                }
+            );
+        }
+
+        for expected_data in ["one", "two", "three"] {
+            let (item_id, item) = consumer
+                .receive_wait(&mailbox_id, Duration::from_secs(5))
+                .await?
+                .expect("Item sent");
+            assert_eq!(item.data, expected_data);
+            consumer.acknowledge(&mailbox_id, &item_id).await?;
+        }
+        sender.await??;
+
+        let nothing = consumer
+            .receive_wait(&mailbox_id, Duration::from_millis(100))
+            .await?;
+        assert!(nothing.is_none());
+
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn it_stores_large_payloads_in_sidecars() -> Result<()> {
+        let dir = TempDir::new()?;
+        let mut path = dir.path().to_path_buf();
+        path.push("test_items");
+        let mut mailbox =
+            MailboxDisk::<TestItem>::at(&path, Path::new("test_item")).with_sidecar_threshold(100);
+        mailbox.ensure_storage_exists().await?;
+        let mailbox_id = String::from("42");
+
+        let large = "x".repeat(10_000);
+        mailbox
+            .send(&mailbox_id, TestItem::new(large.clone()))
+            .await?;
+        mailbox
+            .send_transaction(
+                &mailbox_id,
+                vec![TestItem::new("small".into()), TestItem::new(large.clone())],
+            )
+            .await?;
+
+        let folder = path.join(&mailbox_id);
+        let envelope_size = |id: &str| -> Result<u64> {
+            Ok(std::fs::metadata(folder.join(format!("{id}.test_item")))?.len())
+        };
+        assert!(envelope_size("1")? < 1_000);
+        assert!(folder.join("1.payload").exists());
+        assert!(!folder.join("2.payload").exists());
+        assert!(folder.join("3.payload").exists());
+
+        let (item_id, item) = mailbox.receive(&mailbox_id).await?.expect("Item pending");
+        assert_eq!(item.data, large);
+        mailbox.acknowledge(&mailbox_id, &item_id).await?;
+        assert!(envelope_size("1")? < 1_000);
+        let (_item_id, item) = mailbox.pop(&mailbox_id).await?.expect("Item pending");
+        assert_eq!(item.data, "small");
+
+        std::fs::remove_file(folder.join("3.payload"))?;
+        let e = mailbox
+            .receive(&mailbox_id)
+            .await
+            .expect_err("Missing sidecar");
+        let message = format!("{e}");
+        assert!(message.contains("3.payload"), "{message}");
+        assert!(message.contains("3.test_item"), "{message}");
+
+        assert_eq!(mailbox.compact(&mailbox_id).await?, 2);
+        assert!(!folder.join("1.payload").exists());
+        assert!(!folder.join("1.test_item").exists());
+
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn it_detects_tampered_envelopes() -> Result<()> {
+        let dir = TempDir::new()?;
+        let mut path = dir.path().to_path_buf();
+        path.push("test_items");
+        let extension = Path::new("test_item");
+        let mut mailbox = MailboxDisk::<TestItem>::at(&path, extension).with_signing_key(b"secret");
+        mailbox.ensure_storage_exists().await?;
+        let mailbox_id = String::from("42");
+
+        for data in ["one", "two", "three"] {
+            mailbox
+                .send(&mailbox_id, TestItem::new(data.into()))
+                .await?;
+        }
+        let envelope_path = |id: &str| path.join(&mailbox_id).join(format!("{id}.test_item"));
+        let tamper = |id: &str, field: &str, f: &dyn Fn(&str) -> String| -> Result<()> {
+            let p = envelope_path(id);
+            let mut envelope: serde_json::Value = serde_json::from_slice(&std::fs::read(&p)?)?;
+            envelope[field] = f(envelope[field].as_str().expect("Field exists")).into();
+            std::fs::write(&p, serde_json::to_vec(&envelope)?)?;
+            Ok(())
+        };
+        let is_signature_mismatch = |e: &color_eyre::eyre::Report| {
+            matches!(
+                e.downcast_ref::<MailboxError>(),
+                Some(MailboxError::SignatureMismatch { .. })
+            )
+        };
+
+        // the read flag is not signed
+        let (item_id, item) = mailbox.receive(&mailbox_id).await?.expect("Item pending");
+        assert_eq!(item.data, "one");
+        mailbox.acknowledge(&mailbox_id, &item_id).await?;
+
+        tamper("2", "data", &|data| {
+            use base64::prelude::*;
+            let mut data = BASE64_STANDARD.decode(data).expect("Valid base64");
+            data[0] ^= 1;
+            BASE64_STANDARD.encode(data)
+        })?;
+        let e = mailbox
+            .receive(&mailbox_id)
+            .await
+            .expect_err("Tampered payload");
+        assert!(is_signature_mismatch(&e), "{e:?}");
+
+        tamper("3", "sent_at", &|sent_at| sent_at.replacen("20", "19", 1))?;
+        let e = mailbox
+            .delivery_count(&mailbox_id, "3")
+            .await
+            .expect_err("Tampered sent_at");
+        assert!(is_signature_mismatch(&e), "{e:?}");
+
+        // unsigned envelopes are only accepted for compatibility
+        let mut unsigned = MailboxDisk::<TestItem>::at(&path, extension);
+        unsigned.ensure_storage_exists().await?;
+        unsigned
+            .send(&mailbox_id, TestItem::new("four".into()))
+            .await?;
+        let e = mailbox
+            .delivery_count(&mailbox_id, "4")
+            .await
+            .expect_err("Unsigned");
+        assert!(is_signature_mismatch(&e), "{e:?}");
+        let compatible = MailboxDisk::<TestItem>::at(&path, extension)
+            .with_signing_key(b"secret")
+            .accept_unsigned();
+        assert_eq!(compatible.delivery_count(&mailbox_id, "4").await?, 0);
+        let compatible = MailboxDisk::<TestItem>::at(&path, extension)
+            .accept_unsigned()
+            .with_signing_key(b"secret");
+        assert_eq!(compatible.delivery_count(&mailbox_id, "4").await?, 0);
+
+        Ok(())
+    }
+
+    #[test]
+    fn it_signs_with_hmac_sha256() {
+        // RFC 4231, test case 2
+        let signer = super::EnvelopeSigner::new(b"Jefe");
+        assert_eq!(
+            super::hex(&signer.mac(b"what do ya want for nothing?")),
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+    }
+
+    #[test(tokio::test)]
+    async fn it_receives_by_header() -> Result<()> {
+        let dir = TempDir::new()?;
+        let mailbox = create_mailbox::<KindItem>(&dir).await?;
+        let mailbox_id = String::from("42");
+
+        for n in 0..3 {
+            for kind in ["invoice", "receipt"] {
+                let data = format!("{kind} {n}");
+                let kind = kind.to_string();
+                mailbox.send(&mailbox_id, KindItem { kind, data }).await?;
+            }
+            // Note: a KindItem can't deserialize this, so it must never be touched
+            let broken = create_mailbox::<BrokenItem>(&dir).await?;
+            broken.send(&mailbox_id, BrokenItem::default()).await?;
+        }
+
+        let invoices = HeaderSelector::new().equals("kind", "invoice");
+        let receipts = HeaderSelector::new().one_of("kind", ["receipt", "refund"]);
+        let mut received = BTreeMap::<&str, Vec<(String, String)>>::new();
+        for _ in 0..4 {
+            for (consumer, selector) in [("invoices", &invoices), ("receipts", &receipts)] {
+                if let Some((item_id, item)) =
+                    mailbox.receive_matching(&mailbox_id, selector).await?
+                {
+                    mailbox.acknowledge(&mailbox_id, &item_id).await?;
+                    received
+                        .entry(consumer)
+                        .or_default()
+                        .push((item_id, item.data));
+                }
+            }
+        }
+        let expected = |kind: &str, ids: [&str; 3]| -> Vec<(String, String)> {
+            ids.iter()
+                .enumerate()
+                .map(|(n, id)| (id.to_string(), format!("{kind} {n}")))
+                .collect()
+        };
+        assert_eq!(received["invoices"], expected("invoice", ["1", "4", "7"]));
+        assert_eq!(received["receipts"], expected("receipt", ["2", "5", "8"]));
+
+        // the broken items are still pending, and the first one is next
+        assert_eq!(mailbox.stats(&mailbox_id).await?.pending, 3);
+        assert_eq!(mailbox.delivery_count(&mailbox_id, "3").await?, 0);
+        assert!(mailbox.receive(&mailbox_id).await.is_err());
+
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn it_sets_corrupt_items_aside_when_receiving_by_header() -> Result<()> {
+        for policy in [
+            CorruptionPolicy::Fail,
+            CorruptionPolicy::Skip,
+            CorruptionPolicy::Quarantine,
+        ] {
+            let dir = TempDir::new()?;
+            let path = dir.path().join("test_items");
+            let mut mailbox = MailboxDisk::<KindItem>::at(&path, Path::new("test_item"))
+                .with_corruption_policy(policy);
+            mailbox.ensure_storage_exists().await?;
+            for n in 1..=2 {
+                let kind = "invoice".to_string();
+                let data = format!("invoice {n}");
+                mailbox.send("42", KindItem { kind, data }).await?;
+            }
+            fs::write(path.join("42").join("1.test_item"), "{ broken")?;
+
+            let invoices = HeaderSelector::new().equals("kind", "invoice");
+            let r = mailbox.receive_matching("42", &invoices).await;
+            if policy == CorruptionPolicy::Fail {
+                assert!(r.is_err());
+                assert_eq!(mailbox.stats("42").await?.pending, 2);
+                continue;
+            }
+            let (item_id, item) = r?.expect("The second invoice");
+            assert_eq!((item_id.as_str(), item.data.as_str()), ("2", "invoice 2"));
+            mailbox.acknowledge("42", &item_id).await?;
+            assert_eq!(mailbox.stats("42").await?.pending, 0, "{policy:?}");
+            let quarantined = mailbox.quarantined("42").await?;
+            let ids: Vec<&str> = quarantined.iter().map(|q| q.item_id.as_str()).collect();
+            match policy {
+                CorruptionPolicy::Quarantine => assert_eq!(ids, ["1"]),
+                _ => assert!(ids.is_empty()),
+            }
+        }
+
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn it_updates_unread_items() -> Result<()> {
+        let dir = TempDir::new()?;
+        let mailbox = create_mailbox::<KindItem>(&dir).await?;
+        let mailbox_id = String::from("42");
+
+        let item = |data: &str| KindItem {
+            kind: "run".to_string(),
+            data: data.to_string(),
+        };
+        let a = mailbox.send(&mailbox_id, item("A")).await?;
+        let b = mailbox.send(&mailbox_id, item("B")).await?;
+        mailbox.update(&mailbox_id, &a, KindItem::default()).await?;
+        mailbox.update(&mailbox_id, &a, item("A'")).await?;
+
+        let selector = HeaderSelector::new().equals("kind", "run");
+        let (item_id, received) = mailbox
+            .receive_matching(&mailbox_id, &selector)
+            .await?
+            .expect("Headers are kept");
+        assert_eq!(item_id, a);
+        assert_eq!(received.data, "A'");
+        mailbox.acknowledge(&mailbox_id, &a).await?;
+
+        let e = mailbox
+            .update(&mailbox_id, &a, item("A''"))
+            .await
+            .expect_err("Already read");
+        assert!(matches!(
+            e.downcast_ref::<MailboxError>(),
+            Some(MailboxError::AlreadyRead { item_id, .. }) if *item_id == a
+        ));
+        let (item_id, received) = mailbox.receive(&mailbox_id).await?.expect("Item pending");
+        assert_eq!(item_id, b);
+        assert_eq!(received.data, "B");
+
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn it_keeps_the_state_of_updated_items() -> Result<()> {
+        use crate::FreezeMode;
+
+        let dir = TempDir::new()?;
+        let quota = QuotaManager::by_first_segment().with_max_bytes(200);
+        let mailbox =
+            MailboxDisk::<TestItem>::at(dir.path(), Path::new("test_item")).with_quota(quota);
+        let mailbox_id = String::from("42");
+
+        for data in ["a0", "a1"] {
+            mailbox
+                .send_grouped(&mailbox_id, "a", TestItem::new(data.into()))
+                .await?;
+        }
+        let deferred = mailbox.defer(&mailbox_id, "1").await?;
+        let (in_flight, _item) = mailbox.receive(&mailbox_id).await?.expect("a1");
+        assert_eq!(in_flight, "2");
+        mailbox
+            .update(&mailbox_id, &deferred, TestItem::new("a0'".into()))
+            .await?;
+        assert_eq!(mailbox.deferred_count(&mailbox_id, &deferred).await?, 1);
+        assert_eq!(mailbox.provenance(&mailbox_id, &deferred).await?.len(), 1);
+        // still waiting behind the item of its group in flight
+        assert!(mailbox.receive(&mailbox_id).await?.is_none());
+        mailbox.acknowledge(&mailbox_id, &in_flight).await?;
+        let (item_id, item) = mailbox.receive(&mailbox_id).await?.expect("a0'");
+        assert_eq!(
+            (item_id.as_str(), item.data.as_str()),
+            (deferred.as_str(), "a0'")
+        );
+        mailbox
+            .reject_with_delay(&mailbox_id, &item_id, Duration::ZERO)
+            .await?;
+
+        // the quota follows the size of the payload
+        mailbox
+            .update(&mailbox_id, &deferred, TestItem::new("longer".repeat(5)))
+            .await?;
+        let usage = mailbox.quota_usage(&mailbox_id).await?;
+        assert_eq!(mailbox.recount(&mailbox_id).await?, usage);
+        let e = mailbox
+            .update(&mailbox_id, &deferred, TestItem::new("x".repeat(200)))
+            .await
+            .expect_err("Quota reached");
+        assert!(matches!(
+            e.downcast_ref::<MailboxError>(),
+            Some(MailboxError::QuotaExceeded { .. })
+        ));
+
+        // and frozen mailboxes can't be changed
+        mailbox.set_frozen(&mailbox_id, FreezeMode::NoSend).await?;
+        assert!(mailbox
+            .update(&mailbox_id, &deferred, TestItem::new("frozen".into()))
+            .await
+            .is_err());
+
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn it_restores_a_meta_snapshot() -> Result<()> {
+        let dir = TempDir::new()?;
+        let mailbox = create_journaled_mailbox(&dir, 100).await?;
+        let mailbox_id = String::from("42");
+
+        for data in ["one", "two", "three"] {
+            mailbox
+                .send(&mailbox_id, TestItem::new(data.into()))
+                .await?;
+        }
+        let snapshot = mailbox.snapshot_meta(&mailbox_id).await?;
+        assert_eq!(snapshot.item_ids().collect::<Vec<_>>(), ["1", "2", "3"]);
+        let snapshot: super::MetaSnapshot =
+            serde_json::from_str(&serde_json::to_string(&snapshot)?)?;
+
+        for _ in 0..2 {
+            let (item_id, _item) = mailbox.receive(&mailbox_id).await?.expect("Item pending");
+            mailbox.acknowledge(&mailbox_id, &item_id).await?;
+        }
+        mailbox.restore_meta(&mailbox_id, &snapshot).await?;
+        assert_eq!(mailbox.stats(&mailbox_id).await?.pending, 3);
+        let (item_id, item) = mailbox.receive(&mailbox_id).await?.expect("Item pending");
+        assert_eq!(item_id, "1");
+        assert_eq!(item.data, "one");
+
+        // compaction removes items the snapshot needs
+        for _ in 0..2 {
+            let (item_id, _item) = mailbox.receive(&mailbox_id).await?.expect("Item pending");
+            mailbox.acknowledge(&mailbox_id, &item_id).await?;
+        }
+        assert_eq!(mailbox.compact(&mailbox_id).await?, 2);
+        let e = mailbox
+            .restore_meta(&mailbox_id, &snapshot)
+            .await
+            .expect_err("Items missing");
+        assert!(matches!(
+            e.downcast_ref::<MailboxError>(),
+            Some(MailboxError::SnapshotItemsMissing { item_ids, .. }) if *item_ids == ["1", "2"]
+        ));
+        assert_eq!(mailbox.stats(&mailbox_id).await?.pending, 1);
+        mailbox.force_restore_meta(&mailbox_id, &snapshot).await?;
+        assert_eq!(mailbox.stats(&mailbox_id).await?.pending, 3);
+
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn it_keeps_items_sent_after_a_meta_snapshot() -> Result<()> {
+        let dir = TempDir::new()?;
+        let mailbox = create_journaled_mailbox(&dir, 100).await?;
+        let mailbox_id = String::from("42");
+
+        mailbox
+            .send(&mailbox_id, TestItem::new("one".into()))
+            .await?;
+        let snapshot = mailbox.snapshot_meta(&mailbox_id).await?;
+        for data in ["two", "three"] {
+            mailbox
+                .send(&mailbox_id, TestItem::new(data.into()))
+                .await?;
+        }
+        let (item_id, _item) = mailbox.receive(&mailbox_id).await?.expect("Item pending");
+        mailbox.acknowledge(&mailbox_id, &item_id).await?;
+
+        mailbox.restore_meta(&mailbox_id, &snapshot).await?;
+        assert_eq!(mailbox.stats(&mailbox_id).await?.pending, 3);
+        let four = mailbox
+            .send(&mailbox_id, TestItem::new("four".into()))
+            .await?;
+        assert_eq!(four, "4");
+
+        let mut received = Vec::new();
+        while let Some((item_id, item)) = mailbox.receive(&mailbox_id).await? {
+            mailbox.acknowledge(&mailbox_id, &item_id).await?;
+            received.push(item.data);
+        }
+        assert_eq!(received, ["one", "two", "three", "four"]);
+
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn it_scans_past_broken_envelopes() -> Result<()> {
+        use tokio_stream::StreamExt;
+
+        let dir = TempDir::new()?;
+        let mailbox = create_journaled_mailbox(&dir, 100).await?;
+        let mailbox_id = String::from("42");
+        for data in ["one", "two", "three", "four"] {
+            mailbox
+                .send(&mailbox_id, TestItem::new(data.into()))
+                .await?;
+        }
+        let (item_id, _item) = mailbox.receive(&mailbox_id).await?.expect("Item pending");
+        mailbox.acknowledge(&mailbox_id, &item_id).await?;
+        let broken = dir.path().join("test_items/42/3.test_item");
+        std::fs::write(&broken, "{ not json")?;
+
+        let scanned: Vec<_> = mailbox.scan(&mailbox_id).collect().await;
+        assert_eq!(scanned.len(), 4);
+        assert!(scanned[2].is_err());
+        let good = scanned
+            .iter()
+            .filter_map(|s| s.as_ref().ok())
+            .map(|s| Ok((s.item_id.as_str(), s.read, s.decode()?.data)))
+            .collect::<Result<Vec<_>>>()?;
+        assert_eq!(
+            good,
+            [
+                ("1", true, "one".to_string()),
+                ("2", false, "two".to_string()),
+                ("4", false, "four".to_string()),
+            ]
+        );
+        assert_eq!(mailbox.delivery_count(&mailbox_id, "2").await?, 0);
+
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn it_acknowledges_through_an_id() -> Result<()> {
+        let dir = TempDir::new()?;
+        let mailbox = create_journaled_mailbox(&dir, 100).await?;
+        let mailbox_id = String::from("42");
+        for n in 1..=6 {
+            mailbox
+                .send(&mailbox_id, TestItem::new(format!("{n}")))
+                .await?;
+        }
+        mailbox.acknowledge(&mailbox_id, "3").await?;
+
+        assert_eq!(mailbox.acknowledge_through(&mailbox_id, "4").await?, 3);
+        assert_eq!(mailbox.acknowledge_through(&mailbox_id, "2").await?, 0);
+        let (item_id, _item) = mailbox.receive(&mailbox_id).await?.expect("Item pending");
+        assert_eq!(item_id, "5");
+
+        assert_eq!(mailbox.acknowledge_through(&mailbox_id, "100").await?, 2);
+        assert!(mailbox.receive(&mailbox_id).await?.is_none());
+        let stats = mailbox.stats(&mailbox_id).await?;
+        assert_eq!(stats.total_acknowledged, 6);
+
+        // the journal is replayed by the next instance
+        let reopened = create_journaled_mailbox(&dir, 100).await?;
+        assert!(reopened.receive(&mailbox_id).await?.is_none());
+        mailbox.send(&mailbox_id, TestItem::new("7".into())).await?;
+        let (item_id, _item) = reopened.receive(&mailbox_id).await?.expect("Item pending");
+        assert_eq!(item_id, "7");
+
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn it_encodes_mailbox_ids() -> Result<()> {
+        let dir = TempDir::new()?;
+        let mut path = dir.path().to_path_buf();
+        path.push("test_items");
+        let extension = Path::new("test_item");
+
+        // a plain mailbox from before the encoding was enabled
+        let mut plain = MailboxDisk::<TestItem>::at(&path, extension);
+        plain.ensure_storage_exists().await?;
+        plain.send("plain", TestItem::new("old".into())).await?;
+
+        let mut mailbox = MailboxDisk::<TestItem>::at(&path, extension).with_encoded_ids();
+        mailbox.ensure_storage_exists().await?;
+        let long = "x".repeat(300);
+        let ids = ["a/b", "a:b*c", "Ünïcødé 📬", "ünïcødé 📬", long.as_str()];
+        for id in ids {
+            mailbox.send(id, TestItem::new(id.to_string())).await?;
+        }
+        for id in ids {
+            let (item_id, item) = mailbox.receive(id).await?.expect("Item pending");
+            assert_eq!(item.data, id);
+            mailbox.acknowledge(id, &item_id).await?;
+            assert!(mailbox.receive(id).await?.is_none());
+        }
+        let (_item_id, item) = mailbox.receive("plain").await?.expect("Item pending");
+        assert_eq!(item.data, "old");
+
+        let mut expected: Vec<String> = ids.iter().map(|id| id.to_string()).collect();
+        expected.push("plain".into());
+        expected.sort();
+        assert_eq!(mailbox.list_mailboxes().await?, expected);
+        for entry in fs::read_dir(&path)? {
+            let name = entry?.file_name();
+            assert!(name.len() < 255, "{name:?}");
+        }
+
+        Ok(())
+    }
+
+    async fn create_counted_mailbox(dir: &TempDir) -> Result<MailboxDisk<TestItem>> {
+        let mut path = dir.path().to_path_buf();
+        path.push("test_items");
+        let extension = Path::new("test_item");
+
+        let mut mailbox = MailboxDisk::<TestItem>::at(&path, extension).with_id_counter();
+        mailbox.ensure_storage_exists().await?;
+
+        Ok(mailbox)
+    }
+
+    #[test(tokio::test(flavor = "multi_thread", worker_threads = 4))]
+    async fn it_allocates_ids_concurrently() -> Result<()> {
+        let dir = TempDir::new()?;
+        let mailbox = std::sync::Arc::new(create_counted_mailbox(&dir).await?);
+        let mailbox_id = "42";
+
+        let mut tasks = Vec::new();
+        for task in 0..8 {
+            let mailbox = mailbox.clone();
+            tasks.push(tokio::spawn(async move {
+                let mut ids = Vec::new();
+                for n in 0..25 {
+                    let item = TestItem::new(format!("{task} {n}"));
+                    let item_id = mailbox.send(mailbox_id, item).await?;
+                    ids.push(item_id.parse::<u64>()?);
+                }
+                color_eyre::eyre::Ok(ids)
+            }));
+        }
+        let mut all_ids = HashSet::new();
+        for task in tasks {
+            let ids = task.await??;
+            assert!(ids.windows(2).all(|w| w[0] < w[1]), "{ids:?}");
+            for id in ids {
+                assert!(all_ids.insert(id), "{id} used twice");
+            }
+        }
+        assert_eq!(all_ids.len(), 200);
+
+        let mut received = Vec::new();
+        while let Some((item_id, _item)) = mailbox.receive(mailbox_id).await? {
+            mailbox.acknowledge(mailbox_id, &item_id).await?;
+            received.push(item_id.parse::<u64>()?);
+        }
+        assert!(received.windows(2).all(|w| w[0] < w[1]));
+        assert_eq!(received.into_iter().collect::<HashSet<_>>(), all_ids);
+        assert_eq!(mailbox.stats(mailbox_id).await?.total_sent, 200);
+
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn it_reconciles_a_stale_id_counter() -> Result<()> {
+        let dir = TempDir::new()?;
+        let mailbox_id = "42";
+        {
+            let mailbox = create_counted_mailbox(&dir).await?;
+            for n in 1..=3 {
+                mailbox
+                    .send(mailbox_id, TestItem::new(format!("{n}")))
+                    .await?;
+            }
+            // dropped before anything is added to the meta
+        }
+        let counter_path = dir.path().join("test_items/42/mailbox_meta.counter");
+        assert_eq!(fs::read(&counter_path)?, 3u64.to_le_bytes());
+        fs::write(&counter_path, 1u64.to_le_bytes())?;
+
+        let mailbox = create_counted_mailbox(&dir).await?;
+        let item_id = mailbox.send(mailbox_id, TestItem::new("4".into())).await?;
+        assert_eq!(item_id, "4");
+        assert_eq!(fs::read(&counter_path)?, 4u64.to_le_bytes());
+        for n in 1..=4 {
+            let (item_id, item) = mailbox.receive(mailbox_id).await?.expect("Item pending");
+            assert_eq!(item_id, format!("{n}"));
+            assert_eq!(item.data, format!("{n}"));
+            mailbox.acknowledge(mailbox_id, &item_id).await?;
+        }
+        assert!(mailbox.receive(mailbox_id).await?.is_none());
+
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn it_reports_item_sizes() -> Result<()> {
+        let dir = TempDir::new()?;
+        let mut mailbox =
+            MailboxDisk::<RawItem>::at(dir.path(), Path::new("raw")).with_sidecar_threshold(100);
+        mailbox.ensure_storage_exists().await?;
+
+        for payload in [Vec::new(), b"x".to_vec(), vec![7u8; 1000]] {
+            let item = RawItem::new(payload);
+            let expected = item.serialize()?.len() as u64;
+            let item_id = mailbox.send("42", item).await?;
+            assert_eq!(mailbox.item_size("42", &item_id).await?, expected);
+        }
+
+        // envelopes from before the size was recorded are decoded
+        let p = dir.path().join("42/2.raw");
+        let mut envelope: serde_json::Value = serde_json::from_slice(&fs::read(&p)?)?;
+        envelope
+            .as_object_mut()
+            .expect("Envelope is an object")
+            .remove("payload_len");
+        fs::write(&p, serde_json::to_vec(&envelope)?)?;
+        assert_eq!(mailbox.item_size("42", "2").await?, 1);
+        assert_eq!(mailbox.delivery_count("42", "2").await?, 0);
+
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn it_pages_through_items() -> Result<()> {
+        let dir = TempDir::new()?;
+        let mailbox = create_journaled_mailbox(&dir, 100).await?;
+        let mailbox_id = "42";
+        let items = (1..=2_500).map(|n| TestItem::new(format!("{n}"))).collect();
+        mailbox.send_transaction(mailbox_id, items).await?;
+
+        let mut listed = Vec::new();
+        let mut after = None;
+        let mut pages = 0;
+        loop {
+            let page = mailbox
+                .list_items_page(mailbox_id, after.as_deref(), 1_000)
+                .await?;
+            pages += 1;
+            listed.extend(
+                page.entries
+                    .iter()
+                    .map(|e| e.item_id.parse::<u64>().unwrap()),
+            );
+            if pages == 1 {
+                // removed between pages, so they are skipped
+                mailbox.acknowledge_through(mailbox_id, "1200").await?;
+                mailbox.compact(mailbox_id).await?;
+            }
+            match page.next_cursor {
+                Some(cursor) => after = Some(cursor),
+                None => break,
+            }
+        }
+
+        assert_eq!(pages, 3);
+        let expected: Vec<u64> = (1..=1_000).chain(1_201..=2_500).collect();
+        assert_eq!(listed, expected);
+
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn it_pages_through_mailboxes() -> Result<()> {
+        let dir = TempDir::new()?;
+        let mailbox = create_journaled_mailbox(&dir, 100).await?;
+        for mailbox_id in ["e", "a", "d", "b", "c"] {
+            mailbox.send(mailbox_id, TestItem::new("x".into())).await?;
+        }
+
+        let page = mailbox.list_mailboxes_page(None, 2).await?;
+        assert_eq!(page.entries, ["a", "b"]);
+        let page = mailbox
+            .list_mailboxes_page(page.next_cursor.as_deref(), 2)
+            .await?;
+        assert_eq!(page.entries, ["c", "d"]);
+        let page = mailbox
+            .list_mailboxes_page(page.next_cursor.as_deref(), 2)
+            .await?;
+        assert_eq!(page.entries, ["e"]);
+        assert_eq!(page.next_cursor, None);
+
+        let page = mailbox.list_items_page("a", None, 10).await?;
+        assert_eq!(page.entries.len(), 1);
+        assert_eq!(
+            page.entries[0].size,
+            MailboxItem::serialize(&TestItem::new("x".into()))?.len() as u64
+        );
+        assert!(!page.entries[0].read);
+
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn it_keeps_mailbox_attributes() -> Result<()> {
+        let dir = TempDir::new()?;
+        let mailbox_id = "42";
+        {
+            let mailbox = create_journaled_mailbox(&dir, 100).await?;
+            mailbox
+                .send(mailbox_id, TestItem::new("one".into()))
+                .await?;
+            mailbox
+                .set_mailbox_attr(mailbox_id, "owner", "alice")
+                .await?;
+            mailbox
+                .set_mailbox_attr(mailbox_id, "purpose", "tests")
+                .await?;
+            mailbox.set_mailbox_attr(mailbox_id, "temp", "x").await?;
+            mailbox.delete_mailbox_attr(mailbox_id, "temp").await?;
+            mailbox.pop(mailbox_id).await?;
+            mailbox.compact(mailbox_id).await?;
+
+            let e = mailbox
+                .set_mailbox_attr(mailbox_id, "notes", &"x".repeat(5000))
+                .await
+                .expect_err("Too large");
+            assert!(matches!(
+                e.downcast_ref::<MailboxError>(),
+                Some(MailboxError::AttributesTooLarge { .. })
+            ));
+        }
+
+        let path = dir.path().join("test_items");
+        let mut mailbox = MailboxDisk::<TestItem>::at(&path, Path::new("test_item")).read_only();
+        mailbox.ensure_storage_exists().await?;
+        let attrs = mailbox.mailbox_attrs(mailbox_id).await?;
+        assert_eq!(attrs.len(), 2);
+        assert_eq!(attrs["owner"], "alice");
+        assert_eq!(
+            mailbox
+                .get_mailbox_attr(mailbox_id, "purpose")
+                .await?
+                .as_deref(),
+            Some("tests")
+        );
+        assert_eq!(mailbox.get_mailbox_attr(mailbox_id, "temp").await?, None);
+
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn it_only_uses_created_mailboxes_when_strict() -> Result<()> {
+        let dir = TempDir::new()?;
+        let path = dir.path().join("test_items");
+        let extension = Path::new("test_item");
+
+        let mut mailbox = MailboxDisk::<TestItem>::at(&path, extension).strict();
+        mailbox.ensure_storage_exists().await?;
+        let e = mailbox
+            .send("user_1234 ", TestItem::new("lost".into()))
+            .await
+            .expect_err("Mailbox was never created");
+        assert!(matches!(
+            e.downcast_ref::<MailboxError>(),
+            Some(MailboxError::UnknownMailbox { .. })
+        ));
+        assert!(mailbox.receive("user_1234 ").await.is_err());
+        assert!(mailbox.acknowledge("user_1234 ", "1").await.is_err());
+        assert_eq!(fs::read_dir(&path)?.count(), 0);
+
+        mailbox.create_mailbox("user_1234").await?;
+        mailbox.create_mailbox("user_1234").await?;
+        mailbox
+            .send("user_1234", TestItem::new("kept".into()))
+            .await?;
+        let (_item_id, item) = mailbox.receive("user_1234").await?.expect("Item pending");
+        assert_eq!(item.data, "kept");
+
+        // without strict mode mailboxes are still created on first use
+        let lenient = MailboxDisk::<TestItem>::at(&path, extension);
+        lenient.send("other", TestItem::new("one".into())).await?;
+        lenient.create_mailbox("other").await?;
+        assert_eq!(lenient.list_mailboxes().await?, ["other", "user_1234"]);
+
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn it_waits_for_space_in_bounded_mailboxes() -> Result<()> {
+        use crate::Backpressure;
+        use std::sync::atomic::AtomicUsize;
+        use std::sync::atomic::Ordering;
+        use std::sync::Arc;
+        use std::time::Duration;
+
+        let dir = TempDir::new()?;
+        let path = dir.path().join("test_items");
+        let extension = Path::new("test_item");
+
+        let mut mailbox = MailboxDisk::<TestItem>::at(&path, extension)
+            .with_capacity(2, Backpressure::Wait { timeout: None });
+        mailbox.ensure_storage_exists().await?;
+        let mailbox = Arc::new(mailbox);
+        let acknowledged = Arc::new(AtomicUsize::new(0));
+
+        let consumer = {
+            let mailbox = mailbox.clone();
+            let acknowledged = acknowledged.clone();
+            tokio::spawn(async move {
+                let mut data = Vec::new();
+                while data.len() < 10 {
+                    let Some((item_id, item)) = mailbox.receive("42").await? else {
+                        tokio::time::sleep(Duration::from_millis(5)).await;
+                        continue;
+                    };
+                    tokio::time::sleep(Duration::from_millis(10)).await;
+                    mailbox.acknowledge("42", &item_id).await?;
+                    acknowledged.fetch_add(1, Ordering::SeqCst);
+                    data.push(item.data);
+                }
+                Result::<Vec<String>>::Ok(data)
+            })
+        };
+        for n in 1..=10 {
+            mailbox.send("42", TestItem::new(format!("{n}"))).await?;
+            assert!(mailbox.stats("42").await?.pending <= 2);
+        }
+        // Note: the last two items fit without waiting, everything before had to be acknowledged
+        assert!(acknowledged.load(Ordering::SeqCst) >= 8);
+        let data = consumer.await??;
+        let expected: Vec<String> = (1..=10).map(|n| format!("{n}")).collect();
+        assert_eq!(data, expected);
+
+        let mailbox = MailboxDisk::<TestItem>::at(&path, extension).with_capacity(
+            1,
+            Backpressure::Wait {
+                timeout: Some(Duration::from_millis(20)),
+            },
+        );
+        mailbox.send("43", TestItem::new("1".into())).await?;
+        let e = mailbox
+            .send("43", TestItem::new("2".into()))
+            .await
+            .expect_err("Mailbox is full");
+        assert!(matches!(
+            e.downcast_ref::<MailboxError>(),
+            Some(MailboxError::Timeout { .. })
+        ));
+        let mailbox = mailbox.with_capacity(1, Backpressure::Reject);
+        let e = mailbox
+            .send("43", TestItem::new("2".into()))
+            .await
+            .expect_err("Mailbox is full");
+        assert!(matches!(
+            e.downcast_ref::<MailboxError>(),
+            Some(MailboxError::MailboxFull { capacity: 1, .. })
+        ));
+
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn it_trashes_compacted_ulid_items() -> Result<()> {
+        use crate::IdScheme;
+
+        let dir = TempDir::new()?;
+        let path = dir.path().join("test_items");
+        let mut mailbox = MailboxDisk::<TestItem>::at(&path, Path::new("test_item"))
+            .with_id_scheme(IdScheme::Ulid)
+            .with_trash();
+        mailbox.ensure_storage_exists().await?;
+        let first = mailbox.send("42", TestItem::new("one".into())).await?;
+        mailbox.send("42", TestItem::new("two".into())).await?;
+        let (item_id, _item) = mailbox.receive("42").await?.expect("Item pending");
+        mailbox.acknowledge("42", &item_id).await?;
+
+        assert_eq!(mailbox.compact("42").await?, 1);
+        let trashed: Vec<String> = mailbox
+            .list_trash("42")
+            .await?
+            .into_iter()
+            .map(|i| i.item_id)
+            .collect();
+        assert_eq!(trashed, [first]);
+        assert_eq!(mailbox.stats("42").await?.pending, 1);
+
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn it_orders_ulids_across_writers() -> Result<()> {
+        use crate::IdScheme;
+        use std::sync::Arc;
+        use std::time::Duration;
+
+        let dir = TempDir::new()?;
+        let path = dir.path().join("test_items");
+        let extension = Path::new("test_item");
+
+        let mut writers = Vec::new();
+        for _ in 0..2 {
+            let mut mailbox =
+                MailboxDisk::<TestItem>::at(&path, extension).with_id_scheme(IdScheme::Ulid);
+            mailbox.ensure_storage_exists().await?;
+            writers.push(Arc::new(mailbox));
+        }
+        let mut item_ids = HashSet::new();
+        for n in 0..20 {
+            let item_id = writers[n % 2]
+                .send("42", TestItem::new(format!("{n}")))
+                .await?;
+            assert!(item_ids.insert(item_id));
+            // Note: ULIDs of different writers are only ordered across milliseconds
+            tokio::time::sleep(Duration::from_millis(2)).await;
+        }
+
+        let reader = &writers[0];
+        let mut received = Vec::new();
+        while let Some((item_id, item)) = reader.receive("42").await? {
+            reader.acknowledge("42", &item_id).await?;
+            received.push(item.data);
+        }
+        let expected: Vec<String> = (0..20).map(|n| format!("{n}")).collect();
+        assert_eq!(received, expected);
+        let stats = reader.stats("42").await?;
+        assert_eq!(stats.pending, 0);
+        assert_eq!(stats.total_sent, 20);
+        assert_eq!(stats.total_acknowledged, 20);
+        assert_eq!(reader.compact("42").await?, 20);
+
+        let tasks: Vec<_> = writers
+            .iter()
+            .enumerate()
+            .map(|(w, writer)| {
+                let writer = writer.clone();
+                tokio::spawn(async move {
+                    let mut item_ids = Vec::new();
+                    for n in 0..50 {
+                        let item = TestItem::new(format!("{w}/{n}"));
+                        item_ids.push(writer.send("42", item).await?);
+                    }
+                    Result::<Vec<String>>::Ok(item_ids)
+                })
+            })
+            .collect();
+        let mut item_ids = HashSet::new();
+        for task in tasks {
+            item_ids.extend(task.await??);
+        }
+        assert_eq!(item_ids.len(), 100);
+        let received = reader.drain("42", None).await?;
+        assert_eq!(received.len(), 100);
+        for w in 0..2 {
+            let own: Vec<&str> = received
+                .iter()
+                .map(|(_, item)| item.data.as_str())
+                .filter(|data| data.starts_with(&format!("{w}/")))
+                .collect();
+            let expected: Vec<String> = (0..50).map(|n| format!("{w}/{n}")).collect();
+            assert_eq!(own, expected);
+        }
+
+        let numeric = MailboxDisk::<TestItem>::at(&path, extension);
+        let e = numeric
+            .send("42", TestItem::new("mixed".into()))
+            .await
+            .expect_err("Mailbox uses ULIDs");
+        assert!(matches!(
+            e.downcast_ref::<MailboxError>(),
+            Some(MailboxError::IdSchemeMismatch {
+                expected: IdScheme::Numeric,
+                found: IdScheme::Ulid,
+                ..
+            })
+        ));
+
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn it_defers_items_behind_pending_ones() -> Result<()> {
+        let dir = TempDir::new()?;
+        let path = dir.path().join("test_items");
+        let extension = Path::new("test_item");
+
+        let mut mailbox =
+            MailboxDisk::<TestItem>::at(&path, extension).with_defer_limit(2, Some("dead"));
+        mailbox.ensure_storage_exists().await?;
+        for data in ["a", "b", "c"] {
+            mailbox.send("42", TestItem::new(data.into())).await?;
+        }
+
+        let (item_id, _a) = mailbox.receive("42").await?.expect("Item pending");
+        let item_id = mailbox.defer("42", &item_id).await?;
+        assert_eq!(item_id, "4");
+        let item_id = mailbox.defer("42", &item_id).await?;
+        assert_eq!(item_id, "5");
+
+        let mut received = Vec::new();
+        while let Some((item_id, item)) = mailbox.receive("42").await? {
+            received.push((item.data, mailbox.deferred_count("42", &item_id).await?));
+            mailbox.acknowledge("42", &item_id).await?;
+        }
+        assert_eq!(
+            received,
+            [
+                ("b".to_string(), 0),
+                ("c".to_string(), 0),
+                ("a".to_string(), 2)
+            ]
+        );
+        let stats = mailbox.stats("42").await?;
+        assert_eq!(stats.total_acknowledged, 3);
+
+        // past the limit items end up in the dead-letter mailbox
+        let mut item_id = mailbox.send("42", TestItem::new("d".into())).await?;
+        for _ in 0..3 {
+            item_id = mailbox.defer("42", &item_id).await?;
+        }
+        assert!(mailbox.receive("42").await?.is_none());
+        let (dead_id, d) = mailbox.receive("dead").await?.expect("Item pending");
+        assert_eq!((dead_id.as_str(), d.data.as_str()), (item_id.as_str(), "d"));
+        assert_eq!(mailbox.deferred_count("dead", &dead_id).await?, 3);
+
+        let strict = MailboxDisk::<TestItem>::at(&path, extension).with_defer_limit(0, None);
+        let item_id = strict.send("42", TestItem::new("e".into())).await?;
+        let e = strict
+            .defer("42", &item_id)
+            .await
+            .expect_err("Deferring is not allowed");
+        assert!(matches!(
+            e.downcast_ref::<MailboxError>(),
+            Some(MailboxError::DeferLimitReached { limit: 0, .. })
+        ));
+
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn it_hides_rejected_items_until_the_delay_passed() -> Result<()> {
+        // Note: not the shared test clock, tests run in parallel
+        use std::time::Duration;
+
+        static NOW: AtomicI64 = AtomicI64::new(1_700_000_000);
+        fn clock() -> DateTime<Utc> {
+            DateTime::from_timestamp(NOW.load(Ordering::Relaxed), 0).unwrap_or_default()
+        }
+
+        let dir = TempDir::new()?;
+        let path = dir.path().join("test_items");
+        let mut mailbox =
+            MailboxDisk::<TestItem>::at(&path, Path::new("test_item")).with_clock(clock);
+        mailbox.ensure_storage_exists().await?;
+        for data in ["a", "b"] {
+            mailbox.send("42", TestItem::new(data.into())).await?;
+        }
+
+        let (a_id, _a) = mailbox.receive("42").await?.expect("Item pending");
+        mailbox
+            .reject_with_delay("42", &a_id, Duration::from_secs(30))
+            .await?;
+        let (b_id, b) = mailbox.receive("42").await?.expect("Item pending");
+        assert_eq!(b.data, "b");
+        mailbox.acknowledge("42", &b_id).await?;
+        assert!(mailbox.receive("42").await?.is_none());
+        assert!(mailbox.peek("42").await?.is_none());
+        assert_eq!(mailbox.stats("42").await?.pending, 1);
+
+        mailbox.send("42", TestItem::new("c".into())).await?;
+        let (_c_id, c) = mailbox.receive("42").await?.expect("Item pending");
+        assert_eq!(c.data, "c");
+
+        NOW.fetch_add(31, Ordering::Relaxed);
+        let (id, a) = mailbox.receive("42").await?.expect("Item pending");
+        assert_eq!((id.as_str(), a.data.as_str()), (a_id.as_str(), "a"));
+        assert_eq!(mailbox.delivery_count("42", &a_id).await?, 2);
+
+        mailbox.acknowledge("42", &a_id).await?;
+        let e = mailbox
+            .reject_with_delay("42", &a_id, Duration::from_secs(30))
+            .await
+            .expect_err("Item is read");
+        assert!(matches!(
+            e.downcast_ref::<MailboxError>(),
+            Some(MailboxError::AlreadyRead { .. })
+        ));
+
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn it_leaves_rejected_items_to_a_later_drain() -> Result<()> {
+        // Note: not the shared test clock, tests run in parallel
+        use std::time::Duration;
+
+        static NOW: AtomicI64 = AtomicI64::new(1_700_000_000);
+        fn clock() -> DateTime<Utc> {
+            DateTime::from_timestamp(NOW.load(Ordering::Relaxed), 0).unwrap_or_default()
+        }
+
+        let dir = TempDir::new()?;
+        let mailbox =
+            MailboxDisk::<TestItem>::at(dir.path(), Path::new("test_item")).with_clock(clock);
+        for data in ["a", "b", "c"] {
+            mailbox.send("42", TestItem::new(data.into())).await?;
+        }
+        let (a_id, _a) = mailbox.receive("42").await?.expect("Item pending");
+        mailbox
+            .reject_with_delay("42", &a_id, Duration::from_secs(30))
+            .await?;
+
+        let drained: Vec<String> = mailbox
+            .drain("42", None)
+            .await?
+            .into_iter()
+            .map(|(_item_id, item)| item.data)
+            .collect();
+        assert_eq!(drained, ["b", "c"]);
+        assert!(mailbox.drain("42", None).await?.is_empty());
+        assert_eq!(mailbox.stats("42").await?.pending, 1);
+
+        NOW.fetch_add(31, Ordering::Relaxed);
+        let drained = mailbox.drain("42", None).await?;
+        assert_eq!(drained.len(), 1);
+        assert_eq!(drained[0].0, a_id);
+        assert_eq!(mailbox.stats("42").await?.pending, 0);
+
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn it_restores_compacted_items_from_the_trash() -> Result<()> {
+        use std::time::Duration;
+
+        let dir = TempDir::new()?;
+        let path = dir.path().join("test_items");
+        let mut mailbox = MailboxDisk::<TestItem>::at(&path, Path::new("test_item"))
+            .with_sidecar_threshold(8)
+            .with_trash();
+        mailbox.ensure_storage_exists().await?;
+        for data in ["one", "a large second item", "three"] {
+            mailbox.send("42", TestItem::new(data.into())).await?;
+        }
+        mailbox.acknowledge_through("42", "2").await?;
+        assert_eq!(mailbox.compact("42").await?, 2);
+
+        let trash = path.join("42").join(".trash");
+        assert!(trash.join("2.payload").exists());
+        let page = mailbox.list_items_page("42", None, 10).await?;
+        let listed: Vec<&str> = page.entries.iter().map(|i| i.item_id.as_str()).collect();
+        assert_eq!(listed, ["3"]);
+        assert_eq!(mailbox.stats("42").await?.pending, 1);
+        let trashed: Vec<String> = mailbox
+            .list_trash("42")
+            .await?
+            .into_iter()
+            .map(|i| i.item_id)
+            .collect();
+        assert_eq!(trashed, ["1", "2"]);
+
+        let restored = mailbox.restore_from_trash("42", "2").await?;
+        assert_eq!(restored, "4");
+        assert!(!trash.join("2.payload").exists());
+        let mut received = Vec::new();
+        while let Some((item_id, item)) = mailbox.receive("42").await? {
+            mailbox.acknowledge("42", &item_id).await?;
+            received.push((item_id, item.data));
+        }
+        assert_eq!(
+            received,
+            [
+                ("3".to_string(), "three".to_string()),
+                ("4".to_string(), "a large second item".to_string())
+            ]
+        );
+
+        assert_eq!(
+            mailbox
+                .empty_trash("42", Some(Duration::from_secs(3600)))
+                .await?,
+            0
+        );
+        assert_eq!(mailbox.empty_trash("42", None).await?, 1);
+        assert!(!trash.exists());
+        assert!(mailbox.list_trash("42").await?.is_empty());
+
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn it_reports_missing_and_stray_files() -> Result<()> {
+        let dir = TempDir::new()?;
+        let path = dir.path().join("test_items");
+        let mut mailbox = MailboxDisk::<TestItem>::at(&path, Path::new("test_item"));
+        mailbox.ensure_storage_exists().await?;
+        for data in ["one", "two", "three", "four", "five"] {
+            mailbox.send("42", TestItem::new(data.into())).await?;
+        }
+        mailbox.acknowledge_through("42", "2").await?;
+        mailbox.acknowledge("42", "4").await?;
+
+        let folder = path.join("42");
+        fs::remove_file(folder.join("3.test_item"))?;
+        fs::write(folder.join("9.test_item"), "{}")?;
+        fs::write(folder.join("notes.txt"), "stray")?;
+
+        let inspection = mailbox.inspect("42").await?;
+        assert_eq!(inspection.highest_used_id, 5);
+        assert_eq!(inspection.lowest_unread_id, 3);
+        assert_eq!(inspection.read_ranges, [1..=2, 4..=4]);
+        assert_eq!(inspection.envelope_files, 4);
+        assert_eq!(inspection.missing_items, ["3"]);
+        assert_eq!(
+            inspection.stray_files,
+            [folder.join("9.test_item"), folder.join("notes.txt")]
+        );
+        assert_eq!(inspection.meta_error, None);
+
+        fs::write(folder.join("mailbox_meta.json"), "{ broken")?;
+        let inspection = mailbox.inspect("42").await?;
+        assert!(inspection.meta_error.is_some());
+        assert_eq!(inspection.envelope_files, 0);
+
+        let e = mailbox.inspect("7").await.expect_err("Mailbox is unknown");
+        assert!(matches!(
+            e.downcast_ref::<MailboxError>(),
+            Some(MailboxError::UnknownMailbox { .. })
+        ));
+        assert!(!path.join("7").exists());
+
+        Ok(())
+    }
+
+    /// `len` bytes of a pattern, failing after `fail_after` bytes
+    struct GeneratedStream {
+        pos: u64,
+        len: u64,
+        fail_after: Option<u64>,
+    }
+
+    impl GeneratedStream {
+        fn byte(pos: u64) -> u8 {
+            (pos % 251) as u8
+        }
+    }
+
+    impl tokio::io::AsyncRead for GeneratedStream {
+        fn poll_read(
+            mut self: std::pin::Pin<&mut Self>,
+            _cx: &mut std::task::Context<'_>,
+            buf: &mut tokio::io::ReadBuf<'_>,
+        ) -> std::task::Poll<std::io::Result<()>> {
+            if self
+                .fail_after
+                .is_some_and(|fail_after| self.pos >= fail_after)
+            {
+                return std::task::Poll::Ready(Err(std::io::ErrorKind::ConnectionReset.into()));
+            }
+            let n = (buf.remaining() as u64).min(self.len - self.pos);
+            let chunk: Vec<u8> = (self.pos..self.pos + n).map(Self::byte).collect();
+            buf.put_slice(&chunk);
+            self.pos += n;
+            std::task::Poll::Ready(Ok(()))
+        }
+    }
+
+    #[test(tokio::test)]
+    async fn it_streams_large_payloads() -> Result<()> {
+        use tokio::io::AsyncReadExt;
+
+        let dir = TempDir::new()?;
+        let path = dir.path().join("test_items");
+        let mut mailbox =
+            MailboxDisk::<TestItem>::at(&path, Path::new("test_item")).with_signing_key(b"secret");
+        mailbox.ensure_storage_exists().await?;
+        let len = 50 * 1024 * 1024;
+
+        let broken = GeneratedStream {
+            pos: 0,
+            len,
+            fail_after: Some(len / 2),
+        };
+        assert!(mailbox.send_stream("42", broken, Some(len)).await.is_err());
+        let short = GeneratedStream {
+            pos: 0,
+            len: len / 2,
+            fail_after: None,
+        };
+        assert!(mailbox.send_stream("42", short, Some(len)).await.is_err());
+        assert!(mailbox.receive("42").await?.is_none());
+        let inspection = mailbox.inspect("42").await?;
+        assert!(inspection.stray_files.is_empty());
+
+        let stream = GeneratedStream {
+            pos: 0,
+            len,
+            fail_after: None,
+        };
+        let item_id = mailbox.send_stream("42", stream, Some(len)).await?;
+        assert_eq!(item_id, "1");
+        assert_eq!(mailbox.item_size("42", &item_id).await?, len);
+
+        let (received_id, mut reader) = mailbox.receive_stream("42").await?.expect("Item pending");
+        assert_eq!(received_id, item_id);
+        let mut chunk = vec![0u8; 1024 * 1024];
+        let mut pos = 0;
+        loop {
+            let n = reader.read(&mut chunk).await?;
+            if n == 0 {
+                break;
+            }
+            assert!(chunk[..n]
+                .iter()
+                .enumerate()
+                .all(|(i, b)| *b == GeneratedStream::byte(pos + i as u64)));
+            pos += n as u64;
+        }
+        assert_eq!(pos, len);
+        assert_eq!(mailbox.delivery_count("42", &item_id).await?, 1);
+        mailbox.acknowledge("42", &item_id).await?;
+
+        // small items sent as usual can be streamed too, and tampering is noticed at the end
+        mailbox.send("42", TestItem::new("inline".into())).await?;
+        let (_item_id, mut reader) = mailbox.receive_stream("42").await?.expect("Item pending");
+        let mut data = Vec::new();
+        reader.read_to_end(&mut data).await?;
+        assert_eq!(
+            data,
+            MailboxItem::serialize(&TestItem::new("inline".into()))?
+        );
+
+        let stream = GeneratedStream {
+            pos: 0,
+            len: 1000,
+            fail_after: None,
+        };
+        let item_id = mailbox.send_stream("7", stream, None).await?;
+        fs::write(
+            path.join("7").join(format!("{item_id}.payload")),
+            [0u8; 1000],
+        )?;
+        let (_item_id, mut reader) = mailbox.receive_stream("7").await?.expect("Item pending");
+        let e = reader
+            .read_to_end(&mut Vec::new())
+            .await
+            .expect_err("Payload was changed");
+        assert_eq!(e.kind(), std::io::ErrorKind::InvalidData);
+
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn it_rebuilds_a_broken_meta() -> Result<()> {
+        let dir = TempDir::new()?;
+        let path = dir.path().join("test_items");
+        let mut mailbox =
+            MailboxDisk::<TestItem>::at(&path, Path::new("test_item")).with_journal(100);
+        mailbox.ensure_storage_exists().await?;
+        for data in ["one", "two", "three"] {
+            mailbox.send("42", TestItem::new(data.into())).await?;
+        }
+        mailbox.acknowledge("42", "1").await?;
+        let folder = path.join("42");
+        fs::write(folder.join("mailbox_meta.json"), "{ broken")?;
+
+        let report = mailbox.check("42", false).await?;
+        assert!(matches!(
+            report.issues.as_slice(),
+            [CheckIssue::BrokenMeta { .. }]
+        ));
+        assert!(report.repaired.is_empty());
+        assert!(mailbox.stats("42").await.is_err());
+
+        let report = mailbox.check("42", true).await?;
+        assert_eq!(report.repaired, report.issues);
+        assert!(!folder.join("mailbox_meta.journal").exists());
+        let stats = mailbox.stats("42").await?;
+        assert_eq!((stats.pending, stats.total_sent), (2, 3));
+        let (item_id, _two) = mailbox.receive("42").await?.expect("Item pending");
+        assert_eq!(item_id, "2");
+        assert!(mailbox.check("42", false).await?.is_healthy());
+
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn it_drops_missing_and_moves_orphaned_envelopes() -> Result<()> {
+        let dir = TempDir::new()?;
+        let path = dir.path().join("test_items");
+        let mut mailbox = MailboxDisk::<TestItem>::at(&path, Path::new("test_item"));
+        mailbox.ensure_storage_exists().await?;
+        for data in ["one", "two", "three"] {
+            mailbox.send("42", TestItem::new(data.into())).await?;
+        }
+        let folder = path.join("42");
+        fs::remove_file(folder.join("2.test_item"))?;
+        fs::copy(folder.join("1.test_item"), folder.join("7.test_item"))?;
+        let stale = folder.join(".3.test_item.tmp");
+        let fresh = folder.join(".stream-1.payload.tmp");
+        fs::write(&stale, "partial")?;
+        fs::write(&fresh, "streaming")?;
+        let an_hour_ago = SystemTime::now() - std::time::Duration::from_secs(3600);
+        fs::File::options()
+            .write(true)
+            .open(&stale)?
+            .set_modified(an_hour_ago)?;
+
+        let expected = vec![
+            CheckIssue::OrphanedEnvelope {
+                item_id: "7".into(),
+            },
+            CheckIssue::MissingEnvelope {
+                item_id: "2".into(),
+            },
+            CheckIssue::StaleTempFile {
+                path: stale.clone(),
+            },
+        ];
+        let report = mailbox.check("42", false).await?;
+        assert_eq!(report.issues, expected);
+        assert!(stale.exists());
+
+        let report = mailbox.check("42", true).await?;
+        assert_eq!(report.issues, expected);
+        assert_eq!(report.unrepaired().count(), 0);
+        assert!(folder.join("7.test_item.orphaned").exists());
+        assert!(!stale.exists());
+        assert!(fresh.exists());
+
+        let received: Vec<String> = mailbox
+            .drain("42", None)
+            .await?
+            .into_iter()
+            .map(|(item_id, _item)| item_id)
+            .collect();
+        assert_eq!(received, ["1", "3"]);
+
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn it_clears_read_flags_but_not_broken_payloads() -> Result<()> {
+        let dir = TempDir::new()?;
+        let path = dir.path().join("test_items");
+        let mut mailbox =
+            MailboxDisk::<TestItem>::at(&path, Path::new("test_item")).with_sidecar_threshold(8);
+        mailbox.ensure_storage_exists().await?;
+        for data in ["a large first item", "a large second item"] {
+            mailbox.send("42", TestItem::new(data.into())).await?;
+        }
+        let folder = path.join("42");
+        let envelope = folder.join("1.test_item");
+        let json = fs::read_to_string(&envelope)?;
+        fs::write(&envelope, json.replace("\"read\": false", "\"read\": true"))?;
+        fs::write(folder.join("2.payload"), "tampered")?;
+
+        let report = mailbox.check("42", true).await?;
+        assert_eq!(
+            report.repaired,
+            [CheckIssue::ReadFlagMismatch {
+                item_id: "1".into()
+            }]
+        );
+        let unrepaired: Vec<&CheckIssue> = report.unrepaired().collect();
+        assert!(matches!(
+            unrepaired.as_slice(),
+            [CheckIssue::PayloadMismatch { item_id, .. }] if item_id == "2"
+        ));
+        assert!(fs::read_to_string(&envelope)?.contains("\"read\": false"));
+
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn it_trims_mailboxes_to_the_retention_limit() -> Result<()> {
+        let dir = TempDir::new()?;
+        let path = dir.path().join("test_items");
+        let mut mailbox = MailboxDisk::<TestItem>::at(&path, Path::new("test_item"))
+            .with_journal(100)
+            .with_retention(RetentionPolicy {
+                max_items: 5,
+                drop_unread: true,
+            });
+        mailbox.ensure_storage_exists().await?;
+        for i in 1..=10 {
+            mailbox.send("42", TestItem::new(format!("{i}"))).await?;
+        }
+        let stats = mailbox.stats("42").await?;
+        assert_eq!((stats.pending, stats.total_dropped_unread), (5, 5));
+        assert_eq!(mailbox.item_files("42").await?.len(), 5);
+
+        // Note: a fresh instance replays the journal
+        let reopened = MailboxDisk::<TestItem>::at(&path, Path::new("test_item"));
+        assert_eq!(reopened.stats("42").await?.total_dropped_unread, 5);
+        let received: Vec<String> = reopened
+            .drain("42", None)
+            .await?
+            .into_iter()
+            .map(|(item_id, _item)| item_id)
+            .collect();
+        assert_eq!(received, ["6", "7", "8", "9", "10"]);
+
+        // only read items are removed, and only as many as needed
+        let policy = RetentionPolicy {
+            max_items: 3,
+            drop_unread: false,
+        };
+        mailbox.set_retention("7", Some(policy)).await?;
+        assert_eq!(mailbox.retention("7").await?, Some(policy));
+        for i in 1..=4 {
+            mailbox.send("7", TestItem::new(format!("{i}"))).await?;
+        }
+        mailbox.acknowledge("7", "1").await?;
+        mailbox.acknowledge("7", "2").await?;
+        mailbox.send("7", TestItem::new("5".into())).await?;
+        let ids: BTreeSet<u64> = mailbox
+            .item_files("7")
+            .await?
+            .into_iter()
+            .map(|(id, _)| id)
+            .collect();
+        assert_eq!(ids, BTreeSet::from([3, 4, 5]));
+        mailbox.send("7", TestItem::new("6".into())).await?;
+        let stats = mailbox.stats("7").await?;
+        assert_eq!((stats.pending, stats.total_dropped_unread), (4, 0));
+
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn it_takes_turns_receiving_from_any_mailbox_on_disk() -> Result<()> {
+        let dir = TempDir::new()?;
+        let path = dir.path().join("test_items");
+        let mut mailbox = MailboxDisk::<TestItem>::at(&path, Path::new("test_item"));
+        mailbox.ensure_storage_exists().await?;
+        mailbox.create_mailbox("empty").await?;
+        for i in 1..=3 {
+            for id in ["a", "b"] {
+                mailbox.send(id, TestItem::new(format!("{id}{i}"))).await?;
+            }
+        }
+
+        let ids = ["a", "empty", "missing", "b"].map(String::from);
+        let mut served = Vec::new();
+        while let Some((id, item_id, item)) = mailbox.receive_any(&ids).await? {
+            mailbox.acknowledge(&id, &item_id).await?;
+            served.push(item.data);
+        }
+        assert_eq!(served, ["a1", "b1", "a2", "b2", "a3", "b3"]);
+        assert!(!path.join("missing").exists());
+
+        let strict = MailboxDisk::<TestItem>::at(&path, Path::new("test_item")).strict();
+        let e = strict.receive_any(&ids).await.unwrap_err();
+        assert!(matches!(
+            e.downcast_ref::<MailboxError>(),
+            Some(MailboxError::UnknownMailbox { .. })
+        ));
+
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn it_checks_the_configuration_on_open() -> Result<()> {
+        fn error_of<T>(r: Result<T>) -> MailboxError {
+            match r {
+                Ok(_) => panic!("Invalid configuration accepted"),
+                Err(e) => e.downcast::<MailboxError>().expect("A MailboxError"),
+            }
+        }
+
+        let dir = TempDir::new()?;
+        let path = dir.path().join("test_items");
+        let extension = Path::new("test_item");
+        let e = error_of(MailboxDisk::<TestItem>::open_existing(&path, extension));
+        assert!(matches!(e, MailboxError::StorageNotFound { .. }));
+        assert!(!path.exists());
+
+        for (extension, reason) in [("", "empty"), ("test.item", "dot"), ("a/b", "separator")] {
+            let e = error_of(MailboxDisk::<TestItem>::open(&path, Path::new(extension)));
+            match e {
+                MailboxError::InvalidExtension { reason: r, .. } => assert!(r.contains(reason)),
+                e => panic!("Unexpected error {e:?}"),
+            }
+        }
+        let e = error_of(MailboxDisk::<TestItem>::open(&path, Path::new("payload")));
+        assert!(matches!(e, MailboxError::InvalidExtension { .. }));
+        assert!(!path.exists());
+
+        let file = dir.path().join("file");
+        fs::write(&file, "not a folder")?;
+        let e = error_of(MailboxDisk::<TestItem>::open(&file, extension));
+        assert!(matches!(e, MailboxError::StorageNotADirectory { .. }));
+        let e = error_of(MailboxDisk::<TestItem>::open(&file.join("sub"), extension));
+        assert!(matches!(e, MailboxError::StorageNotWritable { .. }));
+
+        let mailbox =
+            MailboxDisk::<TestItem>::open(&dir.path().join(".").join("test_items"), extension)?;
+        assert_eq!(mailbox.base_path, path.canonicalize()?);
+        assert_eq!(fs::read_dir(&path)?.count(), 0);
+        mailbox.send("42", TestItem::new("one".into())).await?;
+        let mailbox = MailboxDisk::<TestItem>::open_existing(&path, extension)?;
+        assert_eq!(mailbox.stats("42").await?.pending, 1);
+
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn it_sets_corrupt_items_aside() -> Result<()> {
+        for policy in [
+            CorruptionPolicy::Fail,
+            CorruptionPolicy::Skip,
+            CorruptionPolicy::Quarantine,
+        ] {
+            let dir = TempDir::new()?;
+            let path = dir.path().join("test_items");
+            let mut mailbox = MailboxDisk::<TestItem>::at(&path, Path::new("test_item"))
+                .with_corruption_policy(policy);
+            mailbox.ensure_storage_exists().await?;
+            for i in 1..=3 {
+                mailbox.send("42", TestItem::new(format!("{i}"))).await?;
+            }
+            mailbox.send_raw("42", b"not json").await?;
+            mailbox.send("42", TestItem::new("5".into())).await?;
+            fs::write(path.join("42").join("2.test_item"), "{ broken")?;
+
+            let mut received = Vec::new();
+            let r = loop {
+                match mailbox.receive("42").await {
+                    Ok(Some((item_id, item))) => {
+                        mailbox.acknowledge("42", &item_id).await?;
+                        received.push(item.data);
+                    }
+                    Ok(None) => break Ok(()),
+                    Err(e) => break Err(e),
+                }
+            };
+            let quarantined = mailbox.quarantined("42").await?;
+            if policy == CorruptionPolicy::Fail {
+                assert!(r.is_err());
+                assert_eq!(received, ["1"]);
+                continue;
+            }
+            r?;
+            assert_eq!(received, ["1", "3", "5"], "{policy:?}");
+            assert_eq!(mailbox.stats("42").await?.pending, 0);
+            if policy == CorruptionPolicy::Skip {
+                assert!(quarantined.is_empty());
+                continue;
+            }
+            let ids: Vec<&str> = quarantined.iter().map(|q| q.item_id.as_str()).collect();
+            assert_eq!(ids, ["2", "4"]);
+            assert_eq!(fs::read_to_string(&quarantined[0].path)?, "{ broken");
+            assert!(quarantined[1].reason.contains("expected"));
+            assert!(!path.join("42").join("4.test_item").exists());
+        }
+
+        Ok(())
+    }
+
+    #[test(tokio::test(start_paused = true))]
+    async fn it_times_out_waiting_for_a_stuck_operation() -> Result<()> {
+        use std::time::Duration;
+
+        let dir = TempDir::new()?;
+        let path = dir.path().join("test_items");
+        let mut mailbox = MailboxDisk::<TestItem>::at(&path, Path::new("test_item"))
+            .with_op_timeout(Duration::from_secs(5));
+        mailbox.ensure_storage_exists().await?;
+        mailbox.send("42", TestItem::new("one".into())).await?;
+
+        // Note: like an operation stuck in the file system, while holding the lock
+        let stuck = mailbox.lock().await?;
+        let e = mailbox
+            .send("42", TestItem::new("two".into()))
+            .await
+            .unwrap_err();
+        match e.downcast_ref::<MailboxError>() {
+            Some(MailboxError::Timeout { op, mailbox_id }) => {
+                assert_eq!((op.as_str(), mailbox_id.as_str()), ("send", "42"));
+            }
+            _ => panic!("Unexpected error {e:?}"),
+        }
+        drop(stuck);
+
+        let mailbox = mailbox.with_lock_timeout(Duration::from_secs(1));
+        let stuck = mailbox.lock().await?;
+        let e = mailbox.receive("42").await.unwrap_err();
+        assert!(matches!(
+            e.downcast_ref::<MailboxError>(),
+            Some(MailboxError::LockTimeout { .. })
+        ));
+        assert!(MailboxError::is_retryable_report(&e));
+        drop(stuck);
+
+        let (_item_id, item) = mailbox.receive("42").await?.expect("Item pending");
+        assert_eq!(item.data, "one");
+        assert_eq!(mailbox.stats("42").await?.pending, 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn it_keeps_other_mailboxes_going_while_a_file_system_call_hangs() -> Result<()> {
+        use std::sync::Condvar;
+
+        // Note: only two blocking threads, so the calls waiting for the hung one must not take any
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .max_blocking_threads(2)
+            .enable_all()
+            .build()?;
+        let dir = TempDir::new()?;
+        let path = dir.path().join("test_items");
+        let hung = Arc::new((Mutex::new(false), Condvar::new()));
+        let storage = crate::FsStorage::new().with_io_hook({
+            let (hung, hung_path) = (hung.clone(), path.join("a"));
+            move |key| {
+                let (hung, changed) = &*hung;
+                let mut hung = hung.lock().unwrap();
+                while *hung && key.starts_with(&hung_path) {
+                    hung = changed.wait(hung).unwrap();
+                }
+            }
+        });
+        let set_hung = |value: bool| {
+            *hung.0.lock().unwrap() = value;
+            hung.1.notify_all();
+        };
+        // Note: a failing test would wait for the hung call forever, when dropping the runtime
+        let _release = super::CancelGuard::new(|| set_hung(false));
+        runtime.block_on(async {
+            let mut mailbox =
+                MailboxKv::<TestItem, _>::in_storage(storage, &path, Path::new("test_item"))
+                    .with_op_timeout(Duration::from_millis(200));
+            mailbox.ensure_storage_exists().await?;
+            mailbox.send("a", TestItem::new("one".into())).await?;
+
+            set_hung(true);
+            for _ in 0..4 {
+                let e = mailbox
+                    .send("a", TestItem::new("two".into()))
+                    .await
+                    .unwrap_err();
+                assert!(matches!(
+                    e.downcast_ref::<MailboxError>(),
+                    Some(MailboxError::Timeout { .. })
+                ));
+            }
+            let item_id = mailbox.send("b", TestItem::new("one".into())).await?;
+            let (received_id, item) = mailbox.receive("b").await?.expect("Item pending");
+            assert_eq!((received_id, item.data.as_str()), (item_id, "one"));
+            mailbox.acknowledge("b", "1").await?;
+            set_hung(false);
+
+            assert_eq!(mailbox.stats("a").await?.pending, 1);
+            assert_eq!(mailbox.stats("b").await?.pending, 0);
+            Ok(())
+        })
+    }
+
+    #[test(tokio::test)]
+    async fn it_keeps_the_quota_of_sends_dropped_while_charging() -> Result<()> {
+        use std::sync::atomic::AtomicUsize;
+        use std::sync::Condvar;
+
+        let dir = TempDir::new()?;
+        let hung = Arc::new((Mutex::new(false), Condvar::new()));
+        let quota_calls = Arc::new(AtomicUsize::new(0));
+        let storage = crate::FsStorage::new().with_io_hook({
+            let (hung, quota_calls) = (hung.clone(), quota_calls.clone());
+            let quota_path = dir.path().join(".quota");
+            move |key| {
+                // Note: the first call checks the quota, the second one is part of charging it
+                if !key.starts_with(&quota_path) || quota_calls.fetch_add(1, Ordering::SeqCst) != 1
+                {
+                    return;
+                }
+                let (hung, changed) = &*hung;
+                let mut hung = hung.lock().unwrap();
+                while *hung {
+                    hung = changed.wait(hung).unwrap();
+                }
+            }
+        });
+        let set_hung = |value: bool| {
+            *hung.0.lock().unwrap() = value;
+            hung.1.notify_all();
+        };
+        let _release = super::CancelGuard::new(|| set_hung(false));
+        let mut mailbox =
+            MailboxKv::<TestItem, _>::in_storage(storage, dir.path(), Path::new("test_item"))
+                .with_quota(QuotaManager::by_first_segment());
+        mailbox.ensure_storage_exists().await?;
+        mailbox.send("42", TestItem::new("one".into())).await?;
+
+        quota_calls.store(0, Ordering::SeqCst);
+        set_hung(true);
+        let send = mailbox.send("42", TestItem::new("two".into()));
+        assert!(tokio::time::timeout(Duration::from_millis(200), send)
+            .await
+            .is_err());
+        set_hung(false);
+
+        // the send is either committed with its charge, or not at all
+        let pending = mailbox.stats("42").await?.pending;
+        assert_eq!(mailbox.quota_usage("42").await?.items, pending);
+
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn it_times_out_while_the_file_system_is_stuck() -> Result<()> {
+        use std::sync::Condvar;
+
+        let dir = TempDir::new()?;
+        let path = dir.path().join("test_items");
+        let stuck = Arc::new((Mutex::new(false), Condvar::new()));
+        let storage = crate::FsStorage::new().with_io_hook({
+            let stuck = stuck.clone();
+            move |_key| {
+                let (stuck, changed) = &*stuck;
+                let mut stuck = stuck.lock().unwrap();
+                while *stuck {
+                    stuck = changed.wait(stuck).unwrap();
+                }
+            }
+        });
+        let set_stuck = |value: bool| {
+            *stuck.0.lock().unwrap() = value;
+            stuck.1.notify_all();
+        };
+        let mut mailbox =
+            MailboxKv::<TestItem, _>::in_storage(storage, &path, Path::new("test_item"))
+                .with_op_timeout(Duration::from_millis(200));
+        mailbox.ensure_storage_exists().await?;
+        mailbox.send("42", TestItem::new("one".into())).await?;
+
+        set_stuck(true);
+        let started = std::time::Instant::now();
+        let e = mailbox
+            .send("42", TestItem::new("two".into()))
+            .await
+            .unwrap_err();
+        match e.downcast_ref::<MailboxError>() {
+            Some(MailboxError::Timeout { op, mailbox_id }) => {
+                assert_eq!((op.as_str(), mailbox_id.as_str()), ("send", "42"));
+            }
+            _ => panic!("Unexpected error {e:?}"),
+        }
+        assert!(started.elapsed() < Duration::from_secs(5));
+        let e = mailbox.receive("42").await.unwrap_err();
+        assert!(matches!(
+            e.downcast_ref::<MailboxError>(),
+            Some(MailboxError::Timeout { .. })
+        ));
+        set_stuck(false);
+
+        // Note: the calls of the timed out operations finish first, and change nothing visible
+        assert_eq!(mailbox.stats("42").await?.pending, 1);
+        let item_id = mailbox.send("42", TestItem::new("three".into())).await?;
+        assert_eq!(item_id, "2");
+        let (item_id, item) = mailbox.receive("42").await?.expect("Item pending");
+        assert_eq!((item_id.as_str(), item.data.as_str()), ("1", "one"));
+        mailbox.acknowledge("42", &item_id).await?;
+        let (_item_id, item) = mailbox.receive("42").await?.expect("Item pending");
+        assert_eq!(item.data, "three");
+        assert!(mailbox.check("42", false).await?.is_healthy());
+
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn it_overrides_the_configuration_per_mailbox() -> Result<()> {
+        let dir = TempDir::new()?;
+        let path = dir.path().join("test_items");
+        let mut mailbox = MailboxDisk::<TestItem>::at(&path, Path::new("test_item"));
+        mailbox.ensure_storage_exists().await?;
+        for i in 0..3 {
+            mailbox.send("a", TestItem::new(format!("{i}"))).await?;
+            mailbox.send("b", TestItem::new(format!("{i}"))).await?;
+        }
+        assert!(mailbox.get_settings("a").await?.is_empty());
+
+        let settings = MailboxSettings {
+            capacity: Some((2, crate::Backpressure::Reject)),
+            delivery_mode: Some(DeliveryMode::AtMostOnce),
+            ..Default::default()
+        };
+        mailbox.set_settings("a", settings.clone()).await?;
+
+        // Note: the items above the capacity are kept, but no more are accepted
+        assert_eq!(mailbox.stats("a").await?.pending, 3);
+        let e = mailbox
+            .send("a", TestItem::new("3".into()))
+            .await
+            .expect_err("Mailbox is full");
+        assert!(matches!(
+            e.downcast_ref::<MailboxError>(),
+            Some(MailboxError::MailboxFull { capacity: 2, .. })
+        ));
+        mailbox.send("b", TestItem::new("3".into())).await?;
+
+        let (_id, item) = mailbox.receive("a").await?.expect("Item pending");
+        assert_eq!(item.data, "0");
+        assert_eq!(mailbox.stats("a").await?.pending, 2);
+        mailbox.receive("b").await?.expect("Item pending");
+        assert_eq!(mailbox.stats("b").await?.pending, 4);
+
+        let snapshot = mailbox.snapshot_meta("a").await?;
+        mailbox
+            .set_settings("a", MailboxSettings::default())
+            .await?;
+        mailbox.restore_meta("a", &snapshot).await?;
+
+        let mailbox = MailboxDisk::<TestItem>::at(&path, Path::new("test_item"));
+        assert_eq!(mailbox.get_settings("a").await?, settings);
+        assert!(mailbox.get_settings("b").await?.is_empty());
+
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn it_expires_items_past_their_ttl() -> Result<()> {
+        // Note: not the shared test clock, tests run in parallel
+        use std::time::Duration;
+
+        static NOW: AtomicI64 = AtomicI64::new(1_700_000_000);
+        fn clock() -> DateTime<Utc> {
+            DateTime::from_timestamp(NOW.load(Ordering::Relaxed), 0).unwrap_or_default()
+        }
+
+        let dir = TempDir::new()?;
+        let path = dir.path().join("test_items");
+        let mut mailbox = MailboxDisk::<TestItem>::at(&path, Path::new("test_item"))
+            .with_clock(clock)
+            .with_in_flight_timeout(Duration::from_secs(120));
+        mailbox.ensure_storage_exists().await?;
+        let ttl = Duration::from_secs(60);
+        for data in ["leading", "in flight", "kept", "middle"] {
+            mailbox
+                .send_with_ttl("42", TestItem::new(data.into()), ttl)
+                .await?;
+        }
+        mailbox.send("42", TestItem::new("forever".into())).await?;
+        // "1" and "2" are delivered, "1" is acknowledged, "3" is delivered after expiring
+        let (item_id, _item) = mailbox.receive("42").await?.expect("Item pending");
+        mailbox.acknowledge("42", &item_id).await?;
+        let (in_flight, _item) = mailbox.receive("42").await?.expect("Item pending");
+        assert_eq!(in_flight, "2");
+        assert_eq!(mailbox.expire_items("42").await?, Default::default());
+
+        NOW.fetch_add(61, Ordering::Relaxed);
+        let (item_id, _item) = mailbox.peek("42").await?.expect("Item pending");
+        assert_eq!(item_id, "5");
+        let report = mailbox.expire_items("42").await?;
+        assert_eq!(report.in_flight, ["2"]);
+        assert_eq!(report.expired, ["3", "4"]);
+
+        // the consumer of "2" is gone
+        NOW.fetch_add(60, Ordering::Relaxed);
+        let report = mailbox.expire_items("42").await?;
+        assert_eq!(report.expired, ["2"]);
+        assert!(report.in_flight.is_empty());
+
+        assert_eq!(mailbox.stats("42").await?.pending, 1);
+        let (item_id, item) = mailbox.receive("42").await?.expect("Item pending");
+        assert_eq!((item_id.as_str(), item.data.as_str()), ("5", "forever"));
+        assert_eq!(mailbox.expire_items("42").await?, Default::default());
+
+        // drain leaves expired items to expire_items too
+        mailbox.acknowledge("42", &item_id).await?;
+        for data in ["short", "long"] {
+            let ttl = Duration::from_secs(if data == "short" { 60 } else { 600 });
+            mailbox
+                .send_with_ttl("42", TestItem::new(data.into()), ttl)
+                .await?;
+        }
+        NOW.fetch_add(61, Ordering::Relaxed);
+        let drained = mailbox.drain("42", None).await?;
+        let data: Vec<&str> = drained.iter().map(|(_, item)| item.data.as_str()).collect();
+        assert_eq!(data, ["long"]);
+        assert_eq!(mailbox.expire_items("42").await?.expired, ["6"]);
+
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn it_expires_past_corrupt_items() -> Result<()> {
+        use std::time::Duration;
+
+        for policy in [CorruptionPolicy::Fail, CorruptionPolicy::Skip] {
+            let dir = TempDir::new()?;
+            let path = dir.path().join("test_items");
+            let mut mailbox = MailboxDisk::<TestItem>::at(&path, Path::new("test_item"))
+                .with_corruption_policy(policy);
+            mailbox.ensure_storage_exists().await?;
+            for data in ["one", "two"] {
+                mailbox
+                    .send_with_ttl("42", TestItem::new(data.into()), Duration::ZERO)
+                    .await?;
+            }
+            fs::write(path.join("42").join("1.test_item"), "{ broken")?;
+
+            let report = mailbox.expire_items("42").await?;
+            assert_eq!(report.corrupt, ["1"], "{policy:?}");
+            assert_eq!(report.expired, ["2"], "{policy:?}");
+            let pending = match policy {
+                CorruptionPolicy::Fail => 1,
+                _ => 0,
+            };
+            assert_eq!(mailbox.stats("42").await?.pending, pending, "{policy:?}");
+        }
+
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn it_checks_its_health() -> Result<()> {
+        use crate::HealthStatus;
+
+        let dir = TempDir::new()?;
+        let path = dir.path().join("test_items");
+        let mut mailbox = MailboxDisk::<TestItem>::at(&path, Path::new("test_item"));
+        mailbox.ensure_storage_exists().await?;
+
+        let report = mailbox.health_check().await?;
+        assert_eq!(report.status, HealthStatus::Healthy, "{}", report.message);
+        assert!(report.free_space.is_some());
+        assert!(mailbox.list_mailboxes().await?.is_empty());
+        assert_eq!(fs::read_dir(&path)?.count(), 0);
+
+        let full = MailboxDisk::<TestItem>::at(&path, Path::new("test_item"))
+            .with_min_free_space(u64::MAX);
+        let report = full.health_check().await?;
+        assert_eq!(report.status, HealthStatus::Degraded, "{}", report.message);
+        assert!(report.free_space.is_some());
+
+        // e.g. unmounted, and replaced by something else
+        fs::remove_dir(&path)?;
+        fs::write(&path, b"not a folder")?;
+        let report = mailbox.health_check().await?;
+        assert_eq!(report.status, HealthStatus::Unhealthy);
+        assert!(
+            report.message.contains("not a directory"),
+            "{}",
+            report.message
+        );
+
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn it_keeps_the_state_in_status_files() -> Result<()> {
+        let dir = TempDir::new()?;
+        let mut path = dir.path().to_path_buf();
+        path.push("test_items");
+        let mut mailbox =
+            MailboxDisk::<TestItem>::at(&path, Path::new("test_item")).with_status_files();
+        mailbox.ensure_storage_exists().await?;
+        let mailbox_id = String::from("42");
+
+        let large = "x".repeat(100_000);
+        mailbox
+            .send(&mailbox_id, TestItem::new(large.clone()))
+            .await?;
+        mailbox
+            .send(&mailbox_id, TestItem::new("small".into()))
+            .await?;
+
+        let envelope = path.join(&mailbox_id).join("1.test_item");
+        let status = path.join(&mailbox_id).join("1.status.json");
+        let before = std::fs::metadata(&envelope)?;
+        tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+
+        let (item_id, item) = mailbox.receive(&mailbox_id).await?.expect("Item pending");
+        assert_eq!(item.data, large);
+        mailbox.acknowledge(&mailbox_id, &item_id).await?;
+        assert!(status.exists());
+        let after = std::fs::metadata(&envelope)?;
+        assert_eq!(after.len(), before.len());
+        assert_eq!(after.modified()?, before.modified()?);
+        assert_eq!(mailbox.delivery_count(&mailbox_id, &item_id).await?, 1);
+
+        // without status files the state is still read, and old envelopes keep working
+        let plain = MailboxDisk::<TestItem>::at(&path, Path::new("test_item"));
+        assert_eq!(plain.delivery_count(&mailbox_id, &item_id).await?, 1);
+        let (item_id, _item) = plain.receive(&mailbox_id).await?.expect("Item pending");
+        assert!(!path.join(&mailbox_id).join("2.status.json").exists());
+        plain.acknowledge(&mailbox_id, &item_id).await?;
+        assert_eq!(mailbox.delivery_count(&mailbox_id, &item_id).await?, 1);
+        assert!(mailbox.receive(&mailbox_id).await?.is_none());
+
+        assert!(mailbox.check(&mailbox_id, false).await?.is_healthy());
+        assert!(mailbox.inspect(&mailbox_id).await?.stray_files.is_empty());
+        mailbox.compact(&mailbox_id).await?;
+        assert!(!envelope.exists());
+        assert!(!status.exists());
+
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn it_reports_disk_usage() -> Result<()> {
+        let dir = TempDir::new()?;
+        let mut mailbox = MailboxDisk::<TestItem>::at(dir.path(), Path::new("test_item"))
+            .with_sidecar_threshold(1_000);
+        mailbox.ensure_storage_exists().await?;
+        let mailbox_id = String::from("42");
+
+        mailbox.create_mailbox(&mailbox_id).await?;
+        let empty = mailbox.disk_usage(&mailbox_id).await?;
+        for _ in 0..3 {
+            mailbox
+                .send(&mailbox_id, TestItem::new("x".repeat(10_000)))
+                .await?;
+        }
+        let full = mailbox.disk_usage(&mailbox_id).await?;
+        assert!(full >= empty + 30_000);
+        assert!(full < empty + 35_000);
+        assert_eq!(mailbox.stats(&mailbox_id).await?.disk_usage, Some(full));
+
+        while let Some((item_id, _item)) = mailbox.receive(&mailbox_id).await? {
+            mailbox.acknowledge(&mailbox_id, &item_id).await?;
+        }
+        mailbox.compact(&mailbox_id).await?;
+        assert!(mailbox.disk_usage(&mailbox_id).await? < empty + 5_000);
+
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn it_fails_sends_without_free_space() -> Result<()> {
+        let dir = TempDir::new()?;
+        let mut mailbox = MailboxDisk::<TestItem>::at(dir.path(), Path::new("test_item"))
+            .with_min_free_space(u64::MAX);
+        mailbox.ensure_storage_exists().await?;
+
+        let e = mailbox
+            .send("42", TestItem::new("first".into()))
+            .await
+            .expect_err("No space");
+        assert!(matches!(
+            e.downcast_ref::<MailboxError>(),
+            Some(MailboxError::StorageFull { .. })
+        ));
+        assert!(!dir.path().join("42").exists());
+
+        let mut mailbox =
+            MailboxDisk::<TestItem>::at(dir.path(), Path::new("test_item")).with_min_free_space(1);
+        mailbox.ensure_storage_exists().await?;
+        mailbox.send("42", TestItem::new("first".into())).await?;
+
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn it_does_not_create_mailboxes_by_looking() -> Result<()> {
+        let dir = TempDir::new()?;
+        let base_path = dir.path().join("test_items");
+        let mut mailbox = MailboxDisk::<TestItem>::at(&base_path, Path::new("test_item"));
+        mailbox.ensure_storage_exists().await?;
+        let folders = || -> Result<usize> { Ok(std::fs::read_dir(&base_path)?.count()) };
+        let before = folders()?;
+
+        for device in 0..100 {
+            let mailbox_id = format!("device-{device}");
+            assert!(mailbox.receive(&mailbox_id).await?.is_none());
+            assert!(mailbox.peek(&mailbox_id).await?.is_none());
+            assert_eq!(mailbox.stats(&mailbox_id).await?.pending, 0);
+            let page = mailbox.list_items_page(&mailbox_id, None, 10).await?;
+            assert!(page.entries.is_empty());
+            assert!(!mailbox.exists(&mailbox_id).await?);
+        }
+        assert_eq!(folders()?, before);
+        assert!(mailbox.list_mailboxes().await?.is_empty());
+
+        mailbox
+            .send("device-7", TestItem::new("hello".into()))
+            .await?;
+        assert!(mailbox.exists("device-7").await?);
+        let (id, item) = mailbox.receive("device-7").await?.expect("Item pending");
+        assert_eq!(item.data, "hello");
+        mailbox.acknowledge("device-7", &id).await?;
+        assert!(mailbox.receive("device-7").await?.is_none());
+        assert_eq!(folders()?, before + 1);
+
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn it_works_on_mailbox_prefixes() -> Result<()> {
+        let dir = TempDir::new()?;
+        let mut mailbox = MailboxDisk::<TestItem>::at(dir.path(), Path::new("test_item"));
+        mailbox.ensure_storage_exists().await?;
+        for (mailbox_id, count) in [
+            ("orders/eu/123", 1),
+            ("orders/eu/456", 2),
+            ("orders/eu-archive/789", 3),
+            ("orders/us/123", 4),
+        ] {
+            for _ in 0..count {
+                mailbox
+                    .send(mailbox_id, TestItem::new("order".into()))
+                    .await?;
+            }
+        }
+        assert!(dir.path().join("orders/eu/123").is_dir());
+
+        assert_eq!(
+            mailbox.list_mailboxes_with_prefix("orders/eu").await?,
+            ["orders/eu/123", "orders/eu/456"]
+        );
+        assert_eq!(
+            mailbox.list_mailboxes_with_prefix("orders/eu/").await?,
+            ["orders/eu/123", "orders/eu/456"]
+        );
+        assert_eq!(
+            mailbox.list_mailboxes_with_prefix("orders/us/123").await?,
+            ["orders/us/123"]
+        );
+        assert!(mailbox
+            .list_mailboxes_with_prefix("orders/e")
+            .await?
+            .is_empty());
+        assert_eq!(mailbox.list_mailboxes_with_prefix("").await?.len(), 4);
+
+        let eu = mailbox.stats_prefix("orders/eu").await?;
+        assert_eq!((eu.mailboxes, eu.pending), (2, 3));
+        let all = mailbox.stats_prefix("orders").await?;
+        assert_eq!((all.mailboxes, all.pending), (4, 10));
+        assert_eq!(
+            eu.disk_usage,
+            mailbox.disk_usage("orders/eu/123").await?
+                + mailbox.disk_usage("orders/eu/456").await?
+        );
+
+        assert!(mailbox.purge_prefix("").await.is_err());
+        let purged = mailbox.purge_prefix("orders/eu").await?;
+        assert!(purged.is_all_ok());
+        assert_eq!(purged.len(), 2);
+        assert!(!dir.path().join("orders/eu").exists());
+        assert_eq!(
+            mailbox.list_mailboxes().await?,
+            ["orders/eu-archive/789", "orders/us/123"]
+        );
+        assert_eq!(mailbox.stats("orders/eu-archive/789").await?.pending, 3);
+        assert!(mailbox.pop("orders/us/123").await?.is_some());
+        assert!(mailbox.pop("orders/eu/123").await?.is_none());
+
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn it_follows_the_mock_clock() -> Result<()> {
+        let dir = TempDir::new()?;
+        let clock = MockClock::default();
+        let mut mailbox = MailboxDisk::<TestItem>::at(dir.path(), Path::new("test_item"))
+            .with_clock(clock.clone());
+        mailbox.ensure_storage_exists().await?;
+        let minute = std::time::Duration::from_secs(60);
+
+        let short = mailbox
+            .send_with_ttl("ttl", TestItem::new("short".into()), minute)
+            .await?;
+        mailbox
+            .send_with_ttl("ttl", TestItem::new("long".into()), 10 * minute)
+            .await?;
+        assert!(mailbox.expire_items("ttl").await?.expired.is_empty());
+        clock.advance(2 * minute);
+        assert_eq!(mailbox.expire_items("ttl").await?.expired, [short]);
+
+        mailbox.send("delay", TestItem::new("later".into())).await?;
+        let (item_id, _item) = mailbox.receive("delay").await?.expect("Item pending");
+        mailbox.reject_with_delay("delay", &item_id, minute).await?;
+        assert!(mailbox.receive("delay").await?.is_none());
+        clock.advance(2 * minute);
+        assert!(mailbox.receive("delay").await?.is_some());
+
+        mailbox
+            .send("retention", TestItem::new("old".into()))
+            .await?;
+        assert_eq!(mailbox.drop_older_than("retention", 60 * minute).await?, 0);
+        clock.advance(61 * minute);
+        assert_eq!(mailbox.drop_older_than("retention", 60 * minute).await?, 1);
+
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn it_sends_many_past_failures() -> Result<()> {
+        let dir = TempDir::new()?;
+        let mut mailbox =
+            MailboxDisk::<TestItem>::at(dir.path(), Path::new("test_item")).with_max_item_size(64);
+        mailbox.ensure_storage_exists().await?;
+
+        let items = vec![
+            TestItem::new("first".into()),
+            TestItem::new("x".repeat(100)),
+            TestItem::new("third".into()),
+        ];
+        let sent = mailbox.send_many("42", items).await?;
+        assert!(!sent.is_all_ok());
+        assert_eq!(sent.succeeded().count(), 2);
+        let failed: Vec<usize> = sent.failed().map(|(index, _entry)| index).collect();
+        assert_eq!(failed, [1]);
+        let entry = &sent.entries[1];
+        assert_eq!(entry.mailbox_id, "42");
+        assert_eq!(entry.item_id, None);
+        let e = entry.outcome.as_ref().expect_err("Too large");
+        assert!(!e.retryable);
+        assert!(matches!(
+            e.mailbox_error(),
+            Some(MailboxError::ItemTooLarge { limit: 64, .. })
+        ));
+
+        let json = serde_json::to_string(&sent)?;
+        let restored: BulkResult<String> = serde_json::from_str(&json)?;
+        assert_eq!(restored.failed().count(), 1);
+        assert!(sent.into_result().is_err());
+
+        assert_eq!(mailbox.pop("42").await?.expect("First").1.data, "first");
+        assert_eq!(mailbox.pop("42").await?.expect("Third").1.data, "third");
+        assert!(mailbox.pop("42").await?.is_none());
+
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn it_drains_message_groups_one_item_at_a_time() -> Result<()> {
+        let dir = TempDir::new()?;
+        let mailbox = MailboxDisk::<TestItem>::at(dir.path(), Path::new("test_item"));
+        for n in 0..2 {
+            for group_id in ["a", "b"] {
+                let item = TestItem::new(format!("{group_id}{n}"));
+                mailbox.send_grouped("42", group_id, item).await?;
+            }
+        }
+        mailbox.send("42", TestItem::new("plain".into())).await?;
+        let (a0, _item) = mailbox.receive("42").await?.expect("a0");
+
+        let drain = || async {
+            let drained: Vec<String> = mailbox
+                .drain("42", None)
+                .await?
+                .into_iter()
+                .map(|(_item_id, item)| item.data)
+                .collect();
+            Ok::<_, color_eyre::Report>(drained)
+        };
+        assert_eq!(drain().await?, ["b0", "plain"]);
+        assert_eq!(drain().await?, ["b1"]);
+        assert!(drain().await?.is_empty());
+        mailbox.acknowledge("42", &a0).await?;
+        assert_eq!(drain().await?, ["a1"]);
+
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn it_releases_items_of_message_groups() -> Result<()> {
+        let dir = TempDir::new()?;
+        let mut mailbox = MailboxDisk::<TestItem>::at(dir.path(), Path::new("test_item"));
+        mailbox.ensure_storage_exists().await?;
+        for data in ["a0", "a1"] {
+            mailbox
+                .send_grouped("42", "a", TestItem::new(data.into()))
+                .await?;
+        }
+
+        // the group waits for the item in flight, until it is released
+        let (a0, _item) = mailbox.receive("42").await?.expect("a0");
+        assert!(mailbox.receive("42").await?.is_none());
+        mailbox.release("42", &a0).await?;
+        assert_eq!(mailbox.receive("42").await?.expect("a0 again").0, a0);
+
+        // released by the next call taking the lock
+        mailbox.release_later("42", &a0);
+        assert_eq!(mailbox.receive("42").await?.expect("a0 again").0, a0);
+        assert_eq!(mailbox.delivery_count("42", &a0).await?, 3);
+        mailbox.acknowledge("42", &a0).await?;
+        let (_a1, item) = mailbox.receive("42").await?.expect("a1");
+        assert_eq!(item.data, "a1");
+
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn it_delivers_message_groups_in_order() -> Result<()> {
+        let dir = TempDir::new()?;
+        let mut mailbox = MailboxDisk::<TestItem>::at(dir.path(), Path::new("test_item"));
+        mailbox.ensure_storage_exists().await?;
+        for n in 0..5 {
+            for group_id in ["a", "b"] {
+                let item = TestItem::new(format!("{group_id}{n}"));
+                mailbox.send_grouped("42", group_id, item).await?;
+            }
+        }
+        mailbox.send("42", TestItem::new("plain".into())).await?;
 
-        let mailbox = MailboxDisk::<TestItem>::new(&path, &extension).await;
-        let mut mailbox: Box<dyn Mailbox<TestItem>> = Box::new(mailbox);
+        // Note: one item per group is in flight, the plain item is delivered as usual
+        let (a0, item) = mailbox.receive("42").await?.expect("a0");
+        assert_eq!(item.data, "a0");
+        let (b0, item) = mailbox.receive("42").await?.expect("b0");
+        assert_eq!(item.data, "b0");
+        let (plain, item) = mailbox.receive("42").await?.expect("Plain");
+        assert_eq!(item.data, "plain");
+        assert_eq!(mailbox.receive("42").await?.expect("Plain").0, plain);
+        mailbox.reject_with_delay("42", &b0, Duration::ZERO).await?;
+        assert_eq!(mailbox.receive("42").await?.expect("b0 again").0, b0);
+        mailbox.acknowledge("42", &a0).await?;
+        mailbox.acknowledge("42", &b0).await?;
+        mailbox.acknowledge("42", &plain).await?;
+
+        let mailbox = Arc::new(mailbox);
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let busy = Arc::new(AtomicUsize::new(0));
+        let most_busy = Arc::new(AtomicUsize::new(0));
+        let consumers: Vec<_> = (0..2)
+            .map(|_| {
+                let (mailbox, log) = (mailbox.clone(), log.clone());
+                let (busy, most_busy) = (busy.clone(), most_busy.clone());
+                tokio::spawn(async move {
+                    let mut idle = 0;
+                    while idle < 20 {
+                        let Some((item_id, item)) = mailbox.receive("42").await? else {
+                            idle += 1;
+                            tokio::time::sleep(Duration::from_millis(5)).await;
+                            continue;
+                        };
+                        idle = 0;
+                        let now_busy = busy.fetch_add(1, Ordering::SeqCst) + 1;
+                        most_busy.fetch_max(now_busy, Ordering::SeqCst);
+                        log.lock().unwrap().push(item.data);
+                        tokio::time::sleep(Duration::from_millis(20)).await;
+                        busy.fetch_sub(1, Ordering::SeqCst);
+                        mailbox.acknowledge("42", &item_id).await?;
+                    }
+                    Ok::<_, color_eyre::Report>(())
+                })
+            })
+            .collect();
+        for consumer in consumers {
+            consumer.await??;
+        }
+
+        let log = log.lock().unwrap().clone();
+        for group_id in ["a", "b"] {
+            let group: Vec<&str> = log
+                .iter()
+                .map(String::as_str)
+                .filter(|data| data.starts_with(group_id))
+                .collect();
+            let expected: Vec<String> = (1..5).map(|n| format!("{group_id}{n}")).collect();
+            assert_eq!(group, expected);
+        }
+        // Note: with strict ordering across the mailbox only one consumer could ever be busy
+        assert_eq!(most_busy.load(Ordering::SeqCst), 2);
+
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn it_decorates_outgoing_envelopes() -> Result<()> {
+        use tokio_stream::StreamExt;
+
+        let dir = TempDir::new()?;
+        let decorated = Arc::new(AtomicUsize::new(0));
+        let counter = decorated.clone();
+        let mut mailbox = MailboxDisk::<KindItem>::at(dir.path(), Path::new("kind_item"))
+            .with_default_headers(HashMap::from([
+                ("service".to_string(), "billing".to_string()),
+                ("kind".to_string(), "unknown".to_string()),
+            ]))
+            .with_decorator(move |draft| {
+                counter.fetch_add(1, Ordering::SeqCst);
+                let request_id = format!("{}-{}", draft.mailbox_id(), draft.item_id());
+                draft.headers.insert("request_id".to_string(), request_id);
+            });
+        mailbox.ensure_storage_exists().await?;
+
+        let item = |kind: &str| KindItem {
+            kind: kind.to_string(),
+            data: String::new(),
+        };
+        mailbox.send("42", item("invoice")).await?;
         mailbox
-            .ensure_storage_exists()
+            .send_transaction("42", vec![item("order"), item("")])
+            .await?;
+        assert_eq!(decorated.load(Ordering::SeqCst), 3);
+
+        let scanned: Vec<_> = mailbox.scan("42").collect().await;
+        let headers: Vec<_> = scanned
+            .into_iter()
+            .map(|s| s.map(|s| s.headers))
+            .collect::<Result<_>>()?;
+        for (n, (headers, kind)) in headers.iter().zip(["invoice", "order", ""]).enumerate() {
+            assert_eq!(headers["kind"], kind);
+            assert_eq!(headers["service"], "billing");
+            assert_eq!(headers["request_id"], format!("42-{}", n + 1));
+        }
+        assert_eq!(
+            mailbox.pop("42").await?.expect("Item pending").1.kind,
+            "invoice"
+        );
+
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn it_warns_at_soft_limits() -> Result<()> {
+        let dir = TempDir::new()?;
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let sink = {
+            let events = events.clone();
+            move |event| events.lock().unwrap().push(event)
+        };
+        let mut mailbox = MailboxDisk::<TestItem>::at(dir.path(), Path::new("test_item"))
+            .with_capacity(10, Backpressure::Reject)
+            .with_thresholds(Thresholds::default().with_capacity(0.8), sink);
+        mailbox.ensure_storage_exists().await?;
+        let warnings = || events.lock().unwrap().len();
+
+        for n in 1..=7 {
+            mailbox.send("42", TestItem::new(format!("{n}"))).await?;
+        }
+        assert_eq!(warnings(), 0);
+        mailbox.send("42", TestItem::new("8".into())).await?;
+        assert_eq!(
+            events.lock().unwrap()[..],
+            [ThresholdEvent::Capacity {
+                mailbox_id: "42".to_string(),
+                pending: 8,
+                capacity: 10,
+            }]
+        );
+        mailbox.send("42", TestItem::new("9".into())).await?;
+        mailbox.send("42", TestItem::new("10".into())).await?;
+        assert!(mailbox
+            .send("42", TestItem::new("11".into()))
             .await
-            .expect("Storage exists");
+            .is_err());
+        assert_eq!(warnings(), 1);
+        assert_eq!(mailbox.stats("42").await?.utilization(), Some(1.0));
 
-        let mailbox_id = format!("42");
+        assert_eq!(mailbox.drain("42", None).await?.len(), 10);
+        for n in 1..=8 {
+            mailbox.send("42", TestItem::new(format!("{n}"))).await?;
+        }
+        assert_eq!(warnings(), 2);
+        assert_eq!(mailbox.stats("42").await?.utilization(), Some(0.8));
 
-        let item = TestItem::new(String::from("one"));
-        mailbox.send(&mailbox_id, item).await.expect("Can send");
-        let item = TestItem::new(String::from("two"));
-        mailbox.send(&mailbox_id, item).await.expect("Can send");
+        Ok(())
+    }
 
-        let mut count = 0;
-        while let Some((id, item)) = mailbox.receive(&mailbox_id).await.expect("Can receive") {
-            count += 1;
-            tracing::info!("Received {id} {item:?}");
+    #[test(tokio::test)]
+    async fn it_validates_cached_metas() -> Result<()> {
+        let dir = TempDir::new()?;
+        let open = || {
+            MailboxDisk::<TestItem>::at(dir.path(), Path::new("test_item"))
+                .with_meta_cache(CacheMode::Validate)
+        };
+        let mut sender = open();
+        sender.ensure_storage_exists().await?;
+        let receiver = open();
 
-            mailbox.acknowledge(&mailbox_id, &id).await?;
-            // break;
-            if count > 10 {
-                break;
+        assert!(receiver.receive("42").await?.is_none());
+        sender.create_mailbox("42").await?;
+        assert!(receiver.receive("42").await?.is_none());
+        for n in 0..20 {
+            let item_id = sender.send("42", TestItem::new(format!("{n}"))).await?;
+            let (received, item) = receiver.receive("42").await?.expect("Item pending");
+            assert_eq!(
+                (received.as_str(), item.data),
+                (item_id.as_str(), format!("{n}"))
+            );
+            receiver.acknowledge("42", &received).await?;
+            assert_eq!(sender.stats("42").await?.pending, 0);
+        }
+
+        let stats = receiver.meta_cache_stats();
+        assert!(stats.hits >= 20, "{stats:?}");
+        assert!(stats.invalidations >= 20, "{stats:?}");
+
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn it_exports_without_item_data() -> Result<()> {
+        use crate::ExportContent;
+        use crate::ExportEntry;
+
+        let dir = TempDir::new()?;
+        let path = dir.path().join("test_items");
+        let mut mailbox = MailboxDisk::<TestItem>::at(&path, Path::new("test_item"))
+            .with_default_headers(HashMap::from([
+                ("tenant".to_string(), "acme".to_string()),
+                ("customer".to_string(), "Jane Doe".to_string()),
+            ]))
+            .with_sidecar_threshold(256)
+            .with_status_files()
+            .with_trash()
+            .with_corruption_policy(CorruptionPolicy::Quarantine);
+        mailbox.ensure_storage_exists().await?;
+        mailbox
+            .send("42", TestItem::new("secret one".into()))
+            .await?;
+        mailbox
+            .send("42", TestItem::new("secret two".into()))
+            .await?;
+        mailbox.send_raw("42", b"secret raw").await?;
+        mailbox
+            .send("42", TestItem::new("secret big ".repeat(50)))
+            .await?;
+        let (item_id, _item) = mailbox.receive("42").await?.expect("Item pending");
+        mailbox.acknowledge("42", &item_id).await?;
+        mailbox.compact("42").await?;
+        let (item_id, _item) = mailbox.receive("42").await?.expect("Item pending");
+        assert_eq!(item_id, "2");
+        mailbox.acknowledge("42", &item_id).await?;
+        // the raw item is quarantined on the way
+        assert_eq!(mailbox.receive("42").await?.expect("Item pending").0, "4");
+
+        let mut archive = Vec::new();
+        let summary = mailbox
+            .redact_export("42", &["tenant"], &mut archive)
+            .await?;
+        let text = String::from_utf8(archive)?;
+        for secret in ["secret", "Jane", "c2VjcmV0"] {
+            assert!(!text.contains(secret), "{secret} in {text}");
+        }
+
+        let entries: Vec<ExportEntry> = text
+            .lines()
+            .map(serde_json::from_str)
+            .collect::<std::result::Result<_, _>>()?;
+        assert_eq!(entries.len() as u64, summary.files);
+        assert_eq!(summary.dropped_headers, summary.envelopes);
+        let meta = entries
+            .iter()
+            .find(|entry| entry.path == "mailbox_meta.json")
+            .expect("Meta exported");
+        let expected = fs::read_to_string(path.join("42").join("mailbox_meta.json"))?;
+        assert_eq!(meta.content, ExportContent::Verbatim { text: expected });
+        assert!(entries.iter().any(|entry| entry.path == "4.payload"));
+        let mut envelopes = Vec::new();
+        for entry in &entries {
+            match &entry.content {
+                ExportContent::Envelope { envelope } => {
+                    assert_eq!(envelope["data"], "");
+                    assert_eq!(envelope["headers"]["tenant"], "acme");
+                    assert!(envelope["payload_sha256"].is_string());
+                    envelopes.push((entry.path.as_str(), envelope["id"].as_str()));
+                }
+                ExportContent::Redacted { .. } => {
+                    assert!(
+                        entry.path.ends_with(".payload") || entry.path.starts_with(".quarantine/")
+                    )
+                }
+                ExportContent::Verbatim { .. } => {}
             }
         }
+        assert_eq!(
+            envelopes,
+            [
+                (".quarantine/3.test_item", Some("3")),
+                (".trash/1.test_item", Some("1")),
+                ("2.test_item", Some("2")),
+                ("4.test_item", Some("4")),
+            ]
+        );
 
-        assert!(count == 2);
+        Ok(())
+    }
+
+    /// Strips what newer versions record from the envelopes and the meta in `folder`
+    fn make_legacy(folder: &Path, extension: &str) -> Result<()> {
+        for entry in fs::read_dir(folder)? {
+            let p = entry?.path();
+            let fields: &[&str] = match p.extension().and_then(|e| e.to_str()) {
+                Some(e) if e == extension => &["payload_len", "sent_at"],
+                Some("json") => &["layout_version"],
+                _ => continue,
+            };
+            let mut json: serde_json::Value = serde_json::from_slice(&fs::read(&p)?)?;
+            for field in fields {
+                json.as_object_mut().expect("Object").remove(*field);
+            }
+            fs::write(&p, serde_json::to_vec(&json)?)?;
+        }
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn it_upgrades_legacy_mailboxes() -> Result<()> {
+        let dir = TempDir::new()?;
+        let mailbox = MailboxDisk::<TestItem>::at(dir.path(), Path::new("test_item"));
+        for data in ["one", "two", "three"] {
+            mailbox.send("42", TestItem::new(data.into())).await?;
+        }
+        let (item_id, _item) = mailbox.receive("42").await?.expect("Item pending");
+        mailbox.acknowledge("42", &item_id).await?;
+        let folder = dir.path().join("42");
+        make_legacy(&folder, "test_item")?;
+        let legacy = fs::read_to_string(folder.join("2.test_item"))?;
+
+        let report = mailbox
+            .upgrade_mailbox("42", LayoutVersion::V2, true)
+            .await?;
+        assert_eq!(
+            (report.from, report.to),
+            (LayoutVersion::V1, LayoutVersion::V2)
+        );
+        assert_eq!(report.upgraded, ["1", "2", "3"]);
+        assert_eq!(fs::read_to_string(folder.join("2.test_item"))?, legacy);
+
+        let upgraded = mailbox
+            .upgrade_mailbox("42", LayoutVersion::V2, false)
+            .await?;
+        assert_eq!(upgraded, report);
+        let e: serde_json::Value = serde_json::from_slice(&fs::read(folder.join("2.test_item"))?)?;
+        assert!(e["payload_len"].is_u64() && e["sent_at"].is_string());
+        assert!(!folder.join("mailbox_meta.upgrade").exists());
+        assert!(mailbox
+            .upgrade_mailbox("42", LayoutVersion::V2, false)
+            .await?
+            .is_noop());
+
+        let r = mailbox
+            .upgrade_mailbox("42", LayoutVersion::V1, false)
+            .await;
+        assert!(matches!(
+            r.expect_err("Downgrade").downcast_ref::<MailboxError>(),
+            Some(MailboxError::LayoutDowngrade { .. })
+        ));
+        assert_eq!(
+            mailbox.pop("42").await?.expect("Item pending").1.data,
+            "two"
+        );
+        assert_eq!(
+            mailbox.pop("42").await?.expect("Item pending").1.data,
+            "three"
+        );
+
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn it_freezes_mailboxes() -> Result<()> {
+        use crate::FreezeMode;
+
+        let dir = TempDir::new()?;
+        let mailbox = MailboxDisk::<TestItem>::at(dir.path(), Path::new("test_item"));
+        fn frozen_with<T>(r: Result<T>) -> Option<FreezeMode> {
+            match r.err()?.downcast_ref::<MailboxError>() {
+                Some(MailboxError::Frozen { mode, .. }) => Some(*mode),
+                _ => None,
+            }
+        }
+        mailbox.send("42", TestItem::new("one".into())).await?;
+        mailbox.send("42", TestItem::new("two".into())).await?;
+        let (item_id, _item) = mailbox.receive("42").await?.expect("Item pending");
+
+        mailbox.set_frozen("42", FreezeMode::NoReceive).await?;
+        assert_eq!(
+            frozen_with(mailbox.receive("42").await),
+            Some(FreezeMode::NoReceive)
+        );
+        assert!(frozen_with(mailbox.drain("42", None).await).is_some());
+        mailbox.acknowledge("42", &item_id).await?;
+        mailbox.send("42", TestItem::new("three".into())).await?;
+        assert_eq!(mailbox.stats("42").await?.frozen, FreezeMode::NoReceive);
+        assert_eq!(mailbox.inspect("42").await?.frozen, FreezeMode::NoReceive);
+
+        // the freeze is in the meta
+        let reopened = MailboxDisk::<TestItem>::at(dir.path(), Path::new("test_item"));
+        assert_eq!(reopened.frozen("42").await?, FreezeMode::NoReceive);
+        assert!(frozen_with(reopened.pop("42").await).is_some());
+
+        reopened.set_frozen("42", FreezeMode::NoSend).await?;
+        let sent = mailbox.send("42", TestItem::new("lost".into())).await;
+        assert_eq!(frozen_with(sent), Some(FreezeMode::NoSend));
+        let (item_id, item) = mailbox.receive("42").await?.expect("Item pending");
+        assert_eq!(item.data, "two");
+        mailbox.acknowledge("42", &item_id).await?;
+
+        mailbox.set_frozen("42", FreezeMode::Full).await?;
+        assert!(frozen_with(mailbox.send("42", TestItem::new("lost".into())).await).is_some());
+        assert!(frozen_with(mailbox.receive("42").await).is_some());
+        assert!(mailbox.peek("42").await?.is_some());
+
+        mailbox.set_frozen("42", FreezeMode::None).await?;
+        // no id was used up by the frozen sends
+        let item_id = mailbox.send("42", TestItem::new("four".into())).await?;
+        assert_eq!(item_id, "4");
+        assert_eq!(
+            mailbox.pop("42").await?.expect("Item pending").1.data,
+            "three"
+        );
+
+        Ok(())
+    }
+
+    #[cfg(feature = "fs-watch")]
+    #[test(tokio::test)]
+    async fn it_wakes_waiting_receivers_when_unfrozen() -> Result<()> {
+        use crate::FreezeMode;
+
+        let dir = TempDir::new()?;
+        let mailbox = Arc::new(MailboxDisk::<TestItem>::at(
+            dir.path(),
+            Path::new("test_item"),
+        ));
+        mailbox.send("42", TestItem::new("one".into())).await?;
+        mailbox.set_frozen("42", FreezeMode::NoReceive).await?;
+
+        let waiting = tokio::spawn({
+            let mailbox = mailbox.clone();
+            async move {
+                let started = std::time::Instant::now();
+                let received = mailbox.receive_wait("42", Duration::from_secs(10)).await?;
+                Result::<_>::Ok((received, started.elapsed()))
+            }
+        });
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        mailbox.set_frozen("42", FreezeMode::None).await?;
+        let (received, waited) = waiting.await??;
+        assert_eq!(received.expect("Item pending").1.data, "one");
+        assert!(waited < Duration::from_secs(5));
+
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn it_publishes_events_after_saving() -> Result<()> {
+        let dir = TempDir::new()?;
+        let mailbox = MailboxDisk::<TestItem>::at(dir.path(), Path::new("test_item"));
+        mailbox.create_mailbox("42").await?;
+        let mut events = mailbox.subscribe_events();
+
+        mailbox.send("42", TestItem::new("one".into())).await?;
+        mailbox.send("42", TestItem::new("two".into())).await?;
+        for _ in 0..2 {
+            let (item_id, _item) = mailbox.receive("42").await?.expect("Item pending");
+            mailbox.acknowledge("42", &item_id).await?;
+        }
+
+        let mailbox_id = || "42".to_string();
+        let expected = [
+            MailboxEvent::ItemSent {
+                mailbox_id: mailbox_id(),
+                item_id: "1".into(),
+            },
+            MailboxEvent::MailboxBecameNonEmpty {
+                mailbox_id: mailbox_id(),
+            },
+            MailboxEvent::ItemSent {
+                mailbox_id: mailbox_id(),
+                item_id: "2".into(),
+            },
+            MailboxEvent::ItemAcknowledged {
+                mailbox_id: mailbox_id(),
+                item_id: "1".into(),
+            },
+            MailboxEvent::ItemAcknowledged {
+                mailbox_id: mailbox_id(),
+                item_id: "2".into(),
+            },
+            MailboxEvent::MailboxBecameEmpty {
+                mailbox_id: mailbox_id(),
+            },
+        ];
+        for event in expected {
+            assert_eq!(events.try_recv()?, event);
+        }
+        assert!(events.try_recv().is_err());
+
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn it_starves_low_priorities_strictly() -> Result<()> {
+        let dir = TempDir::new()?;
+        let clock = MockClock::default();
+        let mailbox = MailboxDisk::<PriorityItem>::at(dir.path(), Path::new("test_item"))
+            .with_clock(clock.clone());
+        mailbox
+            .set_fairness("42", Some(FairnessPolicy::Strict))
+            .await?;
+        let minute = Duration::from_secs(60);
+
+        mailbox.send("42", PriorityItem::new(0, "low")).await?;
+        for _ in 0..5 {
+            clock.advance(minute);
+            mailbox.send("42", PriorityItem::new(1, "high")).await?;
+            let (_item_id, item) = mailbox.pop("42").await?.expect("Item pending");
+            assert_eq!(item.data, "high");
+        }
+
+        mailbox.send("42", PriorityItem::new(2, "urgent")).await?;
+        mailbox.send("42", PriorityItem::new(2, "urgent")).await?;
+        clock.advance(minute);
+        let priorities = mailbox.stats("42").await?.priorities;
+        assert_eq!(priorities.keys().copied().collect::<Vec<_>>(), vec![0, 2]);
+        assert_eq!(priorities[&0].pending, 1);
+        assert_eq!(priorities[&0].oldest_age(clock.now()), Some(6 * minute));
+        assert_eq!(priorities[&2].pending, 2);
+        assert_eq!(priorities[&2].oldest_age(clock.now()), Some(minute));
+
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn it_boosts_waiting_items() -> Result<()> {
+        let dir = TempDir::new()?;
+        let clock = MockClock::default();
+        let mailbox = MailboxDisk::<PriorityItem>::at(dir.path(), Path::new("test_item"))
+            .with_clock(clock.clone());
+        let minute = Duration::from_secs(60);
+        mailbox
+            .set_fairness("42", Some(FairnessPolicy::AgingBoost { after: minute }))
+            .await?;
+
+        mailbox.send("42", PriorityItem::new(0, "low one")).await?;
+        mailbox.send("42", PriorityItem::new(0, "low two")).await?;
+        mailbox.send("42", PriorityItem::new(1, "high one")).await?;
+        let (_item_id, item) = mailbox.pop("42").await?.expect("Item pending");
+        assert_eq!(item.data, "high one");
+
+        clock.advance(minute);
+        mailbox.send("42", PriorityItem::new(1, "high two")).await?;
+        // boosted items keep their order, and stay with their priority in the stats
+        assert_eq!(mailbox.stats("42").await?.priorities[&0].pending, 2);
+        let received: Vec<String> = mailbox
+            .receive_many("42", 3)
+            .await?
+            .into_iter()
+            .map(|(_item_id, item)| item.data)
+            .collect();
+        assert_eq!(received, vec!["low one", "low two", "high two"]);
+
+        Ok(())
+    }
+
+    #[test(tokio::test(flavor = "multi_thread", worker_threads = 4))]
+    async fn it_lets_exactly_one_claim_a_mailbox() -> Result<()> {
+        let dir = TempDir::new()?;
+        let instances: Vec<Arc<MailboxDisk<TestItem>>> = (0..2)
+            .map(|_| Arc::new(MailboxDisk::at(dir.path(), Path::new("test_item"))))
+            .collect();
+
+        for job in 0..20 {
+            let mailbox_id = format!("job-{job}");
+            let claims: Vec<_> = instances
+                .iter()
+                .enumerate()
+                .map(|(owner, mailbox)| {
+                    let mailbox = mailbox.clone();
+                    let mailbox_id = mailbox_id.clone();
+                    tokio::spawn(async move {
+                        mailbox
+                            .create_mailbox_exclusive(&mailbox_id, &format!("owner-{owner}"))
+                            .await
+                    })
+                })
+                .collect();
+            let mut won = Vec::new();
+            let mut lost = Vec::new();
+            for claim in claims {
+                match claim.await? {
+                    Ok(token) => won.push(token),
+                    Err(e) => lost.push(e),
+                }
+            }
+            assert_eq!(won.len(), 1);
+            assert!(matches!(
+                lost[0].downcast_ref::<MailboxError>(),
+                Some(MailboxError::AlreadyOwned { owner: Some(owner), .. }) if *owner == won[0].owner
+            ));
+        }
+
+        Ok(())
+    }
+
+    #[test(tokio::test(flavor = "multi_thread", worker_threads = 4))]
+    async fn it_lets_exactly_one_take_over_an_expired_ownership() -> Result<()> {
+        let dir = TempDir::new()?;
+        let clock = MockClock::default();
+        let minute = Duration::from_secs(60);
+        // Note: separate instances don't share a lock, like separate processes
+        let instances: Vec<Arc<MailboxDisk<TestItem>>> = (0..4)
+            .map(|_| {
+                Arc::new(
+                    MailboxDisk::at(dir.path(), Path::new("test_item"))
+                        .with_clock(clock.clone())
+                        .with_ownership_ttl(minute),
+                )
+            })
+            .collect();
+
+        let token = instances[0]
+            .create_mailbox_exclusive("job", "first")
+            .await?;
+        // the owner can renew an expired ownership, as long as nobody took over
+        clock.advance(2 * minute);
+        let mut token = instances[0].renew_ownership(&token).await?;
+        for round in 0..10 {
+            clock.advance(2 * minute);
+            let claims: Vec<_> = instances
+                .iter()
+                .enumerate()
+                .map(|(owner, mailbox)| {
+                    let mailbox = mailbox.clone();
+                    let owner = format!("owner-{round}-{owner}");
+                    tokio::spawn(
+                        async move { mailbox.create_mailbox_exclusive("job", &owner).await },
+                    )
+                })
+                .collect();
+            let mut won = Vec::new();
+            let mut lost = Vec::new();
+            for claim in claims {
+                match claim.await? {
+                    Ok(token) => won.push(token),
+                    Err(e) => lost.push(e),
+                }
+            }
+            assert_eq!(won.len(), 1, "{lost:?}");
+            for e in lost {
+                assert!(matches!(
+                    e.downcast_ref::<MailboxError>(),
+                    Some(MailboxError::AlreadyOwned { owner: Some(owner), .. }) if *owner == won[0].owner
+                ));
+            }
+            // the previous owner is too late to renew
+            assert!(instances[0].renew_ownership(&token).await.is_err());
+            token = won.remove(0);
+        }
+        assert!(instances[0].check("job", false).await?.is_healthy());
+
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn it_takes_over_expired_ownerships() -> Result<()> {
+        let dir = TempDir::new()?;
+        let clock = MockClock::default();
+        let minute = Duration::from_secs(60);
+        let instance = || {
+            MailboxDisk::<TestItem>::at(dir.path(), Path::new("test_item"))
+                .with_clock(clock.clone())
+                .with_ownership_ttl(minute)
+                .with_ownership_checks()
+        };
+        let (a, b) = (instance(), instance());
+        let not_owner = |e: color_eyre::Report| {
+            matches!(
+                e.downcast_ref::<MailboxError>(),
+                Some(MailboxError::NotOwner { .. })
+            )
+        };
+
+        let token_a = a.create_mailbox_exclusive("job", "a").await?;
+        assert!(b.create_mailbox_exclusive("job", "b").await.is_err());
+        a.send("job", TestItem::new("from a".into())).await?;
+        assert!(not_owner(b.receive("job").await.unwrap_err()));
+
+        // renewing keeps the mailbox, letting it expire doesn't
+        clock.advance(minute / 2);
+        let token_a = a.renew_ownership(&token_a).await?;
+        clock.advance(minute / 2);
+        assert!(b.create_mailbox_exclusive("job", "b").await.is_err());
+        clock.advance(minute);
+        let token_b = b.create_mailbox_exclusive("job", "b").await?;
+
+        assert!(not_owner(
+            a.send("job", TestItem::new("from a".into()))
+                .await
+                .unwrap_err()
+        ));
+        assert!(not_owner(
+            a.release_mailbox("job", &token_a).await.unwrap_err()
+        ));
+        let (_item_id, item) = b.receive("job").await?.expect("Item pending");
+        assert_eq!(item.data, "from a");
+        b.release_mailbox("job", &token_b).await?;
+        a.send("job", TestItem::new("anybody".into())).await?;
+
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn it_round_trips_text_dumps() -> Result<()> {
+        let dir = TempDir::new()?;
+        let mailbox = MailboxDisk::<KindItem>::at(dir.path(), Path::new("test_item"));
+        for (kind, data) in [("a", "one"), ("b", "two \"quoted\""), ("a", "three")] {
+            let item = KindItem {
+                kind: kind.into(),
+                data: data.into(),
+            };
+            mailbox.send("42", item).await?;
+        }
+        let (item_id, _item) = mailbox.receive("42").await?.expect("Item pending");
+        mailbox.acknowledge("42", &item_id).await?;
+        // delivered, but not acknowledged
+        mailbox.receive("42").await?.expect("Item pending");
+
+        let text = mailbox.dump_text("42").await?;
+        assert_eq!(text.lines().count(), 5);
+        assert!(text
+            .lines()
+            .nth(2)
+            .is_some_and(|l| l.starts_with("item 1 read,delivered=1,")));
+
+        let not_empty = mailbox.load_text("42", &text, false).await.unwrap_err();
+        assert!(matches!(
+            not_empty.downcast_ref::<MailboxError>(),
+            Some(MailboxError::MailboxNotEmpty { .. })
+        ));
+        mailbox.load_text("copy", &text, false).await?;
+        assert_eq!(mailbox.dump_text("copy").await?, text);
+
+        // wiping, and loading again, leaves the same behavior
+        std::fs::remove_dir_all(dir.path().join("42"))?;
+        mailbox.load_text("42", &text, false).await?;
+        for mailbox_id in ["42", "copy"] {
+            let mut received = Vec::new();
+            while let Some((item_id, item)) = mailbox.receive(mailbox_id).await? {
+                mailbox.acknowledge(mailbox_id, &item_id).await?;
+                let count = mailbox.delivery_count(mailbox_id, &item_id).await?;
+                received.push((item_id, item.kind, item.data, count));
+            }
+            assert_eq!(
+                received,
+                vec![
+                    ("2".into(), "b".into(), "two \"quoted\"".into(), 2),
+                    ("3".into(), "a".into(), "three".into(), 1),
+                ]
+            );
+        }
+
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn it_delivers_a_single_item_from_a_fixture() -> Result<()> {
+        let dir = TempDir::new()?;
+        let mailbox = MailboxDisk::<TestItem>::at(dir.path(), Path::new("test_item"));
+        let fixture = r#"
+            meta {"highest_used_id":1,"lowest_unread_id":1,"read_ids":[],"total_sent":1,"total_acknowledged":0}
+            item 1 - {} "{\"data\":\"only\"}"
+        "#;
+        mailbox.load_text("42", fixture, false).await?;
+
+        let (item_id, item) = mailbox.receive("42").await?.expect("Item pending");
+        assert_eq!((item_id.as_str(), item.data.as_str()), ("1", "only"));
+        mailbox.acknowledge("42", &item_id).await?;
+        assert!(mailbox.receive("42").await?.is_none());
+
+        // a cursor past the items is refused
+        let broken = fixture.replace(r#""lowest_unread_id":1"#, r#""lowest_unread_id":3"#);
+        assert!(mailbox.load_text("broken", &broken, false).await.is_err());
+        assert!(!dir.path().join("broken").exists());
+
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn it_cancels_sends_cooperatively() -> Result<()> {
+        use crate::Backpressure;
+        use tokio::io::AsyncWriteExt;
+        use tokio_util::sync::CancellationToken;
+
+        let dir = TempDir::new()?;
+        let mailbox = MailboxDisk::<TestItem>::at(dir.path(), Path::new("test_item"))
+            .with_capacity(1, Backpressure::Wait { timeout: None });
+        let cancel_soon = || {
+            let cancel = CancellationToken::new();
+            let canceller = cancel.clone();
+            tokio::spawn(async move {
+                tokio::time::sleep(Duration::from_millis(20)).await;
+                canceller.cancel();
+            });
+            cancel
+        };
+        let is_cancelled = |e: color_eyre::Report| {
+            matches!(
+                e.downcast_ref::<MailboxError>(),
+                Some(MailboxError::Cancelled { .. })
+            )
+        };
+
+        mailbox.send("42", TestItem::new("one".into())).await?;
+        let cancel = cancel_soon();
+        let full = mailbox.send_with_cancel("42", TestItem::new("two".into()), &cancel);
+        assert!(is_cancelled(full.await.unwrap_err()));
+        let cancelled = CancellationToken::new();
+        cancelled.cancel();
+        let items = vec![TestItem::new("three".into())];
+        let r = mailbox.send_transaction_with_cancel("43", items, &cancelled);
+        assert!(is_cancelled(r.await.unwrap_err()));
+        assert!(mailbox.check("42", false).await?.is_healthy());
+        assert_eq!(mailbox.stats("42").await?.pending, 1);
+        assert!(!dir.path().join("43").exists());
+
+        // streams that never end leave nothing behind, whether cancelled or dropped
+        // Note: the undo runs after the file system calls already started, so ask the mailbox first
+        let staged = || -> Result<usize> {
+            let entries = fs::read_dir(dir.path().join("44"))?;
+            Ok(entries
+                .filter(|e| {
+                    e.as_ref()
+                        .is_ok_and(|e| e.path().to_string_lossy().ends_with(".tmp"))
+                })
+                .count())
+        };
+        let (mut writer, reader) = tokio::io::duplex(64);
+        writer.write_all(b"partial").await?;
+        let cancel = cancel_soon();
+        let stream = mailbox.send_stream_with_cancel("44", reader, None, &cancel);
+        assert!(is_cancelled(stream.await.unwrap_err()));
+        assert_eq!(mailbox.stats("44").await?.pending, 0);
+        assert_eq!(staged()?, 0);
+        let (mut writer, reader) = tokio::io::duplex(64);
+        writer.write_all(b"partial").await?;
+        let stream = mailbox.send_stream("44", reader, None);
+        assert!(tokio::time::timeout(Duration::from_millis(20), stream)
+            .await
+            .is_err());
+        assert_eq!(mailbox.stats("44").await?.pending, 0);
+        assert_eq!(staged()?, 0);
+
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn it_records_where_items_came_from() -> Result<()> {
+        use tokio_stream::StreamExt;
+
+        let dir = TempDir::new()?;
+        let mailbox = MailboxDisk::<TestItem>::at(dir.path(), Path::new("test_item"))
+            .with_defer_limit(1, Some("dead"));
+        let item_id = mailbox.send("42", TestItem::new("a".into())).await?;
+        assert!(mailbox.provenance("42", &item_id).await?.is_empty());
+        let item_id = mailbox.defer("42", &item_id).await?;
+        let dead_id = mailbox.defer("42", &item_id).await?;
+
+        let hops: Vec<_> = mailbox
+            .provenance("dead", &dead_id)
+            .await?
+            .into_iter()
+            .map(|p| (p.action, p.from_mailbox, p.from_item_id))
+            .collect();
+        assert_eq!(
+            hops,
+            vec![
+                (
+                    ProvenanceAction::Deferred,
+                    "42".to_string(),
+                    "1".to_string()
+                ),
+                (
+                    ProvenanceAction::DeadLettered,
+                    "42".to_string(),
+                    "2".to_string()
+                ),
+            ]
+        );
+        let scanned: Vec<_> = mailbox.scan("dead").collect().await;
+        let chain = mailbox.provenance("dead", &dead_id).await?;
+        assert_eq!(scanned.len(), 1);
+        assert_eq!(scanned.into_iter().next().unwrap()?.provenance, chain);
+
+        // survives a dump, and load
+        let text = mailbox.dump_text("dead").await?;
+        mailbox.load_text("copy", &text, false).await?;
+        assert_eq!(mailbox.provenance("copy", &dead_id).await?, chain);
+        assert_eq!(mailbox.dump_text("copy").await?, text);
+
+        // endless defers only keep the latest hops
+        let unlimited = MailboxDisk::<TestItem>::at(dir.path(), Path::new("test_item"));
+        let mut item_id = unlimited.send("43", TestItem::new("b".into())).await?;
+        for _ in 0..MAX_PROVENANCE + 5 {
+            item_id = unlimited.defer("43", &item_id).await?;
+        }
+        let chain = unlimited.provenance("43", &item_id).await?;
+        assert_eq!(chain.len(), MAX_PROVENANCE);
+        assert_eq!(chain[0].from_item_id, "6");
+
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn it_enforces_quotas_across_the_mailboxes_of_a_namespace() -> Result<()> {
+        let dir = TempDir::new()?;
+        let quota = QuotaManager::by_first_segment().with_max_items(6);
+        let mailbox =
+            MailboxDisk::<TestItem>::at(dir.path(), Path::new("test_item")).with_quota(quota);
+        for n in 0..6 {
+            let mailbox_id = ["user-1/a", "user-1/b", "user-1/c"][n % 3];
+            mailbox
+                .send(mailbox_id, TestItem::new(format!("{n}")))
+                .await?;
+        }
+        let e = mailbox
+            .send("user-1/a", TestItem::new("full".into()))
+            .await
+            .expect_err("Quota reached");
+        assert!(matches!(
+            e.downcast_ref::<MailboxError>(),
+            Some(MailboxError::QuotaExceeded { namespace, which: QuotaLimit::Items })
+                if namespace == "user-1"
+        ));
+        assert!(mailbox
+            .send_transaction("user-1/b", vec![TestItem::new("full".into())])
+            .await
+            .is_err());
+        // other namespaces have their own quota
+        mailbox
+            .send("user-2/a", TestItem::new("other".into()))
+            .await?;
+
+        // acknowledging alone frees nothing, compacting does
+        let (item_id, _item) = mailbox.receive("user-1/a").await?.expect("Item pending");
+        mailbox.acknowledge("user-1/a", &item_id).await?;
+        assert!(mailbox
+            .send("user-1/a", TestItem::new("full".into()))
+            .await
+            .is_err());
+        mailbox.compact("user-1/a").await?;
+        mailbox
+            .send("user-1/a", TestItem::new("fits".into()))
+            .await?;
+        assert!(mailbox
+            .send("user-1/a", TestItem::new("full".into()))
+            .await
+            .is_err());
+
+        mailbox.purge_prefix("user-1/c").await?.into_result()?;
+        let usage = mailbox.quota_usage("user-1").await?;
+        assert_eq!(usage.items, 4);
+        mailbox
+            .send("user-1/b", TestItem::new("resumed".into()))
+            .await?;
+        let usage = mailbox.quota_usage("user-1").await?;
+        assert_eq!(mailbox.recount("user-1").await?, usage);
+        assert_eq!(mailbox.recount("user-2").await?.items, 1);
+
+        // drift is repaired by recounting
+        std::fs::remove_dir_all(dir.path().join("user-1/b"))?;
+        assert_eq!(mailbox.quota_usage("user-1").await?, usage);
+        let recounted = mailbox.recount("user-1").await?;
+        assert_eq!(recounted.items, 2);
+        assert!(recounted.bytes < usage.bytes);
+        assert_eq!(mailbox.quota_usage("user-1").await?, recounted);
+
+        Ok(())
+    }
+
+    /// Sends, receives, acknowledges and compacts, so most kinds of files are written
+    async fn run_layout_scenario<S: MailboxStorage + Clone>(
+        storage: S,
+        base_path: &Path,
+    ) -> Result<()> {
+        let at = DateTime::parse_from_rfc3339("2024-05-06T07:08:09Z")?.with_timezone(&Utc);
+        let clock = MockClock::new(at);
+        let mailbox = MailboxKv::<TestItem, S>::in_storage(
+            storage.clone(),
+            base_path,
+            Path::new("test_item"),
+        )
+        .with_clock(clock.clone())
+        .with_journal(10)
+        .with_sidecar_threshold(32)
+        .with_status_files()
+        .with_trash();
+        mailbox.send("42", TestItem::new("a".into())).await?;
+        let large = TestItem::new("a payload that goes into a sidecar".into());
+        mailbox.send("42", large).await?;
+        mailbox.send("42", TestItem::new("c".into())).await?;
+        clock.advance(Duration::from_secs(60));
+        let (item_id, _) = mailbox.receive("42").await?.expect("Item pending");
+        mailbox.acknowledge("42", &item_id).await?;
+        let (item_id, _) = mailbox.receive("42").await?.expect("Item pending");
+        mailbox.set_mailbox_attr("42", "owner", "test").await?;
+        mailbox.compact("42").await?;
+        mailbox.acknowledge("42", &item_id).await?;
+
+        let daily =
+            MailboxKv::<TestItem, S>::in_storage(storage, base_path, Path::new("test_item"))
+                .with_clock(clock.clone())
+                .with_layout(EnvelopeLayout::Daily)
+                .with_id_counter();
+        daily.send("7", TestItem::new("x".into())).await?;
+        clock.advance(Duration::from_secs(24 * 60 * 60));
+        daily.send("7", TestItem::new("y".into())).await?;
+        let (item_id, _) = daily.receive("7").await?.expect("Item pending");
+        daily.acknowledge("7", &item_id).await?;
+
+        Ok(())
+    }
+
+    /// Every file [run_layout_scenario] leaves behind, with its exact content
+    ///
+    /// The fixture was written by the [MailboxDisk] from before [MailboxStorage] existed.
+    /// Note: Changing it breaks mailboxes written by earlier versions, bump the [LayoutVersion] instead.
+    fn golden_layout() -> Result<BTreeMap<PathBuf, Vec<u8>>> {
+        let folder = Path::new(env!("CARGO_MANIFEST_DIR")).join("fixtures/golden_layout");
+        let mut files = BTreeMap::new();
+        files_below(&folder, &folder, &mut files)?;
+        Ok(files)
+    }
+
+    /// All files below `folder`, by their path relative to `base_path`
+    fn files_below(
+        base_path: &Path,
+        folder: &Path,
+        files: &mut BTreeMap<PathBuf, Vec<u8>>,
+    ) -> Result<()> {
+        for entry in fs::read_dir(folder)? {
+            let path = entry?.path();
+            if path.is_dir() {
+                files_below(base_path, &path, files)?;
+            } else {
+                files.insert(
+                    path.strip_prefix(base_path)?.to_path_buf(),
+                    fs::read(&path)?,
+                );
+            }
+        }
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn it_keeps_the_on_disk_layout() -> Result<()> {
+        let dir = TempDir::new()?;
+        run_layout_scenario(crate::FsStorage::new(), dir.path()).await?;
+
+        let mut files = BTreeMap::new();
+        files_below(dir.path(), dir.path(), &mut files)?;
+        let expected = golden_layout()?;
+        assert_eq!(
+            files.keys().collect::<Vec<_>>(),
+            expected.keys().collect::<Vec<_>>()
+        );
+        for (path, value) in expected {
+            assert_eq!(
+                String::from_utf8_lossy(&files[&path]),
+                String::from_utf8_lossy(&value),
+                "{path:?}"
+            );
+        }
+
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn it_lays_out_memory_storage_like_the_disk() -> Result<()> {
+        let storage = MemoryStorage::new();
+        let base_path = Path::new("/mailboxes");
+        run_layout_scenario(storage.clone(), base_path).await?;
+
+        let values: BTreeMap<PathBuf, Vec<u8>> = storage
+            .values()
+            .into_iter()
+            .map(|(key, value)| Ok((key.strip_prefix(base_path)?.to_path_buf(), value)))
+            .collect::<Result<_>>()?;
+        assert_eq!(values, golden_layout()?);
+
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn it_works_in_memory() -> Result<()> {
+        use crate::HealthStatus;
+
+        let storage = MemoryStorage::new();
+        let base_path = Path::new("/mailboxes");
+        let mut mailbox = MailboxKv::<TestItem, _>::in_storage(
+            storage.clone(),
+            base_path,
+            Path::new("test_item"),
+        );
+        mailbox.ensure_storage_exists().await?;
+        let report = mailbox.health_check().await?;
+        assert_eq!(report.status, HealthStatus::Healthy, "{}", report.message);
+        assert_eq!(report.free_space, None);
+
+        for i in 0..3 {
+            mailbox
+                .send("42", TestItem::new(format!("item {i}")))
+                .await?;
+        }
+        mailbox.send("7", TestItem::new("other".into())).await?;
+        let mut mailbox_ids = mailbox.list_mailboxes().await?;
+        mailbox_ids.sort();
+        assert_eq!(mailbox_ids, vec!["42".to_string(), "7".to_string()]);
+        assert_eq!(mailbox.stats("42").await?.pending, 3);
+
+        let (item_id, item) = mailbox.receive("42").await?.expect("Item pending");
+        assert_eq!(item.data, "item 0");
+        mailbox.acknowledge("42", &item_id).await?;
+        // Note: the storage can't be watched, so this polls
+        let batch = mailbox
+            .receive_batch("42", 5, Duration::from_millis(10))
+            .await?;
+        assert_eq!(batch.len(), 2);
+        for (item_id, _) in batch {
+            mailbox.acknowledge("42", &item_id).await?;
+        }
+        assert_eq!(mailbox.compact("42").await?, 3);
+        assert!(mailbox.check("42", false).await?.is_healthy());
+        assert_eq!(mailbox.stats("42").await?.pending, 0);
+
+        // Note: clones share the values, like two instances on the same folder
+        let other =
+            MailboxKv::<TestItem, _>::in_storage(storage, base_path, Path::new("test_item"));
+        let (_item_id, item) = other.receive("7").await?.expect("Item pending");
+        assert_eq!(item.data, "other");
 
         Ok(())
     }
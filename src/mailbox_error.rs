@@ -0,0 +1,225 @@
+use color_eyre::eyre::Report;
+
+/// Typed errors returned by the mailbox backends
+///
+/// The trait still returns a [color_eyre::eyre::Result],
+/// so to inspect the error you have to downcast it:
+/// ```
+/// # use color_eyre::eyre::Report;
+/// # use oml_mailbox::MailboxError;
+/// # fn handle(e: Report) {
+/// if let Some(MailboxError::NotFound { mailbox_id, item_id }) = e.downcast_ref::<MailboxError>() {
+///     // ...
+/// }
+/// # }
+/// ```
+#[derive(Debug, thiserror::Error)]
+#[non_exhaustive]
+pub enum MailboxError {
+    #[error("Invalid id {id:?}")]
+    InvalidId { id: String },
+    #[error("Item {item_id} not found in mailbox {mailbox_id}")]
+    NotFound { mailbox_id: String, item_id: String },
+    #[error("IO error -> {0}")]
+    Io(#[from] std::io::Error),
+    #[error("{op} on mailbox {mailbox_id} timed out")]
+    Timeout { op: String, mailbox_id: String },
+    #[error("{op} on mailbox {mailbox_id} rate limited, retry after {retry_after:?}")]
+    RateLimited {
+        op: String,
+        mailbox_id: String,
+        retry_after: std::time::Duration,
+    },
+    #[error("Item {item_id} already exists in mailbox {mailbox_id}, is the meta out of date?")]
+    IdCollision { mailbox_id: String, item_id: String },
+    #[error("Item {item_id} in mailbox {mailbox_id} has already been read")]
+    AlreadyRead { mailbox_id: String, item_id: String },
+    #[error("Mailbox {mailbox_id} does not exist")]
+    UnknownMailbox { mailbox_id: String },
+    #[error("Mailbox {mailbox_id} uses the {found:?} layout, not {expected:?}")]
+    LayoutMismatch {
+        mailbox_id: String,
+        expected: crate::EnvelopeLayout,
+        found: crate::EnvelopeLayout,
+    },
+    #[error("Mailbox {mailbox_id} uses {found:?} ids, not {expected:?}")]
+    IdSchemeMismatch {
+        mailbox_id: String,
+        expected: crate::IdScheme,
+        found: crate::IdScheme,
+    },
+    #[error("{op} is not possible on a read-only mailbox")]
+    ReadOnly { op: String },
+    #[error("Mailbox is closed")]
+    Closed,
+    #[error("Can't decrypt item {item_id} in mailbox {mailbox_id} -> {reason}")]
+    Decryption {
+        mailbox_id: String,
+        item_id: String,
+        reason: String,
+    },
+    #[error("Can't convert item {item_id} in mailbox {mailbox_id} -> {reason}")]
+    Conversion {
+        mailbox_id: String,
+        item_id: String,
+        reason: String,
+    },
+    #[error("{op} is not supported -> {reason}")]
+    Unsupported { op: String, reason: String },
+    #[error("Attributes of mailbox {mailbox_id} would take {size} bytes, more than {limit}")]
+    AttributesTooLarge {
+        mailbox_id: String,
+        size: usize,
+        limit: usize,
+    },
+    #[error("Item {item_id} in mailbox {mailbox_id} was deferred {limit} times already")]
+    DeferLimitReached {
+        mailbox_id: String,
+        item_id: String,
+        limit: u32,
+    },
+    #[error("Mailbox {mailbox_id} is full, it holds at most {capacity} pending items")]
+    MailboxFull { mailbox_id: String, capacity: u64 },
+    #[error("Signature of item {item_id} in mailbox {mailbox_id} doesn't match -> {reason}")]
+    SignatureMismatch {
+        mailbox_id: String,
+        item_id: String,
+        reason: String,
+    },
+    #[error("Storage {path:?} does not exist")]
+    StorageNotFound { path: std::path::PathBuf },
+    #[error("Storage {path:?} is not a directory")]
+    StorageNotADirectory { path: std::path::PathBuf },
+    #[error("Storage {path:?} is not writable -> {reason}")]
+    StorageNotWritable {
+        path: std::path::PathBuf,
+        reason: String,
+    },
+    #[error("Invalid item extension {extension:?} -> {reason}")]
+    InvalidExtension { extension: String, reason: String },
+    #[error("Storage {path:?} has only {available} bytes free, sends need {min_free_space}")]
+    StorageFull {
+        path: std::path::PathBuf,
+        available: u64,
+        min_free_space: u64,
+    },
+    #[error("Waiting for the lock timed out after {timeout:?}, is another operation stuck?")]
+    LockTimeout { timeout: std::time::Duration },
+    #[error("Item for mailbox {mailbox_id} takes {size} bytes, more than {limit}")]
+    ItemTooLarge {
+        mailbox_id: String,
+        size: u64,
+        limit: u64,
+    },
+    #[error("Mailbox {mailbox_id} is frozen with {mode:?}")]
+    Frozen {
+        mailbox_id: String,
+        mode: crate::FreezeMode,
+    },
+    #[error("Mailbox {mailbox_id} uses layout {found:?}, can't downgrade to {target:?}")]
+    LayoutDowngrade {
+        mailbox_id: String,
+        found: crate::LayoutVersion,
+        target: crate::LayoutVersion,
+    },
+    /// `owner` is `None` for a mailbox that exists without an owner, e.g. after it was released
+    #[error("Mailbox {mailbox_id} already exists, owned by {owner:?}")]
+    AlreadyOwned {
+        mailbox_id: String,
+        owner: Option<String>,
+    },
+    #[error("Mailbox {mailbox_id} is not owned with this token")]
+    NotOwner { mailbox_id: String },
+    #[error("Mailbox {mailbox_id} is not empty")]
+    MailboxNotEmpty { mailbox_id: String },
+    /// Nothing was changed, the operation was cancelled before it started to commit
+    #[error("{op} on mailbox {mailbox_id} was cancelled")]
+    Cancelled { op: String, mailbox_id: String },
+    /// See [crate::MailboxDisk::with_quota]
+    #[error("Quota of namespace {namespace} exceeded -> {which:?}")]
+    QuotaExceeded {
+        namespace: String,
+        which: crate::QuotaLimit,
+    },
+    /// See [crate::MailboxDisk::force_restore_meta]
+    #[error(
+        "Can't restore mailbox {mailbox_id}, items {item_ids:?} from the snapshot are missing"
+    )]
+    SnapshotItemsMissing {
+        mailbox_id: String,
+        item_ids: Vec<String>,
+    },
+}
+
+impl MailboxError {
+    /// If trying the same operation again might succeed
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            MailboxError::InvalidId { .. } => false,
+            MailboxError::NotFound { .. } => false,
+            MailboxError::Io(e) => io_error_is_retryable(e),
+            MailboxError::Timeout { .. } => true,
+            MailboxError::RateLimited { .. } => true,
+            MailboxError::IdCollision { .. } => false,
+            MailboxError::AlreadyRead { .. } => false,
+            MailboxError::UnknownMailbox { .. } => false,
+            MailboxError::LayoutMismatch { .. } => false,
+            MailboxError::IdSchemeMismatch { .. } => false,
+            MailboxError::ReadOnly { .. } => false,
+            MailboxError::Closed => false,
+            MailboxError::Decryption { .. } => false,
+            MailboxError::Conversion { .. } => false,
+            MailboxError::Unsupported { .. } => false,
+            MailboxError::AttributesTooLarge { .. } => false,
+            MailboxError::DeferLimitReached { .. } => false,
+            MailboxError::MailboxFull { .. } => true,
+            MailboxError::SignatureMismatch { .. } => false,
+            MailboxError::StorageNotFound { .. } => false,
+            MailboxError::StorageNotADirectory { .. } => false,
+            MailboxError::StorageNotWritable { .. } => false,
+            MailboxError::InvalidExtension { .. } => false,
+            MailboxError::StorageFull { .. } => true,
+            MailboxError::LockTimeout { .. } => true,
+            MailboxError::ItemTooLarge { .. } => false,
+            MailboxError::LayoutDowngrade { .. } => false,
+            // Note: freezes are meant to be lifted again
+            MailboxError::Frozen { .. } => true,
+            MailboxError::AlreadyOwned { .. } => false,
+            MailboxError::NotOwner { .. } => false,
+            MailboxError::MailboxNotEmpty { .. } => false,
+            // Note: the caller asked for it, retrying would ignore that
+            MailboxError::Cancelled { .. } => false,
+            MailboxError::QuotaExceeded { .. } => true,
+            MailboxError::SnapshotItemsMissing { .. } => false,
+        }
+    }
+
+    /// Classifies any error returned by a [crate::Mailbox]
+    ///
+    /// Looks through the whole chain for a [MailboxError], or a [std::io::Error].
+    /// Everything else is considered permanent.
+    pub fn is_retryable_report(report: &Report) -> bool {
+        for e in report.chain() {
+            if let Some(e) = e.downcast_ref::<MailboxError>() {
+                return e.is_retryable();
+            }
+            if let Some(e) = e.downcast_ref::<std::io::Error>() {
+                return io_error_is_retryable(e);
+            }
+        }
+        false
+    }
+}
+
+fn io_error_is_retryable(e: &std::io::Error) -> bool {
+    use std::io::ErrorKind;
+    !matches!(
+        e.kind(),
+        ErrorKind::NotFound
+            | ErrorKind::PermissionDenied
+            | ErrorKind::AlreadyExists
+            | ErrorKind::InvalidInput
+            | ErrorKind::InvalidData
+            | ErrorKind::Unsupported
+    )
+}
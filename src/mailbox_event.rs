@@ -0,0 +1,102 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use tokio::sync::broadcast;
+
+/// How many events a subscriber can fall behind, before it misses the oldest ones
+pub const DEFAULT_EVENT_CAPACITY: usize = 1024;
+
+/// A state change of a mailbox, see [crate::MailboxDisk::subscribe_events] and [crate::EventedMailbox]
+///
+/// Events are sent once the change is persisted, in the order the changes happened.
+/// A subscriber falling more than the capacity behind gets [broadcast::error::RecvError::Lagged],
+/// and misses the oldest events.
+/// The emptiness edges are computed from the pending items after every change,
+/// not from the edges sent before, so the next edge of a mailbox is right again after lagging.
+/// Call [crate::Mailbox::stats] to catch up right away.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum MailboxEvent {
+    ItemSent {
+        mailbox_id: String,
+        item_id: String,
+    },
+    /// Only for the default consumer group, and only the last id for `acknowledge_through`
+    ItemAcknowledged {
+        mailbox_id: String,
+        item_id: String,
+    },
+    MailboxBecameEmpty {
+        mailbox_id: String,
+    },
+    MailboxBecameNonEmpty {
+        mailbox_id: String,
+    },
+    /// Moved to `dead_letter` by [crate::MailboxDisk::defer], as `new_item_id`
+    ItemDeadLettered {
+        mailbox_id: String,
+        item_id: String,
+        dead_letter: String,
+        new_item_id: String,
+    },
+}
+
+/// The sending side of [MailboxEvent]s, it never blocks, and never fails
+#[derive(Debug)]
+pub(crate) struct EventBus {
+    sender: broadcast::Sender<MailboxEvent>,
+    /// If the last edge sent for a mailbox was [MailboxEvent::MailboxBecameEmpty]
+    empty: Mutex<HashMap<String, bool>>,
+}
+
+impl EventBus {
+    pub(crate) fn new(capacity: usize) -> Self {
+        let (sender, _receiver) = broadcast::channel(capacity.max(1));
+        Self {
+            sender,
+            empty: Mutex::default(),
+        }
+    }
+
+    pub(crate) fn subscribe(&self) -> broadcast::Receiver<MailboxEvent> {
+        self.sender.subscribe()
+    }
+
+    /// If anybody listens, so callers can skip collecting events
+    pub(crate) fn is_observed(&self) -> bool {
+        self.sender.receiver_count() > 0
+    }
+
+    /// Sends `events`, followed by an edge if the mailbox became empty, or non-empty
+    ///
+    /// Note: Mailboxes not seen yet count as empty.
+    pub(crate) fn publish(
+        &self,
+        mailbox_id: &str,
+        events: Vec<MailboxEvent>,
+        pending: Option<u64>,
+    ) {
+        for event in events {
+            // Note: fails only without receivers
+            let _ = self.sender.send(event);
+        }
+        let Some(pending) = pending else {
+            return;
+        };
+        let empty = pending == 0;
+        let was_empty = self
+            .empty
+            .lock()
+            .unwrap()
+            .insert(mailbox_id.to_string(), empty)
+            .unwrap_or(true);
+        if was_empty == empty {
+            return;
+        }
+        let mailbox_id = mailbox_id.to_string();
+        let _ = self.sender.send(if empty {
+            MailboxEvent::MailboxBecameEmpty { mailbox_id }
+        } else {
+            MailboxEvent::MailboxBecameNonEmpty { mailbox_id }
+        });
+    }
+}
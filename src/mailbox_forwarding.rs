@@ -0,0 +1,278 @@
+use crate::BulkResult;
+use crate::ExpireReport;
+use crate::HeaderSelector;
+use crate::HealthReport;
+use crate::ItemSummary;
+use crate::Mailbox;
+use crate::MailboxError;
+use crate::MailboxItem;
+use crate::MailboxStats;
+use crate::Page;
+use crate::ReceiveAnyTurn;
+use crate::ReceiveStatus;
+use async_trait::async_trait;
+use color_eyre::eyre::Result;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Access for `ensure_storage_exists` and `close`, the only methods taking `&mut self`
+trait MutAccess<M: ?Sized> {
+    fn mut_access(&mut self, op: &str) -> Result<&mut M>;
+}
+
+impl<M: ?Sized> MutAccess<M> for Box<M> {
+    fn mut_access(&mut self, _op: &str) -> Result<&mut M> {
+        Ok(self)
+    }
+}
+
+impl<M: ?Sized> MutAccess<M> for Arc<M> {
+    fn mut_access(&mut self, op: &str) -> Result<&mut M> {
+        Arc::get_mut(self).ok_or_else(|| {
+            MailboxError::Unsupported {
+                op: op.to_string(),
+                reason: "the Arc is shared, call it before cloning the Arc".to_string(),
+            }
+            .into()
+        })
+    }
+}
+
+impl<M: ?Sized> MutAccess<M> for &M {
+    fn mut_access(&mut self, op: &str) -> Result<&mut M> {
+        Err(MailboxError::Unsupported {
+            op: op.to_string(),
+            reason: "the mailbox is only borrowed".to_string(),
+        }
+        .into())
+    }
+}
+
+/// Implements [Mailbox] for a pointer to a mailbox, by forwarding every method
+///
+/// `ensure_storage_exists` and `close` need the mailbox itself, so they fail with [MailboxError::Unsupported]
+/// through an `Arc` that is shared, and through `&M`.
+macro_rules! forward_mailbox {
+    ([$($generics:tt)*] $pointer:ty) => {
+        #[async_trait]
+        impl<$($generics)*> Mailbox<ITEM> for $pointer {
+            async fn ensure_storage_exists(&mut self) -> Result<()> {
+                self.mut_access("ensure_storage_exists")?
+                    .ensure_storage_exists()
+                    .await
+            }
+            async fn close(&mut self) -> Result<()> {
+                self.mut_access("close")?.close().await
+            }
+
+            async fn create_mailbox(&self, id: &str) -> Result<()> {
+                (**self).create_mailbox(id).await
+            }
+            async fn send(&self, id: &str, item: ITEM) -> Result<String> {
+                (**self).send(id, item).await
+            }
+            async fn send_transaction(&self, id: &str, items: Vec<ITEM>) -> Result<Vec<String>> {
+                (**self).send_transaction(id, items).await
+            }
+            async fn send_many(&self, id: &str, items: Vec<ITEM>) -> Result<BulkResult<String>>
+            where
+                ITEM: 'static,
+            {
+                (**self).send_many(id, items).await
+            }
+            async fn receive(&self, id: &str) -> Result<Option<(String, ITEM)>> {
+                (**self).receive(id).await
+            }
+            async fn acknowledge(&self, id: &str, item_id: &str) -> Result<()> {
+                (**self).acknowledge(id, item_id).await
+            }
+            async fn send_raw(&self, id: &str, data: &[u8]) -> Result<String> {
+                (**self).send_raw(id, data).await
+            }
+            async fn receive_raw(&self, id: &str) -> Result<Option<(String, Vec<u8>)>> {
+                (**self).receive_raw(id).await
+            }
+            async fn acknowledge_through(&self, id: &str, item_id: &str) -> Result<u64> {
+                (**self).acknowledge_through(id, item_id).await
+            }
+            async fn release(&self, id: &str, item_id: &str) -> Result<()> {
+                (**self).release(id, item_id).await
+            }
+            fn release_later(&self, id: &str, item_id: &str) {
+                (**self).release_later(id, item_id)
+            }
+            async fn update(&self, id: &str, item_id: &str, item: ITEM) -> Result<()> {
+                (**self).update(id, item_id, item).await
+            }
+            async fn peek(&self, id: &str) -> Result<Option<(String, ITEM)>> {
+                (**self).peek(id).await
+            }
+            async fn receive_many(&self, id: &str, max: usize) -> Result<Vec<(String, ITEM)>> {
+                (**self).receive_many(id, max).await
+            }
+            async fn receive_status(&self, id: &str) -> Result<ReceiveStatus<ITEM>> {
+                (**self).receive_status(id).await
+            }
+            async fn receive_any(&self, ids: &[String]) -> Result<Option<(String, String, ITEM)>> {
+                (**self).receive_any(ids).await
+            }
+            fn receive_any_turn(&self) -> Option<&ReceiveAnyTurn> {
+                (**self).receive_any_turn()
+            }
+            async fn receive_batch(
+                &self,
+                id: &str,
+                max_items: usize,
+                max_wait: Duration,
+            ) -> Result<Vec<(String, ITEM)>> {
+                (**self).receive_batch(id, max_items, max_wait).await
+            }
+            async fn receive_matching(
+                &self,
+                id: &str,
+                selector: &HeaderSelector,
+            ) -> Result<Option<(String, ITEM)>> {
+                (**self).receive_matching(id, selector).await
+            }
+            async fn list_mailboxes(&self) -> Result<Vec<String>> {
+                (**self).list_mailboxes().await
+            }
+            async fn list_mailboxes_page(
+                &self,
+                after: Option<&str>,
+                limit: usize,
+            ) -> Result<Page<String>> {
+                (**self).list_mailboxes_page(after, limit).await
+            }
+            async fn list_items_page(
+                &self,
+                id: &str,
+                after: Option<&str>,
+                limit: usize,
+            ) -> Result<Page<ItemSummary>> {
+                (**self).list_items_page(id, after, limit).await
+            }
+            async fn delivery_count(&self, id: &str, item_id: &str) -> Result<u32> {
+                (**self).delivery_count(id, item_id).await
+            }
+            async fn item_size(&self, id: &str, item_id: &str) -> Result<u64> {
+                (**self).item_size(id, item_id).await
+            }
+            async fn stats(&self, id: &str) -> Result<MailboxStats> {
+                (**self).stats(id).await
+            }
+            async fn receive_for(&self, id: &str, group: &str) -> Result<Option<(String, ITEM)>> {
+                (**self).receive_for(id, group).await
+            }
+            async fn acknowledge_for(&self, id: &str, group: &str, item_id: &str) -> Result<()> {
+                (**self).acknowledge_for(id, group, item_id).await
+            }
+            async fn compact(&self, id: &str) -> Result<u64> {
+                (**self).compact(id).await
+            }
+            async fn drop_older_than(&self, id: &str, max_age: Duration) -> Result<u64> {
+                (**self).drop_older_than(id, max_age).await
+            }
+            async fn expire_items(&self, id: &str) -> Result<ExpireReport> {
+                (**self).expire_items(id).await
+            }
+            async fn health_check(&self) -> Result<HealthReport> {
+                (**self).health_check().await
+            }
+            async fn pop(&self, id: &str) -> Result<Option<(String, ITEM)>> {
+                (**self).pop(id).await
+            }
+            async fn drain(&self, id: &str, max: Option<usize>) -> Result<Vec<(String, ITEM)>>
+            where
+                ITEM: 'static,
+            {
+                (**self).drain(id, max).await
+            }
+        }
+    };
+}
+
+forward_mailbox!([ITEM: MailboxItem + Send + 'static, M: Mailbox<ITEM> + ?Sized] Box<M>);
+forward_mailbox!([ITEM: MailboxItem + Send + 'static, M: Mailbox<ITEM> + ?Sized] Arc<M>);
+forward_mailbox!(['a, ITEM: MailboxItem + Send + 'static, M: Mailbox<ITEM> + ?Sized] &'a M);
+// Note: No impl for `&mut M`, it would be picked for method calls on `&mut self` inside the backends,
+// and `&M` already covers everything but `ensure_storage_exists` and `close`.
+
+#[cfg(test)]
+mod tests {
+    use crate::Mailbox;
+    use crate::MailboxDisk;
+    use crate::MailboxError;
+    use crate::MailboxItem;
+    use color_eyre::Result;
+    use std::path::Path;
+    use std::sync::Arc;
+    use tempfile::TempDir;
+
+    use test_log::test;
+
+    #[derive(Debug, Default)]
+    struct TestItem {
+        data: String,
+    }
+
+    impl MailboxItem for TestItem {
+        fn serialize(&self) -> Result<Vec<u8>> {
+            Ok(self.data.clone().into())
+        }
+        fn deserialize(data: &[u8]) -> Result<Self>
+        where
+            Self: Sized,
+        {
+            Ok(Self {
+                data: String::from_utf8(data.to_vec())?,
+            })
+        }
+    }
+
+    async fn send_some(mailbox: impl Mailbox<TestItem>, prefix: &str) -> Result<()> {
+        for i in 0..5 {
+            let data = format!("{prefix}{i}");
+            mailbox.send("42", TestItem { data }).await?;
+        }
+        Ok(())
+    }
+
+    async fn pop_all(mailbox: impl Mailbox<TestItem>) -> Result<Vec<String>> {
+        let mut received = Vec::new();
+        while let Some((item_id, item)) = mailbox.receive("42").await? {
+            mailbox.acknowledge("42", &item_id).await?;
+            received.push(item.data);
+        }
+        Ok(received)
+    }
+
+    #[test(tokio::test)]
+    async fn it_forwards_through_pointers() -> Result<()> {
+        let dir = TempDir::new()?;
+        let mut disk = Arc::new(MailboxDisk::<TestItem>::at(dir.path(), Path::new("item")));
+        disk.ensure_storage_exists().await?;
+
+        let tasks = ["a", "b"].map(|prefix| tokio::spawn(send_some(disk.clone(), prefix)));
+        for task in tasks {
+            task.await??;
+        }
+        assert_eq!(disk.stats("42").await?.pending, 10);
+
+        let e = disk.clone().close().await.expect_err("Arc is shared");
+        assert!(matches!(
+            e.downcast_ref::<MailboxError>(),
+            Some(MailboxError::Unsupported { .. })
+        ));
+        let mut received = pop_all(&disk).await?;
+        received.sort();
+        assert_eq!(received.len(), 10);
+        assert_eq!(received[..2], ["a0", "a1"]);
+
+        let boxed: Box<dyn Mailbox<TestItem>> = Box::new(disk);
+        send_some(&boxed, "c").await?;
+        assert_eq!(pop_all(boxed).await?.len(), 5);
+
+        Ok(())
+    }
+}
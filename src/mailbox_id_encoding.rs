@@ -0,0 +1,90 @@
+use sha2::Digest;
+
+const ALPHABET: &[u8; 32] = b"abcdefghijklmnopqrstuvwxyz234567";
+/// Keeps folder names well below the common limit of 255 bytes
+const MAX_ENCODED_LEN: usize = 200;
+
+pub(crate) const ENCODED_PREFIX: &str = "~";
+const BASE32_PREFIX: &str = "~b";
+const HASH_PREFIX: &str = "~h";
+
+/// A file system safe folder name for any mailbox id, see [crate::MailboxDisk::with_encoded_ids]
+///
+/// Ids are encoded as lowercase base32, which survives case-insensitive file systems, prefixed with `~b`.
+/// Ids that would get too long are replaced by `~h` and their SHA-256,
+/// so for those the original id has to be kept elsewhere.
+pub(crate) fn encode(mailbox_id: &str) -> String {
+    let encoded = base32(mailbox_id.as_bytes());
+    if encoded.len() <= MAX_ENCODED_LEN {
+        return format!("{BASE32_PREFIX}{encoded}");
+    }
+    let hash: String = sha2::Sha256::digest(mailbox_id.as_bytes())
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect();
+    format!("{HASH_PREFIX}{hash}")
+}
+
+/// The id for a folder name from [encode], `None` for hashed ids
+pub(crate) fn decode(name: &str) -> Option<String> {
+    let encoded = name.strip_prefix(BASE32_PREFIX)?;
+    String::from_utf8(unbase32(encoded)?).ok()
+}
+
+fn base32(data: &[u8]) -> String {
+    let mut out = String::with_capacity(data.len().div_ceil(5) * 8);
+    let mut buffer = 0u32;
+    let mut bits = 0;
+    for &b in data {
+        buffer = (buffer << 8) | u32::from(b);
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            out.push(ALPHABET[((buffer >> bits) & 0x1f) as usize] as char);
+        }
+    }
+    if bits > 0 {
+        out.push(ALPHABET[((buffer << (5 - bits)) & 0x1f) as usize] as char);
+    }
+    out
+}
+
+fn unbase32(encoded: &str) -> Option<Vec<u8>> {
+    let mut out = Vec::with_capacity(encoded.len() * 5 / 8);
+    let mut buffer = 0u32;
+    let mut bits = 0;
+    for c in encoded.bytes() {
+        let value = ALPHABET.iter().position(|&a| a == c)? as u32;
+        buffer = (buffer << 5) | value;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((buffer >> bits) as u8);
+        }
+    }
+    Some(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::decode;
+    use super::encode;
+
+    #[test]
+    fn it_round_trips() {
+        for id in ["", "a", "Alice@example.com", "a/b:c*d", "ünïcødé 📬"] {
+            let encoded = encode(id);
+            assert!(encoded
+                .bytes()
+                .all(|b| b == b'~' || b.is_ascii_lowercase() || b.is_ascii_digit()));
+            assert_eq!(decode(&encoded).as_deref(), Some(id));
+        }
+        assert_ne!(encode("Alice"), encode("alice"));
+
+        let long = "x".repeat(300);
+        let encoded = encode(&long);
+        assert!(encoded.len() < 100);
+        assert_eq!(decode(&encoded), None);
+        assert_ne!(encoded, encode(&"x".repeat(301)));
+    }
+}
@@ -0,0 +1,22 @@
+use crate::FreezeMode;
+use std::ops::RangeInclusive;
+use std::path::PathBuf;
+
+/// The internals of a mailbox, as returned by [crate::MailboxDisk::inspect]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MailboxInspection {
+    pub highest_used_id: u64,
+    pub lowest_unread_id: u64,
+    /// All read ids, including the compacted ones
+    pub read_ranges: Vec<RangeInclusive<u64>>,
+    /// Number of envelopes in the mailbox folder, not counting the trash
+    pub envelope_files: u64,
+    /// Ids up to `highest_used_id` that are not compacted, but have no envelope
+    pub missing_items: Vec<String>,
+    /// Files in the mailbox folder that don't belong to any item of the meta
+    pub stray_files: Vec<PathBuf>,
+    /// Why the meta couldn't be loaded, everything else is based on an empty meta then
+    pub meta_error: Option<String>,
+    /// See [crate::MailboxDisk::set_frozen]
+    pub frozen: FreezeMode,
+}
@@ -1,5 +1,7 @@
 use async_trait::async_trait;
+use bytes::Bytes;
 use color_eyre::eyre::Result;
+use std::collections::BTreeMap;
 
 /// The `trait` your items need to implement to be sendable via a mailbox
 ///
@@ -36,4 +38,21 @@ pub trait MailboxItem: core::fmt::Debug + std::default::Default + std::marker::S
     fn deserialize(data: &[u8]) -> Result<Self>
     where
         Self: Sized;
+
+    /// Like [MailboxItem::deserialize], for backends that hand over a buffer they don't need anymore
+    ///
+    /// Override it to keep (parts of) the buffer, instead of copying out of it, see [crate::RawItem].
+    fn deserialize_from_bytes(data: Bytes) -> Result<Self>
+    where
+        Self: Sized,
+    {
+        Self::deserialize(&data)
+    }
+
+    /// Headers describing the item, stored next to its payload
+    ///
+    /// Backends match them without deserializing the item, see [crate::HeaderSelector].
+    fn headers(&self) -> BTreeMap<String, String> {
+        BTreeMap::new()
+    }
 }
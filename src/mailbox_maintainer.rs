@@ -0,0 +1,285 @@
+use crate::Mailbox;
+use crate::MailboxItem;
+use color_eyre::eyre::Result;
+use core::marker::PhantomData;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+
+/// What [MailboxMaintainer] does in every cycle
+#[derive(Debug, Clone)]
+pub struct MaintenanceConfig {
+    /// Time between the end of one cycle, and the start of the next one
+    pub interval: Duration,
+    /// Remove acknowledged items
+    pub compact: bool,
+    /// Skip unread items older than this, see [Mailbox::drop_older_than]
+    pub retention: Option<Duration>,
+    /// Drop unread items past their time to live, see [Mailbox::expire_items]
+    pub expire: bool,
+}
+
+impl Default for MaintenanceConfig {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_secs(60),
+            compact: true,
+            retention: None,
+            expire: true,
+        }
+    }
+}
+
+/// What happened in one maintenance cycle
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MaintenanceSummary {
+    pub mailboxes: usize,
+    pub dropped: u64,
+    pub expired: u64,
+    pub compacted: u64,
+    /// Mailboxes that failed, and were skipped
+    pub failed: usize,
+}
+
+/// Runs the configured maintenance on all mailboxes of a backend, periodically
+///
+/// ```no_run
+/// # use oml_mailbox::{MailboxMaintainer, MaintenanceConfig, MailboxMemory};
+/// # use std::sync::Arc;
+/// # #[derive(Debug, Default)]
+/// # struct TestItem;
+/// # impl oml_mailbox::MailboxItem for TestItem {
+/// #     fn serialize(&self) -> color_eyre::eyre::Result<Vec<u8>> { Ok(Vec::new()) }
+/// #     fn deserialize(_data: &[u8]) -> color_eyre::eyre::Result<Self> { Ok(TestItem) }
+/// # }
+/// # async fn run() {
+/// let mailbox = Arc::new(MailboxMemory::<TestItem>::new());
+/// let maintainer = MailboxMaintainer::new(mailbox.clone(), MaintenanceConfig::default());
+/// let task = maintainer.clone().spawn();
+/// // ...
+/// maintainer.shutdown();
+/// task.await.unwrap();
+/// # }
+/// ```
+#[derive(Debug)]
+pub struct MailboxMaintainer<ITEM: MailboxItem, M: Mailbox<ITEM>> {
+    mailbox: Arc<M>,
+    config: MaintenanceConfig,
+    shutdown: Arc<watch::Sender<bool>>,
+    item_type: PhantomData<ITEM>,
+}
+
+// Note: derive(Clone) would require ITEM and M to be Clone
+impl<ITEM: MailboxItem, M: Mailbox<ITEM>> Clone for MailboxMaintainer<ITEM, M> {
+    fn clone(&self) -> Self {
+        Self {
+            mailbox: self.mailbox.clone(),
+            config: self.config.clone(),
+            shutdown: self.shutdown.clone(),
+            item_type: PhantomData,
+        }
+    }
+}
+
+impl<ITEM: MailboxItem + Send + 'static, M: Mailbox<ITEM> + 'static> MailboxMaintainer<ITEM, M> {
+    pub fn new(mailbox: Arc<M>, config: MaintenanceConfig) -> Self {
+        let (shutdown, _) = watch::channel(false);
+        Self {
+            mailbox,
+            config,
+            shutdown: Arc::new(shutdown),
+            item_type: PhantomData,
+        }
+    }
+
+    /// Run cycles until [MailboxMaintainer::shutdown] is called
+    pub fn spawn(self) -> JoinHandle<()> {
+        tokio::spawn(async move {
+            let mut shutdown = self.shutdown.subscribe();
+            loop {
+                if *shutdown.borrow() {
+                    break;
+                }
+                match self.run_once().await {
+                    Ok(summary) => tracing::info!("Maintenance done: {summary:?}"),
+                    Err(e) => tracing::error!("Maintenance failed -> {e:?}"),
+                }
+                tokio::select! {
+                    _ = tokio::time::sleep(self.config.interval) => {}
+                    _ = shutdown.changed() => {}
+                }
+            }
+            tracing::debug!("Maintenance stopped");
+        })
+    }
+
+    /// Stop the spawned task, after the mailbox it is working on
+    pub fn shutdown(&self) {
+        self.shutdown.send_replace(true);
+    }
+
+    /// Run a single cycle over all mailboxes
+    ///
+    /// Only fails if the mailboxes can't be listed, failing mailboxes are logged and skipped.
+    pub async fn run_once(&self) -> Result<MaintenanceSummary> {
+        let mut summary = MaintenanceSummary::default();
+        for mailbox_id in self.mailbox.list_mailboxes().await? {
+            if *self.shutdown.borrow() {
+                break;
+            }
+            summary.mailboxes += 1;
+            match self.maintain(&mailbox_id).await {
+                Ok((dropped, expired, compacted)) => {
+                    summary.dropped += dropped;
+                    summary.expired += expired;
+                    summary.compacted += compacted;
+                }
+                Err(e) => {
+                    tracing::warn!("Skipping mailbox {mailbox_id} -> {e:?}");
+                    summary.failed += 1;
+                }
+            }
+        }
+
+        Ok(summary)
+    }
+
+    async fn maintain(&self, mailbox_id: &str) -> Result<(u64, u64, u64)> {
+        let dropped = match self.config.retention {
+            Some(retention) => self.mailbox.drop_older_than(mailbox_id, retention).await?,
+            None => 0,
+        };
+        let expired = if self.config.expire {
+            self.mailbox.expire_items(mailbox_id).await?.expired.len() as u64
+        } else {
+            0
+        };
+        let compacted = if self.config.compact {
+            self.mailbox.compact(mailbox_id).await?
+        } else {
+            0
+        };
+
+        Ok((dropped, expired, compacted))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::Mailbox;
+    use crate::MailboxDisk;
+    use crate::MailboxError;
+    use crate::MailboxItem;
+    use crate::MailboxMaintainer;
+    use crate::MailboxMemory;
+    use crate::MaintenanceConfig;
+    use crate::MockMailbox;
+    use crate::MockOperation;
+    use color_eyre::Result;
+    use std::path::Path;
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tempfile::TempDir;
+
+    use test_log::test;
+
+    #[derive(Default, Debug)]
+    struct TestItem {}
+
+    impl MailboxItem for TestItem {
+        fn serialize(&self) -> Result<Vec<u8>> {
+            Ok(Vec::new())
+        }
+        fn deserialize(_data: &[u8]) -> Result<Self>
+        where
+            Self: Sized,
+        {
+            Ok(Self {})
+        }
+    }
+
+    fn item_count(dir: &TempDir, mailbox_id: &str) -> Result<usize> {
+        let mut path = dir.path().to_path_buf();
+        path.push(mailbox_id);
+        let count = std::fs::read_dir(path)?
+            .filter(|e| {
+                e.as_ref()
+                    .is_ok_and(|e| e.path().extension().is_some_and(|e| e == "item"))
+            })
+            .count();
+        Ok(count)
+    }
+
+    #[test(tokio::test(start_paused = true))]
+    async fn it_compacts_periodically() -> Result<()> {
+        let dir = TempDir::new()?;
+        let mut disk = MailboxDisk::<TestItem>::at(dir.path(), Path::new("item"));
+        disk.ensure_storage_exists().await?;
+        let mailbox = Arc::new(disk);
+        for _ in 0..3 {
+            mailbox.send("42", TestItem::default()).await?;
+        }
+        mailbox.pop("42").await?;
+        mailbox.pop("42").await?;
+
+        let config = MaintenanceConfig {
+            interval: Duration::from_secs(10),
+            ..Default::default()
+        };
+        let maintainer = MailboxMaintainer::new(mailbox.clone(), config);
+        let task = maintainer.clone().spawn();
+
+        // the first cycle runs right away
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        assert_eq!(item_count(&dir, "42")?, 1);
+
+        mailbox.pop("42").await?;
+        assert_eq!(item_count(&dir, "42")?, 1);
+        tokio::time::sleep(Duration::from_secs(10)).await;
+        assert_eq!(item_count(&dir, "42")?, 0);
+
+        maintainer.shutdown();
+        task.await?;
+
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn it_skips_failing_mailboxes() -> Result<()> {
+        let mock = MockMailbox::<TestItem>::default();
+        mock.send("1", TestItem::default()).await?;
+        mock.send("2", TestItem::default()).await?;
+        mock.pop("1").await?;
+        mock.pop("2").await?;
+        mock.fail_next(
+            MockOperation::Compact,
+            MailboxError::Io(std::io::ErrorKind::Interrupted.into()),
+        );
+
+        let config = MaintenanceConfig {
+            retention: Some(Duration::from_secs(3600)),
+            ..Default::default()
+        };
+        let maintainer = MailboxMaintainer::new(Arc::new(mock), config);
+        let summary = maintainer.run_once().await?;
+        assert_eq!(
+            (summary.mailboxes, summary.failed, summary.compacted),
+            (2, 1, 1)
+        );
+
+        // retention drops what nobody read in time
+        let memory = Arc::new(MailboxMemory::<TestItem>::new());
+        memory.send("1", TestItem::default()).await?;
+        let config = MaintenanceConfig {
+            retention: Some(Duration::ZERO),
+            ..Default::default()
+        };
+        let maintainer = MailboxMaintainer::new(memory.clone(), config);
+        let summary = maintainer.run_once().await?;
+        assert_eq!((summary.dropped, summary.compacted), (1, 1));
+        assert!(memory.receive("1").await?.is_none());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,618 @@
+use crate::clock::SharedClock;
+use crate::mailbox::RECEIVE_BATCH_POLL_INTERVAL;
+use crate::Clock;
+use crate::DeliveryMode;
+use crate::HeaderSelector;
+use crate::ItemSummary;
+use crate::Mailbox;
+use crate::MailboxError;
+use crate::MailboxItem;
+use crate::MailboxStats;
+use crate::Page;
+use crate::ReceiveAnyTurn;
+use crate::DEFAULT_GROUP;
+use async_trait::async_trait;
+use chrono::DateTime;
+use chrono::Utc;
+use color_eyre::eyre::Result;
+use core::marker::PhantomData;
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// A mailbox that only lives in memory
+///
+/// Useful for tests, and for things that don't need to survive a restart.
+/// Items are kept serialized, so they behave exactly like they would with a persistent backend.
+#[derive(Debug, Default)]
+pub struct MailboxMemory<ITEM: MailboxItem> {
+    mailboxes: Mutex<HashMap<String, MemoryMailbox>>,
+    delivery_mode: DeliveryMode,
+    clock: SharedClock,
+    receive_any_turn: ReceiveAnyTurn,
+    item_type: PhantomData<ITEM>,
+}
+
+#[derive(Debug, Default)]
+struct MemoryMailbox {
+    highest_used_id: u64,
+    entries: BTreeMap<u64, MemoryEntry>,
+    stats: MailboxStats,
+    /// The read ids of all groups, except the default group
+    groups: HashMap<String, BTreeSet<u64>>,
+}
+
+#[derive(Debug)]
+struct MemoryEntry {
+    data: Vec<u8>,
+    headers: BTreeMap<String, String>,
+    read: bool,
+    delivery_count: u32,
+    sent_at: DateTime<Utc>,
+}
+
+impl MemoryMailbox {
+    fn unread(&mut self) -> impl Iterator<Item = (&u64, &mut MemoryEntry)> {
+        self.entries.iter_mut().filter(|(_, e)| !e.read)
+    }
+}
+
+impl<ITEM: MailboxItem> MailboxMemory<ITEM> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_delivery_mode(mut self, delivery_mode: DeliveryMode) -> Self {
+        self.delivery_mode = delivery_mode;
+        self
+    }
+
+    /// Use a different source for the current time, e.g. a [crate::MockClock]
+    pub fn with_clock(mut self, clock: impl Clock + 'static) -> Self {
+        self.clock = SharedClock::new(clock);
+        self
+    }
+
+    pub fn delivery_mode(&self) -> DeliveryMode {
+        self.delivery_mode
+    }
+
+    fn parse_item_id(item_id: &str) -> Result<u64> {
+        item_id.parse::<u64>().map_err(|_| {
+            MailboxError::InvalidId {
+                id: item_id.to_string(),
+            }
+            .into()
+        })
+    }
+
+    fn with_entry<T>(
+        &self,
+        mailbox_id: &str,
+        item_id: &str,
+        f: impl FnOnce(&mut MemoryEntry) -> T,
+    ) -> Result<T> {
+        let id = Self::parse_item_id(item_id)?;
+        let mut mailboxes = self.mailboxes.lock().unwrap();
+        let entry = mailboxes
+            .get_mut(mailbox_id)
+            .and_then(|m| m.entries.get_mut(&id))
+            .ok_or_else(|| MailboxError::NotFound {
+                mailbox_id: mailbox_id.to_string(),
+                item_id: item_id.to_string(),
+            })?;
+
+        Ok(f(entry))
+    }
+
+    /// How many items, up to `max`, `receive_many` would deliver now
+    fn unread_count(&self, mailbox_id: &str, max: usize) -> usize {
+        let mut mailboxes = self.mailboxes.lock().unwrap();
+        mailboxes
+            .get_mut(mailbox_id)
+            .map_or(0, |mailbox| mailbox.unread().take(max).count())
+    }
+}
+
+#[async_trait]
+impl<ITEM: MailboxItem + Send> Mailbox<ITEM> for MailboxMemory<ITEM> {
+    async fn ensure_storage_exists(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    async fn create_mailbox(&self, mailbox_id: &str) -> Result<()> {
+        let mut mailboxes = self.mailboxes.lock().unwrap();
+        mailboxes.entry(mailbox_id.to_string()).or_default();
+        Ok(())
+    }
+    async fn send(&self, mailbox_id: &str, item: ITEM) -> Result<String> {
+        let mut item_ids = self.send_transaction(mailbox_id, vec![item]).await?;
+        Ok(item_ids.remove(0))
+    }
+    async fn send_transaction(&self, mailbox_id: &str, items: Vec<ITEM>) -> Result<Vec<String>> {
+        let data = items
+            .iter()
+            .map(|item| Ok((item.serialize()?, item.headers())))
+            .collect::<Result<Vec<_>>>()?;
+        let mut mailboxes = self.mailboxes.lock().unwrap();
+        let mailbox = mailboxes.entry(mailbox_id.to_string()).or_default();
+        let mut item_ids = Vec::new();
+        for (data, headers) in data {
+            mailbox.highest_used_id += 1;
+            let id = mailbox.highest_used_id;
+            mailbox.entries.insert(
+                id,
+                MemoryEntry {
+                    data,
+                    headers,
+                    read: false,
+                    delivery_count: 0,
+                    sent_at: self.clock.now(),
+                },
+            );
+            mailbox.stats.total_sent += 1;
+            mailbox.stats.last_send_at = Some(self.clock.now());
+            item_ids.push(format!("{id}"));
+        }
+
+        Ok(item_ids)
+    }
+    async fn receive(&self, mailbox_id: &str) -> Result<Option<(String, ITEM)>> {
+        Ok(self.receive_many(mailbox_id, 1).await?.pop())
+    }
+    fn receive_any_turn(&self) -> Option<&ReceiveAnyTurn> {
+        Some(&self.receive_any_turn)
+    }
+    async fn acknowledge(&self, mailbox_id: &str, item_id: &str) -> Result<()> {
+        let was_read = self.with_entry(mailbox_id, item_id, |e| {
+            if e.read {
+                tracing::warn!(
+                    "Trying to acknowledge message {mailbox_id} {item_id} that is already read!"
+                );
+            }
+            std::mem::replace(&mut e.read, true)
+        })?;
+        if !was_read {
+            let mut mailboxes = self.mailboxes.lock().unwrap();
+            if let Some(mailbox) = mailboxes.get_mut(mailbox_id) {
+                mailbox.stats.total_acknowledged += 1;
+                mailbox.stats.last_ack_at = Some(self.clock.now());
+            }
+        }
+
+        Ok(())
+    }
+    async fn acknowledge_through(&self, mailbox_id: &str, item_id: &str) -> Result<u64> {
+        let through = Self::parse_item_id(item_id)?;
+        let mut mailboxes = self.mailboxes.lock().unwrap();
+        let Some(mailbox) = mailboxes.get_mut(mailbox_id) else {
+            return Ok(0);
+        };
+        if through > mailbox.highest_used_id {
+            tracing::warn!(
+                "Acknowledging {mailbox_id} through {through}, clamped to {}",
+                mailbox.highest_used_id
+            );
+        }
+        let mut acknowledged = 0;
+        for (_id, e) in mailbox.entries.range_mut(..=through) {
+            if !e.read {
+                e.read = true;
+                acknowledged += 1;
+            }
+        }
+        if acknowledged > 0 {
+            mailbox.stats.total_acknowledged += acknowledged;
+            mailbox.stats.last_ack_at = Some(self.clock.now());
+        }
+
+        Ok(acknowledged)
+    }
+    async fn update(&self, mailbox_id: &str, item_id: &str, item: ITEM) -> Result<()> {
+        let data = item.serialize()?;
+        let updated = self.with_entry(mailbox_id, item_id, |e| {
+            if !e.read {
+                e.data = data;
+            }
+            !e.read
+        })?;
+        if !updated {
+            return Err(MailboxError::AlreadyRead {
+                mailbox_id: mailbox_id.to_string(),
+                item_id: item_id.to_string(),
+            }
+            .into());
+        }
+
+        Ok(())
+    }
+    async fn peek(&self, mailbox_id: &str) -> Result<Option<(String, ITEM)>> {
+        let mut mailboxes = self.mailboxes.lock().unwrap();
+        let Some(mailbox) = mailboxes.get_mut(mailbox_id) else {
+            return Ok(None);
+        };
+        let next = match mailbox.unread().next() {
+            Some((id, e)) => Some((format!("{id}"), ITEM::deserialize(&e.data)?)),
+            None => None,
+        };
+
+        Ok(next)
+    }
+    /// Waits until `max_items` are unread, so waiting doesn't count deliveries
+    async fn receive_batch(
+        &self,
+        mailbox_id: &str,
+        max_items: usize,
+        max_wait: Duration,
+    ) -> Result<Vec<(String, ITEM)>> {
+        let deadline = tokio::time::Instant::now() + max_wait;
+        while self.unread_count(mailbox_id, max_items) < max_items {
+            let now = tokio::time::Instant::now();
+            if now >= deadline {
+                break;
+            }
+            tokio::time::sleep_until(deadline.min(now + RECEIVE_BATCH_POLL_INTERVAL)).await;
+        }
+        self.receive_many(mailbox_id, max_items).await
+    }
+    async fn receive_many(&self, mailbox_id: &str, max: usize) -> Result<Vec<(String, ITEM)>> {
+        let mut mailboxes = self.mailboxes.lock().unwrap();
+        let Some(mailbox) = mailboxes.get_mut(mailbox_id) else {
+            return Ok(Vec::new());
+        };
+        let at_most_once = self.delivery_mode == DeliveryMode::AtMostOnce;
+        let mut items = Vec::new();
+        let mut failure = None;
+        for (id, e) in mailbox.unread().take(max) {
+            let item = match ITEM::deserialize(&e.data) {
+                Ok(item) => item,
+                // Note: a broken item is only skipped when it is the first one, so the error is returned
+                Err(err) if at_most_once && items.is_empty() => {
+                    e.read = true;
+                    failure = Some(err);
+                    break;
+                }
+                Err(_) if at_most_once => break,
+                Err(err) => return Err(err),
+            };
+            e.delivery_count += 1;
+            e.read |= at_most_once;
+            items.push((format!("{id}"), item));
+        }
+        if at_most_once {
+            let read = items.len() as u64 + u64::from(failure.is_some());
+            if read > 0 {
+                mailbox.stats.total_acknowledged += read;
+                mailbox.stats.last_ack_at = Some(self.clock.now());
+            }
+        }
+
+        match failure {
+            Some(e) => Err(e),
+            None => Ok(items),
+        }
+    }
+    async fn receive_matching(
+        &self,
+        mailbox_id: &str,
+        selector: &HeaderSelector,
+    ) -> Result<Option<(String, ITEM)>> {
+        let mut mailboxes = self.mailboxes.lock().unwrap();
+        let Some(mailbox) = mailboxes.get_mut(mailbox_id) else {
+            return Ok(None);
+        };
+        let Some(id) = mailbox
+            .unread()
+            .find(|(_, e)| selector.matches(&e.headers))
+            .map(|(id, _)| *id)
+        else {
+            return Ok(None);
+        };
+        let at_most_once = self.delivery_mode == DeliveryMode::AtMostOnce;
+        let e = mailbox.entries.get_mut(&id).expect("Unread entry exists");
+        let item = ITEM::deserialize(&e.data);
+        if item.is_ok() {
+            e.delivery_count += 1;
+        }
+        // Note: like `receive_many`, a broken item is skipped for at most once delivery
+        if at_most_once {
+            e.read = true;
+            mailbox.stats.total_acknowledged += 1;
+            mailbox.stats.last_ack_at = Some(self.clock.now());
+        }
+
+        Ok(Some((format!("{id}"), item?)))
+    }
+    async fn list_mailboxes(&self) -> Result<Vec<String>> {
+        let mailboxes = self.mailboxes.lock().unwrap();
+        let mut ids: Vec<String> = mailboxes.keys().cloned().collect();
+        ids.sort();
+
+        Ok(ids)
+    }
+    async fn delivery_count(&self, mailbox_id: &str, item_id: &str) -> Result<u32> {
+        self.with_entry(mailbox_id, item_id, |e| e.delivery_count)
+    }
+    async fn list_items_page(
+        &self,
+        mailbox_id: &str,
+        after: Option<&str>,
+        limit: usize,
+    ) -> Result<Page<ItemSummary>> {
+        let after = after
+            .map(Self::parse_item_id)
+            .transpose()?
+            .unwrap_or_default();
+        let limit = limit.max(1);
+        let mailboxes = self.mailboxes.lock().unwrap();
+        let Some(mailbox) = mailboxes.get(mailbox_id) else {
+            return Ok(Page::new(Vec::new(), limit, |e: &ItemSummary| {
+                e.item_id.clone()
+            }));
+        };
+        let entries = mailbox
+            .entries
+            .range(after + 1..)
+            .take(limit + 1)
+            .map(|(id, e)| ItemSummary {
+                item_id: format!("{id}"),
+                read: e.read,
+                delivery_count: e.delivery_count,
+                sent_at: Some(e.sent_at),
+                size: e.data.len() as u64,
+            })
+            .collect();
+
+        Ok(Page::new(entries, limit, |e| e.item_id.clone()))
+    }
+    async fn item_size(&self, mailbox_id: &str, item_id: &str) -> Result<u64> {
+        self.with_entry(mailbox_id, item_id, |e| e.data.len() as u64)
+    }
+    async fn stats(&self, mailbox_id: &str) -> Result<MailboxStats> {
+        let mut mailboxes = self.mailboxes.lock().unwrap();
+        let Some(mailbox) = mailboxes.get_mut(mailbox_id) else {
+            return Ok(MailboxStats::default());
+        };
+        let pending = mailbox.unread().count() as u64;
+
+        Ok(MailboxStats {
+            pending,
+            ..mailbox.stats.clone()
+        })
+    }
+    async fn receive_for(&self, mailbox_id: &str, group: &str) -> Result<Option<(String, ITEM)>> {
+        if group == DEFAULT_GROUP {
+            return self.receive(mailbox_id).await;
+        }
+        let mailboxes = self.mailboxes.lock().unwrap();
+        let Some(mailbox) = mailboxes.get(mailbox_id) else {
+            return Ok(None);
+        };
+        let read = mailbox.groups.get(group);
+        let next = mailbox
+            .entries
+            .iter()
+            .find(|(id, _)| !read.is_some_and(|read| read.contains(id)));
+        match next {
+            Some((id, e)) => Ok(Some((format!("{id}"), ITEM::deserialize(&e.data)?))),
+            None => Ok(None),
+        }
+    }
+    async fn acknowledge_for(&self, mailbox_id: &str, group: &str, item_id: &str) -> Result<()> {
+        if group == DEFAULT_GROUP {
+            return self.acknowledge(mailbox_id, item_id).await;
+        }
+        let id = Self::parse_item_id(item_id)?;
+        let mut mailboxes = self.mailboxes.lock().unwrap();
+        let mailbox = mailboxes
+            .get_mut(mailbox_id)
+            .filter(|m| m.entries.contains_key(&id))
+            .ok_or_else(|| MailboxError::NotFound {
+                mailbox_id: mailbox_id.to_string(),
+                item_id: item_id.to_string(),
+            })?;
+        mailbox
+            .groups
+            .entry(group.to_string())
+            .or_default()
+            .insert(id);
+
+        Ok(())
+    }
+    async fn compact(&self, mailbox_id: &str) -> Result<u64> {
+        let mut mailboxes = self.mailboxes.lock().unwrap();
+        let Some(mailbox) = mailboxes.get_mut(mailbox_id) else {
+            return Ok(0);
+        };
+        let groups = &mailbox.groups;
+        let before = mailbox.entries.len();
+        mailbox
+            .entries
+            .retain(|id, e| !(e.read && groups.values().all(|read| read.contains(id))));
+
+        Ok((before - mailbox.entries.len()) as u64)
+    }
+    async fn drop_older_than(&self, mailbox_id: &str, max_age: Duration) -> Result<u64> {
+        let cutoff = self.clock.now() - max_age;
+        let mut mailboxes = self.mailboxes.lock().unwrap();
+        let Some(mailbox) = mailboxes.get_mut(mailbox_id) else {
+            return Ok(0);
+        };
+        let mut dropped = 0;
+        for (_id, e) in mailbox.unread() {
+            if e.sent_at >= cutoff {
+                break;
+            }
+            e.read = true;
+            dropped += 1;
+        }
+
+        Ok(dropped)
+    }
+    async fn pop(&self, mailbox_id: &str) -> Result<Option<(String, ITEM)>> {
+        match self.receive(mailbox_id).await? {
+            Some((item_id, item)) => {
+                // Note: already acknowledged by receive
+                if self.delivery_mode == DeliveryMode::AtLeastOnce {
+                    self.acknowledge(mailbox_id, &item_id).await?;
+                }
+                Ok(Some((item_id, item)))
+            }
+            None => Ok(None),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::Mailbox;
+    use crate::MailboxError;
+    use crate::MailboxItem;
+    use crate::MailboxMemory;
+    use color_eyre::Result;
+    use std::time::Duration;
+    use tokio::time::Instant;
+
+    use test_log::test;
+
+    #[derive(Default, Debug)]
+    struct TestItem {
+        data: String,
+    }
+
+    impl MailboxItem for TestItem {
+        fn serialize(&self) -> Result<Vec<u8>> {
+            Ok(self.data.clone().into())
+        }
+        fn deserialize(data: &[u8]) -> Result<Self>
+        where
+            Self: Sized,
+        {
+            Ok(Self {
+                data: String::from_utf8(data.to_vec())?,
+            })
+        }
+    }
+
+    #[test(tokio::test)]
+    async fn it_sends_and_receives() -> Result<()> {
+        let mailbox = MailboxMemory::<TestItem>::new();
+        for data in ["one", "two"] {
+            mailbox
+                .send(
+                    "42",
+                    TestItem {
+                        data: data.to_string(),
+                    },
+                )
+                .await?;
+        }
+
+        let (id, item) = mailbox.receive("42").await?.expect("Item pending");
+        assert_eq!((id.as_str(), item.data.as_str()), ("1", "one"));
+        let (id, _item) = mailbox.receive("42").await?.expect("Item pending");
+        assert_eq!(id, "1");
+        assert_eq!(mailbox.delivery_count("42", "1").await?, 2);
+
+        mailbox.acknowledge("42", "1").await?;
+        let (id, item) = mailbox.pop("42").await?.expect("Item pending");
+        assert_eq!((id.as_str(), item.data.as_str()), ("2", "two"));
+        assert!(mailbox.receive("42").await?.is_none());
+        assert!(mailbox.receive("other").await?.is_none());
+
+        let e = mailbox.acknowledge("42", "3").await.expect_err("No item 3");
+        assert!(matches!(
+            e.downcast_ref::<MailboxError>(),
+            Some(MailboxError::NotFound { .. })
+        ));
+
+        let stats = mailbox.stats("42").await?;
+        assert_eq!(
+            (stats.pending, stats.total_sent, stats.total_acknowledged),
+            (0, 2, 2)
+        );
+        assert!(stats.last_ack_at.is_some());
+
+        // other groups still see everything
+        let (id, _item) = mailbox
+            .receive_for("42", "other")
+            .await?
+            .expect("Item pending");
+        mailbox.acknowledge_for("42", "other", &id).await?;
+        let (id, _item) = mailbox
+            .receive_for("42", "other")
+            .await?
+            .expect("Item pending");
+        assert_eq!(id, "2");
+
+        Ok(())
+    }
+
+    #[test(tokio::test(start_paused = true))]
+    async fn it_receives_batches() -> Result<()> {
+        let mailbox = std::sync::Arc::new(MailboxMemory::<TestItem>::new());
+        let max_wait = Duration::from_millis(200);
+        let item = |n: usize| TestItem {
+            data: format!("{n}"),
+        };
+
+        // nothing arrives
+        let start = Instant::now();
+        assert!(mailbox.receive_batch("42", 3, max_wait).await?.is_empty());
+        assert_eq!(start.elapsed(), max_wait);
+
+        // the batch is full right away
+        for n in 1..=3 {
+            mailbox.send("42", item(n)).await?;
+        }
+        let start = Instant::now();
+        let batch = mailbox.receive_batch("42", 3, max_wait).await?;
+        assert_eq!(batch.len(), 3);
+        assert_eq!(start.elapsed(), Duration::ZERO);
+        for (item_id, _item) in batch {
+            mailbox.acknowledge("42", &item_id).await?;
+        }
+
+        // only part of the batch arrives in time
+        mailbox.send("42", item(4)).await?;
+        let sender = {
+            let mailbox = mailbox.clone();
+            tokio::spawn(async move {
+                tokio::time::sleep(Duration::from_millis(50)).await;
+                mailbox.send("42", item(5)).await
+            })
+        };
+        let start = Instant::now();
+        let batch = mailbox.receive_batch("42", 3, max_wait).await?;
+        assert_eq!(start.elapsed(), max_wait);
+        sender.await??;
+        let ids: Vec<&str> = batch.iter().map(|(id, _)| id.as_str()).collect();
+        assert_eq!(ids, ["4", "5"]);
+        assert_eq!(mailbox.delivery_count("42", "4").await?, 1);
+
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn it_takes_turns_receiving_from_any_mailbox_in_memory() -> Result<()> {
+        let mailbox = MailboxMemory::<TestItem>::new();
+        for i in 1..=3 {
+            for id in ["a", "b"] {
+                let data = format!("{id}{i}");
+                mailbox.send(id, TestItem { data }).await?;
+            }
+        }
+
+        let ids = ["a", "idle", "b"].map(String::from);
+        let mut served = Vec::new();
+        while let Some((id, item_id, item)) = mailbox.receive_any(&ids).await? {
+            mailbox.acknowledge(&id, &item_id).await?;
+            served.push(item.data);
+        }
+        assert_eq!(served, ["a1", "b1", "a2", "b2", "a3", "b3"]);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,36 @@
+use crate::Backpressure;
+use crate::CorruptionPolicy;
+use crate::DeliveryMode;
+use crate::FairnessPolicy;
+use crate::RetentionPolicy;
+use serde::Deserialize;
+use serde::Serialize;
+
+/// Overrides of the [crate::MailboxDisk] configuration for a single mailbox, see [crate::MailboxDisk::set_settings]
+///
+/// `None` uses the configuration of the backend.
+/// The settings are stored in the meta of the mailbox, so they are shared by all instances.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct MailboxSettings {
+    /// Like [crate::MailboxDisk::with_capacity], a mailbox already holding more items keeps them,
+    /// but sends are held back until it drops below the capacity
+    pub capacity: Option<(u64, Backpressure)>,
+    /// Like [crate::MailboxDisk::with_delivery_mode]
+    pub delivery_mode: Option<DeliveryMode>,
+    /// Like [crate::MailboxDisk::with_defer_limit], the maximum number of defers, and the dead-letter mailbox
+    pub defer_limit: Option<(u32, Option<String>)>,
+    /// Like [crate::MailboxDisk::with_retention]
+    pub retention: Option<RetentionPolicy>,
+    /// Like [crate::MailboxDisk::with_corruption_policy]
+    pub corruption_policy: Option<CorruptionPolicy>,
+    /// Like [crate::MailboxDisk::with_fairness]
+    pub fairness: Option<FairnessPolicy>,
+}
+
+impl MailboxSettings {
+    /// If nothing is overridden
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+}
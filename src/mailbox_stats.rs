@@ -0,0 +1,56 @@
+use crate::FreezeMode;
+use chrono::DateTime;
+use chrono::Utc;
+use std::collections::BTreeMap;
+
+/// Counters for a single mailbox, as returned by [crate::Mailbox::stats]
+///
+/// The `total_*` counters only ever grow, they are not affected by items being removed.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MailboxStats {
+    /// Items that are not acknowledged yet
+    pub pending: u64,
+    /// Items successfully sent, ever
+    pub total_sent: u64,
+    /// Items acknowledged, ever
+    ///
+    /// Note: Acknowledging the same item twice only counts once.
+    pub total_acknowledged: u64,
+    /// Unread items dropped by a [crate::RetentionPolicy], ever
+    pub total_dropped_unread: u64,
+    pub last_send_at: Option<DateTime<Utc>>,
+    pub last_ack_at: Option<DateTime<Utc>>,
+    /// Bytes used by the files of the mailbox, `None` for backends that don't know
+    pub disk_usage: Option<u64>,
+    /// The most pending items the mailbox takes, `None` without a limit
+    pub capacity: Option<u64>,
+    /// Set by [crate::MailboxDisk::set_frozen], always [FreezeMode::None] for other backends
+    pub frozen: FreezeMode,
+    /// The pending items by their [crate::PRIORITY_HEADER], only filled by [crate::MailboxDisk]
+    ///
+    /// Note: Items boosted by [crate::FairnessPolicy::AgingBoost] stay with their original priority.
+    pub priorities: BTreeMap<u32, PriorityStats>,
+}
+
+/// The pending items of a single priority, see [MailboxStats::priorities]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PriorityStats {
+    pub pending: u64,
+    /// When the oldest of them was sent, `None` if that is not known
+    pub oldest_sent_at: Option<DateTime<Utc>>,
+}
+
+impl PriorityStats {
+    /// How long the oldest of them has been waiting at `now`
+    pub fn oldest_age(&self, now: DateTime<Utc>) -> Option<std::time::Duration> {
+        self.oldest_sent_at.and_then(|t| (now - t).to_std().ok())
+    }
+}
+
+impl MailboxStats {
+    /// How full the mailbox is, `pending` over `capacity`
+    pub fn utilization(&self) -> Option<f64> {
+        self.capacity
+            .map(|capacity| self.pending as f64 / capacity.max(1) as f64)
+    }
+}
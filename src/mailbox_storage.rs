@@ -0,0 +1,398 @@
+use async_trait::async_trait;
+use color_eyre::eyre::eyre;
+use color_eyre::eyre::Result;
+use std::collections::BTreeMap;
+use std::hash::Hash;
+use std::hash::Hasher;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::SystemTime;
+use tokio::io::AsyncRead;
+
+/// Where a [crate::MailboxKv] keeps its files, e.g. [crate::FsStorage] for [crate::MailboxDisk]
+///
+/// Keys are paths, and the folders are the keys' parents.
+/// Only the first five methods are required, the others fall back to them,
+/// override them if your storage can do better.
+#[async_trait]
+pub trait MailboxStorage: std::fmt::Debug + Send + Sync + 'static {
+    /// `None` for a missing key
+    async fn get(&self, key: &Path) -> Result<Option<Vec<u8>>>;
+    /// Readers see either the old or the new value, never a partial one
+    async fn put(&self, key: &Path, value: &[u8]) -> Result<()>;
+    /// Returns `false`, and changes nothing, if the key exists already
+    async fn put_if_absent(&self, key: &Path, value: &[u8]) -> Result<bool>;
+    /// Deleting a missing key is not an error
+    async fn delete(&self, key: &Path) -> Result<()>;
+    /// The keys and folders directly in `folder`, in no particular order, `None` if it doesn't exist
+    async fn list(&self, folder: &Path) -> Result<Option<Vec<StorageEntry>>>;
+
+    /// If there is a value or a folder at `key`
+    async fn exists(&self, key: &Path) -> Result<bool> {
+        Ok(self.get(key).await?.is_some() || self.list(key).await?.is_some())
+    }
+    /// `None` if there is no value at `key`
+    ///
+    /// Note: The default has no modification times, and hashes the value for its version.
+    async fn stat(&self, key: &Path) -> Result<Option<StorageStat>> {
+        let Some(value) = self.get(key).await? else {
+            return Ok(None);
+        };
+        let mut hasher = std::collections::hash_map::DefaultHasher::new();
+        value.hash(&mut hasher);
+        Ok(Some(StorageStat {
+            len: value.len() as u64,
+            modified: None,
+            version: hasher.finish(),
+        }))
+    }
+    /// Like [MailboxStorage::put], but readers might see a partial value
+    async fn write(&self, key: &Path, value: &[u8]) -> Result<()> {
+        self.put(key, value).await
+    }
+    async fn append(&self, key: &Path, value: &[u8]) -> Result<()> {
+        let mut appended = self.get(key).await?.unwrap_or_default();
+        appended.extend_from_slice(value);
+        self.put(key, &appended).await
+    }
+    /// Replaces the value at `to`, returns `false`, and changes nothing, if there is nothing at `from`
+    ///
+    /// Note: The default only moves values, not folders.
+    async fn rename(&self, from: &Path, to: &Path) -> Result<bool> {
+        let Some(value) = self.get(from).await? else {
+            return Ok(false);
+        };
+        self.put(to, &value).await?;
+        self.delete(from).await?;
+        Ok(true)
+    }
+    /// Sets the modification time, if the storage keeps them
+    async fn touch(&self, _key: &Path, _modified: SystemTime) -> Result<()> {
+        Ok(())
+    }
+    /// Creates `folder` and its parents, if the storage needs folders to exist before putting keys in them
+    async fn create_folder(&self, _folder: &Path) -> Result<()> {
+        Ok(())
+    }
+    /// Removes `folder` if it is empty, returns `false` if it isn't, or doesn't exist
+    async fn delete_folder(&self, folder: &Path) -> Result<bool> {
+        Ok(self.list(folder).await?.is_some_and(|e| e.is_empty()))
+    }
+    /// Removes `folder` and everything in it, a missing folder is not an error
+    async fn delete_all(&self, folder: &Path) -> Result<()> {
+        let Some(entries) = self.list(folder).await? else {
+            return Ok(());
+        };
+        for entry in entries {
+            match entry.is_folder {
+                true => self.delete_all(&entry.key).await?,
+                false => self.delete(&entry.key).await?,
+            }
+        }
+        self.delete_folder(folder).await?;
+        Ok(())
+    }
+    /// The space left below `folder`, `None` if the storage can't tell
+    async fn available_space(&self, _folder: &Path) -> Result<Option<u64>> {
+        Ok(None)
+    }
+    /// Streams the value at `key`, `None` for a missing key
+    async fn reader(&self, key: &Path) -> Result<Option<Box<dyn AsyncRead + Send + Unpin>>> {
+        let Some(value) = self.get(key).await? else {
+            return Ok(None);
+        };
+        Ok(Some(Box::new(std::io::Cursor::new(value))))
+    }
+    /// Why `folder` can't be used, `None` if it can, see [crate::Mailbox::health_check]
+    async fn problem(&self, folder: &Path, writable: bool) -> Option<String> {
+        match self.list(folder).await {
+            Ok(Some(_)) => {}
+            Ok(None) => return Some(format!("{folder:?} doesn't exist")),
+            Err(e) => return Some(format!("Can't access {folder:?} -> {e}")),
+        }
+        if !writable {
+            return None;
+        }
+        let probe = folder.join(format!(".probe-{:016x}.tmp", fastrand::u64(..)));
+        if let Err(e) = self.put(&probe, b"probe").await {
+            return Some(format!("Can't write {probe:?} -> {e}"));
+        }
+        self.delete(&probe)
+            .await
+            .err()
+            .map(|e| format!("Can't remove {probe:?} -> {e}"))
+    }
+    /// Puts `value` back, or deletes `key` if it is `None`, without waiting for it
+    ///
+    /// Used to undo half done writes, when their future is dropped.
+    /// The default spawns the change on the current runtime, and gives up outside of one.
+    fn undo(self: Arc<Self>, key: PathBuf, value: Option<Vec<u8>>) {
+        let Ok(handle) = tokio::runtime::Handle::try_current() else {
+            tracing::warn!("Can't undo the write of {key:?} outside of a runtime");
+            return;
+        };
+        handle.spawn(async move {
+            let undone = match value {
+                Some(value) => self.put(&key, &value).await,
+                None => self.delete(&key).await,
+            };
+            if let Err(e) = undone {
+                tracing::warn!("Can't undo the write of {key:?} -> {e:#}");
+            }
+        });
+    }
+    /// Starts watching `folder` for changes, `None` if the storage can't be watched, see [crate::MailboxKv::watch]
+    #[cfg(feature = "fs-watch")]
+    fn watch(
+        &self,
+        _folder: &Path,
+        _recursive: bool,
+        _tx: tokio::sync::mpsc::UnboundedSender<notify::Result<notify::Event>>,
+    ) -> Result<Option<Box<dyn notify::Watcher + Send>>> {
+        Ok(None)
+    }
+}
+
+/// A key, or a folder, found by [MailboxStorage::list]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StorageEntry {
+    /// The full key, including the folder listed
+    pub key: PathBuf,
+    pub is_folder: bool,
+}
+
+/// Returned by [MailboxStorage::stat]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StorageStat {
+    pub len: u64,
+    pub modified: Option<SystemTime>,
+    /// Changes whenever the value is replaced, even if length and time don't
+    pub version: u64,
+}
+
+/// A [MailboxStorage] in a `BTreeMap`, for tests, and mailboxes that don't have to survive a restart
+///
+/// Clones share their values, like two [crate::MailboxDisk]s on the same folder.
+#[derive(Debug, Default, Clone)]
+pub struct MemoryStorage {
+    nodes: Arc<Mutex<BTreeMap<PathBuf, MemoryNode>>>,
+    versions: Arc<AtomicU64>,
+}
+
+#[derive(Debug)]
+enum MemoryNode {
+    Folder,
+    Value {
+        value: Vec<u8>,
+        modified: SystemTime,
+        version: u64,
+    },
+}
+
+impl MemoryStorage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// All values, by key, e.g. to compare with what [crate::FsStorage] wrote
+    pub fn values(&self) -> BTreeMap<PathBuf, Vec<u8>> {
+        let nodes = self.nodes.lock().unwrap();
+        nodes
+            .iter()
+            .filter_map(|(key, node)| match node {
+                MemoryNode::Folder => None,
+                MemoryNode::Value { value, .. } => Some((key.clone(), value.clone())),
+            })
+            .collect()
+    }
+
+    fn insert(
+        &self,
+        nodes: &mut BTreeMap<PathBuf, MemoryNode>,
+        key: &Path,
+        value: Vec<u8>,
+    ) -> Result<()> {
+        if let Some(folder) = key.parent() {
+            Self::insert_folder(nodes, folder)?;
+        }
+        if matches!(nodes.get(key), Some(MemoryNode::Folder)) {
+            return Err(eyre!("Can't save to {key:?}: it is a folder"));
+        }
+        let node = MemoryNode::Value {
+            value,
+            modified: SystemTime::now(),
+            version: self.versions.fetch_add(1, Ordering::Relaxed),
+        };
+        nodes.insert(key.to_path_buf(), node);
+        Ok(())
+    }
+
+    fn insert_folder(nodes: &mut BTreeMap<PathBuf, MemoryNode>, folder: &Path) -> Result<()> {
+        for folder in folder.ancestors().filter(|f| !f.as_os_str().is_empty()) {
+            match nodes.get(folder) {
+                Some(MemoryNode::Folder) => break,
+                Some(MemoryNode::Value { .. }) => {
+                    return Err(eyre!("Can't create folder {folder:?}: it is a value"));
+                }
+                None => {
+                    nodes.insert(folder.to_path_buf(), MemoryNode::Folder);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// The keys below `folder`, in order, not including `folder` itself
+    fn below<'a>(
+        nodes: &'a BTreeMap<PathBuf, MemoryNode>,
+        folder: &'a Path,
+    ) -> impl Iterator<Item = (&'a PathBuf, &'a MemoryNode)> + 'a {
+        nodes
+            .range(folder.to_path_buf()..)
+            .skip_while(move |(key, _)| key.as_path() == folder)
+            .take_while(move |(key, _)| key.starts_with(folder))
+    }
+}
+
+#[async_trait]
+impl MailboxStorage for MemoryStorage {
+    async fn get(&self, key: &Path) -> Result<Option<Vec<u8>>> {
+        match self.nodes.lock().unwrap().get(key) {
+            None => Ok(None),
+            Some(MemoryNode::Folder) => Err(eyre!("Can't load from {key:?}: it is a folder")),
+            Some(MemoryNode::Value { value, .. }) => Ok(Some(value.clone())),
+        }
+    }
+    async fn put(&self, key: &Path, value: &[u8]) -> Result<()> {
+        let mut nodes = self.nodes.lock().unwrap();
+        self.insert(&mut nodes, key, value.to_vec())
+    }
+    async fn put_if_absent(&self, key: &Path, value: &[u8]) -> Result<bool> {
+        let mut nodes = self.nodes.lock().unwrap();
+        if nodes.contains_key(key) {
+            return Ok(false);
+        }
+        self.insert(&mut nodes, key, value.to_vec())?;
+        Ok(true)
+    }
+    async fn delete(&self, key: &Path) -> Result<()> {
+        let mut nodes = self.nodes.lock().unwrap();
+        if matches!(nodes.get(key), Some(MemoryNode::Folder)) {
+            return Err(eyre!("Can't remove {key:?}: it is a folder"));
+        }
+        nodes.remove(key);
+        Ok(())
+    }
+    async fn list(&self, folder: &Path) -> Result<Option<Vec<StorageEntry>>> {
+        let nodes = self.nodes.lock().unwrap();
+        match nodes.get(folder) {
+            None => return Ok(None),
+            Some(MemoryNode::Value { .. }) => {
+                return Err(eyre!("Can't list {folder:?}: it is a value"));
+            }
+            Some(MemoryNode::Folder) => {}
+        }
+        let entries = Self::below(&nodes, folder)
+            .filter(|(key, _)| key.parent() == Some(folder))
+            .map(|(key, node)| StorageEntry {
+                key: key.clone(),
+                is_folder: matches!(node, MemoryNode::Folder),
+            })
+            .collect();
+        Ok(Some(entries))
+    }
+
+    async fn exists(&self, key: &Path) -> Result<bool> {
+        Ok(self.nodes.lock().unwrap().contains_key(key))
+    }
+    async fn stat(&self, key: &Path) -> Result<Option<StorageStat>> {
+        match self.nodes.lock().unwrap().get(key) {
+            Some(MemoryNode::Value {
+                value,
+                modified,
+                version,
+            }) => Ok(Some(StorageStat {
+                len: value.len() as u64,
+                modified: Some(*modified),
+                version: *version,
+            })),
+            _ => Ok(None),
+        }
+    }
+    async fn append(&self, key: &Path, value: &[u8]) -> Result<()> {
+        let mut nodes = self.nodes.lock().unwrap();
+        let mut appended = match nodes.get(key) {
+            Some(MemoryNode::Value { value, .. }) => value.clone(),
+            _ => Vec::new(),
+        };
+        appended.extend_from_slice(value);
+        self.insert(&mut nodes, key, appended)
+    }
+    /// Note: Moves folders too, with everything in them.
+    async fn rename(&self, from: &Path, to: &Path) -> Result<bool> {
+        let mut nodes = self.nodes.lock().unwrap();
+        if !nodes.contains_key(from) {
+            return Ok(false);
+        }
+        let moved: Vec<PathBuf> = Self::below(&nodes, from)
+            .map(|(key, _)| key.clone())
+            .collect();
+        if let Some(folder) = to.parent() {
+            Self::insert_folder(&mut nodes, folder)?;
+        }
+        for key in std::iter::once(from.to_path_buf()).chain(moved) {
+            let node = nodes.remove(&key).expect("listed above");
+            let rest = key.strip_prefix(from).expect("below from");
+            nodes.insert(to.join(rest), node);
+        }
+        Ok(true)
+    }
+    async fn touch(&self, key: &Path, time: SystemTime) -> Result<()> {
+        match self.nodes.lock().unwrap().get_mut(key) {
+            Some(MemoryNode::Value { modified, .. }) => {
+                *modified = time;
+                Ok(())
+            }
+            _ => Err(eyre!("Can't touch {key:?}: no such value")),
+        }
+    }
+    async fn create_folder(&self, folder: &Path) -> Result<()> {
+        Self::insert_folder(&mut self.nodes.lock().unwrap(), folder)
+    }
+    async fn delete_folder(&self, folder: &Path) -> Result<bool> {
+        let mut nodes = self.nodes.lock().unwrap();
+        if !matches!(nodes.get(folder), Some(MemoryNode::Folder))
+            || Self::below(&nodes, folder).next().is_some()
+        {
+            return Ok(false);
+        }
+        nodes.remove(folder);
+        Ok(true)
+    }
+    async fn delete_all(&self, folder: &Path) -> Result<()> {
+        let mut nodes = self.nodes.lock().unwrap();
+        let removed: Vec<PathBuf> = Self::below(&nodes, folder)
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in removed {
+            nodes.remove(&key);
+        }
+        nodes.remove(folder);
+        Ok(())
+    }
+    fn undo(self: Arc<Self>, key: PathBuf, value: Option<Vec<u8>>) {
+        let mut nodes = self.nodes.lock().unwrap();
+        match value {
+            Some(value) => {
+                let _ = self.insert(&mut nodes, &key, value);
+            }
+            None => {
+                nodes.remove(&key);
+            }
+        }
+    }
+}
@@ -0,0 +1,188 @@
+use crate::MailboxDisk;
+use crate::MailboxItem;
+use crate::PollPolicy;
+use chrono::DateTime;
+use chrono::Utc;
+use color_eyre::eyre::Report;
+use color_eyre::eyre::Result;
+
+/// One item seen by [MailboxTail]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TailEntry {
+    pub item_id: String,
+    /// `None` for items stored before the time was kept
+    pub sent_at: Option<DateTime<Utc>>,
+    pub read: bool,
+    /// The serialized item
+    pub data: Vec<u8>,
+}
+
+impl TailEntry {
+    /// One line, e.g. `17 2024-06-01T12:00:00+00:00 5B "hello"`
+    ///
+    /// Shows the first `max_bytes` of the data, as text with `decode` if it is valid UTF-8, as hex otherwise.
+    pub fn summary(&self, decode: bool, max_bytes: usize) -> String {
+        let sent_at = self
+            .sent_at
+            .map(|t| t.to_rfc3339())
+            .unwrap_or_else(|| "-".to_string());
+        let head = &self.data[..self.data.len().min(max_bytes)];
+        let more = if head.len() < self.data.len() {
+            "…"
+        } else {
+            ""
+        };
+        let preview = match std::str::from_utf8(&self.data) {
+            Ok(text) if decode => {
+                let text: String = text.chars().take(max_bytes).collect();
+                format!("{text:?}{more}")
+            }
+            _ => {
+                let hex: Vec<String> = head.iter().map(|b| format!("{b:02x}")).collect();
+                format!("{}{more}", hex.join(" "))
+            }
+        };
+        let read = if self.read { " (read)" } else { "" };
+
+        format!(
+            "{} {sent_at} {}B{read} {preview}",
+            self.item_id,
+            self.data.len()
+        )
+    }
+}
+
+/// Follows a [MailboxDisk] like `tail -f`, without marking anything read
+///
+/// Starts with the unread items, or all items that were not compacted yet with `all`.
+#[derive(Debug)]
+pub struct MailboxTail<'a, ITEM: MailboxItem> {
+    mailbox: &'a MailboxDisk<ITEM>,
+    mailbox_id: String,
+    all: bool,
+    next_id: Option<u64>,
+}
+
+impl<'a, ITEM: MailboxItem> MailboxTail<'a, ITEM> {
+    pub fn new(mailbox: &'a MailboxDisk<ITEM>, mailbox_id: &str, all: bool) -> Self {
+        Self {
+            mailbox,
+            mailbox_id: mailbox_id.to_string(),
+            all,
+            next_id: None,
+        }
+    }
+
+    /// The items sent since the last poll
+    pub async fn poll(&mut self) -> Result<Vec<TailEntry>> {
+        let (entries, next_id) = self
+            .mailbox
+            .tail_entries(&self.mailbox_id, self.next_id, self.all)
+            .await?;
+        self.next_id = Some(next_id);
+
+        Ok(entries)
+    }
+
+    /// Polls forever, errors, e.g. for a deleted mailbox, are reported and retried
+    ///
+    /// Note: There are no wakeups, [PollPolicy::Notified] only backs off.
+    pub async fn follow(
+        &mut self,
+        poll: PollPolicy,
+        mut on_entry: impl FnMut(&TailEntry),
+        mut on_error: impl FnMut(&Report),
+    ) {
+        let mut waiter = poll.waiter();
+        loop {
+            match self.poll().await {
+                Ok(entries) if !entries.is_empty() => {
+                    entries.iter().for_each(&mut on_entry);
+                    waiter.reset();
+                }
+                Ok(_) => {}
+                Err(e) => on_error(&e),
+            }
+            waiter.wait().await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::Mailbox;
+    use crate::MailboxDisk;
+    use crate::MailboxError;
+    use crate::MailboxTail;
+    use crate::PollPolicy;
+    use crate::RawItem;
+    use color_eyre::Result;
+    use std::path::Path;
+    use std::time::Duration;
+    use tempfile::TempDir;
+
+    use test_log::test;
+
+    #[test(tokio::test)]
+    async fn it_follows_without_reading() -> Result<()> {
+        let dir = TempDir::new()?;
+        let mut mailbox = MailboxDisk::<RawItem>::at(dir.path(), Path::new("item"));
+        mailbox.ensure_storage_exists().await?;
+        for data in ["one", "two", "three"] {
+            mailbox.send("42", RawItem::new(data.into())).await?;
+        }
+        mailbox.pop("42").await?;
+
+        let mut unread = MailboxTail::new(&mailbox, "42", false);
+        let entries = unread.poll().await?;
+        let ids: Vec<&str> = entries.iter().map(|e| e.item_id.as_str()).collect();
+        assert_eq!(ids, vec!["2", "3"]);
+        assert!(entries[0].summary(true, 2).ends_with(r#" 3B "tw"…"#));
+        assert!(entries[0].summary(false, 3).ends_with(" 3B 74 77 6f"));
+
+        let mut all = MailboxTail::new(&mailbox, "42", true);
+        let entries = all.poll().await?;
+        assert_eq!(entries.len(), 3);
+        assert!(entries[0].read);
+        assert!(entries[0].summary(true, 10).contains("(read)"));
+
+        mailbox.send("42", RawItem::new("four".into())).await?;
+        let entries = unread.poll().await?;
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].data, b"four");
+        assert!(unread.poll().await?.is_empty());
+
+        let stats = mailbox.stats("42").await?;
+        assert_eq!(stats.pending, 3);
+        assert_eq!(mailbox.delivery_count("42", "2").await?, 0);
+
+        // the mailbox is deleted, and recreated
+        std::fs::remove_dir_all(dir.path().join("42"))?;
+        let e = unread.poll().await.expect_err("Deleted");
+        assert!(matches!(
+            e.downcast_ref::<MailboxError>(),
+            Some(MailboxError::UnknownMailbox { .. })
+        ));
+        let mut seen = Vec::new();
+        let mut errors = 0;
+        let follow = tokio::time::timeout(
+            Duration::from_millis(500),
+            unread.follow(
+                PollPolicy::Fixed(Duration::from_millis(10)),
+                |e| seen.push(e.clone()),
+                |_| errors += 1,
+            ),
+        );
+        let recreate = async {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            mailbox.send("42", RawItem::new("again".into())).await
+        };
+        let (_timeout, sent) = tokio::join!(follow, recreate);
+        assert_eq!(sent?, "1");
+        assert!(errors > 0);
+        assert_eq!(seen.len(), 1);
+        assert_eq!(seen[0].data, b"again");
+
+        Ok(())
+    }
+}
@@ -0,0 +1,95 @@
+use crate::MailboxKv;
+
+/// A mailbox on top of any [crate::MailboxStorage], for targets without a file system,
+/// e.g. wasm in the browser with a [crate::LocalStorageKvStore]
+///
+/// It is the same [MailboxKv] as [crate::MailboxDisk], with the same layout, see [MailboxKv::in_storage].
+/// Note: Like [crate::MailboxDisk] all operations take one lock, so only use one instance per store.
+pub type MailboxWasm<ITEM, S> = MailboxKv<ITEM, S>;
+
+#[cfg(test)]
+mod tests {
+    use crate::Mailbox;
+    use crate::MailboxError;
+    use crate::MailboxItem;
+    use crate::MailboxStorage;
+    use crate::MailboxWasm;
+    use crate::MemoryStorage;
+    use color_eyre::Result;
+    use std::path::Path;
+
+    use test_log::test;
+
+    #[derive(Default, Debug)]
+    struct TestItem {
+        data: String,
+    }
+
+    impl MailboxItem for TestItem {
+        fn serialize(&self) -> Result<Vec<u8>> {
+            Ok(self.data.clone().into())
+        }
+        fn deserialize(data: &[u8]) -> Result<Self>
+        where
+            Self: Sized,
+        {
+            Ok(Self {
+                data: String::from_utf8(data.to_vec())?,
+            })
+        }
+    }
+
+    #[test(tokio::test)]
+    async fn it_round_trips_through_the_store() -> Result<()> {
+        let store = MemoryStorage::new();
+        let in_store = |store: &MemoryStorage| {
+            MailboxWasm::<TestItem, _>::in_storage(
+                store.clone(),
+                Path::new("mailboxes"),
+                Path::new("item"),
+            )
+        };
+        let mut mailbox = in_store(&store);
+        mailbox.ensure_storage_exists().await?;
+        for data in ["one", "two", "three"] {
+            mailbox
+                .send(
+                    "42",
+                    TestItem {
+                        data: data.to_string(),
+                    },
+                )
+                .await?;
+        }
+
+        let (id, item) = mailbox.receive("42").await?.expect("Item pending");
+        assert_eq!((id.as_str(), item.data.as_str()), ("1", "one"));
+        assert_eq!(mailbox.delivery_count("42", "1").await?, 1);
+        mailbox.acknowledge("42", "1").await?;
+
+        let e = mailbox.acknowledge("42", "4").await.expect_err("No item 4");
+        assert!(matches!(
+            e.downcast_ref::<MailboxError>(),
+            Some(MailboxError::NotFound { .. })
+        ));
+
+        // everything lives in the store
+        let mailbox = in_store(&store);
+        assert_eq!(mailbox.list_mailboxes().await?, vec!["42".to_string()]);
+        let (id, item) = mailbox.pop("42").await?.expect("Item pending");
+        assert_eq!((id.as_str(), item.data.as_str()), ("2", "two"));
+
+        let stats = mailbox.stats("42").await?;
+        assert_eq!(
+            (stats.pending, stats.total_sent, stats.total_acknowledged),
+            (1, 3, 2)
+        );
+
+        assert_eq!(mailbox.compact("42").await?, 2);
+        let item_path = Path::new("mailboxes/42/1.item");
+        assert!(store.get(item_path).await?.is_none());
+        assert!(store.get(Path::new("mailboxes/42/3.item")).await?.is_some());
+
+        Ok(())
+    }
+}
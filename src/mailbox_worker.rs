@@ -0,0 +1,498 @@
+use crate::ClosedReason;
+use crate::Mailbox;
+use crate::MailboxError;
+use crate::MailboxItem;
+use crate::PollPolicy;
+use crate::ReceiveStatus;
+use crate::RetryPolicy;
+use color_eyre::eyre::eyre;
+use color_eyre::eyre::Result;
+use core::future::Future;
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+use tokio::task::JoinSet;
+
+/// What a [MailboxWorker] does, when a handler panics
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PanicPolicy {
+    /// Treat the panic like a failed handler, the worker keeps going
+    #[default]
+    KeepAlive,
+    /// Stop the worker, [WorkerHandle::shutdown] returns the panic as error
+    Stop,
+}
+
+/// How a [MailboxWorker] receives, and retries
+#[derive(Debug, Clone)]
+pub struct WorkerOptions {
+    /// Items handled at the same time, received together with `receive_many`
+    pub concurrency: usize,
+    /// How often an empty mailbox is checked again
+    pub poll: PollPolicy,
+    /// How often a failing item is handled, and the backoff in between
+    ///
+    /// [RetryPolicy::retry_send] is not used.
+    pub retry: RetryPolicy,
+    pub panic_policy: PanicPolicy,
+}
+
+impl Default for WorkerOptions {
+    fn default() -> Self {
+        Self {
+            concurrency: 1,
+            poll: PollPolicy::default(),
+            retry: RetryPolicy::default(),
+            panic_policy: PanicPolicy::default(),
+        }
+    }
+}
+
+/// What a [MailboxWorker] did, until it was shut down
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct WorkerSummary {
+    /// Items handled, and acknowledged
+    pub handled: u64,
+    /// Failed, or panicked, handler calls
+    pub failed: u64,
+    /// Handled items that could not be acknowledged, they are handled again
+    pub unacknowledged: u64,
+    /// Items that failed [RetryPolicy::max_attempts] times, they are left unacknowledged
+    pub abandoned: Vec<String>,
+    /// Why the worker stopped on its own, `None` if it was shut down
+    pub closed: Option<ClosedReason>,
+}
+
+/// Runs a handler for every item of a mailbox, and acknowledges the item if the handler succeeds
+///
+/// ```no_run
+/// # use oml_mailbox::{MailboxMemory, MailboxWorker, WorkerOptions};
+/// # use std::sync::Arc;
+/// # #[derive(Debug, Default)]
+/// # struct Job;
+/// # impl oml_mailbox::MailboxItem for Job {
+/// #     fn serialize(&self) -> color_eyre::eyre::Result<Vec<u8>> { Ok(Vec::new()) }
+/// #     fn deserialize(_data: &[u8]) -> color_eyre::eyre::Result<Self> { Ok(Job) }
+/// # }
+/// # async fn run() -> color_eyre::eyre::Result<()> {
+/// let mailbox = Arc::new(MailboxMemory::<Job>::new());
+/// let worker = MailboxWorker::spawn(
+///     mailbox,
+///     "jobs".to_string(),
+///     |_item_id, _job| async move { Ok(()) },
+///     WorkerOptions::default(),
+/// );
+/// // ...
+/// let summary = worker.shutdown().await?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug)]
+pub struct MailboxWorker;
+
+impl MailboxWorker {
+    /// Receive and handle items until [WorkerHandle::shutdown] is called, or the mailbox is closed
+    ///
+    /// An empty batch is checked with `receive_status`, a [ReceiveStatus::Closed] mailbox stops the worker,
+    /// see [WorkerSummary::closed].
+    /// Note: [ClosedReason::Deleted] only stops the worker once it saw the mailbox,
+    /// so it can be started before the first send.
+    ///
+    /// Failed items are received again after a backoff, until they are abandoned,
+    /// abandoned items are skipped until the worker is restarted.
+    ///
+    /// Note: Items are handled in batches, the next batch is only received once the whole batch is done.
+    /// With the default `receive_many` only one item is handled at a time.
+    pub fn spawn<ITEM, H, Fut>(
+        mailbox: Arc<dyn Mailbox<ITEM>>,
+        mailbox_id: String,
+        handler: H,
+        opts: WorkerOptions,
+    ) -> WorkerHandle
+    where
+        ITEM: MailboxItem + Send + 'static,
+        H: Fn(String, ITEM) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<()>> + Send + 'static,
+    {
+        let (shutdown, _) = watch::channel(false);
+        let worker = Worker {
+            mailbox,
+            mailbox_id,
+            handler: Arc::new(handler),
+            opts,
+            shutdown: shutdown.subscribe(),
+            attempts: HashMap::new(),
+            abandoned: HashSet::new(),
+            seen: false,
+            summary: WorkerSummary::default(),
+        };
+        WorkerHandle {
+            shutdown,
+            task: tokio::spawn(worker.run()),
+        }
+    }
+}
+
+/// Controls a worker started by [MailboxWorker::spawn]
+#[derive(Debug)]
+pub struct WorkerHandle {
+    shutdown: watch::Sender<bool>,
+    task: JoinHandle<Result<WorkerSummary>>,
+}
+
+impl WorkerHandle {
+    /// Stop the worker, after the handlers in flight are done
+    pub async fn shutdown(self) -> Result<WorkerSummary> {
+        self.shutdown.send_replace(true);
+        self.task.await?
+    }
+
+    /// If the worker stopped on its own, e.g. with [PanicPolicy::Stop], or on a closed mailbox
+    pub fn is_finished(&self) -> bool {
+        self.task.is_finished()
+    }
+}
+
+struct Worker<ITEM: MailboxItem, H> {
+    mailbox: Arc<dyn Mailbox<ITEM>>,
+    mailbox_id: String,
+    handler: Arc<H>,
+    opts: WorkerOptions,
+    shutdown: watch::Receiver<bool>,
+    /// Failed attempts of the items not abandoned yet
+    attempts: HashMap<String, u32>,
+    abandoned: HashSet<String>,
+    /// If the mailbox existed at some point, see [ClosedReason::Deleted]
+    seen: bool,
+    summary: WorkerSummary,
+}
+
+impl<ITEM, H, Fut> Worker<ITEM, H>
+where
+    ITEM: MailboxItem + Send + 'static,
+    H: Fn(String, ITEM) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = Result<()>> + Send + 'static,
+{
+    async fn run(mut self) -> Result<WorkerSummary> {
+        let mut receive_errors = 0;
+        let mut idle = self.opts.poll.waiter();
+        while !*self.shutdown.borrow() {
+            // Note: abandoned items are the oldest unread ones, so they come first
+            let max = self.opts.concurrency.max(1) + self.abandoned.len();
+            let items = match self.mailbox.receive_many(&self.mailbox_id, max).await {
+                Ok(items) => {
+                    receive_errors = 0;
+                    items
+                }
+                Err(e) if ClosedReason::of(&e).is_some_and(|reason| self.closed(reason)) => break,
+                Err(e) => {
+                    receive_errors += 1;
+                    let delay = self.opts.retry.delay(receive_errors);
+                    tracing::warn!("Receiving from {} failed -> {e:?}", self.mailbox_id);
+                    self.sleep(delay).await;
+                    continue;
+                }
+            };
+            let received = !items.is_empty();
+            self.seen |= received;
+            let mut batch: Vec<(String, ITEM)> = items
+                .into_iter()
+                .filter(|(item_id, _)| !self.abandoned.contains(item_id))
+                .take(self.opts.concurrency.max(1))
+                .collect();
+            if !received {
+                match self.mailbox.receive_status(&self.mailbox_id).await {
+                    Ok(ReceiveStatus::Received(item_id, item)) => {
+                        self.seen = true;
+                        if !self.abandoned.contains(&item_id) {
+                            batch.push((item_id, item));
+                        }
+                    }
+                    Ok(ReceiveStatus::Empty) => self.seen = true,
+                    Ok(ReceiveStatus::Closed(reason)) if self.closed(reason) => break,
+                    Ok(ReceiveStatus::Closed(_)) => {}
+                    Err(e) => {
+                        tracing::warn!("Checking {} failed -> {e:?}", self.mailbox_id);
+                    }
+                }
+            }
+            if batch.is_empty() {
+                self.sleep(idle.next_delay()).await;
+                continue;
+            }
+            idle.reset();
+            let retry = self.handle(batch).await?;
+            if retry > 0 {
+                self.sleep(self.opts.retry.delay(retry)).await;
+            }
+        }
+        tracing::debug!("Worker for {} stopped", self.mailbox_id);
+
+        Ok(self.summary)
+    }
+
+    /// Records why the worker stops, false if it keeps going
+    fn closed(&mut self, reason: ClosedReason) -> bool {
+        if reason == ClosedReason::Deleted && !self.seen {
+            return false;
+        }
+        tracing::info!(
+            "Stopping worker for {}, closed -> {reason:?}",
+            self.mailbox_id
+        );
+        self.summary.closed = Some(reason);
+        true
+    }
+
+    /// Handles all items, returns the highest retry coming up, zero if none
+    async fn handle(&mut self, batch: Vec<(String, ITEM)>) -> Result<u32> {
+        let mut running = JoinSet::new();
+        for (item_id, item) in batch {
+            let handler = self.handler.clone();
+            let id = item_id.clone();
+            // Note: a separate task, so a panic ends up in its JoinError, even when calling the handler panics
+            let call = tokio::spawn(async move { handler(id, item).await });
+            running.spawn(async move { (item_id, call.await) });
+        }
+        let mut retry = 0;
+        while let Some(done) = running.join_next().await {
+            let (item_id, r) = done?;
+            let e = match r {
+                Ok(Ok(())) => {
+                    match self.mailbox.acknowledge(&self.mailbox_id, &item_id).await {
+                        Ok(()) => {}
+                        // Note: at least once, the item is received, and handled, again
+                        Err(e) if MailboxError::is_retryable_report(&e) => {
+                            tracing::warn!(
+                                "Acknowledging item {item_id} in {} failed -> {e:?}",
+                                self.mailbox_id
+                            );
+                            self.summary.unacknowledged += 1;
+                            continue;
+                        }
+                        Err(e) => return Err(e),
+                    }
+                    self.attempts.remove(&item_id);
+                    self.summary.handled += 1;
+                    continue;
+                }
+                Ok(Err(e)) => e,
+                Err(e) if e.is_panic() && self.opts.panic_policy == PanicPolicy::Stop => {
+                    running.shutdown().await;
+                    return Err(eyre!("Handler panicked on item {item_id} -> {e}"));
+                }
+                Err(e) => eyre!("Handler panicked -> {e}"),
+            };
+            self.summary.failed += 1;
+            let attempts = self.attempts.entry(item_id.clone()).or_default();
+            *attempts += 1;
+            if *attempts >= self.opts.retry.max_attempts {
+                tracing::error!("Abandoning item {item_id} in {} -> {e:?}", self.mailbox_id);
+                self.attempts.remove(&item_id);
+                self.abandoned.insert(item_id.clone());
+                self.summary.abandoned.push(item_id);
+            } else {
+                tracing::warn!(
+                    "Handling item {item_id} in {} failed (attempt {attempts}) -> {e:?}",
+                    self.mailbox_id
+                );
+                retry = retry.max(*attempts);
+            }
+        }
+
+        Ok(retry)
+    }
+
+    /// Sleeps, unless the worker is shut down first
+    async fn sleep(&mut self, duration: Duration) {
+        tokio::select! {
+            _ = tokio::time::sleep(duration) => {}
+            _ = self.shutdown.changed() => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::ClosedReason;
+    use crate::FreezeMode;
+    use crate::Mailbox;
+    use crate::MailboxDisk;
+    use crate::MailboxItem;
+    use crate::MailboxWorker;
+    use crate::PanicPolicy;
+    use crate::PollPolicy;
+    use crate::RetryPolicy;
+    use crate::WorkerOptions;
+    use color_eyre::eyre::eyre;
+    use color_eyre::Result;
+    use std::path::Path;
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::atomic::Ordering;
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tempfile::TempDir;
+
+    use test_log::test;
+
+    #[derive(Debug, Default)]
+    struct TestItem {
+        data: String,
+    }
+
+    impl MailboxItem for TestItem {
+        fn serialize(&self) -> Result<Vec<u8>> {
+            Ok(self.data.clone().into())
+        }
+        fn deserialize(data: &[u8]) -> Result<Self>
+        where
+            Self: Sized,
+        {
+            Ok(Self {
+                data: String::from_utf8(data.to_vec())?,
+            })
+        }
+    }
+
+    fn options() -> WorkerOptions {
+        WorkerOptions {
+            concurrency: 4,
+            poll: PollPolicy::Fixed(Duration::from_millis(10)),
+            retry: RetryPolicy {
+                max_attempts: 2,
+                base_delay: Duration::from_millis(10),
+                jitter: 0.0,
+                ..Default::default()
+            },
+            ..Default::default()
+        }
+    }
+
+    async fn wait_for_pending(mailbox: &MailboxDisk<TestItem>, pending: u64) -> Result<()> {
+        for _ in 0..500 {
+            if mailbox.stats("42").await?.pending == pending {
+                return Ok(());
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        Err(eyre!("Mailbox never got to {pending} pending items"))
+    }
+
+    #[test(tokio::test)]
+    async fn it_acknowledges_handled_items() -> Result<()> {
+        let dir = TempDir::new()?;
+        let mut disk = MailboxDisk::<TestItem>::at(dir.path(), Path::new("item"));
+        disk.ensure_storage_exists().await?;
+        let mailbox = Arc::new(disk);
+        for i in 1..=20 {
+            let data = format!("{i}");
+            mailbox.send("42", TestItem { data }).await?;
+        }
+
+        let calls = Arc::new(AtomicUsize::new(0));
+        let counted = calls.clone();
+        let worker = MailboxWorker::spawn(
+            mailbox.clone(),
+            "42".to_string(),
+            move |_item_id, item: TestItem| {
+                counted.fetch_add(1, Ordering::Relaxed);
+                async move {
+                    match item.data.as_str() {
+                        "7" => Err(eyre!("Item 7 is broken")),
+                        _ => Ok(()),
+                    }
+                }
+            },
+            options(),
+        );
+        wait_for_pending(&mailbox, 1).await?;
+        let summary = worker.shutdown().await?;
+
+        assert_eq!(summary.handled, 19);
+        assert_eq!(summary.failed, 2);
+        assert_eq!(summary.abandoned, ["7"]);
+        assert_eq!(calls.load(Ordering::Relaxed), 21);
+        let (item_id, _item) = mailbox.peek("42").await?.expect("Item pending");
+        assert_eq!(item_id, "7");
+
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn it_survives_panicking_handlers() -> Result<()> {
+        let dir = TempDir::new()?;
+        let mut disk = MailboxDisk::<TestItem>::at(dir.path(), Path::new("item"));
+        disk.ensure_storage_exists().await?;
+        let mailbox = Arc::new(disk);
+        for data in ["ok", "panic", "ok"] {
+            let data = data.to_string();
+            mailbox.send("42", TestItem { data }).await?;
+        }
+        let handler = |_item_id, item: TestItem| async move {
+            assert_ne!(item.data, "panic", "Handler panics");
+            Ok(())
+        };
+
+        // Note: one at a time, so the last item is only handled after the panicking one is abandoned
+        let opts = WorkerOptions {
+            concurrency: 1,
+            ..options()
+        };
+        let worker = MailboxWorker::spawn(mailbox.clone(), "42".to_string(), handler, opts);
+        wait_for_pending(&mailbox, 1).await?;
+        let summary = worker.shutdown().await?;
+        assert_eq!((summary.handled, summary.abandoned.len()), (2, 1));
+
+        let opts = WorkerOptions {
+            panic_policy: PanicPolicy::Stop,
+            ..options()
+        };
+        let worker = MailboxWorker::spawn(mailbox.clone(), "42".to_string(), handler, opts);
+        while !worker.is_finished() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert!(worker.shutdown().await.is_err());
+        assert_eq!(mailbox.stats("42").await?.pending, 1);
+
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn it_stops_when_the_mailbox_is_closed() -> Result<()> {
+        let dir = TempDir::new()?;
+        let mut disk = MailboxDisk::<TestItem>::at(dir.path(), Path::new("item"));
+        disk.ensure_storage_exists().await?;
+        let mailbox = Arc::new(disk);
+        let handler = |_item_id, _item: TestItem| async move { Ok(()) };
+
+        // Note: started before the mailbox exists, so it waits for the first send
+        let worker = MailboxWorker::spawn(mailbox.clone(), "42".to_string(), handler, options());
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!worker.is_finished());
+        for data in ["a", "b"] {
+            let data = data.to_string();
+            mailbox.send("42", TestItem { data }).await?;
+        }
+        wait_for_pending(&mailbox, 0).await?;
+        mailbox.purge_prefix("42").await?;
+        while !worker.is_finished() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        let summary = worker.shutdown().await?;
+        assert_eq!(summary.handled, 2);
+        assert_eq!(summary.closed, Some(ClosedReason::Deleted));
+
+        mailbox.create_mailbox("42").await?;
+        let worker = MailboxWorker::spawn(mailbox.clone(), "42".to_string(), handler, options());
+        mailbox.set_frozen("42", FreezeMode::NoReceive).await?;
+        while !worker.is_finished() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(worker.shutdown().await?.closed, Some(ClosedReason::Frozen));
+
+        Ok(())
+    }
+}
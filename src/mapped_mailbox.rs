@@ -0,0 +1,378 @@
+use crate::ExpireReport;
+use crate::HeaderSelector;
+use crate::HealthReport;
+use crate::ItemGuard;
+use crate::ItemSummary;
+use crate::Mailbox;
+use crate::MailboxError;
+use crate::MailboxItem;
+use crate::MailboxStats;
+use crate::Page;
+use async_trait::async_trait;
+use color_eyre::eyre::Result;
+use std::time::Duration;
+
+type Convert<FROM, TO> = Box<dyn Fn(FROM) -> Result<TO> + Send + Sync>;
+
+/// Adds [MailboxExt::map] and [MailboxExt::receive_guarded] to every mailbox
+pub trait MailboxExt<A: MailboxItem>: Mailbox<A> + Sized {
+    /// Use the mailbox for items of type `B`, converted from and to the `A` items it stores
+    fn map<B: MailboxItem>(
+        self,
+        to_inner: impl Fn(B) -> Result<A> + Send + Sync + 'static,
+        from_inner: impl Fn(A) -> Result<B> + Send + Sync + 'static,
+    ) -> MappedMailbox<A, B, Self> {
+        MappedMailbox::new(self, to_inner, from_inner)
+    }
+
+    /// Like `receive`, but the item comes in an [ItemGuard], that warns if it is dropped without `ack` or `reject`
+    fn receive_guarded(
+        &self,
+        id: &str,
+    ) -> impl std::future::Future<Output = Result<Option<ItemGuard<'_, A>>>> + Send
+    where
+        A: 'static,
+    {
+        async move {
+            let guard = self
+                .receive(id)
+                .await?
+                .map(|(item_id, item)| ItemGuard::new(self, id, item_id, item));
+            Ok(guard)
+        }
+    }
+}
+
+impl<A: MailboxItem, M: Mailbox<A>> MailboxExt<A> for M {}
+
+/// A `Mailbox<B>` on top of a `Mailbox<A>`, converting items on the way in, and on the way out
+///
+/// Items that fail to convert on the way out are reported as [MailboxError::Conversion],
+/// and stay unacknowledged, so they can still be received as `A`.
+///
+/// Note: Headers are the ones of the stored `A` items, `receive_matching` matches against them.
+pub struct MappedMailbox<A: MailboxItem, B: MailboxItem, M: Mailbox<A>> {
+    inner: M,
+    to_inner: Convert<B, A>,
+    from_inner: Convert<A, B>,
+}
+
+impl<A: MailboxItem, B: MailboxItem, M: Mailbox<A>> MappedMailbox<A, B, M> {
+    pub fn new(
+        inner: M,
+        to_inner: impl Fn(B) -> Result<A> + Send + Sync + 'static,
+        from_inner: impl Fn(A) -> Result<B> + Send + Sync + 'static,
+    ) -> Self {
+        Self {
+            inner,
+            to_inner: Box::new(to_inner),
+            from_inner: Box::new(from_inner),
+        }
+    }
+
+    pub fn inner(&self) -> &M {
+        &self.inner
+    }
+
+    pub fn into_inner(self) -> M {
+        self.inner
+    }
+
+    fn convert(&self, mailbox_id: &str, item_id: &str, item: A) -> Result<B> {
+        (self.from_inner)(item).map_err(|e| {
+            MailboxError::Conversion {
+                mailbox_id: mailbox_id.to_string(),
+                item_id: item_id.to_string(),
+                reason: format!("{e:#}"),
+            }
+            .into()
+        })
+    }
+
+    fn convert_all(&self, mailbox_id: &str, items: Vec<(String, A)>) -> Result<Vec<(String, B)>> {
+        items
+            .into_iter()
+            .map(|(item_id, item)| {
+                let item = self.convert(mailbox_id, &item_id, item)?;
+                Ok((item_id, item))
+            })
+            .collect()
+    }
+
+    fn convert_one(
+        &self,
+        mailbox_id: &str,
+        received: Option<(String, A)>,
+    ) -> Result<Option<(String, B)>> {
+        match received {
+            Some((item_id, item)) => {
+                let item = self.convert(mailbox_id, &item_id, item)?;
+                Ok(Some((item_id, item)))
+            }
+            None => Ok(None),
+        }
+    }
+}
+
+impl<A: MailboxItem, B: MailboxItem, M: Mailbox<A>> std::fmt::Debug for MappedMailbox<A, B, M> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("MappedMailbox")
+            .field("inner", &self.inner)
+            .finish_non_exhaustive()
+    }
+}
+
+#[async_trait]
+impl<A: MailboxItem + Send, B: MailboxItem + Send, M: Mailbox<A>> Mailbox<B>
+    for MappedMailbox<A, B, M>
+{
+    async fn ensure_storage_exists(&mut self) -> Result<()> {
+        self.inner.ensure_storage_exists().await
+    }
+    async fn close(&mut self) -> Result<()> {
+        self.inner.close().await
+    }
+
+    async fn create_mailbox(&self, id: &str) -> Result<()> {
+        self.inner.create_mailbox(id).await
+    }
+    async fn send(&self, id: &str, item: B) -> Result<String> {
+        let item = (self.to_inner)(item)?;
+        self.inner.send(id, item).await
+    }
+    async fn send_transaction(&self, id: &str, items: Vec<B>) -> Result<Vec<String>> {
+        let items = items
+            .into_iter()
+            .map(&self.to_inner)
+            .collect::<Result<Vec<_>>>()?;
+        self.inner.send_transaction(id, items).await
+    }
+    async fn receive(&self, id: &str) -> Result<Option<(String, B)>> {
+        let received = self.inner.receive(id).await?;
+        self.convert_one(id, received)
+    }
+    async fn receive_any(&self, ids: &[String]) -> Result<Option<(String, String, B)>> {
+        match self.inner.receive_any(ids).await? {
+            Some((id, item_id, item)) => {
+                let item = self.convert(&id, &item_id, item)?;
+                Ok(Some((id, item_id, item)))
+            }
+            None => Ok(None),
+        }
+    }
+    async fn acknowledge(&self, id: &str, item_id: &str) -> Result<()> {
+        self.inner.acknowledge(id, item_id).await
+    }
+    async fn acknowledge_through(&self, id: &str, item_id: &str) -> Result<u64> {
+        self.inner.acknowledge_through(id, item_id).await
+    }
+    async fn release(&self, id: &str, item_id: &str) -> Result<()> {
+        self.inner.release(id, item_id).await
+    }
+    fn release_later(&self, id: &str, item_id: &str) {
+        self.inner.release_later(id, item_id)
+    }
+    async fn update(&self, id: &str, item_id: &str, item: B) -> Result<()> {
+        let item = (self.to_inner)(item)?;
+        self.inner.update(id, item_id, item).await
+    }
+    async fn peek(&self, id: &str) -> Result<Option<(String, B)>> {
+        let peeked = self.inner.peek(id).await?;
+        self.convert_one(id, peeked)
+    }
+    async fn receive_many(&self, id: &str, max: usize) -> Result<Vec<(String, B)>> {
+        let items = self.inner.receive_many(id, max).await?;
+        self.convert_all(id, items)
+    }
+    async fn receive_batch(
+        &self,
+        id: &str,
+        max_items: usize,
+        max_wait: Duration,
+    ) -> Result<Vec<(String, B)>> {
+        let items = self.inner.receive_batch(id, max_items, max_wait).await?;
+        self.convert_all(id, items)
+    }
+    async fn receive_matching(
+        &self,
+        id: &str,
+        selector: &HeaderSelector,
+    ) -> Result<Option<(String, B)>> {
+        let received = self.inner.receive_matching(id, selector).await?;
+        self.convert_one(id, received)
+    }
+    async fn list_mailboxes(&self) -> Result<Vec<String>> {
+        self.inner.list_mailboxes().await
+    }
+    async fn list_mailboxes_page(&self, after: Option<&str>, limit: usize) -> Result<Page<String>> {
+        self.inner.list_mailboxes_page(after, limit).await
+    }
+    async fn delivery_count(&self, id: &str, item_id: &str) -> Result<u32> {
+        self.inner.delivery_count(id, item_id).await
+    }
+    /// Note: The sizes are the sizes of the stored `A` items.
+    async fn list_items_page(
+        &self,
+        id: &str,
+        after: Option<&str>,
+        limit: usize,
+    ) -> Result<Page<ItemSummary>> {
+        self.inner.list_items_page(id, after, limit).await
+    }
+    /// Note: The size of the stored `A` item.
+    async fn item_size(&self, id: &str, item_id: &str) -> Result<u64> {
+        self.inner.item_size(id, item_id).await
+    }
+    async fn stats(&self, id: &str) -> Result<MailboxStats> {
+        self.inner.stats(id).await
+    }
+    async fn receive_for(&self, id: &str, group: &str) -> Result<Option<(String, B)>> {
+        let received = self.inner.receive_for(id, group).await?;
+        self.convert_one(id, received)
+    }
+    async fn acknowledge_for(&self, id: &str, group: &str, item_id: &str) -> Result<()> {
+        self.inner.acknowledge_for(id, group, item_id).await
+    }
+    async fn compact(&self, id: &str) -> Result<u64> {
+        self.inner.compact(id).await
+    }
+    async fn drop_older_than(&self, id: &str, max_age: Duration) -> Result<u64> {
+        self.inner.drop_older_than(id, max_age).await
+    }
+    async fn health_check(&self) -> Result<HealthReport> {
+        self.inner.health_check().await
+    }
+    async fn expire_items(&self, id: &str) -> Result<ExpireReport> {
+        self.inner.expire_items(id).await
+    }
+    // Note: `pop` and `drain` use the default implementations,
+    // since the inner ones would acknowledge items before we know they convert.
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::Mailbox;
+    use crate::MailboxDisk;
+    use crate::MailboxError;
+    use crate::MailboxExt;
+    use crate::MailboxItem;
+    use crate::MailboxMemory;
+    use color_eyre::eyre::eyre;
+    use color_eyre::Result;
+    use serde::Deserialize;
+    use serde::Serialize;
+    use std::path::Path;
+    use tempfile::TempDir;
+
+    use test_log::test;
+
+    /// The transport item, with a JSON body of the type named by `kind`
+    #[derive(Default, Debug, Serialize, Deserialize)]
+    struct RawEvent {
+        kind: String,
+        body: String,
+    }
+
+    impl MailboxItem for RawEvent {
+        fn serialize(&self) -> Result<Vec<u8>> {
+            Ok(serde_json::to_vec(self)?)
+        }
+        fn deserialize(data: &[u8]) -> Result<Self>
+        where
+            Self: Sized,
+        {
+            Ok(serde_json::from_slice(data)?)
+        }
+    }
+
+    #[derive(Default, Debug, PartialEq, Serialize, Deserialize)]
+    struct Greeting {
+        name: String,
+    }
+
+    impl MailboxItem for Greeting {
+        fn serialize(&self) -> Result<Vec<u8>> {
+            Ok(serde_json::to_vec(self)?)
+        }
+        fn deserialize(data: &[u8]) -> Result<Self>
+        where
+            Self: Sized,
+        {
+            Ok(serde_json::from_slice(data)?)
+        }
+    }
+
+    fn to_event(greeting: Greeting) -> Result<RawEvent> {
+        Ok(RawEvent {
+            kind: "greeting".to_string(),
+            body: serde_json::to_string(&greeting)?,
+        })
+    }
+
+    fn from_event(event: RawEvent) -> Result<Greeting> {
+        if event.kind != "greeting" {
+            return Err(eyre!("Not a greeting, but {:?}", event.kind));
+        }
+        Ok(serde_json::from_str(&event.body)?)
+    }
+
+    fn greeting(name: &str) -> Greeting {
+        Greeting {
+            name: name.to_string(),
+        }
+    }
+
+    #[test(tokio::test)]
+    async fn it_round_trips_typed_items() -> Result<()> {
+        let mailbox: Box<dyn Mailbox<Greeting>> =
+            Box::new(MailboxMemory::<RawEvent>::new().map(to_event, from_event));
+        mailbox.send("42", greeting("alice")).await?;
+        mailbox
+            .send_transaction("42", vec![greeting("bob"), greeting("carol")])
+            .await?;
+
+        let (_id, alice) = mailbox.pop("42").await?.expect("Item pending");
+        assert_eq!(alice, greeting("alice"));
+        let names: Vec<String> = mailbox
+            .drain("42", None)
+            .await?
+            .into_iter()
+            .map(|(_id, greeting)| greeting.name)
+            .collect();
+        assert_eq!(names, ["bob", "carol"]);
+
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn it_leaves_items_that_fail_to_convert() -> Result<()> {
+        let dir = TempDir::new()?;
+        let mut disk = MailboxDisk::<RawEvent>::at(dir.path(), Path::new("event"));
+        disk.ensure_storage_exists().await?;
+        let mailbox = disk.map(to_event, from_event);
+        let event = RawEvent {
+            kind: "farewell".to_string(),
+            body: "{}".to_string(),
+        };
+        let item_id = mailbox.inner().send("42", event).await?;
+
+        let e = mailbox.receive("42").await.expect_err("Wrong kind");
+        match e.downcast_ref::<MailboxError>() {
+            Some(MailboxError::Conversion {
+                mailbox_id,
+                item_id: failed,
+                reason,
+            }) => {
+                assert_eq!((mailbox_id.as_str(), failed), ("42", &item_id));
+                assert!(reason.contains("farewell"));
+            }
+            _ => panic!("Unexpected error {e:?}"),
+        }
+        assert!(mailbox.pop("42").await.is_err());
+
+        let (raw_id, raw) = mailbox.inner().pop("42").await?.expect("Item pending");
+        assert_eq!((raw_id, raw.kind.as_str()), (item_id, "farewell"));
+
+        Ok(())
+    }
+}
@@ -0,0 +1,287 @@
+use crate::ItemSummary;
+use crate::Mailbox;
+use crate::MailboxItem;
+use color_eyre::eyre::eyre;
+use color_eyre::eyre::Result;
+use std::collections::HashMap;
+
+/// The consumer group [migrate_mailbox] reads the history of the source with
+pub const MIGRATE_GROUP: &str = "migrate";
+
+/// How [migrate_mailbox] moves a mailbox
+#[derive(Debug, Clone, Default)]
+pub struct MigrateOptions {
+    /// Only report what would be moved, without touching either mailbox
+    pub dry_run: bool,
+    /// Also copy the read items, they are acknowledged in the destination right away
+    ///
+    /// Note: The source only hands out read items to a consumer group,
+    /// so this leaves the [MIGRATE_GROUP] cursor behind in the source.
+    pub include_read: bool,
+    /// Acknowledge the unread items in the source, once the destination has all of them
+    pub drain_source: bool,
+}
+
+/// One item moved by [migrate_mailbox]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MigratedItem {
+    pub old_id: String,
+    /// `None` for a dry run
+    pub new_id: Option<String>,
+    pub read: bool,
+}
+
+/// What [migrate_mailbox] did, or would have done for a dry run
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MigrateReport {
+    /// In the order they were sent to the destination
+    pub items: Vec<MigratedItem>,
+    /// Unread items copied
+    pub pending: u64,
+    /// Read items copied, see [MigrateOptions::include_read]
+    pub read: u64,
+    /// Items acknowledged in the source, see [MigrateOptions::drain_source]
+    pub drained: u64,
+}
+
+impl MigrateReport {
+    /// The new id of every moved item, by its old id
+    pub fn id_map(&self) -> HashMap<&str, &str> {
+        self.items
+            .iter()
+            .filter_map(|i| Some((i.old_id.as_str(), i.new_id.as_deref()?)))
+            .collect()
+    }
+}
+
+/// Copies the items of a mailbox to another backend, in order
+///
+/// Unread items are received from the source, so their delivery count goes up,
+/// but unless [MigrateOptions::drain_source] is set they stay unread.
+/// The items get new ids, see [MigrateReport::id_map].
+/// Fails if the pending items of the destination didn't grow by the number of unread items copied.
+///
+/// Note: Nothing must be sent to, or received from, the source while migrating.
+pub async fn migrate_mailbox<ITEM: MailboxItem>(
+    source: &dyn Mailbox<ITEM>,
+    dest: &dyn Mailbox<ITEM>,
+    mailbox_id: &str,
+    opts: MigrateOptions,
+) -> Result<MigrateReport> {
+    let listed = list_items(source, mailbox_id).await?;
+    let unread: Vec<&str> = listed
+        .iter()
+        .filter(|i| !i.read)
+        .map(|i| i.item_id.as_str())
+        .collect();
+
+    let mut report = MigrateReport::default();
+    if opts.dry_run {
+        for i in listed.iter().filter(|i| opts.include_read || !i.read) {
+            report.items.push(MigratedItem {
+                old_id: i.item_id.clone(),
+                new_id: None,
+                read: i.read,
+            });
+        }
+        report.pending = unread.len() as u64;
+        report.read = report.items.len() as u64 - report.pending;
+        return Ok(report);
+    }
+
+    let items = if opts.include_read {
+        let read: HashMap<&str, bool> = listed
+            .iter()
+            .map(|i| (i.item_id.as_str(), i.read))
+            .collect();
+        let mut items = Vec::new();
+        while let Some((item_id, item)) = source.receive_for(mailbox_id, MIGRATE_GROUP).await? {
+            source
+                .acknowledge_for(mailbox_id, MIGRATE_GROUP, &item_id)
+                .await?;
+            // Note: items sent after listing are left for the next migration
+            if let Some(read) = read.get(item_id.as_str()) {
+                items.push((item_id, item, *read));
+            }
+        }
+        items
+    } else {
+        source
+            .receive_many(mailbox_id, unread.len())
+            .await?
+            .into_iter()
+            .map(|(item_id, item)| (item_id, item, false))
+            .collect()
+    };
+    let copied_unread: Vec<&str> = items
+        .iter()
+        .filter(|(_, _, read)| !read)
+        .map(|(item_id, _, _)| item_id.as_str())
+        .collect();
+    if copied_unread != unread {
+        return Err(eyre!(
+            "Unread items of mailbox {mailbox_id} changed while migrating, expected {unread:?}, got {copied_unread:?}"
+        ));
+    }
+
+    dest.create_mailbox(mailbox_id).await?;
+    let pending_before = dest.stats(mailbox_id).await?.pending;
+    for (old_id, item, read) in items {
+        let new_id = dest.send(mailbox_id, item).await?;
+        if read {
+            dest.acknowledge(mailbox_id, &new_id).await?;
+            report.read += 1;
+        } else {
+            report.pending += 1;
+        }
+        report.items.push(MigratedItem {
+            old_id,
+            new_id: Some(new_id),
+            read,
+        });
+    }
+    let pending_after = dest.stats(mailbox_id).await?.pending;
+    if pending_after != pending_before + report.pending {
+        return Err(eyre!(
+            "Mailbox {mailbox_id} has {pending_after} pending items after migrating, expected {}",
+            pending_before + report.pending
+        ));
+    }
+
+    if opts.drain_source {
+        for item in report.items.iter().filter(|i| !i.read) {
+            source.acknowledge(mailbox_id, &item.old_id).await?;
+            report.drained += 1;
+        }
+    }
+
+    Ok(report)
+}
+
+async fn list_items<ITEM: MailboxItem>(
+    source: &dyn Mailbox<ITEM>,
+    mailbox_id: &str,
+) -> Result<Vec<ItemSummary>> {
+    let mut items = Vec::new();
+    let mut after = None;
+    loop {
+        let page = source
+            .list_items_page(mailbox_id, after.as_deref(), 100)
+            .await?;
+        items.extend(page.entries);
+        match page.next_cursor {
+            Some(cursor) => after = Some(cursor),
+            None => return Ok(items),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::migrate_mailbox;
+    use crate::Mailbox;
+    use crate::MailboxDisk;
+    use crate::MailboxItem;
+    use crate::MailboxMemory;
+    use crate::MigrateOptions;
+    use color_eyre::Result;
+    use std::path::Path;
+    use tempfile::TempDir;
+
+    use test_log::test;
+
+    #[derive(Debug, Default)]
+    struct TestItem {
+        data: String,
+    }
+
+    impl MailboxItem for TestItem {
+        fn serialize(&self) -> Result<Vec<u8>> {
+            Ok(self.data.clone().into())
+        }
+        fn deserialize(data: &[u8]) -> Result<Self>
+        where
+            Self: Sized,
+        {
+            Ok(Self {
+                data: String::from_utf8(data.to_vec())?,
+            })
+        }
+    }
+
+    async fn disk_with_items(dir: &TempDir) -> Result<MailboxDisk<TestItem>> {
+        let mut disk = MailboxDisk::<TestItem>::at(dir.path(), Path::new("item"));
+        disk.ensure_storage_exists().await?;
+        for data in ["one", "two", "three", "four"] {
+            disk.send(
+                "42",
+                TestItem {
+                    data: data.to_string(),
+                },
+            )
+            .await?;
+        }
+        disk.acknowledge("42", "2").await?;
+        Ok(disk)
+    }
+
+    async fn received(mailbox: &dyn Mailbox<TestItem>) -> Result<Vec<String>> {
+        Ok(mailbox
+            .drain("42", None)
+            .await?
+            .into_iter()
+            .map(|(_id, item)| item.data)
+            .collect())
+    }
+
+    #[test(tokio::test)]
+    async fn it_copies_pending_items() -> Result<()> {
+        let dir = TempDir::new()?;
+        let disk = disk_with_items(&dir).await?;
+        let memory = MailboxMemory::<TestItem>::new();
+
+        let opts = MigrateOptions {
+            dry_run: true,
+            ..Default::default()
+        };
+        let report = migrate_mailbox(&disk, &memory, "42", opts).await?;
+        assert_eq!(report.pending, 3);
+        assert!(report.id_map().is_empty());
+        assert_eq!(memory.stats("42").await?.pending, 0);
+        assert_eq!(disk.delivery_count("42", "1").await?, 0);
+
+        let report = migrate_mailbox(&disk, &memory, "42", MigrateOptions::default()).await?;
+        assert_eq!((report.pending, report.read, report.drained), (3, 0, 0));
+        let id_map = report.id_map();
+        assert_eq!(id_map.get("3"), Some(&"2"));
+        assert_eq!(disk.stats("42").await?.pending, 3);
+        assert_eq!(received(&memory).await?, ["one", "three", "four"]);
+
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn it_drains_the_source_with_history() -> Result<()> {
+        let dir = TempDir::new()?;
+        let disk = disk_with_items(&dir).await?;
+        let memory = MailboxMemory::<TestItem>::new();
+
+        let opts = MigrateOptions {
+            include_read: true,
+            drain_source: true,
+            ..Default::default()
+        };
+        let report = migrate_mailbox(&disk, &memory, "42", opts).await?;
+        assert_eq!((report.pending, report.read, report.drained), (3, 1, 3));
+        let old_ids: Vec<&str> = report.items.iter().map(|i| i.old_id.as_str()).collect();
+        assert_eq!(old_ids, ["1", "2", "3", "4"]);
+        assert!(report.items[1].read);
+
+        assert_eq!(disk.stats("42").await?.pending, 0);
+        let stats = memory.stats("42").await?;
+        assert_eq!((stats.total_sent, stats.pending), (4, 3));
+        assert_eq!(received(&memory).await?, ["one", "three", "four"]);
+
+        Ok(())
+    }
+}
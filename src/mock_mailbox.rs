@@ -0,0 +1,227 @@
+use crate::HeaderSelector;
+use crate::ItemSummary;
+use crate::Mailbox;
+use crate::MailboxError;
+use crate::MailboxItem;
+use crate::MailboxMemory;
+use crate::MailboxStats;
+use crate::Page;
+use async_trait::async_trait;
+use color_eyre::eyre::Result;
+use std::collections::HashMap;
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// The operations of a [MockMailbox] that can be counted and made to fail
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum MockOperation {
+    EnsureStorageExists,
+    Close,
+    CreateMailbox,
+    Send,
+    SendTransaction,
+    Receive,
+    ReceiveAny,
+    Acknowledge,
+    AcknowledgeThrough,
+    Release,
+    ReleaseLater,
+    Update,
+    Peek,
+    ReceiveMany,
+    ReceiveBatch,
+    ReceiveMatching,
+    ListMailboxes,
+    ListItemsPage,
+    DeliveryCount,
+    ItemSize,
+    Stats,
+    ReceiveFor,
+    AcknowledgeFor,
+    Compact,
+    DropOlderThan,
+}
+
+/// A [MailboxMemory] for tests, with call counting and failure injection
+///
+/// ```
+/// # use oml_mailbox::{MailboxError, MockMailbox, MockOperation};
+/// # #[derive(Debug, Default)]
+/// # struct TestItem;
+/// # impl oml_mailbox::MailboxItem for TestItem {
+/// #     fn serialize(&self) -> color_eyre::eyre::Result<Vec<u8>> { Ok(Vec::new()) }
+/// #     fn deserialize(_data: &[u8]) -> color_eyre::eyre::Result<Self> { Ok(TestItem) }
+/// # }
+/// let mailbox = MockMailbox::<TestItem>::default();
+/// mailbox.fail_next(
+///     MockOperation::Send,
+///     MailboxError::Io(std::io::ErrorKind::Interrupted.into()),
+/// );
+/// ```
+#[derive(Debug, Default)]
+pub struct MockMailbox<ITEM: MailboxItem> {
+    inner: MailboxMemory<ITEM>,
+    state: Mutex<MockState>,
+}
+
+#[derive(Debug, Default)]
+struct MockState {
+    failures: HashMap<MockOperation, VecDeque<MailboxError>>,
+    calls: HashMap<MockOperation, usize>,
+}
+
+impl<ITEM: MailboxItem> MockMailbox<ITEM> {
+    /// Queue an error to be returned by the next call of `operation`
+    ///
+    /// Queued errors are returned in order, one per call.
+    pub fn fail_next(&self, operation: MockOperation, error: MailboxError) {
+        let mut state = self.state.lock().unwrap();
+        state
+            .failures
+            .entry(operation)
+            .or_default()
+            .push_back(error);
+    }
+
+    /// How often `operation` was called, including failed calls
+    pub fn calls(&self, operation: MockOperation) -> usize {
+        let state = self.state.lock().unwrap();
+        state.calls.get(&operation).copied().unwrap_or_default()
+    }
+
+    fn begin(&self, operation: MockOperation) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        *state.calls.entry(operation).or_default() += 1;
+        match state
+            .failures
+            .get_mut(&operation)
+            .and_then(|f| f.pop_front())
+        {
+            Some(e) => Err(e.into()),
+            None => Ok(()),
+        }
+    }
+}
+
+#[async_trait]
+impl<ITEM: MailboxItem + Send> Mailbox<ITEM> for MockMailbox<ITEM> {
+    async fn ensure_storage_exists(&mut self) -> Result<()> {
+        self.begin(MockOperation::EnsureStorageExists)?;
+        self.inner.ensure_storage_exists().await
+    }
+    async fn close(&mut self) -> Result<()> {
+        self.begin(MockOperation::Close)?;
+        self.inner.close().await
+    }
+
+    async fn create_mailbox(&self, mailbox_id: &str) -> Result<()> {
+        self.begin(MockOperation::CreateMailbox)?;
+        self.inner.create_mailbox(mailbox_id).await
+    }
+    async fn send(&self, mailbox_id: &str, item: ITEM) -> Result<String> {
+        self.begin(MockOperation::Send)?;
+        self.inner.send(mailbox_id, item).await
+    }
+    async fn send_transaction(&self, mailbox_id: &str, items: Vec<ITEM>) -> Result<Vec<String>> {
+        self.begin(MockOperation::SendTransaction)?;
+        self.inner.send_transaction(mailbox_id, items).await
+    }
+    async fn receive(&self, mailbox_id: &str) -> Result<Option<(String, ITEM)>> {
+        self.begin(MockOperation::Receive)?;
+        self.inner.receive(mailbox_id).await
+    }
+    async fn receive_any(&self, mailbox_ids: &[String]) -> Result<Option<(String, String, ITEM)>> {
+        self.begin(MockOperation::ReceiveAny)?;
+        self.inner.receive_any(mailbox_ids).await
+    }
+    async fn acknowledge(&self, mailbox_id: &str, item_id: &str) -> Result<()> {
+        self.begin(MockOperation::Acknowledge)?;
+        self.inner.acknowledge(mailbox_id, item_id).await
+    }
+    async fn acknowledge_through(&self, mailbox_id: &str, item_id: &str) -> Result<u64> {
+        self.begin(MockOperation::AcknowledgeThrough)?;
+        self.inner.acknowledge_through(mailbox_id, item_id).await
+    }
+    async fn release(&self, mailbox_id: &str, item_id: &str) -> Result<()> {
+        self.begin(MockOperation::Release)?;
+        self.inner.release(mailbox_id, item_id).await
+    }
+    /// Note: Can't fail, a failure queued for [MockOperation::ReleaseLater] is dropped.
+    fn release_later(&self, mailbox_id: &str, item_id: &str) {
+        let _ = self.begin(MockOperation::ReleaseLater);
+        self.inner.release_later(mailbox_id, item_id)
+    }
+    async fn update(&self, mailbox_id: &str, item_id: &str, item: ITEM) -> Result<()> {
+        self.begin(MockOperation::Update)?;
+        self.inner.update(mailbox_id, item_id, item).await
+    }
+    async fn peek(&self, mailbox_id: &str) -> Result<Option<(String, ITEM)>> {
+        self.begin(MockOperation::Peek)?;
+        self.inner.peek(mailbox_id).await
+    }
+    async fn receive_many(&self, mailbox_id: &str, max: usize) -> Result<Vec<(String, ITEM)>> {
+        self.begin(MockOperation::ReceiveMany)?;
+        self.inner.receive_many(mailbox_id, max).await
+    }
+    async fn receive_batch(
+        &self,
+        mailbox_id: &str,
+        max_items: usize,
+        max_wait: Duration,
+    ) -> Result<Vec<(String, ITEM)>> {
+        self.begin(MockOperation::ReceiveBatch)?;
+        self.inner
+            .receive_batch(mailbox_id, max_items, max_wait)
+            .await
+    }
+    async fn receive_matching(
+        &self,
+        mailbox_id: &str,
+        selector: &HeaderSelector,
+    ) -> Result<Option<(String, ITEM)>> {
+        self.begin(MockOperation::ReceiveMatching)?;
+        self.inner.receive_matching(mailbox_id, selector).await
+    }
+    async fn list_mailboxes(&self) -> Result<Vec<String>> {
+        self.begin(MockOperation::ListMailboxes)?;
+        self.inner.list_mailboxes().await
+    }
+    async fn delivery_count(&self, mailbox_id: &str, item_id: &str) -> Result<u32> {
+        self.begin(MockOperation::DeliveryCount)?;
+        self.inner.delivery_count(mailbox_id, item_id).await
+    }
+    async fn list_items_page(
+        &self,
+        mailbox_id: &str,
+        after: Option<&str>,
+        limit: usize,
+    ) -> Result<Page<ItemSummary>> {
+        self.begin(MockOperation::ListItemsPage)?;
+        self.inner.list_items_page(mailbox_id, after, limit).await
+    }
+    async fn item_size(&self, mailbox_id: &str, item_id: &str) -> Result<u64> {
+        self.begin(MockOperation::ItemSize)?;
+        self.inner.item_size(mailbox_id, item_id).await
+    }
+    async fn stats(&self, mailbox_id: &str) -> Result<MailboxStats> {
+        self.begin(MockOperation::Stats)?;
+        self.inner.stats(mailbox_id).await
+    }
+    async fn receive_for(&self, mailbox_id: &str, group: &str) -> Result<Option<(String, ITEM)>> {
+        self.begin(MockOperation::ReceiveFor)?;
+        self.inner.receive_for(mailbox_id, group).await
+    }
+    async fn acknowledge_for(&self, mailbox_id: &str, group: &str, item_id: &str) -> Result<()> {
+        self.begin(MockOperation::AcknowledgeFor)?;
+        self.inner.acknowledge_for(mailbox_id, group, item_id).await
+    }
+    async fn compact(&self, mailbox_id: &str) -> Result<u64> {
+        self.begin(MockOperation::Compact)?;
+        self.inner.compact(mailbox_id).await
+    }
+    async fn drop_older_than(&self, mailbox_id: &str, max_age: Duration) -> Result<u64> {
+        self.begin(MockOperation::DropOlderThan)?;
+        self.inner.drop_older_than(mailbox_id, max_age).await
+    }
+}
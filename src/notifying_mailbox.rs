@@ -0,0 +1,401 @@
+use crate::ExpireReport;
+use crate::HeaderSelector;
+use crate::HealthReport;
+use crate::ItemSummary;
+use crate::Mailbox;
+use crate::MailboxItem;
+use crate::MailboxStats;
+use crate::Page;
+use crate::ReceiveStatus;
+use async_trait::async_trait;
+use chrono::Utc;
+use color_eyre::eyre::eyre;
+use color_eyre::eyre::Result;
+use color_eyre::eyre::WrapErr;
+use core::marker::PhantomData;
+use std::time::Duration;
+
+/// What [NotifyingMailbox] does when a webhook can't be delivered
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum WebhookFailurePolicy {
+    /// Notify in the background, and only log failures
+    #[default]
+    Log,
+    /// Wait for the notification, and fail the send if it can't be delivered
+    ///
+    /// Note: The item has already been stored at that point.
+    Fail,
+}
+
+/// Where, and how patiently, [NotifyingMailbox] posts its notifications
+#[derive(Debug, Clone)]
+pub struct WebhookConfig {
+    /// The url to POST to, `{mailbox_id}` is replaced with the id of the mailbox
+    pub url_template: String,
+    /// Timeout for every single request
+    pub timeout: Duration,
+    /// Total number of attempts, including the first one
+    pub max_attempts: u32,
+    pub retry_delay: Duration,
+    pub failure_policy: WebhookFailurePolicy,
+}
+
+impl WebhookConfig {
+    pub fn new(url_template: &str) -> Self {
+        Self {
+            url_template: url_template.to_string(),
+            timeout: Duration::from_secs(5),
+            max_attempts: 3,
+            retry_delay: Duration::from_millis(200),
+            failure_policy: WebhookFailurePolicy::default(),
+        }
+    }
+}
+
+/// Wraps any mailbox, and POSTs to a webhook after every successful send
+///
+/// The body is a small JSON object:
+/// ```json
+/// { "mailbox_id": "42", "item_id": "17", "sent_at": "2024-06-01T12:00:00Z" }
+/// ```
+/// Requests are only made after the inner send returned, so no backend lock is held.
+/// Failed requests, and responses other than 2xx, are retried up to [WebhookConfig::max_attempts] times.
+#[derive(Debug)]
+pub struct NotifyingMailbox<ITEM: MailboxItem, M: Mailbox<ITEM>> {
+    inner: M,
+    config: WebhookConfig,
+    client: reqwest::Client,
+    item_type: PhantomData<ITEM>,
+}
+
+impl<ITEM: MailboxItem, M: Mailbox<ITEM>> NotifyingMailbox<ITEM, M> {
+    pub fn new(inner: M, config: WebhookConfig) -> Result<Self> {
+        let client = reqwest::Client::builder()
+            .timeout(config.timeout)
+            .build()
+            .wrap_err("Can't create webhook client")?;
+        Ok(Self {
+            inner,
+            config,
+            client,
+            item_type: PhantomData,
+        })
+    }
+
+    pub fn inner(&self) -> &M {
+        &self.inner
+    }
+
+    pub fn into_inner(self) -> M {
+        self.inner
+    }
+
+    async fn notify(&self, mailbox_id: &str, item_ids: &[String]) -> Result<()> {
+        let url = self.config.url_template.replace("{mailbox_id}", mailbox_id);
+        let sent_at = Utc::now().to_rfc3339();
+        let bodies: Vec<_> = item_ids
+            .iter()
+            .map(|item_id| {
+                serde_json::json!({
+                    "mailbox_id": mailbox_id,
+                    "item_id": item_id,
+                    "sent_at": sent_at,
+                })
+            })
+            .collect();
+        let client = self.client.clone();
+        let config = self.config.clone();
+        let notification = async move {
+            for body in bodies {
+                post(&client, &config, &url, &body).await?;
+            }
+            Result::<()>::Ok(())
+        };
+
+        match self.config.failure_policy {
+            WebhookFailurePolicy::Fail => notification.await,
+            WebhookFailurePolicy::Log => {
+                let mailbox_id = mailbox_id.to_string();
+                tokio::spawn(async move {
+                    if let Err(e) = notification.await {
+                        tracing::warn!("Webhook for mailbox {mailbox_id} failed -> {e:?}");
+                    }
+                });
+                Ok(())
+            }
+        }
+    }
+}
+
+async fn post(
+    client: &reqwest::Client,
+    config: &WebhookConfig,
+    url: &str,
+    body: &serde_json::Value,
+) -> Result<()> {
+    let mut attempt = 1;
+    loop {
+        let r = match client.post(url).json(body).send().await {
+            Ok(response) if response.status().is_success() => return Ok(()),
+            Ok(response) => Err(eyre!("Webhook {url} returned {}", response.status())),
+            Err(e) => Err(e).wrap_err_with(|| format!("Webhook {url} failed")),
+        };
+        if attempt >= config.max_attempts {
+            return r;
+        }
+        tracing::debug!(
+            "Webhook attempt {attempt}/{} failed, retrying",
+            config.max_attempts
+        );
+        tokio::time::sleep(config.retry_delay).await;
+        attempt += 1;
+    }
+}
+
+#[async_trait]
+impl<ITEM: MailboxItem + Send, M: Mailbox<ITEM>> Mailbox<ITEM> for NotifyingMailbox<ITEM, M> {
+    async fn ensure_storage_exists(&mut self) -> Result<()> {
+        self.inner.ensure_storage_exists().await
+    }
+    async fn close(&mut self) -> Result<()> {
+        self.inner.close().await
+    }
+
+    async fn create_mailbox(&self, id: &str) -> Result<()> {
+        self.inner.create_mailbox(id).await
+    }
+    async fn send(&self, id: &str, item: ITEM) -> Result<String> {
+        let item_id = self.inner.send(id, item).await?;
+        self.notify(id, std::slice::from_ref(&item_id)).await?;
+        Ok(item_id)
+    }
+    async fn send_transaction(&self, id: &str, items: Vec<ITEM>) -> Result<Vec<String>> {
+        let item_ids = self.inner.send_transaction(id, items).await?;
+        self.notify(id, &item_ids).await?;
+        Ok(item_ids)
+    }
+    async fn receive(&self, id: &str) -> Result<Option<(String, ITEM)>> {
+        self.inner.receive(id).await
+    }
+    async fn receive_status(&self, id: &str) -> Result<ReceiveStatus<ITEM>> {
+        self.inner.receive_status(id).await
+    }
+    async fn receive_any(&self, ids: &[String]) -> Result<Option<(String, String, ITEM)>> {
+        self.inner.receive_any(ids).await
+    }
+    async fn acknowledge(&self, id: &str, item_id: &str) -> Result<()> {
+        self.inner.acknowledge(id, item_id).await
+    }
+    async fn acknowledge_through(&self, id: &str, item_id: &str) -> Result<u64> {
+        self.inner.acknowledge_through(id, item_id).await
+    }
+    async fn release(&self, id: &str, item_id: &str) -> Result<()> {
+        self.inner.release(id, item_id).await
+    }
+    fn release_later(&self, id: &str, item_id: &str) {
+        self.inner.release_later(id, item_id)
+    }
+    async fn update(&self, id: &str, item_id: &str, item: ITEM) -> Result<()> {
+        self.inner.update(id, item_id, item).await
+    }
+    async fn peek(&self, id: &str) -> Result<Option<(String, ITEM)>> {
+        self.inner.peek(id).await
+    }
+    async fn receive_many(&self, id: &str, max: usize) -> Result<Vec<(String, ITEM)>> {
+        self.inner.receive_many(id, max).await
+    }
+    async fn receive_batch(
+        &self,
+        id: &str,
+        max_items: usize,
+        max_wait: Duration,
+    ) -> Result<Vec<(String, ITEM)>> {
+        self.inner.receive_batch(id, max_items, max_wait).await
+    }
+    async fn receive_matching(
+        &self,
+        id: &str,
+        selector: &HeaderSelector,
+    ) -> Result<Option<(String, ITEM)>> {
+        self.inner.receive_matching(id, selector).await
+    }
+    async fn list_mailboxes(&self) -> Result<Vec<String>> {
+        self.inner.list_mailboxes().await
+    }
+    async fn delivery_count(&self, id: &str, item_id: &str) -> Result<u32> {
+        self.inner.delivery_count(id, item_id).await
+    }
+    async fn list_items_page(
+        &self,
+        id: &str,
+        after: Option<&str>,
+        limit: usize,
+    ) -> Result<Page<ItemSummary>> {
+        self.inner.list_items_page(id, after, limit).await
+    }
+    async fn item_size(&self, id: &str, item_id: &str) -> Result<u64> {
+        self.inner.item_size(id, item_id).await
+    }
+    async fn stats(&self, id: &str) -> Result<MailboxStats> {
+        self.inner.stats(id).await
+    }
+    async fn receive_for(&self, id: &str, group: &str) -> Result<Option<(String, ITEM)>> {
+        self.inner.receive_for(id, group).await
+    }
+    async fn acknowledge_for(&self, id: &str, group: &str, item_id: &str) -> Result<()> {
+        self.inner.acknowledge_for(id, group, item_id).await
+    }
+    async fn compact(&self, id: &str) -> Result<u64> {
+        self.inner.compact(id).await
+    }
+    async fn drop_older_than(&self, id: &str, max_age: Duration) -> Result<u64> {
+        self.inner.drop_older_than(id, max_age).await
+    }
+    async fn expire_items(&self, id: &str) -> Result<ExpireReport> {
+        self.inner.expire_items(id).await
+    }
+    async fn health_check(&self) -> Result<HealthReport> {
+        self.inner.health_check().await
+    }
+    async fn pop(&self, id: &str) -> Result<Option<(String, ITEM)>> {
+        self.inner.pop(id).await
+    }
+    async fn drain(&self, id: &str, max: Option<usize>) -> Result<Vec<(String, ITEM)>>
+    where
+        ITEM: 'static,
+    {
+        self.inner.drain(id, max).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::Mailbox;
+    use crate::MailboxItem;
+    use crate::MockMailbox;
+    use crate::NotifyingMailbox;
+    use crate::WebhookConfig;
+    use crate::WebhookFailurePolicy;
+    use color_eyre::Result;
+    use std::time::Duration;
+    use tokio::io::AsyncReadExt;
+    use tokio::io::AsyncWriteExt;
+    use tokio::net::TcpListener;
+    use tokio::sync::mpsc;
+
+    use test_log::test;
+
+    #[derive(Default, Debug)]
+    struct TestItem {}
+
+    impl MailboxItem for TestItem {
+        fn serialize(&self) -> Result<Vec<u8>> {
+            Ok(Vec::new())
+        }
+        fn deserialize(_data: &[u8]) -> Result<Self>
+        where
+            Self: Sized,
+        {
+            Ok(Self {})
+        }
+    }
+
+    /// A minimal http server, answering every request with `status`, and passing on path and body
+    async fn serve(status: u16) -> Result<(String, mpsc::UnboundedReceiver<(String, String)>)> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let url = format!("http://{}/mailbox/{{mailbox_id}}", listener.local_addr()?);
+        let (tx, rx) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let mut request = Vec::new();
+                let mut buf = [0u8; 1024];
+                let (head, body) = loop {
+                    let n = stream.read(&mut buf).await.unwrap_or_default();
+                    if n == 0 {
+                        break (String::new(), String::new());
+                    }
+                    request.extend_from_slice(&buf[..n]);
+                    let text = String::from_utf8_lossy(&request).to_string();
+                    let Some((head, body)) = text.split_once("\r\n\r\n") else {
+                        continue;
+                    };
+                    let len = head
+                        .lines()
+                        .filter_map(|l| l.split_once(':'))
+                        .find(|(k, _)| k.eq_ignore_ascii_case("content-length"))
+                        .and_then(|(_, v)| v.trim().parse::<usize>().ok())
+                        .unwrap_or_default();
+                    if body.len() >= len {
+                        break (head.to_string(), body.to_string());
+                    }
+                };
+                let path = head.split(' ').nth(1).unwrap_or_default().to_string();
+                let _ = tx.send((path, body));
+                let response = format!(
+                    "HTTP/1.1 {status} X\r\nconnection: close\r\ncontent-length: 0\r\n\r\n"
+                );
+                let _ = stream.write_all(response.as_bytes()).await;
+            }
+        });
+
+        Ok((url, rx))
+    }
+
+    #[test(tokio::test)]
+    async fn it_notifies_once_per_send() -> Result<()> {
+        let (url, mut requests) = serve(200).await?;
+        let config = WebhookConfig {
+            failure_policy: WebhookFailurePolicy::Fail,
+            ..WebhookConfig::new(&url)
+        };
+        let mailbox = NotifyingMailbox::new(MockMailbox::<TestItem>::default(), config)?;
+
+        let one = mailbox.send("42", TestItem::default()).await?;
+        let more = mailbox
+            .send_transaction("42", vec![TestItem::default(), TestItem::default()])
+            .await?;
+
+        for item_id in std::iter::once(one).chain(more) {
+            let (path, body) = requests.recv().await.expect("Request");
+            assert_eq!(path, "/mailbox/42");
+            let body: serde_json::Value = serde_json::from_str(&body)?;
+            assert_eq!(body["mailbox_id"], "42");
+            assert_eq!(body["item_id"], item_id.as_str());
+            assert!(body["sent_at"].is_string());
+        }
+        assert!(requests.try_recv().is_err());
+
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn it_applies_the_failure_policy() -> Result<()> {
+        let (url, mut requests) = serve(500).await?;
+        let config = WebhookConfig {
+            retry_delay: Duration::from_millis(10),
+            ..WebhookConfig::new(&url)
+        };
+        let mailbox = NotifyingMailbox::new(MockMailbox::<TestItem>::default(), config.clone())?;
+
+        // logged only, the send doesn't wait for it
+        mailbox.send("42", TestItem::default()).await?;
+        for _ in 0..3 {
+            requests.recv().await.expect("Request");
+        }
+
+        let config = WebhookConfig {
+            failure_policy: WebhookFailurePolicy::Fail,
+            ..config
+        };
+        let mailbox = NotifyingMailbox::new(mailbox.into_inner(), config)?;
+        assert!(mailbox.send("42", TestItem::default()).await.is_err());
+        for _ in 0..3 {
+            requests.recv().await.expect("Request");
+        }
+        assert!(requests.try_recv().is_err());
+        // the item is stored anyway
+        assert_eq!(mailbox.stats("42").await?.total_sent, 2);
+
+        Ok(())
+    }
+}
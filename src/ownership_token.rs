@@ -0,0 +1,28 @@
+use chrono::DateTime;
+use chrono::Utc;
+use serde::Deserialize;
+use serde::Serialize;
+
+/// Proof of owning a mailbox, see [crate::MailboxDisk::create_mailbox_exclusive]
+///
+/// Tokens can be serialized, to hand them to another instance with [crate::MailboxDisk::hold_ownership].
+/// Note: The token is random, but not cryptographically so, it keeps cooperating instances apart, not attackers.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OwnershipToken {
+    pub mailbox_id: String,
+    pub owner: String,
+    pub token: String,
+    /// After this the mailbox can be claimed by somebody else, `None` for never
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
+impl OwnershipToken {
+    pub fn is_expired_at(&self, now: DateTime<Utc>) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at <= now)
+    }
+
+    /// If `other` proves the same ownership, the expiry might differ after a renewal
+    pub(crate) fn matches(&self, other: &OwnershipToken) -> bool {
+        self.mailbox_id == other.mailbox_id && self.token == other.token
+    }
+}
@@ -0,0 +1,24 @@
+/// One page of a listing, e.g. [crate::Mailbox::list_items_page]
+///
+/// Pass `next_cursor` as `after` to get the next page, it is `None` on the last page.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Page<T> {
+    pub entries: Vec<T>,
+    pub next_cursor: Option<String>,
+}
+
+impl<T> Page<T> {
+    /// Builds the page from up to `limit + 1` entries, the extra entry only tells that there are more
+    pub(crate) fn new(mut entries: Vec<T>, limit: usize, cursor: impl Fn(&T) -> String) -> Self {
+        let next_cursor = if entries.len() > limit {
+            entries.truncate(limit);
+            entries.last().map(cursor)
+        } else {
+            None
+        };
+        Self {
+            entries,
+            next_cursor,
+        }
+    }
+}
@@ -0,0 +1,197 @@
+use core::future::Future;
+use std::time::Duration;
+
+/// How long to sleep while a mailbox is empty, before looking again
+///
+/// A policy is only the configuration, every place that waits gets its own [PollWaiter],
+/// so one idle mailbox backing off doesn't slow down a busy one.
+#[derive(Debug, Clone, PartialEq)]
+pub enum PollPolicy {
+    /// Always the same interval
+    Fixed(Duration),
+    /// Starts at `start`, multiplied by `factor` for every empty poll, up to `max`
+    ///
+    /// `jitter` is the fraction (0.0 - 1.0) of each delay that is randomized, like [crate::RetryPolicy::jitter].
+    ExponentialBackoff {
+        start: Duration,
+        max: Duration,
+        factor: f64,
+        jitter: f64,
+    },
+    /// Waits for the wakeups of the backend, e.g. sends through a [crate::MailboxSender],
+    /// and backs off from `start` to `max`, doubling, in case a wakeup is missed or there is none
+    Notified { start: Duration, max: Duration },
+}
+
+impl Default for PollPolicy {
+    fn default() -> Self {
+        Self::ExponentialBackoff {
+            start: Duration::from_millis(10),
+            max: Duration::from_millis(100),
+            factor: 2.0,
+            jitter: 0.1,
+        }
+    }
+}
+
+impl PollPolicy {
+    /// The state for one place that waits, starting with the shortest delay
+    pub fn waiter(&self) -> PollWaiter {
+        PollWaiter {
+            policy: self.clone(),
+            empty_polls: 0,
+        }
+    }
+}
+
+/// Waits according to a [PollPolicy], backing off while the polls come back empty
+#[derive(Debug, Clone)]
+pub struct PollWaiter {
+    policy: PollPolicy,
+    empty_polls: i32,
+}
+
+impl PollWaiter {
+    /// The delay after the next empty poll, and counts it
+    pub fn next_delay(&mut self) -> Duration {
+        let n = self.empty_polls;
+        self.empty_polls = self.empty_polls.saturating_add(1);
+        match &self.policy {
+            PollPolicy::Fixed(interval) => *interval,
+            PollPolicy::ExponentialBackoff {
+                start,
+                max,
+                factor,
+                jitter,
+            } => {
+                let delay = backoff(*start, *max, *factor, n);
+                let jitter = jitter.clamp(0.0, 1.0);
+                if jitter > 0.0 {
+                    delay.mul_f64(1.0 - jitter * fastrand::f64())
+                } else {
+                    delay
+                }
+            }
+            PollPolicy::Notified { start, max } => backoff(*start, *max, 2.0, n),
+        }
+    }
+
+    /// Starts over with the shortest delay, call it whenever a poll found something
+    pub fn reset(&mut self) {
+        self.empty_polls = 0;
+    }
+
+    /// Sleeps after an empty poll
+    pub async fn wait(&mut self) {
+        tokio::time::sleep(self.next_delay()).await;
+    }
+
+    /// Like [PollWaiter::wait], but with [PollPolicy::Notified] `wakeup` ends the wait early
+    ///
+    /// Note: Create `wakeup` before polling, so a wakeup in between is not missed.
+    pub async fn wait_or(&mut self, wakeup: impl Future<Output = ()>) {
+        let sleep = tokio::time::sleep(self.next_delay());
+        if !matches!(self.policy, PollPolicy::Notified { .. }) {
+            return sleep.await;
+        }
+        tokio::select! {
+            _ = sleep => {}
+            _ = wakeup => {}
+        }
+    }
+}
+
+/// `start * factor^n`, but at most `max`
+fn backoff(start: Duration, max: Duration, factor: f64, n: i32) -> Duration {
+    let scaled = start.as_secs_f64() * factor.max(1.0).powi(n);
+    if scaled >= max.as_secs_f64() {
+        return max;
+    }
+    Duration::from_secs_f64(scaled).min(max)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::PollPolicy;
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tokio::sync::Notify;
+    use tokio::time::Instant;
+
+    use test_log::test;
+
+    #[test(tokio::test(start_paused = true))]
+    async fn it_backs_off_exponentially() {
+        let ms = Duration::from_millis;
+        let policy = PollPolicy::ExponentialBackoff {
+            start: ms(10),
+            max: ms(100),
+            factor: 2.0,
+            jitter: 0.0,
+        };
+        let mut waiter = policy.waiter();
+        let mut slept = Vec::new();
+        for _ in 0..6 {
+            let before = Instant::now();
+            waiter.wait().await;
+            slept.push(before.elapsed());
+        }
+        assert_eq!(slept, [10, 20, 40, 80, 100, 100].map(ms));
+
+        // every waiter has its own state
+        let mut other = policy.waiter();
+        assert_eq!(other.next_delay(), ms(10));
+        waiter.reset();
+        assert_eq!(waiter.next_delay(), ms(10));
+
+        let jittered = PollPolicy::ExponentialBackoff {
+            start: ms(10),
+            max: ms(100),
+            factor: 2.0,
+            jitter: 0.5,
+        };
+        let mut waiter = jittered.waiter();
+        for expected in [10, 20, 40, 80, 100, 100].map(ms) {
+            let delay = waiter.next_delay();
+            assert!(delay <= expected && delay >= expected / 2, "{delay:?}");
+        }
+    }
+
+    #[test(tokio::test(start_paused = true))]
+    async fn it_wakes_right_away_when_notified() {
+        let ms = Duration::from_millis;
+        let policy = PollPolicy::Notified {
+            start: ms(100),
+            max: ms(1000),
+        };
+        let notify = Arc::new(Notify::new());
+        let mut waiter = policy.waiter();
+
+        let before = Instant::now();
+        let wakeup = notify.notified();
+        let notifier = notify.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(ms(5)).await;
+            notifier.notify_waiters();
+        });
+        waiter.wait_or(wakeup).await;
+        assert_eq!(before.elapsed(), ms(5));
+
+        // without wakeups it backs off, but never beyond the max
+        let mut slept = Vec::new();
+        for _ in 0..5 {
+            let before = Instant::now();
+            waiter.wait_or(notify.notified()).await;
+            slept.push(before.elapsed());
+        }
+        assert_eq!(slept, [200, 400, 800, 1000, 1000].map(ms));
+
+        // other policies ignore the wakeups
+        let mut fixed = PollPolicy::Fixed(ms(50)).waiter();
+        let before = Instant::now();
+        let wakeup = notify.notified();
+        notify.notify_waiters();
+        fixed.wait_or(wakeup).await;
+        assert_eq!(before.elapsed(), ms(50));
+    }
+}
@@ -0,0 +1,37 @@
+use chrono::DateTime;
+use chrono::Utc;
+use serde::Deserialize;
+use serde::Serialize;
+
+/// How many hops an item remembers, older ones are dropped, so defer loops can't grow it forever
+pub const MAX_PROVENANCE: usize = 10;
+
+/// What moved an item, see [ProvenanceEntry]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ProvenanceAction {
+    /// [crate::MailboxDisk::defer] sent it again, behind the other items
+    Deferred,
+    /// [crate::MailboxDisk::defer] moved it to the dead-letter mailbox
+    DeadLettered,
+    /// [crate::MailboxDisk::restore_from_trash] brought it back
+    RestoredFromTrash,
+}
+
+/// One hop of an item, the oldest hop comes first
+///
+/// Every move gives the item a new id, `from_item_id` is the one it had before.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ProvenanceEntry {
+    pub action: ProvenanceAction,
+    pub from_mailbox: String,
+    pub from_item_id: String,
+    pub at: DateTime<Utc>,
+}
+
+/// Appends `entry`, dropping the oldest entries beyond [MAX_PROVENANCE]
+pub(crate) fn push(provenance: &mut Vec<ProvenanceEntry>, entry: ProvenanceEntry) {
+    provenance.push(entry);
+    let excess = provenance.len().saturating_sub(MAX_PROVENANCE);
+    provenance.drain(..excess);
+}
@@ -0,0 +1,16 @@
+use chrono::DateTime;
+use chrono::Utc;
+use serde::Deserialize;
+use serde::Serialize;
+use std::path::PathBuf;
+
+/// An item moved aside by [crate::CorruptionPolicy::Quarantine], listed by [crate::MailboxDisk::quarantined]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct QuarantinedItem {
+    pub item_id: String,
+    /// The envelope in the `.quarantine` folder, its sidecar, if any, is next to it
+    pub path: PathBuf,
+    /// Why the item couldn't be received
+    pub reason: String,
+    pub quarantined_at: DateTime<Utc>,
+}
@@ -0,0 +1,96 @@
+use serde::Deserialize;
+use serde::Serialize;
+use std::sync::Arc;
+
+/// Limits shared by all mailboxes of a namespace, see [crate::MailboxDisk::with_quota]
+///
+/// ```
+/// # use oml_mailbox::QuotaManager;
+/// let quota = QuotaManager::by_first_segment()
+///     .with_max_items(10_000)
+///     .with_max_bytes(50 * 1024 * 1024);
+/// assert_eq!(quota.namespace("user-17/inbox"), "user-17");
+/// ```
+#[derive(Clone)]
+pub struct QuotaManager {
+    namespace_of: Arc<dyn Fn(&str) -> String + Send + Sync>,
+    max_items: Option<u64>,
+    max_bytes: Option<u64>,
+}
+
+impl QuotaManager {
+    /// `namespace_of` maps a mailbox id to its namespace, without limits yet
+    pub fn new(namespace_of: impl Fn(&str) -> String + Send + Sync + 'static) -> Self {
+        Self {
+            namespace_of: Arc::new(namespace_of),
+            max_items: None,
+            max_bytes: None,
+        }
+    }
+
+    /// The namespace is the first segment of `/` separated mailbox ids, or the whole id
+    pub fn by_first_segment() -> Self {
+        Self::new(|mailbox_id| {
+            let (namespace, _rest) = mailbox_id
+                .split_once(crate::SCOPE_SEPARATOR)
+                .unwrap_or((mailbox_id, ""));
+            namespace.to_string()
+        })
+    }
+
+    pub fn with_max_items(mut self, items: u64) -> Self {
+        self.max_items = Some(items);
+        self
+    }
+
+    /// Counts the serialized items, like [crate::MailboxDisk::with_max_item_size]
+    pub fn with_max_bytes(mut self, bytes: u64) -> Self {
+        self.max_bytes = Some(bytes);
+        self
+    }
+
+    pub fn namespace(&self, mailbox_id: &str) -> String {
+        (self.namespace_of)(mailbox_id)
+    }
+
+    /// The limit `usage` would cross with `added` on top
+    pub(crate) fn exceeded(&self, usage: &QuotaUsage, added: &QuotaUsage) -> Option<QuotaLimit> {
+        if self
+            .max_items
+            .is_some_and(|max| usage.items.saturating_add(added.items) > max)
+        {
+            return Some(QuotaLimit::Items);
+        }
+        if self
+            .max_bytes
+            .is_some_and(|max| usage.bytes.saturating_add(added.bytes) > max)
+        {
+            return Some(QuotaLimit::Bytes);
+        }
+        None
+    }
+}
+
+impl std::fmt::Debug for QuotaManager {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("QuotaManager")
+            .field("max_items", &self.max_items)
+            .field("max_bytes", &self.max_bytes)
+            .finish_non_exhaustive()
+    }
+}
+
+/// The items of a namespace, from their send until they are compacted, or their mailbox is purged
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct QuotaUsage {
+    pub items: u64,
+    /// The serialized items
+    pub bytes: u64,
+}
+
+/// Which limit of a [QuotaManager] a send would cross
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QuotaLimit {
+    Items,
+    Bytes,
+}
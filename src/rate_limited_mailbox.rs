@@ -0,0 +1,599 @@
+use crate::ExpireReport;
+use crate::HeaderSelector;
+use crate::HealthReport;
+use crate::ItemSummary;
+use crate::Mailbox;
+use crate::MailboxError;
+use crate::MailboxItem;
+use crate::MailboxStats;
+use crate::Page;
+use crate::ReceiveAnyTurn;
+use crate::ReceiveStatus;
+use async_trait::async_trait;
+use color_eyre::eyre::Result;
+use core::marker::PhantomData;
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// What [RateLimitedMailbox] does when no token is available
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum RateLimitMode {
+    /// Wait until a token is available
+    #[default]
+    Wait,
+    /// Fail right away with [MailboxError::RateLimited]
+    Reject,
+}
+
+/// A token bucket, refilled with `per_second` tokens, holding at most `burst` tokens
+///
+/// A `per_second` of zero, or below, never refills the bucket,
+/// once the `burst` is used up operations fail with [MailboxError::RateLimited], even with [RateLimitMode::Wait].
+#[derive(Debug, Clone, Copy)]
+pub struct RateLimit {
+    pub per_second: f64,
+    pub burst: u32,
+}
+
+impl RateLimit {
+    pub fn per_second(per_second: f64) -> Self {
+        Self {
+            per_second,
+            burst: 1,
+        }
+    }
+}
+
+/// Limits for the individual operations, `None` means unlimited
+#[derive(Debug, Clone, Default)]
+pub struct RateLimitConfig {
+    pub send: Option<RateLimit>,
+    /// Also used for `peek`, `pop` and `drain`, with one token per call
+    ///
+    /// `receive_many` and `receive_batch` take one token per item they return, at least one per call.
+    /// They only return as many items as there are tokens right away, but wait for the first one.
+    pub receive: Option<RateLimit>,
+    pub acknowledge: Option<RateLimit>,
+    /// Use a separate bucket for every mailbox id, instead of one shared bucket
+    pub per_mailbox: bool,
+    pub mode: RateLimitMode,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Operation {
+    Send,
+    Receive,
+    Acknowledge,
+}
+
+impl Operation {
+    fn name(&self) -> &'static str {
+        match self {
+            Operation::Send => "send",
+            Operation::Receive => "receive",
+            Operation::Acknowledge => "acknowledge",
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    last_refill: Instant,
+}
+
+/// Wraps any mailbox, and limits the rate of operations with token buckets
+///
+/// Note: With [RateLimitConfig::per_mailbox] a bucket is kept for every mailbox id ever seen.
+#[derive(Debug)]
+pub struct RateLimitedMailbox<ITEM: MailboxItem, M: Mailbox<ITEM>> {
+    inner: M,
+    config: RateLimitConfig,
+    buckets: Mutex<HashMap<(Operation, Option<String>), Bucket>>,
+    receive_any_turn: ReceiveAnyTurn,
+    item_type: PhantomData<ITEM>,
+}
+
+impl<ITEM: MailboxItem, M: Mailbox<ITEM>> RateLimitedMailbox<ITEM, M> {
+    pub fn new(inner: M, config: RateLimitConfig) -> Self {
+        Self {
+            inner,
+            config,
+            buckets: Default::default(),
+            receive_any_turn: Default::default(),
+            item_type: PhantomData,
+        }
+    }
+
+    pub fn inner(&self) -> &M {
+        &self.inner
+    }
+
+    fn limit(&self, op: Operation) -> Option<RateLimit> {
+        match op {
+            Operation::Send => self.config.send,
+            Operation::Receive => self.config.receive,
+            Operation::Acknowledge => self.config.acknowledge,
+        }
+    }
+
+    /// Takes a token, waiting or failing according to the mode
+    async fn acquire(&self, op: Operation, mailbox_id: &str) -> Result<()> {
+        self.acquire_many(op, mailbox_id, 1).await
+    }
+
+    /// Takes `count` tokens at once, or none of them
+    async fn acquire_many(&self, op: Operation, mailbox_id: &str, count: usize) -> Result<()> {
+        let Some(limit) = self.limit(op) else {
+            return Ok(());
+        };
+        if count == 0 {
+            return Ok(());
+        }
+        let wait = {
+            let mut buckets = self.buckets.lock().unwrap();
+            let bucket = self.refilled(&mut buckets, op, mailbox_id, limit);
+            let burst = f64::from(limit.burst.max(1));
+            let per_second = limit.per_second.max(0.0);
+
+            let needed = count as f64;
+            let missing = needed - bucket.tokens;
+            // Note: `None` if the tokens never come together
+            let wait = if missing <= 0.0 {
+                Some(Duration::ZERO)
+            } else if self.config.mode == RateLimitMode::Reject && needed > burst {
+                None
+            } else {
+                Duration::try_from_secs_f64(missing / per_second).ok()
+            };
+            match (self.config.mode, wait) {
+                (RateLimitMode::Wait, Some(wait))
+                | (RateLimitMode::Reject, Some(wait @ Duration::ZERO)) => {
+                    // Note: Waiting callers reserve their tokens up front, which keeps them in order
+                    bucket.tokens -= needed;
+                    wait
+                }
+                (_, wait) => {
+                    return Err(MailboxError::RateLimited {
+                        op: op.name().to_string(),
+                        mailbox_id: mailbox_id.to_string(),
+                        retry_after: wait.unwrap_or(Duration::MAX),
+                    }
+                    .into());
+                }
+            }
+        };
+
+        if !wait.is_zero() {
+            tracing::debug!(
+                "{} on {mailbox_id} rate limited, waiting {wait:?}",
+                op.name()
+            );
+            tokio::time::sleep(wait).await;
+        }
+        Ok(())
+    }
+
+    /// Takes a token like [Self::acquire], and up to `max - 1` more if they are there right away
+    ///
+    /// Returns how many tokens were taken, `max` if `op` is unlimited.
+    async fn acquire_up_to(&self, op: Operation, mailbox_id: &str, max: usize) -> Result<usize> {
+        let Some(limit) = self.limit(op) else {
+            return Ok(max);
+        };
+        self.acquire(op, mailbox_id).await?;
+
+        let mut buckets = self.buckets.lock().unwrap();
+        let bucket = self.refilled(&mut buckets, op, mailbox_id, limit);
+        let more = (bucket.tokens.max(0.0) as usize).min(max.saturating_sub(1));
+        bucket.tokens -= more as f64;
+        Ok(1 + more)
+    }
+
+    /// Puts `count` unused tokens back
+    fn give_back(&self, op: Operation, mailbox_id: &str, count: usize) {
+        let Some(limit) = self.limit(op) else {
+            return;
+        };
+        let mut buckets = self.buckets.lock().unwrap();
+        let bucket = self.refilled(&mut buckets, op, mailbox_id, limit);
+        bucket.tokens = (bucket.tokens + count as f64).min(f64::from(limit.burst.max(1)));
+    }
+
+    /// Receives up to `max` items with `receive`, taking one token per item, at least one
+    async fn receive_up_to<F>(
+        &self,
+        mailbox_id: &str,
+        max: usize,
+        receive: impl FnOnce(usize) -> F,
+    ) -> Result<Vec<(String, ITEM)>>
+    where
+        F: Future<Output = Result<Vec<(String, ITEM)>>>,
+    {
+        let taken = self
+            .acquire_up_to(Operation::Receive, mailbox_id, max)
+            .await?;
+        let items = receive(taken.min(max)).await;
+        let used = items.as_ref().map_or(1, |items| items.len().max(1));
+        self.give_back(Operation::Receive, mailbox_id, taken.saturating_sub(used));
+        items
+    }
+
+    /// The bucket of `op` on `mailbox_id`, refilled up to now
+    fn refilled<'a>(
+        &self,
+        buckets: &'a mut HashMap<(Operation, Option<String>), Bucket>,
+        op: Operation,
+        mailbox_id: &str,
+        limit: RateLimit,
+    ) -> &'a mut Bucket {
+        let key = if self.config.per_mailbox {
+            (op, Some(mailbox_id.to_string()))
+        } else {
+            (op, None)
+        };
+        let now = Instant::now();
+        let burst = f64::from(limit.burst.max(1));
+        let bucket = buckets.entry(key).or_insert_with(|| Bucket {
+            tokens: burst,
+            last_refill: now,
+        });
+        let elapsed = now.duration_since(bucket.last_refill).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * limit.per_second.max(0.0)).min(burst);
+        bucket.last_refill = now;
+        bucket
+    }
+}
+
+#[async_trait]
+impl<ITEM: MailboxItem + Send, M: Mailbox<ITEM>> Mailbox<ITEM> for RateLimitedMailbox<ITEM, M> {
+    async fn ensure_storage_exists(&mut self) -> Result<()> {
+        self.inner.ensure_storage_exists().await
+    }
+    async fn close(&mut self) -> Result<()> {
+        self.inner.close().await
+    }
+
+    async fn create_mailbox(&self, id: &str) -> Result<()> {
+        self.inner.create_mailbox(id).await
+    }
+    async fn send(&self, id: &str, item: ITEM) -> Result<String> {
+        self.acquire(Operation::Send, id).await?;
+        self.inner.send(id, item).await
+    }
+    async fn send_transaction(&self, id: &str, items: Vec<ITEM>) -> Result<Vec<String>> {
+        self.acquire_many(Operation::Send, id, items.len()).await?;
+        self.inner.send_transaction(id, items).await
+    }
+    async fn receive(&self, id: &str) -> Result<Option<(String, ITEM)>> {
+        self.acquire(Operation::Receive, id).await?;
+        self.inner.receive(id).await
+    }
+    async fn receive_status(&self, id: &str) -> Result<ReceiveStatus<ITEM>> {
+        self.acquire(Operation::Receive, id).await?;
+        self.inner.receive_status(id).await
+    }
+    fn receive_any_turn(&self) -> Option<&ReceiveAnyTurn> {
+        Some(&self.receive_any_turn)
+    }
+    async fn acknowledge(&self, id: &str, item_id: &str) -> Result<()> {
+        self.acquire(Operation::Acknowledge, id).await?;
+        self.inner.acknowledge(id, item_id).await
+    }
+    async fn acknowledge_through(&self, id: &str, item_id: &str) -> Result<u64> {
+        self.acquire(Operation::Acknowledge, id).await?;
+        self.inner.acknowledge_through(id, item_id).await
+    }
+    async fn release(&self, id: &str, item_id: &str) -> Result<()> {
+        self.acquire(Operation::Acknowledge, id).await?;
+        self.inner.release(id, item_id).await
+    }
+    fn release_later(&self, id: &str, item_id: &str) {
+        self.inner.release_later(id, item_id)
+    }
+    async fn update(&self, id: &str, item_id: &str, item: ITEM) -> Result<()> {
+        self.acquire(Operation::Send, id).await?;
+        self.inner.update(id, item_id, item).await
+    }
+    async fn peek(&self, id: &str) -> Result<Option<(String, ITEM)>> {
+        self.acquire(Operation::Receive, id).await?;
+        self.inner.peek(id).await
+    }
+    async fn receive_many(&self, id: &str, max: usize) -> Result<Vec<(String, ITEM)>> {
+        self.receive_up_to(id, max, |max| self.inner.receive_many(id, max))
+            .await
+    }
+    async fn receive_batch(
+        &self,
+        id: &str,
+        max_items: usize,
+        max_wait: Duration,
+    ) -> Result<Vec<(String, ITEM)>> {
+        self.receive_up_to(id, max_items, |max_items| {
+            self.inner.receive_batch(id, max_items, max_wait)
+        })
+        .await
+    }
+    async fn receive_matching(
+        &self,
+        id: &str,
+        selector: &HeaderSelector,
+    ) -> Result<Option<(String, ITEM)>> {
+        self.acquire(Operation::Receive, id).await?;
+        self.inner.receive_matching(id, selector).await
+    }
+    async fn list_mailboxes(&self) -> Result<Vec<String>> {
+        self.inner.list_mailboxes().await
+    }
+    async fn delivery_count(&self, id: &str, item_id: &str) -> Result<u32> {
+        self.inner.delivery_count(id, item_id).await
+    }
+    async fn list_items_page(
+        &self,
+        id: &str,
+        after: Option<&str>,
+        limit: usize,
+    ) -> Result<Page<ItemSummary>> {
+        self.inner.list_items_page(id, after, limit).await
+    }
+    async fn item_size(&self, id: &str, item_id: &str) -> Result<u64> {
+        self.inner.item_size(id, item_id).await
+    }
+    async fn stats(&self, id: &str) -> Result<MailboxStats> {
+        self.inner.stats(id).await
+    }
+    async fn receive_for(&self, id: &str, group: &str) -> Result<Option<(String, ITEM)>> {
+        self.acquire(Operation::Receive, id).await?;
+        self.inner.receive_for(id, group).await
+    }
+    async fn acknowledge_for(&self, id: &str, group: &str, item_id: &str) -> Result<()> {
+        self.acquire(Operation::Acknowledge, id).await?;
+        self.inner.acknowledge_for(id, group, item_id).await
+    }
+    async fn compact(&self, id: &str) -> Result<u64> {
+        self.inner.compact(id).await
+    }
+    async fn drop_older_than(&self, id: &str, max_age: Duration) -> Result<u64> {
+        self.inner.drop_older_than(id, max_age).await
+    }
+    async fn expire_items(&self, id: &str) -> Result<ExpireReport> {
+        self.inner.expire_items(id).await
+    }
+    async fn health_check(&self) -> Result<HealthReport> {
+        self.inner.health_check().await
+    }
+    async fn pop(&self, id: &str) -> Result<Option<(String, ITEM)>> {
+        self.acquire(Operation::Receive, id).await?;
+        self.inner.pop(id).await
+    }
+    async fn drain(&self, id: &str, max: Option<usize>) -> Result<Vec<(String, ITEM)>>
+    where
+        ITEM: 'static,
+    {
+        self.acquire(Operation::Receive, id).await?;
+        self.inner.drain(id, max).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::Mailbox;
+    use crate::MailboxError;
+    use crate::MailboxItem;
+    use crate::MockMailbox;
+    use crate::RateLimit;
+    use crate::RateLimitConfig;
+    use crate::RateLimitMode;
+    use crate::RateLimitedMailbox;
+    use color_eyre::Result;
+    use std::time::Duration;
+    use tokio::time::Instant;
+
+    use test_log::test;
+
+    #[derive(Default, Debug)]
+    struct TestItem {}
+
+    impl MailboxItem for TestItem {
+        fn serialize(&self) -> Result<Vec<u8>> {
+            Ok(Vec::new())
+        }
+        fn deserialize(_data: &[u8]) -> Result<Self>
+        where
+            Self: Sized,
+        {
+            Ok(Self {})
+        }
+    }
+
+    #[test(tokio::test(start_paused = true))]
+    async fn it_waits_for_tokens() -> Result<()> {
+        let config = RateLimitConfig {
+            send: Some(RateLimit::per_second(10.0)),
+            ..Default::default()
+        };
+        let mailbox: Box<dyn Mailbox<TestItem>> = Box::new(RateLimitedMailbox::new(
+            MockMailbox::<TestItem>::default(),
+            config,
+        ));
+
+        let start = Instant::now();
+        for _ in 0..100 {
+            mailbox.send("42", TestItem::default()).await?;
+        }
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_millis(9_800), "{elapsed:?}");
+        assert!(elapsed <= Duration::from_millis(10_100), "{elapsed:?}");
+
+        // receive is not limited
+        let start = Instant::now();
+        for _ in 0..100 {
+            mailbox.receive("42").await?;
+        }
+        assert_eq!(start.elapsed(), Duration::ZERO);
+
+        Ok(())
+    }
+
+    #[test(tokio::test(start_paused = true))]
+    async fn it_rejects_without_delay() -> Result<()> {
+        let config = RateLimitConfig {
+            send: Some(RateLimit {
+                per_second: 10.0,
+                burst: 5,
+            }),
+            mode: RateLimitMode::Reject,
+            ..Default::default()
+        };
+        let mailbox = RateLimitedMailbox::new(MockMailbox::<TestItem>::default(), config);
+
+        let start = Instant::now();
+        for _ in 0..5 {
+            mailbox.send("42", TestItem::default()).await?;
+        }
+        let e = mailbox
+            .send("42", TestItem::default())
+            .await
+            .expect_err("Bucket is empty");
+        assert!(matches!(
+            e.downcast_ref::<MailboxError>(),
+            Some(MailboxError::RateLimited { retry_after, .. }) if *retry_after == Duration::from_millis(100)
+        ));
+        assert_eq!(start.elapsed(), Duration::ZERO);
+
+        tokio::time::advance(Duration::from_millis(100)).await;
+        mailbox.send("42", TestItem::default()).await?;
+
+        Ok(())
+    }
+
+    #[test(tokio::test(start_paused = true))]
+    async fn it_limits_per_mailbox() -> Result<()> {
+        let config = RateLimitConfig {
+            send: Some(RateLimit::per_second(1.0)),
+            per_mailbox: true,
+            mode: RateLimitMode::Reject,
+            ..Default::default()
+        };
+        let mailbox = RateLimitedMailbox::new(MockMailbox::<TestItem>::default(), config);
+
+        mailbox.send("1", TestItem::default()).await?;
+        mailbox.send("2", TestItem::default()).await?;
+        assert!(mailbox.send("1", TestItem::default()).await.is_err());
+        assert!(mailbox.send("2", TestItem::default()).await.is_err());
+
+        Ok(())
+    }
+
+    #[test(tokio::test(start_paused = true))]
+    async fn it_takes_all_tokens_of_a_transaction_or_none() -> Result<()> {
+        let config = RateLimitConfig {
+            send: Some(RateLimit {
+                per_second: 10.0,
+                burst: 3,
+            }),
+            mode: RateLimitMode::Reject,
+            ..Default::default()
+        };
+        let mailbox = RateLimitedMailbox::new(MockMailbox::<TestItem>::default(), config);
+
+        mailbox.send("42", TestItem::default()).await?;
+        let items = (0..3).map(|_| TestItem::default()).collect();
+        assert!(mailbox.send_transaction("42", items).await.is_err());
+        // the two tokens left are still there
+        let items = (0..2).map(|_| TestItem::default()).collect();
+        mailbox.send_transaction("42", items).await?;
+
+        // more items than the bucket holds never fit
+        tokio::time::advance(Duration::from_secs(10)).await;
+        let items = (0..4).map(|_| TestItem::default()).collect();
+        let e = mailbox
+            .send_transaction("42", items)
+            .await
+            .expect_err("Too many");
+        assert!(matches!(
+            e.downcast_ref::<MailboxError>(),
+            Some(MailboxError::RateLimited { retry_after, .. }) if *retry_after == Duration::MAX
+        ));
+
+        Ok(())
+    }
+
+    #[test(tokio::test(start_paused = true))]
+    async fn it_takes_a_token_per_received_item() -> Result<()> {
+        let config = RateLimitConfig {
+            receive: Some(RateLimit {
+                per_second: 1.0,
+                burst: 3,
+            }),
+            mode: RateLimitMode::Reject,
+            ..Default::default()
+        };
+        let mailbox = RateLimitedMailbox::new(MockMailbox::<TestItem>::default(), config);
+        for _ in 0..5 {
+            mailbox.send("42", TestItem::default()).await?;
+        }
+
+        let mailbox = &mailbox;
+        let acknowledge = |items: Vec<(String, TestItem)>| async move {
+            for (item_id, _) in &items {
+                mailbox.acknowledge("42", item_id).await?;
+            }
+            Result::<_>::Ok(items.len())
+        };
+
+        // only as many items as there are tokens
+        assert_eq!(acknowledge(mailbox.receive_many("42", 10).await?).await?, 3);
+        let e = mailbox
+            .receive_many("42", 10)
+            .await
+            .expect_err("Bucket is empty");
+        assert!(matches!(
+            e.downcast_ref::<MailboxError>(),
+            Some(MailboxError::RateLimited { .. })
+        ));
+        tokio::time::advance(Duration::from_secs(1)).await;
+        assert_eq!(acknowledge(mailbox.receive_many("42", 10).await?).await?, 1);
+
+        // unused tokens are put back
+        tokio::time::advance(Duration::from_secs(3)).await;
+        let items = mailbox
+            .receive_batch("42", 10, Duration::from_millis(10))
+            .await?;
+        assert_eq!(acknowledge(items).await?, 1);
+        assert!(mailbox.receive("42").await?.is_none());
+        assert!(mailbox.receive_many("42", 10).await?.is_empty());
+        assert!(mailbox.receive("42").await.is_err());
+
+        Ok(())
+    }
+
+    #[test(tokio::test(start_paused = true))]
+    async fn it_never_refills_without_a_rate() -> Result<()> {
+        let config = RateLimitConfig {
+            send: Some(RateLimit {
+                per_second: 0.0,
+                burst: 2,
+            }),
+            ..Default::default()
+        };
+        let mailbox = RateLimitedMailbox::new(MockMailbox::<TestItem>::default(), config);
+
+        mailbox.send("42", TestItem::default()).await?;
+        mailbox.send("42", TestItem::default()).await?;
+        tokio::time::advance(Duration::from_secs(3600)).await;
+        let e = mailbox
+            .send("42", TestItem::default())
+            .await
+            .expect_err("Bucket never refills");
+        assert!(matches!(
+            e.downcast_ref::<MailboxError>(),
+            Some(MailboxError::RateLimited { .. })
+        ));
+
+        Ok(())
+    }
+}
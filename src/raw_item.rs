@@ -0,0 +1,159 @@
+use crate::MailboxItem;
+use bytes::Bytes;
+use color_eyre::eyre::Result;
+
+/// An item that is just bytes, passed through unchanged
+///
+/// Useful for middleware that transforms the payload, e.g. [crate::EncryptedMailbox],
+/// or for shuffling opaque data around.
+/// Received items keep the buffer of the backend, without copying it.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct RawItem(pub Bytes);
+
+impl RawItem {
+    pub fn new(data: Vec<u8>) -> Self {
+        Self(data.into())
+    }
+
+    pub fn data(&self) -> &[u8] {
+        &self.0
+    }
+
+    /// Note: Only copies if the buffer is shared
+    pub fn into_data(self) -> Vec<u8> {
+        self.0.into()
+    }
+
+    pub fn into_bytes(self) -> Bytes {
+        self.0
+    }
+}
+
+impl From<Vec<u8>> for RawItem {
+    fn from(data: Vec<u8>) -> Self {
+        Self(data.into())
+    }
+}
+
+impl From<Bytes> for RawItem {
+    fn from(data: Bytes) -> Self {
+        Self(data)
+    }
+}
+
+impl MailboxItem for RawItem {
+    fn serialize(&self) -> Result<Vec<u8>> {
+        Ok(self.0.to_vec())
+    }
+    fn deserialize(data: &[u8]) -> Result<Self>
+    where
+        Self: Sized,
+    {
+        Ok(Self(Bytes::copy_from_slice(data)))
+    }
+    fn deserialize_from_bytes(data: Bytes) -> Result<Self>
+    where
+        Self: Sized,
+    {
+        Ok(Self(data))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::Mailbox;
+    use crate::MailboxDisk;
+    use crate::MailboxItem;
+    use crate::MailboxMemory;
+    use crate::RawItem;
+    use bytes::Bytes;
+    use color_eyre::Result;
+    use std::fs;
+    use std::path::Path;
+    use tempfile::TempDir;
+
+    use test_log::test;
+
+    /// Only implements the required methods, like items written before `deserialize_from_bytes`
+    #[derive(Debug, Default, PartialEq)]
+    struct PlainItem(String);
+
+    impl MailboxItem for PlainItem {
+        fn serialize(&self) -> Result<Vec<u8>> {
+            Ok(self.0.clone().into())
+        }
+        fn deserialize(data: &[u8]) -> Result<Self>
+        where
+            Self: Sized,
+        {
+            Ok(Self(String::from_utf8(data.to_vec())?))
+        }
+    }
+
+    #[test]
+    fn it_keeps_the_buffer() -> Result<()> {
+        let data = Bytes::from(b"payload".to_vec());
+        let ptr = data.as_ptr();
+        let item = RawItem::deserialize_from_bytes(data)?;
+        assert_eq!(item.data().as_ptr(), ptr);
+        let data = item.into_data();
+        assert_eq!(data.as_ptr(), ptr);
+
+        let plain = PlainItem::deserialize_from_bytes(Bytes::from_static(b"plain"))?;
+        assert_eq!(plain, PlainItem("plain".to_string()));
+
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn it_round_trips_through_disk() -> Result<()> {
+        let dir = TempDir::new()?;
+        let mut mailbox =
+            MailboxDisk::<RawItem>::at(dir.path(), Path::new("raw")).with_sidecar_threshold(64);
+        mailbox.ensure_storage_exists().await?;
+        let small = RawItem::new(b"small".to_vec());
+        let large = RawItem::from(Bytes::from(vec![7u8; 1000]));
+        mailbox.send("42", small.clone()).await?;
+        mailbox.send("42", large.clone()).await?;
+
+        let received: Vec<RawItem> = mailbox
+            .drain("42", None)
+            .await?
+            .into_iter()
+            .map(|(_id, item)| item)
+            .collect();
+        assert_eq!(received, [small, large]);
+
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn it_sends_and_receives_raw_bytes() -> Result<()> {
+        let dir = TempDir::new()?;
+        let mut disk =
+            MailboxDisk::<PlainItem>::at(dir.path(), Path::new("plain")).with_sidecar_threshold(64);
+        disk.ensure_storage_exists().await?;
+        let memory = MailboxMemory::<RawItem>::new();
+        let binary = b"nul\0invalid\xff\xfeutf8".to_vec();
+        let large = [binary.as_slice(); 10].concat();
+
+        // Note: the disk doesn't deserialize, so even bytes a `PlainItem` can't hold pass through
+        disk.send_raw("42", &binary).await?;
+        disk.send_raw("42", &large).await?;
+        memory.send_raw("42", &binary).await?;
+        memory.send_raw("42", &large).await?;
+
+        let envelope = fs::read_to_string(dir.path().join("42").join("1.plain"))?;
+        assert!(envelope.contains("\u{fffd}"));
+        for expected in [&binary, &large] {
+            let (item_id, data) = disk.receive_raw("42").await?.expect("Item pending");
+            assert_eq!(&data, expected);
+            disk.acknowledge("42", &item_id).await?;
+            let (item_id, data) = memory.receive_raw("42").await?.expect("Item pending");
+            assert_eq!(&data, expected);
+            memory.acknowledge("42", &item_id).await?;
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,36 @@
+use crate::MailboxError;
+use color_eyre::eyre::Report;
+
+/// Why a mailbox won't deliver anything, see [ReceiveStatus::Closed]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClosedReason {
+    /// The mailbox doesn't exist, it was removed, or never created
+    Deleted,
+    /// The mailbox is frozen for receiving, see [crate::FreezeMode::NoReceive]
+    Frozen,
+    /// The backend was closed, see [crate::Mailbox::close]
+    BackendClosed,
+}
+
+impl ClosedReason {
+    /// The reason for errors that mean a mailbox is closed, `None` for all others
+    pub fn of(report: &Report) -> Option<Self> {
+        match report.downcast_ref::<MailboxError>()? {
+            MailboxError::UnknownMailbox { .. } => Some(ClosedReason::Deleted),
+            MailboxError::Frozen { .. } => Some(ClosedReason::Frozen),
+            MailboxError::Closed => Some(ClosedReason::BackendClosed),
+            _ => None,
+        }
+    }
+}
+
+/// What [crate::Mailbox::receive_status] found
+#[derive(Debug)]
+pub enum ReceiveStatus<ITEM> {
+    /// The item id, and the item
+    Received(String, ITEM),
+    /// Nothing right now, items might still come
+    Empty,
+    /// Nothing until the reason goes away, which might be never
+    Closed(ClosedReason),
+}
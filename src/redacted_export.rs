@@ -0,0 +1,34 @@
+use serde::Deserialize;
+use serde::Serialize;
+
+/// One file of a mailbox in the archive written by [crate::MailboxDisk::redact_export]
+///
+/// The archive has one entry per line, as JSON, sorted by path.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ExportEntry {
+    /// Relative to the mailbox folder, `/` separated, e.g. `.quarantine/2.item`
+    pub path: String,
+    pub content: ExportContent,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum ExportContent {
+    /// Files without item data, e.g. the meta, the journal, and status files, as they are
+    Verbatim { text: String },
+    /// An envelope without payload and `debug`, `payload_len` and `payload_sha256` are always set
+    Envelope { envelope: serde_json::Value },
+    /// Everything else, e.g. sidecars, and envelopes that can't be parsed
+    Redacted { len: u64, sha256: String },
+}
+
+/// What [crate::MailboxDisk::redact_export] wrote
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ExportSummary {
+    pub files: u64,
+    pub envelopes: u64,
+    /// Files only exported with their length and checksum
+    pub redacted_files: u64,
+    /// Headers not on the allowlist, summed over all envelopes
+    pub dropped_headers: u64,
+}
@@ -0,0 +1,14 @@
+use serde::Deserialize;
+use serde::Serialize;
+
+/// How many items [crate::MailboxDisk] keeps per mailbox, see [crate::MailboxDisk::with_retention]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RetentionPolicy {
+    /// Read and unread items kept, the oldest read items are removed first
+    pub max_items: u64,
+    /// Also drop the oldest unread items, when there are more than `max_items` of them
+    ///
+    /// They are skipped without being acknowledged, and counted in [crate::MailboxStats::total_dropped_unread].
+    #[serde(default)]
+    pub drop_unread: bool,
+}
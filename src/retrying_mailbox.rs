@@ -0,0 +1,441 @@
+use crate::ExpireReport;
+use crate::HeaderSelector;
+use crate::HealthReport;
+use crate::ItemSummary;
+use crate::Mailbox;
+use crate::MailboxError;
+use crate::MailboxItem;
+use crate::MailboxStats;
+use crate::Page;
+use crate::ReceiveStatus;
+use async_trait::async_trait;
+use color_eyre::eyre::Result;
+use core::future::Future;
+use core::marker::PhantomData;
+use std::time::Duration;
+
+/// How often, and how patiently, [RetryingMailbox] retries
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    /// Total number of attempts, including the first one
+    pub max_attempts: u32,
+    /// Delay before the first retry, doubled for every further retry
+    pub base_delay: Duration,
+    /// Upper bound for the delay between two attempts
+    pub max_delay: Duration,
+    /// Fraction (0.0 - 1.0) of each delay that is randomized
+    pub jitter: f64,
+    /// If failed sends are retried at all, see [RetryingMailbox] for why you might not want that
+    pub retry_send: bool,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 3,
+            base_delay: Duration::from_millis(100),
+            max_delay: Duration::from_secs(5),
+            jitter: 0.5,
+            retry_send: true,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// The delay before the given retry (1 being the first retry)
+    pub fn delay(&self, retry: u32) -> Duration {
+        let factor = 2u32.saturating_pow(retry.saturating_sub(1));
+        let delay = self.base_delay.saturating_mul(factor).min(self.max_delay);
+        let jitter = self.jitter.clamp(0.0, 1.0);
+        if jitter > 0.0 {
+            delay.mul_f64(1.0 - jitter * fastrand::f64())
+        } else {
+            delay
+        }
+    }
+}
+
+/// Wraps any mailbox, and retries operations that failed with a retryable error
+///
+/// Errors are classified via [MailboxError::is_retryable_report],
+/// so e.g. IO errors and timeouts are retried, while [MailboxError::NotFound] is returned right away.
+///
+/// Note: A send can fail after the inner backend already stored the item (e.g. a timeout on the way back),
+/// so retrying it can produce duplicates. Consumers must be prepared for that,
+/// or set [RetryPolicy::retry_send] to `false`.
+///
+/// `drain` is never retried, since a failed drain already returns the drained items inside its error.
+#[derive(Debug)]
+pub struct RetryingMailbox<ITEM: MailboxItem, M: Mailbox<ITEM>> {
+    inner: M,
+    policy: RetryPolicy,
+    item_type: PhantomData<ITEM>,
+}
+
+impl<ITEM: MailboxItem, M: Mailbox<ITEM>> RetryingMailbox<ITEM, M> {
+    pub fn new(inner: M, policy: RetryPolicy) -> Self {
+        Self {
+            inner,
+            policy,
+            item_type: PhantomData,
+        }
+    }
+
+    pub fn inner(&self) -> &M {
+        &self.inner
+    }
+
+    pub fn into_inner(self) -> M {
+        self.inner
+    }
+
+    async fn retry<T, F, Fut>(&self, op: &str, mut f: F) -> Result<T>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T>>,
+    {
+        let mut attempt = 1;
+        loop {
+            match f().await {
+                Ok(r) => return Ok(r),
+                Err(e)
+                    if attempt < self.policy.max_attempts
+                        && MailboxError::is_retryable_report(&e) =>
+                {
+                    let delay = self.policy.delay(attempt);
+                    tracing::warn!(
+                        "{op} failed (attempt {attempt}/{}), retrying in {delay:?} -> {e}",
+                        self.policy.max_attempts
+                    );
+                    tokio::time::sleep(delay).await;
+                    attempt += 1;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl<ITEM: MailboxItem + Send, M: Mailbox<ITEM>> Mailbox<ITEM> for RetryingMailbox<ITEM, M> {
+    async fn ensure_storage_exists(&mut self) -> Result<()> {
+        let mut attempt = 1;
+        loop {
+            match self.inner.ensure_storage_exists().await {
+                Ok(()) => return Ok(()),
+                Err(e)
+                    if attempt < self.policy.max_attempts
+                        && MailboxError::is_retryable_report(&e) =>
+                {
+                    let delay = self.policy.delay(attempt);
+                    tracing::warn!("ensure_storage_exists failed, retrying in {delay:?} -> {e}");
+                    tokio::time::sleep(delay).await;
+                    attempt += 1;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+    async fn close(&mut self) -> Result<()> {
+        self.inner.close().await
+    }
+
+    async fn create_mailbox(&self, id: &str) -> Result<()> {
+        self.retry("create_mailbox", || self.inner.create_mailbox(id))
+            .await
+    }
+    async fn send(&self, id: &str, item: ITEM) -> Result<String> {
+        if !self.policy.retry_send {
+            return self.inner.send(id, item).await;
+        }
+        // Note: the item is consumed by every attempt, so we keep a serialized copy for the retries
+        let data = item.serialize()?;
+        let mut item = Some(item);
+        self.retry("send", || {
+            let item = match item.take() {
+                Some(item) => Ok(item),
+                None => ITEM::deserialize(&data),
+            };
+            async move { self.inner.send(id, item?).await }
+        })
+        .await
+    }
+    async fn send_transaction(&self, id: &str, items: Vec<ITEM>) -> Result<Vec<String>> {
+        if !self.policy.retry_send {
+            return self.inner.send_transaction(id, items).await;
+        }
+        // Note: a failed transaction leaves nothing behind, so it can always be retried
+        let data = items
+            .iter()
+            .map(|item| item.serialize())
+            .collect::<Result<Vec<_>>>()?;
+        let mut items = Some(items);
+        self.retry("send_transaction", || {
+            let items = match items.take() {
+                Some(items) => Ok(items),
+                None => data.iter().map(|d| ITEM::deserialize(d)).collect(),
+            };
+            async move { self.inner.send_transaction(id, items?).await }
+        })
+        .await
+    }
+    async fn receive(&self, id: &str) -> Result<Option<(String, ITEM)>> {
+        self.retry("receive", || self.inner.receive(id)).await
+    }
+    async fn receive_status(&self, id: &str) -> Result<ReceiveStatus<ITEM>> {
+        self.retry("receive_status", || self.inner.receive_status(id))
+            .await
+    }
+    async fn receive_any(&self, ids: &[String]) -> Result<Option<(String, String, ITEM)>> {
+        self.retry("receive_any", || self.inner.receive_any(ids))
+            .await
+    }
+    async fn acknowledge(&self, id: &str, item_id: &str) -> Result<()> {
+        self.retry("acknowledge", || self.inner.acknowledge(id, item_id))
+            .await
+    }
+    async fn acknowledge_through(&self, id: &str, item_id: &str) -> Result<u64> {
+        self.retry("acknowledge_through", || {
+            self.inner.acknowledge_through(id, item_id)
+        })
+        .await
+    }
+    async fn release(&self, id: &str, item_id: &str) -> Result<()> {
+        self.retry("release", || self.inner.release(id, item_id))
+            .await
+    }
+    fn release_later(&self, id: &str, item_id: &str) {
+        self.inner.release_later(id, item_id)
+    }
+    async fn update(&self, id: &str, item_id: &str, item: ITEM) -> Result<()> {
+        // Note: updating twice is harmless, so unlike send this is always retried
+        let data = item.serialize()?;
+        let mut item = Some(item);
+        self.retry("update", || {
+            let item = match item.take() {
+                Some(item) => Ok(item),
+                None => ITEM::deserialize(&data),
+            };
+            async move { self.inner.update(id, item_id, item?).await }
+        })
+        .await
+    }
+    async fn peek(&self, id: &str) -> Result<Option<(String, ITEM)>> {
+        self.retry("peek", || self.inner.peek(id)).await
+    }
+    async fn receive_many(&self, id: &str, max: usize) -> Result<Vec<(String, ITEM)>> {
+        self.retry("receive_many", || self.inner.receive_many(id, max))
+            .await
+    }
+    /// Note: Not retried, the wait would start over
+    async fn receive_batch(
+        &self,
+        id: &str,
+        max_items: usize,
+        max_wait: Duration,
+    ) -> Result<Vec<(String, ITEM)>> {
+        self.inner.receive_batch(id, max_items, max_wait).await
+    }
+    async fn receive_matching(
+        &self,
+        id: &str,
+        selector: &HeaderSelector,
+    ) -> Result<Option<(String, ITEM)>> {
+        self.retry("receive_matching", || {
+            self.inner.receive_matching(id, selector)
+        })
+        .await
+    }
+    async fn list_mailboxes(&self) -> Result<Vec<String>> {
+        self.retry("list_mailboxes", || self.inner.list_mailboxes())
+            .await
+    }
+    async fn delivery_count(&self, id: &str, item_id: &str) -> Result<u32> {
+        self.retry("delivery_count", || self.inner.delivery_count(id, item_id))
+            .await
+    }
+    async fn list_items_page(
+        &self,
+        id: &str,
+        after: Option<&str>,
+        limit: usize,
+    ) -> Result<Page<ItemSummary>> {
+        self.retry("list_items_page", || {
+            self.inner.list_items_page(id, after, limit)
+        })
+        .await
+    }
+    async fn item_size(&self, id: &str, item_id: &str) -> Result<u64> {
+        self.retry("item_size", || self.inner.item_size(id, item_id))
+            .await
+    }
+    async fn stats(&self, id: &str) -> Result<MailboxStats> {
+        self.retry("stats", || self.inner.stats(id)).await
+    }
+    async fn receive_for(&self, id: &str, group: &str) -> Result<Option<(String, ITEM)>> {
+        self.retry("receive_for", || self.inner.receive_for(id, group))
+            .await
+    }
+    async fn acknowledge_for(&self, id: &str, group: &str, item_id: &str) -> Result<()> {
+        self.retry("acknowledge_for", || {
+            self.inner.acknowledge_for(id, group, item_id)
+        })
+        .await
+    }
+    async fn compact(&self, id: &str) -> Result<u64> {
+        self.retry("compact", || self.inner.compact(id)).await
+    }
+    async fn drop_older_than(&self, id: &str, max_age: Duration) -> Result<u64> {
+        self.retry("drop_older_than", || {
+            self.inner.drop_older_than(id, max_age)
+        })
+        .await
+    }
+    async fn expire_items(&self, id: &str) -> Result<ExpireReport> {
+        self.retry("expire_items", || self.inner.expire_items(id))
+            .await
+    }
+    async fn health_check(&self) -> Result<HealthReport> {
+        self.inner.health_check().await
+    }
+    async fn pop(&self, id: &str) -> Result<Option<(String, ITEM)>> {
+        self.retry("pop", || self.inner.pop(id)).await
+    }
+    async fn drain(&self, id: &str, max: Option<usize>) -> Result<Vec<(String, ITEM)>>
+    where
+        ITEM: 'static,
+    {
+        self.inner.drain(id, max).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::Mailbox;
+    use crate::MailboxError;
+    use crate::MailboxItem;
+    use crate::MockMailbox;
+    use crate::MockOperation;
+    use crate::RetryPolicy;
+    use crate::RetryingMailbox;
+    use color_eyre::Result;
+    use std::time::Duration;
+
+    use test_log::test;
+
+    #[derive(Default, Debug, PartialEq)]
+    struct TestItem {
+        data: String,
+    }
+
+    impl MailboxItem for TestItem {
+        fn serialize(&self) -> Result<Vec<u8>> {
+            Ok(self.data.clone().into())
+        }
+        fn deserialize(data: &[u8]) -> Result<Self>
+        where
+            Self: Sized,
+        {
+            Ok(Self {
+                data: String::from_utf8(data.to_vec())?,
+            })
+        }
+    }
+
+    fn io_error() -> MailboxError {
+        MailboxError::Io(std::io::ErrorKind::Interrupted.into())
+    }
+
+    fn policy() -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: 3,
+            base_delay: Duration::from_millis(100),
+            max_delay: Duration::from_secs(1),
+            jitter: 0.0,
+            retry_send: true,
+        }
+    }
+
+    #[test(tokio::test(start_paused = true))]
+    async fn it_succeeds_on_the_third_attempt() -> Result<()> {
+        let mock = MockMailbox::<TestItem>::default();
+        mock.fail_next(MockOperation::Send, io_error());
+        mock.fail_next(MockOperation::Send, io_error());
+        let mailbox = RetryingMailbox::new(mock, policy());
+
+        let start = tokio::time::Instant::now();
+        let id = mailbox.send("42", TestItem { data: "one".into() }).await?;
+        // 100ms + 200ms
+        assert_eq!(start.elapsed(), Duration::from_millis(300));
+        assert_eq!(mailbox.inner().calls(MockOperation::Send), 3);
+
+        mailbox
+            .inner()
+            .fail_next(MockOperation::Receive, io_error());
+        let (received_id, item) = mailbox.receive("42").await?.expect("Item pending");
+        assert_eq!(received_id, id);
+        assert_eq!(item.data, "one");
+        assert_eq!(mailbox.inner().calls(MockOperation::Receive), 2);
+
+        Ok(())
+    }
+
+    #[test(tokio::test(start_paused = true))]
+    async fn it_respects_max_attempts() -> Result<()> {
+        let mock = MockMailbox::<TestItem>::default();
+        for _ in 0..5 {
+            mock.fail_next(MockOperation::Receive, io_error());
+        }
+        let mailbox = RetryingMailbox::new(mock, policy());
+
+        assert!(mailbox.receive("42").await.is_err());
+        assert_eq!(mailbox.inner().calls(MockOperation::Receive), 3);
+
+        Ok(())
+    }
+
+    #[test(tokio::test(start_paused = true))]
+    async fn it_does_not_retry_permanent_errors() -> Result<()> {
+        let mailbox = RetryingMailbox::new(MockMailbox::<TestItem>::default(), policy());
+
+        let e = mailbox
+            .acknowledge("42", "1")
+            .await
+            .expect_err("Nothing to acknowledge");
+        assert!(matches!(
+            e.downcast_ref::<MailboxError>(),
+            Some(MailboxError::NotFound { .. })
+        ));
+        assert_eq!(mailbox.inner().calls(MockOperation::Acknowledge), 1);
+
+        let mut no_send_retry = policy();
+        no_send_retry.retry_send = false;
+        let mock = MockMailbox::<TestItem>::default();
+        mock.fail_next(MockOperation::Send, io_error());
+        let mailbox = RetryingMailbox::new(mock, no_send_retry);
+        assert!(mailbox.send("42", TestItem::default()).await.is_err());
+        assert_eq!(mailbox.inner().calls(MockOperation::Send), 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn it_backs_off_exponentially() {
+        let policy = policy();
+        assert_eq!(policy.delay(1), Duration::from_millis(100));
+        assert_eq!(policy.delay(2), Duration::from_millis(200));
+        assert_eq!(policy.delay(3), Duration::from_millis(400));
+        assert_eq!(policy.delay(5), Duration::from_secs(1));
+        assert_eq!(policy.delay(100), Duration::from_secs(1));
+
+        let jittered = RetryPolicy {
+            jitter: 0.5,
+            ..policy
+        };
+        for _ in 0..100 {
+            let delay = jittered.delay(2);
+            assert!(delay >= Duration::from_millis(100));
+            assert!(delay <= Duration::from_millis(200));
+        }
+    }
+}
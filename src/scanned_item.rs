@@ -0,0 +1,38 @@
+use crate::MailboxItem;
+use crate::ProvenanceEntry;
+use chrono::DateTime;
+use chrono::Utc;
+use color_eyre::eyre::Result;
+use core::marker::PhantomData;
+use std::collections::BTreeMap;
+
+/// One item seen by [crate::MailboxDisk::scan]
+///
+/// The payload is kept serialized, so a broken item doesn't abort the scan, see [ScannedItem::decode].
+#[derive(Debug, Clone)]
+pub struct ScannedItem<ITEM: MailboxItem> {
+    pub item_id: String,
+    /// Read by the default group
+    pub read: bool,
+    pub delivery_count: u32,
+    /// `None` for items stored before the time was kept
+    pub sent_at: Option<DateTime<Utc>>,
+    pub updated_at: Option<DateTime<Utc>>,
+    pub headers: BTreeMap<String, String>,
+    /// Where the item was moved from, oldest first, empty if it never moved
+    pub provenance: Vec<ProvenanceEntry>,
+    /// The serialized item
+    pub data: Vec<u8>,
+    pub(crate) item_type: PhantomData<ITEM>,
+}
+
+impl<ITEM: MailboxItem> ScannedItem<ITEM> {
+    pub fn decode(&self) -> Result<ITEM> {
+        ITEM::deserialize(&self.data)
+    }
+
+    /// The length of the serialized item, same as [crate::Mailbox::item_size]
+    pub fn size(&self) -> u64 {
+        self.data.len() as u64
+    }
+}
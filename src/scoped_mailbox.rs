@@ -0,0 +1,332 @@
+use crate::ExpireReport;
+use crate::HeaderSelector;
+use crate::HealthReport;
+use crate::ItemSummary;
+use crate::Mailbox;
+use crate::MailboxError;
+use crate::MailboxItem;
+use crate::MailboxStats;
+use crate::Page;
+use crate::ReceiveStatus;
+use async_trait::async_trait;
+use color_eyre::eyre::Result;
+use core::marker::PhantomData;
+use std::time::Duration;
+
+/// Separates the scope from the mailbox id, [crate::MailboxDisk] stores scoped mailboxes in a folder per scope
+pub const SCOPE_SEPARATOR: char = '/';
+
+/// A view on the mailboxes of one scope, e.g. a tenant, of a shared backend
+///
+/// Every mailbox id `id` is stored as `{scope}/{id}` in the inner mailbox,
+/// and `list_mailboxes` only returns the mailboxes of the scope, without the prefix.
+/// Ids, and the scope itself, can't contain the [SCOPE_SEPARATOR] or a `\`,
+/// can't start with `.` or `~`, and can't be empty,
+/// so there is no way to reach the mailboxes of another scope.
+/// Everything else fails with [MailboxError::InvalidId].
+///
+/// Note: Item ids are passed through unchanged, they are only unique within a mailbox anyway.
+#[derive(Debug)]
+pub struct ScopedMailbox<ITEM: MailboxItem, M: Mailbox<ITEM>> {
+    inner: M,
+    prefix: String,
+    item_type: PhantomData<ITEM>,
+}
+
+impl<ITEM: MailboxItem, M: Mailbox<ITEM>> ScopedMailbox<ITEM, M> {
+    pub fn new(inner: M, scope: &str) -> Result<Self> {
+        check_segment(scope)?;
+        Ok(Self {
+            inner,
+            prefix: format!("{scope}{SCOPE_SEPARATOR}"),
+            item_type: PhantomData,
+        })
+    }
+
+    pub fn scope(&self) -> &str {
+        self.prefix.trim_end_matches(SCOPE_SEPARATOR)
+    }
+
+    pub fn inner(&self) -> &M {
+        &self.inner
+    }
+
+    pub fn into_inner(self) -> M {
+        self.inner
+    }
+
+    /// The id of the mailbox in the inner mailbox
+    fn scoped(&self, mailbox_id: &str) -> Result<String> {
+        check_segment(mailbox_id)?;
+        Ok(format!("{}{mailbox_id}", self.prefix))
+    }
+
+    /// The id of an inner mailbox within the scope, `None` for mailboxes outside of it
+    fn unscoped<'a>(&self, mailbox_id: &'a str) -> Option<&'a str> {
+        mailbox_id
+            .strip_prefix(&self.prefix)
+            .filter(|id| check_segment(id).is_ok())
+    }
+}
+
+/// Rejects everything that could reach outside of a scope, or isn't listed by [crate::MailboxDisk]
+fn check_segment(id: &str) -> Result<()> {
+    let valid = !id.is_empty()
+        && !id.starts_with(['.', '~'])
+        && !id.contains([SCOPE_SEPARATOR, '\\', '\0']);
+    if !valid {
+        return Err(MailboxError::InvalidId { id: id.to_string() }.into());
+    }
+    Ok(())
+}
+
+#[async_trait]
+impl<ITEM: MailboxItem + Send + 'static, M: Mailbox<ITEM>> Mailbox<ITEM>
+    for ScopedMailbox<ITEM, M>
+{
+    async fn ensure_storage_exists(&mut self) -> Result<()> {
+        self.inner.ensure_storage_exists().await
+    }
+    async fn close(&mut self) -> Result<()> {
+        self.inner.close().await
+    }
+
+    async fn create_mailbox(&self, id: &str) -> Result<()> {
+        self.inner.create_mailbox(&self.scoped(id)?).await
+    }
+    async fn send(&self, id: &str, item: ITEM) -> Result<String> {
+        self.inner.send(&self.scoped(id)?, item).await
+    }
+    async fn send_transaction(&self, id: &str, items: Vec<ITEM>) -> Result<Vec<String>> {
+        self.inner.send_transaction(&self.scoped(id)?, items).await
+    }
+    async fn receive(&self, id: &str) -> Result<Option<(String, ITEM)>> {
+        self.inner.receive(&self.scoped(id)?).await
+    }
+    async fn receive_status(&self, id: &str) -> Result<ReceiveStatus<ITEM>> {
+        self.inner.receive_status(&self.scoped(id)?).await
+    }
+    async fn acknowledge(&self, id: &str, item_id: &str) -> Result<()> {
+        self.inner.acknowledge(&self.scoped(id)?, item_id).await
+    }
+    async fn send_raw(&self, id: &str, data: &[u8]) -> Result<String> {
+        self.inner.send_raw(&self.scoped(id)?, data).await
+    }
+    async fn receive_raw(&self, id: &str) -> Result<Option<(String, Vec<u8>)>> {
+        self.inner.receive_raw(&self.scoped(id)?).await
+    }
+    async fn acknowledge_through(&self, id: &str, item_id: &str) -> Result<u64> {
+        self.inner
+            .acknowledge_through(&self.scoped(id)?, item_id)
+            .await
+    }
+    async fn release(&self, id: &str, item_id: &str) -> Result<()> {
+        self.inner.release(&self.scoped(id)?, item_id).await
+    }
+    /// Note: Nothing is recorded for an invalid id, nothing could have been received with it.
+    fn release_later(&self, id: &str, item_id: &str) {
+        if let Ok(id) = self.scoped(id) {
+            self.inner.release_later(&id, item_id)
+        }
+    }
+    async fn update(&self, id: &str, item_id: &str, item: ITEM) -> Result<()> {
+        self.inner.update(&self.scoped(id)?, item_id, item).await
+    }
+    async fn peek(&self, id: &str) -> Result<Option<(String, ITEM)>> {
+        self.inner.peek(&self.scoped(id)?).await
+    }
+    async fn receive_many(&self, id: &str, max: usize) -> Result<Vec<(String, ITEM)>> {
+        self.inner.receive_many(&self.scoped(id)?, max).await
+    }
+    async fn receive_any(&self, ids: &[String]) -> Result<Option<(String, String, ITEM)>> {
+        let scoped = ids
+            .iter()
+            .map(|id| self.scoped(id))
+            .collect::<Result<Vec<_>>>()?;
+        match self.inner.receive_any(&scoped).await? {
+            Some((id, item_id, item)) => {
+                let id = id.strip_prefix(&self.prefix).unwrap_or(&id).to_string();
+                Ok(Some((id, item_id, item)))
+            }
+            None => Ok(None),
+        }
+    }
+    async fn receive_batch(
+        &self,
+        id: &str,
+        max_items: usize,
+        max_wait: Duration,
+    ) -> Result<Vec<(String, ITEM)>> {
+        self.inner
+            .receive_batch(&self.scoped(id)?, max_items, max_wait)
+            .await
+    }
+    async fn receive_matching(
+        &self,
+        id: &str,
+        selector: &HeaderSelector,
+    ) -> Result<Option<(String, ITEM)>> {
+        self.inner
+            .receive_matching(&self.scoped(id)?, selector)
+            .await
+    }
+    async fn list_mailboxes(&self) -> Result<Vec<String>> {
+        let ids = self.inner.list_mailboxes().await?;
+        Ok(ids
+            .iter()
+            .filter_map(|id| self.unscoped(id))
+            .map(String::from)
+            .collect())
+    }
+    // Note: `list_mailboxes_page` uses the default implementation,
+    // the inner cursor would page through all scopes.
+    async fn list_items_page(
+        &self,
+        id: &str,
+        after: Option<&str>,
+        limit: usize,
+    ) -> Result<Page<ItemSummary>> {
+        self.inner
+            .list_items_page(&self.scoped(id)?, after, limit)
+            .await
+    }
+    async fn delivery_count(&self, id: &str, item_id: &str) -> Result<u32> {
+        self.inner.delivery_count(&self.scoped(id)?, item_id).await
+    }
+    async fn item_size(&self, id: &str, item_id: &str) -> Result<u64> {
+        self.inner.item_size(&self.scoped(id)?, item_id).await
+    }
+    async fn stats(&self, id: &str) -> Result<MailboxStats> {
+        self.inner.stats(&self.scoped(id)?).await
+    }
+    async fn receive_for(&self, id: &str, group: &str) -> Result<Option<(String, ITEM)>> {
+        self.inner.receive_for(&self.scoped(id)?, group).await
+    }
+    async fn acknowledge_for(&self, id: &str, group: &str, item_id: &str) -> Result<()> {
+        self.inner
+            .acknowledge_for(&self.scoped(id)?, group, item_id)
+            .await
+    }
+    async fn compact(&self, id: &str) -> Result<u64> {
+        self.inner.compact(&self.scoped(id)?).await
+    }
+    async fn drop_older_than(&self, id: &str, max_age: Duration) -> Result<u64> {
+        self.inner.drop_older_than(&self.scoped(id)?, max_age).await
+    }
+    async fn health_check(&self) -> Result<HealthReport> {
+        self.inner.health_check().await
+    }
+    async fn expire_items(&self, id: &str) -> Result<ExpireReport> {
+        self.inner.expire_items(&self.scoped(id)?).await
+    }
+    async fn pop(&self, id: &str) -> Result<Option<(String, ITEM)>> {
+        self.inner.pop(&self.scoped(id)?).await
+    }
+    async fn drain(&self, id: &str, max: Option<usize>) -> Result<Vec<(String, ITEM)>>
+    where
+        ITEM: 'static,
+    {
+        self.inner.drain(&self.scoped(id)?, max).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::Mailbox;
+    use crate::MailboxDisk;
+    use crate::MailboxError;
+    use crate::MailboxItem;
+    use crate::ScopedMailbox;
+    use color_eyre::Result;
+    use std::path::Path;
+    use std::sync::Arc;
+    use tempfile::TempDir;
+
+    use test_log::test;
+
+    #[derive(Debug, Default)]
+    struct TestItem {
+        data: String,
+    }
+
+    impl MailboxItem for TestItem {
+        fn serialize(&self) -> Result<Vec<u8>> {
+            Ok(self.data.clone().into())
+        }
+        fn deserialize(data: &[u8]) -> Result<Self>
+        where
+            Self: Sized,
+        {
+            Ok(Self {
+                data: String::from_utf8(data.to_vec())?,
+            })
+        }
+    }
+
+    fn item(data: &str) -> TestItem {
+        TestItem {
+            data: data.to_string(),
+        }
+    }
+
+    #[test(tokio::test)]
+    async fn it_isolates_scopes() -> Result<()> {
+        let dir = TempDir::new()?;
+        let mut disk = MailboxDisk::<TestItem>::at(dir.path(), Path::new("item"));
+        disk.ensure_storage_exists().await?;
+        let disk = Arc::new(disk);
+        disk.send("orders", item("unscoped")).await?;
+        let tenant1 = ScopedMailbox::new(disk.clone(), "tenant1")?;
+        let tenant2 = ScopedMailbox::new(disk.clone(), "tenant2")?;
+
+        tenant1.send("orders", item("one")).await?;
+        tenant2.send("orders", item("two")).await?;
+        tenant2.send("invoices", item("three")).await?;
+        assert!(dir.path().join("tenant1").join("orders").is_dir());
+
+        assert_eq!(tenant1.list_mailboxes().await?, ["orders"]);
+        assert_eq!(tenant2.list_mailboxes().await?, ["invoices", "orders"]);
+        assert_eq!(
+            disk.list_mailboxes().await?,
+            [
+                "orders",
+                "tenant1/orders",
+                "tenant2/invoices",
+                "tenant2/orders"
+            ]
+        );
+
+        let (_id, one) = tenant1.pop("orders").await?.expect("Item pending");
+        assert_eq!(one.data, "one");
+        assert!(tenant1.pop("orders").await?.is_none());
+        assert!(tenant1.pop("invoices").await?.is_none());
+        let (_id, two) = tenant2.pop("orders").await?.expect("Item pending");
+        assert_eq!(two.data, "two");
+        let (_id, unscoped) = disk.pop("orders").await?.expect("Item pending");
+        assert_eq!(unscoped.data, "unscoped");
+
+        for id in [
+            "",
+            "../tenant2/orders",
+            "..",
+            "a/b",
+            ".trash",
+            "~bxyz",
+            "a\\b",
+        ] {
+            let e = tenant1.send(id, item("escaped")).await.expect_err(id);
+            assert!(
+                matches!(
+                    e.downcast_ref::<MailboxError>(),
+                    Some(MailboxError::InvalidId { .. })
+                ),
+                "{id:?}"
+            );
+        }
+        assert!(ScopedMailbox::new(disk.clone(), "../tenant2").is_err());
+        assert_eq!(tenant2.stats("invoices").await?.pending, 1);
+        assert_eq!(tenant2.stats("orders").await?.pending, 0);
+
+        Ok(())
+    }
+}
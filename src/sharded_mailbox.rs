@@ -0,0 +1,282 @@
+use crate::ExpireReport;
+use crate::HeaderSelector;
+use crate::HealthReport;
+use crate::ItemSummary;
+use crate::Mailbox;
+use crate::MailboxItem;
+use crate::MailboxStats;
+use crate::Page;
+use crate::ReceiveAnyTurn;
+use async_trait::async_trait;
+use color_eyre::eyre::eyre;
+use color_eyre::eyre::Result;
+use std::time::Duration;
+
+/// Spreads mailboxes over multiple backends, by hashing the mailbox id
+///
+/// Every operation for a mailbox goes to `shards[fnv1a(mailbox_id) % shards.len()]`,
+/// with [ShardedMailbox::fnv1a] being the 64 bit FNV-1a hash over the UTF-8 bytes of the id.
+///
+/// Note: The hash, and the number and order of shards, decide where a mailbox lives.
+/// Changing any of them orphans existing data.
+#[derive(Debug)]
+pub struct ShardedMailbox<ITEM: MailboxItem> {
+    shards: Vec<Box<dyn Mailbox<ITEM>>>,
+    receive_any_turn: ReceiveAnyTurn,
+}
+
+impl<ITEM: MailboxItem> ShardedMailbox<ITEM> {
+    /// # Panics
+    ///
+    /// If `shards` is empty.
+    pub fn new(shards: Vec<Box<dyn Mailbox<ITEM>>>) -> Self {
+        assert!(
+            !shards.is_empty(),
+            "ShardedMailbox needs at least one shard"
+        );
+        Self {
+            shards,
+            receive_any_turn: Default::default(),
+        }
+    }
+
+    pub fn shards(&self) -> &[Box<dyn Mailbox<ITEM>>] {
+        &self.shards
+    }
+
+    /// 64 bit FNV-1a, stable across runs and platforms
+    pub fn fnv1a(data: &[u8]) -> u64 {
+        const OFFSET_BASIS: u64 = 0xcbf29ce484222325;
+        const PRIME: u64 = 0x100000001b3;
+
+        data.iter().fold(OFFSET_BASIS, |hash, b| {
+            (hash ^ u64::from(*b)).wrapping_mul(PRIME)
+        })
+    }
+
+    /// The index of the shard the mailbox lives on, e.g. for debugging
+    pub fn shard_index(&self, mailbox_id: &str) -> usize {
+        (Self::fnv1a(mailbox_id.as_bytes()) % self.shards.len() as u64) as usize
+    }
+
+    fn shard(&self, mailbox_id: &str) -> &dyn Mailbox<ITEM> {
+        self.shards[self.shard_index(mailbox_id)].as_ref()
+    }
+}
+
+#[async_trait]
+impl<ITEM: MailboxItem + Send + 'static> Mailbox<ITEM> for ShardedMailbox<ITEM> {
+    async fn ensure_storage_exists(&mut self) -> Result<()> {
+        for shard in self.shards.iter_mut() {
+            shard.ensure_storage_exists().await?;
+        }
+        Ok(())
+    }
+    async fn close(&mut self) -> Result<()> {
+        // Note: close all shards, even if one fails
+        let mut result = Ok(());
+        for shard in self.shards.iter_mut() {
+            let r = shard.close().await;
+            if result.is_ok() {
+                result = r;
+            }
+        }
+        result
+    }
+
+    async fn create_mailbox(&self, id: &str) -> Result<()> {
+        self.shard(id).create_mailbox(id).await
+    }
+    async fn send(&self, id: &str, item: ITEM) -> Result<String> {
+        self.shard(id).send(id, item).await
+    }
+    async fn send_transaction(&self, id: &str, items: Vec<ITEM>) -> Result<Vec<String>> {
+        self.shard(id).send_transaction(id, items).await
+    }
+    async fn receive(&self, id: &str) -> Result<Option<(String, ITEM)>> {
+        self.shard(id).receive(id).await
+    }
+    fn receive_any_turn(&self) -> Option<&ReceiveAnyTurn> {
+        Some(&self.receive_any_turn)
+    }
+    async fn acknowledge(&self, id: &str, item_id: &str) -> Result<()> {
+        self.shard(id).acknowledge(id, item_id).await
+    }
+    async fn acknowledge_through(&self, id: &str, item_id: &str) -> Result<u64> {
+        self.shard(id).acknowledge_through(id, item_id).await
+    }
+    async fn release(&self, id: &str, item_id: &str) -> Result<()> {
+        self.shard(id).release(id, item_id).await
+    }
+    fn release_later(&self, id: &str, item_id: &str) {
+        self.shard(id).release_later(id, item_id)
+    }
+    async fn update(&self, id: &str, item_id: &str, item: ITEM) -> Result<()> {
+        self.shard(id).update(id, item_id, item).await
+    }
+    async fn peek(&self, id: &str) -> Result<Option<(String, ITEM)>> {
+        self.shard(id).peek(id).await
+    }
+    async fn receive_many(&self, id: &str, max: usize) -> Result<Vec<(String, ITEM)>> {
+        self.shard(id).receive_many(id, max).await
+    }
+    async fn receive_batch(
+        &self,
+        id: &str,
+        max_items: usize,
+        max_wait: Duration,
+    ) -> Result<Vec<(String, ITEM)>> {
+        self.shard(id).receive_batch(id, max_items, max_wait).await
+    }
+    async fn receive_matching(
+        &self,
+        id: &str,
+        selector: &HeaderSelector,
+    ) -> Result<Option<(String, ITEM)>> {
+        self.shard(id).receive_matching(id, selector).await
+    }
+    async fn list_mailboxes(&self) -> Result<Vec<String>> {
+        let mut ids = Vec::new();
+        for shard in self.shards.iter() {
+            ids.extend(shard.list_mailboxes().await?);
+        }
+        ids.sort();
+        ids.dedup();
+
+        Ok(ids)
+    }
+    async fn delivery_count(&self, id: &str, item_id: &str) -> Result<u32> {
+        self.shard(id).delivery_count(id, item_id).await
+    }
+    async fn list_items_page(
+        &self,
+        id: &str,
+        after: Option<&str>,
+        limit: usize,
+    ) -> Result<Page<ItemSummary>> {
+        self.shard(id).list_items_page(id, after, limit).await
+    }
+    async fn item_size(&self, id: &str, item_id: &str) -> Result<u64> {
+        self.shard(id).item_size(id, item_id).await
+    }
+    async fn stats(&self, id: &str) -> Result<MailboxStats> {
+        self.shard(id).stats(id).await
+    }
+    async fn receive_for(&self, id: &str, group: &str) -> Result<Option<(String, ITEM)>> {
+        self.shard(id).receive_for(id, group).await
+    }
+    async fn acknowledge_for(&self, id: &str, group: &str, item_id: &str) -> Result<()> {
+        self.shard(id).acknowledge_for(id, group, item_id).await
+    }
+    async fn compact(&self, id: &str) -> Result<u64> {
+        self.shard(id).compact(id).await
+    }
+    async fn drop_older_than(&self, id: &str, max_age: Duration) -> Result<u64> {
+        self.shard(id).drop_older_than(id, max_age).await
+    }
+    async fn health_check(&self) -> Result<HealthReport> {
+        let mut report: Option<HealthReport> = None;
+        for (index, shard) in self.shards.iter().enumerate() {
+            let mut shard_report = shard.health_check().await?;
+            shard_report.message = format!("Shard {index}: {}", shard_report.message);
+            report = Some(match report {
+                Some(report) => report.combine(shard_report),
+                None => shard_report,
+            });
+        }
+        report.ok_or_else(|| eyre!("No shards"))
+    }
+    async fn expire_items(&self, id: &str) -> Result<ExpireReport> {
+        self.shard(id).expire_items(id).await
+    }
+    async fn pop(&self, id: &str) -> Result<Option<(String, ITEM)>> {
+        self.shard(id).pop(id).await
+    }
+    async fn drain(&self, id: &str, max: Option<usize>) -> Result<Vec<(String, ITEM)>>
+    where
+        ITEM: 'static,
+    {
+        self.shard(id).drain(id, max).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::Mailbox;
+    use crate::MailboxItem;
+    use crate::MailboxMemory;
+    use crate::ShardedMailbox;
+    use color_eyre::Result;
+
+    use test_log::test;
+
+    #[derive(Default, Debug)]
+    struct TestItem {
+        data: String,
+    }
+
+    impl MailboxItem for TestItem {
+        fn serialize(&self) -> Result<Vec<u8>> {
+            Ok(self.data.clone().into())
+        }
+        fn deserialize(data: &[u8]) -> Result<Self>
+        where
+            Self: Sized,
+        {
+            Ok(Self {
+                data: String::from_utf8(data.to_vec())?,
+            })
+        }
+    }
+
+    fn sharded() -> ShardedMailbox<TestItem> {
+        ShardedMailbox::new(vec![
+            Box::new(MailboxMemory::<TestItem>::new()),
+            Box::new(MailboxMemory::<TestItem>::new()),
+        ])
+    }
+
+    #[test]
+    fn it_hashes_stable() {
+        // reference values for 64 bit FNV-1a
+        assert_eq!(ShardedMailbox::<TestItem>::fnv1a(b""), 0xcbf29ce484222325);
+        assert_eq!(ShardedMailbox::<TestItem>::fnv1a(b"a"), 0xaf63dc4c8601ec8c);
+        assert_eq!(
+            ShardedMailbox::<TestItem>::fnv1a(b"foobar"),
+            0x85944171f73967e8
+        );
+    }
+
+    #[test(tokio::test)]
+    async fn it_routes_consistently() -> Result<()> {
+        let mailbox = sharded();
+        let ids: Vec<String> = (0..20).map(|i| format!("user_{i}")).collect();
+
+        for id in ids.iter() {
+            mailbox.send(id, TestItem { data: id.clone() }).await?;
+        }
+
+        // both shards are used
+        let used: std::collections::HashSet<usize> =
+            ids.iter().map(|id| mailbox.shard_index(id)).collect();
+        assert_eq!(used.len(), 2);
+
+        // every shard only knows its own mailboxes
+        for (index, shard) in mailbox.shards().iter().enumerate() {
+            for id in shard.list_mailboxes().await? {
+                assert_eq!(mailbox.shard_index(&id), index);
+            }
+        }
+
+        let mut expected = ids.clone();
+        expected.sort();
+        assert_eq!(mailbox.list_mailboxes().await?, expected);
+
+        for id in ids.iter() {
+            let (_item_id, item) = mailbox.pop(id).await?.expect("Item pending");
+            assert_eq!(&item.data, id);
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,350 @@
+use crate::clock::SharedClock;
+use crate::for_each_mailbox;
+use crate::BulkError;
+use crate::Clock;
+use crate::Mailbox;
+use crate::MailboxItem;
+use crate::MailboxStats;
+use async_trait::async_trait;
+use chrono::DateTime;
+use chrono::Utc;
+use color_eyre::eyre::Result;
+use color_eyre::eyre::WrapErr;
+use core::marker::PhantomData;
+use std::io::Write;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+
+/// How many mailboxes [StatsCollector] asks for their stats at a time, by default
+pub const DEFAULT_COLLECT_CONCURRENCY: usize = 4;
+
+/// The stats of one mailbox, as collected by [StatsCollector]
+#[derive(Debug, Clone)]
+pub struct MailboxSnapshot {
+    pub mailbox_id: String,
+    /// When the collection started, the same for every snapshot of a batch
+    pub taken_at: DateTime<Utc>,
+    /// Mailboxes failing to report their stats are skipped, and keep the error here
+    pub stats: std::result::Result<MailboxStats, BulkError>,
+}
+
+impl MailboxSnapshot {
+    /// How long the oldest pending item has been waiting, `None` if unknown, or nothing is pending
+    ///
+    /// Note: Only [crate::MailboxDisk] knows, see [MailboxStats::priorities].
+    pub fn oldest_age(&self) -> Option<Duration> {
+        let stats = self.stats.as_ref().ok()?;
+        stats
+            .priorities
+            .values()
+            .filter_map(|priority| priority.oldest_age(self.taken_at))
+            .max()
+    }
+}
+
+/// Where [StatsCollector] sends the snapshots, e.g. a metrics service, or a database
+#[async_trait]
+pub trait StatsReporter: Send + Sync {
+    /// Called once per collection, with the snapshots of all mailboxes, ordered by id
+    async fn report(&self, snapshots: &[MailboxSnapshot]) -> Result<()>;
+}
+
+/// Logs one [tracing::info!] event per mailbox, and a warning for mailboxes that were skipped
+#[derive(Debug, Default, Clone, Copy)]
+pub struct TracingStatsReporter;
+
+#[async_trait]
+impl StatsReporter for TracingStatsReporter {
+    async fn report(&self, snapshots: &[MailboxSnapshot]) -> Result<()> {
+        for snapshot in snapshots {
+            let mailbox_id = snapshot.mailbox_id.as_str();
+            match &snapshot.stats {
+                Ok(stats) => tracing::info!(
+                    mailbox_id,
+                    pending = stats.pending,
+                    total_sent = stats.total_sent,
+                    total_acknowledged = stats.total_acknowledged,
+                    oldest_age_secs = snapshot.oldest_age().map(|age| age.as_secs()),
+                    "Mailbox stats"
+                ),
+                Err(e) => tracing::warn!(mailbox_id, error = %e, "Mailbox stats skipped"),
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Appends one CSV row per mailbox to a file, writing the header first if the file is new, or empty
+///
+/// The columns are `taken_at,mailbox_id,pending,total_sent,total_acknowledged,oldest_age_secs,error`,
+/// the stats of skipped mailboxes are left empty, and `error` is only set for them.
+#[derive(Debug)]
+pub struct CsvStatsReporter {
+    path: PathBuf,
+    /// Keeps the rows of concurrent reports apart
+    lock: Mutex<()>,
+}
+
+impl CsvStatsReporter {
+    pub const HEADER: &'static str =
+        "taken_at,mailbox_id,pending,total_sent,total_acknowledged,oldest_age_secs,error";
+
+    pub fn new(path: &Path) -> Self {
+        Self {
+            path: path.to_path_buf(),
+            lock: Mutex::default(),
+        }
+    }
+
+    fn row(snapshot: &MailboxSnapshot) -> String {
+        let taken_at = snapshot.taken_at.to_rfc3339();
+        let mailbox_id = csv_field(&snapshot.mailbox_id);
+        match &snapshot.stats {
+            Ok(stats) => {
+                let oldest_age = snapshot
+                    .oldest_age()
+                    .map(|age| age.as_secs().to_string())
+                    .unwrap_or_default();
+                format!(
+                    "{taken_at},{mailbox_id},{},{},{},{oldest_age},",
+                    stats.pending, stats.total_sent, stats.total_acknowledged
+                )
+            }
+            Err(e) => format!("{taken_at},{mailbox_id},,,,,{}", csv_field(&e.message)),
+        }
+    }
+}
+
+#[async_trait]
+impl StatsReporter for CsvStatsReporter {
+    async fn report(&self, snapshots: &[MailboxSnapshot]) -> Result<()> {
+        let mut lines = String::new();
+        for snapshot in snapshots {
+            lines.push_str(&Self::row(snapshot));
+            lines.push('\n');
+        }
+        let _lock = self.lock.lock().unwrap();
+        let mut f = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .wrap_err_with(|| format!("Can't open {:?}", self.path))?;
+        if f.metadata()?.len() == 0 {
+            lines.insert_str(0, &format!("{}\n", Self::HEADER));
+        }
+        f.write_all(lines.as_bytes())
+            .wrap_err_with(|| format!("Can't append to {:?}", self.path))?;
+        Ok(())
+    }
+}
+
+/// Quotes a field containing a separator, a quote, or a line break
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+/// Collects the stats of all mailboxes of a backend, and hands them to a [StatsReporter], periodically
+///
+/// Every mailbox gets a single [Mailbox::stats] call, so no lock is held for longer than that.
+/// Note: Like [crate::MailboxMaintainer], but read only, so both can run side by side.
+pub struct StatsCollector<ITEM: MailboxItem, M: Mailbox<ITEM>> {
+    mailbox: Arc<M>,
+    reporter: Arc<dyn StatsReporter>,
+    interval: Duration,
+    concurrency: usize,
+    clock: SharedClock,
+    shutdown: Arc<watch::Sender<bool>>,
+    item_type: PhantomData<ITEM>,
+}
+
+// Note: derive(Clone) would require ITEM and M to be Clone
+impl<ITEM: MailboxItem, M: Mailbox<ITEM>> Clone for StatsCollector<ITEM, M> {
+    fn clone(&self) -> Self {
+        Self {
+            mailbox: self.mailbox.clone(),
+            reporter: self.reporter.clone(),
+            interval: self.interval,
+            concurrency: self.concurrency,
+            clock: self.clock.clone(),
+            shutdown: self.shutdown.clone(),
+            item_type: PhantomData,
+        }
+    }
+}
+
+impl<ITEM: MailboxItem, M: Mailbox<ITEM>> std::fmt::Debug for StatsCollector<ITEM, M> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("StatsCollector")
+            .field("interval", &self.interval)
+            .field("concurrency", &self.concurrency)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<ITEM: MailboxItem + Send + 'static, M: Mailbox<ITEM> + 'static> StatsCollector<ITEM, M> {
+    /// Collects every `interval`, once spawned, see [StatsCollector::spawn]
+    pub fn new(mailbox: Arc<M>, reporter: Arc<dyn StatsReporter>, interval: Duration) -> Self {
+        let (shutdown, _) = watch::channel(false);
+        Self {
+            mailbox,
+            reporter,
+            interval,
+            concurrency: DEFAULT_COLLECT_CONCURRENCY,
+            clock: SharedClock::default(),
+            shutdown: Arc::new(shutdown),
+            item_type: PhantomData,
+        }
+    }
+
+    /// How many mailboxes are asked for their stats at a time, zero is treated as one
+    pub fn with_concurrency(mut self, concurrency: usize) -> Self {
+        self.concurrency = concurrency;
+        self
+    }
+
+    /// Use a different source for [MailboxSnapshot::taken_at], e.g. a [crate::MockClock] in tests
+    pub fn with_clock(mut self, clock: impl Clock + 'static) -> Self {
+        self.clock = SharedClock::new(clock);
+        self
+    }
+
+    /// Collect until [StatsCollector::shutdown] is called
+    pub fn spawn(self) -> JoinHandle<()> {
+        tokio::spawn(async move {
+            let mut shutdown = self.shutdown.subscribe();
+            loop {
+                if *shutdown.borrow() {
+                    break;
+                }
+                if let Err(e) = self.collect_once().await {
+                    tracing::error!("Collecting stats failed -> {e:?}");
+                }
+                tokio::select! {
+                    _ = tokio::time::sleep(self.interval) => {}
+                    _ = shutdown.changed() => {}
+                }
+            }
+            tracing::debug!("Stats collection stopped");
+        })
+    }
+
+    /// Stop the spawned task, after the collection it is working on
+    pub fn shutdown(&self) {
+        self.shutdown.send_replace(true);
+    }
+
+    /// Collect the stats of all mailboxes, and report them
+    ///
+    /// Only fails if the mailboxes can't be listed, or the reporter fails,
+    /// mailboxes failing to report their stats are part of the batch.
+    pub async fn collect_once(&self) -> Result<Vec<MailboxSnapshot>> {
+        let taken_at = self.clock.now();
+        let mailbox: &dyn Mailbox<ITEM> = self.mailbox.as_ref();
+        let result = for_each_mailbox(mailbox, self.concurrency, |mailbox_id| async move {
+            self.mailbox.stats(&mailbox_id).await
+        })
+        .await?;
+        let mut snapshots: Vec<MailboxSnapshot> = result
+            .entries
+            .into_iter()
+            .map(|entry| MailboxSnapshot {
+                mailbox_id: entry.mailbox_id,
+                taken_at,
+                stats: entry.outcome,
+            })
+            .collect();
+        snapshots.sort_by(|a, b| a.mailbox_id.cmp(&b.mailbox_id));
+        self.reporter.report(&snapshots).await?;
+
+        Ok(snapshots)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::CsvStatsReporter;
+    use crate::Mailbox;
+    use crate::MailboxDisk;
+    use crate::MailboxItem;
+    use crate::MockClock;
+    use crate::StatsCollector;
+    use chrono::DateTime;
+    use color_eyre::Result;
+    use std::path::Path;
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tempfile::TempDir;
+
+    use test_log::test;
+
+    #[derive(Default, Debug)]
+    struct TestItem {}
+
+    impl MailboxItem for TestItem {
+        fn serialize(&self) -> Result<Vec<u8>> {
+            Ok(Vec::new())
+        }
+        fn deserialize(_data: &[u8]) -> Result<Self>
+        where
+            Self: Sized,
+        {
+            Ok(Self {})
+        }
+    }
+
+    #[test(tokio::test)]
+    async fn it_reports_every_mailbox_as_csv() -> Result<()> {
+        let dir = TempDir::new()?;
+        let clock = MockClock::new(DateTime::parse_from_rfc3339("2024-06-01T12:00:00Z")?.into());
+        let mailbox = Arc::new(
+            MailboxDisk::<TestItem>::at(&dir.path().join("mailboxes"), Path::new("test_item"))
+                .with_clock(clock.clone()),
+        );
+        for (mailbox_id, count) in [("a", 1), ("b", 2), ("c", 3)] {
+            for _ in 0..count {
+                mailbox.send(mailbox_id, TestItem::default()).await?;
+            }
+        }
+        let (item_id, _item) = mailbox.receive("c").await?.expect("Item pending");
+        mailbox.acknowledge("c", &item_id).await?;
+        clock.advance(Duration::from_secs(90));
+        // a broken meta makes the stats of the mailbox fail
+        std::fs::write(dir.path().join("mailboxes/b/mailbox_meta.json"), "not json")?;
+
+        let csv = dir.path().join("stats.csv");
+        let collector = StatsCollector::new(
+            mailbox,
+            Arc::new(CsvStatsReporter::new(&csv)),
+            Duration::from_secs(60),
+        )
+        .with_clock(clock);
+        let snapshots = collector.collect_once().await?;
+        assert_eq!(snapshots.len(), 3);
+        assert!(snapshots[1].stats.is_err());
+
+        let rows = std::fs::read_to_string(&csv)?;
+        let rows: Vec<&str> = rows.lines().collect();
+        assert_eq!(rows[0], CsvStatsReporter::HEADER);
+        assert_eq!(rows[1], "2024-06-01T12:01:30+00:00,a,1,1,0,90,");
+        assert!(rows[2].starts_with("2024-06-01T12:01:30+00:00,b,,,,,"));
+        assert!(rows[2].len() > "2024-06-01T12:01:30+00:00,b,,,,,".len());
+        assert_eq!(rows[3], "2024-06-01T12:01:30+00:00,c,2,3,1,90,");
+        assert_eq!(rows.len(), 4);
+
+        // the header is only written once
+        collector.collect_once().await?;
+        let rows = std::fs::read_to_string(&csv)?;
+        assert_eq!(rows.lines().count(), 7);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,390 @@
+use crate::ExpireReport;
+use crate::HeaderSelector;
+use crate::HealthReport;
+use crate::HealthStatus;
+use crate::ItemSummary;
+use crate::Mailbox;
+use crate::MailboxItem;
+use crate::MailboxStats;
+use crate::Page;
+use async_trait::async_trait;
+use color_eyre::eyre::Result;
+use color_eyre::eyre::WrapErr;
+use core::marker::PhantomData;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+
+/// What [TeeMailbox] does when mirroring a send to the secondary fails
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum MirrorFailurePolicy {
+    /// Log, and count, the failure, but report the send as successful
+    #[default]
+    Log,
+    /// Fail the send
+    ///
+    /// Note: The item has already been stored in the primary at that point.
+    Fail,
+}
+
+/// Sends every item to the primary, and mirrors it to a secondary mailbox
+///
+/// Useful during storage migrations.
+/// Everything else (`receive`, `acknowledge`, ...) only goes to the primary.
+///
+/// Note: The secondary assigns its own item ids, the primary's id is returned.
+#[derive(Debug)]
+pub struct TeeMailbox<ITEM: MailboxItem, P: Mailbox<ITEM>, S: Mailbox<ITEM>> {
+    primary: P,
+    secondary: S,
+    policy: MirrorFailurePolicy,
+    mirrored: AtomicU64,
+    failed_mirrors: AtomicU64,
+    item_type: PhantomData<ITEM>,
+}
+
+impl<ITEM: MailboxItem, P: Mailbox<ITEM>, S: Mailbox<ITEM>> TeeMailbox<ITEM, P, S> {
+    pub fn new(primary: P, secondary: S, policy: MirrorFailurePolicy) -> Self {
+        Self {
+            primary,
+            secondary,
+            policy,
+            mirrored: AtomicU64::new(0),
+            failed_mirrors: AtomicU64::new(0),
+            item_type: PhantomData,
+        }
+    }
+
+    pub fn primary(&self) -> &P {
+        &self.primary
+    }
+
+    pub fn secondary(&self) -> &S {
+        &self.secondary
+    }
+
+    /// Number of sends successfully mirrored to the secondary
+    pub fn mirrored_sends(&self) -> u64 {
+        self.mirrored.load(Ordering::Relaxed)
+    }
+
+    /// Number of sends that reached the primary, but not the secondary
+    pub fn failed_mirror_sends(&self) -> u64 {
+        self.failed_mirrors.load(Ordering::Relaxed)
+    }
+
+    async fn mirror(&self, mailbox_id: &str, data: &[u8]) -> Result<String> {
+        let item = ITEM::deserialize(data)?;
+        self.secondary.send(mailbox_id, item).await
+    }
+
+    async fn mirror_transaction(&self, mailbox_id: &str, data: &[Vec<u8>]) -> Result<Vec<String>> {
+        let items = data
+            .iter()
+            .map(|d| ITEM::deserialize(d))
+            .collect::<Result<Vec<_>>>()?;
+        self.secondary.send_transaction(mailbox_id, items).await
+    }
+
+    /// Counts the outcome of mirroring `count` items, and applies the policy to failures
+    fn mirrored(&self, id: &str, item_ids: &str, count: u64, r: Result<String>) -> Result<()> {
+        match r {
+            Ok(secondary_ids) => {
+                tracing::debug!("Mirrored {id} {item_ids} as {secondary_ids}");
+                self.mirrored.fetch_add(count, Ordering::Relaxed);
+            }
+            Err(e) => {
+                self.failed_mirrors.fetch_add(count, Ordering::Relaxed);
+                match self.policy {
+                    MirrorFailurePolicy::Log => {
+                        tracing::error!("Failed mirroring {id} {item_ids} -> {e:?}");
+                    }
+                    MirrorFailurePolicy::Fail => {
+                        return Err(e).wrap_err_with(|| {
+                            format!("Failed mirroring {id} {item_ids}, the primary has the item")
+                        });
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl<ITEM: MailboxItem + Send, P: Mailbox<ITEM>, S: Mailbox<ITEM>> Mailbox<ITEM>
+    for TeeMailbox<ITEM, P, S>
+{
+    async fn ensure_storage_exists(&mut self) -> Result<()> {
+        self.primary.ensure_storage_exists().await?;
+        self.secondary.ensure_storage_exists().await
+    }
+    async fn close(&mut self) -> Result<()> {
+        let primary = self.primary.close().await;
+        let secondary = self.secondary.close().await;
+        primary.and(secondary)
+    }
+
+    async fn create_mailbox(&self, id: &str) -> Result<()> {
+        self.primary.create_mailbox(id).await?;
+        self.secondary.create_mailbox(id).await
+    }
+    async fn send(&self, id: &str, item: ITEM) -> Result<String> {
+        // Note: the item is consumed by the primary, so the secondary gets a deserialized copy
+        let data = item.serialize()?;
+        let item_id = self.primary.send(id, item).await?;
+
+        let r = self.mirror(id, &data).await;
+        self.mirrored(id, &item_id, 1, r)?;
+
+        Ok(item_id)
+    }
+    async fn send_transaction(&self, id: &str, items: Vec<ITEM>) -> Result<Vec<String>> {
+        let data = items
+            .iter()
+            .map(|item| item.serialize())
+            .collect::<Result<Vec<_>>>()?;
+        let item_ids = self.primary.send_transaction(id, items).await?;
+
+        let r = self
+            .mirror_transaction(id, &data)
+            .await
+            .map(|ids| ids.join(","));
+        self.mirrored(id, &item_ids.join(","), item_ids.len() as u64, r)?;
+
+        Ok(item_ids)
+    }
+    async fn receive(&self, id: &str) -> Result<Option<(String, ITEM)>> {
+        self.primary.receive(id).await
+    }
+    async fn receive_any(&self, ids: &[String]) -> Result<Option<(String, String, ITEM)>> {
+        self.primary.receive_any(ids).await
+    }
+    async fn acknowledge(&self, id: &str, item_id: &str) -> Result<()> {
+        self.primary.acknowledge(id, item_id).await
+    }
+    /// Note: Only the primary is updated, the secondary doesn't know the primary's item ids.
+    async fn acknowledge_through(&self, id: &str, item_id: &str) -> Result<u64> {
+        self.primary.acknowledge_through(id, item_id).await
+    }
+    async fn release(&self, id: &str, item_id: &str) -> Result<()> {
+        self.primary.release(id, item_id).await
+    }
+    fn release_later(&self, id: &str, item_id: &str) {
+        self.primary.release_later(id, item_id)
+    }
+    async fn update(&self, id: &str, item_id: &str, item: ITEM) -> Result<()> {
+        self.primary.update(id, item_id, item).await
+    }
+    async fn peek(&self, id: &str) -> Result<Option<(String, ITEM)>> {
+        self.primary.peek(id).await
+    }
+    async fn receive_many(&self, id: &str, max: usize) -> Result<Vec<(String, ITEM)>> {
+        self.primary.receive_many(id, max).await
+    }
+    async fn receive_batch(
+        &self,
+        id: &str,
+        max_items: usize,
+        max_wait: Duration,
+    ) -> Result<Vec<(String, ITEM)>> {
+        self.primary.receive_batch(id, max_items, max_wait).await
+    }
+    async fn receive_matching(
+        &self,
+        id: &str,
+        selector: &HeaderSelector,
+    ) -> Result<Option<(String, ITEM)>> {
+        self.primary.receive_matching(id, selector).await
+    }
+    async fn list_mailboxes(&self) -> Result<Vec<String>> {
+        self.primary.list_mailboxes().await
+    }
+    async fn delivery_count(&self, id: &str, item_id: &str) -> Result<u32> {
+        self.primary.delivery_count(id, item_id).await
+    }
+    async fn list_items_page(
+        &self,
+        id: &str,
+        after: Option<&str>,
+        limit: usize,
+    ) -> Result<Page<ItemSummary>> {
+        self.primary.list_items_page(id, after, limit).await
+    }
+    async fn item_size(&self, id: &str, item_id: &str) -> Result<u64> {
+        self.primary.item_size(id, item_id).await
+    }
+    async fn stats(&self, id: &str) -> Result<MailboxStats> {
+        self.primary.stats(id).await
+    }
+    async fn receive_for(&self, id: &str, group: &str) -> Result<Option<(String, ITEM)>> {
+        self.primary.receive_for(id, group).await
+    }
+    async fn acknowledge_for(&self, id: &str, group: &str, item_id: &str) -> Result<()> {
+        self.primary.acknowledge_for(id, group, item_id).await
+    }
+    async fn compact(&self, id: &str) -> Result<u64> {
+        self.primary.compact(id).await
+    }
+    async fn drop_older_than(&self, id: &str, max_age: Duration) -> Result<u64> {
+        self.primary.drop_older_than(id, max_age).await
+    }
+    async fn expire_items(&self, id: &str) -> Result<ExpireReport> {
+        self.primary.expire_items(id).await
+    }
+    /// Note: An unhealthy secondary only degrades the tee, sends still reach the primary.
+    async fn health_check(&self) -> Result<HealthReport> {
+        let mut primary = self.primary.health_check().await?;
+        primary.message = format!("Primary: {}", primary.message);
+        let mut secondary = self.secondary.health_check().await?;
+        secondary.message = format!("Secondary: {}", secondary.message);
+        secondary.status = secondary.status.min(HealthStatus::Degraded);
+
+        Ok(primary.combine(secondary))
+    }
+    async fn pop(&self, id: &str) -> Result<Option<(String, ITEM)>> {
+        self.primary.pop(id).await
+    }
+    async fn drain(&self, id: &str, max: Option<usize>) -> Result<Vec<(String, ITEM)>>
+    where
+        ITEM: 'static,
+    {
+        self.primary.drain(id, max).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::HealthStatus;
+    use crate::Mailbox;
+    use crate::MailboxDisk;
+    use crate::MailboxError;
+    use crate::MailboxItem;
+    use crate::MailboxMemory;
+    use crate::MirrorFailurePolicy;
+    use crate::MockMailbox;
+    use crate::MockOperation;
+    use crate::TeeMailbox;
+    use color_eyre::Result;
+    use std::path::Path;
+    use tempfile::TempDir;
+
+    use test_log::test;
+
+    #[derive(Default, Debug)]
+    struct TestItem {
+        data: String,
+    }
+
+    impl MailboxItem for TestItem {
+        fn serialize(&self) -> Result<Vec<u8>> {
+            Ok(self.data.clone().into())
+        }
+        fn deserialize(data: &[u8]) -> Result<Self>
+        where
+            Self: Sized,
+        {
+            Ok(Self {
+                data: String::from_utf8(data.to_vec())?,
+            })
+        }
+    }
+
+    fn item(data: &str) -> TestItem {
+        TestItem {
+            data: data.to_string(),
+        }
+    }
+
+    async fn payloads<M: Mailbox<TestItem>>(mailbox: &M, mailbox_id: &str) -> Result<Vec<String>> {
+        let mut payloads = Vec::new();
+        while let Some((_id, item)) = mailbox.pop(mailbox_id).await? {
+            payloads.push(item.data);
+        }
+        Ok(payloads)
+    }
+
+    #[test(tokio::test)]
+    async fn it_mirrors_sends() -> Result<()> {
+        let dir = TempDir::new()?;
+        let disk = MailboxDisk::<TestItem>::at(dir.path(), Path::new("test_item"));
+        let mut mailbox = TeeMailbox::new(
+            disk,
+            MailboxMemory::<TestItem>::new(),
+            MirrorFailurePolicy::Fail,
+        );
+        mailbox.ensure_storage_exists().await?;
+
+        for data in ["one", "two", "three"] {
+            mailbox.send("42", item(data)).await?;
+        }
+        assert_eq!(mailbox.mirrored_sends(), 3);
+        assert_eq!(mailbox.failed_mirror_sends(), 0);
+
+        assert_eq!(payloads(&mailbox, "42").await?, ["one", "two", "three"]);
+        assert_eq!(
+            payloads(mailbox.secondary(), "42").await?,
+            ["one", "two", "three"]
+        );
+
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn it_applies_the_failure_policy() -> Result<()> {
+        let failing = MockMailbox::<TestItem>::default();
+        failing.fail_next(
+            MockOperation::Send,
+            MailboxError::Io(std::io::ErrorKind::Other.into()),
+        );
+        let logging = TeeMailbox::new(
+            MailboxMemory::<TestItem>::new(),
+            failing,
+            MirrorFailurePolicy::Log,
+        );
+        let id = logging.send("42", item("one")).await?;
+        assert_eq!(id, "1");
+        assert_eq!(logging.failed_mirror_sends(), 1);
+        assert_eq!(logging.mirrored_sends(), 0);
+
+        let failing = MockMailbox::<TestItem>::default();
+        failing.fail_next(
+            MockOperation::Send,
+            MailboxError::Io(std::io::ErrorKind::Other.into()),
+        );
+        let strict = TeeMailbox::new(
+            MailboxMemory::<TestItem>::new(),
+            failing,
+            MirrorFailurePolicy::Fail,
+        );
+        assert!(strict.send("42", item("one")).await.is_err());
+        assert_eq!(strict.failed_mirror_sends(), 1);
+        // the primary still has it
+        assert_eq!(payloads(strict.primary(), "42").await?, ["one"]);
+
+        // and the next send goes through both
+        strict.send("42", item("two")).await?;
+        assert_eq!(strict.mirrored_sends(), 1);
+        assert_eq!(payloads(strict.secondary(), "42").await?, ["two"]);
+
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn it_is_degraded_by_an_unhealthy_secondary() -> Result<()> {
+        let dir = TempDir::new()?;
+        let broken = dir.path().join("broken");
+        std::fs::write(&broken, b"not a folder")?;
+        let tee = TeeMailbox::new(
+            MailboxMemory::<TestItem>::new(),
+            MailboxDisk::<TestItem>::at(&broken, Path::new("item")),
+            MirrorFailurePolicy::Log,
+        );
+
+        let report = tee.health_check().await?;
+        assert_eq!(report.status, HealthStatus::Degraded);
+        assert!(report.message.contains("Secondary"), "{}", report.message);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,181 @@
+use crate::ProvenanceEntry;
+use base64::prelude::*;
+use chrono::DateTime;
+use chrono::Utc;
+use color_eyre::eyre::eyre;
+use color_eyre::eyre::Result;
+use serde::Deserialize;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
+
+/// The line [crate::MailboxDisk::dump_text] starts with, lines starting with `#` are skipped when loading
+const HEADER: &str = "# oml-mailbox dump v1";
+
+/// The cursor of the default group, the first line of a dump after the header
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub(crate) struct DumpedMeta {
+    pub(crate) highest_used_id: u64,
+    pub(crate) lowest_unread_id: u64,
+    /// Only ids above `lowest_unread_id`
+    pub(crate) read_ids: BTreeSet<u64>,
+    pub(crate) total_sent: u64,
+    pub(crate) total_acknowledged: u64,
+}
+
+/// One line per item, e.g. `item 2 read,delivered=1,sent=2024-06-01T12:00:00Z {"kind":"a"} "payload"`
+///
+/// The flags are `-` if there are none, the payload is a JSON string if it is UTF-8, and `base64:...` otherwise.
+/// Items that were moved end with their provenance as a JSON array.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub(crate) struct DumpedItem {
+    pub(crate) id: u64,
+    pub(crate) read: bool,
+    pub(crate) delivery_count: u32,
+    pub(crate) deferred_count: u32,
+    pub(crate) sent_at: Option<DateTime<Utc>>,
+    pub(crate) headers: BTreeMap<String, String>,
+    pub(crate) payload: Vec<u8>,
+    pub(crate) provenance: Vec<ProvenanceEntry>,
+}
+
+pub(crate) fn write(meta: &DumpedMeta, items: &[DumpedItem]) -> Result<String> {
+    let mut text = format!("{HEADER}\nmeta {}\n", serde_json::to_string(meta)?);
+    for item in items {
+        let mut flags = Vec::new();
+        if item.read {
+            flags.push("read".to_string());
+        }
+        if item.delivery_count > 0 {
+            flags.push(format!("delivered={}", item.delivery_count));
+        }
+        if item.deferred_count > 0 {
+            flags.push(format!("deferred={}", item.deferred_count));
+        }
+        if let Some(sent_at) = item.sent_at {
+            flags.push(format!("sent={}", sent_at.to_rfc3339()));
+        }
+        let flags = if flags.is_empty() {
+            "-".to_string()
+        } else {
+            flags.join(",")
+        };
+        let mut payload = match std::str::from_utf8(&item.payload) {
+            Ok(payload) => serde_json::to_string(payload)?,
+            Err(_) => format!("base64:{}", BASE64_STANDARD.encode(&item.payload)),
+        };
+        if !item.provenance.is_empty() {
+            payload.push(' ');
+            payload.push_str(&serde_json::to_string(&item.provenance)?);
+        }
+        text.push_str(&format!(
+            "item {} {flags} {} {payload}\n",
+            item.id,
+            serde_json::to_string(&item.headers)?
+        ));
+    }
+    Ok(text)
+}
+
+/// Parses a dump, and checks it is consistent, e.g. every unread id has an item
+///
+/// The items are returned in id order.
+pub(crate) fn parse(text: &str) -> Result<(DumpedMeta, Vec<DumpedItem>)> {
+    let mut meta = None;
+    let mut items = BTreeMap::new();
+    for (n, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let invalid = |reason: &str| eyre!("Invalid dump line {}: {reason}", n + 1);
+        match line.split_once(' ') {
+            Some(("meta", json)) if meta.is_none() => {
+                meta = Some(
+                    serde_json::from_str::<DumpedMeta>(json)
+                        .map_err(|e| invalid(&e.to_string()))?,
+                );
+            }
+            Some(("item", rest)) if meta.is_some() => {
+                let item = parse_item(rest).map_err(|e| invalid(&e.to_string()))?;
+                if items.insert(item.id, item).is_some() {
+                    return Err(invalid("duplicate id"));
+                }
+            }
+            _ => return Err(invalid("expected one meta line, followed by item lines")),
+        }
+    }
+    let meta = meta.ok_or_else(|| eyre!("Invalid dump: no meta line"))?;
+
+    let highest = meta.highest_used_id;
+    if meta.lowest_unread_id == 0 || meta.lowest_unread_id > highest + 1 {
+        return Err(eyre!(
+            "Invalid dump: lowest_unread_id {} is outside of 1..={}",
+            meta.lowest_unread_id,
+            highest + 1
+        ));
+    }
+    if let Some(id) = meta
+        .read_ids
+        .iter()
+        .find(|&&id| id <= meta.lowest_unread_id || id > highest)
+    {
+        return Err(eyre!(
+            "Invalid dump: read id {id} is not above the lowest unread id, or was never used"
+        ));
+    }
+    if let Some(id) = items.keys().find(|&&id| id == 0 || id > highest) {
+        return Err(eyre!(
+            "Invalid dump: item {id} is above highest_used_id {highest}"
+        ));
+    }
+    let unread = (meta.lowest_unread_id..=highest).filter(|id| !meta.read_ids.contains(id));
+    if let Some(id) = unread.into_iter().find(|id| !items.contains_key(id)) {
+        return Err(eyre!("Invalid dump: unread item {id} is missing"));
+    }
+
+    Ok((meta, items.into_values().collect()))
+}
+
+fn parse_item(line: &str) -> Result<DumpedItem> {
+    let (id, rest) = line.split_once(' ').ok_or_else(|| eyre!("no flags"))?;
+    let (flags, rest) = rest.split_once(' ').ok_or_else(|| eyre!("no headers"))?;
+    let mut item = DumpedItem {
+        id: id.parse()?,
+        ..Default::default()
+    };
+    for flag in flags.split(',').filter(|flag| *flag != "-") {
+        match flag.split_once('=') {
+            None if flag == "read" => item.read = true,
+            Some(("delivered", count)) => item.delivery_count = count.parse()?,
+            Some(("deferred", count)) => item.deferred_count = count.parse()?,
+            Some(("sent", at)) => item.sent_at = Some(DateTime::parse_from_rfc3339(at)?.into()),
+            _ => return Err(eyre!("unknown flag {flag:?}")),
+        }
+    }
+    let mut headers = serde_json::Deserializer::from_str(rest).into_iter();
+    item.headers = headers.next().ok_or_else(|| eyre!("no headers"))??;
+    let rest = rest[headers.byte_offset()..].trim();
+    let provenance = match rest.strip_prefix("base64:") {
+        Some(rest) => {
+            let (encoded, provenance) = rest.split_once(' ').unwrap_or((rest, ""));
+            item.payload = BASE64_STANDARD.decode(encoded)?;
+            provenance
+        }
+        None => {
+            let mut payload = serde_json::Deserializer::from_str(rest).into_iter::<String>();
+            item.payload = payload
+                .next()
+                .ok_or_else(|| eyre!("no payload"))??
+                .into_bytes();
+            &rest[payload.byte_offset()..]
+        }
+    };
+    let provenance = provenance.trim();
+    if !provenance.is_empty() {
+        item.provenance = serde_json::from_str(provenance)?;
+    }
+
+    Ok(item)
+}
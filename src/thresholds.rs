@@ -0,0 +1,121 @@
+use std::path::PathBuf;
+use std::sync::Arc;
+
+/// How far a level has to drop below a soft limit, before the warning fires again
+pub const DEFAULT_HYSTERESIS: f64 = 0.05;
+
+/// Soft limits below the hard ones, see [crate::MailboxDisk::with_thresholds]
+///
+/// ```
+/// # use oml_mailbox::Thresholds;
+/// let thresholds = Thresholds::default()
+///     .with_capacity(0.8)
+///     .with_free_space(1024 * 1024 * 1024);
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Thresholds {
+    /// Fraction of the capacity, see [crate::MailboxDisk::with_capacity]
+    pub capacity: Option<f64>,
+    /// Fraction of the item size limit, see [crate::MailboxDisk::with_max_item_size]
+    pub item_size: Option<f64>,
+    /// Bytes of free space on the storage
+    pub free_space: Option<u64>,
+    /// Fraction of the soft limit a level has to drop below it, to re-arm the warning
+    pub hysteresis: f64,
+}
+
+impl Default for Thresholds {
+    fn default() -> Self {
+        Self {
+            capacity: None,
+            item_size: None,
+            free_space: None,
+            hysteresis: DEFAULT_HYSTERESIS,
+        }
+    }
+}
+
+impl Thresholds {
+    pub fn with_capacity(mut self, fraction: f64) -> Self {
+        self.capacity = Some(fraction);
+        self
+    }
+
+    pub fn with_item_size(mut self, fraction: f64) -> Self {
+        self.item_size = Some(fraction);
+        self
+    }
+
+    pub fn with_free_space(mut self, bytes: u64) -> Self {
+        self.free_space = Some(bytes);
+        self
+    }
+
+    pub fn with_hysteresis(mut self, fraction: f64) -> Self {
+        self.hysteresis = fraction;
+        self
+    }
+}
+
+/// A soft limit of [Thresholds] was crossed, the operation itself went through
+#[derive(Debug, Clone, PartialEq)]
+pub enum ThresholdEvent {
+    /// `pending` includes the items being sent
+    Capacity {
+        mailbox_id: String,
+        pending: u64,
+        capacity: u64,
+    },
+    ItemSize {
+        mailbox_id: String,
+        size: u64,
+        limit: u64,
+    },
+    FreeSpace {
+        path: PathBuf,
+        available: u64,
+        threshold: u64,
+    },
+}
+
+/// Where [ThresholdEvent]s go, any `Fn(ThresholdEvent)` is a sink too
+///
+/// Note: Called with the lock of the backend held, so hand anything slow off to a task.
+pub trait WarningSink: Send + Sync {
+    fn warn(&self, event: ThresholdEvent);
+}
+
+impl<F: Fn(ThresholdEvent) + Send + Sync> WarningSink for F {
+    fn warn(&self, event: ThresholdEvent) {
+        self(event)
+    }
+}
+
+/// Logs every event with [tracing::warn!]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct TracingWarningSink;
+
+impl WarningSink for TracingWarningSink {
+    fn warn(&self, event: ThresholdEvent) {
+        tracing::warn!("Soft limit crossed -> {event:?}");
+    }
+}
+
+#[derive(Clone)]
+pub(crate) struct SharedWarningSink(Arc<dyn WarningSink>);
+
+impl SharedWarningSink {
+    pub(crate) fn new(sink: impl WarningSink + 'static) -> Self {
+        Self(Arc::new(sink))
+    }
+
+    pub(crate) fn warn(&self, event: ThresholdEvent) {
+        self.0.warn(event)
+    }
+}
+
+impl std::fmt::Debug for SharedWarningSink {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("SharedWarningSink")
+    }
+}
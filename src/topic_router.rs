@@ -0,0 +1,216 @@
+use crate::BulkResult;
+use crate::Mailbox;
+use crate::MailboxItem;
+use crate::MailboxStorage;
+use color_eyre::eyre::Result;
+use color_eyre::eyre::WrapErr;
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::Arc;
+use tokio::sync::RwLock;
+
+/// The name of the subscription table in the folder of a [TopicRouter]
+pub const SUBSCRIPTIONS_KEY: &str = "topic_subscriptions";
+
+type Subscriptions = BTreeMap<String, BTreeSet<String>>;
+
+/// Publishes items to topics, every mailbox subscribed to the topic gets a copy
+///
+/// The subscriptions are kept in a [MailboxStorage], e.g. a [crate::FsStorage],
+/// as one JSON value at [SUBSCRIPTIONS_KEY] in the folder given to [TopicRouter::new].
+///
+/// `subscribe` and `unsubscribe` wait for publishes in flight,
+/// so once `unsubscribe` returns the mailbox doesn't get any more copies,
+/// and a publish in flight while subscribing doesn't reach the new subscriber.
+///
+/// Note: The table is loaded once, routers sharing a store don't see each others changes until they are created again.
+#[derive(Debug)]
+pub struct TopicRouter<ITEM: MailboxItem + 'static> {
+    backend: Arc<dyn Mailbox<ITEM>>,
+    store: Arc<dyn MailboxStorage>,
+    /// The key of the subscription table
+    key: PathBuf,
+    subscriptions: RwLock<Subscriptions>,
+}
+
+impl<ITEM: MailboxItem + Send + 'static> TopicRouter<ITEM> {
+    /// Loads the subscriptions from `folder` in `store`, none if there are none yet
+    pub async fn new(
+        backend: Arc<dyn Mailbox<ITEM>>,
+        store: Arc<dyn MailboxStorage>,
+        folder: &Path,
+    ) -> Result<Self> {
+        store.create_folder(folder).await?;
+        let key = folder.join(SUBSCRIPTIONS_KEY);
+        let subscriptions = match store.get(&key).await? {
+            Some(b) => serde_json::from_slice(&b).wrap_err("Broken topic subscriptions")?,
+            None => Subscriptions::default(),
+        };
+        Ok(Self {
+            backend,
+            store,
+            key,
+            subscriptions: RwLock::new(subscriptions),
+        })
+    }
+
+    pub fn backend(&self) -> &Arc<dyn Mailbox<ITEM>> {
+        &self.backend
+    }
+
+    /// Returns if the mailbox wasn't subscribed to the topic yet
+    pub async fn subscribe(&self, topic: &str, mailbox_id: &str) -> Result<bool> {
+        let mut subscriptions = self.subscriptions.write().await;
+        if subscriptions
+            .get(topic)
+            .is_some_and(|ids| ids.contains(mailbox_id))
+        {
+            return Ok(false);
+        }
+        let mut changed = subscriptions.clone();
+        changed
+            .entry(topic.to_string())
+            .or_default()
+            .insert(mailbox_id.to_string());
+        self.save(&changed).await?;
+        *subscriptions = changed;
+        Ok(true)
+    }
+
+    /// Returns if the mailbox was subscribed to the topic
+    pub async fn unsubscribe(&self, topic: &str, mailbox_id: &str) -> Result<bool> {
+        let mut subscriptions = self.subscriptions.write().await;
+        if !subscriptions
+            .get(topic)
+            .is_some_and(|ids| ids.contains(mailbox_id))
+        {
+            return Ok(false);
+        }
+        let mut changed = subscriptions.clone();
+        if let Some(ids) = changed.get_mut(topic) {
+            ids.remove(mailbox_id);
+            if ids.is_empty() {
+                changed.remove(topic);
+            }
+        }
+        self.save(&changed).await?;
+        *subscriptions = changed;
+        Ok(true)
+    }
+
+    /// The mailboxes subscribed to `topic`, sorted
+    pub async fn subscribers(&self, topic: &str) -> Vec<String> {
+        let subscriptions = self.subscriptions.read().await;
+        subscriptions
+            .get(topic)
+            .map(|ids| ids.iter().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    /// Sends a copy of `item` to every mailbox subscribed to `topic`, with an entry per subscriber
+    ///
+    /// The item is serialized once, and sent with [Mailbox::send_raw].
+    /// A failing subscriber doesn't keep the others from getting their copy,
+    /// only failing to serialize the item fails the whole publish.
+    /// Publishing to a topic without subscribers does nothing.
+    /// Note: There is no rollback across mailboxes, check [BulkResult::is_all_ok].
+    pub async fn publish(&self, topic: &str, item: ITEM) -> Result<BulkResult<String>> {
+        let subscriptions = self.subscriptions.read().await;
+        let mut sent = BulkResult::default();
+        let Some(ids) = subscriptions.get(topic) else {
+            return Ok(sent);
+        };
+        let data = item.serialize()?;
+        for mailbox_id in ids {
+            let r = self
+                .backend
+                .send_raw(mailbox_id, &data)
+                .await
+                .wrap_err_with(|| format!("Can't publish {topic} to {mailbox_id}"));
+            sent.push(mailbox_id, r.as_ref().ok().cloned(), r);
+        }
+        Ok(sent)
+    }
+
+    async fn save(&self, subscriptions: &Subscriptions) -> Result<()> {
+        let b = serde_json::to_vec_pretty(subscriptions)?;
+        self.store.put(&self.key, &b).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::FsStorage;
+    use crate::Mailbox;
+    use crate::MailboxDisk;
+    use crate::MailboxItem;
+    use crate::TopicRouter;
+    use color_eyre::Result;
+    use std::path::Path;
+    use std::sync::Arc;
+    use tempfile::TempDir;
+
+    use test_log::test;
+
+    #[derive(Debug, Default)]
+    struct TestItem {
+        data: String,
+    }
+
+    impl MailboxItem for TestItem {
+        fn serialize(&self) -> Result<Vec<u8>> {
+            Ok(self.data.clone().into())
+        }
+        fn deserialize(data: &[u8]) -> Result<Self>
+        where
+            Self: Sized,
+        {
+            Ok(Self {
+                data: String::from_utf8(data.to_vec())?,
+            })
+        }
+    }
+
+    fn item(data: &str) -> TestItem {
+        TestItem {
+            data: data.to_string(),
+        }
+    }
+
+    #[test(tokio::test)]
+    async fn it_fans_out_to_subscribers() -> Result<()> {
+        let dir = TempDir::new()?;
+        let mut disk =
+            MailboxDisk::<TestItem>::at(&dir.path().join("mailboxes"), Path::new("item"));
+        disk.ensure_storage_exists().await?;
+        let disk = Arc::new(disk);
+        let store = Arc::new(FsStorage::new());
+        let folder = dir.path().join("subscriptions");
+
+        let router = TopicRouter::new(disk.clone(), store.clone(), &folder).await?;
+        assert!(router.subscribe("news", "alice").await?);
+        assert!(router.subscribe("news", "bob").await?);
+        assert!(!router.subscribe("news", "bob").await?);
+
+        let sent = router.publish("news", item("one")).await?;
+        assert_eq!(sent.len(), 2);
+        assert_eq!(disk.pop("alice").await?.expect("Copy").1.data, "one");
+        assert_eq!(disk.pop("bob").await?.expect("Copy").1.data, "one");
+
+        assert!(router.unsubscribe("news", "alice").await?);
+        router.publish("news", item("two")).await?;
+        assert!(disk.pop("alice").await?.is_none());
+        assert_eq!(disk.pop("bob").await?.expect("Copy").1.data, "two");
+        assert!(router.publish("weather", item("sunny")).await?.is_empty());
+
+        let router = TopicRouter::new(disk.clone(), store, &folder).await?;
+        assert_eq!(router.subscribers("news").await, ["bob"]);
+        router.publish("news", item("three")).await?;
+        assert!(disk.pop("alice").await?.is_none());
+        assert_eq!(disk.pop("bob").await?.expect("Copy").1.data, "three");
+
+        Ok(())
+    }
+}
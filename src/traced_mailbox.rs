@@ -0,0 +1,391 @@
+use crate::ExpireReport;
+use crate::HeaderSelector;
+use crate::HealthReport;
+use crate::ItemSummary;
+use crate::Mailbox;
+use crate::MailboxError;
+use crate::MailboxItem;
+use crate::MailboxStats;
+use crate::Page;
+use crate::RawItem;
+use async_trait::async_trait;
+use color_eyre::eyre::eyre;
+use color_eyre::eyre::Result;
+use core::marker::PhantomData;
+use opentelemetry::trace::TraceContextExt;
+use opentelemetry::Context;
+use std::collections::HashMap;
+use std::time::Duration;
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+const FORMAT_VERSION: u8 = 1;
+
+/// An item received via [TracedMailbox::receive_traced], with the trace context of its sender
+#[derive(Debug)]
+pub struct TracedItem<ITEM> {
+    pub item_id: String,
+    pub item: ITEM,
+    headers: HashMap<String, String>,
+}
+
+impl<ITEM> TracedItem<ITEM> {
+    /// The context of the span that sent the item, use it as parent for the processing span
+    ///
+    /// Empty if the item was sent without a span, or without a propagator installed.
+    pub fn context(&self) -> Context {
+        extract(&self.headers)
+    }
+}
+
+/// Carries the trace context of the sender to the receiver, for any mailbox storing [RawItem]s
+///
+/// `send` injects the context of the current span via the global propagator,
+/// see [opentelemetry::global::set_text_map_propagator], e.g. as W3C `traceparent`.
+/// The stored bytes are framed as:
+/// ```text
+/// version (u8) | headers length (u16, big endian) | headers | item
+/// ```
+/// with the headers as `key=value` lines.
+///
+/// The receiving `Mailbox` methods link the current span to the sender's span.
+/// To make the sender's span the parent instead use [TracedMailbox::receive_traced].
+///
+/// Without a subscriber or propagator nothing is injected, and the headers stay empty.
+#[derive(Debug)]
+pub struct TracedMailbox<ITEM: MailboxItem, M: Mailbox<RawItem>> {
+    inner: M,
+    item_type: PhantomData<ITEM>,
+}
+
+impl<ITEM: MailboxItem, M: Mailbox<RawItem>> TracedMailbox<ITEM, M> {
+    pub fn new(inner: M) -> Self {
+        Self {
+            inner,
+            item_type: PhantomData,
+        }
+    }
+
+    pub fn inner(&self) -> &M {
+        &self.inner
+    }
+
+    pub fn into_inner(self) -> M {
+        self.inner
+    }
+
+    /// Like `receive`, but also returns the trace context of the sender
+    pub async fn receive_traced(&self, id: &str) -> Result<Option<TracedItem<ITEM>>> {
+        match self.inner.receive(id).await? {
+            Some((item_id, raw)) => {
+                let (headers, item) = unwrap_frame(&raw)?;
+                Ok(Some(TracedItem {
+                    item_id,
+                    item: ITEM::deserialize(item)?,
+                    headers,
+                }))
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn wrap(&self, item: &ITEM) -> Result<RawItem> {
+        let mut headers = HashMap::new();
+        let span = tracing::Span::current();
+        if !span.is_disabled() {
+            let cx = span.context();
+            opentelemetry::global::get_text_map_propagator(|p| p.inject_context(&cx, &mut headers));
+        }
+        let mut encoded = String::new();
+        for (key, value) in headers {
+            encoded.push_str(&format!("{key}={value}\n"));
+        }
+        let len = u16::try_from(encoded.len()).map_err(|_| eyre!("Trace headers too long"))?;
+
+        let mut frame = vec![FORMAT_VERSION];
+        frame.extend_from_slice(&len.to_be_bytes());
+        frame.extend_from_slice(encoded.as_bytes());
+        frame.extend_from_slice(&item.serialize()?);
+
+        Ok(RawItem::new(frame))
+    }
+
+    /// Unwraps the item, and links the current span to the sender's span
+    fn unwrap(&self, raw: &RawItem) -> Result<ITEM> {
+        let (headers, item) = unwrap_frame(raw)?;
+        if !headers.is_empty() {
+            let cx = extract(&headers);
+            let span_context = cx.span().span_context().clone();
+            if span_context.is_valid() {
+                tracing::Span::current().add_link(span_context);
+            }
+        }
+
+        ITEM::deserialize_from_bytes(raw.0.slice_ref(item))
+    }
+}
+
+fn unwrap_frame(raw: &RawItem) -> Result<(HashMap<String, String>, &[u8])> {
+    let frame = raw.data();
+    let truncated = || eyre!("Truncated trace frame");
+
+    let (&version, rest) = frame.split_first().ok_or_else(truncated)?;
+    if version != FORMAT_VERSION {
+        return Err(eyre!("Unsupported trace frame version {version}"));
+    }
+    let len = rest.get(..2).ok_or_else(truncated)?;
+    let len = u16::from_be_bytes([len[0], len[1]]) as usize;
+    let encoded = rest.get(2..2 + len).ok_or_else(truncated)?;
+    let item = &rest[2 + len..];
+
+    let headers = String::from_utf8_lossy(encoded)
+        .lines()
+        .filter_map(|line| line.split_once('='))
+        .map(|(key, value)| (key.to_string(), value.to_string()))
+        .collect();
+
+    Ok((headers, item))
+}
+
+fn extract(headers: &HashMap<String, String>) -> Context {
+    opentelemetry::global::get_text_map_propagator(|p| p.extract(headers))
+}
+
+#[async_trait]
+impl<ITEM: MailboxItem + Send, M: Mailbox<RawItem>> Mailbox<ITEM> for TracedMailbox<ITEM, M> {
+    async fn ensure_storage_exists(&mut self) -> Result<()> {
+        self.inner.ensure_storage_exists().await
+    }
+    async fn close(&mut self) -> Result<()> {
+        self.inner.close().await
+    }
+
+    async fn create_mailbox(&self, id: &str) -> Result<()> {
+        self.inner.create_mailbox(id).await
+    }
+    async fn send(&self, id: &str, item: ITEM) -> Result<String> {
+        let raw = self.wrap(&item)?;
+        self.inner.send(id, raw).await
+    }
+    async fn send_transaction(&self, id: &str, items: Vec<ITEM>) -> Result<Vec<String>> {
+        let raws = items
+            .iter()
+            .map(|item| self.wrap(item))
+            .collect::<Result<Vec<_>>>()?;
+        self.inner.send_transaction(id, raws).await
+    }
+    async fn receive(&self, id: &str) -> Result<Option<(String, ITEM)>> {
+        match self.inner.receive(id).await? {
+            Some((item_id, raw)) => Ok(Some((item_id, self.unwrap(&raw)?))),
+            None => Ok(None),
+        }
+    }
+    async fn receive_any(&self, ids: &[String]) -> Result<Option<(String, String, ITEM)>> {
+        match self.inner.receive_any(ids).await? {
+            Some((id, item_id, raw)) => {
+                let item = self.unwrap(&raw)?;
+                Ok(Some((id, item_id, item)))
+            }
+            None => Ok(None),
+        }
+    }
+    async fn acknowledge(&self, id: &str, item_id: &str) -> Result<()> {
+        self.inner.acknowledge(id, item_id).await
+    }
+    async fn acknowledge_through(&self, id: &str, item_id: &str) -> Result<u64> {
+        self.inner.acknowledge_through(id, item_id).await
+    }
+    async fn release(&self, id: &str, item_id: &str) -> Result<()> {
+        self.inner.release(id, item_id).await
+    }
+    fn release_later(&self, id: &str, item_id: &str) {
+        self.inner.release_later(id, item_id)
+    }
+    async fn update(&self, id: &str, item_id: &str, item: ITEM) -> Result<()> {
+        let raw = self.wrap(&item)?;
+        self.inner.update(id, item_id, raw).await
+    }
+    async fn peek(&self, id: &str) -> Result<Option<(String, ITEM)>> {
+        match self.inner.peek(id).await? {
+            Some((item_id, raw)) => Ok(Some((item_id, self.unwrap(&raw)?))),
+            None => Ok(None),
+        }
+    }
+    async fn receive_many(&self, id: &str, max: usize) -> Result<Vec<(String, ITEM)>> {
+        let raws = self.inner.receive_many(id, max).await?;
+        raws.into_iter()
+            .map(|(item_id, raw)| Ok((item_id, self.unwrap(&raw)?)))
+            .collect()
+    }
+    async fn receive_batch(
+        &self,
+        id: &str,
+        max_items: usize,
+        max_wait: Duration,
+    ) -> Result<Vec<(String, ITEM)>> {
+        let raws = self.inner.receive_batch(id, max_items, max_wait).await?;
+        raws.into_iter()
+            .map(|(item_id, raw)| Ok((item_id, self.unwrap(&raw)?)))
+            .collect()
+    }
+    /// Note: Only an empty selector is supported, the inner mailbox only sees the framed item.
+    async fn receive_matching(
+        &self,
+        id: &str,
+        selector: &HeaderSelector,
+    ) -> Result<Option<(String, ITEM)>> {
+        if !selector.is_empty() {
+            return Err(MailboxError::Unsupported {
+                op: "receive_matching".to_string(),
+                reason: "the inner mailbox only sees the framed item".to_string(),
+            }
+            .into());
+        }
+        self.receive(id).await
+    }
+    async fn list_mailboxes(&self) -> Result<Vec<String>> {
+        self.inner.list_mailboxes().await
+    }
+    async fn delivery_count(&self, id: &str, item_id: &str) -> Result<u32> {
+        self.inner.delivery_count(id, item_id).await
+    }
+    /// Note: The sizes include the trace headers.
+    async fn list_items_page(
+        &self,
+        id: &str,
+        after: Option<&str>,
+        limit: usize,
+    ) -> Result<Page<ItemSummary>> {
+        self.inner.list_items_page(id, after, limit).await
+    }
+    async fn item_size(&self, _id: &str, _item_id: &str) -> Result<u64> {
+        Err(MailboxError::Unsupported {
+            op: "item_size".to_string(),
+            reason: "the inner mailbox only knows the size of the framed item".to_string(),
+        }
+        .into())
+    }
+    async fn stats(&self, id: &str) -> Result<MailboxStats> {
+        self.inner.stats(id).await
+    }
+    async fn receive_for(&self, id: &str, group: &str) -> Result<Option<(String, ITEM)>> {
+        match self.inner.receive_for(id, group).await? {
+            Some((item_id, raw)) => Ok(Some((item_id, self.unwrap(&raw)?))),
+            None => Ok(None),
+        }
+    }
+    async fn acknowledge_for(&self, id: &str, group: &str, item_id: &str) -> Result<()> {
+        self.inner.acknowledge_for(id, group, item_id).await
+    }
+    async fn compact(&self, id: &str) -> Result<u64> {
+        self.inner.compact(id).await
+    }
+    async fn drop_older_than(&self, id: &str, max_age: Duration) -> Result<u64> {
+        self.inner.drop_older_than(id, max_age).await
+    }
+    async fn expire_items(&self, id: &str) -> Result<ExpireReport> {
+        self.inner.expire_items(id).await
+    }
+    async fn health_check(&self) -> Result<HealthReport> {
+        self.inner.health_check().await
+    }
+    // Note: `pop` and `drain` use the default implementations, so a broken frame is not acknowledged
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::Mailbox;
+    use crate::MailboxDisk;
+    use crate::MailboxItem;
+    use crate::RawItem;
+    use crate::TracedMailbox;
+    use color_eyre::Result;
+    use opentelemetry::trace::TracerProvider;
+    use opentelemetry_sdk::propagation::TraceContextPropagator;
+    use opentelemetry_sdk::trace::InMemorySpanExporter;
+    use opentelemetry_sdk::trace::SdkTracerProvider;
+    use std::path::Path;
+    use tempfile::TempDir;
+    use tracing::Instrument;
+    use tracing_opentelemetry::OpenTelemetrySpanExt;
+    use tracing_subscriber::layer::SubscriberExt;
+
+    #[derive(Default, Debug)]
+    struct TestItem {
+        data: String,
+    }
+
+    impl MailboxItem for TestItem {
+        fn serialize(&self) -> Result<Vec<u8>> {
+            Ok(self.data.clone().into())
+        }
+        fn deserialize(data: &[u8]) -> Result<Self>
+        where
+            Self: Sized,
+        {
+            Ok(Self {
+                data: String::from_utf8(data.to_vec())?,
+            })
+        }
+    }
+
+    // Note: no `test_log` here, the test installs its own subscriber
+    #[tokio::test]
+    async fn it_continues_the_trace() -> Result<()> {
+        let exporter = InMemorySpanExporter::default();
+        let provider = SdkTracerProvider::builder()
+            .with_simple_exporter(exporter.clone())
+            .build();
+        let subscriber = tracing_subscriber::registry()
+            .with(tracing_opentelemetry::layer().with_tracer(provider.tracer("test")));
+        let _guard = tracing::subscriber::set_default(subscriber);
+        opentelemetry::global::set_text_map_propagator(TraceContextPropagator::new());
+
+        let dir = TempDir::new()?;
+        let mut disk = MailboxDisk::<RawItem>::at(dir.path(), Path::new("item"));
+        disk.ensure_storage_exists().await?;
+        let mailbox = TracedMailbox::<TestItem, _>::new(disk);
+
+        for data in ["one", "two"] {
+            let item = TestItem { data: data.into() };
+            mailbox
+                .send("42", item)
+                .instrument(tracing::info_span!("produce"))
+                .await?;
+        }
+
+        let traced = mailbox.receive_traced("42").await?.expect("Item pending");
+        assert_eq!(traced.item.data, "one");
+        mailbox.acknowledge("42", &traced.item_id).await?;
+        let span = tracing::info_span!("consume");
+        span.set_parent(traced.context())?;
+        drop(span);
+
+        let (_id, item) = mailbox
+            .receive("42")
+            .instrument(tracing::info_span!("consume_linked"))
+            .await?
+            .expect("Item pending");
+        assert_eq!(item.data, "two");
+
+        provider.force_flush()?;
+        let spans = exporter.get_finished_spans()?;
+        let find = |name: &str| spans.iter().filter(|s| s.name == name).collect::<Vec<_>>();
+        let produced = find("produce");
+        let consumed = find("consume");
+        let linked = find("consume_linked");
+        assert_eq!((produced.len(), consumed.len(), linked.len()), (2, 1, 1));
+
+        let parent = &produced[0].span_context;
+        assert_eq!(consumed[0].span_context.trace_id(), parent.trace_id());
+        assert_eq!(consumed[0].parent_span_id, parent.span_id());
+
+        let links = &linked[0].links.links;
+        assert_eq!(links.len(), 1);
+        assert_eq!(
+            links[0].span_context.span_id(),
+            produced[1].span_context.span_id()
+        );
+
+        Ok(())
+    }
+}
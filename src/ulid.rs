@@ -0,0 +1,82 @@
+use chrono::DateTime;
+use chrono::Utc;
+use std::sync::Mutex;
+
+/// Crockford's base32, which sorts like the numbers it encodes
+const ALPHABET: &[u8; 32] = b"0123456789ABCDEFGHJKMNPQRSTVWXYZ";
+const ULID_LEN: usize = 26;
+const RANDOM_BITS: u32 = 80;
+
+/// Generates ULIDs, 48 bits of milliseconds since the epoch followed by 80 random bits
+///
+/// Ids from one generator are strictly increasing,
+/// within the same millisecond, or if the clock goes backwards, the last id is incremented instead.
+#[derive(Debug, Default)]
+pub(crate) struct UlidGenerator {
+    last: Mutex<u128>,
+}
+
+impl UlidGenerator {
+    pub(crate) fn next(&self, now: DateTime<Utc>) -> String {
+        let ms = now.timestamp_millis().max(0) as u128 & ((1 << 48) - 1);
+        let random = fastrand::u128(..) & ((1 << RANDOM_BITS) - 1);
+        let mut ulid = (ms << RANDOM_BITS) | random;
+        let mut last = self.last.lock().unwrap();
+        if ulid <= *last {
+            ulid = *last + 1;
+        }
+        *last = ulid;
+        encode(ulid)
+    }
+}
+
+fn encode(ulid: u128) -> String {
+    (0..ULID_LEN)
+        .rev()
+        .map(|i| ALPHABET[((ulid >> (i * 5)) & 0x1f) as usize] as char)
+        .collect()
+}
+
+/// `None` for everything that is not a ULID in canonical form, e.g. lowercase
+pub(crate) fn decode(ulid: &str) -> Option<u128> {
+    if ulid.len() != ULID_LEN || !ulid.starts_with(|c: char| ('0'..='7').contains(&c)) {
+        return None;
+    }
+    ulid.bytes().try_fold(0u128, |value, c| {
+        let digit = ALPHABET.iter().position(|&a| a == c)? as u128;
+        Some((value << 5) | digit)
+    })
+}
+
+/// The time encoded in a ULID
+pub(crate) fn timestamp(ulid: &str) -> Option<DateTime<Utc>> {
+    let ms = decode(ulid)? >> RANDOM_BITS;
+    DateTime::from_timestamp_millis(ms as i64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::decode;
+    use super::timestamp;
+    use super::UlidGenerator;
+    use chrono::DateTime;
+
+    #[test]
+    fn it_generates_increasing_ulids() {
+        let now = DateTime::parse_from_rfc3339("2024-06-01T12:00:00.123Z")
+            .unwrap()
+            .to_utc();
+        let generator = UlidGenerator::default();
+        let ulids: Vec<String> = (0..100).map(|_| generator.next(now)).collect();
+        assert!(ulids.windows(2).all(|w| w[0] < w[1]));
+        for ulid in ulids.iter() {
+            assert!(decode(ulid).is_some());
+            assert_eq!(timestamp(ulid), Some(now));
+        }
+
+        let earlier = generator.next(now - std::time::Duration::from_secs(1));
+        assert!(earlier > ulids[99]);
+        assert_eq!(decode("17"), None);
+        assert_eq!(decode(&ulids[0].to_lowercase()), None);
+    }
+}
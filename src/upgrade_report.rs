@@ -0,0 +1,19 @@
+use crate::LayoutVersion;
+
+/// What [crate::MailboxDisk::upgrade_mailbox] did, or would have done for a dry run
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UpgradeReport {
+    pub from: LayoutVersion,
+    pub to: LayoutVersion,
+    /// The envelopes rewritten, in id order
+    pub upgraded: Vec<String>,
+    /// An interrupted upgrade was picked up where it stopped
+    pub resumed: bool,
+}
+
+impl UpgradeReport {
+    /// Nothing had to change
+    pub fn is_noop(&self) -> bool {
+        self.from == self.to && self.upgraded.is_empty() && !self.resumed
+    }
+}
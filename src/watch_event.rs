@@ -0,0 +1,7 @@
+/// Something that happened in a watched mailbox, see [crate::MailboxDisk::watch]
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum WatchEvent {
+    /// A new item was sent, possibly by another process
+    NewItem { item_id: String },
+}